@@ -36,6 +36,7 @@ impl From<GroupListGroup> for Group {
         Self {
             id: group.id,
             display_name: group.display_name,
+            description: group.description,
         }
     }
 }
@@ -86,10 +87,13 @@ impl CommonComponent<AddUserToGroupComponent> for AddUserToGroupComponent {
             }
             Msg::SelectionChanged(option_props) => {
                 let was_some = self.selected_group.is_some();
-                self.selected_group = option_props.map(|props| Group {
-                    id: props.value.parse::<i64>().unwrap(),
-                    display_name: props.text,
-                });
+                let group_id = option_props.map(|props| props.value.parse::<i64>().unwrap());
+                self.selected_group = self
+                    .group_list
+                    .iter()
+                    .flatten()
+                    .find(|group| Some(group.id) == group_id)
+                    .cloned();
                 return Ok(self.selected_group.is_some() != was_some);
             }
         }
@@ -167,7 +171,7 @@ impl Component for AddUserToGroupComponent {
             #[allow(unused_braces)]
             let make_select_option = |group: Group| {
                 html_nested! {
-                    <SelectOption value=group.id.to_string() text=group.display_name key=group.id />
+                    <SelectOption value=group.id.to_string() text=group.description.unwrap_or(group.display_name) key=group.id />
                 }
             };
             html! {
@@ -1,5 +1,6 @@
 use crate::{
     components::{
+        accept_invitation::AcceptInvitationForm,
         change_password::ChangePasswordForm,
         create_group::CreateGroupForm,
         create_user::CreateUserForm,
@@ -121,6 +122,7 @@ impl App {
             || current_route == "/"
             || current_route.contains("login")
             || current_route.contains("reset-password")
+            || current_route.contains("accept-invitation")
         {
             None
         } else {
@@ -132,7 +134,7 @@ impl App {
     fn apply_initial_redirections(&mut self) {
         let route_service = RouteService::<()>::new();
         let current_route = route_service.get_path();
-        if current_route.contains("reset-password") {
+        if current_route.contains("reset-password") || current_route.contains("accept-invitation") {
             return;
         }
         match &self.user_info {
@@ -198,6 +200,9 @@ impl App {
             AppRoute::FinishResetPassword(token) => html! {
                 <ResetPasswordStep2Form token=token />
             },
+            AppRoute::AcceptInvitation(token) => html! {
+                <AcceptInvitationForm token=token />
+            },
         }
     }
 
@@ -5,7 +5,7 @@ use crate::{
         common_component::{CommonComponent, CommonComponentParts},
     },
 };
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Context, Result};
 use lldap_auth::*;
 use validator_derive::Validate;
 use yew::{prelude::*, services::ConsoleService};
@@ -19,7 +19,8 @@ use yew_router::{
 #[derive(PartialEq, Eq)]
 enum OpaqueData {
     None,
-    Login(opaque::client::login::ClientLogin),
+    /// Whether the raw password was used, see `opaque::needs_raw_password_fallback`.
+    Login(opaque::client::login::ClientLogin, bool),
     Registration(opaque::client::registration::ClientRegistration),
 }
 
@@ -94,38 +95,29 @@ impl CommonComponent<ChangePasswordForm> for ChangePasswordForm {
                     if old_password.is_empty() {
                         bail!("Current password should not be empty");
                     }
-                    let mut rng = rand::rngs::OsRng;
-                    let login_start_request =
-                        opaque::client::login::start_login(&old_password, &mut rng)
-                            .context("Could not initialize login")?;
-                    self.opaque_data = OpaqueData::Login(login_start_request.state);
-                    let req = login::ClientLoginStartRequest {
-                        username: self.common.username.clone(),
-                        login_start_request: login_start_request.message,
-                    };
-                    self.common.call_backend(
-                        HostService::login_start,
-                        req,
-                        Msg::AuthenticationStartResponse,
-                    )?;
+                    self.start_login(&old_password, false)?;
                     Ok(true)
                 }
             }
             Msg::AuthenticationStartResponse(res) => {
                 let res = res.context("Could not initiate login")?;
                 match self.opaque_data.take() {
-                    OpaqueData::Login(l) => {
-                        opaque::client::login::finish_login(l, res.credential_response).map_err(
-                            |e| {
-                                // Common error, we want to print a full error to the console but only a
-                                // simple one to the user.
-                                ConsoleService::error(&format!(
-                                    "Invalid username or password: {}",
-                                    e
-                                ));
-                                anyhow!("Invalid username or password")
-                            },
-                        )?;
+                    OpaqueData::Login(l, raw_password) => {
+                        if let Err(e) =
+                            opaque::client::login::finish_login(l, res.credential_response)
+                        {
+                            let old_password = self.form.model().old_password;
+                            if !raw_password && opaque::needs_raw_password_fallback(&old_password) {
+                                // The password may have been registered before the passwords were
+                                // prepared.
+                                self.start_login(&old_password, true)?;
+                                return Ok(false);
+                            }
+                            // Common error, we want to print a full error to the console but only a
+                            // simple one to the user.
+                            ConsoleService::error(&format!("Invalid username or password: {}", e));
+                            bail!("Invalid username or password");
+                        }
                     }
                     _ => panic!("Unexpected data in opaque_data field"),
                 };
@@ -194,6 +186,30 @@ impl CommonComponent<ChangePasswordForm> for ChangePasswordForm {
     }
 }
 
+impl ChangePasswordForm {
+    /// Checks the current password, with the raw password instead of the prepared one if
+    /// `raw_password` is set.
+    fn start_login(&mut self, password: &str, raw_password: bool) -> Result<()> {
+        let mut rng = rand::rngs::OsRng;
+        let login_start_request = if raw_password {
+            opaque::client::login::start_login_with_raw_password(password, &mut rng)
+        } else {
+            opaque::client::login::start_login(password, &mut rng)
+        }
+        .context("Could not initialize login")?;
+        self.opaque_data = OpaqueData::Login(login_start_request.state, raw_password);
+        let req = login::ClientLoginStartRequest {
+            username: self.common.username.clone(),
+            login_start_request: login_start_request.message,
+        };
+        self.common.call_backend(
+            HostService::login_start,
+            req,
+            Msg::AuthenticationStartResponse,
+        )
+    }
+}
+
 impl Component for ChangePasswordForm {
     type Message = Msg;
     type Properties = Props;
@@ -32,6 +32,7 @@ pub struct CreateGroupForm {
 pub struct CreateGroupModel {
     #[validate(length(min = 1, message = "Groupname is required"))]
     groupname: String,
+    description: String,
 }
 
 pub enum Msg {
@@ -51,6 +52,7 @@ impl CommonComponent<CreateGroupForm> for CreateGroupForm {
                 let model = self.form.model();
                 let req = create_group::Variables {
                     name: model.groupname,
+                    description: Some(model.description).filter(|d| !d.is_empty()),
                 };
                 self.common.call_graphql::<CreateGroup, _>(
                     req,
@@ -123,6 +125,24 @@ impl Component for CreateGroupForm {
                   </div>
                 </div>
               </div>
+              <div class="form-group row mb-3">
+                <label for="description"
+                  class="form-label col-4 col-form-label">
+                  {"Description:"}
+                </label>
+                <div class="col-8">
+                  <Field
+                    form=&self.form
+                    field_name="description"
+                    class="form-control"
+                    class_invalid="is-invalid has-error"
+                    class_valid="has-success"
+                    oninput=self.common.callback(|_| Msg::Update) />
+                  <div class="invalid-feedback">
+                    {&self.form.field_message("description")}
+                  </div>
+                </div>
+              </div>
               <div class="form-group row justify-content-center">
                 <button
                   class="btn btn-primary col-auto col-form-label"
@@ -119,7 +119,7 @@ impl GroupTable {
           <tr key=group.id>
               <td>
                 <Link route=AppRoute::GroupDetails(group.id)>
-                  {&group.display_name}
+                  {group.description.as_ref().unwrap_or(&group.display_name)}
                 </Link>
               </td>
               <td>
@@ -34,9 +34,12 @@ pub struct Props {
 pub enum Msg {
     Update,
     Submit,
+    /// The login state, whether the raw password was used (see
+    /// `opaque::needs_raw_password_fallback`), and the response.
     AuthenticationStartResponse(
         (
             opaque::client::login::ClientLogin,
+            bool,
             Result<Box<login::ServerLoginStartResponse>>,
         ),
     ),
@@ -51,26 +54,25 @@ impl CommonComponent<LoginForm> for LoginForm {
                 if !self.form.validate() {
                     bail!("Check the form for errors");
                 }
-                let FormModel { username, password } = self.form.model();
-                let mut rng = rand::rngs::OsRng;
-                let opaque::client::login::ClientLoginStartResult { state, message } =
-                    opaque::client::login::start_login(&password, &mut rng)
-                        .context("Could not initialize login")?;
-                let req = login::ClientLoginStartRequest {
-                    username,
-                    login_start_request: message,
-                };
-                self.common
-                    .call_backend(HostService::login_start, req, move |r| {
-                        Msg::AuthenticationStartResponse((state, r))
-                    })?;
+                self.start_login(false)?;
                 Ok(true)
             }
-            Msg::AuthenticationStartResponse((login_start, res)) => {
+            Msg::AuthenticationStartResponse((login_start, raw_password, res)) => {
                 let res = res.context("Could not log in (invalid response to login start)")?;
                 let login_finish =
                     match opaque::client::login::finish_login(login_start, res.credential_response)
                     {
+                        Err(_)
+                            if !raw_password
+                                && opaque::needs_raw_password_fallback(
+                                    &self.form.model().password,
+                                ) =>
+                        {
+                            // The password may have been registered before the passwords were
+                            // prepared.
+                            self.start_login(true)?;
+                            return Ok(false);
+                        }
                         Err(e) => {
                             // Common error, we want to print a full error to the console but only a
                             // simple one to the user.
@@ -107,6 +109,29 @@ impl CommonComponent<LoginForm> for LoginForm {
     }
 }
 
+impl LoginForm {
+    /// Starts the login with the password of the form, raw instead of prepared if `raw_password`
+    /// is set.
+    fn start_login(&mut self, raw_password: bool) -> Result<()> {
+        let FormModel { username, password } = self.form.model();
+        let mut rng = rand::rngs::OsRng;
+        let opaque::client::login::ClientLoginStartResult { state, message } = if raw_password {
+            opaque::client::login::start_login_with_raw_password(&password, &mut rng)
+        } else {
+            opaque::client::login::start_login(&password, &mut rng)
+        }
+        .context("Could not initialize login")?;
+        let req = login::ClientLoginStartRequest {
+            username,
+            login_start_request: message,
+        };
+        self.common
+            .call_backend(HostService::login_start, req, move |r| {
+                Msg::AuthenticationStartResponse((state, raw_password, r))
+            })
+    }
+}
+
 impl Component for LoginForm {
     type Message = Msg;
     type Properties = Props;
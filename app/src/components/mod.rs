@@ -1,3 +1,4 @@
+pub mod accept_invitation;
 pub mod add_group_member;
 pub mod add_user_to_group;
 pub mod app;
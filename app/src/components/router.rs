@@ -11,6 +11,8 @@ pub enum AppRoute {
     StartResetPassword,
     #[to = "/reset-password/step2/{token}"]
     FinishResetPassword(String),
+    #[to = "/accept-invitation/{token}"]
+    AcceptInvitation(String),
     #[to = "/users/create"]
     CreateUser,
     #[to = "/users"]
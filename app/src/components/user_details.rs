@@ -102,13 +102,14 @@ impl UserDetails {
     fn view_group_memberships(&self, u: &User) -> Html {
         let make_group_row = |group: &Group| {
             let display_name = group.display_name.clone();
+            let shown_name = group.description.as_ref().unwrap_or(&group.display_name);
             html! {
               <tr key="groupRow_".to_string() + &display_name>
                 {if self.common.is_admin { html! {
                   <>
                     <td>
                       <Link route=AppRoute::GroupDetails(group.id)>
-                        {&group.display_name}
+                        {shown_name}
                       </Link>
                     </td>
                     <td>
@@ -120,7 +121,7 @@ impl UserDetails {
                     </td>
                   </>
                 } } else { html! {
-                  <td>{&group.display_name}</td>
+                  <td>{shown_name}</td>
                 } } }
               </tr>
             }
@@ -1,7 +1,7 @@
 use super::cookies::set_cookie;
 use anyhow::{anyhow, Context, Result};
 use graphql_client::GraphQLQuery;
-use lldap_auth::{login, registration, JWTClaims};
+use lldap_auth::{invitation, login, registration, JWTClaims};
 
 use yew::callback::Callback;
 use yew::format::Json;
@@ -249,6 +249,18 @@ impl HostService {
         )
     }
 
+    pub fn accept_invitation(
+        request: invitation::AcceptInvitationRequest,
+        callback: Callback<Result<()>>,
+    ) -> Result<FetchTask> {
+        call_server_empty_response_with_error_message(
+            "/auth/accept-invitation",
+            &request,
+            callback,
+            "Could not accept the invitation",
+        )
+    }
+
     pub fn reset_password_step2(
         token: &str,
         callback: Callback<Result<lldap_auth::password_reset::ServerPasswordResetResponse>>,
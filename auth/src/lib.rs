@@ -104,6 +104,29 @@ pub mod password_reset {
     }
 }
 
+/// The messages for the user invitation process.
+pub mod invitation {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct CreateInvitationRequest {
+        pub email: String,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct CreateInvitationResponse {
+        pub id: i32,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct AcceptInvitationRequest {
+        pub token: String,
+        #[serde(rename = "displayName")]
+        pub display_name: String,
+        pub password: String,
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct JWTClaims {
     pub exp: DateTime<Utc>,
@@ -1,5 +1,6 @@
 use opaque_ke::ciphersuite::CipherSuite;
 use rand::{CryptoRng, RngCore};
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(thiserror::Error, Debug)]
 pub enum AuthenticationError {
@@ -47,6 +48,47 @@ impl<D: opaque_ke::hash::Hash> opaque_ke::slow_hash::SlowHash<D> for ArgonHasher
     }
 }
 
+/// Prepares a password before it goes into the OPAQUE protocol, in the spirit of SASLprep (RFC
+/// 4013): the non-ASCII spaces are mapped to a space, the characters "commonly mapped to nothing"
+/// (soft hyphen, zero-width spaces, variation selectors...) are removed, and the result is
+/// normalized with NFKC. That way, the same password typed on different systems, e.g. with "é" as
+/// one character or as "e" followed by a combining accent, gives the same bytes.
+///
+/// Both the registration and the login go through it, whether on the web client or on the server
+/// for LDAP binds.
+pub fn prepare_password(password: &str) -> String {
+    password
+        .chars()
+        .filter_map(|c| match c {
+            // RFC 3454 table B.1.
+            '\u{00AD}'
+            | '\u{034F}'
+            | '\u{1806}'
+            | '\u{180B}'..='\u{180D}'
+            | '\u{200B}'..='\u{200D}'
+            | '\u{2060}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}' => None,
+            // RFC 3454 table C.1.2.
+            '\u{00A0}'
+            | '\u{1680}'
+            | '\u{2000}'..='\u{200A}'
+            | '\u{202F}'
+            | '\u{205F}'
+            | '\u{3000}' => Some(' '),
+            c => Some(c),
+        })
+        .nfkc()
+        .collect()
+}
+
+/// Whether `prepare_password` changes the password. The passwords registered before it was
+/// introduced went into OPAQUE as is: if the login with the prepared password fails, the clients
+/// try again with `start_login_with_raw_password`.
+pub fn needs_raw_password_fallback(password: &str) -> bool {
+    prepare_password(password) != password
+}
+
 /// The ciphersuite trait allows to specify the underlying primitives
 /// that will be used in the OPAQUE protocol
 #[allow(dead_code)]
@@ -80,7 +122,10 @@ pub mod client {
             password: &str,
             rng: &mut R,
         ) -> AuthenticationResult<ClientRegistrationStartResult> {
-            Ok(ClientRegistration::start(rng, password.as_bytes())?)
+            Ok(ClientRegistration::start(
+                rng,
+                prepare_password(password).as_bytes(),
+            )?)
         }
 
         /// Finalize the registration negotiation.
@@ -111,6 +156,18 @@ pub mod client {
         pub fn start_login<R: RngCore + CryptoRng>(
             password: &str,
             rng: &mut R,
+        ) -> AuthenticationResult<ClientLoginStartResult> {
+            Ok(ClientLogin::start(
+                rng,
+                prepare_password(password).as_bytes(),
+            )?)
+        }
+
+        /// Initiate the login negotiation without preparing the password, for the passwords
+        /// registered before `prepare_password`, see `needs_raw_password_fallback`.
+        pub fn start_login_with_raw_password<R: RngCore + CryptoRng>(
+            password: &str,
+            rng: &mut R,
         ) -> AuthenticationResult<ClientLoginStartResult> {
             Ok(ClientLogin::start(rng, password.as_bytes())?)
         }
@@ -199,3 +256,102 @@ pub mod server {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_password() {
+        assert_eq!(prepare_password("plain password"), "plain password");
+        // Composed and decomposed forms.
+        assert_eq!(prepare_password("caf\u{E9}"), "caf\u{E9}");
+        assert_eq!(prepare_password("cafe\u{301}"), "caf\u{E9}");
+        // Compatibility characters.
+        assert_eq!(prepare_password("\u{FB01}ve"), "five");
+        assert_eq!(prepare_password("\u{FF21}\u{FF22}"), "AB");
+        // Spaces and characters mapped to nothing.
+        assert_eq!(prepare_password("a\u{A0}b\u{3000}c"), "a b c");
+        assert_eq!(prepare_password("pass\u{AD}wo\u{200B}rd"), "password");
+    }
+
+    #[cfg(all(feature = "opaque_client", feature = "opaque_server"))]
+    fn register_and_login(registered_password: &str, login_password: &str) -> bool {
+        let mut rng = rand::rngs::OsRng;
+        login_with(
+            client::registration::start_registration(registered_password, &mut rng).unwrap(),
+            client::login::start_login(login_password, &mut rng).unwrap(),
+        )
+    }
+
+    #[cfg(all(feature = "opaque_client", feature = "opaque_server"))]
+    fn login_with(
+        registration_start: client::registration::ClientRegistrationStartResult,
+        login_start: client::login::ClientLoginStartResult,
+    ) -> bool {
+        let mut rng = rand::rngs::OsRng;
+        let server_setup = server::ServerSetup::new(&mut rng);
+        let registration_response = server::registration::start_registration(
+            &server_setup,
+            registration_start.message,
+            "bob",
+        )
+        .unwrap();
+        let registration_finish = client::registration::finish_registration(
+            registration_start.state,
+            registration_response.message,
+            &mut rng,
+        )
+        .unwrap();
+        let password_file = server::registration::get_password_file(registration_finish.message);
+
+        let login_response = server::login::start_login(
+            &mut rng,
+            &server_setup,
+            Some(password_file),
+            login_start.message,
+            "bob",
+        )
+        .unwrap();
+        match client::login::finish_login(login_start.state, login_response.message) {
+            Ok(login_finish) => {
+                server::login::finish_login(login_response.state, login_finish.message).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(all(feature = "opaque_client", feature = "opaque_server"))]
+    #[test]
+    fn test_login_with_equivalent_password() {
+        // Accented, set with a composed "é" and logged in with a combining accent.
+        assert!(register_and_login(
+            "r\u{E9}sum\u{E9}",
+            "re\u{301}sume\u{301}"
+        ));
+        // Combining characters in a different order (cedilla and acute accent).
+        assert!(register_and_login("c\u{327}\u{301}a", "c\u{301}\u{327}a"));
+        assert!(!register_and_login("r\u{E9}sum\u{E9}", "resume"));
+    }
+
+    #[cfg(all(feature = "opaque_client", feature = "opaque_server"))]
+    #[test]
+    fn test_login_with_raw_password_fallback() {
+        let mut rng = rand::rngs::OsRng;
+        let password = "cafe\u{301}";
+        assert!(needs_raw_password_fallback(password));
+        assert!(!needs_raw_password_fallback("plain password"));
+        // Registered before the passwords were prepared.
+        let legacy_registration = |rng: &mut rand::rngs::OsRng| {
+            client::registration::ClientRegistration::start(rng, password.as_bytes()).unwrap()
+        };
+        assert!(!login_with(
+            legacy_registration(&mut rng),
+            client::login::start_login(password, &mut rng).unwrap()
+        ));
+        assert!(login_with(
+            legacy_registration(&mut rng),
+            client::login::start_login_with_raw_password(password, &mut rng).unwrap()
+        ));
+    }
+}
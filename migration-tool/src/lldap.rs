@@ -138,12 +138,32 @@ fn try_login(
     username: &str,
     password: &str,
     client: &Client,
+) -> Result<String> {
+    let result = try_opaque_login(lldap_server, username, password, false, client);
+    if result.is_err() && lldap_auth::opaque::needs_raw_password_fallback(password) {
+        // The password may have been registered before the passwords were prepared.
+        return try_opaque_login(lldap_server, username, password, true, client);
+    }
+    result
+}
+
+/// Logs in with the raw password instead of the prepared one if `raw_password` is set.
+fn try_opaque_login(
+    lldap_server: &str,
+    username: &str,
+    password: &str,
+    raw_password: bool,
+    client: &Client,
 ) -> Result<String> {
     let mut rng = rand::rngs::OsRng;
     use lldap_auth::login::*;
     use lldap_auth::opaque::client::login::*;
-    let ClientLoginStartResult { state, message } =
-        start_login(password, &mut rng).context("Could not initialize login")?;
+    let ClientLoginStartResult { state, message } = if raw_password {
+        start_login_with_raw_password(password, &mut rng)
+    } else {
+        start_login(password, &mut rng)
+    }
+    .context("Could not initialize login")?;
     let req = ClientLoginStartRequest {
         username: username.to_owned(),
         login_start_request: message,
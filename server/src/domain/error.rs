@@ -17,6 +17,60 @@ pub enum DomainError {
     Base64DecodeError(#[from] base64::DecodeError),
     #[error("Internal error: `{0}`")]
     InternalError(String),
+    /// Invalid value for a field: the field, and the reason.
+    #[error("Invalid {0}: {1}")]
+    ValidationError(String, String),
+    /// Another entry already has the same value for a unique attribute.
+    #[error("Constraint violation: another user has the same {0}")]
+    ConstraintViolation(String),
+    #[error("Timeout: the {0} didn't finish in time")]
+    Timeout(TimeoutKind),
+    /// The user doesn't exist, or doesn't have a password. Displayed like a wrong password, not
+    /// to disclose which users exist.
+    #[error("Authentication error: ` for user '{0}'`")]
+    CredentialsNotFound(String),
+    /// The password was correct, but expired and without any grace login left.
+    #[error("Authentication error: the password expired")]
+    PasswordExpired,
+}
+
+/// What didn't finish in time, see `DomainError::Timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// A database query, see `database_query_timeout_ms`.
+    Query,
+    /// A backend operation, see `TimeoutBackendHandler`.
+    Operation,
+}
+
+impl std::fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            TimeoutKind::Query => "database query",
+            TimeoutKind::Operation => "backend operation",
+        })
+    }
+}
+
+impl DomainError {
+    pub fn query_timeout() -> Self {
+        DomainError::Timeout(TimeoutKind::Query)
+    }
+
+    /// Whether the error comes from a database query that didn't finish in time.
+    pub fn is_query_timeout(&self) -> bool {
+        matches!(self, DomainError::Timeout(TimeoutKind::Query))
+    }
+
+    pub fn operation_timeout() -> Self {
+        DomainError::Timeout(TimeoutKind::Operation)
+    }
+
+    /// Whether the error comes from a backend operation that didn't finish in time, see
+    /// `TimeoutBackendHandler`.
+    pub fn is_operation_timeout(&self) -> bool {
+        matches!(self, DomainError::Timeout(TimeoutKind::Operation))
+    }
 }
 
 pub type Result<T> = std::result::Result<T, DomainError>;
@@ -1,9 +1,10 @@
 use super::error::*;
 use async_trait::async_trait;
+use futures_util::future::BoxFuture;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default, Serialize, Deserialize)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(sqlx::FromRow))]
 #[serde(from = "String")]
 pub struct UserId(String);
@@ -34,7 +35,7 @@ impl From<String> for UserId {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(sqlx::FromRow))]
 pub struct User {
     pub user_id: UserId,
@@ -44,6 +45,27 @@ pub struct User {
     pub last_name: String,
     // pub avatar: ?,
     pub creation_date: chrono::DateTime<chrono::Utc>,
+    /// Additional addresses delivered to this user's mailbox.
+    #[cfg_attr(not(target_arch = "wasm32"), sqlx(default))]
+    pub mail_aliases: Vec<String>,
+    /// External addresses the user's mail is forwarded to.
+    #[cfg_attr(not(target_arch = "wasm32"), sqlx(default))]
+    pub mail_forwarding: Vec<String>,
+    /// The phone numbers of the user, in E.164 format when `phone_validation` is enabled.
+    #[cfg_attr(not(target_arch = "wasm32"), sqlx(default))]
+    pub phone_numbers: Vec<String>,
+    /// Overrides `search_result_limit_for_non_admin` for this user's LDAP searches.
+    #[cfg_attr(not(target_arch = "wasm32"), sqlx(default))]
+    pub max_search_results: Option<u32>,
+    /// When the password was last changed, if known.
+    #[cfg_attr(not(target_arch = "wasm32"), sqlx(default))]
+    pub password_modified_date: Option<chrono::DateTime<chrono::Utc>>,
+    /// The user this user reports to, exposed as the LDAP `manager` attribute.
+    #[cfg_attr(not(target_arch = "wasm32"), sqlx(default))]
+    pub manager_user_id: Option<UserId>,
+    /// The last successful LDAP bind of the user, if any.
+    #[cfg_attr(not(target_arch = "wasm32"), sqlx(default))]
+    pub last_login_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl Default for User {
@@ -56,6 +78,13 @@ impl Default for User {
             first_name: String::new(),
             last_name: String::new(),
             creation_date: chrono::Utc.timestamp(0, 0),
+            mail_aliases: Vec::new(),
+            mail_forwarding: Vec::new(),
+            phone_numbers: Vec::new(),
+            max_search_results: None,
+            password_modified_date: None,
+            manager_user_id: None,
+            last_login_at: None,
         }
     }
 }
@@ -64,6 +93,8 @@ impl Default for User {
 pub struct Group {
     pub id: GroupId,
     pub display_name: String,
+    /// The name shown to the users, if different from the display name (the cn).
+    pub description: Option<String>,
     pub users: Vec<UserId>,
 }
 
@@ -73,17 +104,72 @@ pub struct BindRequest {
     pub password: String,
 }
 
+/// A substring assertion, like the LDAP filter `(cn=ab*cd*ef)`: the value starts with `initial`,
+/// contains the `any` parts in order, and ends with `final_`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SubStringFilter {
+    pub initial: Option<String>,
+    pub any: Vec<String>,
+    pub final_: Option<String>,
+}
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub enum UserRequestFilter {
     And(Vec<UserRequestFilter>),
     Or(Vec<UserRequestFilter>),
     Not(Box<UserRequestFilter>),
     UserId(UserId),
+    // Any of the given user ids, as a single `IN` clause.
+    UserIdIn(Vec<UserId>),
     Equality(String, String),
+    // Case-insensitive substring match on a field.
+    SubString(String, SubStringFilter),
+    // Check that the field is set and not empty.
+    Present(String),
     // Check if a user belongs to a group identified by name.
     MemberOf(String),
     // Same, by id.
     MemberOfId(GroupId),
+    // Check if a user belongs to at least one group.
+    MemberOfAnyGroup,
+    // Check if the user has the given mail alias.
+    MailAlias(String),
+    // Check if the user reports directly to the given manager.
+    Manager(UserId),
+    // Check if the user was created strictly before/after the given date.
+    CreatedBefore(chrono::DateTime<chrono::Utc>),
+    CreatedAfter(chrono::DateTime<chrono::Utc>),
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum UserSortField {
+    UserId,
+    Email,
+    DisplayName,
+    CreationDate,
+}
+
+/// The order of the users returned by `list_users_sorted`. The ties are sorted by user ID.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct UserSort {
+    pub field: UserSortField,
+    pub descending: bool,
+}
+
+impl Default for UserSort {
+    fn default() -> Self {
+        UserSort {
+            field: UserSortField::UserId,
+            descending: false,
+        }
+    }
+}
+
+/// A page of results: skips the first `offset` entries, and returns at most `limit` entries.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Page {
+    pub offset: u64,
+    pub limit: u64,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
@@ -105,6 +191,9 @@ pub struct CreateUserRequest {
     pub display_name: Option<String>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
+    pub mail_aliases: Vec<String>,
+    pub mail_forwarding: Vec<String>,
+    pub phone_numbers: Vec<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
@@ -115,17 +204,41 @@ pub struct UpdateUserRequest {
     pub display_name: Option<String>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
+    /// Replaces all the aliases of the user.
+    pub mail_aliases: Option<Vec<String>>,
+    /// Replaces all the forwarding addresses of the user.
+    pub mail_forwarding: Option<Vec<String>>,
+    /// Replaces all the phone numbers of the user.
+    pub phone_numbers: Option<Vec<String>>,
+    /// Sets the LDAP search limit of the user, or removes it with `Some(None)`.
+    pub max_search_results: Option<Option<u32>>,
+    /// Sets the manager of the user, or removes it with `Some(None)`.
+    pub manager_user_id: Option<Option<UserId>>,
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct UpdateGroupRequest {
     pub group_id: GroupId,
     pub display_name: Option<String>,
+    /// Sets the name shown to the users, or removes it with `Some(None)`.
+    pub description: Option<Option<String>>,
+    /// Makes the group dynamic: its members are the users matching the LDAP filter, e.g.
+    /// `(sn=Smith)`, instead of the ones added to it. `Some(None)` makes it a regular group again,
+    /// without members.
+    pub dynamic_filter: Option<Option<String>>,
 }
 
 #[async_trait]
 pub trait LoginHandler: Clone + Send {
     async fn bind(&self, request: BindRequest) -> Result<()>;
+    /// The number of binds left before the user is locked out because of an expired password,
+    /// or -1 if the password hasn't expired.
+    async fn get_grace_logins_remaining(&self, user_id: &UserId) -> Result<i32>;
+    /// Whether the user has a password set in lldap, possibly a legacy one.
+    async fn has_password(&self, user_id: &UserId) -> Result<bool>;
+    /// Whether the password is the current OPAQUE password of the user. Unlike `bind`, this has
+    /// no side effects: the expiry isn't checked and the legacy hashes aren't migrated.
+    async fn check_password(&self, user_id: &UserId, password: &str) -> Result<bool>;
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -134,21 +247,146 @@ pub struct GroupId(pub i32);
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::FromRow)]
 pub struct GroupIdAndName(pub GroupId, pub String);
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct InvitationId(pub i32);
+
+/// An invitation for a new user to create their account, sent by email.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invitation {
+    pub id: InvitationId,
+    pub email: String,
+    pub token: String,
+    pub expiry_date: chrono::DateTime<chrono::Utc>,
+    pub used: bool,
+}
+
+impl Invitation {
+    pub fn is_pending(&self) -> bool {
+        !self.used && self.expiry_date > chrono::Utc::now()
+    }
+}
+
+/// The logins of a user over the window of a login report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UserLoginStats {
+    pub user_id: UserId,
+    pub login_count: i64,
+    /// The last login, even if it's before the window.
+    pub last_login_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 #[async_trait]
 pub trait BackendHandler: Clone + Send {
     async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+    /// Same as `list_users`, in the given order and restricted to the page if any.
+    async fn list_users_sorted(
+        &self,
+        filters: Option<UserRequestFilter>,
+        sort: UserSort,
+        page: Option<Page>,
+    ) -> Result<Vec<User>>;
     async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+    /// The number of users matching the filter, without fetching them.
+    async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<u64>;
+    async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<u64>;
     async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
+    /// The users whose manager is `manager_id`.
+    async fn get_reports(&self, manager_id: &UserId) -> Result<Vec<User>>;
+    /// Get the user that owns the given mail alias, if any.
+    async fn find_user_by_email_alias(&self, alias: &str) -> Result<Option<User>>;
+    /// Get the user authenticated by a client certificate with this common name, if any: the
+    /// common name is the user ID.
+    async fn find_user_by_certificate_cn(&self, cn: &str) -> Result<Option<User>>;
     async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
     async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
     async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
     async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
     async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+    /// Changes the ID of the user, keeping their groups, mail addresses and sessions.
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
+    /// Adds a phone number to the user, unless they already have it.
+    async fn add_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+    /// Removes a phone number from the user, if they have it.
+    async fn remove_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+    /// Replaces all the phone numbers of the user.
+    async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()>;
+    /// Records a successful login of the user. The write can be buffered until the next
+    /// `flush_last_logins`.
+    async fn update_last_login(
+        &self,
+        user_id: &UserId,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()>;
+    /// Writes the buffered logins to the database. Returns the number of users updated.
+    async fn flush_last_logins(&self) -> Result<usize>;
+    /// The number of logins of every user since the given day, most active users first. The
+    /// users without logins are included, last.
+    async fn get_login_report(
+        &self,
+        since: chrono::NaiveDate,
+        page: Page,
+    ) -> Result<Vec<UserLoginStats>>;
     async fn create_group(&self, group_name: &str) -> Result<GroupId>;
     async fn delete_group(&self, group_id: GroupId) -> Result<()>;
     async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
     async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>>;
+    /// Same as `get_user_groups` for several users, in a single query.
+    async fn get_groups_for_users(
+        &self,
+        user_ids: &[UserId],
+    ) -> Result<HashMap<UserId, HashSet<GroupIdAndName>>>;
+    /// Get the groups the user is a member of, directly or through nested groups.
+    async fn get_groups_containing_user_recursive(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashSet<GroupIdAndName>>;
+    /// Drops the cached groups of all the users, see `membership_cache_ttl_secs`.
+    fn clear_membership_cache(&self);
+    /// Changes after every write to the users and groups, to invalidate the caches built on top
+    /// of them.
+    fn data_version(&self) -> u64;
+    /// Create an invitation for the given email, valid for 48 hours.
+    async fn create_invitation(&self, email: &str) -> Result<Invitation>;
+    /// Get the invitation for a token, even if it's expired or used.
+    async fn get_invitation(&self, token: &str) -> Result<Invitation>;
+    /// Marks the invitation as used, unless it was already used or expired: returns whether it
+    /// was marked, only once even for concurrent requests.
+    async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool>;
+    /// List the invitations that are neither used nor expired.
+    async fn list_pending_invitations(&self) -> Result<Vec<Invitation>>;
+}
+
+/// The operations available in `TransactionHandler::transaction`. They see the changes made
+/// earlier in the transaction, the other handlers only see them once it's committed.
+#[async_trait]
+pub trait BackendTransaction: Send + Sync {
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>>;
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
+    async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
+    async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()>;
+    async fn create_group(&self, group_name: &str) -> Result<GroupId>;
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
+    async fn delete_group(&self, group_id: GroupId) -> Result<()>;
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+    async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool>;
+}
+
+/// The backends that can run several operations atomically.
+#[async_trait]
+pub trait TransactionHandler: BackendHandler {
+    type Transaction: BackendTransaction;
+    /// Runs the operations of `f` in a single transaction, committed if `f` returns Ok and rolled
+    /// back otherwise.
+    async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send,
+        F: for<'t> FnOnce(&'t Self::Transaction) -> BoxFuture<'t, Result<T>> + Send;
 }
 
 #[cfg(test)]
@@ -160,21 +398,110 @@ mockall::mock! {
     #[async_trait]
     impl BackendHandler for TestBackendHandler {
         async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+        async fn list_users_sorted(&self, filters: Option<UserRequestFilter>, sort: UserSort, page: Option<Page>) -> Result<Vec<User>>;
         async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+        async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<u64>;
+        async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<u64>;
         async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
+        async fn get_reports(&self, manager_id: &UserId) -> Result<Vec<User>>;
+        async fn find_user_by_email_alias(&self, alias: &str) -> Result<Option<User>>;
+        async fn find_user_by_certificate_cn(&self, cn: &str) -> Result<Option<User>>;
         async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
         async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+        async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
+        async fn add_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+        async fn remove_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+        async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()>;
+        async fn update_last_login(&self, user_id: &UserId, timestamp: chrono::DateTime<chrono::Utc>) -> Result<()>;
+        async fn flush_last_logins(&self) -> Result<usize>;
+        async fn get_login_report(&self, since: chrono::NaiveDate, page: Page) -> Result<Vec<UserLoginStats>>;
         async fn create_group(&self, group_name: &str) -> Result<GroupId>;
         async fn delete_group(&self, group_id: GroupId) -> Result<()>;
         async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>>;
+        async fn get_groups_for_users(
+            &self,
+            user_ids: &[UserId],
+        ) -> Result<HashMap<UserId, HashSet<GroupIdAndName>>>;
+        async fn get_groups_containing_user_recursive(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>>;
+        fn clear_membership_cache(&self);
+        fn data_version(&self) -> u64;
         async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
         async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+        async fn create_invitation(&self, email: &str) -> Result<Invitation>;
+        async fn get_invitation(&self, token: &str) -> Result<Invitation>;
+        async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool>;
+        async fn list_pending_invitations(&self) -> Result<Vec<Invitation>>;
     }
     #[async_trait]
     impl LoginHandler for TestBackendHandler {
         async fn bind(&self, request: BindRequest) -> Result<()>;
+        async fn get_grace_logins_remaining(&self, user_id: &UserId) -> Result<i32>;
+        async fn has_password(&self, user_id: &UserId) -> Result<bool>;
+        async fn check_password(&self, user_id: &UserId, password: &str) -> Result<bool>;
+    }
+}
+
+/// The mock has no transactions: the operations of `f` run on the mock itself, against its
+/// expectations.
+#[cfg(test)]
+#[async_trait]
+impl TransactionHandler for MockTestBackendHandler {
+    type Transaction = Self;
+    async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send,
+        F: for<'t> FnOnce(&'t Self::Transaction) -> BoxFuture<'t, Result<T>> + Send,
+    {
+        f(self).await
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl BackendTransaction for MockTestBackendHandler {
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+        BackendHandler::get_user_details(self, user_id).await
+    }
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        BackendHandler::get_group_details(self, group_id).await
+    }
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        BackendHandler::get_user_groups(self, user_id).await
+    }
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        BackendHandler::create_user(self, request).await
+    }
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        BackendHandler::update_user(self, request).await
+    }
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        BackendHandler::delete_user(self, user_id).await
+    }
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        BackendHandler::rename_user(self, user_id, new_user_id).await
+    }
+    async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()> {
+        BackendHandler::set_phone_numbers(self, user_id, phone_numbers).await
+    }
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        BackendHandler::create_group(self, group_name).await
+    }
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        BackendHandler::update_group(self, request).await
+    }
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        BackendHandler::delete_group(self, group_id).await
+    }
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        BackendHandler::add_user_to_group(self, user_id, group_id).await
+    }
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        BackendHandler::remove_user_from_group(self, user_id, group_id).await
+    }
+    async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool> {
+        BackendHandler::mark_invitation_used(self, invitation_id).await
     }
 }
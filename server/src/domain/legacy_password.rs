@@ -0,0 +1,121 @@
+use super::error::*;
+use sha1::{Digest, Sha1};
+
+/// Verifies passwords against a hash imported from another system, in the `{SCHEME}hash` format
+/// used by the `userPassword` LDAP attribute.
+pub trait LegacyPasswordVerifier: Send + Sync {
+    /// The scheme prefix, including the braces, e.g. `{BCRYPT}`.
+    fn scheme(&self) -> &'static str;
+    /// Checks the password against the hash, stripped of its scheme prefix.
+    fn verify(&self, hash: &str, clear_password: &str) -> Result<bool>;
+}
+
+/// Bcrypt hashes, like `{BCRYPT}$2y$10$...`.
+pub struct BcryptVerifier;
+
+impl LegacyPasswordVerifier for BcryptVerifier {
+    fn scheme(&self) -> &'static str {
+        "{BCRYPT}"
+    }
+
+    fn verify(&self, hash: &str, clear_password: &str) -> Result<bool> {
+        bcrypt::verify(clear_password, hash)
+            .map_err(|e| DomainError::InternalError(format!("Invalid bcrypt hash: {}", e)))
+    }
+}
+
+/// Salted SHA-1 hashes, as generated by OpenLDAP: `{SSHA}base64(sha1(password + salt) + salt)`.
+pub struct SshaVerifier;
+
+const SHA1_DIGEST_LENGTH: usize = 20;
+
+impl LegacyPasswordVerifier for SshaVerifier {
+    fn scheme(&self) -> &'static str {
+        "{SSHA}"
+    }
+
+    fn verify(&self, hash: &str, clear_password: &str) -> Result<bool> {
+        let decoded = base64::decode(hash)?;
+        if decoded.len() <= SHA1_DIGEST_LENGTH {
+            return Err(DomainError::InternalError(
+                "Invalid SSHA hash: missing salt".to_string(),
+            ));
+        }
+        let (digest, salt) = decoded.split_at(SHA1_DIGEST_LENGTH);
+        let mut hasher = Sha1::new();
+        hasher.update(clear_password.as_bytes());
+        hasher.update(salt);
+        Ok(hasher.finalize().as_slice() == digest)
+    }
+}
+
+/// Dispatches a legacy hash to the verifier for its scheme.
+pub struct CompatPasswordVerifier {
+    verifiers: Vec<Box<dyn LegacyPasswordVerifier>>,
+}
+
+impl Default for CompatPasswordVerifier {
+    fn default() -> Self {
+        Self {
+            verifiers: vec![Box::new(BcryptVerifier), Box::new(SshaVerifier)],
+        }
+    }
+}
+
+impl CompatPasswordVerifier {
+    pub fn verify(&self, stored_hash: &str, clear_password: &str) -> Result<bool> {
+        for verifier in &self.verifiers {
+            let scheme = verifier.scheme();
+            if stored_hash.len() >= scheme.len()
+                && stored_hash.is_char_boundary(scheme.len())
+                && stored_hash[..scheme.len()].eq_ignore_ascii_case(scheme)
+            {
+                return verifier.verify(&stored_hash[scheme.len()..], clear_password);
+            }
+        }
+        Err(DomainError::InternalError(
+            "Unsupported legacy password hash scheme".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_ssha(password: &str, salt: &[u8]) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(password.as_bytes());
+        hasher.update(salt);
+        let mut bytes = hasher.finalize().to_vec();
+        bytes.extend_from_slice(salt);
+        format!("{{SSHA}}{}", base64::encode(bytes))
+    }
+
+    #[test]
+    fn test_bcrypt() {
+        let hash = format!("{{BCRYPT}}{}", bcrypt::hash("bob00", 4).unwrap());
+        let verifier = CompatPasswordVerifier::default();
+        assert!(verifier.verify(&hash, "bob00").unwrap());
+        assert!(!verifier.verify(&hash, "wrong_password").unwrap());
+    }
+
+    #[test]
+    fn test_ssha() {
+        let hash = make_ssha("bob00", b"saltsalt");
+        let verifier = CompatPasswordVerifier::default();
+        assert!(verifier.verify(&hash, "bob00").unwrap());
+        assert!(!verifier.verify(&hash, "wrong_password").unwrap());
+        assert!(verifier
+            .verify(&hash.replace("{SSHA}", "{ssha}"), "bob00")
+            .unwrap());
+    }
+
+    #[test]
+    fn test_invalid_hashes() {
+        let verifier = CompatPasswordVerifier::default();
+        verifier.verify("{MD5}abcd", "bob00").unwrap_err();
+        verifier.verify("{SSHA}Ym9i", "bob00").unwrap_err();
+        verifier.verify("{BCRYPT}bob", "bob00").unwrap_err();
+    }
+}
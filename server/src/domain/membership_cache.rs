@@ -0,0 +1,196 @@
+use super::handler::{GroupIdAndName, UserId};
+use crate::infra::metrics::{MEMBERSHIP_CACHE_HITS, MEMBERSHIP_CACHE_MISSES};
+use lru::LruCache;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct CachedGroups {
+    inserted_at: Instant,
+    groups: HashSet<GroupIdAndName>,
+}
+
+struct Inner {
+    entries: LruCache<UserId, CachedGroups>,
+    /// Incremented on every invalidation, to drop the results of the reads that started before.
+    generation: u64,
+}
+
+/// Per-process cache of the groups of each user. Entries expire after the TTL, and are
+/// invalidated by the writes that can change the memberships. Clones share the same cache.
+#[derive(Clone, Default)]
+pub struct MembershipCache {
+    inner: Option<Arc<Mutex<Inner>>>,
+    ttl: Duration,
+}
+
+impl MembershipCache {
+    /// Creates a cache holding the groups of up to `size` users. A size or TTL of 0 disables the
+    /// cache.
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        if size == 0 || ttl.is_zero() {
+            return Self::default();
+        }
+        Self {
+            inner: Some(Arc::new(Mutex::new(Inner {
+                entries: LruCache::new(size),
+                generation: 0,
+            }))),
+            ttl,
+        }
+    }
+
+    /// To call before reading the memberships from the database, and pass to `insert`.
+    pub fn generation(&self) -> u64 {
+        self.inner
+            .as_ref()
+            .map(|inner| inner.lock().unwrap().generation)
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, user_id: &UserId) -> Option<HashSet<GroupIdAndName>> {
+        let mut inner = self.inner.as_ref()?.lock().unwrap();
+        let groups = match inner.entries.get(user_id) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => Some(entry.groups.clone()),
+            Some(_) => {
+                inner.entries.pop(user_id);
+                None
+            }
+            None => None,
+        };
+        if groups.is_some() {
+            MEMBERSHIP_CACHE_HITS.inc();
+        } else {
+            MEMBERSHIP_CACHE_MISSES.inc();
+        }
+        groups
+    }
+
+    /// Stores the groups read from the database, unless the cache was invalidated since
+    /// `generation`: the read may have missed the change.
+    pub fn insert(&self, generation: u64, user_id: UserId, groups: HashSet<GroupIdAndName>) {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().unwrap();
+            if inner.generation != generation {
+                return;
+            }
+            inner.entries.put(
+                user_id,
+                CachedGroups {
+                    inserted_at: Instant::now(),
+                    groups,
+                },
+            );
+        }
+    }
+
+    pub fn invalidate_user(&self, user_id: &UserId) {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().unwrap();
+            inner.generation += 1;
+            inner.entries.pop(user_id);
+        }
+    }
+
+    /// To hold for the duration of a write that can change the groups of the user (of all the
+    /// users for None): the groups are invalidated once the write is done, even if it failed
+    /// halfway or returned early.
+    pub fn invalidate_on_drop(&self, user_id: Option<&UserId>) -> InvalidationGuard {
+        InvalidationGuard {
+            cache: self.clone(),
+            user_id: user_id.cloned(),
+        }
+    }
+
+    /// Removes all the entries, e.g. after a change to a group.
+    pub fn clear(&self) {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.lock().unwrap();
+            inner.generation += 1;
+            inner.entries.clear();
+        }
+    }
+}
+
+/// Invalidates the cached groups when dropped, see `MembershipCache::invalidate_on_drop`.
+#[must_use]
+pub struct InvalidationGuard {
+    cache: MembershipCache,
+    user_id: Option<UserId>,
+}
+
+impl Drop for InvalidationGuard {
+    fn drop(&mut self) {
+        match &self.user_id {
+            Some(user_id) => self.cache.invalidate_user(user_id),
+            None => self.cache.clear(),
+        }
+    }
+}
+
+impl std::fmt::Debug for MembershipCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MembershipCache")
+            .field("enabled", &self.inner.is_some())
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::handler::GroupId;
+
+    fn make_groups(name: &str) -> HashSet<GroupIdAndName> {
+        let mut groups = HashSet::new();
+        groups.insert(GroupIdAndName(GroupId(2), name.to_string()));
+        groups
+    }
+
+    #[test]
+    fn test_invalidate_user() {
+        let cache = MembershipCache::new(10, Duration::from_secs(60));
+        let bob = UserId::new("bob");
+        let jim = UserId::new("jim");
+        cache.insert(cache.generation(), bob.clone(), make_groups("bob_group"));
+        cache.insert(cache.generation(), jim.clone(), make_groups("jim_group"));
+        cache.invalidate_user(&bob);
+        assert_eq!(cache.get(&bob), None);
+        assert_eq!(cache.get(&jim), Some(make_groups("jim_group")));
+        cache.clear();
+        assert_eq!(cache.get(&jim), None);
+    }
+
+    #[test]
+    fn test_stale_insert() {
+        let cache = MembershipCache::new(10, Duration::from_secs(60));
+        let bob = UserId::new("bob");
+        let generation = cache.generation();
+        cache.invalidate_user(&bob);
+        cache.insert(generation, bob.clone(), make_groups("old_group"));
+        assert_eq!(cache.get(&bob), None);
+    }
+
+    #[test]
+    fn test_invalidate_on_drop() {
+        let cache = MembershipCache::new(10, Duration::from_secs(60));
+        let bob = UserId::new("bob");
+        {
+            let _invalidation = cache.invalidate_on_drop(Some(&bob));
+            cache.insert(cache.generation(), bob.clone(), make_groups("bob_group"));
+            assert_eq!(cache.get(&bob), Some(make_groups("bob_group")));
+        }
+        assert_eq!(cache.get(&bob), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = MembershipCache::new(10, Duration::ZERO);
+        let bob = UserId::new("bob");
+        cache.insert(cache.generation(), bob.clone(), make_groups("bob_group"));
+        assert_eq!(cache.get(&bob), None);
+    }
+}
@@ -1,6 +1,10 @@
 pub mod error;
 pub mod handler;
+pub mod legacy_password;
+pub mod membership_cache;
 pub mod opaque_handler;
 pub mod sql_backend_handler;
+pub mod sql_index_analysis;
 pub mod sql_opaque_handler;
 pub mod sql_tables;
+pub mod timeout_backend_handler;
@@ -14,10 +14,11 @@ pub trait OpaqueHandler: Clone + Send {
         &self,
         request: registration::ClientRegistrationStartRequest,
     ) -> Result<registration::ServerRegistrationStartResponse>;
+    /// Returns the user whose password was set.
     async fn registration_finish(
         &self,
         request: registration::ClientRegistrationFinishRequest,
-    ) -> Result<()>;
+    ) -> Result<UserId>;
 }
 
 #[cfg(test)]
@@ -40,6 +41,6 @@ mockall::mock! {
         async fn registration_finish(
             &self,
             request: registration::ClientRegistrationFinishRequest
-        ) -> Result<()>;
+        ) -> Result<UserId>;
     }
 }
@@ -1,755 +1,4646 @@
-use super::{error::*, handler::*, sql_tables::*};
-use crate::infra::configuration::Configuration;
+use super::{
+    error::*,
+    handler::*,
+    membership_cache::{InvalidationGuard, MembershipCache},
+    opaque_handler::OpaqueHandler,
+    sql_tables::*,
+};
+use crate::infra::{
+    configuration::Configuration, jwt_sql_tables::JwtRefreshStorage, ldap_filter::parse_user_filter,
+};
 use async_trait::async_trait;
-use futures_util::StreamExt;
-use sea_query::{Expr, Iden, Order, Query, SimpleExpr};
+use futures_util::{future::BoxFuture, TryStreamExt};
+use lldap_auth::{login, registration};
+use log::*;
+use sea_query::{Alias, Expr, Iden, Order, Query, SelectStatement, SimpleExpr, Value};
 use sqlx::Row;
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::Semaphore;
 
 #[derive(Debug, Clone)]
 pub struct SqlBackendHandler {
     pub(crate) config: Configuration,
     pub(crate) sql_pool: Pool,
+    /// Limits the number of password hashing operations running at the same time.
+    password_hashing_slots: Arc<Semaphore>,
+    /// The logins not written to the database yet, see `last_login_flush_interval_secs`.
+    pending_last_logins: Arc<Mutex<HashMap<UserId, PendingLogins>>>,
+    membership_cache: MembershipCache,
+    /// Incremented after every write to the users and groups, see `BackendHandler::data_version`.
+    data_version: Arc<AtomicU64>,
+    /// Set for the handler of a `Txn`: all the queries run in this transaction.
+    transaction: Option<SharedTransaction>,
 }
 
-impl SqlBackendHandler {
-    pub fn new(config: Configuration, sql_pool: Pool) -> Self {
-        SqlBackendHandler { config, sql_pool }
+/// The buffered logins of a user.
+#[derive(Debug, Clone, Copy)]
+struct PendingLogins {
+    last: chrono::DateTime<chrono::Utc>,
+    /// Counted on the day of the last one.
+    count: i64,
+}
+
+#[derive(Clone)]
+struct SharedTransaction(Arc<tokio::sync::Mutex<sqlx::Transaction<'static, sqlx::Sqlite>>>);
+
+impl std::fmt::Debug for SharedTransaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("SharedTransaction")
     }
 }
 
-struct RequiresGroup(bool);
+/// The connection a query runs on, see `SqlBackendHandler::connection`.
+pub(super) enum DbConnection<'a> {
+    Pooled(sqlx::pool::PoolConnection<sqlx::Sqlite>),
+    Transaction(tokio::sync::MutexGuard<'a, sqlx::Transaction<'static, sqlx::Sqlite>>),
+}
 
-// Returns the condition for the SQL query, and whether it requires joining with the groups table.
-fn get_user_filter_expr(filter: UserRequestFilter) -> (RequiresGroup, SimpleExpr) {
-    use UserRequestFilter::*;
-    fn get_repeated_filter(
-        fs: Vec<UserRequestFilter>,
-        field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
-    ) -> (RequiresGroup, SimpleExpr) {
-        let mut requires_group = false;
-        let mut it = fs.into_iter();
-        let first_expr = match it.next() {
-            None => return (RequiresGroup(false), Expr::value(true)),
-            Some(f) => {
-                let (group, filter) = get_user_filter_expr(f);
-                requires_group |= group.0;
-                filter
-            }
-        };
-        let filter = it.fold(first_expr, |e, f| {
-            let (group, filters) = get_user_filter_expr(f);
-            requires_group |= group.0;
-            field(e, filters)
-        });
-        (RequiresGroup(requires_group), filter)
+impl std::ops::Deref for DbConnection<'_> {
+    type Target = sqlx::SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            DbConnection::Pooled(connection) => connection,
+            DbConnection::Transaction(transaction) => transaction,
+        }
     }
-    match filter {
-        And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
-        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
-        Not(f) => {
-            let (requires_group, filters) = get_user_filter_expr(*f);
-            (requires_group, Expr::not(Expr::expr(filters)))
+}
+
+impl std::ops::DerefMut for DbConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            DbConnection::Pooled(connection) => connection,
+            DbConnection::Transaction(transaction) => transaction,
         }
-        UserId(user_id) => (
-            RequiresGroup(false),
-            Expr::col((Users::Table, Users::UserId)).eq(user_id),
-        ),
-        Equality(s1, s2) => (
-            RequiresGroup(false),
-            if s1 == Users::DisplayName.to_string() {
-                Expr::col((Users::Table, Users::DisplayName)).eq(s2)
-            } else if s1 == Users::UserId.to_string() {
-                panic!("User id should be wrapped")
-            } else {
-                Expr::expr(Expr::cust(&s1)).eq(s2)
-            },
-        ),
-        MemberOf(group) => (
-            RequiresGroup(true),
-            Expr::col((Groups::Table, Groups::DisplayName)).eq(group),
-        ),
-        MemberOfId(group_id) => (
-            RequiresGroup(true),
-            Expr::col((Groups::Table, Groups::GroupId)).eq(group_id),
-        ),
     }
 }
 
-// Returns the condition for the SQL query, and whether it requires joining with the groups table.
-fn get_group_filter_expr(filter: GroupRequestFilter) -> SimpleExpr {
-    use GroupRequestFilter::*;
-    fn get_repeated_filter(
-        fs: Vec<GroupRequestFilter>,
-        field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
-    ) -> SimpleExpr {
-        let mut it = fs.into_iter();
-        let first_expr = match it.next() {
-            None => return Expr::value(true),
-            Some(f) => get_group_filter_expr(f),
-        };
-        it.fold(first_expr, |e, f| field(e, get_group_filter_expr(f)))
+/// Invalidates the caches of the users and groups when dropped, see
+/// `SqlBackendHandler::invalidate_on_drop`.
+#[must_use]
+pub(crate) struct WriteGuard {
+    _membership: InvalidationGuard,
+    data_version: Arc<AtomicU64>,
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        self.data_version.fetch_add(1, Ordering::AcqRel);
     }
-    match filter {
-        And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
-        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
-        Not(f) => Expr::not(Expr::expr(get_group_filter_expr(*f))),
-        DisplayName(name) => Expr::col((Groups::Table, Groups::DisplayName)).eq(name),
-        GroupId(id) => Expr::col((Groups::Table, Groups::GroupId)).eq(id.0),
-        // WHERE (group_id in (SELECT group_id FROM memberships WHERE user_id = user))
-        Member(user) => Expr::col((Memberships::Table, Memberships::GroupId)).in_subquery(
-            Query::select()
-                .column(Memberships::GroupId)
-                .from(Memberships::Table)
-                .and_where(Expr::col(Memberships::UserId).eq(user))
-                .take(),
-        ),
+}
+
+/// The operations of `TransactionHandler::transaction`, run on the connection of the transaction.
+#[derive(Clone)]
+pub struct Txn {
+    handler: SqlBackendHandler,
+}
+
+#[async_trait]
+impl BackendTransaction for Txn {
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+        self.handler.get_user_details(user_id).await
+    }
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.handler.get_group_details(group_id).await
+    }
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        self.handler.get_user_groups(user_id).await
+    }
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        self.handler.create_user(request).await
+    }
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        self.handler.update_user(request).await
+    }
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        self.handler.delete_user(user_id).await
+    }
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        self.handler.rename_user(user_id, new_user_id).await
+    }
+    async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()> {
+        self.handler.set_phone_numbers(user_id, phone_numbers).await
+    }
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.handler.create_group(group_name).await
+    }
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        self.handler.update_group(request).await
+    }
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        self.handler.delete_group(group_id).await
+    }
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.handler.add_user_to_group(user_id, group_id).await
+    }
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.handler.remove_user_from_group(user_id, group_id).await
+    }
+    async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool> {
+        self.handler.mark_invitation_used(invitation_id).await
     }
 }
 
+/// The password changes of the transaction, e.g. for a new user.
 #[async_trait]
-impl BackendHandler for SqlBackendHandler {
-    async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>> {
-        let query = {
-            let mut query_builder = Query::select()
-                .column((Users::Table, Users::UserId))
-                .column(Users::Email)
-                .column((Users::Table, Users::DisplayName))
-                .column(Users::FirstName)
-                .column(Users::LastName)
-                .column(Users::Avatar)
-                .column(Users::CreationDate)
-                .from(Users::Table)
-                .order_by((Users::Table, Users::UserId), Order::Asc)
-                .to_owned();
-            if let Some(filter) = filters {
-                if filter == UserRequestFilter::Not(Box::new(UserRequestFilter::And(Vec::new()))) {
-                    return Ok(Vec::new());
-                }
-                if filter != UserRequestFilter::And(Vec::new())
-                    && filter != UserRequestFilter::Or(Vec::new())
-                {
-                    let (RequiresGroup(requires_group), condition) = get_user_filter_expr(filter);
-                    query_builder.and_where(condition);
-                    if requires_group {
-                        query_builder
-                            .left_join(
-                                Memberships::Table,
-                                Expr::tbl(Users::Table, Users::UserId)
-                                    .equals(Memberships::Table, Memberships::UserId),
-                            )
-                            .left_join(
-                                Groups::Table,
-                                Expr::tbl(Memberships::Table, Memberships::GroupId)
-                                    .equals(Groups::Table, Groups::GroupId),
-                            );
-                    }
-                }
-            }
+impl OpaqueHandler for Txn {
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.handler.login_start(request).await
+    }
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId> {
+        self.handler.login_finish(request).await
+    }
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        self.handler.registration_start(request).await
+    }
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<UserId> {
+        self.handler.registration_finish(request).await
+    }
+}
 
-            query_builder.to_string(DbQueryBuilder {})
+impl SqlBackendHandler {
+    pub fn new(config: Configuration, sql_pool: Pool) -> Self {
+        let password_hashing_workers = match config.password_hashing_workers {
+            0 => std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1),
+            workers => workers,
         };
+        let membership_cache = MembershipCache::new(
+            config.membership_cache_size,
+            Duration::from_secs(config.membership_cache_ttl_secs),
+        );
+        SqlBackendHandler {
+            config,
+            sql_pool,
+            password_hashing_slots: Arc::new(Semaphore::new(password_hashing_workers)),
+            pending_last_logins: Arc::default(),
+            membership_cache,
+            data_version: Arc::default(),
+            transaction: None,
+        }
+    }
 
-        let results = sqlx::query_as::<_, User>(&query)
-            .fetch(&self.sql_pool)
-            .collect::<Vec<sqlx::Result<User>>>()
-            .await;
-
-        Ok(results.into_iter().collect::<sqlx::Result<Vec<User>>>()?)
+    /// To hold for the duration of a write to the users or groups: once it's done, even if it
+    /// failed halfway, the cached groups of the user (of all the users for None) are invalidated
+    /// and the data version is bumped.
+    pub(crate) fn invalidate_on_drop(&self, user_id: Option<&UserId>) -> WriteGuard {
+        WriteGuard {
+            _membership: self.membership_cache.invalidate_on_drop(user_id),
+            data_version: self.data_version.clone(),
+        }
     }
 
-    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
-        let query: String = {
-            let mut query_builder = Query::select()
-                .column((Groups::Table, Groups::GroupId))
-                .column(Groups::DisplayName)
-                .column(Memberships::UserId)
+    /// On the first startup, i.e. with neither users nor groups in the database, creates the
+    /// lldap_admin group with the configured `admin_group_id`. Returns whether it was created.
+    pub async fn init_admin_group(&self) -> Result<bool> {
+        let queries = [
+            Query::select()
+                .column(Users::UserId)
+                .from(Users::Table)
+                .limit(1)
+                .to_string(DbQueryBuilder {}),
+            Query::select()
+                .column(Groups::GroupId)
                 .from(Groups::Table)
-                .left_join(
-                    Memberships::Table,
-                    Expr::tbl(Groups::Table, Groups::GroupId)
-                        .equals(Memberships::Table, Memberships::GroupId),
+                .limit(1)
+                .to_string(DbQueryBuilder {}),
+        ];
+        for query in queries {
+            if self
+                .with_timeout(
+                    &query,
+                    sqlx::query(&query).fetch_optional(&mut *self.connection().await?),
                 )
-                .order_by(Groups::DisplayName, Order::Asc)
-                .order_by(Memberships::UserId, Order::Asc)
-                .to_owned();
-
-            if let Some(filter) = filters {
-                if filter == GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(Vec::new())))
-                {
-                    return Ok(Vec::new());
-                }
-                if filter != GroupRequestFilter::And(Vec::new())
-                    && filter != GroupRequestFilter::Or(Vec::new())
-                {
-                    query_builder.and_where(get_group_filter_expr(filter));
-                }
+                .await?
+                .is_some()
+            {
+                return Ok(false);
             }
+        }
+        let query = Query::insert()
+            .into_table(Groups::Table)
+            .columns(vec![Groups::GroupId, Groups::DisplayName])
+            .values_panic(vec![
+                self.config.admin_group_id.into(),
+                "lldap_admin".into(),
+            ])
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        Ok(true)
+    }
 
-            query_builder.to_string(DbQueryBuilder {})
+    /// The connection to run the next query on: the one of the transaction for a `Txn`, or one
+    /// from the pool. Don't hold it across another query, the transaction can only run one at a
+    /// time.
+    pub(super) async fn connection(&self) -> Result<DbConnection<'_>> {
+        Ok(match &self.transaction {
+            Some(transaction) => DbConnection::Transaction(transaction.0.lock().await),
+            None => DbConnection::Pooled(self.sql_pool.acquire().await?),
+        })
+    }
+
+    /// Runs CPU-heavy password hashing or verification on the blocking thread pool, so that it
+    /// doesn't stall the async runtime, with at most `password_hashing_workers` running at once.
+    pub(crate) async fn run_password_hashing<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .password_hashing_slots
+            .acquire()
+            .await
+            .map_err(|e| DomainError::InternalError(e.to_string()))?;
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| DomainError::InternalError(format!("Password hashing failed: {}", e)))
+    }
+
+    /// Checks the domain of the email against the configured `allowed_email_domains` and
+    /// `blocked_email_domains`.
+    fn check_email_domain(&self, email: &str) -> Result<()> {
+        // Users without an email, like the default admin, have no domain to check.
+        if email.is_empty() {
+            return Ok(());
+        }
+        let domain = email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let matches_any = |patterns: &Vec<String>| {
+            patterns
+                .iter()
+                .any(|pattern| email_domain_matches(&pattern.to_ascii_lowercase(), &domain))
         };
+        let allowed = self
+            .config
+            .allowed_email_domains
+            .as_ref()
+            .map_or(true, matches_any);
+        let blocked = self
+            .config
+            .blocked_email_domains
+            .as_ref()
+            .map_or(false, matches_any);
+        if allowed && !blocked {
+            Ok(())
+        } else {
+            Err(DomainError::ValidationError(
+                "email".to_string(),
+                "domain not allowed".to_string(),
+            ))
+        }
+    }
 
-        // For group_by.
-        use itertools::Itertools;
-        let mut groups = Vec::new();
-        // The rows are returned sorted by display_name, equivalent to group_id. We group them by
-        // this key which gives us one element (`rows`) per group.
-        for ((group_id, display_name), rows) in &sqlx::query(&query)
-            .fetch_all(&self.sql_pool)
-            .await?
-            .into_iter()
-            .group_by(|row| {
-                (
-                    GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())),
-                    row.get::<String, _>(&*Groups::DisplayName.to_string()),
+    /// Checks that no other user has the same value for one of the configured
+    /// `unique_ldap_attributes`. `values` maps the user fields to their new values. The emails are
+    /// left to the unique index of the table (see `email_constraint_violation`).
+    async fn check_unique_attributes(
+        &self,
+        user_id: &UserId,
+        values: &[(&str, Option<&str>)],
+    ) -> Result<()> {
+        for attribute in &self.config.unique_ldap_attributes {
+            let field = match get_unique_attribute_field(attribute) {
+                Some("email") | None => continue,
+                Some(field) => field,
+            };
+            let value = match values.iter().find(|(f, _)| *f == field) {
+                Some((_, Some(value))) if !value.is_empty() => *value,
+                _ => continue,
+            };
+            let query = Query::select()
+                .column(Users::UserId)
+                .from(Users::Table)
+                .and_where(Expr::col(get_user_column(field).unwrap()).eq(value))
+                .and_where(Expr::col(Users::UserId).ne(user_id))
+                .limit(1)
+                .to_string(DbQueryBuilder {});
+            if self
+                .with_timeout(
+                    &query,
+                    sqlx::query(&query).fetch_optional(&mut *self.connection().await?),
                 )
-            })
-        {
-            groups.push(Group {
-                id: group_id,
-                display_name,
-                users: rows
-                    .map(|row| row.get::<UserId, _>(&*Memberships::UserId.to_string()))
-                    // If a group has no users, an empty string is returned because of the left
-                    // join.
-                    .filter(|s| !s.as_str().is_empty())
-                    .collect(),
-            });
+                .await?
+                .is_some()
+            {
+                return Err(DomainError::ConstraintViolation(attribute.to_string()));
+            }
         }
-        Ok(groups)
+        Ok(())
     }
 
-    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+    /// Checks that the manager is an existing user, other than the user themselves.
+    async fn check_manager(&self, user_id: &UserId, manager_id: &UserId) -> Result<()> {
+        if manager_id == user_id {
+            return Err(DomainError::ValidationError(
+                "manager".to_string(),
+                "a user can't be their own manager".to_string(),
+            ));
+        }
         let query = Query::select()
             .column(Users::UserId)
-            .column(Users::Email)
-            .column(Users::DisplayName)
-            .column(Users::FirstName)
-            .column(Users::LastName)
-            .column(Users::Avatar)
-            .column(Users::CreationDate)
             .from(Users::Table)
-            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .and_where(Expr::col(Users::UserId).eq(manager_id))
             .to_string(DbQueryBuilder {});
+        if self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_optional(&mut *self.connection().await?),
+            )
+            .await?
+            .is_none()
+        {
+            return Err(DomainError::ValidationError(
+                "manager".to_string(),
+                format!("unknown user {}", manager_id),
+            ));
+        }
+        Ok(())
+    }
 
-        Ok(sqlx::query_as::<_, User>(&query)
-            .fetch_one(&self.sql_pool)
-            .await?)
+    /// The virtual group containing all the users, if configured.
+    fn all_users_group(&self) -> Option<GroupIdAndName> {
+        self.config
+            .all_users_group
+            .as_ref()
+            .map(|name| GroupIdAndName(ALL_USERS_GROUP_ID, name.clone()))
     }
 
-    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+    /// Replaces the membership conditions on the groups that aren't stored with their members,
+    /// the dynamic groups and the group of all users, with the conditions that define them.
+    async fn resolve_user_filter(
+        &self,
+        filters: Option<UserRequestFilter>,
+    ) -> Result<Option<UserRequestFilter>> {
+        let filter = match filters {
+            Some(filter) => filter,
+            None => return Ok(None),
+        };
+        let dynamic_groups = if refers_to_groups(&filter) {
+            self.list_dynamic_groups().await?
+        } else {
+            Vec::new()
+        };
+        Ok(Some(self.resolve_groups(filter, &dynamic_groups)))
+    }
+
+    fn resolve_groups(
+        &self,
+        filter: UserRequestFilter,
+        dynamic_groups: &[DynamicGroup],
+    ) -> UserRequestFilter {
+        let filter = self
+            .resolve_dynamic_groups(filter, dynamic_groups, &mut Vec::new())
+            .unwrap_or_else(|e| {
+                warn!("Invalid dynamic groups, the filter matches no user: {}", e);
+                UserRequestFilter::Not(Box::new(UserRequestFilter::And(Vec::new())))
+            });
+        match &self.config.all_users_group {
+            Some(group_name) => resolve_all_users_group(filter, group_name),
+            None => filter,
+        }
+    }
+
+    /// Replaces the membership conditions on the dynamic groups with the filters of the groups,
+    /// parsing the filters of the groups that are referenced. `path` is the chain of dynamic
+    /// groups being resolved, to detect the cycles.
+    fn resolve_dynamic_groups(
+        &self,
+        filter: UserRequestFilter,
+        groups: &[DynamicGroup],
+        path: &mut Vec<GroupId>,
+    ) -> std::result::Result<UserRequestFilter, String> {
+        use UserRequestFilter::*;
+        Ok(match filter {
+            And(filters) => And(self.resolve_dynamic_groups_in_all(filters, groups, path)?),
+            Or(filters) => Or(self.resolve_dynamic_groups_in_all(filters, groups, path)?),
+            Not(f) => Not(Box::new(self.resolve_dynamic_groups(*f, groups, path)?)),
+            MemberOf(name) => match groups.iter().find(|group| group.display_name == name) {
+                Some(group) => self.resolve_dynamic_group(group, groups, path)?,
+                None => MemberOf(name),
+            },
+            MemberOfId(group_id) => match groups.iter().find(|group| group.id == group_id) {
+                Some(group) => self.resolve_dynamic_group(group, groups, path)?,
+                None => MemberOfId(group_id),
+            },
+            MemberOfAnyGroup => {
+                let mut filters = vec![MemberOfAnyGroup];
+                // A group being resolved doesn't add any member to itself.
+                let other_groups: Vec<&DynamicGroup> = groups
+                    .iter()
+                    .filter(|group| !path.contains(&group.id))
+                    .collect();
+                for group in other_groups {
+                    filters.push(self.resolve_dynamic_group(group, groups, path)?);
+                }
+                Or(filters)
+            }
+            f => f,
+        })
+    }
+
+    fn resolve_dynamic_groups_in_all(
+        &self,
+        filters: Vec<UserRequestFilter>,
+        groups: &[DynamicGroup],
+        path: &mut Vec<GroupId>,
+    ) -> std::result::Result<Vec<UserRequestFilter>, String> {
+        filters
+            .into_iter()
+            .map(|f| self.resolve_dynamic_groups(f, groups, path))
+            .collect()
+    }
+
+    fn resolve_dynamic_group(
+        &self,
+        group: &DynamicGroup,
+        groups: &[DynamicGroup],
+        path: &mut Vec<GroupId>,
+    ) -> std::result::Result<UserRequestFilter, String> {
+        if path.contains(&group.id) {
+            return Err(format!(
+                "the dynamic group {} contains itself",
+                group.display_name
+            ));
+        }
+        if path.len() >= MAX_DYNAMIC_GROUP_DEPTH {
+            return Err(format!(
+                "the dynamic groups are nested more than {} levels deep",
+                MAX_DYNAMIC_GROUP_DEPTH
+            ));
+        }
+        let filter = self
+            .parse_dynamic_filter(&group.filter)
+            .unwrap_or_else(|e| {
+                warn!(
+                    r#"Invalid filter for the dynamic group "{}", it has no member: {}"#,
+                    group.display_name, e
+                );
+                UserRequestFilter::Not(Box::new(UserRequestFilter::And(Vec::new())))
+            });
+        path.push(group.id);
+        let filter = self.resolve_dynamic_groups(filter, groups, path);
+        path.pop();
+        filter
+    }
+
+    /// Parses the LDAP filter of a dynamic group, relative to the configured base DN.
+    fn parse_dynamic_filter(&self, filter: &str) -> std::result::Result<UserRequestFilter, String> {
+        parse_user_filter(
+            filter,
+            &self.config.ldap_base_dn,
+            self.config.ldap_ignore_dn_value_case,
+        )
+        .map_err(|e| format!("{:#}", e))
+    }
+
+    /// The groups whose members are the users matching a filter, sorted by name.
+    async fn list_dynamic_groups(&self) -> Result<Vec<DynamicGroup>> {
         let query = Query::select()
             .column(Groups::GroupId)
             .column(Groups::DisplayName)
+            .column(Groups::Description)
+            .column(Groups::DynamicFilter)
             .from(Groups::Table)
-            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .and_where(Expr::col(Groups::DynamicFilter).is_not_null())
+            .order_by(Groups::DisplayName, Order::Asc)
             .to_string(DbQueryBuilder {});
-
-        Ok(sqlx::query_as::<_, GroupIdAndName>(&query)
-            .fetch_one(&self.sql_pool)
-            .await?)
+        let rows = self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_all(&mut *self.connection().await?),
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DynamicGroup {
+                id: row.get::<GroupId, _>(&*Groups::GroupId.to_string()),
+                display_name: row.get::<String, _>(&*Groups::DisplayName.to_string()),
+                description: row.get::<Option<String>, _>(&*Groups::Description.to_string()),
+                filter: row.get::<String, _>(&*Groups::DynamicFilter.to_string()),
+            })
+            .collect())
     }
 
-    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
-        if *user_id == self.config.ldap_user_dn {
-            let mut groups = HashSet::new();
-            groups.insert(GroupIdAndName(GroupId(1), "lldap_admin".to_string()));
-            return Ok(groups);
+    /// The dynamic groups matching the filter. Their members are only computed if `with_members`,
+    /// or if the filter has conditions on the members, and then only for the groups that can still
+    /// match.
+    async fn list_dynamic_groups_with_members(
+        &self,
+        filters: Option<&GroupRequestFilter>,
+        with_members: bool,
+    ) -> Result<Vec<Group>> {
+        let dynamic_groups = self.list_dynamic_groups().await?;
+        let filter_needs_members = filters.map_or(false, refers_to_members);
+        let mut groups = Vec::new();
+        for dynamic_group in &dynamic_groups {
+            let mut group = Group {
+                id: dynamic_group.id,
+                display_name: dynamic_group.display_name.clone(),
+                description: dynamic_group.description.clone(),
+                users: Vec::new(),
+            };
+            let matches = |group: &Group| filters.map_or(true, |f| group_matches(f, group));
+            if !filter_needs_members && !matches(&group) {
+                continue;
+            }
+            if with_members || filter_needs_members {
+                let members_filter = self.resolve_groups(
+                    UserRequestFilter::MemberOfId(dynamic_group.id),
+                    &dynamic_groups,
+                );
+                group.users = self.list_user_ids(Some(members_filter)).await?;
+            }
+            if filter_needs_members && !matches(&group) {
+                continue;
+            }
+            groups.push(group);
         }
-        let query: String = Query::select()
-            .column((Groups::Table, Groups::GroupId))
-            .column(Groups::DisplayName)
-            .from(Groups::Table)
-            .inner_join(
-                Memberships::Table,
-                Expr::tbl(Groups::Table, Groups::GroupId)
-                    .equals(Memberships::Table, Memberships::GroupId),
-            )
-            .and_where(Expr::col(Memberships::UserId).eq(user_id))
-            .to_string(DbQueryBuilder {});
+        Ok(groups)
+    }
 
-        sqlx::query(&query)
-            // Extract the group id from the row.
-            .map(|row: DbRow| {
-                GroupIdAndName(
-                    row.get::<GroupId, _>(&*Groups::GroupId.to_string()),
-                    row.get::<String, _>(&*Groups::DisplayName.to_string()),
-                )
-            })
-            .fetch(&self.sql_pool)
-            // Collect the vector of rows, each potentially an error.
-            .collect::<Vec<sqlx::Result<GroupIdAndName>>>()
-            .await
-            .into_iter()
-            // Transform it into a single result (the first error if any), and group the group_ids
-            // into a HashSet.
-            .collect::<sqlx::Result<HashSet<_>>>()
-            // Map the sqlx::Error into a DomainError.
-            .map_err(DomainError::DatabaseError)
+    /// The dynamic groups of each of the users, one query per dynamic group.
+    async fn get_dynamic_groups_for_users(
+        &self,
+        user_ids: &[UserId],
+    ) -> Result<Vec<(UserId, GroupIdAndName)>> {
+        let dynamic_groups = self.list_dynamic_groups().await?;
+        let mut memberships = Vec::new();
+        for group in &dynamic_groups {
+            let filter = UserRequestFilter::And(vec![
+                UserRequestFilter::UserIdIn(user_ids.to_vec()),
+                self.resolve_groups(UserRequestFilter::MemberOfId(group.id), &dynamic_groups),
+            ]);
+            for user_id in self.list_user_ids(Some(filter)).await? {
+                memberships.push((
+                    user_id,
+                    GroupIdAndName(group.id, group.display_name.clone()),
+                ));
+            }
+        }
+        Ok(memberships)
     }
 
-    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
-        let columns = vec![
-            Users::UserId,
-            Users::Email,
-            Users::DisplayName,
-            Users::FirstName,
-            Users::LastName,
-            Users::CreationDate,
-        ];
-        let values = vec![
-            request.user_id.into(),
-            request.email.into(),
-            request.display_name.unwrap_or_default().into(),
-            request.first_name.unwrap_or_default().into(),
-            request.last_name.unwrap_or_default().into(),
-            chrono::Utc::now().naive_utc().into(),
-        ];
-        let query = Query::insert()
-            .into_table(Users::Table)
-            .columns(columns)
-            .values_panic(values)
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+    /// Checks that the dynamic groups still resolve after the update: no group can contain itself,
+    /// or nest other dynamic groups too deeply. The admin group can't be dynamic.
+    async fn check_dynamic_groups_update(&self, request: &UpdateGroupRequest) -> Result<()> {
+        let GroupIdAndName(group_id, display_name) =
+            self.get_group_details(request.group_id).await?;
+        let mut dynamic_groups = self.list_dynamic_groups().await?;
+        let current_filter = dynamic_groups
+            .iter()
+            .position(|group| group.id == group_id)
+            .map(|index| dynamic_groups.remove(index).filter);
+        let filter = match &request.dynamic_filter {
+            Some(filter) => {
+                if let Some(filter) = filter {
+                    self.parse_dynamic_filter(filter).map_err(|e| {
+                        DomainError::ValidationError("dynamic_filter".to_string(), e)
+                    })?;
+                }
+                filter.clone()
+            }
+            None => current_filter,
+        };
+        if let Some(filter) = filter {
+            if group_id == GroupId(self.config.admin_group_id) {
+                return Err(DomainError::ValidationError(
+                    "dynamic_filter".to_string(),
+                    "the admin group can't be dynamic".to_string(),
+                ));
+            }
+            dynamic_groups.push(DynamicGroup {
+                id: group_id,
+                display_name: request.display_name.clone().unwrap_or(display_name),
+                description: None,
+                filter,
+            });
+        }
+        for group in &dynamic_groups {
+            self.resolve_dynamic_group(group, &dynamic_groups, &mut Vec::new())
+                .map_err(|e| DomainError::ValidationError("dynamic_filter".to_string(), e))?;
+        }
         Ok(())
     }
 
-    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
-        let mut values = Vec::new();
-        if let Some(email) = request.email {
-            values.push((Users::Email, email.into()));
-        }
-        if let Some(display_name) = request.display_name {
-            values.push((Users::DisplayName, display_name.into()));
+    /// Same as `check_group_is_stored`, and the members of the dynamic groups can't be changed
+    /// either.
+    async fn check_group_members_are_stored(&self, group_id: GroupId) -> Result<()> {
+        self.check_group_is_stored(Some(group_id), None)?;
+        if let Some(group) = self
+            .list_dynamic_groups()
+            .await?
+            .into_iter()
+            .find(|group| group.id == group_id)
+        {
+            return Err(DomainError::ValidationError(
+                "group".to_string(),
+                format!(
+                    "the members of the dynamic group {} are computed",
+                    group.display_name
+                ),
+            ));
         }
-        if let Some(first_name) = request.first_name {
-            values.push((Users::FirstName, first_name.into()));
+        Ok(())
+    }
+
+    /// The members of the group of all users are computed, they can't be changed. No stored group
+    /// can have the same name either.
+    fn check_group_is_stored(
+        &self,
+        group_id: Option<GroupId>,
+        display_name: Option<&str>,
+    ) -> Result<()> {
+        let all_users_group = match &self.config.all_users_group {
+            Some(all_users_group) => all_users_group,
+            None => return Ok(()),
+        };
+        if group_id == Some(ALL_USERS_GROUP_ID) || display_name == Some(all_users_group.as_str()) {
+            return Err(DomainError::ValidationError(
+                "group".to_string(),
+                format!("{} is the virtual group of all users", all_users_group),
+            ));
         }
-        if let Some(last_name) = request.last_name {
-            values.push((Users::LastName, last_name.into()));
+        Ok(())
+    }
+
+    /// The IDs of the users matching the filter, already resolved with `resolve_groups`.
+    async fn fetch_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        if *user_id == self.config.ldap_user_dn {
+            let mut groups = HashSet::new();
+            groups.insert(GroupIdAndName(
+                GroupId(self.config.admin_group_id),
+                "lldap_admin".to_string(),
+            ));
+            groups.extend(self.all_users_group());
+            return Ok(groups);
         }
-        if values.is_empty() {
-            return Ok(());
+        let query: String = Query::select()
+            .column((Groups::Table, Groups::GroupId))
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .inner_join(
+                Memberships::Table,
+                Expr::tbl(Groups::Table, Groups::GroupId)
+                    .equals(Memberships::Table, Memberships::GroupId),
+            )
+            .and_where(Expr::col(Memberships::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+
+        let mut groups = self
+            .with_timeout(
+                &query,
+                sqlx::query(&query)
+                    // Extract the group id from the row.
+                    .map(|row: DbRow| {
+                        GroupIdAndName(
+                            row.get::<GroupId, _>(&*Groups::GroupId.to_string()),
+                            row.get::<String, _>(&*Groups::DisplayName.to_string()),
+                        )
+                    })
+                    .fetch(&mut *self.connection().await?)
+                    // Collect the rows into a single result (the first error if any), and group the
+                    // group_ids into a HashSet.
+                    .try_collect::<HashSet<_>>(),
+            )
+            .await?;
+        groups.extend(
+            self.get_dynamic_groups_for_users(std::slice::from_ref(user_id))
+                .await?
+                .into_iter()
+                .map(|(_, group)| group),
+        );
+        groups.extend(self.all_users_group());
+        Ok(groups)
+    }
+
+    async fn fetch_groups_for_users(
+        &self,
+        user_ids: &[UserId],
+    ) -> Result<HashMap<UserId, HashSet<GroupIdAndName>>> {
+        let mut groups: HashMap<UserId, HashSet<GroupIdAndName>> = user_ids
+            .iter()
+            .map(|user_id| (user_id.clone(), HashSet::new()))
+            .collect();
+        if user_ids.is_empty() {
+            return Ok(groups);
         }
-        let query = Query::update()
-            .table(Users::Table)
-            .values(values)
-            .and_where(Expr::col(Users::UserId).eq(request.user_id))
+        let query: String = Query::select()
+            .column(Memberships::UserId)
+            .column((Groups::Table, Groups::GroupId))
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .inner_join(
+                Memberships::Table,
+                Expr::tbl(Groups::Table, Groups::GroupId)
+                    .equals(Memberships::Table, Memberships::GroupId),
+            )
+            .and_where(Expr::col(Memberships::UserId).is_in(user_ids))
             .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
-        Ok(())
+        let memberships = self
+            .with_timeout(
+                &query,
+                sqlx::query(&query)
+                    .map(|row: DbRow| {
+                        (
+                            row.get::<UserId, _>(&*Memberships::UserId.to_string()),
+                            GroupIdAndName(
+                                row.get::<GroupId, _>(&*Groups::GroupId.to_string()),
+                                row.get::<String, _>(&*Groups::DisplayName.to_string()),
+                            ),
+                        )
+                    })
+                    .fetch(&mut *self.connection().await?)
+                    .try_collect::<Vec<_>>(),
+            )
+            .await?;
+        for (user_id, group) in memberships
+            .into_iter()
+            .chain(self.get_dynamic_groups_for_users(user_ids).await?)
+        {
+            groups.entry(user_id).or_default().insert(group);
+        }
+        // Same as in `fetch_user_groups`: the admin from the configuration is only in lldap_admin.
+        if let Some(admin_groups) = groups.get_mut(&self.config.ldap_user_dn) {
+            admin_groups.clear();
+            admin_groups.insert(GroupIdAndName(
+                GroupId(self.config.admin_group_id),
+                "lldap_admin".to_string(),
+            ));
+        }
+        if let Some(all_users_group) = self.all_users_group() {
+            for user_groups in groups.values_mut() {
+                user_groups.insert(all_users_group.clone());
+            }
+        }
+        Ok(groups)
     }
 
-    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
-        let mut values = Vec::new();
-        if let Some(display_name) = request.display_name {
-            values.push((Groups::DisplayName, display_name.into()));
+    async fn list_user_ids(&self, filters: Option<UserRequestFilter>) -> Result<Vec<UserId>> {
+        let mut query_builder = Query::select()
+            .column((Users::Table, Users::UserId))
+            .from(Users::Table)
+            .order_by((Users::Table, Users::UserId), Order::Asc)
+            .to_owned();
+        if !add_user_filter(&mut query_builder, filters) {
+            return Ok(Vec::new());
         }
-        if values.is_empty() {
+        let query = query_builder.to_string(DbQueryBuilder {});
+        let mut user_ids = self
+            .with_timeout(
+                &query,
+                sqlx::query(&query)
+                    .map(|row: DbRow| row.get::<UserId, _>(&*Users::UserId.to_string()))
+                    .fetch_all(&mut *self.connection().await?),
+            )
+            .await?;
+        // The joins with the groups return a user once per matching group.
+        user_ids.dedup();
+        Ok(user_ids)
+    }
+
+    /// Runs the query, giving up after the configured `database_query_timeout_ms`.
+    async fn with_timeout<T, F>(&self, query: &str, future: F) -> Result<T>
+    where
+        F: Future<Output = sqlx::Result<T>>,
+    {
+        with_query_timeout(
+            Duration::from_millis(self.config.database_query_timeout_ms),
+            query,
+            future,
+        )
+        .await
+    }
+
+    /// Fetches the addresses stored in `table` for each of the users.
+    async fn get_mail_addresses<T: Iden + Copy + 'static>(
+        &self,
+        table: T,
+        user_column: T,
+        address_column: T,
+        user_ids: Vec<UserId>,
+    ) -> Result<HashMap<UserId, Vec<String>>> {
+        let query = Query::select()
+            .column(user_column)
+            .column(address_column)
+            .from(table)
+            .and_where(Expr::col(user_column).is_in(user_ids))
+            .order_by(address_column, Order::Asc)
+            .to_string(DbQueryBuilder {});
+        let mut addresses = HashMap::<UserId, Vec<String>>::new();
+        for row in self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_all(&mut *self.connection().await?),
+            )
+            .await?
+        {
+            addresses
+                .entry(row.get::<UserId, _>(&*user_column.to_string()))
+                .or_default()
+                .push(row.get::<String, _>(&*address_column.to_string()));
+        }
+        Ok(addresses)
+    }
+
+    /// Loads the mail aliases, forwarding addresses and phone numbers of the users.
+    async fn fill_mail_addresses(&self, users: &mut [User]) -> Result<()> {
+        if users.is_empty() {
             return Ok(());
         }
-        let query = Query::update()
-            .table(Groups::Table)
-            .values(values)
-            .and_where(Expr::col(Groups::GroupId).eq(request.group_id))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+        let user_ids = users.iter().map(|u| u.user_id.clone()).collect::<Vec<_>>();
+        let mut aliases = self
+            .get_mail_addresses(
+                MailAliases::Table,
+                MailAliases::UserId,
+                MailAliases::Alias,
+                user_ids.clone(),
+            )
+            .await?;
+        let mut forwarding = self
+            .get_mail_addresses(
+                MailForwarding::Table,
+                MailForwarding::UserId,
+                MailForwarding::Address,
+                user_ids.clone(),
+            )
+            .await?;
+        let mut phone_numbers = self
+            .get_mail_addresses(
+                UserPhoneNumbers::Table,
+                UserPhoneNumbers::UserId,
+                UserPhoneNumbers::PhoneNumber,
+                user_ids,
+            )
+            .await?;
+        for user in users {
+            user.mail_aliases = aliases.remove(&user.user_id).unwrap_or_default();
+            user.mail_forwarding = forwarding.remove(&user.user_id).unwrap_or_default();
+            user.phone_numbers = phone_numbers.remove(&user.user_id).unwrap_or_default();
+        }
+        self.apply_pending_last_logins(users);
         Ok(())
     }
 
-    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+    /// Replaces all the addresses stored in `table` for the user.
+    async fn set_mail_addresses<T: Iden + Copy + 'static>(
+        &self,
+        table: T,
+        user_column: T,
+        address_column: T,
+        user_id: &UserId,
+        addresses: Vec<String>,
+    ) -> Result<()> {
         let delete_query = Query::delete()
-            .from_table(Users::Table)
-            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .from_table(table)
+            .and_where(Expr::col(user_column).eq(user_id))
             .to_string(DbQueryBuilder {});
-        sqlx::query(&delete_query).execute(&self.sql_pool).await?;
+        self.with_timeout(
+            &delete_query,
+            sqlx::query(&delete_query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        if addresses.is_empty() {
+            return Ok(());
+        }
+        let mut insert = Query::insert()
+            .into_table(table)
+            .columns(vec![user_column, address_column])
+            .to_owned();
+        for address in addresses {
+            insert.values_panic(vec![user_id.into(), address.into()]);
+        }
+        let query = insert.to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
         Ok(())
     }
 
-    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
-        let query = Query::insert()
-            .into_table(Groups::Table)
-            .columns(vec![Groups::DisplayName])
-            .values_panic(vec![group_name.into()])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
-        let query = Query::select()
-            .column(Groups::GroupId)
-            .from(Groups::Table)
-            .and_where(Expr::col(Groups::DisplayName).eq(group_name))
-            .to_string(DbQueryBuilder {});
-        let row = sqlx::query(&query).fetch_one(&self.sql_pool).await?;
-        Ok(GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())))
+    async fn set_mail_aliases(&self, user_id: &UserId, aliases: Vec<String>) -> Result<()> {
+        self.set_mail_addresses(
+            MailAliases::Table,
+            MailAliases::UserId,
+            MailAliases::Alias,
+            user_id,
+            aliases.iter().map(|a| normalize_mail_alias(a)).collect(),
+        )
+        .await
     }
 
-    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
-        let delete_query = Query::delete()
-            .from_table(Groups::Table)
-            .and_where(Expr::col(Groups::GroupId).eq(group_id))
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&delete_query).execute(&self.sql_pool).await?;
-        Ok(())
+    async fn set_mail_forwarding(&self, user_id: &UserId, addresses: Vec<String>) -> Result<()> {
+        self.set_mail_addresses(
+            MailForwarding::Table,
+            MailForwarding::UserId,
+            MailForwarding::Address,
+            user_id,
+            addresses,
+        )
+        .await
     }
 
-    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
-        let query = Query::insert()
-            .into_table(Memberships::Table)
-            .columns(vec![Memberships::UserId, Memberships::GroupId])
-            .values_panic(vec![user_id.into(), group_id.into()])
-            .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
-        Ok(())
+    /// The IDs of the `default_groups`, created if missing with `create_missing_default_groups`.
+    /// Fails if one of them doesn't exist or has computed members, e.g. a dynamic group.
+    async fn get_default_group_ids(&self) -> Result<Vec<GroupId>> {
+        let mut group_ids = Vec::with_capacity(self.config.default_groups.len());
+        for group_name in &self.config.default_groups {
+            let group = self
+                .list_groups(Some(GroupRequestFilter::DisplayName(group_name.clone())))
+                .await?
+                .into_iter()
+                .next();
+            group_ids.push(match group {
+                Some(group) => {
+                    self.check_group_members_are_stored(group.id).await?;
+                    group.id
+                }
+                None if self.config.create_missing_default_groups => {
+                    info!(r#"Creating the default group "{}""#, group_name);
+                    self.create_group(group_name).await?
+                }
+                None => {
+                    return Err(DomainError::ValidationError(
+                        "default_groups".to_string(),
+                        format!(r#"the default group "{}" doesn't exist"#, group_name),
+                    ))
+                }
+            });
+        }
+        Ok(group_ids)
     }
 
-    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+    /// Shows the logins that are not flushed yet.
+    fn apply_pending_last_logins(&self, users: &mut [User]) {
+        let pending = self.pending_last_logins.lock().unwrap();
+        if pending.is_empty() {
+            return;
+        }
+        for user in users {
+            if let Some(logins) = pending.get(&user.user_id) {
+                user.last_login_at = user.last_login_at.max(Some(logins.last));
+            }
+        }
+    }
+
+    /// Writes the last login of the users and adds up their logins of the day, in a single
+    /// transaction. The daily counts older than `login_history_days` are removed.
+    async fn store_last_logins(&self, logins: Vec<(UserId, PendingLogins)>) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let mut transaction = sqlx::Connection::begin(&mut *connection).await?;
+        for (user_id, logins) in logins {
+            let query = Query::update()
+                .table(Users::Table)
+                .values(vec![(Users::LastLoginAt, logins.last.naive_utc().into())])
+                .and_where(Expr::col(Users::UserId).eq(user_id.clone()))
+                .to_string(DbQueryBuilder {});
+            self.with_timeout(&query, sqlx::query(&query).execute(&mut transaction))
+                .await?;
+            // Add to the count of the day if there is one already, otherwise create it. The
+            // transaction keeps another writer from creating it in between.
+            let day = logins.last.date().naive_utc();
+            let query = Query::update()
+                .table(UserLogins::Table)
+                .value_expr(
+                    UserLogins::LoginCount,
+                    Expr::cust_with_values(
+                        &format!("{} + ?", UserLogins::LoginCount.to_string()),
+                        vec![logins.count],
+                    ),
+                )
+                .and_where(Expr::col(UserLogins::UserId).eq(user_id.clone()))
+                .and_where(Expr::col(UserLogins::Day).eq(day))
+                .to_string(DbQueryBuilder {});
+            let updated = self
+                .with_timeout(&query, sqlx::query(&query).execute(&mut transaction))
+                .await?
+                .rows_affected();
+            if updated == 0 {
+                let query = Query::insert()
+                    .into_table(UserLogins::Table)
+                    .columns(vec![
+                        UserLogins::UserId,
+                        UserLogins::Day,
+                        UserLogins::LoginCount,
+                    ])
+                    .values_panic(vec![user_id.into(), day.into(), logins.count.into()])
+                    .to_string(DbQueryBuilder {});
+                self.with_timeout(&query, sqlx::query(&query).execute(&mut transaction))
+                    .await?;
+            }
+        }
+        let oldest_day = chrono::Utc::now().date().naive_utc()
+            - chrono::Duration::days(i64::from(self.config.login_history_days));
         let query = Query::delete()
-            .from_table(Memberships::Table)
-            .and_where(Expr::col(Memberships::GroupId).eq(group_id))
-            .and_where(Expr::col(Memberships::UserId).eq(user_id))
+            .from_table(UserLogins::Table)
+            .and_where(Expr::col(UserLogins::Day).lt(oldest_day))
             .to_string(DbQueryBuilder {});
-        sqlx::query(&query).execute(&self.sql_pool).await?;
+        self.with_timeout(&query, sqlx::query(&query).execute(&mut transaction))
+            .await?;
+        transaction.commit().await?;
         Ok(())
     }
+
+    /// Normalizes the phone numbers, dropping the duplicates.
+    fn normalize_phone_numbers(&self, phone_numbers: Vec<String>) -> Result<Vec<String>> {
+        let mut normalized = Vec::with_capacity(phone_numbers.len());
+        for phone_number in phone_numbers {
+            let phone_number = normalize_phone_number(&phone_number, self.config.phone_validation)?;
+            if !normalized.contains(&phone_number) {
+                normalized.push(phone_number);
+            }
+        }
+        Ok(normalized)
+    }
+
+    /// Replaces all the phone numbers of the user with the already normalized ones.
+    async fn store_phone_numbers(
+        &self,
+        user_id: &UserId,
+        phone_numbers: Vec<String>,
+    ) -> Result<()> {
+        self.set_mail_addresses(
+            UserPhoneNumbers::Table,
+            UserPhoneNumbers::UserId,
+            UserPhoneNumbers::PhoneNumber,
+            user_id,
+            phone_numbers,
+        )
+        .await
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::domain::sql_tables::init_table;
-    use crate::infra::configuration::ConfigurationBuilder;
-    use lldap_auth::{opaque, registration};
+fn merge_pending_logins(
+    pending: &mut HashMap<UserId, PendingLogins>,
+    user_id: UserId,
+    logins: PendingLogins,
+) {
+    pending
+        .entry(user_id)
+        .and_modify(|pending| {
+            pending.last = pending.last.max(logins.last);
+            pending.count += logins.count;
+        })
+        .or_insert(logins);
+}
 
-    fn get_default_config() -> Configuration {
-        ConfigurationBuilder::default()
-            .verbose(true)
-            .build()
-            .unwrap()
+/// Trims the phone number and, with `validate`, checks that it is a valid E.164 number once the
+/// usual separators are removed: "+1 (415) 555-0123" becomes "+14155550123".
+fn normalize_phone_number(phone_number: &str, validate: bool) -> Result<String> {
+    let phone_number = phone_number.trim();
+    let invalid = |reason: &str| {
+        Err(DomainError::ValidationError(
+            "phone_number".to_string(),
+            format!("{:?}: {}", phone_number, reason),
+        ))
+    };
+    if phone_number.is_empty() {
+        return invalid("empty phone number");
+    }
+    if !validate {
+        return Ok(phone_number.to_string());
+    }
+    let normalized = phone_number
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect::<String>();
+    let digits = match normalized.strip_prefix('+') {
+        Some(digits) => digits,
+        None => return invalid("expected an international number starting with '+'"),
+    };
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return invalid("unexpected character");
+    }
+    // E.164 numbers have at most 15 digits, and country codes don't start with 0.
+    if digits.is_empty() || digits.len() > 15 || digits.starts_with('0') {
+        return invalid("not a valid E.164 number");
     }
+    Ok(normalized)
+}
 
-    async fn get_in_memory_db() -> Pool {
-        PoolOptions::new().connect("sqlite::memory:").await.unwrap()
+/// Mail aliases are matched case-insensitively.
+fn normalize_mail_alias(alias: &str) -> String {
+    alias.trim().to_lowercase()
+}
+
+/// Whether the lowercase domain matches the pattern: either the domain itself, or e.g.
+/// "*.example.com" for any subdomain of example.com.
+fn email_domain_matches(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => domain.strip_suffix(parent).map_or(false, |subdomain| {
+            subdomain.len() > 1 && subdomain.ends_with('.')
+        }),
+        None => domain == pattern,
     }
+}
 
-    async fn get_initialized_db() -> Pool {
-        let sql_pool = get_in_memory_db().await;
-        init_table(&sql_pool).await.unwrap();
-        sql_pool
+/// Random alphanumeric string from the OS generator, e.g. for tokens.
+pub(crate) fn gen_random_string(len: usize) -> String {
+    use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+    let mut rng = OsRng;
+    std::iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(len)
+        .collect()
+}
+
+fn row_to_invitation(row: DbRow) -> Invitation {
+    Invitation {
+        id: InvitationId(row.get::<i32, _>(&*PendingInvitations::InvitationId.to_string())),
+        email: row.get::<String, _>(&*PendingInvitations::Email.to_string()),
+        token: row.get::<String, _>(&*PendingInvitations::Token.to_string()),
+        expiry_date: row
+            .get::<chrono::DateTime<chrono::Utc>, _>(&*PendingInvitations::ExpiryDate.to_string()),
+        used: row.get::<bool, _>(&*PendingInvitations::Used.to_string()),
     }
+}
 
-    async fn insert_user(handler: &SqlBackendHandler, name: &str, pass: &str) {
-        use crate::domain::opaque_handler::OpaqueHandler;
-        insert_user_no_password(handler, name).await;
-        let mut rng = rand::rngs::OsRng;
-        let client_registration_start =
-            opaque::client::registration::start_registration(pass, &mut rng).unwrap();
-        let response = handler
-            .registration_start(registration::ClientRegistrationStartRequest {
-                username: name.to_string(),
-                registration_start_request: client_registration_start.message,
-            })
-            .await
-            .unwrap();
-        let registration_upload = opaque::client::registration::finish_registration(
-            client_registration_start.state,
-            response.registration_response,
-            &mut rng,
-        )
-        .unwrap();
-        handler
-            .registration_finish(registration::ClientRegistrationFinishRequest {
-                server_data: response.server_data,
-                registration_upload: registration_upload.message,
-            })
+fn select_invitations() -> sea_query::SelectStatement {
+    Query::select()
+        .column(PendingInvitations::InvitationId)
+        .column(PendingInvitations::Email)
+        .column(PendingInvitations::Token)
+        .column(PendingInvitations::ExpiryDate)
+        .column(PendingInvitations::Used)
+        .from(PendingInvitations::Table)
+        .to_owned()
+}
+
+/// Runs the query future, returning a timeout error if it takes longer than `timeout`. A timeout
+/// of 0 means no limit.
+async fn with_query_timeout<T, F>(timeout: Duration, query: &str, future: F) -> Result<T>
+where
+    F: Future<Output = sqlx::Result<T>>,
+{
+    if timeout.is_zero() {
+        return Ok(future.await?);
+    }
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            warn!("Database query timed out after {:?}: {}", timeout, query);
+            Err(DomainError::query_timeout())
+        }
+    }
+}
+
+/// Reports the violations of the unique index on the user emails like the other unique
+/// attributes. The index is named in the message, or its column for the index of older versions.
+fn email_constraint_violation(error: DomainError) -> DomainError {
+    match &error {
+        DomainError::DatabaseError(sqlx::Error::Database(e))
+            if e.message().contains("UNIQUE constraint failed")
+                && (e.message().contains("users_email_lower_unique")
+                    || e.message().contains("users.email")) =>
+        {
+            DomainError::ConstraintViolation("mail".to_string())
+        }
+        _ => error,
+    }
+}
+
+/// The user field stored for an LDAP attribute that can be made unique with
+/// `unique_ldap_attributes`.
+pub(crate) fn get_unique_attribute_field(attribute: &str) -> Option<&'static str> {
+    Some(match attribute.to_lowercase().as_str() {
+        "uid" => "user_id",
+        "mail" => "email",
+        "cn" | "displayname" => "display_name",
+        "givenname" => "first_name",
+        "sn" => "last_name",
+        _ => return None,
+    })
+}
+
+/// Returns the column of the users table for a field name, as used in the filters.
+fn get_user_column(field: &str) -> Option<Users> {
+    Some(match field {
+        "user_id" => Users::UserId,
+        "email" => Users::Email,
+        "display_name" => Users::DisplayName,
+        "first_name" => Users::FirstName,
+        "last_name" => Users::LastName,
+        "avatar" => Users::Avatar,
+        "creation_date" => Users::CreationDate,
+        "manager_user_id" => Users::ManagerUserId,
+        _ => return None,
+    })
+}
+
+/// Whether the user column can be NULL.
+fn is_nullable_user_column(column: &Users) -> bool {
+    matches!(column, Users::Avatar | Users::ManagerUserId)
+}
+
+/// The condition on a column that can be NULL, false instead of NULL for the rows without a
+/// value. SQL's `NOT` keeps NULL, so `NOT (manager_user_id = 'bob')` would skip the users without
+/// a manager, while LDAP's `(!(manager=...))` matches them.
+fn false_if_null(column: Expr, condition: SimpleExpr) -> SimpleExpr {
+    column.is_not_null().and(condition)
+}
+
+/// Builds the lowercase SQL `LIKE` pattern for the filter, using `\` as the escape character.
+fn get_like_pattern(filter: &SubStringFilter) -> String {
+    fn escape(s: &str) -> String {
+        s.to_lowercase()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    }
+    let mut pattern = filter.initial.as_deref().map(escape).unwrap_or_default();
+    pattern.push('%');
+    for any in &filter.any {
+        pattern.push_str(&escape(any));
+        pattern.push('%');
+    }
+    if let Some(final_) = &filter.final_ {
+        pattern.push_str(&escape(final_));
+    }
+    pattern
+}
+
+// Returns the condition for the SQL query on the users table. The conditions on other tables are
+// subqueries on the user ID, so that the negations also match the users without any row there.
+fn get_user_filter_expr(filter: UserRequestFilter) -> SimpleExpr {
+    use UserRequestFilter::*;
+    fn get_repeated_filter(
+        fs: Vec<UserRequestFilter>,
+        field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
+    ) -> SimpleExpr {
+        let mut it = fs.into_iter();
+        let first_expr = match it.next() {
+            None => return Expr::value(true),
+            Some(f) => get_user_filter_expr(f),
+        };
+        it.fold(first_expr, |e, f| field(e, get_user_filter_expr(f)))
+    }
+    match filter {
+        And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
+        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
+        Not(f) => Expr::not(Expr::expr(get_user_filter_expr(*f))),
+        UserId(user_id) => Expr::col((Users::Table, Users::UserId)).eq(user_id),
+        UserIdIn(user_ids) => Expr::col((Users::Table, Users::UserId)).is_in(user_ids),
+        Equality(s1, s2) => {
+            if s1 == Users::DisplayName.to_string() {
+                Expr::col((Users::Table, Users::DisplayName)).eq(s2)
+            } else if s1 == Users::UserId.to_string() {
+                panic!("User id should be wrapped")
+            } else {
+                let condition = Expr::expr(Expr::cust(&s1)).eq(s2);
+                match get_user_column(&s1) {
+                    Some(column) if !is_nullable_user_column(&column) => condition,
+                    _ => false_if_null(Expr::expr(Expr::cust(&s1)), condition),
+                }
+            }
+        }
+        SubString(field, filter) => match get_user_column(&field) {
+            Some(column) => {
+                let condition = Expr::cust_with_values(
+                    &format!(
+                        "LOWER({}.{}) LIKE ? ESCAPE '\\'",
+                        Users::Table.to_string(),
+                        column.to_string(),
+                    ),
+                    vec![get_like_pattern(&filter)],
+                );
+                if is_nullable_user_column(&column) {
+                    false_if_null(Expr::col((Users::Table, column)), condition)
+                } else {
+                    condition
+                }
+            }
+            None => Expr::value(false),
+        },
+        // WHERE (user_id in (SELECT user_id FROM mail_aliases))
+        Present(field) if field == "mail_aliases" => Expr::col((Users::Table, Users::UserId))
+            .in_subquery(
+                Query::select()
+                    .column(MailAliases::UserId)
+                    .from(MailAliases::Table)
+                    .take(),
+            ),
+        Present(field) if field == "mail_forwarding" => Expr::col((Users::Table, Users::UserId))
+            .in_subquery(
+                Query::select()
+                    .column(MailForwarding::UserId)
+                    .from(MailForwarding::Table)
+                    .take(),
+            ),
+        Present(field) => match get_user_column(&field) {
+            Some(column) => Expr::expr(Expr::cust(&format!(
+                "COALESCE({}.{}, '')",
+                Users::Table.to_string(),
+                column.to_string()
+            )))
+            .ne(""),
+            None => Expr::value(false),
+        },
+        // WHERE (user_id in (SELECT user_id FROM memberships JOIN groups WHERE display_name = name))
+        MemberOf(group) => Expr::col((Users::Table, Users::UserId)).in_subquery(
+            Query::select()
+                .column((Memberships::Table, Memberships::UserId))
+                .from(Memberships::Table)
+                .inner_join(
+                    Groups::Table,
+                    Expr::tbl(Memberships::Table, Memberships::GroupId)
+                        .equals(Groups::Table, Groups::GroupId),
+                )
+                .and_where(Expr::col((Groups::Table, Groups::DisplayName)).eq(group))
+                .take(),
+        ),
+        // WHERE (user_id in (SELECT user_id FROM memberships WHERE group_id = group_id))
+        MemberOfId(group_id) => Expr::col((Users::Table, Users::UserId)).in_subquery(
+            Query::select()
+                .column(Memberships::UserId)
+                .from(Memberships::Table)
+                .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+                .take(),
+        ),
+        // WHERE (user_id in (SELECT user_id FROM memberships))
+        MemberOfAnyGroup => Expr::col((Users::Table, Users::UserId)).in_subquery(
+            Query::select()
+                .column(Memberships::UserId)
+                .from(Memberships::Table)
+                .take(),
+        ),
+        // WHERE (user_id in (SELECT user_id FROM mail_aliases WHERE alias = alias))
+        MailAlias(alias) => Expr::col((Users::Table, Users::UserId)).in_subquery(
+            Query::select()
+                .column(MailAliases::UserId)
+                .from(MailAliases::Table)
+                .and_where(Expr::col(MailAliases::Alias).eq(normalize_mail_alias(&alias)))
+                .take(),
+        ),
+        Manager(manager_id) => false_if_null(
+            Expr::col((Users::Table, Users::ManagerUserId)),
+            Expr::col((Users::Table, Users::ManagerUserId)).eq(manager_id),
+        ),
+        CreatedBefore(date) => Expr::col((Users::Table, Users::CreationDate)).lt(date.naive_utc()),
+        CreatedAfter(date) => Expr::col((Users::Table, Users::CreationDate)).gt(date.naive_utc()),
+    }
+}
+
+// Returns the condition for the SQL query.
+fn get_group_filter_expr(filter: GroupRequestFilter) -> SimpleExpr {
+    use GroupRequestFilter::*;
+    fn get_repeated_filter(
+        fs: Vec<GroupRequestFilter>,
+        field: &dyn Fn(SimpleExpr, SimpleExpr) -> SimpleExpr,
+    ) -> SimpleExpr {
+        let mut it = fs.into_iter();
+        let first_expr = match it.next() {
+            None => return Expr::value(true),
+            Some(f) => get_group_filter_expr(f),
+        };
+        it.fold(first_expr, |e, f| field(e, get_group_filter_expr(f)))
+    }
+    match filter {
+        And(fs) => get_repeated_filter(fs, &SimpleExpr::and),
+        Or(fs) => get_repeated_filter(fs, &SimpleExpr::or),
+        Not(f) => Expr::not(Expr::expr(get_group_filter_expr(*f))),
+        DisplayName(name) => Expr::col((Groups::Table, Groups::DisplayName)).eq(name),
+        GroupId(id) => Expr::col((Groups::Table, Groups::GroupId)).eq(id.0),
+        // WHERE (group_id in (SELECT group_id FROM memberships WHERE user_id = user)), on the
+        // group's own ID: the one of the joined memberships is NULL for the groups without members.
+        Member(user) => Expr::col((Groups::Table, Groups::GroupId)).in_subquery(
+            Query::select()
+                .column(Memberships::GroupId)
+                .from(Memberships::Table)
+                .and_where(Expr::col(Memberships::UserId).eq(user))
+                .take(),
+        ),
+    }
+}
+
+/// The ID of the `all_users_group`, which isn't stored: the IDs of the stored groups start at 1.
+pub const ALL_USERS_GROUP_ID: GroupId = GroupId(0);
+
+/// Replaces the membership conditions on the group of all users, which isn't stored, with
+/// conditions that are always true.
+fn resolve_all_users_group(filter: UserRequestFilter, group_name: &str) -> UserRequestFilter {
+    use UserRequestFilter::*;
+    let resolve_all = |filters: Vec<UserRequestFilter>| {
+        filters
+            .into_iter()
+            .map(|f| resolve_all_users_group(f, group_name))
+            .collect()
+    };
+    match filter {
+        And(filters) => And(resolve_all(filters)),
+        Or(filters) => Or(resolve_all(filters)),
+        Not(f) => Not(Box::new(resolve_all_users_group(*f, group_name))),
+        MemberOf(group) if group == group_name => And(Vec::new()),
+        MemberOfId(group_id) if group_id == ALL_USERS_GROUP_ID => And(Vec::new()),
+        MemberOfAnyGroup => And(Vec::new()),
+        f => f,
+    }
+}
+
+/// Whether the group of all users matches the filter. Every user is a member of it.
+fn all_users_group_matches(filter: &GroupRequestFilter, group_name: &str) -> bool {
+    use GroupRequestFilter::*;
+    match filter {
+        And(filters) => filters
+            .iter()
+            .all(|f| all_users_group_matches(f, group_name)),
+        Or(filters) => filters
+            .iter()
+            .any(|f| all_users_group_matches(f, group_name)),
+        Not(f) => !all_users_group_matches(f, group_name),
+        DisplayName(name) => name == group_name,
+        GroupId(group_id) => *group_id == ALL_USERS_GROUP_ID,
+        Member(_) => true,
+    }
+}
+
+/// A group whose members are the users matching a filter, rather than the stored memberships.
+struct DynamicGroup {
+    id: GroupId,
+    display_name: String,
+    description: Option<String>,
+    /// The LDAP filter, only parsed when the group is referenced.
+    filter: String,
+}
+
+/// How deep the filters of the dynamic groups can refer to other dynamic groups, which bounds the
+/// size of the resolved filters.
+const MAX_DYNAMIC_GROUP_DEPTH: usize = 5;
+
+/// Whether the filter has conditions on the groups of the users.
+fn refers_to_groups(filter: &UserRequestFilter) -> bool {
+    use UserRequestFilter::*;
+    match filter {
+        And(filters) | Or(filters) => filters.iter().any(refers_to_groups),
+        Not(f) => refers_to_groups(f),
+        MemberOf(_) | MemberOfId(_) | MemberOfAnyGroup => true,
+        _ => false,
+    }
+}
+
+/// Whether the filter has conditions on the members of the groups.
+fn refers_to_members(filter: &GroupRequestFilter) -> bool {
+    use GroupRequestFilter::*;
+    match filter {
+        And(filters) | Or(filters) => filters.iter().any(refers_to_members),
+        Not(f) => refers_to_members(f),
+        Member(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether a group that isn't stored with its members matches the filter.
+fn group_matches(filter: &GroupRequestFilter, group: &Group) -> bool {
+    use GroupRequestFilter::*;
+    match filter {
+        And(filters) => filters.iter().all(|f| group_matches(f, group)),
+        Or(filters) => filters.iter().any(|f| group_matches(f, group)),
+        Not(f) => !group_matches(f, group),
+        DisplayName(name) => *name == group.display_name,
+        GroupId(group_id) => *group_id == group.id,
+        Member(user_id) => group.users.contains(user_id),
+    }
+}
+
+/// Adds the condition of the filter to a query on the users table.
+/// Returns false if the filter matches no user, in which case there is no need to run the query.
+fn add_user_filter(
+    query_builder: &mut SelectStatement,
+    filters: Option<UserRequestFilter>,
+) -> bool {
+    if let Some(filter) = filters {
+        if filter == UserRequestFilter::Not(Box::new(UserRequestFilter::And(Vec::new()))) {
+            return false;
+        }
+        if filter != UserRequestFilter::And(Vec::new())
+            && filter != UserRequestFilter::Or(Vec::new())
+        {
+            query_builder.and_where(get_user_filter_expr(filter));
+        }
+    }
+    true
+}
+
+/// Same as `add_user_filter`, for a query on the groups table joined with the memberships.
+fn add_group_filter(
+    query_builder: &mut SelectStatement,
+    filters: Option<GroupRequestFilter>,
+) -> bool {
+    if let Some(filter) = filters {
+        if filter == GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(Vec::new()))) {
+            return false;
+        }
+        if filter != GroupRequestFilter::And(Vec::new())
+            && filter != GroupRequestFilter::Or(Vec::new())
+        {
+            query_builder.and_where(get_group_filter_expr(filter));
+        }
+    }
+    true
+}
+
+/// `COUNT(DISTINCT table.column)`, since the joins can return the same row several times.
+fn count_distinct(table: impl Iden, column: impl Iden) -> SimpleExpr {
+    Expr::cust(&format!(
+        "COUNT(DISTINCT {}.{})",
+        table.to_string(),
+        column.to_string()
+    ))
+}
+
+#[async_trait]
+impl BackendHandler for SqlBackendHandler {
+    async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>> {
+        self.list_users_sorted(filters, UserSort::default(), None)
+            .await
+    }
+
+    async fn list_users_sorted(
+        &self,
+        filters: Option<UserRequestFilter>,
+        sort: UserSort,
+        page: Option<Page>,
+    ) -> Result<Vec<User>> {
+        let order = if sort.descending {
+            Order::Desc
+        } else {
+            Order::Asc
+        };
+        let query = {
+            let mut query_builder = Query::select()
+                .column((Users::Table, Users::UserId))
+                .column(Users::Email)
+                .column((Users::Table, Users::DisplayName))
+                .column(Users::FirstName)
+                .column(Users::LastName)
+                .column(Users::Avatar)
+                .column(Users::CreationDate)
+                .column(Users::MaxSearchResults)
+                .column(Users::PasswordModifiedDate)
+                .column(Users::ManagerUserId)
+                .column(Users::LastLoginAt)
+                .from(Users::Table)
+                .to_owned();
+            let sort_column = match sort.field {
+                UserSortField::UserId => Users::UserId,
+                UserSortField::Email => Users::Email,
+                UserSortField::DisplayName => Users::DisplayName,
+                UserSortField::CreationDate => Users::CreationDate,
+            };
+            query_builder.order_by((Users::Table, sort_column), order);
+            if sort.field != UserSortField::UserId {
+                query_builder.order_by((Users::Table, Users::UserId), Order::Asc);
+            }
+            if let Some(page) = page {
+                query_builder.limit(page.limit).offset(page.offset);
+            }
+            if !add_user_filter(&mut query_builder, self.resolve_user_filter(filters).await?) {
+                return Ok(Vec::new());
+            }
+            query_builder.to_string(DbQueryBuilder {})
+        };
+
+        let mut users = self
+            .with_timeout(
+                &query,
+                sqlx::query_as::<_, User>(&query).fetch_all(&mut *self.connection().await?),
+            )
+            .await?;
+        self.fill_mail_addresses(&mut users).await?;
+        Ok(users)
+    }
+
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        let all_users_group = self.all_users_group().filter(|group| {
+            filters
+                .as_ref()
+                .map_or(true, |f| all_users_group_matches(f, &group.1))
+        });
+        let query: String = {
+            let mut query_builder = Query::select()
+                .column((Groups::Table, Groups::GroupId))
+                .column(Groups::DisplayName)
+                .column(Groups::Description)
+                .column(Memberships::UserId)
+                .from(Groups::Table)
+                .left_join(
+                    Memberships::Table,
+                    Expr::tbl(Groups::Table, Groups::GroupId)
+                        .equals(Memberships::Table, Memberships::GroupId),
+                )
+                .and_where(Expr::col((Groups::Table, Groups::DynamicFilter)).is_null())
+                .order_by(Groups::DisplayName, Order::Asc)
+                .order_by(Memberships::UserId, Order::Asc)
+                .to_owned();
+            if !add_group_filter(&mut query_builder, filters.clone()) {
+                return Ok(Vec::new());
+            }
+            query_builder.to_string(DbQueryBuilder {})
+        };
+
+        // For group_by.
+        use itertools::Itertools;
+        let mut groups = Vec::new();
+        // The rows are returned sorted by display_name, equivalent to group_id. We group them by
+        // this key which gives us one element (`rows`) per group.
+        for ((group_id, display_name, description), rows) in &self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_all(&mut *self.connection().await?),
+            )
+            .await?
+            .into_iter()
+            .group_by(|row| {
+                (
+                    GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())),
+                    row.get::<String, _>(&*Groups::DisplayName.to_string()),
+                    row.get::<Option<String>, _>(&*Groups::Description.to_string()),
+                )
+            })
+        {
+            groups.push(Group {
+                id: group_id,
+                display_name,
+                description,
+                users: rows
+                    .map(|row| row.get::<UserId, _>(&*Memberships::UserId.to_string()))
+                    // If a group has no users, an empty string is returned because of the left
+                    // join.
+                    .filter(|s| !s.as_str().is_empty())
+                    .collect(),
+            });
+        }
+        let mut computed_groups = self
+            .list_dynamic_groups_with_members(filters.as_ref(), true)
+            .await?;
+        if let Some(GroupIdAndName(id, display_name)) = all_users_group {
+            computed_groups.push(Group {
+                id,
+                display_name,
+                description: None,
+                users: self.list_user_ids(None).await?,
+            });
+        }
+        for group in computed_groups {
+            let position = groups.partition_point(|g| g.display_name < group.display_name);
+            groups.insert(position, group);
+        }
+        Ok(groups)
+    }
+
+    async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<u64> {
+        let mut query_builder = Query::select()
+            .expr(count_distinct(Users::Table, Users::UserId))
+            .from(Users::Table)
+            .to_owned();
+        if !add_user_filter(&mut query_builder, self.resolve_user_filter(filters).await?) {
+            return Ok(0);
+        }
+        let query = query_builder.to_string(DbQueryBuilder {});
+        let row = self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_one(&mut *self.connection().await?),
+            )
+            .await?;
+        Ok(row.get::<i64, _>(0) as u64)
+    }
+
+    async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<u64> {
+        let all_users_group_count = match &self.config.all_users_group {
+            Some(group_name)
+                if filters
+                    .as_ref()
+                    .map_or(true, |f| all_users_group_matches(f, group_name)) =>
+            {
+                1
+            }
+            _ => 0,
+        };
+        let computed_group_count = all_users_group_count
+            + self
+                .list_dynamic_groups_with_members(filters.as_ref(), false)
+                .await?
+                .len() as u64;
+        let mut query_builder = Query::select()
+            .expr(count_distinct(Groups::Table, Groups::GroupId))
+            .from(Groups::Table)
+            .left_join(
+                Memberships::Table,
+                Expr::tbl(Groups::Table, Groups::GroupId)
+                    .equals(Memberships::Table, Memberships::GroupId),
+            )
+            .and_where(Expr::col((Groups::Table, Groups::DynamicFilter)).is_null())
+            .to_owned();
+        if !add_group_filter(&mut query_builder, filters) {
+            return Ok(computed_group_count);
+        }
+        let query = query_builder.to_string(DbQueryBuilder {});
+        let row = self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_one(&mut *self.connection().await?),
+            )
+            .await?;
+        Ok(row.get::<i64, _>(0) as u64 + computed_group_count)
+    }
+
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+        let query = Query::select()
+            .column(Users::UserId)
+            .column(Users::Email)
+            .column(Users::DisplayName)
+            .column(Users::FirstName)
+            .column(Users::LastName)
+            .column(Users::Avatar)
+            .column(Users::CreationDate)
+            .column(Users::MaxSearchResults)
+            .column(Users::PasswordModifiedDate)
+            .column(Users::ManagerUserId)
+            .column(Users::LastLoginAt)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+
+        let mut user = self
+            .with_timeout(
+                &query,
+                sqlx::query_as::<_, User>(&query).fetch_one(&mut *self.connection().await?),
+            )
+            .await?;
+        self.fill_mail_addresses(std::slice::from_mut(&mut user))
+            .await?;
+        Ok(user)
+    }
+
+    async fn get_reports(&self, manager_id: &UserId) -> Result<Vec<User>> {
+        self.list_users(Some(UserRequestFilter::Manager(manager_id.clone())))
+            .await
+    }
+
+    async fn find_user_by_email_alias(&self, alias: &str) -> Result<Option<User>> {
+        Ok(self
+            .list_users(Some(UserRequestFilter::MailAlias(alias.to_string())))
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    async fn find_user_by_certificate_cn(&self, cn: &str) -> Result<Option<User>> {
+        Ok(self
+            .list_users(Some(UserRequestFilter::UserId(UserId::new(cn))))
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        if let Some(all_users_group) = self.all_users_group() {
+            if group_id == ALL_USERS_GROUP_ID {
+                return Ok(all_users_group);
+            }
+        }
+        let query = Query::select()
+            .column(Groups::GroupId)
+            .column(Groups::DisplayName)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .to_string(DbQueryBuilder {});
+
+        self.with_timeout(
+            &query,
+            sqlx::query_as::<_, GroupIdAndName>(&query).fetch_one(&mut *self.connection().await?),
+        )
+        .await
+    }
+
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        if let Some(groups) = self.membership_cache.get(user_id) {
+            return Ok(groups);
+        }
+        let generation = self.membership_cache.generation();
+        let groups = self.fetch_user_groups(user_id).await?;
+        self.membership_cache
+            .insert(generation, user_id.clone(), groups.clone());
+        Ok(groups)
+    }
+
+    async fn get_groups_for_users(
+        &self,
+        user_ids: &[UserId],
+    ) -> Result<HashMap<UserId, HashSet<GroupIdAndName>>> {
+        let mut groups = HashMap::new();
+        let mut missing_user_ids = Vec::new();
+        for user_id in user_ids {
+            match self.membership_cache.get(user_id) {
+                Some(user_groups) => {
+                    groups.insert(user_id.clone(), user_groups);
+                }
+                None => missing_user_ids.push(user_id.clone()),
+            }
+        }
+        let generation = self.membership_cache.generation();
+        for (user_id, user_groups) in self.fetch_groups_for_users(&missing_user_ids).await? {
+            self.membership_cache
+                .insert(generation, user_id.clone(), user_groups.clone());
+            groups.insert(user_id, user_groups);
+        }
+        Ok(groups)
+    }
+
+    fn clear_membership_cache(&self) {
+        self.membership_cache.clear();
+    }
+
+    fn data_version(&self) -> u64 {
+        self.data_version.load(Ordering::Acquire)
+    }
+
+    async fn get_groups_containing_user_recursive(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashSet<GroupIdAndName>> {
+        // Groups cannot contain other groups, so the direct memberships are the transitive ones.
+        self.get_user_groups(user_id).await
+    }
+
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        // The addresses, phone numbers and groups are separate queries: all or none of them.
+        if self.transaction.is_none() {
+            return self
+                .transaction(|txn| Box::pin(txn.create_user(request)))
+                .await;
+        }
+        let _invalidation = self.invalidate_on_drop(Some(&request.user_id));
+        self.check_email_domain(&request.email)?;
+        self.check_unique_attributes(
+            &request.user_id,
+            &[
+                ("email", Some(request.email.as_str())),
+                ("display_name", request.display_name.as_deref()),
+                ("first_name", request.first_name.as_deref()),
+                ("last_name", request.last_name.as_deref()),
+            ],
+        )
+        .await?;
+        let phone_numbers = self.normalize_phone_numbers(request.phone_numbers)?;
+        // Before creating the user, so that a missing group doesn't leave them half set up.
+        let default_group_ids = self.get_default_group_ids().await?;
+        let columns = vec![
+            Users::UserId,
+            Users::Email,
+            Users::DisplayName,
+            Users::FirstName,
+            Users::LastName,
+            Users::CreationDate,
+        ];
+        let user_id = request.user_id;
+        let values = vec![
+            user_id.clone().into(),
+            request.email.into(),
+            request.display_name.unwrap_or_default().into(),
+            request.first_name.unwrap_or_default().into(),
+            request.last_name.unwrap_or_default().into(),
+            chrono::Utc::now().naive_utc().into(),
+        ];
+        let query = Query::insert()
+            .into_table(Users::Table)
+            .columns(columns)
+            .values_panic(values)
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await
+        .map_err(email_constraint_violation)?;
+        if !request.mail_aliases.is_empty() {
+            self.set_mail_aliases(&user_id, request.mail_aliases)
+                .await?;
+        }
+        if !request.mail_forwarding.is_empty() {
+            self.set_mail_forwarding(&user_id, request.mail_forwarding)
+                .await?;
+        }
+        if !phone_numbers.is_empty() {
+            self.store_phone_numbers(&user_id, phone_numbers).await?;
+        }
+        for group_id in default_group_ids {
+            self.add_user_to_group(&user_id, group_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        if self.transaction.is_none() {
+            return self
+                .transaction(|txn| Box::pin(txn.update_user(request)))
+                .await;
+        }
+        let _invalidation = self.invalidate_on_drop(Some(&request.user_id));
+        if let Some(email) = &request.email {
+            self.check_email_domain(email)?;
+        }
+        self.check_unique_attributes(
+            &request.user_id,
+            &[
+                ("email", request.email.as_deref()),
+                ("display_name", request.display_name.as_deref()),
+                ("first_name", request.first_name.as_deref()),
+                ("last_name", request.last_name.as_deref()),
+            ],
+        )
+        .await?;
+        let phone_numbers = request
+            .phone_numbers
+            .map(|phone_numbers| self.normalize_phone_numbers(phone_numbers))
+            .transpose()?;
+        let mut values = Vec::new();
+        if let Some(email) = request.email {
+            values.push((Users::Email, email.into()));
+        }
+        if let Some(display_name) = request.display_name {
+            values.push((Users::DisplayName, display_name.into()));
+        }
+        if let Some(first_name) = request.first_name {
+            values.push((Users::FirstName, first_name.into()));
+        }
+        if let Some(last_name) = request.last_name {
+            values.push((Users::LastName, last_name.into()));
+        }
+        if let Some(max_search_results) = request.max_search_results {
+            values.push((
+                Users::MaxSearchResults,
+                max_search_results.map_or(Value::Null, |limit| i64::from(limit).into()),
+            ));
+        }
+        if let Some(manager_user_id) = request.manager_user_id {
+            if let Some(manager_id) = &manager_user_id {
+                self.check_manager(&request.user_id, manager_id).await?;
+            }
+            values.push((
+                Users::ManagerUserId,
+                manager_user_id.map_or(Value::Null, Into::into),
+            ));
+        }
+        if let Some(aliases) = request.mail_aliases {
+            self.set_mail_aliases(&request.user_id, aliases).await?;
+        }
+        if let Some(addresses) = request.mail_forwarding {
+            self.set_mail_forwarding(&request.user_id, addresses)
+                .await?;
+        }
+        if let Some(phone_numbers) = phone_numbers {
+            self.store_phone_numbers(&request.user_id, phone_numbers)
+                .await?;
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+        let query = Query::update()
+            .table(Users::Table)
+            .values(values)
+            .and_where(Expr::col(Users::UserId).eq(request.user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await
+        .map_err(email_constraint_violation)?;
+        Ok(())
+    }
+
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        let _invalidation = self.invalidate_on_drop(None);
+        self.check_group_is_stored(Some(request.group_id), request.display_name.as_deref())?;
+        if request.display_name.is_none()
+            && request.description.is_none()
+            && request.dynamic_filter.is_none()
+        {
+            return Ok(());
+        }
+        // Renaming a group can change the groups that the dynamic filters refer to.
+        self.check_dynamic_groups_update(&request).await?;
+        let mut values = Vec::new();
+        if let Some(display_name) = request.display_name {
+            values.push((Groups::DisplayName, display_name.into()));
+        }
+        if let Some(description) = request.description {
+            values.push((
+                Groups::Description,
+                description.map_or(Value::Null, Into::into),
+            ));
+        }
+        let changes_members = request.dynamic_filter.is_some();
+        if let Some(dynamic_filter) = request.dynamic_filter {
+            values.push((
+                Groups::DynamicFilter,
+                dynamic_filter.map_or(Value::Null, Into::into),
+            ));
+        }
+        let query = Query::update()
+            .table(Groups::Table)
+            .values(values)
+            .and_where(Expr::col(Groups::GroupId).eq(request.group_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        if changes_members {
+            // The members of a dynamic group are computed, and a group that stops being dynamic
+            // starts without members.
+            let query = Query::delete()
+                .from_table(Memberships::Table)
+                .and_where(Expr::col(Memberships::GroupId).eq(request.group_id))
+                .to_string(DbQueryBuilder {});
+            self.with_timeout(
+                &query,
+                sqlx::query(&query).execute(&mut *self.connection().await?),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        // The memberships, the reports and the user go away together, or not at all.
+        if self.transaction.is_none() {
+            let user_id = user_id.clone();
+            return self
+                .transaction(|txn| Box::pin(async move { txn.delete_user(&user_id).await }))
+                .await;
+        }
+        let _invalidation = self.invalidate_on_drop(Some(user_id));
+        // Don't rely on the foreign key cascade, which SQLite only enforces when enabled on the
+        // connection.
+        let memberships_query = Query::delete()
+            .from_table(Memberships::Table)
+            .and_where(Expr::col(Memberships::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &memberships_query,
+            sqlx::query(&memberships_query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        let reports_query = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::ManagerUserId, Value::Null)])
+            .and_where(Expr::col(Users::ManagerUserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &reports_query,
+            sqlx::query(&reports_query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        let delete_query = Query::delete()
+            .from_table(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &delete_query,
+            sqlx::query(&delete_query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        // The manager of the reports and the refresh tokens change along with the user, or not
+        // at all.
+        if self.transaction.is_none() {
+            let (user_id, new_user_id) = (user_id.clone(), new_user_id.clone());
+            return self
+                .transaction(|txn| {
+                    Box::pin(async move { txn.rename_user(&user_id, &new_user_id).await })
+                })
+                .await;
+        }
+        let _invalidation = self.invalidate_on_drop(None);
+        let query = Query::select()
+            .column(Users::UserId)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(new_user_id))
+            .to_string(DbQueryBuilder {});
+        if self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_optional(&mut *self.connection().await?),
+            )
+            .await?
+            .is_some()
+        {
+            return Err(DomainError::ConstraintViolation("uid".to_string()));
+        }
+        // The tables that refer to the user follow through their foreign keys, which cascade the
+        // updates.
+        let query = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::UserId, new_user_id.clone().into())])
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        // The manager isn't a foreign key, update the reports of the user as well.
+        let query = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::ManagerUserId, new_user_id.clone().into())])
+            .and_where(Expr::col(Users::ManagerUserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        // The sessions were opened under the old name: log them out, rather than letting them
+        // refresh their tokens under the new one.
+        let query = Query::delete()
+            .from_table(JwtRefreshStorage::Table)
+            .and_where(Expr::col(JwtRefreshStorage::UserId).eq(new_user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        let _invalidation = self.invalidate_on_drop(None);
+        self.check_group_is_stored(None, Some(group_name))?;
+        let query = Query::insert()
+            .into_table(Groups::Table)
+            .columns(vec![Groups::DisplayName])
+            .values_panic(vec![group_name.into()])
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        let query = Query::select()
+            .column(Groups::GroupId)
+            .from(Groups::Table)
+            .and_where(Expr::col(Groups::DisplayName).eq(group_name))
+            .to_string(DbQueryBuilder {});
+        let row = self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_one(&mut *self.connection().await?),
+            )
+            .await?;
+        Ok(GroupId(row.get::<i32, _>(&*Groups::GroupId.to_string())))
+    }
+
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        let _invalidation = self.invalidate_on_drop(None);
+        self.check_group_is_stored(Some(group_id), None)?;
+        let delete_query = Query::delete()
+            .from_table(Groups::Table)
+            .and_where(Expr::col(Groups::GroupId).eq(group_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &delete_query,
+            sqlx::query(&delete_query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        let _invalidation = self.invalidate_on_drop(Some(user_id));
+        self.check_group_members_are_stored(group_id).await?;
+        let query = Query::insert()
+            .into_table(Memberships::Table)
+            .columns(vec![Memberships::UserId, Memberships::GroupId])
+            .values_panic(vec![user_id.into(), group_id.into()])
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn add_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()> {
+        let _invalidation = self.invalidate_on_drop(Some(user_id));
+        let phone_number = normalize_phone_number(phone_number, self.config.phone_validation)?;
+        let query = Query::select()
+            .column(UserPhoneNumbers::UserId)
+            .from(UserPhoneNumbers::Table)
+            .and_where(Expr::col(UserPhoneNumbers::UserId).eq(user_id))
+            .and_where(Expr::col(UserPhoneNumbers::PhoneNumber).eq(phone_number.as_str()))
+            .to_string(DbQueryBuilder {});
+        if self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).fetch_optional(&mut *self.connection().await?),
+            )
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+        let query = Query::insert()
+            .into_table(UserPhoneNumbers::Table)
+            .columns(vec![
+                UserPhoneNumbers::UserId,
+                UserPhoneNumbers::PhoneNumber,
+            ])
+            .values_panic(vec![user_id.into(), phone_number.into()])
+            .to_string(DbQueryBuilder {});
+        match self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).execute(&mut *self.connection().await?),
+            )
+            .await
+        {
+            // Added concurrently since the check.
+            Err(DomainError::DatabaseError(sqlx::Error::Database(e)))
+                if e.message().contains("UNIQUE constraint failed") =>
+            {
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    async fn remove_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()> {
+        let _invalidation = self.invalidate_on_drop(Some(user_id));
+        let phone_number = normalize_phone_number(phone_number, self.config.phone_validation)?;
+        let query = Query::delete()
+            .from_table(UserPhoneNumbers::Table)
+            .and_where(Expr::col(UserPhoneNumbers::UserId).eq(user_id))
+            .and_where(Expr::col(UserPhoneNumbers::PhoneNumber).eq(phone_number))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()> {
+        let _invalidation = self.invalidate_on_drop(Some(user_id));
+        let phone_numbers = self.normalize_phone_numbers(phone_numbers)?;
+        self.store_phone_numbers(user_id, phone_numbers).await
+    }
+
+    async fn update_last_login(
+        &self,
+        user_id: &UserId,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        let login = PendingLogins {
+            last: timestamp,
+            count: 1,
+        };
+        if self.config.last_login_flush_interval_secs == 0 {
+            self.store_last_logins(vec![(user_id.clone(), login)])
+                .await?;
+        } else {
+            let mut pending = self.pending_last_logins.lock().unwrap();
+            merge_pending_logins(&mut pending, user_id.clone(), login);
+        }
+        // The users are listed with the new login from now on, even before it's written: the
+        // cached search results are out of date, but not the cached groups.
+        self.data_version.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    async fn flush_last_logins(&self) -> Result<usize> {
+        let logins = std::mem::take(&mut *self.pending_last_logins.lock().unwrap())
+            .into_iter()
+            .collect::<Vec<_>>();
+        if logins.is_empty() {
+            return Ok(0);
+        }
+        let count = logins.len();
+        if let Err(e) = self.store_last_logins(logins.clone()).await {
+            // Keep them for the next flush, with the logins that came in since.
+            let mut pending = self.pending_last_logins.lock().unwrap();
+            for (user_id, logins) in logins {
+                merge_pending_logins(&mut pending, user_id, logins);
+            }
+            return Err(e);
+        }
+        Ok(count)
+    }
+
+    async fn get_login_report(
+        &self,
+        since: chrono::NaiveDate,
+        page: Page,
+    ) -> Result<Vec<UserLoginStats>> {
+        // Count the buffered logins too.
+        self.flush_last_logins().await?;
+        let login_count = Alias::new("login_count");
+        let query = Query::select()
+            .column((Users::Table, Users::UserId))
+            .column((Users::Table, Users::LastLoginAt))
+            .expr_as(
+                Expr::cust(&format!(
+                    "COALESCE(SUM({}.{}), 0)",
+                    UserLogins::Table.to_string(),
+                    UserLogins::LoginCount.to_string()
+                )),
+                login_count.clone(),
+            )
+            .from(Users::Table)
+            .left_join(
+                UserLogins::Table,
+                Expr::tbl(UserLogins::Table, UserLogins::UserId)
+                    .equals(Users::Table, Users::UserId)
+                    .and(Expr::tbl(UserLogins::Table, UserLogins::Day).gte(since)),
+            )
+            .group_by_columns(vec![
+                (Users::Table, Users::UserId),
+                (Users::Table, Users::LastLoginAt),
+            ])
+            .order_by(login_count, Order::Desc)
+            .order_by((Users::Table, Users::UserId), Order::Asc)
+            .limit(page.limit)
+            .offset(page.offset)
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query_as::<_, UserLoginStats>(&query).fetch_all(&mut *self.connection().await?),
+        )
+        .await
+    }
+
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        let _invalidation = self.invalidate_on_drop(Some(user_id));
+        self.check_group_members_are_stored(group_id).await?;
+        let query = Query::delete()
+            .from_table(Memberships::Table)
+            .and_where(Expr::col(Memberships::GroupId).eq(group_id))
+            .and_where(Expr::col(Memberships::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn create_invitation(&self, email: &str) -> Result<Invitation> {
+        let token = gen_random_string(100);
+        let expiry_date = chrono::Utc::now() + chrono::Duration::hours(48);
+        let query = Query::insert()
+            .into_table(PendingInvitations::Table)
+            .columns(vec![
+                PendingInvitations::Token,
+                PendingInvitations::Email,
+                PendingInvitations::ExpiryDate,
+            ])
+            .values_panic(vec![
+                token.clone().into(),
+                email.into(),
+                expiry_date.naive_utc().into(),
+            ])
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query).execute(&mut *self.connection().await?),
+        )
+        .await?;
+        self.get_invitation(&token).await
+    }
+
+    async fn get_invitation(&self, token: &str) -> Result<Invitation> {
+        let query = select_invitations()
+            .and_where(Expr::col(PendingInvitations::Token).eq(token))
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query)
+                .map(row_to_invitation)
+                .fetch_one(&mut *self.connection().await?),
+        )
+        .await
+    }
+
+    async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool> {
+        let query = Query::update()
+            .table(PendingInvitations::Table)
+            .values(vec![(PendingInvitations::Used, true.into())])
+            .and_where(Expr::col(PendingInvitations::InvitationId).eq(invitation_id))
+            .and_where(Expr::col(PendingInvitations::Used).eq(false))
+            .and_where(Expr::col(PendingInvitations::ExpiryDate).gt(chrono::Utc::now().naive_utc()))
+            .to_string(DbQueryBuilder {});
+        Ok(self
+            .with_timeout(
+                &query,
+                sqlx::query(&query).execute(&mut *self.connection().await?),
+            )
+            .await?
+            .rows_affected()
+            == 1)
+    }
+
+    async fn list_pending_invitations(&self) -> Result<Vec<Invitation>> {
+        let query = select_invitations()
+            .and_where(Expr::col(PendingInvitations::Used).eq(false))
+            .and_where(Expr::col(PendingInvitations::ExpiryDate).gt(chrono::Utc::now().naive_utc()))
+            .order_by(PendingInvitations::ExpiryDate, Order::Asc)
+            .to_string(DbQueryBuilder {});
+        self.with_timeout(
+            &query,
+            sqlx::query(&query)
+                .map(row_to_invitation)
+                .fetch_all(&mut *self.connection().await?),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl TransactionHandler for SqlBackendHandler {
+    type Transaction = Txn;
+
+    async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send,
+        F: for<'t> FnOnce(&'t Self::Transaction) -> BoxFuture<'t, Result<T>> + Send,
+    {
+        let transaction = SharedTransaction(Arc::new(tokio::sync::Mutex::new(
+            self.sql_pool.begin().await?,
+        )));
+        let txn = Txn {
+            handler: SqlBackendHandler {
+                transaction: Some(transaction.clone()),
+                // Don't cache what may be rolled back, the cache is cleared after the commit.
+                membership_cache: MembershipCache::default(),
+                ..self.clone()
+            },
+        };
+        let result = f(&txn).await;
+        drop(txn);
+        let transaction = Arc::try_unwrap(transaction.0)
+            .map_err(|_| DomainError::InternalError("The transaction is still in use".to_string()))?
+            .into_inner();
+        let outcome = match &result {
+            Ok(_) => transaction.commit().await,
+            Err(_) => transaction.rollback().await,
+        };
+        self.membership_cache.clear();
+        self.data_version.fetch_add(1, Ordering::AcqRel);
+        outcome?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_tables::init_table;
+    use crate::infra::configuration::ConfigurationBuilder;
+    use lldap_auth::{opaque, registration};
+
+    fn get_default_config() -> Configuration {
+        ConfigurationBuilder::default()
+            .verbose(true)
+            .build()
+            .unwrap()
+    }
+
+    async fn get_in_memory_db() -> Pool {
+        PoolOptions::new().connect("sqlite::memory:").await.unwrap()
+    }
+
+    async fn get_initialized_db() -> Pool {
+        let sql_pool = get_in_memory_db().await;
+        init_table(&sql_pool).await.unwrap();
+        sql_pool
+    }
+
+    async fn insert_user(handler: &SqlBackendHandler, name: &str, pass: &str) {
+        use crate::domain::opaque_handler::OpaqueHandler;
+        insert_user_no_password(handler, name).await;
+        let mut rng = rand::rngs::OsRng;
+        let client_registration_start =
+            opaque::client::registration::start_registration(pass, &mut rng).unwrap();
+        let response = handler
+            .registration_start(registration::ClientRegistrationStartRequest {
+                username: name.to_string(),
+                registration_start_request: client_registration_start.message,
+            })
+            .await
+            .unwrap();
+        let registration_upload = opaque::client::registration::finish_registration(
+            client_registration_start.state,
+            response.registration_response,
+            &mut rng,
+        )
+        .unwrap();
+        handler
+            .registration_finish(registration::ClientRegistrationFinishRequest {
+                server_data: response.server_data,
+                registration_upload: registration_upload.message,
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn insert_user_no_password(handler: &SqlBackendHandler, name: &str) {
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new(name),
+                email: format!("{}@bob.bob", name),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn insert_group(handler: &SqlBackendHandler, name: &str) -> GroupId {
+        handler.create_group(name).await.unwrap()
+    }
+
+    async fn insert_membership(handler: &SqlBackendHandler, group_id: GroupId, user_id: &str) {
+        handler
+            .add_user_to_group(&UserId::new(user_id), group_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_init_admin_group() {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .admin_group_id(42)
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        assert!(handler.init_admin_group().await.unwrap());
+        assert_eq!(
+            handler
+                .list_groups(None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|g| (g.id, g.display_name))
+                .collect::<Vec<_>>(),
+            vec![(GroupId(42), "lldap_admin".to_string())]
+        );
+        // The admin group, found by its ID, can't be dynamic.
+        set_dynamic_filter(&handler, GroupId(42), Some("(uid=bob)"))
+            .await
+            .unwrap_err();
+        // Not the first startup anymore.
+        assert!(!handler.init_admin_group().await.unwrap());
+        assert_eq!(handler.list_groups(None).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_init_admin_group_not_empty() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        assert!(!handler.init_admin_group().await.unwrap());
+        assert!(handler.list_groups(None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bind_admin() {
+        let sql_pool = get_in_memory_db().await;
+        let config = ConfigurationBuilder::default()
+            .ldap_user_dn(UserId::new("admin"))
+            .ldap_user_pass(secstr::SecUtf8::from("test"))
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .bind(BindRequest {
+                name: UserId::new("admin"),
+                password: "test".to_string(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_user() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap();
+        handler
+            .bind(BindRequest {
+                name: UserId::new("andrew"),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap_err();
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong_password".to_string(),
+            })
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_user_no_password() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_string(),
+            })
+            .await
+            .unwrap_err();
+    }
+
+    async fn set_creation_date(handler: &SqlBackendHandler, user_id: &str, date: &str) {
+        sqlx::query(&format!(
+            "UPDATE users SET creation_date = '{}' WHERE user_id = '{}'",
+            date, user_id
+        ))
+        .execute(&handler.sql_pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_users_sorted() {
+        use chrono::TimeZone;
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        for (user_id, display_name, date) in [
+            ("bob", "Zed", "2021-01-01 00:00:00"),
+            ("john", "Alice", "2020-01-01 00:00:00"),
+            ("patrick", "Alice", "2022-01-01 00:00:00"),
+        ] {
+            insert_user_no_password(&handler, user_id).await;
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: UserId::new(user_id),
+                    display_name: Some(display_name.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            set_creation_date(&handler, user_id, date).await;
+        }
+        let list = |filters: Option<UserRequestFilter>,
+                    field: UserSortField,
+                    descending: bool,
+                    page: Option<Page>| {
+            let handler = handler.clone();
+            async move {
+                handler
+                    .list_users_sorted(filters, UserSort { field, descending }, page)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|u| u.user_id.into_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            list(None, UserSortField::DisplayName, false, None).await,
+            vec!["john", "patrick", "bob"]
+        );
+        // The ties are still sorted by user ID.
+        assert_eq!(
+            list(None, UserSortField::DisplayName, true, None).await,
+            vec!["bob", "john", "patrick"]
+        );
+        assert_eq!(
+            list(None, UserSortField::CreationDate, true, None).await,
+            vec!["patrick", "bob", "john"]
+        );
+        assert_eq!(
+            list(
+                None,
+                UserSortField::UserId,
+                false,
+                Some(Page {
+                    offset: 1,
+                    limit: 1
+                })
+            )
+            .await,
+            vec!["john"]
+        );
+        assert_eq!(
+            list(
+                Some(UserRequestFilter::CreatedAfter(
+                    chrono::Utc.ymd(2020, 6, 1).and_hms(0, 0, 0)
+                )),
+                UserSortField::UserId,
+                false,
+                None
+            )
+            .await,
+            vec!["bob", "patrick"]
+        );
+        assert_eq!(
+            list(
+                Some(UserRequestFilter::And(vec![
+                    UserRequestFilter::CreatedAfter(chrono::Utc.ymd(2020, 6, 1).and_hms(0, 0, 0)),
+                    UserRequestFilter::CreatedBefore(chrono::Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)),
+                ])),
+                UserSortField::UserId,
+                false,
+                None
+            )
+            .await,
+            vec!["bob"]
+        );
+        assert_eq!(
+            list(
+                Some(UserRequestFilter::UserIdIn(vec![
+                    UserId::new("patrick"),
+                    UserId::new("john"),
+                    UserId::new("unknown"),
+                ])),
+                UserSortField::UserId,
+                false,
+                None
+            )
+            .await,
+            vec!["john", "patrick"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        {
+            let users = handler
+                .list_users(None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["bob", "john", "patrick"]);
+        }
+        {
+            let users = handler
+                .list_users(Some(UserRequestFilter::UserId(UserId::new("bob"))))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["bob"]);
+        }
+        {
+            let users = handler
+                .list_users(Some(UserRequestFilter::Or(vec![
+                    UserRequestFilter::UserId(UserId::new("bob")),
+                    UserRequestFilter::UserId(UserId::new("John")),
+                ])))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["bob", "john"]);
+        }
+        {
+            let users = handler
+                .list_users(Some(UserRequestFilter::Not(Box::new(
+                    UserRequestFilter::UserId(UserId::new("bob")),
+                ))))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["john", "patrick"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Worst Group").await;
+        let group_3 = insert_group(&handler, "Empty Group").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        insert_membership(&handler, group_2, "John").await;
+        assert_eq!(
+            handler.list_groups(None).await.unwrap(),
+            vec![
+                Group {
+                    id: group_1,
+                    display_name: "Best Group".to_string(),
+                    description: None,
+                    users: vec![UserId::new("bob"), UserId::new("patrick")]
+                },
+                Group {
+                    id: group_3,
+                    display_name: "Empty Group".to_string(),
+                    description: None,
+                    users: vec![]
+                },
+                Group {
+                    id: group_2,
+                    display_name: "Worst Group".to_string(),
+                    description: None,
+                    users: vec![UserId::new("john"), UserId::new("patrick")]
+                },
+            ]
+        );
+        assert_eq!(
+            handler
+                .list_groups(Some(GroupRequestFilter::Or(vec![
+                    GroupRequestFilter::DisplayName("Empty Group".to_string()),
+                    GroupRequestFilter::Member(UserId::new("bob")),
+                ])))
+                .await
+                .unwrap(),
+            vec![
+                Group {
+                    id: group_1,
+                    display_name: "Best Group".to_string(),
+                    description: None,
+                    users: vec![UserId::new("bob"), UserId::new("patrick")]
+                },
+                Group {
+                    id: group_3,
+                    display_name: "Empty Group".to_string(),
+                    description: None,
+                    users: vec![]
+                },
+            ]
+        );
+        assert_eq!(
+            handler
+                .list_groups(Some(GroupRequestFilter::And(vec![
+                    GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
+                        "value".to_string()
+                    ))),
+                    GroupRequestFilter::GroupId(group_1),
+                ])))
+                .await
+                .unwrap(),
+            vec![Group {
+                id: group_1,
+                display_name: "Best Group".to_string(),
+                description: None,
+                users: vec![UserId::new("bob"), UserId::new("patrick")]
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_description() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let group_id = insert_group(&handler, "it_admins").await;
+        let update_description = |description: Option<&str>| {
+            handler.update_group(UpdateGroupRequest {
+                group_id,
+                display_name: None,
+                description: Some(description.map(str::to_string)),
+                dynamic_filter: None,
+            })
+        };
+        let get_description = || async {
+            handler
+                .list_groups(Some(GroupRequestFilter::GroupId(group_id)))
+                .await
+                .unwrap()
+                .remove(0)
+                .description
+        };
+        update_description(Some("IT Administrators")).await.unwrap();
+        assert_eq!(
+            get_description().await,
+            Some("IT Administrators".to_string())
+        );
+        // The cn doesn't change.
+        assert_eq!(
+            handler.get_group_details(group_id).await.unwrap().1,
+            "it_admins"
+        );
+        update_description(None).await.unwrap();
+        assert_eq!(get_description().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_count_users_and_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Best Group").await;
+        let group_2 = insert_group(&handler, "Worst Group").await;
+        insert_group(&handler, "Empty Group").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        assert_eq!(handler.count_users(None).await.unwrap(), 3);
+        assert_eq!(
+            handler
+                .count_users(Some(UserRequestFilter::Not(Box::new(
+                    UserRequestFilter::UserId(UserId::new("bob")),
+                ))))
+                .await
+                .unwrap(),
+            2
+        );
+        // Patrick is in both groups, but only counted once.
+        assert_eq!(
+            handler
+                .count_users(Some(UserRequestFilter::Or(vec![
+                    UserRequestFilter::MemberOf("Best Group".to_string()),
+                    UserRequestFilter::MemberOf("Worst Group".to_string()),
+                ])))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            handler
+                .count_users(Some(UserRequestFilter::Not(Box::new(
+                    UserRequestFilter::And(vec![]),
+                ))))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(handler.count_groups(None).await.unwrap(), 3);
+        assert_eq!(
+            handler
+                .count_groups(Some(GroupRequestFilter::Member(UserId::new("patrick"))))
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_users_attribute_filters() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        for (user_id, email, display_name, first_name, last_name) in [
+            (
+                "bob",
+                "bob@example.com",
+                "Bob Bobberson",
+                "Bob",
+                "Bobberson",
+            ),
+            ("patrick", "patrick@example.org", "Patrick_Star", "", "Star"),
+            ("john", "john%doe@example.com", "John", "John", ""),
+        ] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(user_id),
+                    email: email.to_string(),
+                    display_name: Some(display_name.to_string()),
+                    first_name: Some(first_name.to_string()),
+                    last_name: Some(last_name.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        use UserRequestFilter::*;
+        let substring = |field: &str, initial: Option<&str>, any: &[&str], final_: Option<&str>| {
+            SubString(
+                field.to_string(),
+                SubStringFilter {
+                    initial: initial.map(str::to_string),
+                    any: any.iter().map(|s| s.to_string()).collect(),
+                    final_: final_.map(str::to_string),
+                },
+            )
+        };
+        let present = |field: &str| Present(field.to_string());
+        let test_cases = vec![
+            (
+                Equality("email".to_string(), "bob@example.com".to_string()),
+                vec!["bob"],
+            ),
+            (substring("email", Some("BOB"), &[], None), vec!["bob"]),
+            (
+                substring("email", None, &[], Some("example.com")),
+                vec!["bob", "john"],
+            ),
+            (substring("email", None, &["%"], None), vec!["john"]),
+            (
+                substring("display_name", None, &["_"], None),
+                vec!["patrick"],
+            ),
+            (
+                substring("display_name", Some("pat"), &["ck"], Some("star")),
+                vec!["patrick"],
+            ),
+            (
+                substring("display_name", Some("bob"), &[], Some("bob")),
+                vec![],
+            ),
+            (
+                substring("user_id", None, &["o"], None),
+                vec!["bob", "john"],
+            ),
+            (substring("last_name", None, &["'"], None), vec![]),
+            (substring("email", Some("x' OR 1=1 --"), &[], None), vec![]),
+            (substring("unknown", Some("bob"), &[], None), vec![]),
+            (present("first_name"), vec!["bob", "john"]),
+            (present("last_name"), vec!["bob", "patrick"]),
+            (present("unknown"), vec![]),
+            (
+                And(vec![present("first_name"), present("last_name")]),
+                vec!["bob"],
+            ),
+            (
+                Or(vec![
+                    substring("email", None, &["%"], None),
+                    present("last_name"),
+                ]),
+                vec!["bob", "john", "patrick"],
+            ),
+            (Not(Box::new(present("first_name"))), vec!["patrick"]),
+            (
+                And(vec![
+                    substring("email", None, &[], Some(".com")),
+                    Not(Box::new(Equality(
+                        "display_name".to_string(),
+                        "John".to_string(),
+                    ))),
+                ]),
+                vec!["bob"],
+            ),
+        ];
+        for (filter, expected) in test_cases {
+            let users = handler
+                .list_users(Some(filter.clone()))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.into_string())
+                .collect::<Vec<_>>();
+            assert_eq!(users, expected, "filter: {:?}", filter);
+        }
+    }
+
+    #[test]
+    fn test_get_like_pattern() {
+        assert_eq!(
+            get_like_pattern(&SubStringFilter {
+                initial: Some("A_b".to_string()),
+                any: vec!["50%".to_string(), r"c\d".to_string()],
+                final_: Some("E".to_string()),
+            }),
+            r"a\_b%50\%%c\\d%e"
+        );
+        assert_eq!(get_like_pattern(&SubStringFilter::default()), "%");
+    }
+
+    /// Filters on the first name, never NULL, on the manager, NULL for the users without one, and
+    /// on the groups, which some users don't have. The groups 1 and 2 exist, the others don't.
+    fn two_attribute_filter() -> impl proptest::strategy::Strategy<Value = UserRequestFilter> {
+        use proptest::{collection::vec, prelude::*, sample::select};
+        use UserRequestFilter::*;
+        let value = || select(vec!["alice", "bob", ""]);
+        let atom = prop_oneof![
+            value().prop_map(|v| Equality("first_name".to_string(), v.to_string())),
+            value().prop_map(|v| SubString(
+                "first_name".to_string(),
+                SubStringFilter {
+                    initial: Some(v.to_string()),
+                    ..Default::default()
+                }
+            )),
+            Just(Present("first_name".to_string())),
+            value().prop_map(|v| Manager(UserId::new(v))),
+            value().prop_map(|v| Equality("manager_user_id".to_string(), v.to_string())),
+            value().prop_map(|v| SubString(
+                "manager_user_id".to_string(),
+                SubStringFilter {
+                    final_: Some(v.to_string()),
+                    ..Default::default()
+                }
+            )),
+            Just(Present("manager_user_id".to_string())),
+            select(vec!["group_a", "group_b", "group_c"]).prop_map(|g| MemberOf(g.to_string())),
+            select(vec![1, 2, 3]).prop_map(|id| MemberOfId(GroupId(id))),
+        ];
+        // No empty And/Or: they are both treated as "no filter".
+        atom.prop_recursive(3, 16, 3, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 1..3).prop_map(And),
+                vec(inner.clone(), 1..3).prop_map(Or),
+                inner.prop_map(|f| Not(Box::new(f))),
+            ]
+        })
+    }
+
+    #[test]
+    fn test_not_filter_de_morgan() {
+        use proptest::{prop_assert_eq, test_runner::TestRunner};
+        use std::collections::BTreeSet;
+        use UserRequestFilter::*;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handler = runtime.block_on(async {
+            let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+            insert_user_no_password(&handler, "alice").await;
+            insert_user_no_password(&handler, "bob").await;
+            let group_a = insert_group(&handler, "group_a").await;
+            let group_b = insert_group(&handler, "group_b").await;
+            assert_eq!((group_a, group_b), (GroupId(1), GroupId(2)));
+            insert_membership(&handler, group_b, "alice").await;
+            let mut index = 0;
+            for first_name in ["alice", "bob", ""] {
+                for manager in [Some("alice"), Some("bob"), None] {
+                    let user_id = UserId::new(&format!("user{}", index));
+                    index += 1;
+                    handler
+                        .create_user(CreateUserRequest {
+                            user_id: user_id.clone(),
+                            email: format!("{}@example.com", user_id),
+                            first_name: Some(first_name.to_string()),
+                            ..Default::default()
+                        })
+                        .await
+                        .unwrap();
+                    handler
+                        .update_user(UpdateUserRequest {
+                            user_id: user_id.clone(),
+                            manager_user_id: Some(manager.map(UserId::new)),
+                            ..Default::default()
+                        })
+                        .await
+                        .unwrap();
+                    // In group_a, in both groups, or in none.
+                    if index % 3 != 0 {
+                        insert_membership(&handler, group_a, user_id.as_str()).await;
+                    }
+                    if index % 3 == 2 {
+                        insert_membership(&handler, group_b, user_id.as_str()).await;
+                    }
+                }
+            }
+            handler
+        });
+        let list = |filter: UserRequestFilter| -> BTreeSet<String> {
+            runtime
+                .block_on(handler.list_users(Some(filter)))
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.into_string())
+                .collect()
+        };
+        let not = |filter: UserRequestFilter| Not(Box::new(filter));
+        let all_users = list(And(Vec::new()));
+        assert_eq!(all_users.len(), 11);
+        TestRunner::default()
+            .run(
+                &(two_attribute_filter(), two_attribute_filter()),
+                |(f, g)| {
+                    // The negation is the complement, including the users without a manager or
+                    // without a group.
+                    prop_assert_eq!(
+                        list(not(f.clone())),
+                        all_users.difference(&list(f.clone())).cloned().collect()
+                    );
+                    prop_assert_eq!(
+                        list(not(And(vec![f.clone(), g.clone()]))),
+                        list(Or(vec![not(f.clone()), not(g.clone())]))
+                    );
+                    prop_assert_eq!(
+                        list(not(Or(vec![f.clone(), g.clone()]))),
+                        list(And(vec![not(f), not(g)]))
+                    );
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_not_filter_matches_null() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let handler = SqlBackendHandler::new(get_default_config(), get_initialized_db().await);
+            insert_user_no_password(&handler, "alice").await;
+            insert_user_no_password(&handler, "bob").await;
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: UserId::new("bob"),
+                    manager_user_id: Some(Some(UserId::new("alice"))),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            let users = handler
+                .list_users(Some(UserRequestFilter::Not(Box::new(
+                    UserRequestFilter::Manager(UserId::new("alice")),
+                ))))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.into_string())
+                .collect::<Vec<_>>();
+            assert_eq!(users, vec!["alice"]);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_get_user_details() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "bob", "bob00").await;
+        {
+            let user = handler.get_user_details(&UserId::new("bob")).await.unwrap();
+            assert_eq!(user.user_id.as_str(), "bob");
+        }
+        {
+            handler
+                .get_user_details(&UserId::new("John"))
+                .await
+                .unwrap_err();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_search_results() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let bob = UserId::new("bob");
+        assert_eq!(
+            handler
+                .get_user_details(&bob)
+                .await
+                .unwrap()
+                .max_search_results,
+            None
+        );
+        for limit in [Some(500), None] {
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: bob.clone(),
+                    max_search_results: Some(limit),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                handler
+                    .get_user_details(&bob)
+                    .await
+                    .unwrap()
+                    .max_search_results,
+                limit
+            );
+            assert_eq!(
+                handler.list_users(None).await.unwrap()[0].max_search_results,
+                limit
+            );
+        }
+    }
+
+    async fn get_report_ids(handler: &SqlBackendHandler, manager: &str) -> Vec<String> {
+        handler
+            .get_reports(&UserId::new(manager))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.user_id.into_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_manager_and_reports() {
+        let sql_pool = get_initialized_db().await;
+        crate::infra::jwt_sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "alice").await;
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "carol").await;
+        let alice = UserId::new("alice");
+        for report in ["bob", "carol"] {
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: UserId::new(report),
+                    manager_user_id: Some(Some(alice.clone())),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            handler
+                .get_user_details(&UserId::new("bob"))
+                .await
+                .unwrap()
+                .manager_user_id,
+            Some(alice.clone())
+        );
+        assert_eq!(
+            get_report_ids(&handler, "alice").await,
+            vec!["bob", "carol"]
+        );
+        assert!(get_report_ids(&handler, "bob").await.is_empty());
+
+        // Unknown managers and cycles of one are rejected.
+        for manager in ["nobody", "bob"] {
+            assert!(matches!(
+                handler
+                    .update_user(UpdateUserRequest {
+                        user_id: UserId::new("bob"),
+                        manager_user_id: Some(Some(UserId::new(manager))),
+                        ..Default::default()
+                    })
+                    .await,
+                Err(DomainError::ValidationError(field, _)) if field == "manager"
+            ));
+        }
+
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("carol"),
+                manager_user_id: Some(None),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(get_report_ids(&handler, "alice").await, vec!["bob"]);
+
+        handler
+            .rename_user(&alice, &UserId::new("alicia"))
+            .await
+            .unwrap();
+        assert_eq!(get_report_ids(&handler, "alicia").await, vec!["bob"]);
+        handler.delete_user(&UserId::new("alicia")).await.unwrap();
+        assert_eq!(
+            handler
+                .get_user_details(&UserId::new("bob"))
+                .await
+                .unwrap()
+                .manager_user_id,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_user_by_certificate_cn() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "backup_service").await;
+        assert_eq!(
+            handler
+                .find_user_by_certificate_cn("Backup_Service")
+                .await
+                .unwrap()
+                .map(|user| user.user_id),
+            Some(UserId::new("backup_service"))
+        );
+        assert_eq!(
+            handler.find_user_by_certificate_cn("nobody").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mail_aliases() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "bob@bob.bob".to_string(),
+                mail_aliases: vec!["Postmaster@bob.bob".to_string()],
+                mail_forwarding: vec!["bob@elsewhere.com".to_string()],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        insert_user_no_password(&handler, "patrick").await;
+        {
+            let user = handler.get_user_details(&UserId::new("bob")).await.unwrap();
+            assert_eq!(user.mail_aliases, vec!["postmaster@bob.bob".to_string()]);
+            assert_eq!(user.mail_forwarding, vec!["bob@elsewhere.com".to_string()]);
+        }
+        {
+            let user = handler
+                .find_user_by_email_alias("POSTMASTER@bob.bob")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(user.user_id.as_str(), "bob");
+        }
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                mail_aliases: Some(vec![
+                    "abuse@bob.bob".to_string(),
+                    "webmaster@bob.bob".to_string(),
+                ]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            handler
+                .find_user_by_email_alias("postmaster@bob.bob")
+                .await
+                .unwrap(),
+            None
+        );
+        {
+            let users = handler.list_users(None).await.unwrap();
+            assert_eq!(
+                users
+                    .iter()
+                    .map(|u| (u.user_id.as_str(), u.mail_aliases.clone()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (
+                        "bob",
+                        vec!["abuse@bob.bob".to_string(), "webmaster@bob.bob".to_string()]
+                    ),
+                    ("patrick", vec![]),
+                ]
+            );
+            // The forwarding addresses were left untouched.
+            assert_eq!(
+                users[0].mail_forwarding,
+                vec!["bob@elsewhere.com".to_string()]
+            );
+        }
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("patrick"),
+                mail_aliases: Some(vec!["patrick@bob.bob".to_string()]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        // An alias can only belong to one user, and a failed write changes nothing.
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("patrick"),
+                mail_aliases: Some(vec!["abuse@bob.bob".to_string()]),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            handler
+                .get_user_details(&UserId::new("patrick"))
+                .await
+                .unwrap()
+                .mail_aliases,
+            vec!["patrick@bob.bob".to_string()]
+        );
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("jim"),
+                email: "jim@bob.bob".to_string(),
+                mail_aliases: vec!["abuse@bob.bob".to_string()],
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        handler
+            .get_user_details(&UserId::new("jim"))
+            .await
+            .unwrap_err();
+        let list_user_ids =
+            |filter: &str| handler.list_users(Some(UserRequestFilter::Present(filter.to_string())));
+        assert_eq!(
+            list_user_ids("mail_aliases")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.to_string())
+                .collect::<Vec<_>>(),
+            vec!["bob", "patrick"]
+        );
+        assert_eq!(
+            list_user_ids("mail_forwarding")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id.to_string())
+                .collect::<Vec<_>>(),
+            vec!["bob"]
+        );
+    }
+
+    #[test]
+    fn test_normalize_phone_number() {
+        assert_eq!(
+            normalize_phone_number("+1 (415) 555-0123", true).unwrap(),
+            "+14155550123"
+        );
+        assert_eq!(
+            normalize_phone_number(" +33.1.23.45.67.89 ", true).unwrap(),
+            "+33123456789"
+        );
+        assert!(normalize_phone_number("0123456789", true).is_err());
+        assert!(normalize_phone_number("+0123456789", true).is_err());
+        assert!(normalize_phone_number("+1 415 CALL-NOW", true).is_err());
+        assert!(normalize_phone_number("+1234567890123456", true).is_err());
+        assert!(normalize_phone_number(" ", false).is_err());
+        assert_eq!(
+            normalize_phone_number(" 01 23 45 67 89 ", false).unwrap(),
+            "01 23 45 67 89"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_login() {
+        use chrono::TimeZone;
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        let bob = UserId::new("bob");
+        let first_login = chrono::Utc.timestamp(1_600_000_000, 0);
+        let second_login = chrono::Utc.timestamp(1_600_000_100, 0);
+        handler.update_last_login(&bob, second_login).await.unwrap();
+        // An older login reported late doesn't go back in time.
+        handler.update_last_login(&bob, first_login).await.unwrap();
+        // The buffered logins are visible before they are written.
+        assert_eq!(
+            handler.get_user_details(&bob).await.unwrap().last_login_at,
+            Some(second_login)
+        );
+        let fresh_handler = SqlBackendHandler::new(get_default_config(), sql_pool.clone());
+        assert_eq!(
+            fresh_handler
+                .get_user_details(&bob)
+                .await
+                .unwrap()
+                .last_login_at,
+            None
+        );
+        // A failed flush keeps the logins for the next one.
+        sqlx::query("DROP TABLE user_logins")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        handler.flush_last_logins().await.unwrap_err();
+        init_table(&sql_pool).await.unwrap();
+        assert_eq!(handler.flush_last_logins().await.unwrap(), 1);
+        assert_eq!(handler.flush_last_logins().await.unwrap(), 0);
+        assert_eq!(
+            fresh_handler
+                .list_users(None)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| (u.user_id.into_string(), u.last_login_at))
+                .collect::<Vec<_>>(),
+            vec![
+                ("bob".to_string(), Some(second_login)),
+                ("patrick".to_string(), None)
+            ]
+        );
+
+        // Without a flush interval, the logins are written right away.
+        let config = ConfigurationBuilder::default()
+            .last_login_flush_interval_secs(0)
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        handler
+            .update_last_login(&UserId::new("patrick"), first_login)
+            .await
+            .unwrap();
+        assert_eq!(
+            fresh_handler
+                .get_user_details(&UserId::new("patrick"))
+                .await
+                .unwrap()
+                .last_login_at,
+            Some(first_login)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_report() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        insert_user_no_password(&handler, "john").await;
+        let now = {
+            use chrono::TimeZone;
+            chrono::Utc.timestamp(chrono::Utc::now().timestamp(), 0)
+        };
+        let bob = UserId::new("bob");
+        let patrick = UserId::new("patrick");
+        handler.update_last_login(&bob, now).await.unwrap();
+        handler.flush_last_logins().await.unwrap();
+        handler.update_last_login(&patrick, now).await.unwrap();
+        handler.update_last_login(&patrick, now).await.unwrap();
+        // Buffered, and added to the count of the day.
+        handler.update_last_login(&bob, now).await.unwrap();
+        handler.update_last_login(&bob, now).await.unwrap();
+        let today = now.date().naive_utc();
+        let report = |page| {
+            let handler = handler.clone();
+            async move {
+                handler
+                    .get_login_report(today, page)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|s| (s.user_id.into_string(), s.login_count))
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            report(Page {
+                offset: 0,
+                limit: 10
+            })
+            .await,
+            vec![
+                ("bob".to_string(), 3),
+                ("patrick".to_string(), 2),
+                ("john".to_string(), 0)
+            ]
+        );
+        assert_eq!(
+            report(Page {
+                offset: 1,
+                limit: 1
+            })
+            .await,
+            vec![("patrick".to_string(), 2)]
+        );
+        // The logins before the window are not counted.
+        assert_eq!(
+            handler
+                .get_login_report(
+                    today.succ(),
+                    Page {
+                        offset: 0,
+                        limit: 1
+                    }
+                )
+                .await
+                .unwrap(),
+            vec![UserLoginStats {
+                user_id: bob,
+                login_count: 0,
+                last_login_at: Some(now),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_groups() {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .default_groups(vec!["users".to_string(), "staff".to_string()])
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let users_id = insert_group(&handler, "users").await;
+        // "staff" doesn't exist, the user is not created.
+        assert!(matches!(
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::ValidationError(..))
+        ));
+        handler
+            .get_user_details(&UserId::new("bob"))
+            .await
+            .unwrap_err();
+        // Nor if it's a dynamic group, whose members can't be added.
+        let staff_id = insert_group(&handler, "staff").await;
+        set_dynamic_filter(&handler, staff_id, Some("(uid=alice)"))
             .await
             .unwrap();
+        assert!(matches!(
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::ValidationError(..))
+        ));
+        handler
+            .get_user_details(&UserId::new("bob"))
+            .await
+            .unwrap_err();
+        handler.delete_group(staff_id).await.unwrap();
+
+        let mut config = config;
+        config.create_missing_default_groups = true;
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        let staff_id = handler
+            .list_groups(Some(GroupRequestFilter::DisplayName("staff".to_string())))
+            .await
+            .unwrap()[0]
+            .id;
+        for user in ["bob", "patrick"] {
+            assert_eq!(
+                handler.get_user_groups(&UserId::new(user)).await.unwrap(),
+                HashSet::from([
+                    GroupIdAndName(users_id, "users".to_string()),
+                    GroupIdAndName(staff_id, "staff".to_string()),
+                ])
+            );
+        }
     }
 
-    async fn insert_user_no_password(handler: &SqlBackendHandler, name: &str) {
+    #[tokio::test]
+    async fn test_phone_numbers() {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .phone_validation(true)
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let bob = UserId::new("bob");
         handler
             .create_user(CreateUserRequest {
-                user_id: UserId::new(name),
+                user_id: bob.clone(),
                 email: "bob@bob.bob".to_string(),
+                phone_numbers: vec!["+1 415 555 0123".to_string(), "+14155550123".to_string()],
                 ..Default::default()
             })
             .await
             .unwrap();
-    }
-
-    async fn insert_group(handler: &SqlBackendHandler, name: &str) -> GroupId {
-        handler.create_group(name).await.unwrap()
-    }
+        let get_phone_numbers = || async {
+            handler
+                .get_user_details(&UserId::new("bob"))
+                .await
+                .unwrap()
+                .phone_numbers
+        };
+        assert_eq!(get_phone_numbers().await, vec!["+14155550123".to_string()]);
 
-    async fn insert_membership(handler: &SqlBackendHandler, group_id: GroupId, user_id: &str) {
         handler
-            .add_user_to_group(&UserId::new(user_id), group_id)
+            .add_phone_number(&bob, "+33 1 23 45 67 89")
             .await
             .unwrap();
-    }
+        // Adding the same number again is a no-op.
+        handler
+            .add_phone_number(&bob, "+33123456789")
+            .await
+            .unwrap();
+        assert_eq!(
+            get_phone_numbers().await,
+            vec!["+14155550123".to_string(), "+33123456789".to_string()]
+        );
 
-    #[tokio::test]
-    async fn test_bind_admin() {
-        let sql_pool = get_in_memory_db().await;
-        let config = ConfigurationBuilder::default()
-            .ldap_user_dn(UserId::new("admin"))
-            .ldap_user_pass(secstr::SecUtf8::from("test"))
-            .build()
+        handler
+            .remove_phone_number(&bob, "+1 (415) 555-0123")
+            .await
             .unwrap();
-        let handler = SqlBackendHandler::new(config, sql_pool);
+        assert_eq!(get_phone_numbers().await, vec!["+33123456789".to_string()]);
+
         handler
-            .bind(BindRequest {
-                name: UserId::new("admin"),
-                password: "test".to_string(),
+            .add_phone_number(&bob, "555-0123")
+            .await
+            .unwrap_err();
+        // The numbers are left untouched when one of them is invalid.
+        handler
+            .set_phone_numbers(&bob, vec!["+442071838750".to_string(), "nope".to_string()])
+            .await
+            .unwrap_err();
+        assert_eq!(get_phone_numbers().await, vec!["+33123456789".to_string()]);
+
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: bob.clone(),
+                phone_numbers: Some(vec!["+44 20 7183 8750".to_string()]),
+                ..Default::default()
             })
             .await
             .unwrap();
+        assert_eq!(get_phone_numbers().await, vec!["+442071838750".to_string()]);
+
+        handler.set_phone_numbers(&bob, vec![]).await.unwrap();
+        assert_eq!(get_phone_numbers().await, Vec::<String>::new());
     }
 
     #[tokio::test]
-    async fn test_bind_user() {
+    async fn test_user_lowercase() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        insert_user(&handler, "Bob", "bob00").await;
+        {
+            let user = handler.get_user_details(&UserId::new("bOb")).await.unwrap();
+            assert_eq!(user.user_id.as_str(), "bob");
+        }
+        {
+            handler
+                .get_user_details(&UserId::new("John"))
+                .await
+                .unwrap_err();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_user_groups() {
         let sql_pool = get_initialized_db().await;
         let config = get_default_config();
         let handler = SqlBackendHandler::new(config, sql_pool.clone());
         insert_user(&handler, "bob", "bob00").await;
+        insert_user(&handler, "patrick", "pass").await;
+        insert_user(&handler, "John", "Pa33w0rd!").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        insert_membership(&handler, group_1, "bob").await;
+        insert_membership(&handler, group_1, "patrick").await;
+        insert_membership(&handler, group_2, "patrick").await;
+        let mut bob_groups = HashSet::new();
+        bob_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
+        let mut patrick_groups = HashSet::new();
+        patrick_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
+        patrick_groups.insert(GroupIdAndName(group_2, "Group2".to_string()));
+        assert_eq!(
+            handler.get_user_groups(&UserId::new("bob")).await.unwrap(),
+            bob_groups
+        );
+        assert_eq!(
+            handler
+                .get_user_groups(&UserId::new("patrick"))
+                .await
+                .unwrap(),
+            patrick_groups
+        );
+        assert_eq!(
+            handler.get_user_groups(&UserId::new("John")).await.unwrap(),
+            HashSet::new()
+        );
+        let mut expected = HashMap::new();
+        expected.insert(UserId::new("bob"), bob_groups);
+        expected.insert(UserId::new("patrick"), patrick_groups);
+        expected.insert(UserId::new("John"), HashSet::new());
+        assert_eq!(
+            handler
+                .get_groups_for_users(&[
+                    UserId::new("bob"),
+                    UserId::new("patrick"),
+                    UserId::new("John")
+                ])
+                .await
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            handler.get_groups_for_users(&[]).await.unwrap(),
+            HashMap::new()
+        );
+    }
 
-        handler
-            .bind(BindRequest {
-                name: UserId::new("bob"),
-                password: "bob00".to_string(),
+    #[tokio::test]
+    async fn test_data_version() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        let version = handler.data_version();
+        insert_user_no_password(&handler, "bob").await;
+        assert_ne!(handler.data_version(), version);
+        let version = handler.data_version();
+        handler.list_users(None).await.unwrap();
+        assert_eq!(handler.data_version(), version);
+        // Bumped by the write in the transaction, then again once it's committed.
+        let version = handler.data_version();
+        let version_in_transaction = handler
+            .transaction(|txn| {
+                Box::pin(async move {
+                    txn.create_group("Group2").await?;
+                    Ok(txn.handler.data_version())
+                })
             })
             .await
             .unwrap();
+        assert_ne!(version_in_transaction, version);
+        assert_ne!(handler.data_version(), version_in_transaction);
+        // The logins are shown in the lastLogon attribute, even when they are buffered.
+        let version = handler.data_version();
         handler
-            .bind(BindRequest {
-                name: UserId::new("andrew"),
-                password: "bob00".to_string(),
-            })
+            .update_last_login(&UserId::new("bob"), chrono::Utc::now())
             .await
-            .unwrap_err();
+            .unwrap();
+        assert_ne!(handler.data_version(), version);
+    }
+
+    #[tokio::test]
+    async fn test_membership_cache() {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .membership_cache_ttl_secs(60)
+            .build()
+            .unwrap();
+        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        insert_user(&handler, "bob", "bob00").await;
+        let group_1 = insert_group(&handler, "Group1").await;
+        let group_2 = insert_group(&handler, "Group2").await;
+        insert_membership(&handler, group_1, "bob").await;
+        let bob = UserId::new("bob");
+        let get_group_names = |groups: HashSet<GroupIdAndName>| {
+            let mut names = groups.into_iter().map(|g| g.1).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            get_group_names(handler.get_user_groups(&bob).await.unwrap()),
+            vec!["Group1"]
+        );
+        // A change outside of the handler is only visible after a flush.
+        let query = Query::insert()
+            .into_table(Memberships::Table)
+            .columns(vec![Memberships::UserId, Memberships::GroupId])
+            .values_panic(vec![(&bob).into(), group_2.into()])
+            .to_string(DbQueryBuilder {});
+        sqlx::query(&query).execute(&sql_pool).await.unwrap();
+        assert_eq!(
+            get_group_names(handler.get_user_groups(&bob).await.unwrap()),
+            vec!["Group1"]
+        );
+        assert_eq!(
+            get_group_names(
+                handler
+                    .get_groups_for_users(std::slice::from_ref(&bob))
+                    .await
+                    .unwrap()
+                    .remove(&bob)
+                    .unwrap()
+            ),
+            vec!["Group1"]
+        );
+        handler.clear_membership_cache();
+        assert_eq!(
+            get_group_names(handler.get_user_groups(&bob).await.unwrap()),
+            vec!["Group1", "Group2"]
+        );
+        // The changes made through the handler are visible immediately.
+        handler.remove_user_from_group(&bob, group_1).await.unwrap();
+        assert_eq!(
+            get_group_names(handler.get_user_groups(&bob).await.unwrap()),
+            vec!["Group2"]
+        );
         handler
-            .bind(BindRequest {
-                name: UserId::new("bob"),
-                password: "wrong_password".to_string(),
+            .update_group(UpdateGroupRequest {
+                group_id: group_2,
+                display_name: Some("Renamed".to_string()),
+                description: None,
+                dynamic_filter: None,
             })
             .await
-            .unwrap_err();
+            .unwrap();
+        assert_eq!(
+            get_group_names(
+                handler
+                    .get_groups_for_users(std::slice::from_ref(&bob))
+                    .await
+                    .unwrap()
+                    .remove(&bob)
+                    .unwrap()
+            ),
+            vec!["Renamed"]
+        );
     }
 
     #[tokio::test]
-    async fn test_user_no_password() {
+    async fn test_transaction() {
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
-        insert_user_no_password(&handler, "bob").await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        let group_id = handler
+            .transaction(|txn| {
+                Box::pin(async move {
+                    txn.create_user(CreateUserRequest {
+                        user_id: UserId::new("bob"),
+                        email: "bob@bob.bob".to_string(),
+                        ..Default::default()
+                    })
+                    .await?;
+                    let group_id = txn.create_group("Group1").await?;
+                    txn.add_user_to_group(&UserId::new("bob"), group_id).await?;
+                    // The transaction sees its own changes.
+                    assert_eq!(
+                        txn.get_user_groups(&UserId::new("bob")).await?,
+                        [GroupIdAndName(group_id, "Group1".to_string())]
+                            .into_iter()
+                            .collect()
+                    );
+                    Ok(group_id)
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            handler.get_user_groups(&UserId::new("bob")).await.unwrap(),
+            [GroupIdAndName(group_id, "Group1".to_string())]
+                .into_iter()
+                .collect()
+        );
+        // A failure rolls back all the changes of the transaction.
+        let result = handler
+            .transaction(|txn| {
+                Box::pin(async move {
+                    txn.create_user(CreateUserRequest {
+                        user_id: UserId::new("jim"),
+                        email: "jim@bob.bob".to_string(),
+                        ..Default::default()
+                    })
+                    .await?;
+                    txn.delete_group(group_id).await?;
+                    txn.create_user(CreateUserRequest {
+                        user_id: UserId::new("patrick"),
+                        email: "bob@bob.bob".to_string(),
+                        ..Default::default()
+                    })
+                    .await
+                })
+            })
+            .await;
+        assert!(matches!(result, Err(DomainError::ConstraintViolation(_))));
+        assert!(handler.get_user_details(&UserId::new("jim")).await.is_err());
+        assert_eq!(
+            handler.get_user_groups(&UserId::new("bob")).await.unwrap(),
+            [GroupIdAndName(group_id, "Group1".to_string())]
+                .into_iter()
+                .collect()
+        );
+    }
 
-        handler
-            .bind(BindRequest {
-                name: UserId::new("bob"),
-                password: "bob00".to_string(),
+    #[tokio::test]
+    async fn test_transaction_rollback_membership_cache() {
+        let sql_pool = get_initialized_db().await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        insert_user_no_password(&handler, "bob").await;
+        let group_id = insert_group(&handler, "Group1").await;
+        // Cache the groups of bob.
+        assert!(handler
+            .get_user_groups(&UserId::new("bob"))
+            .await
+            .unwrap()
+            .is_empty());
+        let result: Result<()> = handler
+            .transaction(|txn| {
+                Box::pin(async move {
+                    txn.add_user_to_group(&UserId::new("bob"), group_id).await?;
+                    assert_eq!(txn.get_user_groups(&UserId::new("bob")).await?.len(), 1);
+                    Err(DomainError::InternalError("abort".to_string()))
+                })
             })
+            .await;
+        result.unwrap_err();
+        // Neither the database nor the cache keep the rolled back membership.
+        assert!(handler
+            .get_user_groups(&UserId::new("bob"))
             .await
-            .unwrap_err();
+            .unwrap()
+            .is_empty());
+        assert!(handler
+            .get_groups_for_users(&[UserId::new("bob")])
+            .await
+            .unwrap()
+            .get(&UserId::new("bob"))
+            .map_or(true, HashSet::is_empty));
+    }
+
+    #[test]
+    fn test_email_domain_matches() {
+        assert!(email_domain_matches("example.com", "example.com"));
+        assert!(!email_domain_matches("example.com", "sub.example.com"));
+        assert!(email_domain_matches("*.example.com", "sub.example.com"));
+        assert!(email_domain_matches("*.example.com", "a.sub.example.com"));
+        assert!(!email_domain_matches("*.example.com", "example.com"));
+        assert!(!email_domain_matches("*.example.com", "badexample.com"));
     }
 
     #[tokio::test]
-    async fn test_list_users() {
+    async fn test_all_users_group() {
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
+        let config = ConfigurationBuilder::default()
+            .all_users_group(Some("everyone".to_string()))
+            .build()
+            .unwrap();
         let handler = SqlBackendHandler::new(config, sql_pool);
-        insert_user(&handler, "bob", "bob00").await;
-        insert_user(&handler, "patrick", "pass").await;
-        insert_user(&handler, "John", "Pa33w0rd!").await;
-        {
-            let users = handler
-                .list_users(None)
-                .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id.to_string())
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["bob", "john", "patrick"]);
-        }
-        {
-            let users = handler
-                .list_users(Some(UserRequestFilter::UserId(UserId::new("bob"))))
-                .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id.to_string())
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["bob"]);
-        }
-        {
-            let users = handler
-                .list_users(Some(UserRequestFilter::Or(vec![
-                    UserRequestFilter::UserId(UserId::new("bob")),
-                    UserRequestFilter::UserId(UserId::new("John")),
-                ])))
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        let group_id = insert_group(&handler, "Best Group").await;
+        insert_membership(&handler, group_id, "bob").await;
+        let everyone = GroupIdAndName(ALL_USERS_GROUP_ID, "everyone".to_string());
+
+        let groups = handler.list_groups(None).await.unwrap();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (g.display_name.as_str(), g.users.len()))
+                .collect::<Vec<_>>(),
+            vec![("Best Group", 1), ("everyone", 2)]
+        );
+        assert_eq!(
+            handler
+                .list_groups(Some(GroupRequestFilter::DisplayName(
+                    "everyone".to_string()
+                )))
                 .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id.to_string())
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["bob", "john"]);
-        }
-        {
-            let users = handler
-                .list_users(Some(UserRequestFilter::Not(Box::new(
-                    UserRequestFilter::UserId(UserId::new("bob")),
-                ))))
+                .unwrap()[0]
+                .users,
+            vec![UserId::new("bob"), UserId::new("patrick")]
+        );
+        assert!(handler
+            .list_groups(Some(GroupRequestFilter::Not(Box::new(
+                GroupRequestFilter::Member(UserId::new("patrick"))
+            ))))
+            .await
+            .unwrap()
+            .iter()
+            .all(|g| g.id != ALL_USERS_GROUP_ID));
+        assert_eq!(handler.count_groups(None).await.unwrap(), 2);
+        assert_eq!(
+            handler.get_group_details(ALL_USERS_GROUP_ID).await.unwrap(),
+            everyone
+        );
+
+        assert_eq!(
+            handler
+                .get_user_groups(&UserId::new("patrick"))
                 .await
-                .unwrap()
-                .into_iter()
-                .map(|u| u.user_id.to_string())
-                .collect::<Vec<_>>();
-            assert_eq!(users, vec!["john", "patrick"]);
+                .unwrap(),
+            HashSet::from([everyone.clone()])
+        );
+        let groups_for_users = handler
+            .get_groups_for_users(&[UserId::new("bob"), UserId::new("patrick")])
+            .await
+            .unwrap();
+        assert_eq!(groups_for_users[&UserId::new("bob")].len(), 2);
+        assert!(groups_for_users[&UserId::new("patrick")].contains(&everyone));
+
+        for filter in [
+            UserRequestFilter::MemberOf("everyone".to_string()),
+            UserRequestFilter::MemberOfId(ALL_USERS_GROUP_ID),
+            UserRequestFilter::MemberOfAnyGroup,
+        ] {
+            assert_eq!(handler.count_users(Some(filter)).await.unwrap(), 2);
         }
+        assert!(handler
+            .list_users(Some(UserRequestFilter::Not(Box::new(
+                UserRequestFilter::MemberOf("everyone".to_string())
+            ))))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The membership is computed, it can't be changed.
+        handler
+            .add_user_to_group(&UserId::new("bob"), ALL_USERS_GROUP_ID)
+            .await
+            .unwrap_err();
+        handler.delete_group(ALL_USERS_GROUP_ID).await.unwrap_err();
+        handler.create_group("everyone").await.unwrap_err();
+        handler
+            .update_group(UpdateGroupRequest {
+                group_id,
+                display_name: Some("everyone".to_string()),
+                description: None,
+                dynamic_filter: None,
+            })
+            .await
+            .unwrap_err();
+    }
+
+    async fn set_dynamic_filter(
+        handler: &SqlBackendHandler,
+        group_id: GroupId,
+        filter: Option<&str>,
+    ) -> Result<()> {
+        handler
+            .update_group(UpdateGroupRequest {
+                group_id,
+                display_name: None,
+                description: None,
+                dynamic_filter: Some(filter.map(str::to_string)),
+            })
+            .await
     }
 
     #[tokio::test]
-    async fn test_list_groups() {
+    async fn test_dynamic_groups() {
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
-        insert_user(&handler, "bob", "bob00").await;
-        insert_user(&handler, "patrick", "pass").await;
-        insert_user(&handler, "John", "Pa33w0rd!").await;
-        let group_1 = insert_group(&handler, "Best Group").await;
-        let group_2 = insert_group(&handler, "Worst Group").await;
-        let group_3 = insert_group(&handler, "Empty Group").await;
-        insert_membership(&handler, group_1, "bob").await;
-        insert_membership(&handler, group_1, "patrick").await;
-        insert_membership(&handler, group_2, "patrick").await;
-        insert_membership(&handler, group_2, "John").await;
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
+        for (name, last_name) in [("bob", "Smith"), ("patrick", "Smith"), ("john", "Doe")] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(name),
+                    email: format!("{}@bob.bob", name),
+                    last_name: Some(last_name.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        let static_group = insert_group(&handler, "static").await;
+        insert_membership(&handler, static_group, "bob").await;
+        let smiths = insert_group(&handler, "smiths").await;
+        insert_membership(&handler, smiths, "john").await;
+        set_dynamic_filter(&handler, smiths, Some("(sn=Smith)"))
+            .await
+            .unwrap();
+        let others = insert_group(&handler, "others").await;
+        set_dynamic_filter(
+            &handler,
+            others,
+            Some("(!(memberOf=cn=smiths,ou=groups,dc=example,dc=com))"),
+        )
+        .await
+        .unwrap();
+        // The filter must be a valid LDAP filter on the users.
+        for filter in ["(sn=Smith", "(memberOf=cn=smiths)"] {
+            assert!(matches!(
+                set_dynamic_filter(&handler, static_group, Some(filter)).await,
+                Err(DomainError::ValidationError(_, _))
+            ));
+        }
+
+        // The stored members of the group are replaced by the users matching the filter.
+        let groups = handler.list_groups(None).await.unwrap();
         assert_eq!(
-            handler.list_groups(None).await.unwrap(),
+            groups
+                .iter()
+                .map(|g| (g.display_name.as_str(), g.users.clone()))
+                .collect::<Vec<_>>(),
             vec![
-                Group {
-                    id: group_1,
-                    display_name: "Best Group".to_string(),
-                    users: vec![UserId::new("bob"), UserId::new("patrick")]
-                },
-                Group {
-                    id: group_3,
-                    display_name: "Empty Group".to_string(),
-                    users: vec![]
-                },
-                Group {
-                    id: group_2,
-                    display_name: "Worst Group".to_string(),
-                    users: vec![UserId::new("john"), UserId::new("patrick")]
-                },
+                ("others", vec![UserId::new("john")]),
+                ("smiths", vec![UserId::new("bob"), UserId::new("patrick")]),
+                ("static", vec![UserId::new("bob")]),
             ]
         );
         assert_eq!(
             handler
-                .list_groups(Some(GroupRequestFilter::Or(vec![
-                    GroupRequestFilter::DisplayName("Empty Group".to_string()),
-                    GroupRequestFilter::Member(UserId::new("bob")),
-                ])))
+                .list_groups(Some(GroupRequestFilter::Member(UserId::new("patrick"))))
                 .await
-                .unwrap(),
-            vec![
-                Group {
-                    id: group_1,
-                    display_name: "Best Group".to_string(),
-                    users: vec![UserId::new("bob"), UserId::new("patrick")]
-                },
-                Group {
-                    id: group_3,
-                    display_name: "Empty Group".to_string(),
-                    users: vec![]
-                },
-            ]
+                .unwrap()
+                .into_iter()
+                .map(|g| g.id)
+                .collect::<Vec<_>>(),
+            vec![smiths]
         );
+        assert_eq!(handler.count_groups(None).await.unwrap(), 3);
         assert_eq!(
             handler
-                .list_groups(Some(GroupRequestFilter::And(vec![
-                    GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
-                        "value".to_string()
-                    ))),
-                    GroupRequestFilter::GroupId(group_1),
-                ])))
+                .count_groups(Some(GroupRequestFilter::DisplayName("smiths".to_string())))
                 .await
                 .unwrap(),
-            vec![Group {
-                id: group_1,
-                display_name: "Best Group".to_string(),
-                users: vec![UserId::new("bob"), UserId::new("patrick")]
-            }]
+            1
+        );
+        assert_eq!(
+            handler.get_user_groups(&UserId::new("bob")).await.unwrap(),
+            HashSet::from([
+                GroupIdAndName(static_group, "static".to_string()),
+                GroupIdAndName(smiths, "smiths".to_string()),
+            ])
+        );
+        let groups_for_users = handler
+            .get_groups_for_users(&[UserId::new("patrick"), UserId::new("john")])
+            .await
+            .unwrap();
+        assert_eq!(
+            groups_for_users[&UserId::new("john")],
+            HashSet::from([GroupIdAndName(others, "others".to_string())])
+        );
+        assert_eq!(
+            handler
+                .list_users(Some(UserRequestFilter::MemberOfId(smiths)))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|u| u.user_id)
+                .collect::<Vec<_>>(),
+            vec![UserId::new("bob"), UserId::new("patrick")]
+        );
+
+        // The members are computed, and the groups can't contain themselves.
+        handler
+            .add_user_to_group(&UserId::new("john"), smiths)
+            .await
+            .unwrap_err();
+        set_dynamic_filter(
+            &handler,
+            smiths,
+            Some("(memberOf=cn=others,ou=groups,dc=example,dc=com)"),
+        )
+        .await
+        .unwrap_err();
+        handler
+            .update_group(UpdateGroupRequest {
+                group_id: static_group,
+                display_name: Some("smiths_2".to_string()),
+                description: None,
+                dynamic_filter: Some(Some(
+                    "(memberOf=cn=others,ou=groups,dc=example,dc=com)".to_string(),
+                )),
+            })
+            .await
+            .unwrap();
+
+        // A regular group again, without members.
+        set_dynamic_filter(&handler, smiths, None).await.unwrap();
+        assert_eq!(
+            handler
+                .list_users(Some(UserRequestFilter::MemberOf("others".to_string())))
+                .await
+                .unwrap()
+                .len(),
+            3
         );
     }
 
     #[tokio::test]
-    async fn test_get_user_details() {
+    async fn test_email_domain_lists() {
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
+        let config = ConfigurationBuilder::default()
+            .allowed_email_domains(Some(vec![
+                "Example.com".to_string(),
+                "*.example.com".to_string(),
+            ]))
+            .blocked_email_domains(Some(vec!["spam.example.com".to_string()]))
+            .build()
+            .unwrap();
         let handler = SqlBackendHandler::new(config, sql_pool);
-        insert_user(&handler, "bob", "bob00").await;
-        {
-            let user = handler.get_user_details(&UserId::new("bob")).await.unwrap();
-            assert_eq!(user.user_id.as_str(), "bob");
+        let create_user = |user_id: &str, email: &str| {
+            handler.create_user(CreateUserRequest {
+                user_id: UserId::new(user_id),
+                email: email.to_string(),
+                ..Default::default()
+            })
+        };
+        create_user("bob", "bob@EXAMPLE.com").await.unwrap();
+        create_user("patrick", "patrick@sub.example.com")
+            .await
+            .unwrap();
+        create_user("admin", "").await.unwrap();
+        for email in ["john@gmail.com", "john@spam.example.com", "john"] {
+            assert_eq!(
+                create_user("john", email).await.unwrap_err().to_string(),
+                "Invalid email: domain not allowed",
+                "{}",
+                email
+            );
         }
-        {
+        assert!(matches!(
             handler
-                .get_user_details(&UserId::new("John"))
+                .update_user(UpdateUserRequest {
+                    user_id: UserId::new("bob"),
+                    email: Some("bob@gmail.com".to_string()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::ValidationError(_, _))
+        ));
+        assert_eq!(
+            handler
+                .get_user_details(&UserId::new("bob"))
                 .await
-                .unwrap_err();
-        }
+                .unwrap()
+                .email,
+            "bob@EXAMPLE.com"
+        );
     }
 
     #[tokio::test]
-    async fn test_user_lowercase() {
+    async fn test_unique_attributes() {
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
+        let config = ConfigurationBuilder::default()
+            .unique_ldap_attributes(vec!["displayName".to_string()])
+            .build()
+            .unwrap();
         let handler = SqlBackendHandler::new(config, sql_pool);
-        insert_user(&handler, "Bob", "bob00").await;
-        {
-            let user = handler.get_user_details(&UserId::new("bOb")).await.unwrap();
-            assert_eq!(user.user_id.as_str(), "bob");
-        }
-        {
+        let create_user = |user_id: &str, email: &str, display_name: &str| {
+            handler.create_user(CreateUserRequest {
+                user_id: UserId::new(user_id),
+                email: email.to_string(),
+                display_name: Some(display_name.to_string()),
+                ..Default::default()
+            })
+        };
+        create_user("bob", "bob@bob.bob", "Bob").await.unwrap();
+        create_user("admin", "", "").await.unwrap();
+        create_user("service", "", "").await.unwrap();
+        assert_eq!(
+            create_user("bob2", "bob@bob.bob", "Bob 2")
+                .await
+                .unwrap_err()
+                .to_string(),
+            "Constraint violation: another user has the same mail"
+        );
+        // The emails are compared case-insensitively, by the unique index.
+        assert!(matches!(
+            create_user("bob2", "BOB@bob.bob", "Bob 2").await,
+            Err(DomainError::ConstraintViolation(attribute)) if attribute == "mail"
+        ));
+        assert!(matches!(
             handler
-                .get_user_details(&UserId::new("John"))
+                .update_user(UpdateUserRequest {
+                    user_id: UserId::new("admin"),
+                    email: Some("Bob@Bob.Bob".to_string()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::ConstraintViolation(attribute)) if attribute == "mail"
+        ));
+        assert_eq!(
+            create_user("bob2", "bob2@bob.bob", "Bob")
                 .await
-                .unwrap_err();
-        }
+                .unwrap_err()
+                .to_string(),
+            "Constraint violation: another user has the same displayName"
+        );
+        // Setting a user's own value again is fine.
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                email: Some("bob@bob.bob".to_string()),
+                display_name: Some("Bob".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler
+                .update_user(UpdateUserRequest {
+                    user_id: UserId::new("admin"),
+                    display_name: Some("Bob".to_string()),
+                    ..Default::default()
+                })
+                .await,
+            Err(DomainError::ConstraintViolation(attribute)) if attribute == "displayName"
+        ));
     }
 
     #[tokio::test]
-    async fn test_get_user_groups() {
+    async fn test_password_modified_date() {
         let sql_pool = get_initialized_db().await;
-        let config = get_default_config();
-        let handler = SqlBackendHandler::new(config, sql_pool.clone());
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool);
         insert_user(&handler, "bob", "bob00").await;
-        insert_user(&handler, "patrick", "pass").await;
-        insert_user(&handler, "John", "Pa33w0rd!").await;
-        let group_1 = insert_group(&handler, "Group1").await;
-        let group_2 = insert_group(&handler, "Group2").await;
-        insert_membership(&handler, group_1, "bob").await;
-        insert_membership(&handler, group_1, "patrick").await;
-        insert_membership(&handler, group_2, "patrick").await;
-        let mut bob_groups = HashSet::new();
-        bob_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
-        let mut patrick_groups = HashSet::new();
-        patrick_groups.insert(GroupIdAndName(group_1, "Group1".to_string()));
-        patrick_groups.insert(GroupIdAndName(group_2, "Group2".to_string()));
-        assert_eq!(
-            handler.get_user_groups(&UserId::new("bob")).await.unwrap(),
-            bob_groups
-        );
+        insert_user_no_password(&handler, "patrick").await;
+        let bob = handler.get_user_details(&UserId::new("bob")).await.unwrap();
+        let modified = bob.password_modified_date.unwrap();
+        assert!(chrono::Utc::now() - modified < chrono::Duration::minutes(1));
         assert_eq!(
             handler
-                .get_user_groups(&UserId::new("patrick"))
+                .get_user_details(&UserId::new("patrick"))
                 .await
-                .unwrap(),
-            patrick_groups
+                .unwrap()
+                .password_modified_date,
+            None
         );
+        let dates = handler
+            .list_users(None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| (u.user_id.to_string(), u.password_modified_date))
+            .collect::<Vec<_>>();
         assert_eq!(
-            handler.get_user_groups(&UserId::new("John")).await.unwrap(),
-            HashSet::new()
+            dates,
+            vec![
+                ("bob".to_string(), Some(modified)),
+                ("patrick".to_string(), None)
+            ]
         );
     }
 
+    #[tokio::test]
+    async fn test_rename_user() {
+        use crate::infra::tcp_backend_handler::TcpBackendHandler;
+        let sql_pool = get_initialized_db().await;
+        crate::infra::jwt_sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
+        let handler = SqlBackendHandler::new(get_default_config(), sql_pool.clone());
+        insert_user_no_password(&handler, "bob").await;
+        insert_user_no_password(&handler, "patrick").await;
+        handler
+            .create_refresh_token(&UserId::new("bob"))
+            .await
+            .unwrap();
+        handler
+            .create_refresh_token(&UserId::new("patrick"))
+            .await
+            .unwrap();
+        let group_id = insert_group(&handler, "Best Group").await;
+        insert_membership(&handler, group_id, "bob").await;
+        handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                mail_aliases: Some(vec!["bobby@bob.bob".to_string()]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        handler
+            .rename_user(&UserId::new("bob"), &UserId::new("robert"))
+            .await
+            .unwrap();
+
+        let user = handler
+            .get_user_details(&UserId::new("robert"))
+            .await
+            .unwrap();
+        assert_eq!(user.email, "bob@bob.bob");
+        assert_eq!(user.mail_aliases, vec!["bobby@bob.bob".to_string()]);
+        assert!(handler.get_user_details(&UserId::new("bob")).await.is_err());
+        let groups = handler.list_groups(None).await.unwrap();
+        assert_eq!(groups[0].users, vec![UserId::new("robert")]);
+        // Only the refresh tokens of the renamed user are revoked.
+        let refresh_token_users = sqlx::query("SELECT user_id FROM jwt_refresh_storage")
+            .map(|row: DbRow| row.get::<String, _>(0))
+            .fetch_all(&sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(refresh_token_users, vec!["patrick".to_string()]);
+        assert!(matches!(
+            handler
+                .rename_user(&UserId::new("robert"), &UserId::new("patrick"))
+                .await,
+            Err(DomainError::ConstraintViolation(attribute)) if attribute == "uid"
+        ));
+    }
+
     #[tokio::test]
     async fn test_delete_user() {
         let sql_pool = get_initialized_db().await;
@@ -759,9 +4650,14 @@ mod tests {
         insert_user(&handler, "val", "s3np4i").await;
         insert_user(&handler, "Hector", "Be$t").await;
         insert_user(&handler, "Jennz", "boupBoup").await;
+        let group_id = insert_group(&handler, "Best Group").await;
+        insert_membership(&handler, group_id, "jennz").await;
+        insert_membership(&handler, group_id, "val").await;
 
         // Remove a user
         let _request_result = handler.delete_user(&UserId::new("Jennz")).await.unwrap();
+        let groups = handler.list_groups(None).await.unwrap();
+        assert_eq!(groups[0].users, vec![UserId::new("val")]);
 
         let users = handler
             .list_users(None)
@@ -788,4 +4684,57 @@ mod tests {
 
         assert_eq!(users, vec!["val"]);
     }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let slow_query = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(())
+        };
+        let error = with_query_timeout(Duration::from_millis(10), "SELECT 1", slow_query)
+            .await
+            .unwrap_err();
+        assert!(error.is_query_timeout());
+        assert_eq!(
+            error.to_string(),
+            "Timeout: the database query didn't finish in time"
+        );
+        with_query_timeout(Duration::from_millis(0), "SELECT 1", async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invitations() {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let handler = SqlBackendHandler::new(config, sql_pool);
+        let invitation = handler.create_invitation("bob@bob.bob").await.unwrap();
+        assert_eq!(invitation.email, "bob@bob.bob");
+        assert_eq!(invitation.token.len(), 100);
+        assert!(invitation.is_pending());
+        assert_eq!(
+            handler.get_invitation(&invitation.token).await.unwrap(),
+            invitation
+        );
+        assert_eq!(
+            handler.list_pending_invitations().await.unwrap(),
+            vec![invitation.clone()]
+        );
+        assert!(handler.mark_invitation_used(invitation.id).await.unwrap());
+        // Only once, e.g. for concurrent acceptances.
+        assert!(!handler.mark_invitation_used(invitation.id).await.unwrap());
+        assert!(
+            handler
+                .get_invitation(&invitation.token)
+                .await
+                .unwrap()
+                .used
+        );
+        assert_eq!(handler.list_pending_invitations().await.unwrap(), vec![]);
+        handler.get_invitation("unknown").await.unwrap_err();
+    }
 }
@@ -0,0 +1,119 @@
+use super::sql_tables::Pool;
+use log::{debug, info, warn};
+use sqlx::Row;
+use std::time::Instant;
+
+/// A lookup run by most searches, and the index that avoids reading the whole table for it.
+struct IndexedQuery {
+    description: &'static str,
+    query: &'static str,
+    create_index: &'static str,
+}
+
+const INDEXED_QUERIES: &[IndexedQuery] = &[
+    IndexedQuery {
+        description: "users by email",
+        query: "SELECT user_id FROM users WHERE email = 'bob@example.com'",
+        create_index: "CREATE INDEX IF NOT EXISTS users_email ON users (email)",
+    },
+    IndexedQuery {
+        description: "users by uid",
+        query: "SELECT email FROM users WHERE user_id = 'bob'",
+        create_index: "CREATE UNIQUE INDEX IF NOT EXISTS users_user_id ON users (user_id)",
+    },
+    IndexedQuery {
+        description: "groups of a user",
+        query: "SELECT group_id FROM memberships WHERE user_id = 'bob'",
+        create_index: "CREATE INDEX IF NOT EXISTS memberships_user_id ON memberships (user_id)",
+    },
+    IndexedQuery {
+        description: "members of a group",
+        query: "SELECT user_id FROM memberships WHERE group_id = 1",
+        create_index: "CREATE INDEX IF NOT EXISTS memberships_group_id ON memberships (group_id)",
+    },
+];
+
+/// Whether a line of `EXPLAIN QUERY PLAN` reads the whole table, e.g. "SCAN users" or
+/// "SCAN TABLE users" depending on the SQLite version, instead of "SEARCH users USING INDEX".
+fn is_full_scan(plan_detail: &str) -> bool {
+    plan_detail.starts_with("SCAN ")
+}
+
+async fn uses_full_scan(pool: &Pool, query: &str) -> sqlx::Result<bool> {
+    Ok(sqlx::query(&format!("EXPLAIN QUERY PLAN {}", query))
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .any(|row| is_full_scan(&row.get::<String, _>("detail"))))
+}
+
+/// Checks that the common lookups use an index, and logs the `CREATE INDEX` statements for the
+/// ones that don't. The indexes are created if `auto_create_indexes` is set. Returns the
+/// statements of the indexes that are still missing.
+///
+/// The query plans only depend on the schema, so this is fast even on a large database.
+pub async fn check_indexes(
+    pool: &Pool,
+    auto_create_indexes: bool,
+) -> sqlx::Result<Vec<&'static str>> {
+    let start = Instant::now();
+    let mut missing_indexes = Vec::new();
+    for indexed_query in INDEXED_QUERIES {
+        if !uses_full_scan(pool, indexed_query.query).await? {
+            continue;
+        }
+        if auto_create_indexes {
+            info!(
+                "Looking up {} reads the whole table, adding an index: {}",
+                indexed_query.description, indexed_query.create_index
+            );
+            sqlx::query(indexed_query.create_index)
+                .execute(pool)
+                .await?;
+        } else {
+            warn!(
+                "Looking up {} reads the whole table, which is slow for large directories. \
+                 Consider adding an index: {}",
+                indexed_query.description, indexed_query.create_index
+            );
+            missing_indexes.push(indexed_query.create_index);
+        }
+    }
+    debug!("Checked the database indexes in {:?}", start.elapsed());
+    Ok(missing_indexes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::sql_tables::{init_table, PoolOptions};
+
+    #[test]
+    fn test_is_full_scan() {
+        assert!(is_full_scan("SCAN memberships"));
+        assert!(is_full_scan("SCAN TABLE memberships"));
+        assert!(!is_full_scan(
+            "SEARCH users USING INDEX sqlite_autoindex_users_1 (user_id=?)"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_indexes() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let missing_indexes = check_indexes(&sql_pool, false).await.unwrap();
+        // The user ID is the primary key, so it's always indexed.
+        assert!(!missing_indexes
+            .iter()
+            .any(|statement| statement.contains("users_user_id")));
+        assert!(missing_indexes
+            .iter()
+            .any(|statement| statement.contains("memberships_user_id")));
+
+        check_indexes(&sql_pool, true).await.unwrap();
+        assert_eq!(
+            check_indexes(&sql_pool, false).await.unwrap(),
+            Vec::<&str>::new()
+        );
+    }
+}
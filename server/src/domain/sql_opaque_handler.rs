@@ -1,28 +1,75 @@
 use super::{
     error::*,
     handler::{BindRequest, LoginHandler, UserId},
+    legacy_password::CompatPasswordVerifier,
     opaque_handler::*,
     sql_backend_handler::SqlBackendHandler,
     sql_tables::*,
 };
+use crate::infra::metrics::time_credential_verification;
 use async_trait::async_trait;
 use lldap_auth::opaque;
 use log::*;
-use sea_query::{Expr, Iden, Query};
+use sea_query::{Expr, Iden, Query, Value};
 use secstr::SecUtf8;
 use sqlx::Row;
 
 type SqlOpaqueHandler = SqlBackendHandler;
 
+/// Rejects the passwords longer than `max_length` bytes (0 means no limit), before spending any
+/// time hashing them.
+pub(crate) fn check_password_length(password: &str, max_length: usize) -> Result<()> {
+    if max_length != 0 && password.len() > max_length {
+        return Err(DomainError::ValidationError(
+            "password".to_string(),
+            format!("longer than {} bytes", max_length),
+        ));
+    }
+    Ok(())
+}
+
 fn passwords_match(
     password_file_bytes: &[u8],
     clear_password: &str,
     server_setup: &opaque::server::ServerSetup,
     username: &UserId,
+) -> Result<()> {
+    let result = opaque_login(
+        password_file_bytes,
+        clear_password,
+        false,
+        server_setup,
+        username,
+    );
+    if result.is_err() && opaque::needs_raw_password_fallback(clear_password) {
+        // The password may have been registered before the passwords were prepared.
+        return opaque_login(
+            password_file_bytes,
+            clear_password,
+            true,
+            server_setup,
+            username,
+        );
+    }
+    result
+}
+
+/// Runs both sides of the OPAQUE login, with the raw password instead of the prepared one if
+/// `raw_password` is set.
+fn opaque_login(
+    password_file_bytes: &[u8],
+    clear_password: &str,
+    raw_password: bool,
+    server_setup: &opaque::server::ServerSetup,
+    username: &UserId,
 ) -> Result<()> {
     use opaque::{client, server};
     let mut rng = rand::rngs::OsRng;
-    let client_login_start_result = client::login::start_login(clear_password, &mut rng)?;
+    let client_login_start_result = if raw_password {
+        client::login::start_login_with_raw_password(clear_password, &mut rng)?
+    } else {
+        client::login::start_login(clear_password, &mut rng)?
+    };
 
     let password_file = server::ServerRegistration::deserialize(password_file_bytes)
         .map_err(opaque::AuthenticationError::ProtocolError)?;
@@ -78,11 +125,110 @@ impl SqlBackendHandler {
                 DomainError::InternalError(format!("Corrupted password file for {}", username))
             })
     }
+
+    /// Replaces a verified legacy password hash with an OPAQUE password file.
+    async fn migrate_legacy_password(&self, username: &UserId, password: &str) -> Result<()> {
+        register_password(self, username, &SecUtf8::from(password)).await?;
+        let update_query = Query::update()
+            .table(Users::Table)
+            .values(vec![(Users::LegacyPasswordHash, Value::Null)])
+            .and_where(Expr::col(Users::UserId).eq(username))
+            .to_string(DbQueryBuilder {});
+        sqlx::query(&update_query).execute(&self.sql_pool).await?;
+        Ok(())
+    }
+
+    /// Checks the age of the password after a successful bind. Once the password is older than
+    /// `password_max_age_days`, the user can bind `password_grace_logins` more times before being
+    /// locked out until the password is changed.
+    async fn check_password_expiry(&self, username: &UserId) -> Result<()> {
+        if self.config.password_max_age_days == 0 {
+            return Ok(());
+        }
+        let query = Query::select()
+            .column(Users::PasswordModifiedDate)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(username))
+            .to_string(DbQueryBuilder {});
+        let row = sqlx::query(&query).fetch_one(&self.sql_pool).await?;
+        let now = chrono::Utc::now();
+        let modified_date = match row.get::<Option<chrono::DateTime<chrono::Utc>>, _>(
+            &*Users::PasswordModifiedDate.to_string(),
+        ) {
+            Some(date) => date,
+            None => {
+                // The password was set before expiry was enabled: start counting now.
+                self.update_password_expiry(
+                    username,
+                    Users::PasswordModifiedDate,
+                    now.naive_utc().into(),
+                )
+                .await?;
+                return Ok(());
+            }
+        };
+        if now < modified_date + chrono::Duration::days(self.config.password_max_age_days.into()) {
+            return Ok(());
+        }
+        // A single conditional update, so that concurrent binds can't use the same grace login.
+        // A negative count means that none was used yet since the password expired.
+        let update_query = Query::update()
+            .table(Users::Table)
+            .value_expr(
+                Users::GraceLoginsRemaining,
+                Expr::cust_with_values(
+                    &format!(
+                        "(CASE WHEN {0} < 0 THEN ? ELSE {0} END) - 1",
+                        Users::GraceLoginsRemaining.to_string()
+                    ),
+                    vec![i64::from(self.config.password_grace_logins)],
+                ),
+            )
+            .and_where(Expr::col(Users::UserId).eq(username))
+            .and_where(if self.config.password_grace_logins == 0 {
+                Expr::col(Users::GraceLoginsRemaining).gt(0)
+            } else {
+                Expr::col(Users::GraceLoginsRemaining).ne(0)
+            })
+            .to_string(DbQueryBuilder {});
+        let used = sqlx::query(&update_query)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected();
+        if used == 0 {
+            info!(
+                r#"The password of "{}" expired, rejecting the bind"#,
+                username
+            );
+            return Err(DomainError::PasswordExpired);
+        }
+        warn!(
+            r#"The password of "{}" expired, using a grace login"#,
+            username
+        );
+        Ok(())
+    }
+
+    async fn update_password_expiry(
+        &self,
+        username: &UserId,
+        column: Users,
+        value: Value,
+    ) -> Result<()> {
+        let update_query = Query::update()
+            .table(Users::Table)
+            .values(vec![(column, value)])
+            .and_where(Expr::col(Users::UserId).eq(username))
+            .to_string(DbQueryBuilder {});
+        sqlx::query(&update_query).execute(&self.sql_pool).await?;
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl LoginHandler for SqlBackendHandler {
     async fn bind(&self, request: BindRequest) -> Result<()> {
+        check_password_length(&request.password, self.config.max_password_length)?;
         if request.name == self.config.ldap_user_dn {
             if SecUtf8::from(request.password) == self.config.ldap_user_pass {
                 return Ok(());
@@ -96,34 +242,136 @@ impl LoginHandler for SqlBackendHandler {
         }
         let query = Query::select()
             .column(Users::PasswordHash)
+            .column(Users::LegacyPasswordHash)
             .from(Users::Table)
             .and_where(Expr::col(Users::UserId).eq(&request.name))
             .to_string(DbQueryBuilder {});
-        if let Ok(row) = sqlx::query(&query).fetch_one(&self.sql_pool).await {
+        if let Some(row) = sqlx::query(&query).fetch_optional(&self.sql_pool).await? {
+            let legacy_password_hash =
+                row.get::<Option<String>, _>(&*Users::LegacyPasswordHash.to_string());
             if let Some(password_hash) =
                 row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string())
             {
-                if let Err(e) = passwords_match(
-                    &password_hash,
-                    &request.password,
-                    self.config.get_server_setup(),
-                    &request.name,
-                ) {
+                let password = request.password.clone();
+                let server_setup = self.config.get_server_setup().clone();
+                let name = request.name.clone();
+                if let Err(e) = self
+                    .run_password_hashing(move || {
+                        time_credential_verification(|| {
+                            passwords_match(&password_hash, &password, &server_setup, &name)
+                        })
+                    })
+                    .await?
+                {
                     debug!(r#"Invalid password for "{}": {}"#, &request.name, e);
                 } else {
-                    return Ok(());
+                    return self.check_password_expiry(&request.name).await;
                 }
-            } else {
+            } else if legacy_password_hash.is_none() {
                 debug!(r#"User "{}" has no password"#, &request.name);
+                return Err(DomainError::CredentialsNotFound(request.name.to_string()));
+            }
+            if let Some(legacy_password_hash) = legacy_password_hash {
+                let password = request.password.clone();
+                match self
+                    .run_password_hashing(move || {
+                        time_credential_verification(|| {
+                            CompatPasswordVerifier::default()
+                                .verify(&legacy_password_hash, &password)
+                        })
+                    })
+                    .await?
+                {
+                    Ok(true) => {
+                        info!(
+                            r#"Migrating the legacy password of "{}" to OPAQUE"#,
+                            &request.name
+                        );
+                        if let Err(e) = self
+                            .migrate_legacy_password(&request.name, &request.password)
+                            .await
+                        {
+                            warn!(
+                                r#"Could not migrate the legacy password of "{}": {}"#,
+                                &request.name, e
+                            );
+                        }
+                        return Ok(());
+                    }
+                    Ok(false) => {
+                        debug!(r#"Invalid legacy password for "{}""#, &request.name)
+                    }
+                    Err(e) => warn!(
+                        r#"Could not verify the legacy password of "{}": {}"#,
+                        &request.name, e
+                    ),
+                }
             }
         } else {
             debug!(r#"No user found for "{}""#, &request.name);
+            return Err(DomainError::CredentialsNotFound(request.name.to_string()));
         }
         Err(DomainError::AuthenticationError(format!(
             " for user '{}'",
             request.name
         )))
     }
+
+    async fn get_grace_logins_remaining(&self, user_id: &UserId) -> Result<i32> {
+        let query = Query::select()
+            .column(Users::GraceLoginsRemaining)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .map(|row| row.get::<i32, _>(&*Users::GraceLoginsRemaining.to_string()))
+            .unwrap_or(-1))
+    }
+
+    async fn has_password(&self, user_id: &UserId) -> Result<bool> {
+        let query = Query::select()
+            .column(Users::PasswordHash)
+            .column(Users::LegacyPasswordHash)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .map(|row| {
+                row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string())
+                    .is_some()
+                    || row
+                        .get::<Option<String>, _>(&*Users::LegacyPasswordHash.to_string())
+                        .is_some()
+            })
+            .unwrap_or(false))
+    }
+
+    async fn check_password(&self, user_id: &UserId, password: &str) -> Result<bool> {
+        let query = Query::select()
+            .column(Users::PasswordHash)
+            .from(Users::Table)
+            .and_where(Expr::col(Users::UserId).eq(user_id))
+            .to_string(DbQueryBuilder {});
+        let password_hash = match sqlx::query(&query)
+            .fetch_optional(&self.sql_pool)
+            .await?
+            .and_then(|row| row.get::<Option<Vec<u8>>, _>(&*Users::PasswordHash.to_string()))
+        {
+            Some(password_hash) => password_hash,
+            None => return Ok(false),
+        };
+        let password = password.to_string();
+        let server_setup = self.config.get_server_setup().clone();
+        let user_id = user_id.clone();
+        self.run_password_hashing(move || {
+            passwords_match(&password_hash, &password, &server_setup, &user_id).is_ok()
+        })
+        .await
+    }
 }
 
 #[async_trait]
@@ -134,15 +382,22 @@ impl OpaqueHandler for SqlOpaqueHandler {
     ) -> Result<login::ServerLoginStartResponse> {
         let maybe_password_file = self.get_password_file_for_user(&request.username).await?;
 
-        let mut rng = rand::rngs::OsRng;
+        let server_setup = self.config.get_server_setup().clone();
+        let username = request.username.clone();
         // Get the CredentialResponse for the user, or a dummy one if no user/no password.
-        let start_response = opaque::server::login::start_login(
-            &mut rng,
-            self.config.get_server_setup(),
-            maybe_password_file,
-            request.login_start_request,
-            &request.username,
-        )?;
+        let start_response = self
+            .run_password_hashing(move || {
+                time_credential_verification(|| {
+                    opaque::server::login::start_login(
+                        &mut rand::rngs::OsRng,
+                        &server_setup,
+                        maybe_password_file,
+                        request.login_start_request,
+                        &username,
+                    )
+                })
+            })
+            .await??;
         let secret_key = self.get_orion_secret_key()?;
         let server_data = login::ServerData {
             username: request.username,
@@ -167,9 +422,17 @@ impl OpaqueHandler for SqlOpaqueHandler {
         )?)?;
         // Finish the login: this makes sure the client data is correct, and gives a session key we
         // don't need.
-        let _session_key =
-            opaque::server::login::finish_login(server_login, request.credential_finalization)?
-                .session_key;
+        let _session_key = self
+            .run_password_hashing(move || {
+                time_credential_verification(|| {
+                    opaque::server::login::finish_login(
+                        server_login,
+                        request.credential_finalization,
+                    )
+                })
+            })
+            .await??
+            .session_key;
 
         Ok(UserId::new(&username))
     }
@@ -198,7 +461,7 @@ impl OpaqueHandler for SqlOpaqueHandler {
     async fn registration_finish(
         &self,
         request: registration::ClientRegistrationFinishRequest,
-    ) -> Result<()> {
+    ) -> Result<UserId> {
         let secret_key = self.get_orion_secret_key()?;
         let registration::ServerData { username } = bincode::deserialize(&orion::aead::open(
             &secret_key,
@@ -207,25 +470,34 @@ impl OpaqueHandler for SqlOpaqueHandler {
 
         let password_file =
             opaque::server::registration::get_password_file(request.registration_upload);
+        let user_id = UserId::new(&username);
+        let _invalidation = self.invalidate_on_drop(Some(&user_id));
         {
-            // Set the user password to the new password.
+            // Set the user password to the new password, and reset its expiry.
             let update_query = Query::update()
                 .table(Users::Table)
-                .values(vec![(
-                    Users::PasswordHash,
-                    password_file.serialize().into(),
-                )])
-                .and_where(Expr::col(Users::UserId).eq(username))
+                .values(vec![
+                    (Users::PasswordHash, password_file.serialize().into()),
+                    (
+                        Users::PasswordModifiedDate,
+                        chrono::Utc::now().naive_utc().into(),
+                    ),
+                    (Users::GraceLoginsRemaining, (-1).into()),
+                ])
+                .and_where(Expr::col(Users::UserId).eq(username.as_str()))
                 .to_string(DbQueryBuilder {});
-            sqlx::query(&update_query).execute(&self.sql_pool).await?;
+            // On the connection of the transaction, if any.
+            sqlx::query(&update_query)
+                .execute(&mut *self.connection().await?)
+                .await?;
         }
-        Ok(())
+        Ok(user_id)
     }
 }
 
 /// Convenience function to set a user's password.
-pub(crate) async fn register_password(
-    opaque_handler: &SqlOpaqueHandler,
+pub(crate) async fn register_password<Handler: OpaqueHandler>(
+    opaque_handler: &Handler,
     username: &UserId,
     password: &SecUtf8,
 ) -> Result<()> {
@@ -249,7 +521,8 @@ pub(crate) async fn register_password(
             server_data: start_response.server_data,
             registration_upload: registration_finish.message,
         })
-        .await
+        .await?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -285,7 +558,7 @@ mod tests {
         handler
             .create_user(CreateUserRequest {
                 user_id: UserId::new(name),
-                email: "bob@bob.bob".to_string(),
+                email: format!("{}@bob.bob", name),
                 ..Default::default()
             })
             .await
@@ -341,4 +614,300 @@ mod tests {
         attempt_login(&opaque_handler, "bob", "bob00").await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_bind_with_legacy_password() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&backend_handler, "bob").await;
+        let legacy_hash = format!("{{BCRYPT}}{}", bcrypt::hash("bob00", 4).unwrap());
+        sqlx::query("UPDATE users SET legacy_password_hash = ? WHERE user_id = 'bob'")
+            .bind(legacy_hash)
+            .execute(&sql_pool)
+            .await?;
+        let bind = |password: &str| {
+            opaque_handler.bind(BindRequest {
+                name: UserId::new("bob"),
+                password: password.to_string(),
+            })
+        };
+        bind("wrong_password").await.unwrap_err();
+        bind("bob00").await?;
+        // The password was migrated to OPAQUE, and the legacy hash removed.
+        attempt_login(&opaque_handler, "bob", "bob00").await?;
+        let row = sqlx::query("SELECT legacy_password_hash FROM users WHERE user_id = 'bob'")
+            .fetch_one(&sql_pool)
+            .await?;
+        assert_eq!(row.get::<Option<String>, _>("legacy_password_hash"), None);
+        bind("bob00").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_unprepared_password() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&backend_handler, "bob").await;
+        // Registered before the passwords were prepared, with a combining accent.
+        let password = "cafe\u{301}-bob00";
+        let mut rng = rand::rngs::OsRng;
+        use registration::*;
+        let registration_start =
+            opaque::client::registration::ClientRegistration::start(&mut rng, password.as_bytes())
+                .unwrap();
+        let start_response = opaque_handler
+            .registration_start(ClientRegistrationStartRequest {
+                username: "bob".to_string(),
+                registration_start_request: registration_start.message,
+            })
+            .await?;
+        let registration_finish = opaque::client::registration::finish_registration(
+            registration_start.state,
+            start_response.registration_response,
+            &mut rng,
+        )?;
+        opaque_handler
+            .registration_finish(ClientRegistrationFinishRequest {
+                server_data: start_response.server_data,
+                registration_upload: registration_finish.message,
+            })
+            .await?;
+        let bind = |password: &str| {
+            opaque_handler.bind(BindRequest {
+                name: UserId::new("bob"),
+                password: password.to_string(),
+            })
+        };
+        bind(password).await?;
+        // Only the raw form matches such a password.
+        bind("caf\u{E9}-bob00").await.unwrap_err();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_has_password() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&backend_handler, "bob").await;
+        insert_user_no_password(&backend_handler, "john").await;
+        let bob = UserId::new("bob");
+        let john = UserId::new("john");
+        assert!(!opaque_handler.has_password(&bob).await?);
+        assert!(!opaque_handler.has_password(&UserId::new("unknown")).await?);
+        register_password(&opaque_handler, &bob, &secstr::SecUtf8::from("bob00")).await?;
+        assert!(opaque_handler.has_password(&bob).await?);
+        sqlx::query("UPDATE users SET legacy_password_hash = '{CRYPT}x' WHERE user_id = 'john'")
+            .execute(&sql_pool)
+            .await?;
+        assert!(opaque_handler.has_password(&john).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_without_credentials() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = get_default_config();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool);
+        insert_user_no_password(&backend_handler, "bob").await;
+        let bind = |name: &str, password: &str| {
+            opaque_handler.bind(BindRequest {
+                name: UserId::new(name),
+                password: password.to_string(),
+            })
+        };
+        assert!(matches!(
+            bind("bob", "bob00").await,
+            Err(DomainError::CredentialsNotFound(_))
+        ));
+        assert!(matches!(
+            bind("unknown", "bob00").await,
+            Err(DomainError::CredentialsNotFound(_))
+        ));
+        register_password(
+            &opaque_handler,
+            &UserId::new("bob"),
+            &secstr::SecUtf8::from("bob00"),
+        )
+        .await?;
+        assert!(matches!(
+            bind("bob", "wrong_password").await,
+            Err(DomainError::AuthenticationError(_))
+        ));
+        // Displayed the same way, not to disclose which users exist.
+        assert_eq!(
+            bind("unknown", "bob00").await.unwrap_err().to_string(),
+            DomainError::AuthenticationError(" for user 'unknown'".to_string()).to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_expired_password() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .verbose(true)
+            .password_max_age_days(30)
+            .password_grace_logins(2)
+            .build()
+            .unwrap();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&backend_handler, "bob").await;
+        let bob = UserId::new("bob");
+        register_password(&opaque_handler, &bob, &secstr::SecUtf8::from("bob00")).await?;
+        let bind = || {
+            opaque_handler.bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_string(),
+            })
+        };
+        bind().await?;
+        assert_eq!(opaque_handler.get_grace_logins_remaining(&bob).await?, -1);
+        sqlx::query(
+            "UPDATE users SET password_modified_date = '2000-01-01 00:00:00' WHERE user_id = 'bob'",
+        )
+        .execute(&sql_pool)
+        .await?;
+        bind().await?;
+        assert_eq!(opaque_handler.get_grace_logins_remaining(&bob).await?, 1);
+        bind().await?;
+        assert_eq!(opaque_handler.get_grace_logins_remaining(&bob).await?, 0);
+        assert!(matches!(
+            bind().await.unwrap_err(),
+            DomainError::PasswordExpired
+        ));
+        // A wrong password is still reported as such.
+        assert!(!matches!(
+            opaque_handler
+                .bind(BindRequest {
+                    name: bob.clone(),
+                    password: "wrong_password".to_string(),
+                })
+                .await
+                .unwrap_err(),
+            DomainError::PasswordExpired
+        ));
+        // Changing the password resets the expiry.
+        register_password(&opaque_handler, &bob, &secstr::SecUtf8::from("bob01")).await?;
+        assert_eq!(opaque_handler.get_grace_logins_remaining(&bob).await?, -1);
+        opaque_handler
+            .bind(BindRequest {
+                name: bob.clone(),
+                password: "bob01".to_string(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_grace_logins_concurrent_binds() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .verbose(true)
+            .password_max_age_days(30)
+            .password_grace_logins(2)
+            .build()
+            .unwrap();
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&opaque_handler, "bob").await;
+        register_password(
+            &opaque_handler,
+            &UserId::new("bob"),
+            &secstr::SecUtf8::from("bob00"),
+        )
+        .await?;
+        sqlx::query(
+            "UPDATE users SET password_modified_date = '2000-01-01 00:00:00' WHERE user_id = 'bob'",
+        )
+        .execute(&sql_pool)
+        .await?;
+        let results = futures::future::join_all((0..4).map(|_| {
+            opaque_handler.bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob00".to_string(),
+            })
+        }))
+        .await;
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+        assert_eq!(
+            opaque_handler
+                .get_grace_logins_remaining(&UserId::new("bob"))
+                .await?,
+            0
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_password() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .verbose(true)
+            .password_max_age_days(30)
+            .password_grace_logins(2)
+            .build()
+            .unwrap();
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&opaque_handler, "bob").await;
+        let bob = UserId::new("bob");
+        assert!(!opaque_handler.check_password(&bob, "bob00").await?);
+        register_password(&opaque_handler, &bob, &secstr::SecUtf8::from("bob00")).await?;
+        sqlx::query(
+            "UPDATE users SET password_modified_date = '2000-01-01 00:00:00' WHERE user_id = 'bob'",
+        )
+        .execute(&sql_pool)
+        .await?;
+        assert!(opaque_handler.check_password(&bob, "bob00").await?);
+        assert!(
+            !opaque_handler
+                .check_password(&bob, "wrong_password")
+                .await?
+        );
+        assert!(
+            !opaque_handler
+                .check_password(&UserId::new("jim"), "bob00")
+                .await?
+        );
+        // Unlike the binds, the checks don't use up the grace logins.
+        assert_eq!(opaque_handler.get_grace_logins_remaining(&bob).await?, -1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_long_password() -> Result<()> {
+        let sql_pool = get_initialized_db().await;
+        let config = ConfigurationBuilder::default()
+            .verbose(true)
+            .max_password_length(8)
+            .build()
+            .unwrap();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+        let opaque_handler = SqlOpaqueHandler::new(config, sql_pool.clone());
+        insert_user_no_password(&backend_handler, "bob").await;
+        let bob = UserId::new("bob");
+        register_password(&opaque_handler, &bob, &secstr::SecUtf8::from("bob00")).await?;
+        opaque_handler
+            .bind(BindRequest {
+                name: bob.clone(),
+                password: "bob00".to_string(),
+            })
+            .await?;
+        assert!(matches!(
+            opaque_handler
+                .bind(BindRequest {
+                    name: bob.clone(),
+                    password: "a".repeat(9),
+                })
+                .await,
+            Err(DomainError::ValidationError(_, _))
+        ));
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use super::handler::{GroupId, UserId};
+use super::handler::{GroupId, InvitationId, UserId};
 use sea_query::*;
 
 pub type Pool = sqlx::sqlite::SqlitePool;
@@ -37,6 +37,12 @@ where
     }
 }
 
+impl From<InvitationId> for Value {
+    fn from(invitation_id: InvitationId) -> Self {
+        invitation_id.0.into()
+    }
+}
+
 impl<DB> sqlx::Type<DB> for UserId
 where
     DB: sqlx::Database,
@@ -85,8 +91,14 @@ pub enum Users {
     Avatar,
     CreationDate,
     PasswordHash,
+    LegacyPasswordHash,
+    PasswordModifiedDate,
+    GraceLoginsRemaining,
     TotpSecret,
     MfaType,
+    MaxSearchResults,
+    ManagerUserId,
+    LastLoginAt,
 }
 
 #[derive(Iden)]
@@ -94,6 +106,10 @@ pub enum Groups {
     Table,
     GroupId,
     DisplayName,
+    /// The LDAP filter on the users of a dynamic group, null for the other groups.
+    DynamicFilter,
+    /// The name shown to the users, null to show the display name (the cn).
+    Description,
 }
 
 #[derive(Iden)]
@@ -103,6 +119,67 @@ pub enum Memberships {
     GroupId,
 }
 
+/// Additional addresses delivered to a user's mailbox. Each alias belongs to a single user.
+#[derive(Iden, Clone, Copy)]
+pub enum MailAliases {
+    Table,
+    UserId,
+    Alias,
+}
+
+/// External addresses a user's mail is forwarded to.
+#[derive(Iden, Clone, Copy)]
+pub enum MailForwarding {
+    Table,
+    UserId,
+    Address,
+}
+
+/// The phone numbers of a user.
+#[derive(Iden, Clone, Copy)]
+pub enum UserPhoneNumbers {
+    Table,
+    UserId,
+    PhoneNumber,
+}
+
+/// The number of logins of a user per day (UTC), for the login report.
+#[derive(Iden, Clone, Copy)]
+pub enum UserLogins {
+    Table,
+    UserId,
+    Day,
+    LoginCount,
+}
+
+/// Invitations sent by email for new users to create their account.
+#[derive(Iden)]
+pub enum PendingInvitations {
+    Table,
+    InvitationId,
+    Token,
+    Email,
+    ExpiryDate,
+    Used,
+}
+
+/// Adds a column to a table created by an older version. Newer tables already have it, and
+/// SQLite has no `ADD COLUMN IF NOT EXISTS`: only that error is ignored.
+pub(crate) async fn add_column_if_missing<T: Iden + 'static>(
+    pool: &Pool,
+    table: T,
+    column: &mut ColumnDef,
+) -> sqlx::Result<()> {
+    let query = Table::alter()
+        .table(table)
+        .add_column(column)
+        .to_string(DbQueryBuilder {});
+    match sqlx::query(&query).execute(pool).await {
+        Err(sqlx::Error::Database(e)) if e.message().starts_with("duplicate column name") => Ok(()),
+        result => result.map(|_| ()),
+    }
+}
+
 pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
     // SQLite needs this pragma to be turned on. Other DB might not understand this, so ignore the
     // error.
@@ -128,13 +205,67 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
             .col(ColumnDef::new(Users::Avatar).binary())
             .col(ColumnDef::new(Users::CreationDate).date_time().not_null())
             .col(ColumnDef::new(Users::PasswordHash).binary())
+            .col(ColumnDef::new(Users::LegacyPasswordHash).string_len(255))
+            .col(ColumnDef::new(Users::PasswordModifiedDate).date_time())
+            .col(
+                ColumnDef::new(Users::GraceLoginsRemaining)
+                    .integer()
+                    .not_null()
+                    .default(-1),
+            )
             .col(ColumnDef::new(Users::TotpSecret).string_len(64))
             .col(ColumnDef::new(Users::MfaType).string_len(64))
+            .col(ColumnDef::new(Users::MaxSearchResults).integer())
+            .col(ColumnDef::new(Users::ManagerUserId).string_len(255))
+            .col(ColumnDef::new(Users::LastLoginAt).date_time())
             .to_string(DbQueryBuilder {}),
     )
     .execute(pool)
     .await?;
 
+    // Tables created by older versions don't have these columns.
+    for mut column in [
+        ColumnDef::new(Users::LegacyPasswordHash)
+            .string_len(255)
+            .to_owned(),
+        ColumnDef::new(Users::PasswordModifiedDate)
+            .date_time()
+            .to_owned(),
+        ColumnDef::new(Users::GraceLoginsRemaining)
+            .integer()
+            .not_null()
+            .default(-1)
+            .to_owned(),
+        ColumnDef::new(Users::MaxSearchResults).integer().to_owned(),
+        ColumnDef::new(Users::ManagerUserId)
+            .string_len(255)
+            .to_owned(),
+        ColumnDef::new(Users::LastLoginAt).date_time().to_owned(),
+    ] {
+        add_column_if_missing(pool, Users::Table, &mut column).await?;
+    }
+
+    // Users without an email, like the default admin, all share the empty value. The emails are
+    // compared case-insensitively, this replaces the case-sensitive index of older versions.
+    // Creating the index fails if an older database already has duplicate emails: keep going,
+    // with the previous index if there is one.
+    match sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_unique ON users (LOWER(email)) WHERE email <> ''",
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(_) => {
+            sqlx::query("DROP INDEX IF EXISTS users_email_unique")
+                .execute(pool)
+                .await?;
+        }
+        Err(e) => log::warn!(
+            "Could not add the unique constraint on the user emails, remove the duplicate emails and restart: {}",
+            e
+        ),
+    }
+
     sqlx::query(
         &Table::create()
             .table(Groups::Table)
@@ -151,11 +282,28 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
                     .unique_key()
                     .not_null(),
             )
+            .col(ColumnDef::new(Groups::DynamicFilter).text())
+            .col(ColumnDef::new(Groups::Description).string_len(255))
             .to_string(DbQueryBuilder {}),
     )
     .execute(pool)
     .await?;
 
+    // Same as for the users, for the tables created by older versions.
+    add_column_if_missing(
+        pool,
+        Groups::Table,
+        &mut ColumnDef::new(Groups::DynamicFilter).text(),
+    )
+    .await?;
+    // The existing groups get a null description, and keep showing their display name.
+    add_column_if_missing(
+        pool,
+        Groups::Table,
+        &mut ColumnDef::new(Groups::Description).string_len(255),
+    )
+    .await?;
+
     sqlx::query(
         &Table::create()
             .table(Memberships::Table)
@@ -187,6 +335,175 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
     .execute(pool)
     .await?;
 
+    sqlx::query(
+        &Table::create()
+            .table(MailAliases::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(MailAliases::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(MailAliases::Alias)
+                    .string_len(255)
+                    .unique_key()
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("MailAliasesUserForeignKey")
+                    .table(MailAliases::Table, Users::Table)
+                    .col(MailAliases::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(MailForwarding::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(MailForwarding::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(MailForwarding::Address)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("MailForwardingUserForeignKey")
+                    .table(MailForwarding::Table, Users::Table)
+                    .col(MailForwarding::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(UserPhoneNumbers::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(UserPhoneNumbers::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(UserPhoneNumbers::PhoneNumber)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("UserPhoneNumbersUserForeignKey")
+                    .table(UserPhoneNumbers::Table, Users::Table)
+                    .col(UserPhoneNumbers::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    // A user has each phone number once. The duplicates stored before the index are dropped.
+    sqlx::query(
+        "DELETE FROM user_phone_numbers WHERE rowid NOT IN
+           (SELECT MIN(rowid) FROM user_phone_numbers GROUP BY user_id, phone_number)",
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS user_phone_numbers_user_number
+           ON user_phone_numbers (user_id, phone_number)",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(UserLogins::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(UserLogins::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(UserLogins::Day).date().not_null())
+            .col(
+                ColumnDef::new(UserLogins::LoginCount)
+                    .integer()
+                    .not_null()
+                    .default(0),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("UserLoginsUserForeignKey")
+                    .table(UserLogins::Table, Users::Table)
+                    .col(UserLogins::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    // The logins of the same day are added to the same row.
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS user_logins_user_day ON user_logins (user_id, day)",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        &Table::create()
+            .table(PendingInvitations::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(PendingInvitations::InvitationId)
+                    .integer()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(PendingInvitations::Token)
+                    .string_len(255)
+                    .unique_key()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PendingInvitations::Email)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PendingInvitations::ExpiryDate)
+                    .date_time()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(PendingInvitations::Used)
+                    .boolean()
+                    .default(false)
+                    .not_null(),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
     Ok(())
 }
 
@@ -216,10 +533,114 @@ mod tests {
         );
     }
 
+    #[actix_rt::test]
+    async fn test_unique_email() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let insert = |user_id: &'static str, email: &'static str| {
+            sqlx::query(
+                "INSERT INTO users (user_id, email, display_name, first_name, last_name, creation_date)
+                 VALUES (?, ?, '', '', '', '1970-01-01 00:00:00')",
+            )
+            .bind(user_id)
+            .bind(email)
+            .execute(&sql_pool)
+        };
+        insert("admin", "").await.unwrap();
+        insert("service", "").await.unwrap();
+        insert("bob", "bob@bob.bob").await.unwrap();
+        insert("bob2", "bob@bob.bob").await.unwrap_err();
+        insert("bob3", "Bob@Bob.bob").await.unwrap_err();
+    }
+
+    #[actix_rt::test]
+    async fn test_unique_phone_numbers() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO users (user_id, email, display_name, first_name, last_name, creation_date)
+             VALUES ('bob', 'bob@bob.bob', '', '', '', '1970-01-01 00:00:00'),
+               ('john', 'john@bob.bob', '', '', '', '1970-01-01 00:00:00')",
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        let insert = |user_id: &'static str| {
+            sqlx::query(
+                "INSERT INTO user_phone_numbers (user_id, phone_number) VALUES (?, '+15550100')",
+            )
+            .bind(user_id)
+            .execute(&sql_pool)
+        };
+        insert("bob").await.unwrap();
+        insert("john").await.unwrap();
+        insert("bob").await.unwrap_err();
+    }
+
+    #[actix_rt::test]
+    async fn test_unique_email_migration() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query("DROP INDEX users_email_lower_unique")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        sqlx::query("CREATE UNIQUE INDEX users_email_unique ON users (email) WHERE email <> ''")
+            .execute(&sql_pool)
+            .await
+            .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let indexes = sqlx::query(
+            "SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'users'",
+        )
+        .fetch_all(&sql_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.get::<String, _>("name"))
+        .collect::<Vec<_>>();
+        assert!(indexes.contains(&"users_email_lower_unique".to_string()));
+        assert!(!indexes.contains(&"users_email_unique".to_string()));
+    }
+
     #[actix_rt::test]
     async fn test_already_init_table() {
         let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
         init_table(&sql_pool).await.unwrap();
         init_table(&sql_pool).await.unwrap();
     }
+
+    #[actix_rt::test]
+    async fn test_upgrade_table() {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        // The tables of an older version, without the newer columns.
+        sqlx::query(
+            "CREATE TABLE users (user_id VARCHAR(255) NOT NULL PRIMARY KEY,
+               email VARCHAR(255) NOT NULL, display_name VARCHAR(255) NOT NULL,
+               first_name VARCHAR(255) NOT NULL, last_name VARCHAR(255) NOT NULL,
+               avatar BLOB, creation_date TEXT NOT NULL, password_hash BLOB,
+               totp_secret VARCHAR(64), mfa_type VARCHAR(64))",
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "CREATE TABLE groups (group_id INTEGER NOT NULL PRIMARY KEY,
+               display_name VARCHAR(255) NOT NULL UNIQUE)",
+        )
+        .execute(&sql_pool)
+        .await
+        .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        sqlx::query(
+            "SELECT legacy_password_hash, grace_logins_remaining, last_login_at FROM users",
+        )
+        .fetch_all(&sql_pool)
+        .await
+        .unwrap();
+        sqlx::query("SELECT dynamic_filter, description FROM groups")
+            .fetch_all(&sql_pool)
+            .await
+            .unwrap();
+    }
 }
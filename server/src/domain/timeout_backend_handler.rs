@@ -0,0 +1,507 @@
+use crate::{
+    domain::{error::*, handler::*, opaque_handler::*},
+    infra::{
+        configuration::Configuration,
+        tcp_backend_handler::{PasswordResetTokenUse, TcpBackendHandler, UserToken},
+    },
+};
+use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// Runs the backend operation, returning a timeout error if it takes longer than `timeout`. A
+/// timeout of 0 means no limit. The operation is dropped on timeout, which releases its database
+/// connection.
+async fn with_operation_timeout<T, F>(timeout: Duration, operation: &str, future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    if timeout.is_zero() {
+        return future.await;
+    }
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                "Backend operation {} timed out after {:?}",
+                operation, timeout
+            );
+            Err(DomainError::operation_timeout())
+        }
+    }
+}
+
+/// Wraps a backend, limiting the duration of its lookups and logins to `backend_read_timeout_ms`,
+/// and of its transactions to `backend_write_timeout_ms`.
+#[derive(Clone)]
+pub struct TimeoutBackendHandler<Backend> {
+    backend: Backend,
+    read_timeout: Duration,
+    write_timeout: Duration,
+}
+
+impl<Backend> TimeoutBackendHandler<Backend> {
+    pub fn new(backend: Backend, config: &Configuration) -> Self {
+        Self {
+            backend,
+            read_timeout: Duration::from_millis(config.backend_read_timeout_ms),
+            write_timeout: Duration::from_millis(config.backend_write_timeout_ms),
+        }
+    }
+
+    async fn read<T>(&self, operation: &str, future: impl Future<Output = Result<T>>) -> Result<T> {
+        with_operation_timeout(self.read_timeout, operation, future).await
+    }
+
+    /// The changes are not cancelled: dropping one halfway through could leave some of its
+    /// queries applied. Only the transactions, rolled back as a whole, are limited by
+    /// `write_timeout`. The others are logged when they take longer.
+    async fn write<T>(
+        &self,
+        operation: &str,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = future.await;
+        let elapsed = start.elapsed();
+        if !self.write_timeout.is_zero() && elapsed > self.write_timeout {
+            warn!(
+                "Backend operation {} took {:?}, longer than the write timeout of {:?}",
+                operation, elapsed, self.write_timeout
+            );
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + Sync> BackendHandler for TimeoutBackendHandler<Backend> {
+    async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>> {
+        self.read("list_users", self.backend.list_users(filters))
+            .await
+    }
+    async fn list_users_sorted(
+        &self,
+        filters: Option<UserRequestFilter>,
+        sort: UserSort,
+        page: Option<Page>,
+    ) -> Result<Vec<User>> {
+        self.read(
+            "list_users_sorted",
+            self.backend.list_users_sorted(filters, sort, page),
+        )
+        .await
+    }
+    async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>> {
+        self.read("list_groups", self.backend.list_groups(filters))
+            .await
+    }
+    async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<u64> {
+        self.read("count_users", self.backend.count_users(filters))
+            .await
+    }
+    async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<u64> {
+        self.read("count_groups", self.backend.count_groups(filters))
+            .await
+    }
+    async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+        self.read("get_user_details", self.backend.get_user_details(user_id))
+            .await
+    }
+    async fn get_reports(&self, manager_id: &UserId) -> Result<Vec<User>> {
+        self.read("get_reports", self.backend.get_reports(manager_id))
+            .await
+    }
+    async fn find_user_by_email_alias(&self, alias: &str) -> Result<Option<User>> {
+        self.read(
+            "find_user_by_email_alias",
+            self.backend.find_user_by_email_alias(alias),
+        )
+        .await
+    }
+    async fn find_user_by_certificate_cn(&self, cn: &str) -> Result<Option<User>> {
+        self.read(
+            "find_user_by_certificate_cn",
+            self.backend.find_user_by_certificate_cn(cn),
+        )
+        .await
+    }
+    async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+        self.read(
+            "get_group_details",
+            self.backend.get_group_details(group_id),
+        )
+        .await
+    }
+    async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+        self.write("create_user", self.backend.create_user(request))
+            .await
+    }
+    async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+        self.write("update_user", self.backend.update_user(request))
+            .await
+    }
+    async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+        self.write("update_group", self.backend.update_group(request))
+            .await
+    }
+    async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+        self.write("delete_user", self.backend.delete_user(user_id))
+            .await
+    }
+    async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+        self.write(
+            "rename_user",
+            self.backend.rename_user(user_id, new_user_id),
+        )
+        .await
+    }
+    async fn add_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()> {
+        self.write(
+            "add_phone_number",
+            self.backend.add_phone_number(user_id, phone_number),
+        )
+        .await
+    }
+    async fn remove_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()> {
+        self.write(
+            "remove_phone_number",
+            self.backend.remove_phone_number(user_id, phone_number),
+        )
+        .await
+    }
+    async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()> {
+        self.write(
+            "set_phone_numbers",
+            self.backend.set_phone_numbers(user_id, phone_numbers),
+        )
+        .await
+    }
+    async fn update_last_login(
+        &self,
+        user_id: &UserId,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        self.write(
+            "update_last_login",
+            self.backend.update_last_login(user_id, timestamp),
+        )
+        .await
+    }
+    async fn flush_last_logins(&self) -> Result<usize> {
+        self.write("flush_last_logins", self.backend.flush_last_logins())
+            .await
+    }
+    async fn get_login_report(
+        &self,
+        since: chrono::NaiveDate,
+        page: Page,
+    ) -> Result<Vec<UserLoginStats>> {
+        self.read(
+            "get_login_report",
+            self.backend.get_login_report(since, page),
+        )
+        .await
+    }
+    async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+        self.write("create_group", self.backend.create_group(group_name))
+            .await
+    }
+    async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+        self.write("delete_group", self.backend.delete_group(group_id))
+            .await
+    }
+    async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.write(
+            "add_user_to_group",
+            self.backend.add_user_to_group(user_id, group_id),
+        )
+        .await
+    }
+    async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+        self.write(
+            "remove_user_from_group",
+            self.backend.remove_user_from_group(user_id, group_id),
+        )
+        .await
+    }
+    async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+        self.read("get_user_groups", self.backend.get_user_groups(user_id))
+            .await
+    }
+    async fn get_groups_for_users(
+        &self,
+        user_ids: &[UserId],
+    ) -> Result<HashMap<UserId, HashSet<GroupIdAndName>>> {
+        self.read(
+            "get_groups_for_users",
+            self.backend.get_groups_for_users(user_ids),
+        )
+        .await
+    }
+    async fn get_groups_containing_user_recursive(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashSet<GroupIdAndName>> {
+        self.read(
+            "get_groups_containing_user_recursive",
+            self.backend.get_groups_containing_user_recursive(user_id),
+        )
+        .await
+    }
+    fn clear_membership_cache(&self) {
+        self.backend.clear_membership_cache()
+    }
+
+    fn data_version(&self) -> u64 {
+        self.backend.data_version()
+    }
+    async fn create_invitation(&self, email: &str) -> Result<Invitation> {
+        self.write("create_invitation", self.backend.create_invitation(email))
+            .await
+    }
+    async fn get_invitation(&self, token: &str) -> Result<Invitation> {
+        self.read("get_invitation", self.backend.get_invitation(token))
+            .await
+    }
+    async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool> {
+        self.write(
+            "mark_invitation_used",
+            self.backend.mark_invitation_used(invitation_id),
+        )
+        .await
+    }
+    async fn list_pending_invitations(&self) -> Result<Vec<Invitation>> {
+        self.read(
+            "list_pending_invitations",
+            self.backend.list_pending_invitations(),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Backend: TransactionHandler + Sync> TransactionHandler for TimeoutBackendHandler<Backend> {
+    type Transaction = Backend::Transaction;
+    /// Dropping the transaction on timeout rolls it back, none of its changes are kept.
+    async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send,
+        F: for<'t> FnOnce(&'t Self::Transaction) -> BoxFuture<'t, Result<T>> + Send,
+    {
+        with_operation_timeout(
+            self.write_timeout,
+            "transaction",
+            self.backend.transaction(f),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Backend: LoginHandler + Sync> LoginHandler for TimeoutBackendHandler<Backend> {
+    async fn bind(&self, request: BindRequest) -> Result<()> {
+        self.read("bind", self.backend.bind(request)).await
+    }
+    async fn get_grace_logins_remaining(&self, user_id: &UserId) -> Result<i32> {
+        self.read(
+            "get_grace_logins_remaining",
+            self.backend.get_grace_logins_remaining(user_id),
+        )
+        .await
+    }
+    async fn has_password(&self, user_id: &UserId) -> Result<bool> {
+        self.read("has_password", self.backend.has_password(user_id))
+            .await
+    }
+}
+
+#[async_trait]
+impl<Backend: OpaqueHandler + Sync> OpaqueHandler for TimeoutBackendHandler<Backend> {
+    async fn login_start(
+        &self,
+        request: login::ClientLoginStartRequest,
+    ) -> Result<login::ServerLoginStartResponse> {
+        self.read("login_start", self.backend.login_start(request))
+            .await
+    }
+    async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId> {
+        self.read("login_finish", self.backend.login_finish(request))
+            .await
+    }
+    async fn registration_start(
+        &self,
+        request: registration::ClientRegistrationStartRequest,
+    ) -> Result<registration::ServerRegistrationStartResponse> {
+        self.write(
+            "registration_start",
+            self.backend.registration_start(request),
+        )
+        .await
+    }
+    async fn registration_finish(
+        &self,
+        request: registration::ClientRegistrationFinishRequest,
+    ) -> Result<UserId> {
+        self.write(
+            "registration_finish",
+            self.backend.registration_finish(request),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl<Backend: TcpBackendHandler + Sync + Send> TcpBackendHandler
+    for TimeoutBackendHandler<Backend>
+{
+    async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
+        // Only called on startup and by the scheduled jobs, not on behalf of a client.
+        self.backend.get_jwt_blacklist().await
+    }
+    async fn create_refresh_token(&self, user: &UserId) -> Result<(String, chrono::Duration)> {
+        self.write(
+            "create_refresh_token",
+            self.backend.create_refresh_token(user),
+        )
+        .await
+    }
+    async fn check_token(&self, refresh_token_hash: u64, user: &UserId) -> Result<bool> {
+        self.read(
+            "check_token",
+            self.backend.check_token(refresh_token_hash, user),
+        )
+        .await
+    }
+    async fn blacklist_jwts(&self, user: &UserId) -> Result<HashSet<u64>> {
+        self.write("blacklist_jwts", self.backend.blacklist_jwts(user))
+            .await
+    }
+    async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()> {
+        self.write(
+            "delete_refresh_token",
+            self.backend.delete_refresh_token(refresh_token_hash),
+        )
+        .await
+    }
+    async fn start_password_reset(&self, user: &UserId) -> Result<Option<String>> {
+        self.write(
+            "start_password_reset",
+            self.backend.start_password_reset(user),
+        )
+        .await
+    }
+    async fn use_password_reset_token(&self, token: &str) -> Result<PasswordResetTokenUse> {
+        self.write(
+            "use_password_reset_token",
+            self.backend.use_password_reset_token(token),
+        )
+        .await
+    }
+    async fn delete_expired_tokens(&self) -> Result<()> {
+        self.backend.delete_expired_tokens().await
+    }
+    async fn create_user_token(&self, user: &UserId, name: &str) -> Result<(UserToken, String)> {
+        self.write(
+            "create_user_token",
+            self.backend.create_user_token(user, name),
+        )
+        .await
+    }
+    async fn list_user_tokens(&self, user: &UserId) -> Result<Vec<UserToken>> {
+        self.read("list_user_tokens", self.backend.list_user_tokens(user))
+            .await
+    }
+    async fn revoke_user_token(&self, user: &UserId, token_id: i32) -> Result<bool> {
+        self.write(
+            "revoke_user_token",
+            self.backend.revoke_user_token(user, token_id),
+        )
+        .await
+    }
+    async fn check_user_token(&self, token: &str) -> Result<Option<UserId>> {
+        self.read("check_user_token", self.backend.check_user_token(token))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::ConfigurationBuilder;
+
+    #[tokio::test]
+    async fn test_operation_timeout() {
+        let slow_operation = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(())
+        };
+        let error = with_operation_timeout(Duration::from_millis(10), "bind", slow_operation)
+            .await
+            .unwrap_err();
+        assert!(error.is_operation_timeout());
+        assert!(!error.is_query_timeout());
+        with_operation_timeout(Duration::from_millis(0), "bind", async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_and_write_timeouts() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_count_users().return_once(|_| Ok(3));
+        let config = ConfigurationBuilder::default()
+            .backend_read_timeout_ms(1000)
+            .backend_write_timeout_ms(2000)
+            .build()
+            .unwrap();
+        let handler = TimeoutBackendHandler::new(mock, &config);
+        assert_eq!(handler.read_timeout, Duration::from_millis(1000));
+        assert_eq!(handler.write_timeout, Duration::from_millis(2000));
+        assert_eq!(handler.count_users(None).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_create_group()
+            .withf(|name| name == "Group1")
+            .return_once(|_| Ok(GroupId(3)));
+        let config = ConfigurationBuilder::default().build().unwrap();
+        let handler = TimeoutBackendHandler::new(mock, &config);
+        let group_id = handler
+            .transaction(|txn| {
+                Box::pin(async move { BackendTransaction::create_group(txn, "Group1").await })
+            })
+            .await
+            .unwrap();
+        assert_eq!(group_id, GroupId(3));
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_not_cancelled() {
+        let config = ConfigurationBuilder::default()
+            .backend_write_timeout_ms(10)
+            .build()
+            .unwrap();
+        let handler = TimeoutBackendHandler::new(MockTestBackendHandler::new(), &config);
+        let written = std::sync::atomic::AtomicBool::new(false);
+        handler
+            .write("delete_user", async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                written.store(true, std::sync::atomic::Ordering::Relaxed);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert!(written.load(std::sync::atomic::Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::domain::handler::User;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The user fields that can be restricted, as named in the configuration.
+pub const USER_FIELDS: &[&str] = &[
+    "email",
+    "display_name",
+    "first_name",
+    "last_name",
+    "mail_aliases",
+    "mail_forwarding",
+    "phone_numbers",
+];
+
+/// Who can see an attribute of a user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeVisibility {
+    Everyone,
+    /// The admins and the user themselves.
+    #[serde(rename = "self")]
+    SelfAndAdmins,
+    Admins,
+}
+
+/// Maps user fields to who can see them. The fields that are not listed are visible to everyone.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct AttributeVisibilityPolicy(HashMap<String, AttributeVisibility>);
+
+impl AttributeVisibilityPolicy {
+    pub fn new(visibility: HashMap<String, AttributeVisibility>) -> Self {
+        Self(visibility)
+    }
+
+    /// Returns the first field of the policy that is not a known user field.
+    pub fn find_unknown_field(&self) -> Option<&str> {
+        self.0
+            .keys()
+            .map(String::as_str)
+            .find(|field| !USER_FIELDS.contains(field))
+    }
+
+    /// Whether the viewer can see the field of a user. `is_owner` is true when the viewer is the
+    /// user.
+    pub fn is_visible(&self, field: &str, is_admin: bool, is_owner: bool) -> bool {
+        match self.0.get(field) {
+            None | Some(AttributeVisibility::Everyone) => true,
+            Some(AttributeVisibility::SelfAndAdmins) => is_admin || is_owner,
+            Some(AttributeVisibility::Admins) => is_admin,
+        }
+    }
+
+    /// Empties the fields of the user that the viewer can't see, see `is_visible`.
+    pub fn hide_fields(&self, user: &mut User, is_admin: bool, is_owner: bool) {
+        let is_visible = |field| self.is_visible(field, is_admin, is_owner);
+        if !is_visible("email") {
+            user.email.clear();
+        }
+        if !is_visible("display_name") {
+            user.display_name.clear();
+        }
+        if !is_visible("first_name") {
+            user.first_name.clear();
+        }
+        if !is_visible("last_name") {
+            user.last_name.clear();
+        }
+        if !is_visible("mail_aliases") {
+            user.mail_aliases.clear();
+        }
+        if !is_visible("mail_forwarding") {
+            user.mail_forwarding.clear();
+        }
+        if !is_visible("phone_numbers") {
+            user.phone_numbers.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_visible() {
+        use figment::{
+            providers::{Format, Toml},
+            Figment,
+        };
+        let policy: AttributeVisibilityPolicy = Figment::from(Toml::string(
+            r#"
+            email = "self"
+            mail_forwarding = "admins"
+            display_name = "everyone"
+            "#,
+        ))
+        .extract()
+        .unwrap();
+        assert_eq!(policy.find_unknown_field(), None);
+        for (field, expected) in [
+            ("first_name", [true, true, true]),
+            ("display_name", [true, true, true]),
+            ("email", [true, true, false]),
+            ("mail_forwarding", [true, false, false]),
+        ] {
+            assert_eq!(
+                [
+                    policy.is_visible(field, true, false),
+                    policy.is_visible(field, false, true),
+                    policy.is_visible(field, false, false),
+                ],
+                expected,
+                "{}",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn test_hide_fields() {
+        let policy = AttributeVisibilityPolicy::new(
+            [
+                ("email".to_string(), AttributeVisibility::SelfAndAdmins),
+                ("phone_numbers".to_string(), AttributeVisibility::Admins),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let user = User {
+            email: "bob@example.com".to_string(),
+            display_name: "Bob".to_string(),
+            phone_numbers: vec!["+33123456789".to_string()],
+            ..Default::default()
+        };
+        let mut hidden = user.clone();
+        policy.hide_fields(&mut hidden, false, false);
+        assert_eq!(
+            hidden,
+            User {
+                email: String::new(),
+                phone_numbers: vec![],
+                ..user.clone()
+            }
+        );
+        let mut owned = user.clone();
+        policy.hide_fields(&mut owned, false, true);
+        assert_eq!(
+            owned,
+            User {
+                phone_numbers: vec![],
+                ..user.clone()
+            }
+        );
+        let mut admin = user.clone();
+        policy.hide_fields(&mut admin, true, false);
+        assert_eq!(admin, user);
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let policy = AttributeVisibilityPolicy::new(
+            [("phone".to_string(), AttributeVisibility::Admins)]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(policy.find_unknown_field(), Some("phone"));
+    }
+}
@@ -6,7 +6,7 @@ use std::task::{Context, Poll};
 use actix_web::{
     cookie::{Cookie, SameSite},
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    error::{ErrorBadRequest, ErrorUnauthorized},
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorUnauthorized},
     web, HttpRequest, HttpResponse,
 };
 use actix_web_httpauth::extractors::bearer::BearerAuth;
@@ -20,13 +20,17 @@ use log::*;
 use sha2::Sha512;
 use time::ext::NumericalDuration;
 
-use lldap_auth::{login, opaque, password_reset, registration, JWTClaims};
+use lldap_auth::{invitation, login, opaque, password_reset, registration, JWTClaims};
 
 use crate::{
     domain::{
         error::DomainError,
-        handler::{BackendHandler, BindRequest, GroupIdAndName, LoginHandler, UserId},
+        handler::{
+            BackendHandler, BackendTransaction, BindRequest, CreateUserRequest, GroupId,
+            GroupIdAndName, LoginHandler, TransactionHandler, UserId, UserRequestFilter,
+        },
         opaque_handler::OpaqueHandler,
+        sql_opaque_handler::{check_password_length, register_password},
     },
     infra::{
         tcp_backend_handler::*,
@@ -35,14 +39,35 @@ use crate::{
 };
 
 type Token<S> = jwt::Token<jwt::Header, JWTClaims, S>;
+
+/// The name of the admin group in the JWT claims, which the frontend relies on.
+const ADMIN_GROUP_CLAIM: &str = "lldap_admin";
 type SignedToken = Token<jwt::token::Signed>;
 
-fn create_jwt(key: &Hmac<Sha512>, user: String, groups: HashSet<GroupIdAndName>) -> SignedToken {
+/// The claims only have the names of the groups: the admin group, whatever its name, is listed
+/// as `lldap_admin`, and no other group is.
+pub(crate) fn create_jwt(
+    key: &Hmac<Sha512>,
+    user: String,
+    groups: HashSet<GroupIdAndName>,
+    admin_group_id: GroupId,
+) -> SignedToken {
     let claims = JWTClaims {
         exp: Utc::now() + chrono::Duration::days(1),
         iat: Utc::now(),
         user,
-        groups: groups.into_iter().map(|g| g.1).collect(),
+        groups: groups
+            .into_iter()
+            .filter_map(|GroupIdAndName(id, name)| {
+                if id == admin_group_id {
+                    Some(ADMIN_GROUP_CLAIM.to_string())
+                } else if name == ADMIN_GROUP_CLAIM {
+                    None
+                } else {
+                    Some(name)
+                }
+            })
+            .collect(),
     };
     let header = jwt::Header {
         algorithm: jwt::AlgorithmType::Hs512,
@@ -106,7 +131,7 @@ where
         }
         Err(e) => Err(e),
     }
-    .map(|groups| create_jwt(jwt_key, user.to_string(), groups))
+    .map(|groups| create_jwt(jwt_key, user.to_string(), groups, data.admin_group_id))
     .map(|token| {
         HttpResponse::Ok()
             .cookie(
@@ -132,6 +157,9 @@ async fn get_password_reset_step1<Backend>(
 where
     Backend: TcpBackendHandler + BackendHandler + 'static,
 {
+    if data.maintenance_mode.is_enabled() {
+        return read_only_response();
+    }
     let user_id = match request.match_info().get("user_id") {
         None => return HttpResponse::BadRequest().body("Missing user ID"),
         Some(id) => UserId::new(id),
@@ -153,8 +181,10 @@ where
         &user.email,
         &token,
         &data.server_url,
-        &data.mail_options,
-    ) {
+        &data.mailer,
+    )
+    .await
+    {
         warn!("Error sending email: {:#?}", e);
         return HttpResponse::InternalServerError().body(format!("Could not send email: {}", e));
     }
@@ -172,20 +202,22 @@ where
         None => return HttpResponse::BadRequest().body("Missing token"),
         Some(token) => token,
     };
-    let user_id = match data
-        .backend_handler
-        .get_user_id_for_password_reset_token(token)
-        .await
-    {
-        Err(_) => return HttpResponse::Unauthorized().body("Invalid or expired token"),
-        Ok(user_id) => user_id,
+    let user_id = match data.backend_handler.use_password_reset_token(token).await {
+        Ok(PasswordResetTokenUse::Valid(user_id)) => user_id,
+        Ok(PasswordResetTokenUse::AlreadyUsed) => {
+            return HttpResponse::Gone().body("The token was already used")
+        }
+        Ok(PasswordResetTokenUse::Invalid) | Err(_) => {
+            return HttpResponse::Unauthorized().body("Invalid or expired token")
+        }
     };
-    let _ = data
-        .backend_handler
-        .delete_password_reset_token(token)
-        .await;
     let groups = HashSet::new();
-    let token = create_jwt(&data.jwt_key, user_id.to_string(), groups);
+    let token = create_jwt(
+        &data.jwt_key,
+        user_id.to_string(),
+        groups,
+        data.admin_group_id,
+    );
     HttpResponse::Ok()
         .cookie(
             Cookie::build("token", token.as_str())
@@ -255,21 +287,151 @@ where
         .finish()
 }
 
+/// The new user's ID is the local part of the invited email address, with a numeric suffix if
+/// another user already has it.
+async fn new_user_id_from_email<Transaction: BackendTransaction>(
+    txn: &Transaction,
+    email: &str,
+) -> crate::domain::error::Result<UserId> {
+    let local_part = email
+        .split('@')
+        .next()
+        .filter(|local_part| !local_part.is_empty())
+        .ok_or_else(|| {
+            DomainError::ValidationError("invitation email".to_string(), email.to_string())
+        })?;
+    for suffix in std::iter::once(String::new()).chain((2..100).map(|i| i.to_string())) {
+        let user_id = UserId::new(&format!("{}{}", local_part, suffix));
+        match txn.get_user_details(&user_id).await {
+            Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => return Ok(user_id),
+            Ok(_) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(DomainError::ConstraintViolation("user ID".to_string()))
+}
+
+async fn post_accept_invitation<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: web::Json<invitation::AcceptInvitationRequest>,
+) -> HttpResponse
+where
+    Backend: TcpBackendHandler + BackendHandler + TransactionHandler + OpaqueHandler + 'static,
+    Backend::Transaction: OpaqueHandler,
+{
+    if data.maintenance_mode.is_enabled() {
+        return read_only_response();
+    }
+    let request = request.into_inner();
+    let invitation = match data.backend_handler.get_invitation(&request.token).await {
+        Err(_) => return HttpResponse::Unauthorized().body("Invalid invitation token"),
+        Ok(invitation) => invitation,
+    };
+    if !invitation.is_pending() {
+        return HttpResponse::Gone().body("The invitation has expired or was already used");
+    }
+    if request.password.len() < 8 {
+        return HttpResponse::BadRequest().body("Invalid password. Min length: 8");
+    }
+    let display_name = request.display_name;
+    let password = secstr::SecUtf8::from(request.password);
+    // Using the invitation, creating the user and setting their password either all happen or
+    // none do, so a concurrent acceptance of the same invitation can't create a second user.
+    let result = data
+        .backend_handler
+        .transaction(|txn| {
+            Box::pin(async move {
+                if !txn.mark_invitation_used(invitation.id).await? {
+                    return Ok(None);
+                }
+                let user_id = new_user_id_from_email(txn, &invitation.email).await?;
+                txn.create_user(CreateUserRequest {
+                    user_id: user_id.clone(),
+                    email: invitation.email,
+                    display_name: Some(display_name),
+                    ..Default::default()
+                })
+                .await?;
+                register_password(txn, &user_id, &password).await?;
+                Ok(Some(user_id))
+            })
+        })
+        .await;
+    match result {
+        Ok(Some(user_id)) => {
+            info!(r#"User "{}" accepted their invitation"#, user_id);
+            HttpResponse::Ok().finish()
+        }
+        Ok(None) => HttpResponse::Gone().body("The invitation has expired or was already used"),
+        Err(e) => error_to_http_response(e),
+    }
+}
+
+pub(crate) fn read_only_response() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body("The server is in read-only maintenance mode")
+}
+
 pub(crate) fn error_to_api_response<T>(error: DomainError) -> ApiResult<T> {
     ApiResult::Right(error_to_http_response(error))
 }
 
 pub type ApiResult<M> = actix_web::Either<web::Json<M>, HttpResponse>;
 
+/// Resolves the identifier entered in the login form to a user id, by looking it up in the
+/// `attribute` column of the users and in their ids. If no user matches, the identifier is used
+/// as a user id. The `attribute` is unique, see `web_login_attribute`, but its value can still be
+/// another user's id: the login is ambiguous, and fails.
+async fn resolve_login_name<Backend: BackendHandler>(
+    backend_handler: &Backend,
+    attribute: &str,
+    name: &str,
+) -> std::result::Result<UserId, DomainError> {
+    if attribute == "user_id" {
+        return Ok(name.to_string());
+    }
+    let users = backend_handler
+        .list_users(Some(UserRequestFilter::Or(vec![
+            UserRequestFilter::Equality(attribute.to_string(), name.to_string()),
+            UserRequestFilter::UserId(UserId::new(name)),
+        ])))
+        .await?;
+    match users.as_slice() {
+        [] => Ok(name.to_string()),
+        [user] => Ok(user.user_id.to_string()),
+        _ => {
+            warn!(
+                r#"Login failed: "{}" matches the {} of {} users"#,
+                name,
+                attribute,
+                users.len()
+            );
+            Err(DomainError::AuthenticationError(
+                "Invalid username or password".to_string(),
+            ))
+        }
+    }
+}
+
 async fn opaque_login_start<Backend>(
     data: web::Data<AppState<Backend>>,
     request: web::Json<login::ClientLoginStartRequest>,
 ) -> ApiResult<login::ServerLoginStartResponse>
 where
-    Backend: OpaqueHandler + 'static,
+    Backend: BackendHandler + OpaqueHandler + 'static,
 {
+    let mut request = request.into_inner();
+    request.username = match resolve_login_name(
+        &data.backend_handler,
+        &data.web_login_attribute,
+        &request.username,
+    )
+    .await
+    {
+        Ok(name) => name,
+        Err(e) => return error_to_api_response(e),
+    };
     data.backend_handler
-        .login_start(request.into_inner())
+        .login_start(request)
         .await
         .map(|res| ApiResult::Left(web::Json(res)))
         .unwrap_or_else(error_to_api_response)
@@ -289,7 +451,7 @@ where
         .and_then(|g| async { Ok((g, data.backend_handler.create_refresh_token(name).await?)) })
         .await
         .map(|(groups, (refresh_token, max_age))| {
-            let token = create_jwt(&data.jwt_key, name.to_string(), groups);
+            let token = create_jwt(&data.jwt_key, name.to_string(), groups, data.admin_group_id);
             let refresh_token_plus_name = refresh_token + "+" + name.as_str();
 
             HttpResponse::Ok()
@@ -343,48 +505,61 @@ where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
     let password = &request.password;
-    let mut rng = rand::rngs::OsRng;
-    let opaque::client::login::ClientLoginStartResult { state, message } =
-        match opaque::client::login::start_login(password, &mut rng) {
-            Ok(n) => n,
-            Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Internal Server Error: {:#?}", e))
-            }
-        };
-
-    let username = request.username.clone();
-    let start_request = login::ClientLoginStartRequest {
-        username: username.clone(),
-        login_start_request: message,
-    };
-
-    let start_response = match data.backend_handler.login_start(start_request).await {
-        Ok(n) => n,
+    if let Err(e) = check_password_length(password, data.max_password_length) {
+        return error_to_http_response(e);
+    }
+    let username = match resolve_login_name(
+        &data.backend_handler,
+        &data.web_login_attribute,
+        &request.username,
+    )
+    .await
+    {
+        Ok(name) => name,
         Err(e) => return error_to_http_response(e),
     };
+    let mut result = opaque_login(&data.backend_handler, &username, password, false).await;
+    if result.is_err() && opaque::needs_raw_password_fallback(password) {
+        // The password may have been registered before the passwords were prepared.
+        result = opaque_login(&data.backend_handler, &username, password, true).await;
+    }
+    match result {
+        Ok(name) => get_login_successful_response(&data, &name).await,
+        Err(e) => error_to_http_response(e),
+    }
+}
 
+/// Runs the client side of the OPAQUE login against the backend, with the raw password instead
+/// of the prepared one if `raw_password` is set. Returns the ID of the user.
+async fn opaque_login<Backend: OpaqueHandler>(
+    backend_handler: &Backend,
+    username: &str,
+    password: &str,
+    raw_password: bool,
+) -> std::result::Result<UserId, DomainError> {
+    let mut rng = rand::rngs::OsRng;
+    let opaque::client::login::ClientLoginStartResult { state, message } = if raw_password {
+        opaque::client::login::start_login_with_raw_password(password, &mut rng)
+    } else {
+        opaque::client::login::start_login(password, &mut rng)
+    }
+    .map_err(|e| DomainError::InternalError(format!("{:#?}", e)))?;
+    let start_response = backend_handler
+        .login_start(login::ClientLoginStartRequest {
+            username: username.to_string(),
+            login_start_request: message,
+        })
+        .await?;
     let login_finish =
-        match opaque::client::login::finish_login(state, start_response.credential_response) {
-            Err(_) => {
-                return error_to_http_response(DomainError::AuthenticationError(String::from(
-                    "Invalid username or password",
-                )))
-            }
-            Ok(l) => l,
-        };
-
-    let finish_request = login::ClientLoginFinishRequest {
-        server_data: start_response.server_data,
-        credential_finalization: login_finish.message,
-    };
-
-    let name = match data.backend_handler.login_finish(finish_request).await {
-        Ok(n) => n,
-        Err(e) => return error_to_http_response(e),
-    };
-
-    get_login_successful_response(&data, &name).await
+        opaque::client::login::finish_login(state, start_response.credential_response).map_err(
+            |_| DomainError::AuthenticationError(String::from("Invalid username or password")),
+        )?;
+    backend_handler
+        .login_finish(login::ClientLoginFinishRequest {
+            server_data: start_response.server_data,
+            credential_finalization: login_finish.message,
+        })
+        .await
 }
 
 async fn post_authorize<Backend>(
@@ -394,8 +569,27 @@ async fn post_authorize<Backend>(
 where
     Backend: TcpBackendHandler + BackendHandler + LoginHandler + 'static,
 {
-    let name = request.name.clone();
-    if let Err(e) = data.backend_handler.bind(request.into_inner()).await {
+    if let Err(e) = check_password_length(&request.password, data.max_password_length) {
+        return error_to_http_response(e);
+    }
+    let name = match resolve_login_name(
+        &data.backend_handler,
+        &data.web_login_attribute,
+        request.name.as_str(),
+    )
+    .await
+    {
+        Ok(name) => UserId::new(&name),
+        Err(e) => return error_to_http_response(e),
+    };
+    if let Err(e) = data
+        .backend_handler
+        .bind(BindRequest {
+            name: name.clone(),
+            password: request.into_inner().password,
+        })
+        .await
+    {
         return error_to_http_response(e);
     }
     get_login_successful_response(&data, &name).await
@@ -410,6 +604,9 @@ where
     Backend: OpaqueHandler + 'static,
 {
     use actix_web::FromRequest;
+    if data.maintenance_mode.is_enabled() {
+        return ApiResult::Right(read_only_response());
+    }
     let validation_result = match BearerAuth::from_request(&request, &mut payload.0)
         .await
         .ok()
@@ -447,19 +644,40 @@ where
 }
 
 async fn opaque_register_finish<Backend>(
+    http_request: HttpRequest,
     data: web::Data<AppState<Backend>>,
     request: web::Json<registration::ClientRegistrationFinishRequest>,
 ) -> HttpResponse
 where
     Backend: TcpBackendHandler + BackendHandler + OpaqueHandler + 'static,
 {
-    if let Err(e) = data
+    use actix_web::FromRequest;
+    if data.maintenance_mode.is_enabled() {
+        return read_only_response();
+    }
+    let user_id = match data
         .backend_handler
         .registration_finish(request.into_inner())
         .await
     {
-        return error_to_http_response(e);
-    }
+        Ok(user_id) => user_id,
+        Err(e) => return error_to_http_response(e),
+    };
+    // The token was checked in the first step, it's only used to tell who made the change.
+    let changed_by = BearerAuth::extract(&http_request)
+        .await
+        .ok()
+        .and_then(|bearer| check_if_token_is_valid(&data, bearer.token()).ok())
+        .map(|validation_result| validation_result.user)
+        .unwrap_or_else(|| "an unknown user".to_string());
+    super::password_change::on_password_changed(
+        &data.backend_handler,
+        &user_id,
+        &changed_by,
+        &data.mailer,
+        data.admin_group_id,
+    )
+    .await;
     HttpResponse::Ok().finish()
 }
 
@@ -519,6 +737,7 @@ where
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct ValidationResults {
     pub user: String,
     pub is_admin: bool,
@@ -534,7 +753,7 @@ impl ValidationResults {
     }
 
     pub fn can_access(&self, user: &str) -> bool {
-        self.is_admin || self.user == user
+        self.is_admin || UserId::new(&self.user) == UserId::new(user)
     }
 }
 
@@ -561,16 +780,72 @@ pub(crate) fn check_if_token_is_valid<Backend>(
     if state.jwt_blacklist.read().unwrap().contains(&jwt_hash) {
         return Err(ErrorUnauthorized("JWT was logged out"));
     }
-    let is_admin = token.claims().groups.contains("lldap_admin");
+    let is_admin = token.claims().groups.contains(ADMIN_GROUP_CLAIM);
     Ok(ValidationResults {
         user: token.claims().user.clone(),
         is_admin,
     })
 }
 
+/// The identity of the request: the one set by `CertAuthMiddleware` for a connection with a
+/// client certificate, or else the one of the JWT or API token.
+pub(crate) async fn check_request_identity<Backend>(
+    state: &AppState<Backend>,
+    request: &HttpRequest,
+) -> Result<ValidationResults, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler,
+{
+    use actix_web::FromRequest;
+    let certificate_identity = request.extensions().get::<ValidationResults>().cloned();
+    if let Some(identity) = certificate_identity {
+        return Ok(identity);
+    }
+    let bearer = BearerAuth::extract(request).await?;
+    check_bearer_token(state, bearer.token()).await
+}
+
+/// Accepts the API tokens issued to the users, in addition to the JWTs.
+pub(crate) async fn check_bearer_token<Backend>(
+    state: &AppState<Backend>,
+    token_str: &str,
+) -> Result<ValidationResults, actix_web::Error>
+where
+    Backend: TcpBackendHandler + BackendHandler,
+{
+    if !token_str.starts_with(USER_TOKEN_PREFIX) {
+        return check_if_token_is_valid(state, token_str);
+    }
+    let user = state
+        .backend_handler
+        .check_user_token(token_str)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+        .ok_or_else(|| ErrorUnauthorized("Invalid API token"))?;
+    // Unlike the JWTs, the groups aren't part of the token: removing the user from the admin
+    // group applies to the existing tokens.
+    let is_admin = state
+        .backend_handler
+        .get_user_groups(&user)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+        .iter()
+        .any(|group| group.0 == state.admin_group_id);
+    Ok(ValidationResults {
+        user: user.to_string(),
+        is_admin,
+    })
+}
+
 pub fn configure_server<Backend>(cfg: &mut web::ServiceConfig)
 where
-    Backend: TcpBackendHandler + LoginHandler + OpaqueHandler + BackendHandler + 'static,
+    Backend: TcpBackendHandler
+        + LoginHandler
+        + OpaqueHandler
+        + BackendHandler
+        + TransactionHandler
+        + 'static,
+    Backend::Transaction: OpaqueHandler,
 {
     cfg.service(web::resource("").route(web::post().to(post_authorize::<Backend>)))
         .service(
@@ -592,6 +867,10 @@ where
                 .route(web::get().to(get_password_reset_step2::<Backend>)),
         )
         .service(web::resource("/logout").route(web::get().to(get_logout::<Backend>)))
+        .service(
+            web::resource("/accept-invitation")
+                .route(web::post().to(post_accept_invitation::<Backend>)),
+        )
         .service(
             web::scope("/opaque/register")
                 .wrap(CookieToHeaderTranslatorFactory)
@@ -604,3 +883,204 @@ where
                 ),
         );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::{MockTestBackendHandler, User},
+            sql_backend_handler::SqlBackendHandler,
+        },
+        infra::configuration::ConfigurationBuilder,
+    };
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+    use mockall::predicate::eq;
+
+    #[tokio::test]
+    async fn test_resolve_login_name() {
+        let mut mock = MockTestBackendHandler::new();
+        let email_filter = |email: &str| {
+            Some(UserRequestFilter::Or(vec![
+                UserRequestFilter::Equality("email".to_string(), email.to_string()),
+                UserRequestFilter::UserId(UserId::new(email)),
+            ]))
+        };
+        mock.expect_list_users()
+            .with(eq(email_filter("bob@example.com")))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_list_users()
+            .with(eq(email_filter("admin")))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("admin"),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_list_users()
+            .with(eq(email_filter("nobody")))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        // Another user with "admin" as their email.
+        mock.expect_list_users()
+            .with(eq(email_filter("Admin")))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    User {
+                        user_id: UserId::new("admin"),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: UserId::new("mallory"),
+                        email: "Admin".to_string(),
+                        ..Default::default()
+                    },
+                ])
+            });
+        mock.expect_list_users()
+            .with(eq(email_filter("shared@example.com")))
+            .times(1)
+            .return_once(|_| Ok(vec![User::default(), User::default()]));
+        assert_eq!(
+            resolve_login_name(&mock, "user_id", "bob@example.com")
+                .await
+                .unwrap(),
+            "bob@example.com"
+        );
+        assert_eq!(
+            resolve_login_name(&mock, "email", "bob@example.com")
+                .await
+                .unwrap(),
+            "bob"
+        );
+        // Users without a matching email can still log in with their user id.
+        assert_eq!(
+            resolve_login_name(&mock, "email", "admin").await.unwrap(),
+            "admin"
+        );
+        assert_eq!(
+            resolve_login_name(&mock, "email", "nobody").await.unwrap(),
+            "nobody"
+        );
+        resolve_login_name(&mock, "email", "Admin")
+            .await
+            .unwrap_err();
+        resolve_login_name(&mock, "email", "shared@example.com")
+            .await
+            .unwrap_err();
+    }
+
+    async fn accept_invitation(
+        data: &web::Data<AppState<SqlBackendHandler>>,
+        token: &str,
+    ) -> StatusCode {
+        let app = init_service(App::new().app_data(data.clone()).route(
+            "/invitation/accept",
+            web::post().to(post_accept_invitation::<SqlBackendHandler>),
+        ))
+        .await;
+        let request = TestRequest::post()
+            .uri("/invitation/accept")
+            .set_json(&invitation::AcceptInvitationRequest {
+                token: token.to_string(),
+                display_name: "Alice".to_string(),
+                password: "alice_password".to_string(),
+            })
+            .to_request();
+        call_service(&app, request).await.status()
+    }
+
+    #[actix_rt::test]
+    async fn test_accept_invitation() {
+        let config = ConfigurationBuilder::default().build().unwrap();
+        let data = AppState::new_for_tests(&config).await;
+        let handler = &data.backend_handler;
+        let invitation = handler
+            .create_invitation("alice@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            accept_invitation(&data, "not_a_token").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            accept_invitation(&data, &invitation.token).await,
+            StatusCode::OK
+        );
+        handler
+            .bind(BindRequest {
+                name: UserId::new("alice"),
+                password: "alice_password".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            accept_invitation(&data, &invitation.token).await,
+            StatusCode::GONE
+        );
+        assert_eq!(handler.list_users(None).await.unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_accept_invitation_user_id_collision() {
+        let config = ConfigurationBuilder::default().build().unwrap();
+        let data = AppState::new_for_tests(&config).await;
+        let handler = &data.backend_handler;
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("alice"),
+                email: "alice@other.example.com".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let invitation = handler
+            .create_invitation("alice@example.com")
+            .await
+            .unwrap();
+        assert_eq!(
+            accept_invitation(&data, &invitation.token).await,
+            StatusCode::OK
+        );
+        let user = handler
+            .get_user_details(&UserId::new("alice2"))
+            .await
+            .unwrap();
+        assert_eq!(user.email, "alice@example.com");
+    }
+
+    #[actix_rt::test]
+    async fn test_jwt_admin_group() {
+        let config = ConfigurationBuilder::default()
+            .admin_group_id(42)
+            .build()
+            .unwrap();
+        let data = AppState::new_for_tests(&config).await;
+        let is_admin = |groups: &[(i32, &str)]| {
+            let groups = groups
+                .iter()
+                .map(|(id, name)| GroupIdAndName(GroupId(*id), name.to_string()))
+                .collect();
+            let token = create_jwt(&data.jwt_key, "bob".to_string(), groups, GroupId(42));
+            check_if_token_is_valid(&data, token.as_str())
+                .unwrap()
+                .is_admin
+        };
+        // The admin group is recognized by its ID, not by its name.
+        assert!(is_admin(&[(42, "admins")]));
+        assert!(!is_admin(&[(1, "lldap_admin")]));
+        assert!(!is_admin(&[]));
+    }
+}
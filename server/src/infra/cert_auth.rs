@@ -0,0 +1,465 @@
+use crate::{
+    domain::handler::BackendHandler,
+    infra::{auth_service::ValidationResults, tcp_server::AppState},
+};
+use actix_http::Extensions;
+use actix_rt::net::TcpStream;
+use actix_tls::accept::openssl::SslStream;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorUnauthorized,
+    web,
+};
+use anyhow::{anyhow, Context, Result};
+use futures::future::{ok, Ready};
+use futures_util::FutureExt;
+use log::*;
+use openssl::{
+    nid::Nid,
+    ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode},
+    x509::{X509Name, X509Ref},
+};
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    rc::Rc,
+    task::{Context as TaskContext, Poll},
+};
+
+/// Common name of the client certificate of the connection. Only certificates signed by the
+/// configured CA get this far: the others fail the TLS handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificateCn(pub String);
+
+/// TLS acceptor for the HTTP server, asking the clients for a certificate signed by the CA.
+pub fn build_mtls_acceptor(cert_file: &str, key_file: &str, ca_file: &str) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder
+        .set_private_key_file(key_file, SslFiletype::PEM)
+        .with_context(|| format!("while reading the key file {}", key_file))?;
+    builder
+        .set_certificate_chain_file(cert_file)
+        .with_context(|| format!("while reading the certificate file {}", cert_file))?;
+    builder
+        .set_ca_file(ca_file)
+        .with_context(|| format!("while reading the CA file {}", ca_file))?;
+    builder.set_client_ca_list(
+        X509Name::load_client_ca_file(ca_file)
+            .with_context(|| format!("while reading the CA file {}", ca_file))?,
+    );
+    // The certificate is optional, so that the clients without one can use a JWT.
+    builder.set_verify(SslVerifyMode::PEER);
+    Ok(builder.build())
+}
+
+pub fn get_certificate_cn(certificate: &X509Ref) -> Option<String> {
+    certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|cn| cn.to_string())
+}
+
+/// Stores the common name of the client certificate, if any, in the connection data.
+pub fn add_client_certificate_cn(stream: &SslStream<TcpStream>, extensions: &mut Extensions) {
+    if let Some(cn) = stream
+        .ssl()
+        .peer_certificate()
+        .as_deref()
+        .and_then(get_certificate_cn)
+    {
+        extensions.insert(ClientCertificateCn(cn));
+    }
+}
+
+async fn authenticate<Backend: BackendHandler>(
+    data: &AppState<Backend>,
+    cn: &str,
+) -> Result<ValidationResults> {
+    let user = data
+        .backend_handler
+        .find_user_by_certificate_cn(cn)
+        .await?
+        .ok_or_else(|| anyhow!("No user for the client certificate \"{}\"", cn))?;
+    let groups = data.backend_handler.get_user_groups(&user.user_id).await?;
+    Ok(ValidationResults {
+        user: user.user_id.to_string(),
+        is_admin: groups.iter().any(|g| g.0 == data.admin_group_id),
+    })
+}
+
+/// Authenticates the requests of the connections with a client certificate as the user with the
+/// common name of the certificate: the identity is added to the request extensions, where the
+/// handlers look for it before the JWT of the request, see `check_request_identity`.
+pub struct CertAuthMiddlewareFactory<Backend> {
+    _phantom: PhantomData<Backend>,
+}
+
+impl<Backend> Default for CertAuthMiddlewareFactory<Backend> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<S, Backend> Transform<S, ServiceRequest> for CertAuthMiddlewareFactory<Backend>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    Backend: BackendHandler + 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = CertAuthMiddleware<S, Backend>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CertAuthMiddleware {
+            service: Rc::new(service),
+            _phantom: PhantomData,
+        })
+    }
+}
+
+pub struct CertAuthMiddleware<S, Backend> {
+    service: Rc<S>,
+    _phantom: PhantomData<Backend>,
+}
+
+impl<S, Backend> Service<ServiceRequest> for CertAuthMiddleware<S, Backend>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    Backend: BackendHandler + 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let cn = match req.request().conn_data::<ClientCertificateCn>() {
+            Some(cn) => cn.0.clone(),
+            None => return Box::pin(self.service.call(req)),
+        };
+        let data = req
+            .app_data::<web::Data<AppState<Backend>>>()
+            .expect("The app state is missing")
+            .clone();
+        let service = self.service.clone();
+        async move {
+            match authenticate(&data, &cn).await {
+                Ok(identity) => {
+                    req.extensions_mut().insert(identity);
+                    service.call(req).await
+                }
+                Err(e) => {
+                    warn!("Client certificate authentication failed: {:#}", e);
+                    Ok(req.error_response(ErrorUnauthorized(format!("{:#}", e))))
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        ssl::SslConnector,
+        x509::{extension::BasicConstraints, X509Builder, X509NameBuilder, X509},
+    };
+    use std::io::{Read, Write};
+
+    fn make_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    /// A certificate for the common name, self-signed if there is no issuer.
+    fn make_certificate(
+        cn: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        match issuer {
+            Some((issuer, _)) => builder.set_issuer_name(issuer.subject_name()),
+            None => builder.set_issuer_name(&name),
+        }
+        .unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if issuer.is_none() {
+            builder
+                .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+        }
+        builder
+            .sign(issuer.map_or(key, |(_, key)| key), MessageDigest::sha256())
+            .unwrap();
+        builder.build()
+    }
+
+    struct TestFiles {
+        dir: std::path::PathBuf,
+    }
+
+    impl TestFiles {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("lldap_{}_{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self { dir }
+        }
+
+        fn write(&self, name: &str, contents: &[u8]) -> String {
+            let path = self.dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path.to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TestFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Runs a TLS handshake with the acceptor, and returns the CN of the client certificate seen
+    /// by the server.
+    fn handshake(
+        acceptor: &SslAcceptor,
+        client: Option<(X509, PKey<Private>)>,
+    ) -> std::result::Result<Option<String>, ()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            if let Some((certificate, key)) = &client {
+                connector.set_certificate(certificate).unwrap();
+                connector.set_private_key(key).unwrap();
+            }
+            let stream = std::net::TcpStream::connect(address).unwrap();
+            if let Ok(mut stream) = connector.build().connect("localhost", stream) {
+                let _ = stream.write_all(b"x");
+                let _ = stream.read(&mut [0u8; 1]);
+            }
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let result = acceptor
+            .accept(stream)
+            .map(|mut stream| {
+                let _ = stream.read(&mut [0u8; 1]);
+                stream
+                    .ssl()
+                    .peer_certificate()
+                    .as_deref()
+                    .and_then(get_certificate_cn)
+            })
+            .map_err(|_| ());
+        client.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn test_get_certificate_cn() {
+        let key = make_key();
+        assert_eq!(
+            get_certificate_cn(&make_certificate("backup_service", &key, None)),
+            Some("backup_service".to_string())
+        );
+    }
+
+    #[test]
+    fn test_mtls_handshake() {
+        let ca_key = make_key();
+        let ca = make_certificate("Test CA", &ca_key, None);
+        let server_key = make_key();
+        let server_certificate = make_certificate("localhost", &server_key, None);
+        let files = TestFiles::new("mtls");
+        let acceptor = build_mtls_acceptor(
+            &files.write("cert.pem", &server_certificate.to_pem().unwrap()),
+            &files.write("key.pem", &server_key.private_key_to_pem_pkcs8().unwrap()),
+            &files.write("ca.pem", &ca.to_pem().unwrap()),
+        )
+        .unwrap();
+
+        // Signed by the CA.
+        let client_key = make_key();
+        let client_certificate = make_certificate("bob", &client_key, Some((&ca, &ca_key)));
+        assert_eq!(
+            handshake(&acceptor, Some((client_certificate, client_key))),
+            Ok(Some("bob".to_string()))
+        );
+        // No certificate: the client can still use a JWT.
+        assert_eq!(handshake(&acceptor, None), Ok(None));
+        // Signed by another CA.
+        let other_ca_key = make_key();
+        let other_ca = make_certificate("Other CA", &other_ca_key, None);
+        let client_key = make_key();
+        let client_certificate =
+            make_certificate("bob", &client_key, Some((&other_ca, &other_ca_key)));
+        assert_eq!(
+            handshake(&acceptor, Some((client_certificate, client_key))),
+            Err(())
+        );
+    }
+
+    /// Sends a GET request over TLS, with the client certificate if any, and returns the status
+    /// code of the response.
+    fn https_get(port: u16, path: &str, client: Option<&(X509, PKey<Private>)>) -> u16 {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some((certificate, key)) = client {
+            connector.set_certificate(certificate).unwrap();
+            connector.set_private_key(key).unwrap();
+        }
+        let stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut stream = connector.build().connect("localhost", stream).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        // The server may close the connection without a TLS close_notify.
+        let _ = stream.read_to_end(&mut response);
+        let response = String::from_utf8_lossy(&response);
+        response
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .unwrap_or_else(|| panic!("Invalid response: {}", response))
+    }
+
+    #[actix_rt::test]
+    async fn test_certificate_authentication() {
+        use crate::{
+            domain::{
+                handler::{CreateUserRequest, UserId},
+                sql_backend_handler::SqlBackendHandler,
+                sql_tables::{init_table, PoolOptions},
+            },
+            infra::{
+                configuration::{ConfigurationBuilder, LdapsOptionsBuilder},
+                jwt_sql_tables,
+                ldap_connections::LdapConnectionRegistry,
+                mail::Mailer,
+                maintenance::MaintenanceMode,
+                tcp_server::build_tcp_server,
+            },
+        };
+        let ca_key = make_key();
+        let ca = make_certificate("Test CA", &ca_key, None);
+        let server_key = make_key();
+        let server_certificate = make_certificate("localhost", &server_key, None);
+        let files = TestFiles::new("cert_auth");
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = ConfigurationBuilder::default()
+            .http_port(port)
+            .http_mtls_ca_file(Some(files.write("ca.pem", &ca.to_pem().unwrap())))
+            .ldaps_options(
+                LdapsOptionsBuilder::default()
+                    .cert_file(files.write("cert.pem", &server_certificate.to_pem().unwrap()))
+                    .key_file(
+                        files.write("key.pem", &server_key.private_key_to_pem_pkcs8().unwrap()),
+                    )
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        jwt_sql_tables::init_table(&sql_pool).await.unwrap();
+        let handler = SqlBackendHandler::new(config.clone(), sql_pool);
+        // The admin group is recognized by its ID, whatever its name.
+        let admin_group = handler.create_group("administrators").await.unwrap();
+        assert_eq!(admin_group.0, config.admin_group_id);
+        for user in ["bob", "backup_service"] {
+            handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(user),
+                    email: format!("{}@example.com", user),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        handler
+            .add_user_to_group(&UserId::new("backup_service"), admin_group)
+            .await
+            .unwrap();
+        let server = build_tcp_server(
+            &config,
+            handler,
+            MaintenanceMode::default(),
+            Mailer::default(),
+            LdapConnectionRegistry::default(),
+            actix_server::Server::build(),
+        )
+        .await
+        .unwrap()
+        .workers(1)
+        .run();
+
+        let client_certificate = |cn: &str| {
+            let key = make_key();
+            (make_certificate(cn, &key, Some((&ca, &ca_key))), key)
+        };
+        let bob = client_certificate("bob");
+        let backup_service = client_certificate("backup_service");
+        let unknown = client_certificate("mallory");
+        let statuses = tokio::task::spawn_blocking(move || {
+            vec![
+                https_get(port, "/api/v1/users/bob/reports", Some(&bob)),
+                https_get(port, "/api/v1/admin/jobs", Some(&bob)),
+                https_get(port, "/api/v1/admin/jobs", Some(&backup_service)),
+                https_get(port, "/api/v1/admin/jobs", Some(&unknown)),
+                https_get(port, "/api/v1/admin/jobs", None),
+            ]
+        })
+        .await
+        .unwrap();
+        server.stop(true).await;
+        assert_eq!(statuses, vec![200, 403, 200, 401, 401]);
+    }
+
+    #[test]
+    fn test_build_mtls_acceptor_missing_files() {
+        assert!(
+            build_mtls_acceptor("/nonexistent/cert.pem", "/nonexistent/key.pem", "ca.pem").is_err()
+        );
+    }
+}
@@ -3,7 +3,12 @@ use lettre::message::Mailbox;
 
 /// lldap is a lightweight LDAP server
 #[derive(Debug, Parser, Clone)]
-#[clap(version, author)]
+#[clap(
+    version,
+    author,
+    after_help = "The commands exit with 0 on success, 1 on partial failure (e.g. some checks \
+                  passed) and 2 on complete failure."
+)]
 pub struct CLIOpts {
     /// Export
     #[clap(subcommand)]
@@ -19,9 +24,22 @@ pub enum Command {
     /// Run the LDAP and GraphQL server.
     #[clap(name = "run")]
     Run(RunOpts),
-    /// Send a test email.
-    #[clap(name = "send_test_email")]
+    /// Send a test email, to check the SMTP configuration.
+    #[clap(name = "send_test_email", alias = "test-smtp")]
     SendTestEmail(TestEmailOpts),
+    /// Connect to a running server and check that binds and searches work.
+    #[clap(name = "test-ldap")]
+    TestLdap(TestLdapOpts),
+    /// Search the users of a running server through LDAP, as the admin.
+    #[clap(name = "search-users")]
+    SearchUsers(SearchUsersOpts),
+    /// Print the DNS SRV records advertising the LDAP service, to add to a DNS zone.
+    #[clap(name = "generate-dns-records")]
+    GenerateDnsRecords(GenerateDnsRecordsOpts),
+    /// Check the configuration before starting the server: the values, the database connection,
+    /// the LDAPS certificate, the SMTP server and the files to read.
+    #[clap(name = "verify-config")]
+    VerifyConfig(VerifyConfigOpts),
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -38,6 +56,26 @@ pub struct GeneralConfigOpts {
     /// Set verbose logging.
     #[clap(short, long)]
     pub verbose: bool,
+
+    /// Only print the errors, and the results of the command.
+    #[clap(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum OutputFormat {
+    /// Human-readable text, or LDIF for the LDAP entries.
+    #[clap(alias = "ldif")]
+    Text,
+    /// One JSON object per line and per result.
+    Json,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct OutputOpts {
+    /// Format of the results printed to the standard output. The logs go to the standard error.
+    #[clap(long, arg_enum, default_value = "text")]
+    pub output: OutputFormat,
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -78,10 +116,105 @@ pub struct TestEmailOpts {
     #[clap(long, env = "LLDAP_TEST_EMAIL_TO")]
     pub to: String,
 
+    #[clap(flatten)]
+    pub output_opts: OutputOpts,
+
     #[clap(flatten)]
     pub smtp_opts: SmtpOpts,
 }
 
+#[derive(Debug, Parser, Clone)]
+pub struct TestLdapOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// Host of the server to test.
+    #[clap(long, default_value = "localhost")]
+    pub host: String,
+
+    /// DN to bind as. Default: the admin user from the configuration.
+    #[clap(long)]
+    pub bind_dn: Option<String>,
+
+    /// Password to bind with. Default: the admin password from the configuration.
+    #[clap(long, env = "LLDAP_TEST_LDAP_BIND_PW", hide_env_values = true)]
+    pub bind_pw: Option<String>,
+
+    /// Base DN of the user search. Default: "ou=people," followed by the base DN.
+    #[clap(long)]
+    pub search_base: Option<String>,
+
+    /// Connect to the LDAPS port instead of the LDAP port.
+    #[clap(long, conflicts_with = "starttls")]
+    pub ldaps: bool,
+
+    /// Upgrade the LDAP connection to TLS with StartTLS.
+    #[clap(long)]
+    pub starttls: bool,
+
+    /// Don't verify the TLS certificate of the server, e.g. for a self-signed certificate.
+    #[clap(long)]
+    pub no_tls_verify: bool,
+
+    #[clap(flatten)]
+    pub output_opts: OutputOpts,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct SearchUsersOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// Host of the server to search.
+    #[clap(long, default_value = "localhost")]
+    pub host: String,
+
+    /// LDAP filter of the users to return.
+    #[clap(long, default_value = "(objectClass=person)")]
+    pub filter: String,
+
+    /// Comma-separated list of the attributes to return.
+    #[clap(long, default_value = "*", use_value_delimiter = true)]
+    pub attrs: Vec<String>,
+
+    #[clap(flatten)]
+    pub output_opts: OutputOpts,
+
+    /// Connect to the LDAPS port instead of the LDAP port.
+    #[clap(long, conflicts_with = "starttls")]
+    pub ldaps: bool,
+
+    /// Upgrade the LDAP connection to TLS with StartTLS.
+    #[clap(long)]
+    pub starttls: bool,
+
+    /// Don't verify the TLS certificate of the server, e.g. for a self-signed certificate.
+    #[clap(long)]
+    pub no_tls_verify: bool,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct GenerateDnsRecordsOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    /// Syntax of the printed records. Ignored with `--output=json`.
+    #[clap(long, arg_enum, default_value = "bind")]
+    pub format: crate::infra::dns_records::DnsRecordFormat,
+
+    #[clap(flatten)]
+    pub output_opts: OutputOpts,
+}
+
+#[derive(Debug, Parser, Clone)]
+pub struct VerifyConfigOpts {
+    #[clap(flatten)]
+    pub general_config: GeneralConfigOpts,
+
+    #[clap(flatten)]
+    pub output_opts: OutputOpts,
+}
+
 #[derive(Debug, Parser, Clone)]
 #[clap(next_help_heading = Some("LDAPS"), setting = clap::AppSettings::DeriveDisplayOrder)]
 pub struct LdapsOpts {
@@ -113,6 +246,14 @@ pub struct SmtpOpts {
     #[clap(long, env = "LLDAP_SMTP_OPTIONS__TO")]
     pub smtp_reply_to: Option<Mailbox>,
 
+    /// Envelope sender address, if different from the sender.
+    #[clap(long, env = "LLDAP_SMTP_OPTIONS__SENDER")]
+    pub smtp_sender: Option<Mailbox>,
+
+    /// Display name used for the sender, overriding the one from the sender address.
+    #[clap(long, env = "LLDAP_SMTP_OPTIONS__DISPLAY_NAME")]
+    pub smtp_display_name: Option<String>,
+
     /// SMTP server.
     #[clap(long, env = "LLDAP_SMTP_OPTIONS__SERVER")]
     pub smtp_server: Option<String>,
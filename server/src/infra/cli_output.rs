@@ -0,0 +1,177 @@
+use crate::infra::cli::{GeneralConfigOpts, OutputFormat, OutputOpts};
+use serde::Serialize;
+use std::{fmt::Display, io::Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only the errors and the results.
+    Quiet,
+    Normal,
+    /// Debug logs.
+    Verbose,
+}
+
+/// Exit code of the CLI commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = 0,
+    /// Some of the work succeeded, e.g. the connection but not the search.
+    PartialFailure = 1,
+    Failure = 2,
+}
+
+/// How a CLI command reports its progress and results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CliOutputConfig {
+    pub verbosity: Verbosity,
+    pub format: OutputFormat,
+}
+
+impl CliOutputConfig {
+    pub fn new(general_config: &GeneralConfigOpts, output_opts: &OutputOpts) -> Self {
+        Self {
+            verbosity: if general_config.quiet {
+                Verbosity::Quiet
+            } else if general_config.verbose {
+                Verbosity::Verbose
+            } else {
+                Verbosity::Normal
+            },
+            format: output_opts.output,
+        }
+    }
+}
+
+/// Writes the results of a CLI command to the standard output, and everything else to the
+/// standard error, so that the output can be piped to other tools.
+pub struct CliLogger<W: Write = std::io::Stdout> {
+    config: CliOutputConfig,
+    output: W,
+}
+
+impl CliLogger {
+    pub fn new(config: CliOutputConfig) -> Self {
+        Self::with_output(config, std::io::stdout())
+    }
+}
+
+impl<W: Write> CliLogger<W> {
+    pub fn with_output(config: CliOutputConfig, output: W) -> Self {
+        Self { config, output }
+    }
+
+    /// Progress messages, hidden with `--quiet`.
+    pub fn info(&self, message: impl Display) {
+        if self.config.verbosity != Verbosity::Quiet {
+            eprintln!("{}", message);
+        }
+    }
+
+    pub fn error(&self, message: impl Display) {
+        eprintln!("{}", message);
+    }
+
+    /// Prints a result: the text, or the value as a line of JSON.
+    pub fn result<T: Serialize>(&mut self, text: impl Display, value: &T) {
+        let line = match self.config.format {
+            OutputFormat::Text => text.to_string(),
+            OutputFormat::Json => {
+                serde_json::to_string(value).expect("Results are always serializable")
+            }
+        };
+        // A closed pipe is not worth a panic.
+        let _ = writeln!(self.output, "{}", line);
+    }
+
+    /// Prints the value as a line of JSON with `--output=json`, and nothing otherwise: for the
+    /// failures, already reported on the standard error.
+    pub fn json_result<T: Serialize>(&mut self, value: &T) {
+        if self.config.format == OutputFormat::Json {
+            self.result("", value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct TestResult {
+        name: &'static str,
+        passed: bool,
+    }
+
+    fn get_output(format: OutputFormat) -> String {
+        let mut logger = CliLogger::with_output(
+            CliOutputConfig {
+                verbosity: Verbosity::Quiet,
+                format,
+            },
+            Vec::new(),
+        );
+        logger.info("Not in the output");
+        logger.result(
+            "[PASS] bind",
+            &TestResult {
+                name: "bind",
+                passed: true,
+            },
+        );
+        logger.result(
+            "[FAIL] search",
+            &TestResult {
+                name: "search",
+                passed: false,
+            },
+        );
+        String::from_utf8(logger.output).unwrap()
+    }
+
+    #[test]
+    fn test_text_output() {
+        assert_eq!(
+            get_output(OutputFormat::Text),
+            "[PASS] bind\n[FAIL] search\n"
+        );
+    }
+
+    #[test]
+    fn test_json_output() {
+        assert_eq!(
+            get_output(OutputFormat::Json),
+            "{\"name\":\"bind\",\"passed\":true}\n{\"name\":\"search\",\"passed\":false}\n"
+        );
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let general_config = GeneralConfigOpts {
+            config_file: String::new(),
+            verbose: false,
+            quiet: true,
+        };
+        let output_opts = OutputOpts {
+            output: OutputFormat::Json,
+        };
+        assert_eq!(
+            CliOutputConfig::new(&general_config, &output_opts),
+            CliOutputConfig {
+                verbosity: Verbosity::Quiet,
+                format: OutputFormat::Json
+            }
+        );
+        assert_eq!(
+            CliOutputConfig::new(
+                &GeneralConfigOpts {
+                    verbose: true,
+                    quiet: false,
+                    ..general_config
+                },
+                &output_opts
+            )
+            .verbosity,
+            Verbosity::Verbose
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    domain::sql_tables::PoolOptions,
+    infra::{configuration::Configuration, ldap_check::CheckStep},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::{asn1::Asn1Time, pkey::PKey, x509::X509};
+use std::{cmp::Ordering, path::Path, time::Duration};
+
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks what the parsing can't: the values that must have a given format.
+fn validate(config: &Configuration) -> Result<String> {
+    super::ldap_handler::parse_distinguished_name(&config.ldap_base_dn)
+        .with_context(|| format!("invalid ldap_base_dn \"{}\"", config.ldap_base_dn))?;
+    if config.ldap_user_pass.unsecure().len() < 8 {
+        bail!("ldap_user_pass must be at least 8 characters long");
+    }
+    if !config.http_url.starts_with("http://") && !config.http_url.starts_with("https://") {
+        bail!(
+            "http_url \"{}\" must start with http:// or https://",
+            config.http_url
+        );
+    }
+    if config.ldaps_options.enabled && config.ldaps_options.port == config.ldap_port {
+        bail!("the LDAP and LDAPS ports are both {}", config.ldap_port);
+    }
+    Ok("valid".to_string())
+}
+
+async fn check_database(config: &Configuration) -> Result<String> {
+    let sql_pool = PoolOptions::new()
+        .max_connections(1)
+        .connect_timeout(CONNECTION_TIMEOUT)
+        .connect(&config.database_url)
+        .await
+        .context("while connecting to the DB")?;
+    let value: i32 = sqlx::query_scalar("SELECT 1")
+        .fetch_one(&sql_pool)
+        .await
+        .context("while querying the DB")?;
+    sql_pool.close().await;
+    if value != 1 {
+        bail!("SELECT 1 returned {}", value);
+    }
+    Ok("connected".to_string())
+}
+
+fn check_certificate(cert_file: &str, key_file: &str) -> Result<String> {
+    let cert = X509::from_pem(&std::fs::read(cert_file)?)
+        .with_context(|| format!("while parsing the certificate {}", cert_file))?;
+    let key = PKey::private_key_from_pem(&std::fs::read(key_file)?)
+        .with_context(|| format!("while parsing the key {}", key_file))?;
+    if !cert.public_key()?.public_eq(&key) {
+        bail!("the key {} doesn't match the certificate", key_file);
+    }
+    let now = Asn1Time::days_from_now(0)?;
+    if cert.not_after().compare(&now)? != Ordering::Greater {
+        bail!("the certificate expired on {}", cert.not_after());
+    }
+    if cert.not_before().compare(&now)? == Ordering::Greater {
+        bail!("the certificate is not valid before {}", cert.not_before());
+    }
+    Ok(format!("valid until {}", cert.not_after()))
+}
+
+async fn check_smtp_connection(server: &str, port: u16) -> Result<String> {
+    tokio::time::timeout(
+        CONNECTION_TIMEOUT,
+        tokio::net::TcpStream::connect((server, port)),
+    )
+    .await
+    .map_err(|_| anyhow!("timed out after {:?}", CONNECTION_TIMEOUT))??;
+    Ok("connected".to_string())
+}
+
+/// Checks that the file, or the files of the directory, can be read by the current user.
+fn check_readable(path: &str) -> Result<String> {
+    if Path::new(path).is_dir() {
+        std::fs::read_dir(path)?;
+        Ok("readable directory".to_string())
+    } else {
+        std::fs::File::open(path)?;
+        Ok("readable".to_string())
+    }
+}
+
+/// The files and directories the server reads, depending on the configuration.
+fn get_paths(config: &Configuration) -> Vec<&str> {
+    let mut paths = vec!["app", config.key_file.as_str()];
+    if config.ldaps_options.enabled || config.http_mtls_ca_file.is_some() {
+        paths.push(&config.ldaps_options.cert_file);
+        paths.push(&config.ldaps_options.key_file);
+    }
+    paths.extend(config.http_mtls_ca_file.as_deref());
+    paths.extend(config.branding.favicon_file.as_deref());
+    paths.extend(config.branding.assets_dir.as_deref());
+    paths
+}
+
+/// Runs all the checks, even after a failure since they are independent.
+pub async fn run_checks(config: &Configuration) -> Vec<CheckStep> {
+    let mut steps = vec![
+        CheckStep {
+            name: "Validate the configuration".to_string(),
+            outcome: validate(config),
+        },
+        CheckStep {
+            name: "Connect to the database".to_string(),
+            outcome: check_database(config).await,
+        },
+    ];
+    if config.ldaps_options.enabled {
+        steps.push(CheckStep {
+            name: "Load the LDAPS certificate".to_string(),
+            outcome: check_certificate(
+                &config.ldaps_options.cert_file,
+                &config.ldaps_options.key_file,
+            ),
+        });
+    }
+    let smtp = &config.smtp_options;
+    if smtp.enable_password_reset {
+        steps.push(CheckStep {
+            name: format!("Connect to the SMTP server {}:{}", smtp.server, smtp.port),
+            outcome: check_smtp_connection(&smtp.server, smtp.port).await,
+        });
+    }
+    for path in get_paths(config) {
+        steps.push(CheckStep {
+            name: format!("Read {}", path),
+            outcome: check_readable(path),
+        });
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::{ConfigurationBuilder, LdapsOptions};
+    use secstr::SecUtf8;
+
+    #[test]
+    fn test_validate() {
+        let config = ConfigurationBuilder::default()
+            .ldap_user_pass(SecUtf8::from("long_password"))
+            .build()
+            .unwrap();
+        validate(&config).unwrap();
+        let invalid = [
+            ConfigurationBuilder::default().ldap_user_pass(SecUtf8::from("short")),
+            ConfigurationBuilder::default()
+                .ldap_user_pass(SecUtf8::from("long_password"))
+                .ldap_base_dn("example.com".to_string()),
+            ConfigurationBuilder::default()
+                .ldap_user_pass(SecUtf8::from("long_password"))
+                .http_url("ldap.example.com".to_string()),
+            ConfigurationBuilder::default()
+                .ldap_user_pass(SecUtf8::from("long_password"))
+                .ldap_port(3890)
+                .ldaps_options(LdapsOptions {
+                    enabled: true,
+                    port: 3890,
+                    ..Default::default()
+                }),
+        ];
+        for builder in invalid {
+            assert!(validate(&builder.build().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_get_paths() {
+        let config = ConfigurationBuilder::default().build().unwrap();
+        assert_eq!(get_paths(&config), vec!["app", "server_key"]);
+        let config = ConfigurationBuilder::default()
+            .ldaps_options(LdapsOptions {
+                enabled: true,
+                cert_file: "/data/cert.pem".to_string(),
+                key_file: "/data/key.pem".to_string(),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            get_paths(&config),
+            vec!["app", "server_key", "/data/cert.pem", "/data/key.pem"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_check_database() {
+        let config = ConfigurationBuilder::default()
+            .database_url("sqlite::memory:".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(check_database(&config).await.unwrap(), "connected");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::infra::configuration::Configuration;
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A network in CIDR notation, e.g. "10.0.0.0/8" or "fd00::/8". A single address stands for the
+/// network with only that address.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let to_bits = |bytes: &[u8]| bytes.iter().fold(0u128, |acc, b| acc << 8 | u128::from(*b));
+    let shift = network.len() as u32 * 8 - u32::from(prefix_len);
+    shift == 128 || to_bits(network) >> shift == to_bits(ip) >> shift
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address = IpAddr::from_str(address.trim())
+            .with_context(|| format!("Invalid IP address in \"{}\"", s))?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse::<u8>()
+                .with_context(|| format!("Invalid prefix length in \"{}\"", s))?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            bail!("Prefix length too long in \"{}\"", s);
+        }
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> Self {
+        format!("{}/{}", network.address, network.prefix_len)
+    }
+}
+
+/// Only log the rejected connections once in a while, to avoid flooding the logs.
+const REJECTED_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct RejectedConnections {
+    last_log: Option<Instant>,
+    /// Connections rejected since the last log.
+    count: u64,
+}
+
+/// Rejects the connections from the networks in `denied_networks`, or outside of
+/// `allowed_networks` when it's not empty. Clones share the same logging state.
+#[derive(Clone, Default)]
+pub struct ConnectionFilter {
+    allowed: Vec<IpNetwork>,
+    denied: Vec<IpNetwork>,
+    rejected: Arc<Mutex<RejectedConnections>>,
+}
+
+impl ConnectionFilter {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            allowed: config.allowed_networks.clone(),
+            denied: config.denied_networks.clone(),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        (self.allowed.is_empty() || self.allowed.iter().any(|n| n.contains(ip)))
+            && !self.denied.iter().any(|n| n.contains(ip))
+    }
+
+    /// Whether to accept a connection from the peer on the listener ("LDAP", "HTTP"...).
+    pub fn accept(&self, peer: std::io::Result<SocketAddr>, listener: &str) -> bool {
+        if self.allowed.is_empty() && self.denied.is_empty() {
+            return true;
+        }
+        let peer = peer.ok();
+        if peer.map_or(false, |peer| self.is_allowed(peer.ip())) {
+            return true;
+        }
+        let mut rejected = self.rejected.lock().unwrap();
+        rejected.count += 1;
+        if rejected
+            .last_log
+            .map_or(true, |last_log| last_log.elapsed() >= REJECTED_LOG_INTERVAL)
+        {
+            warn!(
+                "[{}] Rejected {} connection(s) from disallowed addresses, the last one from {}",
+                listener,
+                rejected.count,
+                peer.map_or_else(|| "an unknown address".to_string(), |p| p.ip().to_string())
+            );
+            rejected.last_log = Some(Instant::now());
+            rejected.count = 0;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_network() {
+        let network: IpNetwork = "192.168.1.0/24".parse().unwrap();
+        assert!(network.contains(ip("192.168.1.42")));
+        assert!(network.contains(ip("::ffff:192.168.1.42")));
+        assert!(!network.contains(ip("192.168.2.1")));
+        assert!(!network.contains(ip("fd00::1")));
+        let network: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(network.contains(ip("fd12:3456::1")));
+        assert!(!network.contains(ip("fe80::1")));
+        let network: IpNetwork = "10.0.0.1".parse().unwrap();
+        assert_eq!(String::from(network.clone()), "10.0.0.1/32");
+        assert!(network.contains(ip("10.0.0.1")));
+        assert!(!network.contains(ip("10.0.0.2")));
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_connection_filter() {
+        let filter = ConnectionFilter {
+            allowed: vec!["10.0.0.0/8".parse().unwrap()],
+            denied: vec!["10.0.66.0/24".parse().unwrap()],
+            ..ConnectionFilter::default()
+        };
+        assert!(filter.is_allowed(ip("10.1.2.3")));
+        assert!(!filter.is_allowed(ip("10.0.66.1")));
+        assert!(!filter.is_allowed(ip("192.168.1.1")));
+        assert!(filter.accept(Ok(SocketAddr::new(ip("10.1.2.3"), 1234)), "LDAP"));
+        assert!(!filter.accept(
+            Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+            "LDAP"
+        ));
+        assert!(ConnectionFilter::default().accept(
+            Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+            "LDAP"
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::infra::configuration::Configuration;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Explains how to publish the records, printed along with them.
+pub const DNS_RECORDS_DOC_URL: &str =
+    "https://github.com/nitnelave/lldap/blob/main/docs/dns_srv_records.md";
+
+const DEFAULT_TTL: u32 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ArgEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsRecordFormat {
+    /// One BIND-style resource record per line, with fully-qualified names.
+    Bind,
+    /// A CoreDNS server block answering the SRV queries with the template plugin.
+    Coredns,
+    /// A zone file snippet, with the names relative to the domain.
+    Zone,
+}
+
+/// An SRV record, as described in RFC 2782.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SrvRecord {
+    /// Fully-qualified name of the record, e.g. "_ldap._tcp.example.com.".
+    pub name: String,
+    pub ttl: u32,
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Fully-qualified name of the host running lldap.
+    pub target: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DnsSrvRecords {
+    /// The domain of the records, derived from the base DN.
+    pub domain: String,
+    pub records: Vec<SrvRecord>,
+    pub documentation: &'static str,
+}
+
+/// "dc=example,dc=com" -> "example.com".
+fn domain_from_base_dn(base_dn: &str) -> Result<String> {
+    let components = base_dn
+        .split(',')
+        .filter_map(|part| {
+            let (name, value) = part.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case("dc") {
+                Some(value.trim().to_ascii_lowercase())
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    if components.is_empty() {
+        bail!("The base DN \"{}\" has no dc= components", base_dn);
+    }
+    Ok(components.join("."))
+}
+
+/// "https://ldap.example.com:17170/" -> "ldap.example.com".
+fn host_from_url(url: &str) -> Result<String> {
+    let without_scheme = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let authority = without_scheme.split('/').next().unwrap_or_default();
+    let authority = authority.rsplit('@').next().unwrap_or_default();
+    let host = match authority.strip_prefix('[') {
+        // IPv6 literal.
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    if host.is_empty() {
+        bail!("Could not find the host name in the HTTP URL \"{}\"", url);
+    }
+    Ok(host.to_ascii_lowercase())
+}
+
+fn fully_qualified(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// Builds the records advertising the LDAP (and LDAPS, if enabled) ports of the server, on the
+/// host of `http_url`.
+pub fn make_srv_records(config: &Configuration) -> Result<DnsSrvRecords> {
+    let domain = domain_from_base_dn(&config.ldap_base_dn)?;
+    let target = fully_qualified(&host_from_url(&config.http_url)?);
+    let make_record = |service: &str, port: u16| SrvRecord {
+        name: format!("_{}._tcp.{}.", service, domain),
+        ttl: DEFAULT_TTL,
+        priority: 0,
+        weight: 0,
+        port,
+        target: target.clone(),
+    };
+    let mut records = vec![make_record("ldap", config.ldap_port)];
+    if config.ldaps_options.enabled {
+        records.push(make_record("ldaps", config.ldaps_options.port));
+    }
+    Ok(DnsSrvRecords {
+        domain,
+        records,
+        documentation: DNS_RECORDS_DOC_URL,
+    })
+}
+
+impl SrvRecord {
+    fn rdata(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.priority, self.weight, self.port, self.target
+        )
+    }
+}
+
+impl DnsSrvRecords {
+    pub fn format(&self, format: DnsRecordFormat) -> String {
+        let mut lines = vec![format!(
+            "{} See {} to publish these records.",
+            match format {
+                DnsRecordFormat::Bind | DnsRecordFormat::Zone => ";",
+                DnsRecordFormat::Coredns => "#",
+            },
+            self.documentation
+        )];
+        match format {
+            DnsRecordFormat::Bind => {
+                lines.extend(self.records.iter().map(|record| {
+                    format!("{} {} IN SRV {}", record.name, record.ttl, record.rdata())
+                }));
+            }
+            DnsRecordFormat::Zone => {
+                let suffix = format!(".{}.", self.domain);
+                lines.push(format!("$ORIGIN {}.", self.domain));
+                lines.extend(self.records.iter().map(|record| {
+                    let name = record.name.strip_suffix(&suffix).unwrap_or(&record.name);
+                    format!("{}\t{}\tIN\tSRV\t{}", name, record.ttl, record.rdata())
+                }));
+            }
+            DnsRecordFormat::Coredns => {
+                lines.push(format!("{} {{", self.domain));
+                for record in &self.records {
+                    lines.push(format!("    template IN SRV {} {{", self.domain));
+                    lines.push(format!(
+                        "        match \"^{}$\"",
+                        record.name.replace('.', "\\.")
+                    ));
+                    lines.push(format!(
+                        "        answer \"{{{{ .Name }}}} {} IN SRV {}\"",
+                        record.ttl,
+                        record.rdata()
+                    ));
+                    lines.push("        fallthrough".to_string());
+                    lines.push("    }".to_string());
+                }
+                lines.push("}".to_string());
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::{ConfigurationBuilder, LdapsOptions};
+
+    fn make_config(ldaps_enabled: bool) -> Configuration {
+        ConfigurationBuilder::default()
+            .ldap_base_dn("dc=Example,dc=com".to_string())
+            .http_url("https://ldap.example.com:17170/".to_string())
+            .ldap_port(3890)
+            .ldaps_options(LdapsOptions {
+                enabled: ldaps_enabled,
+                port: 6360,
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_domain_from_base_dn() {
+        assert_eq!(
+            domain_from_base_dn("dc=example,dc=com").unwrap(),
+            "example.com"
+        );
+        assert_eq!(
+            domain_from_base_dn("ou=corp, DC=Example, DC=org").unwrap(),
+            "example.org"
+        );
+        assert!(domain_from_base_dn("o=example").is_err());
+    }
+
+    #[test]
+    fn test_host_from_url() {
+        assert_eq!(
+            host_from_url("https://ldap.example.com:17170/").unwrap(),
+            "ldap.example.com"
+        );
+        assert_eq!(host_from_url("http://localhost").unwrap(), "localhost");
+        assert_eq!(host_from_url("http://[::1]:17170").unwrap(), "::1");
+        assert!(host_from_url("http://").is_err());
+    }
+
+    #[test]
+    fn test_make_srv_records() {
+        let records = make_srv_records(&make_config(false)).unwrap();
+        assert_eq!(records.domain, "example.com");
+        assert_eq!(
+            records.records,
+            vec![SrvRecord {
+                name: "_ldap._tcp.example.com.".to_string(),
+                ttl: 3600,
+                priority: 0,
+                weight: 0,
+                port: 3890,
+                target: "ldap.example.com.".to_string(),
+            }]
+        );
+        let records = make_srv_records(&make_config(true)).unwrap();
+        assert_eq!(records.records.len(), 2);
+        assert_eq!(records.records[1].name, "_ldaps._tcp.example.com.");
+        assert_eq!(records.records[1].port, 6360);
+    }
+
+    #[test]
+    fn test_format() {
+        let records = make_srv_records(&make_config(true)).unwrap();
+        assert_eq!(
+            records.format(DnsRecordFormat::Bind),
+            format!(
+                "; See {} to publish these records.\n\
+                 _ldap._tcp.example.com. 3600 IN SRV 0 0 3890 ldap.example.com.\n\
+                 _ldaps._tcp.example.com. 3600 IN SRV 0 0 6360 ldap.example.com.",
+                DNS_RECORDS_DOC_URL
+            )
+        );
+        assert_eq!(
+            records.format(DnsRecordFormat::Zone),
+            format!(
+                "; See {} to publish these records.\n\
+                 $ORIGIN example.com.\n\
+                 _ldap._tcp\t3600\tIN\tSRV\t0 0 3890 ldap.example.com.\n\
+                 _ldaps._tcp\t3600\tIN\tSRV\t0 0 6360 ldap.example.com.",
+                DNS_RECORDS_DOC_URL
+            )
+        );
+        let coredns = records.format(DnsRecordFormat::Coredns);
+        assert!(coredns.starts_with("# See "));
+        assert!(coredns.contains("example.com {\n    template IN SRV example.com {\n"));
+        assert!(coredns.contains("        match \"^_ldap\\._tcp\\.example\\.com\\.$\"\n"));
+        assert!(coredns
+            .contains("        answer \"{{ .Name }} 3600 IN SRV 0 0 6360 ldap.example.com.\"\n"));
+    }
+}
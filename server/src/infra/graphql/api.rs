@@ -1,29 +1,56 @@
 use crate::{
-    domain::handler::BackendHandler,
+    domain::handler::{BackendHandler, GroupId},
     infra::{
-        auth_service::{check_if_token_is_valid, ValidationResults},
+        attribute_visibility::AttributeVisibilityPolicy,
+        auth_service::{check_request_identity, ValidationResults},
         cli::ExportGraphQLSchemaOpts,
+        maintenance::MaintenanceMode,
+        tcp_backend_handler::TcpBackendHandler,
         tcp_server::AppState,
     },
 };
 use actix_web::{web, Error, HttpResponse};
-use actix_web_httpauth::extractors::bearer::BearerAuth;
-use juniper::{EmptySubscription, RootNode};
+use bytes::BytesMut;
+use futures_util::StreamExt;
+use juniper::{
+    http::{GraphQLBatchRequest, GraphQLRequest},
+    EmptySubscription, InputValue, RootNode,
+};
 use juniper_actix::{graphiql_handler, graphql_handler, playground_handler};
+use serde::Deserialize;
 
-use super::{mutation::Mutation, query::Query};
+use super::{
+    mutation::Mutation,
+    query::Query,
+    query_analysis::{get_query_cost, uses_introspection},
+};
 
 pub struct Context<Handler: BackendHandler> {
     pub handler: Box<Handler>,
     pub validation_result: ValidationResults,
+    pub maintenance_mode: MaintenanceMode,
+    /// User attributes hidden from some viewers.
+    pub attribute_visibility: AttributeVisibilityPolicy,
+    /// The admin group can't be deleted, nor can the admins remove themselves from it.
+    pub admin_group_id: GroupId,
 }
 
 impl<Handler: BackendHandler> juniper::Context for Context<Handler> {}
 
+impl<Handler: BackendHandler> Context<Handler> {
+    /// Fails if the server doesn't accept writes at the moment.
+    pub fn check_writable(&self) -> juniper::FieldResult<()> {
+        if self.maintenance_mode.is_enabled() {
+            return Err("The server is in read-only maintenance mode".into());
+        }
+        Ok(())
+    }
+}
+
 type Schema<Handler> =
     RootNode<'static, Query<Handler>, Mutation<Handler>, EmptySubscription<Context<Handler>>>;
 
-fn schema<Handler: BackendHandler + Sync>() -> Schema<Handler> {
+fn schema<Handler: BackendHandler + TcpBackendHandler + Sync>() -> Schema<Handler> {
     Schema::new(
         Query::<Handler>::new(),
         Mutation::<Handler>::new(),
@@ -58,24 +85,195 @@ async fn playground_route() -> Result<HttpResponse, Error> {
     playground_handler("/api/graphql", None).await
 }
 
-async fn graphql_route<Handler: BackendHandler + Sync>(
+#[derive(Deserialize)]
+struct GetGraphQLRequest {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<String>,
+}
+
+/// A GraphQL request, along with the text of its queries so that they can be checked before
+/// execution.
+struct ParsedGraphQLRequest {
+    queries: Vec<String>,
+    request: GraphQLBatchRequest,
+}
+
+fn bad_request(message: impl std::fmt::Display) -> Error {
+    actix_web::error::ErrorBadRequest(message.to_string())
+}
+
+/// Reads the whole body, failing with a 413 Payload Too Large past `max_size` bytes. 0 disables
+/// the limit.
+async fn read_body(
+    req: &actix_web::HttpRequest,
+    mut payload: web::Payload,
+    max_size: usize,
+) -> Result<web::Bytes, Error> {
+    let too_large = || {
+        actix_web::error::ErrorPayloadTooLarge(format!(
+            "The request body exceeds the maximum of {} bytes",
+            max_size
+        ))
+    };
+    if max_size != 0 {
+        let content_length = req
+            .headers()
+            .get(actix_web::http::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+        if content_length.map_or(false, |length| length > max_size) {
+            return Err(too_large());
+        }
+    }
+    // The Content-Length is missing for chunked bodies, and not to be trusted otherwise.
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if max_size != 0 && body.len() + chunk.len() > max_size {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+/// Parses the request the same way `juniper_actix::graphql_handler` does: from the URL
+/// parameters for a GET, from a JSON or `application/graphql` body for a POST.
+async fn parse_graphql_request(
+    req: &actix_web::HttpRequest,
+    payload: actix_web::web::Payload,
+    max_body_size: usize,
+) -> Result<ParsedGraphQLRequest, Error> {
+    if req.method() == actix_web::http::Method::GET {
+        let get_request = web::Query::<GetGraphQLRequest>::from_query(req.query_string())?;
+        let variables = get_request
+            .variables
+            .as_deref()
+            .map(serde_json::from_str::<InputValue>)
+            .transpose()
+            .map_err(bad_request)?;
+        return Ok(ParsedGraphQLRequest {
+            queries: vec![get_request.query.clone()],
+            request: GraphQLBatchRequest::Single(GraphQLRequest::new(
+                get_request.query.clone(),
+                get_request.operation_name.clone(),
+                variables,
+            )),
+        });
+    }
+    let body = read_body(req, payload, max_body_size).await?;
+    let content_type = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("application/graphql") {
+        let query = String::from_utf8(body.to_vec()).map_err(bad_request)?;
+        return Ok(ParsedGraphQLRequest {
+            queries: vec![query.clone()],
+            request: GraphQLBatchRequest::Single(GraphQLRequest::new(query, None, None)),
+        });
+    }
+    let json: serde_json::Value = serde_json::from_slice(&body).map_err(bad_request)?;
+    let queries = match &json {
+        serde_json::Value::Array(requests) => requests.iter().collect(),
+        request => vec![request],
+    }
+    .into_iter()
+    .filter_map(|request| request.get("query")?.as_str().map(str::to_owned))
+    .collect();
+    Ok(ParsedGraphQLRequest {
+        queries,
+        request: serde_json::from_value(json).map_err(bad_request)?,
+    })
+}
+
+/// Returns the reason why the request is not allowed to run, if any.
+fn check_graphql_request<Handler: BackendHandler>(
+    data: &AppState<Handler>,
+    request: &ParsedGraphQLRequest,
+) -> Option<String> {
+    if let GraphQLBatchRequest::Batch(requests) = &request.request {
+        if data.graphql_max_batch_size != 0 && requests.len() > data.graphql_max_batch_size {
+            return Some(format!(
+                "Batch of {} operations exceeds the maximum of {}",
+                requests.len(),
+                data.graphql_max_batch_size
+            ));
+        }
+    }
+    if !data.graphql_introspection && request.queries.iter().any(|q| uses_introspection(q)) {
+        return Some("GraphQL introspection is disabled on this server".to_string());
+    }
+    if data.graphql_max_query_depth == 0 && data.graphql_max_query_complexity == 0 {
+        return None;
+    }
+    let mut complexity = 0usize;
+    for query in &request.queries {
+        let cost = match get_query_cost(query) {
+            Ok(cost) => cost,
+            Err(e) => return Some(format!("Could not analyze the query: {}", e)),
+        };
+        if data.graphql_max_query_depth != 0 && cost.depth > data.graphql_max_query_depth {
+            return Some(format!(
+                "Query depth of {} exceeds the maximum of {}",
+                cost.depth, data.graphql_max_query_depth
+            ));
+        }
+        // The queries of a batch share the budget.
+        complexity = complexity.saturating_add(cost.complexity);
+    }
+    if data.graphql_max_query_complexity != 0 && complexity > data.graphql_max_query_complexity {
+        return Some(format!(
+            "Query complexity of {} exceeds the maximum of {}",
+            complexity, data.graphql_max_query_complexity
+        ));
+    }
+    None
+}
+
+async fn graphql_route<Handler: BackendHandler + TcpBackendHandler + Sync>(
     req: actix_web::HttpRequest,
-    mut payload: actix_web::web::Payload,
+    payload: actix_web::web::Payload,
     data: web::Data<AppState<Handler>>,
 ) -> Result<HttpResponse, Error> {
-    use actix_web::FromRequest;
-    let bearer = BearerAuth::from_request(&req, &mut payload.0).await?;
-    let validation_result = check_if_token_is_valid(&data, bearer.token())?;
+    let validation_result = check_request_identity(&data, &req).await?;
     let context = Context::<Handler> {
         handler: Box::new(data.backend_handler.clone()),
         validation_result,
+        maintenance_mode: data.maintenance_mode.clone(),
+        attribute_visibility: data.attribute_visibility.clone(),
+        admin_group_id: data.admin_group_id,
     };
-    graphql_handler(&schema(), &context, req, payload).await
+    if data.graphql_introspection
+        && data.graphql_max_query_depth == 0
+        && data.graphql_max_query_complexity == 0
+        && data.graphql_max_body_size == 0
+        && data.graphql_max_batch_size == 0
+    {
+        return graphql_handler(&schema(), &context, req, payload).await;
+    }
+    let request = parse_graphql_request(&req, payload, data.graphql_max_body_size).await?;
+    if let Some(error) = check_graphql_request(&data, &request) {
+        log::warn!("Rejected GraphQL request: {}", error);
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "errors": [{ "message": error }]
+        })));
+    }
+    let schema = schema();
+    let response = request.request.execute(&schema, &context).await;
+    Ok(if response.is_ok() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::BadRequest()
+    }
+    .json(&response))
 }
 
 pub fn configure_endpoint<Backend>(cfg: &mut web::ServiceConfig)
 where
-    Backend: BackendHandler + Sync + 'static,
+    Backend: BackendHandler + TcpBackendHandler + Sync + 'static,
 {
     let json_config = web::JsonConfig::default()
         .limit(4096)
@@ -98,3 +296,51 @@ where
     cfg.service(web::resource("/graphql/playground").route(web::get().to(playground_route)));
     cfg.service(web::resource("/graphql/graphiql").route(web::get().to(graphiql_route)));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    async fn read_test_body(request: TestRequest, max_size: usize) -> Result<web::Bytes, Error> {
+        let (req, payload) = request.to_http_parts();
+        read_body(&req, web::Payload(payload), max_size).await
+    }
+
+    #[actix_rt::test]
+    async fn test_read_body() {
+        let body = read_test_body(TestRequest::post().set_payload("{}"), 2)
+            .await
+            .unwrap();
+        assert_eq!(body, "{}");
+        let body = read_test_body(TestRequest::post().set_payload("{}"), 0)
+            .await
+            .unwrap();
+        assert_eq!(body, "{}");
+    }
+
+    #[actix_rt::test]
+    async fn test_read_body_too_large() {
+        let error = read_test_body(TestRequest::post().set_payload("{\"query\": \"\"}"), 8)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_read_body_without_content_length() {
+        let (req, _) = TestRequest::post().to_http_parts();
+        let (_, mut payload) = actix_http::h1::Payload::create(true);
+        payload.unread_data(web::Bytes::from_static(b"0123456789"));
+        let error = read_body(&req, web::Payload(payload.into()), 8)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+}
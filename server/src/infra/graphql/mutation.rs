@@ -1,7 +1,16 @@
-use crate::domain::handler::{
-    BackendHandler, CreateUserRequest, GroupId, UpdateGroupRequest, UpdateUserRequest, UserId,
+use crate::{
+    domain::{
+        error::DomainError,
+        handler::{
+            BackendHandler, CreateUserRequest, GroupId, UpdateGroupRequest, UpdateUserRequest,
+            UserId,
+        },
+    },
+    infra::tcp_backend_handler::TcpBackendHandler,
+};
+use juniper::{
+    graphql_object, graphql_value, FieldError, FieldResult, GraphQLInputObject, GraphQLObject,
 };
-use juniper::{graphql_object, FieldResult, GraphQLInputObject, GraphQLObject};
 
 use super::api::Context;
 
@@ -27,6 +36,9 @@ pub struct CreateUserInput {
     display_name: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
+    mail_aliases: Option<Vec<String>>,
+    mail_forwarding: Option<Vec<String>>,
+    phone_numbers: Option<Vec<String>>,
 }
 
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
@@ -37,6 +49,15 @@ pub struct UpdateUserInput {
     display_name: Option<String>,
     first_name: Option<String>,
     last_name: Option<String>,
+    mail_aliases: Option<Vec<String>>,
+    mail_forwarding: Option<Vec<String>>,
+    /// Replaces all the phone numbers of the user.
+    phone_numbers: Option<Vec<String>>,
+    /// The maximum number of entries returned by the user's LDAP searches, overriding the
+    /// configured limit. A negative value removes the override. Only for admins.
+    max_search_results: Option<i32>,
+    /// The user this user reports to. An empty value removes the manager. Only for admins.
+    manager_id: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
@@ -44,6 +65,13 @@ pub struct UpdateUserInput {
 pub struct UpdateGroupInput {
     id: i32,
     display_name: Option<String>,
+    /// The name shown to the users instead of the display name. An empty value removes it.
+    description: Option<String>,
+    /// Makes the group dynamic: its members are the users matching the LDAP filter, e.g.
+    /// `(sn=Smith)`, and can't be added or removed.
+    dynamic_filter: Option<String>,
+    /// Makes a dynamic group a regular group again, without members.
+    remove_dynamic_filter: Option<bool>,
 }
 
 #[derive(PartialEq, Eq, Debug, GraphQLObject)]
@@ -57,8 +85,39 @@ impl Success {
     }
 }
 
+/// A new API token: the token itself can't be retrieved later.
+pub struct CreatedUserToken<Handler: BackendHandler> {
+    token: String,
+    details: super::query::UserToken<Handler>,
+}
+
 #[graphql_object(context = Context<Handler>)]
-impl<Handler: BackendHandler + Sync> Mutation<Handler> {
+impl<Handler: BackendHandler + Sync> CreatedUserToken<Handler> {
+    /// To send in the Authorization header, as a bearer token.
+    fn token(&self) -> &str {
+        &self.token
+    }
+
+    fn details(&self) -> &super::query::UserToken<Handler> {
+        &self.details
+    }
+}
+
+/// Reports unique attribute violations with the "CONSTRAINT_VIOLATION" code, and the name of the
+/// attribute, for the clients to point at the right field.
+fn user_update_error(error: DomainError) -> FieldError {
+    let message = error.to_string();
+    match error {
+        DomainError::ConstraintViolation(attribute) => FieldError::new(
+            message,
+            graphql_value!({ "code": "CONSTRAINT_VIOLATION", "attribute": attribute }),
+        ),
+        _ => error.into(),
+    }
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler + TcpBackendHandler + Sync> Mutation<Handler> {
     async fn create_user(
         context: &Context<Handler>,
         user: CreateUserInput,
@@ -66,6 +125,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized user creation".into());
         }
+        context.check_writable()?;
         let user_id = UserId::new(&user.id);
         context
             .handler
@@ -75,8 +135,12 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
                 display_name: user.display_name,
                 first_name: user.first_name,
                 last_name: user.last_name,
+                mail_aliases: user.mail_aliases.unwrap_or_default(),
+                mail_forwarding: user.mail_forwarding.unwrap_or_default(),
+                phone_numbers: user.phone_numbers.unwrap_or_default(),
             })
-            .await?;
+            .await
+            .map_err(user_update_error)?;
         Ok(context
             .handler
             .get_user_details(&user_id)
@@ -87,11 +151,24 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
     async fn create_group(
         context: &Context<Handler>,
         name: String,
+        description: Option<String>,
     ) -> FieldResult<super::query::Group<Handler>> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized group creation".into());
         }
+        context.check_writable()?;
         let group_id = context.handler.create_group(&name).await?;
+        if let Some(description) = description.filter(|d| !d.is_empty()) {
+            context
+                .handler
+                .update_group(UpdateGroupRequest {
+                    group_id,
+                    display_name: None,
+                    description: Some(Some(description)),
+                    dynamic_filter: None,
+                })
+                .await?;
+        }
         Ok(context
             .handler
             .get_group_details(group_id)
@@ -106,6 +183,13 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         if !context.validation_result.can_access(&user.id) {
             return Err("Unauthorized user update".into());
         }
+        if user.max_search_results.is_some() && !context.validation_result.is_admin {
+            return Err("Unauthorized search limit update".into());
+        }
+        if user.manager_id.is_some() && !context.validation_result.is_admin {
+            return Err("Unauthorized manager update".into());
+        }
+        context.check_writable()?;
         context
             .handler
             .update_user(UpdateUserRequest {
@@ -114,8 +198,18 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
                 display_name: user.display_name,
                 first_name: user.first_name,
                 last_name: user.last_name,
+                mail_aliases: user.mail_aliases,
+                mail_forwarding: user.mail_forwarding,
+                phone_numbers: user.phone_numbers,
+                max_search_results: user
+                    .max_search_results
+                    .map(|limit| u32::try_from(limit).ok()),
+                manager_user_id: user
+                    .manager_id
+                    .map(|id| (!id.is_empty()).then(|| UserId::new(&id))),
             })
-            .await?;
+            .await
+            .map_err(user_update_error)?;
         Ok(Success::new())
     }
 
@@ -126,14 +220,26 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized group update".into());
         }
-        if group.id == 1 {
+        context.check_writable()?;
+        if GroupId(group.id) == context.admin_group_id {
             return Err("Cannot change admin group details".into());
         }
+        let dynamic_filter = match group.dynamic_filter {
+            Some(filter) => Some(Some(filter)),
+            None => group
+                .remove_dynamic_filter
+                .filter(|remove| *remove)
+                .map(|_| None),
+        };
         context
             .handler
             .update_group(UpdateGroupRequest {
                 group_id: GroupId(group.id),
                 display_name: group.display_name,
+                description: group
+                    .description
+                    .map(|description| (!description.is_empty()).then(|| description)),
+                dynamic_filter,
             })
             .await?;
         Ok(Success::new())
@@ -147,6 +253,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized group membership modification".into());
         }
+        context.check_writable()?;
         context
             .handler
             .add_user_to_group(&UserId::new(&user_id), GroupId(group_id))
@@ -162,7 +269,9 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized group membership modification".into());
         }
-        if context.validation_result.user == user_id && group_id == 1 {
+        context.check_writable()?;
+        if context.validation_result.user == user_id && GroupId(group_id) == context.admin_group_id
+        {
             return Err("Cannot remove admin rights for current user".into());
         }
         context
@@ -176,6 +285,7 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized user deletion".into());
         }
+        context.check_writable()?;
         if context.validation_result.user == user_id {
             return Err("Cannot delete current user".into());
         }
@@ -187,10 +297,50 @@ impl<Handler: BackendHandler + Sync> Mutation<Handler> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized group deletion".into());
         }
-        if group_id == 1 {
+        context.check_writable()?;
+        if GroupId(group_id) == context.admin_group_id {
             return Err("Cannot delete admin group".into());
         }
         context.handler.delete_group(GroupId(group_id)).await?;
         Ok(Success::new())
     }
+
+    /// Issues a long-lived API token for the user, e.g. a service account.
+    async fn create_user_token(
+        context: &Context<Handler>,
+        user_id: String,
+        name: String,
+    ) -> FieldResult<CreatedUserToken<Handler>> {
+        if !context.validation_result.is_admin {
+            return Err("Unauthorized API token creation".into());
+        }
+        context.check_writable()?;
+        let (details, token) = context
+            .handler
+            .create_user_token(&UserId::new(&user_id), &name)
+            .await?;
+        Ok(CreatedUserToken {
+            token,
+            details: details.into(),
+        })
+    }
+
+    async fn revoke_user_token(
+        context: &Context<Handler>,
+        user_id: String,
+        token_id: i32,
+    ) -> FieldResult<Success> {
+        if !context.validation_result.can_access(&user_id) {
+            return Err("Unauthorized API token revocation".into());
+        }
+        context.check_writable()?;
+        if !context
+            .handler
+            .revoke_user_token(&UserId::new(&user_id), token_id)
+            .await?
+        {
+            return Err(format!("No API token with the ID {}", token_id).into());
+        }
+        Ok(Success::new())
+    }
 }
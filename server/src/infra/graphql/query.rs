@@ -1,10 +1,24 @@
-use crate::domain::handler::{BackendHandler, GroupId, GroupIdAndName, UserId};
-use juniper::{graphql_object, FieldResult, GraphQLInputObject};
+use crate::{
+    domain::handler::{
+        BackendHandler, GroupId, GroupIdAndName, GroupRequestFilter, Page, SubStringFilter, UserId,
+        UserSort,
+    },
+    infra::tcp_backend_handler::TcpBackendHandler,
+};
+use chrono::{DateTime, Utc};
+use juniper::{
+    graphql_object, graphql_value, Executor, FieldError, FieldResult, GraphQLEnum,
+    GraphQLInputObject, LookAheadMethods,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
 type DomainRequestFilter = crate::domain::handler::UserRequestFilter;
 type DomainUser = crate::domain::handler::User;
+type DomainUserSortField = crate::domain::handler::UserSortField;
 type DomainGroup = crate::domain::handler::Group;
+type DomainInvitation = crate::domain::handler::Invitation;
+type DomainUserToken = crate::infra::tcp_backend_handler::UserToken;
 use super::api::Context;
 
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
@@ -80,12 +94,184 @@ impl TryInto<DomainRequestFilter> for RequestFilter {
     }
 }
 
+/// Error for filters that can't be converted, with the "INVALID_FILTER" code in the extensions so
+/// that clients can tell it apart from other errors.
+fn invalid_filter(message: String) -> FieldError {
+    FieldError::new(
+        format!("Invalid filter: {}", message),
+        graphql_value!({ "code": "INVALID_FILTER" }),
+    )
+}
+
 #[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
 pub struct EqualityConstraint {
     field: String,
     value: String,
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Copy, GraphQLEnum)]
+/// How the value of an attribute is compared in an `AttributeFilter`.
+pub enum AttributeOperator {
+    Eq,
+    Ne,
+    /// Case-insensitive.
+    Contains,
+    /// Case-insensitive.
+    StartsWith,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// A condition on one of the attributes of the users: "id", "email", "displayName", "firstName"
+/// or "lastName".
+pub struct AttributeFilter {
+    name: String,
+    value: String,
+    operator: AttributeOperator,
+}
+
+impl TryInto<DomainRequestFilter> for AttributeFilter {
+    type Error = String;
+    fn try_into(self) -> Result<DomainRequestFilter, Self::Error> {
+        let field = match self.name.to_lowercase().as_str() {
+            "id" | "uid" | "user_id" => "user_id",
+            "email" | "mail" => "email",
+            "displayname" | "display_name" => "display_name",
+            "firstname" | "first_name" => "first_name",
+            "lastname" | "last_name" => "last_name",
+            _ => return Err(format!("Unknown attribute: {}", self.name)),
+        };
+        let equality = || {
+            if field == "user_id" {
+                DomainRequestFilter::UserId(UserId::new(&self.value))
+            } else {
+                DomainRequestFilter::Equality(field.to_string(), self.value.clone())
+            }
+        };
+        Ok(match self.operator {
+            AttributeOperator::Eq => equality(),
+            AttributeOperator::Ne => DomainRequestFilter::Not(Box::new(equality())),
+            AttributeOperator::Contains => DomainRequestFilter::SubString(
+                field.to_string(),
+                SubStringFilter {
+                    any: vec![self.value],
+                    ..Default::default()
+                },
+            ),
+            AttributeOperator::StartsWith => DomainRequestFilter::SubString(
+                field.to_string(),
+                SubStringFilter {
+                    initial: Some(self.value),
+                    ..Default::default()
+                },
+            ),
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// Conditions on the users, all of which have to match. An empty filter matches all the users.
+pub struct UserFilter {
+    id: Option<String>,
+    email: Option<String>,
+    display_name_contains: Option<String>,
+    /// The id of a group the user belongs to.
+    group_member: Option<i32>,
+    created_before: Option<DateTime<Utc>>,
+    created_after: Option<DateTime<Utc>>,
+    /// Users can't be disabled yet: `true` matches no user.
+    disabled: Option<bool>,
+    has_custom_attribute: Option<AttributeFilter>,
+}
+
+impl TryInto<DomainRequestFilter> for UserFilter {
+    type Error = String;
+    fn try_into(self) -> Result<DomainRequestFilter, Self::Error> {
+        let mut filters = Vec::new();
+        if let Some(id) = self.id {
+            filters.push(DomainRequestFilter::UserId(UserId::new(&id)));
+        }
+        if let Some(email) = self.email {
+            filters.push(DomainRequestFilter::Equality("email".to_string(), email));
+        }
+        if let Some(display_name) = self.display_name_contains {
+            filters.push(DomainRequestFilter::SubString(
+                "display_name".to_string(),
+                SubStringFilter {
+                    any: vec![display_name],
+                    ..Default::default()
+                },
+            ));
+        }
+        if let Some(group_id) = self.group_member {
+            filters.push(DomainRequestFilter::MemberOfId(GroupId(group_id)));
+        }
+        if let Some(date) = self.created_before {
+            filters.push(DomainRequestFilter::CreatedBefore(date));
+        }
+        if let Some(date) = self.created_after {
+            filters.push(DomainRequestFilter::CreatedAfter(date));
+        }
+        if self.disabled == Some(true) {
+            // An empty "And" matches everything.
+            filters.push(DomainRequestFilter::Not(Box::new(
+                DomainRequestFilter::And(vec![]),
+            )));
+        }
+        if let Some(attribute) = self.has_custom_attribute {
+            filters.push(attribute.try_into()?);
+        }
+        Ok(DomainRequestFilter::And(filters))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, GraphQLEnum)]
+pub enum UserSortField {
+    Id,
+    Email,
+    DisplayName,
+    CreationDate,
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// The order of the users. The ties are sorted by user id.
+pub struct SortSpec {
+    field: UserSortField,
+    descending: Option<bool>,
+}
+
+impl From<SortSpec> for UserSort {
+    fn from(sort: SortSpec) -> Self {
+        UserSort {
+            field: match sort.field {
+                UserSortField::Id => DomainUserSortField::UserId,
+                UserSortField::Email => DomainUserSortField::Email,
+                UserSortField::DisplayName => DomainUserSortField::DisplayName,
+                UserSortField::CreationDate => DomainUserSortField::CreationDate,
+            },
+            descending: sort.descending.unwrap_or(false),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, GraphQLInputObject)]
+/// Skips the first `offset` users (0 by default), and returns at most `limit` users.
+pub struct PageInput {
+    offset: Option<i32>,
+    limit: i32,
+}
+
+impl TryInto<Page> for PageInput {
+    type Error = String;
+    fn try_into(self) -> Result<Page, Self::Error> {
+        Ok(Page {
+            offset: u64::try_from(self.offset.unwrap_or(0))
+                .map_err(|_| "The offset can't be negative".to_string())?,
+            limit: u64::try_from(self.limit)
+                .map_err(|_| "The limit can't be negative".to_string())?,
+        })
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 /// The top-level GraphQL query type.
 pub struct Query<Handler: BackendHandler> {
@@ -101,7 +287,7 @@ impl<Handler: BackendHandler> Query<Handler> {
 }
 
 #[graphql_object(context = Context<Handler>)]
-impl<Handler: BackendHandler + Sync> Query<Handler> {
+impl<Handler: BackendHandler + TcpBackendHandler + Sync> Query<Handler> {
     fn api_version() -> &'static str {
         "1.0"
     }
@@ -119,27 +305,122 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
 
     async fn users(
         context: &Context<Handler>,
+        executor: &Executor<'_, '_, Context<Handler>>,
         #[graphql(name = "where")] filters: Option<RequestFilter>,
     ) -> FieldResult<Vec<User<Handler>>> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized access to user list".into());
         }
-        Ok(context
+        let prefetch_groups = executor.look_ahead().has_child("groups");
+        list_users(context, filters, prefetch_groups).await
+    }
+
+    /// Same as `users`, with the number of users matching the filter. A filter that matches no
+    /// user gives an empty list, an invalid filter gives an "INVALID_FILTER" error.
+    async fn search_users(
+        context: &Context<Handler>,
+        executor: &Executor<'_, '_, Context<Handler>>,
+        #[graphql(name = "where")] filters: Option<RequestFilter>,
+    ) -> FieldResult<UserSearchResult<Handler>> {
+        if !context.validation_result.is_admin {
+            return Err("Unauthorized access to user list".into());
+        }
+        let prefetch_groups = executor
+            .look_ahead()
+            .select_child("users")
+            .map_or(false, |users| users.has_child("groups"));
+        let users = list_users(context, filters, prefetch_groups).await?;
+        let total_count = users.len() as u64;
+        Ok(UserSearchResult { users, total_count })
+    }
+
+    /// The users matching the filter, in the given order, restricted to the page. The total count
+    /// is the number of users matching the filter across all the pages.
+    async fn list_users(
+        context: &Context<Handler>,
+        executor: &Executor<'_, '_, Context<Handler>>,
+        filter: Option<UserFilter>,
+        sort: Option<SortSpec>,
+        page: Option<PageInput>,
+    ) -> FieldResult<UserSearchResult<Handler>> {
+        if !context.validation_result.is_admin {
+            return Err("Unauthorized access to user list".into());
+        }
+        let filter: Option<DomainRequestFilter> = filter
+            .map(TryInto::try_into)
+            .transpose()
+            .map_err(invalid_filter)?;
+        let page: Option<Page> = page
+            .map(TryInto::try_into)
+            .transpose()
+            .map_err(|e| format!("Invalid page: {}", e))?;
+        let sort = sort.map(Into::into).unwrap_or_default();
+        let prefetch_groups = executor
+            .look_ahead()
+            .select_child("users")
+            .map_or(false, |users| users.has_child("groups"));
+        let mut users: Vec<User<Handler>> = context
             .handler
-            .list_users(filters.map(TryInto::try_into).transpose()?)
-            .await
-            .map(|v| v.into_iter().map(Into::into).collect())?)
+            .list_users_sorted(filter.clone(), sort, page)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        if prefetch_groups {
+            prefetch_user_groups(context, &mut users).await?;
+        }
+        let total_count = match page {
+            Some(_) => context.handler.count_users(filter).await?,
+            None => users.len() as u64,
+        };
+        Ok(UserSearchResult { users, total_count })
     }
 
-    async fn groups(context: &Context<Handler>) -> FieldResult<Vec<Group<Handler>>> {
+    /// The number of users matching the filter, without fetching them.
+    async fn user_count(
+        context: &Context<Handler>,
+        filter: Option<RequestFilter>,
+    ) -> FieldResult<i32> {
+        if !context.validation_result.is_admin {
+            return Err("Unauthorized access to user list".into());
+        }
+        let filter = filter
+            .map(TryInto::try_into)
+            .transpose()
+            .map_err(invalid_filter)?;
+        let count = context.handler.count_users(filter).await?;
+        Ok(i32::try_from(count).unwrap_or(i32::MAX))
+    }
+
+    async fn group_count(context: &Context<Handler>) -> FieldResult<i32> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized access to group list".into());
         }
-        Ok(context
+        let count = context.handler.count_groups(None).await?;
+        Ok(i32::try_from(count).unwrap_or(i32::MAX))
+    }
+
+    async fn groups(
+        context: &Context<Handler>,
+        executor: &Executor<'_, '_, Context<Handler>>,
+    ) -> FieldResult<Vec<Group<Handler>>> {
+        if !context.validation_result.is_admin {
+            return Err("Unauthorized access to group list".into());
+        }
+        let look_ahead = executor.look_ahead();
+        let prefetch = look_ahead.select_child("users").map(|users| {
+            // Whether to also prefetch the groups of the members.
+            users.has_child("groups")
+        });
+        let mut groups = context
             .handler
             .list_groups(None)
             .await
-            .map(|v| v.into_iter().map(Into::into).collect())?)
+            .map(|v| v.into_iter().map(Into::into).collect())?;
+        if let Some(prefetch_member_groups) = prefetch {
+            prefetch_group_users(context, &mut groups, prefetch_member_groups).await?;
+        }
+        Ok(groups)
     }
 
     async fn group(context: &Context<Handler>, group_id: i32) -> FieldResult<Group<Handler>> {
@@ -152,12 +433,116 @@ impl<Handler: BackendHandler + Sync> Query<Handler> {
             .await
             .map(Into::into)?)
     }
+
+    /// The invitations that haven't been used yet and haven't expired.
+    async fn list_invitations(context: &Context<Handler>) -> FieldResult<Vec<Invitation<Handler>>> {
+        if !context.validation_result.is_admin {
+            return Err("Unauthorized access to invitation list".into());
+        }
+        Ok(context
+            .handler
+            .list_pending_invitations()
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+
+    /// The API tokens of the user, without the tokens themselves.
+    async fn user_tokens(
+        context: &Context<Handler>,
+        user_id: String,
+    ) -> FieldResult<Vec<UserToken<Handler>>> {
+        if !context.validation_result.can_access(&user_id) {
+            return Err("Unauthorized access to user data".into());
+        }
+        Ok(context
+            .handler
+            .list_user_tokens(&UserId::new(&user_id))
+            .await
+            .map(|v| v.into_iter().map(Into::into).collect())?)
+    }
+}
+
+async fn list_users<Handler: BackendHandler>(
+    context: &Context<Handler>,
+    filters: Option<RequestFilter>,
+    prefetch_groups: bool,
+) -> FieldResult<Vec<User<Handler>>> {
+    let filters = filters
+        .map(TryInto::try_into)
+        .transpose()
+        .map_err(invalid_filter)?;
+    let mut users = context
+        .handler
+        .list_users(filters)
+        .await
+        .map(|v| v.into_iter().map(Into::into).collect())?;
+    if prefetch_groups {
+        prefetch_user_groups(context, &mut users).await?;
+    }
+    Ok(users)
+}
+
+/// Fetches the groups of all the users in a single query, instead of one per user.
+async fn prefetch_user_groups<Handler: BackendHandler>(
+    context: &Context<Handler>,
+    users: &mut [User<Handler>],
+) -> FieldResult<()> {
+    let user_ids: Vec<UserId> = users.iter().map(|u| u.user.user_id.clone()).collect();
+    let mut groups = context.handler.get_groups_for_users(&user_ids).await?;
+    for user in users {
+        user.groups = groups.remove(&user.user.user_id);
+    }
+    Ok(())
+}
+
+/// Fetches the members of all the groups in a single query, instead of one per group.
+async fn prefetch_group_users<Handler: BackendHandler>(
+    context: &Context<Handler>,
+    groups: &mut [Group<Handler>],
+    prefetch_member_groups: bool,
+) -> FieldResult<()> {
+    let member_ids: HashSet<&String> = groups
+        .iter()
+        .flat_map(|g| g.members.iter().flatten())
+        .collect();
+    let mut members: Vec<User<Handler>> = if member_ids.is_empty() {
+        Vec::new()
+    } else {
+        context
+            .handler
+            .list_users(Some(DomainRequestFilter::UserIdIn(
+                member_ids.into_iter().map(|id| UserId::new(id)).collect(),
+            )))
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    };
+    if prefetch_member_groups {
+        prefetch_user_groups(context, &mut members).await?;
+    }
+    for group in groups {
+        let group_members = match &group.members {
+            Some(group_members) => group_members,
+            None => continue,
+        };
+        group.users = Some(
+            members
+                .iter()
+                .filter(|u| group_members.iter().any(|m| m == u.user.user_id.as_str()))
+                .cloned()
+                .collect(),
+        );
+    }
+    Ok(())
 }
 
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
 /// Represents a single user.
 pub struct User<Handler: BackendHandler> {
     user: DomainUser,
+    /// Prefetched groups, to avoid a query per user when listing users.
+    groups: Option<HashSet<GroupIdAndName>>,
     _phantom: std::marker::PhantomData<Box<Handler>>,
 }
 
@@ -165,6 +550,17 @@ impl<Handler: BackendHandler> Default for User<Handler> {
     fn default() -> Self {
         Self {
             user: DomainUser::default(),
+            groups: None,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<Handler: BackendHandler> Clone for User<Handler> {
+    fn clone(&self) -> Self {
+        Self {
+            user: self.user.clone(),
+            groups: self.groups.clone(),
             _phantom: std::marker::PhantomData,
         }
     }
@@ -176,28 +572,59 @@ impl<Handler: BackendHandler + Sync> User<Handler> {
         self.user.user_id.as_str()
     }
 
-    fn email(&self) -> &str {
-        &self.user.email
+    fn email(&self, context: &Context<Handler>) -> &str {
+        self.visible_or_empty(context, "email", &self.user.email)
     }
 
-    fn display_name(&self) -> &str {
-        &self.user.display_name
+    fn display_name(&self, context: &Context<Handler>) -> &str {
+        self.visible_or_empty(context, "display_name", &self.user.display_name)
     }
 
-    fn first_name(&self) -> &str {
-        &self.user.first_name
+    fn first_name(&self, context: &Context<Handler>) -> &str {
+        self.visible_or_empty(context, "first_name", &self.user.first_name)
     }
 
-    fn last_name(&self) -> &str {
-        &self.user.last_name
+    fn last_name(&self, context: &Context<Handler>) -> &str {
+        self.visible_or_empty(context, "last_name", &self.user.last_name)
     }
 
     fn creation_date(&self) -> chrono::DateTime<chrono::Utc> {
         self.user.creation_date
     }
 
+    /// Additional addresses delivered to this user's mailbox.
+    fn mail_aliases(&self, context: &Context<Handler>) -> &[String] {
+        self.visible_or_empty(context, "mail_aliases", &self.user.mail_aliases)
+    }
+
+    /// External addresses this user's mail is forwarded to.
+    fn mail_forwarding(&self, context: &Context<Handler>) -> &[String] {
+        self.visible_or_empty(context, "mail_forwarding", &self.user.mail_forwarding)
+    }
+
+    /// The phone numbers of this user, exposed as the LDAP `telephoneNumber` attribute.
+    fn phone_numbers(&self, context: &Context<Handler>) -> &[String] {
+        self.visible_or_empty(context, "phone_numbers", &self.user.phone_numbers)
+    }
+
+    /// The maximum number of entries returned by this user's LDAP searches, if it overrides the
+    /// configured limit.
+    fn max_search_results(&self) -> Option<i32> {
+        self.user
+            .max_search_results
+            .map(|limit| i32::try_from(limit).unwrap_or(i32::MAX))
+    }
+
+    /// The user this user reports to.
+    fn manager_id(&self) -> Option<&str> {
+        self.user.manager_user_id.as_ref().map(UserId::as_str)
+    }
+
     /// The groups to which this user belongs.
     async fn groups(&self, context: &Context<Handler>) -> FieldResult<Vec<Group<Handler>>> {
+        if let Some(groups) = &self.groups {
+            return Ok(groups.iter().cloned().map(Into::into).collect());
+        }
         Ok(context
             .handler
             .get_user_groups(&self.user.user_id)
@@ -206,21 +633,69 @@ impl<Handler: BackendHandler + Sync> User<Handler> {
     }
 }
 
+impl<Handler: BackendHandler> User<Handler> {
+    /// Returns the value of the field, or an empty value if the attribute visibility policy hides
+    /// it from the viewer.
+    fn visible_or_empty<'a, T: ?Sized>(
+        &self,
+        context: &Context<Handler>,
+        field: &str,
+        value: &'a T,
+    ) -> &'a T
+    where
+        &'a T: Default,
+    {
+        let validation_result = &context.validation_result;
+        if context.attribute_visibility.is_visible(
+            field,
+            validation_result.is_admin,
+            validation_result.user == self.user.user_id.as_str(),
+        ) {
+            value
+        } else {
+            Default::default()
+        }
+    }
+}
+
 impl<Handler: BackendHandler> From<DomainUser> for User<Handler> {
     fn from(user: DomainUser) -> Self {
         Self {
             user,
+            groups: None,
             _phantom: std::marker::PhantomData,
         }
     }
 }
 
+#[derive(PartialEq, Eq, Debug)]
+/// The users matching a search.
+pub struct UserSearchResult<Handler: BackendHandler> {
+    users: Vec<User<Handler>>,
+    total_count: u64,
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> UserSearchResult<Handler> {
+    fn users(&self) -> &[User<Handler>] {
+        &self.users
+    }
+
+    fn total_count(&self) -> i32 {
+        i32::try_from(self.total_count).unwrap_or(i32::MAX)
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
 /// Represents a single group.
 pub struct Group<Handler: BackendHandler> {
     group_id: i32,
     display_name: String,
+    /// The description, if it was fetched with the group.
+    description: Option<Option<String>>,
     members: Option<Vec<String>>,
+    /// Prefetched members, to avoid a query per group when listing groups.
+    users: Option<Vec<User<Handler>>>,
     _phantom: std::marker::PhantomData<Box<Handler>>,
 }
 
@@ -232,11 +707,27 @@ impl<Handler: BackendHandler + Sync> Group<Handler> {
     fn display_name(&self) -> String {
         self.display_name.clone()
     }
+    /// The name to show instead of the display name, which is the cn of the group.
+    async fn description(&self, context: &Context<Handler>) -> FieldResult<Option<String>> {
+        if let Some(description) = &self.description {
+            return Ok(description.clone());
+        }
+        Ok(context
+            .handler
+            .list_groups(Some(GroupRequestFilter::GroupId(GroupId(self.group_id))))
+            .await?
+            .into_iter()
+            .next()
+            .and_then(|group| group.description))
+    }
     /// The groups to which this user belongs.
     async fn users(&self, context: &Context<Handler>) -> FieldResult<Vec<User<Handler>>> {
         if !context.validation_result.is_admin {
             return Err("Unauthorized access to group data".into());
         }
+        if let Some(users) = &self.users {
+            return Ok(users.clone());
+        }
         Ok(context
             .handler
             .list_users(Some(DomainRequestFilter::MemberOfId(GroupId(
@@ -252,7 +743,9 @@ impl<Handler: BackendHandler> From<GroupIdAndName> for Group<Handler> {
         Self {
             group_id: group_id_and_name.0 .0,
             display_name: group_id_and_name.1,
+            description: None,
             members: None,
+            users: None,
             _phantom: std::marker::PhantomData,
         }
     }
@@ -263,7 +756,75 @@ impl<Handler: BackendHandler> From<DomainGroup> for Group<Handler> {
         Self {
             group_id: group.id.0,
             display_name: group.display_name,
+            description: Some(group.description),
             members: Some(group.users.into_iter().map(UserId::into_string).collect()),
+            users: None,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+/// Represents an invitation for a new user.
+pub struct Invitation<Handler: BackendHandler> {
+    invitation: DomainInvitation,
+    _phantom: std::marker::PhantomData<Box<Handler>>,
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> Invitation<Handler> {
+    fn id(&self) -> i32 {
+        self.invitation.id.0
+    }
+
+    fn email(&self) -> &str {
+        &self.invitation.email
+    }
+
+    fn expiry_date(&self) -> chrono::DateTime<chrono::Utc> {
+        self.invitation.expiry_date
+    }
+}
+
+impl<Handler: BackendHandler> From<DomainInvitation> for Invitation<Handler> {
+    fn from(invitation: DomainInvitation) -> Self {
+        Self {
+            invitation,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+/// Represents a long-lived API token of a user.
+pub struct UserToken<Handler: BackendHandler> {
+    token: DomainUserToken,
+    _phantom: std::marker::PhantomData<Box<Handler>>,
+}
+
+#[graphql_object(context = Context<Handler>)]
+impl<Handler: BackendHandler + Sync> UserToken<Handler> {
+    fn id(&self) -> i32 {
+        self.token.id
+    }
+
+    fn user_id(&self) -> &str {
+        self.token.user_id.as_str()
+    }
+
+    fn name(&self) -> &str {
+        &self.token.name
+    }
+
+    fn creation_date(&self) -> chrono::DateTime<chrono::Utc> {
+        self.token.creation_date
+    }
+}
+
+impl<Handler: BackendHandler> From<DomainUserToken> for UserToken<Handler> {
+    fn from(token: DomainUserToken) -> Self {
+        Self {
+            token,
             _phantom: std::marker::PhantomData,
         }
     }
@@ -273,9 +834,15 @@ impl<Handler: BackendHandler> From<DomainGroup> for Group<Handler> {
 mod tests {
     use super::*;
     use crate::{
-        domain::handler::{MockTestBackendHandler, UserRequestFilter},
-        infra::auth_service::ValidationResults,
+        domain::handler::UserRequestFilter,
+        infra::{
+            attribute_visibility::{AttributeVisibility, AttributeVisibilityPolicy},
+            auth_service::ValidationResults,
+            maintenance::MaintenanceMode,
+            tcp_backend_handler::MockTestTcpBackendHandler,
+        },
     };
+    use chrono::TimeZone;
     use juniper::{
         execute, graphql_value, DefaultScalarValue, EmptyMutation, EmptySubscription, GraphQLType,
         RootNode, Variables,
@@ -306,7 +873,7 @@ mod tests {
           }
         }"#;
 
-        let mut mock = MockTestBackendHandler::new();
+        let mut mock = MockTestTcpBackendHandler::new();
         mock.expect_get_user_details()
             .with(eq(UserId::new("bob")))
             .return_once(|_| {
@@ -322,12 +889,15 @@ mod tests {
             .with(eq(UserId::new("bob")))
             .return_once(|_| Ok(groups));
 
-        let context = Context::<MockTestBackendHandler> {
+        let context = Context::<MockTestTcpBackendHandler> {
             handler: Box::new(mock),
             validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
         };
 
-        let schema = schema(Query::<MockTestBackendHandler>::new());
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
         assert_eq!(
             execute(QUERY, None, &schema, &Variables::new(), &context).await,
             Ok((
@@ -344,6 +914,61 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn get_user_hidden_attributes() {
+        const QUERY: &str = r#"{
+          user(userId: "bob") {
+            email
+            mailForwarding
+          }
+        }"#;
+
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .return_once(|_| {
+                Ok(DomainUser {
+                    user_id: UserId::new("bob"),
+                    email: "bob@bobbers.on".to_string(),
+                    mail_forwarding: vec!["bob@elsewhere.on".to_string()],
+                    ..Default::default()
+                })
+            });
+
+        let context = Context::<MockTestTcpBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults {
+                user: "bob".to_string(),
+                is_admin: false,
+            },
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::new(
+                [
+                    ("email".to_string(), AttributeVisibility::SelfAndAdmins),
+                    ("mail_forwarding".to_string(), AttributeVisibility::Admins),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            admin_group_id: GroupId(1),
+        };
+
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "user": {
+                        "email": "bob@bobbers.on",
+                        "mailForwarding": [],
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
     #[tokio::test]
     async fn list_users() {
         const QUERY: &str = r#"{
@@ -363,7 +988,7 @@ mod tests {
           }
         }"#;
 
-        let mut mock = MockTestBackendHandler::new();
+        let mut mock = MockTestTcpBackendHandler::new();
         mock.expect_list_users()
             .with(eq(Some(UserRequestFilter::Or(vec![
                 UserRequestFilter::Equality("id".to_string(), "bob".to_string()),
@@ -384,12 +1009,15 @@ mod tests {
                 ])
             });
 
-        let context = Context::<MockTestBackendHandler> {
+        let context = Context::<MockTestTcpBackendHandler> {
             handler: Box::new(mock),
             validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
         };
 
-        let schema = schema(Query::<MockTestBackendHandler>::new());
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
         assert_eq!(
             execute(QUERY, None, &schema, &Variables::new(), &context).await,
             Ok((
@@ -410,4 +1038,342 @@ mod tests {
             ))
         );
     }
+
+    #[tokio::test]
+    async fn list_users_with_groups() {
+        const QUERY: &str = r#"{
+          users {
+            id
+            groups {
+              displayName
+            }
+          }
+        }"#;
+
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users().with(eq(None)).return_once(|_| {
+            Ok(vec![
+                DomainUser {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                },
+                DomainUser {
+                    user_id: UserId::new("robert"),
+                    ..Default::default()
+                },
+            ])
+        });
+        mock.expect_get_groups_for_users()
+            .withf(|ids| *ids == [UserId::new("bob"), UserId::new("robert")])
+            .times(1)
+            .return_once(|_| {
+                let mut groups = HashSet::new();
+                groups.insert(GroupIdAndName(GroupId(3), "Bobbersons".to_string()));
+                Ok([
+                    (UserId::new("bob"), groups),
+                    (UserId::new("robert"), HashSet::new()),
+                ]
+                .into_iter()
+                .collect())
+            });
+
+        let context = Context::<MockTestTcpBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
+        };
+
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "users": [
+                        {
+                            "id": "bob",
+                            "groups": [{"displayName": "Bobbersons"}]
+                        },
+                        {
+                            "id": "robert",
+                            "groups": []
+                        },
+                    ]
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn search_users_no_match() {
+        const QUERY: &str = r#"{
+          searchUsers(filters: {eq: {field: "email", value: "nobody@bobbers.on"}}) {
+            users {
+              id
+            }
+            totalCount
+          }
+        }"#;
+
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::Equality(
+                "email".to_string(),
+                "nobody@bobbers.on".to_string(),
+            ))))
+            .return_once(|_| Ok(vec![]));
+
+        let context = Context::<MockTestTcpBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
+        };
+
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "searchUsers": {
+                        "users": [],
+                        "totalCount": 0
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn search_users_invalid_filter() {
+        const QUERY: &str = r#"{
+          searchUsers(filters: {eq: {field: "email", value: "bob@bobbers.on"}, memberOf: "admins"}) {
+            totalCount
+          }
+        }"#;
+
+        let context = Context::<MockTestTcpBackendHandler> {
+            handler: Box::new(MockTestTcpBackendHandler::new()),
+            validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
+        };
+
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
+        let (_, errors) = execute(QUERY, None, &schema, &Variables::new(), &context)
+            .await
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].error().extensions(),
+            &graphql_value!({ "code": "INVALID_FILTER" })
+        );
+    }
+
+    #[test]
+    fn user_filter_conversion() {
+        let filter: DomainRequestFilter = UserFilter {
+            id: None,
+            email: Some("bob@bobbers.on".to_string()),
+            display_name_contains: Some("bob".to_string()),
+            group_member: Some(3),
+            created_before: None,
+            created_after: None,
+            disabled: Some(false),
+            has_custom_attribute: Some(AttributeFilter {
+                name: "firstName".to_string(),
+                value: "Rob".to_string(),
+                operator: AttributeOperator::StartsWith,
+            }),
+        }
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            filter,
+            DomainRequestFilter::And(vec![
+                DomainRequestFilter::Equality("email".to_string(), "bob@bobbers.on".to_string()),
+                DomainRequestFilter::SubString(
+                    "display_name".to_string(),
+                    SubStringFilter {
+                        any: vec!["bob".to_string()],
+                        ..Default::default()
+                    }
+                ),
+                DomainRequestFilter::MemberOfId(GroupId(3)),
+                DomainRequestFilter::SubString(
+                    "first_name".to_string(),
+                    SubStringFilter {
+                        initial: Some("Rob".to_string()),
+                        ..Default::default()
+                    }
+                ),
+            ])
+        );
+        let attribute = |name: &str, operator| AttributeFilter {
+            name: name.to_string(),
+            value: "bob".to_string(),
+            operator,
+        };
+        assert_eq!(
+            attribute("id", AttributeOperator::Ne).try_into(),
+            Ok(DomainRequestFilter::Not(Box::new(
+                DomainRequestFilter::UserId(UserId::new("bob"))
+            )))
+        );
+        assert!(TryInto::<DomainRequestFilter>::try_into(attribute(
+            "password",
+            AttributeOperator::Eq
+        ))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn list_users_sorted_and_paged() {
+        const QUERY: &str = r#"{
+          listUsers(
+              filter: {createdAfter: "2021-05-01T00:00:00Z", disabled: false},
+              sort: {field: DISPLAY_NAME, descending: true},
+              page: {offset: 2, limit: 1}) {
+            users {
+              id
+            }
+            totalCount
+          }
+        }"#;
+
+        let filter = UserRequestFilter::And(vec![UserRequestFilter::CreatedAfter(
+            chrono::Utc.ymd(2021, 5, 1).and_hms(0, 0, 0),
+        )]);
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_users_sorted()
+            .with(
+                eq(Some(filter.clone())),
+                eq(UserSort {
+                    field: DomainUserSortField::DisplayName,
+                    descending: true,
+                }),
+                eq(Some(Page {
+                    offset: 2,
+                    limit: 1,
+                })),
+            )
+            .return_once(|_, _, _| {
+                Ok(vec![DomainUser {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_count_users()
+            .with(eq(Some(filter)))
+            .return_once(|_| Ok(3));
+
+        let context = Context::<MockTestTcpBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
+        };
+
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "listUsers": {
+                        "users": [{"id": "bob"}],
+                        "totalCount": 3
+                    }
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn user_and_group_count() {
+        const QUERY: &str = r#"{
+          userCount(filter: {memberOf: "admins"})
+          groupCount
+        }"#;
+
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_count_users()
+            .with(eq(Some(UserRequestFilter::MemberOf("admins".to_string()))))
+            .return_once(|_| Ok(2));
+        mock.expect_count_groups()
+            .with(eq(None))
+            .return_once(|_| Ok(5));
+
+        let context = Context::<MockTestTcpBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
+        };
+
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "userCount": 2,
+                    "groupCount": 5,
+                }),
+                vec![]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn list_invitations() {
+        const QUERY: &str = r#"{
+          listInvitations {
+            id
+            email
+          }
+        }"#;
+
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_list_pending_invitations().return_once(|| {
+            use chrono::TimeZone;
+            Ok(vec![DomainInvitation {
+                id: crate::domain::handler::InvitationId(3),
+                email: "bob@bobbers.on".to_string(),
+                token: "secret".to_string(),
+                expiry_date: chrono::Utc.timestamp(0, 0),
+                used: false,
+            }])
+        });
+
+        let context = Context::<MockTestTcpBackendHandler> {
+            handler: Box::new(mock),
+            validation_result: ValidationResults::admin(),
+            maintenance_mode: MaintenanceMode::default(),
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            admin_group_id: GroupId(1),
+        };
+
+        let schema = schema(Query::<MockTestTcpBackendHandler>::new());
+        assert_eq!(
+            execute(QUERY, None, &schema, &Variables::new(), &context).await,
+            Ok((
+                graphql_value!(
+                {
+                    "listInvitations": [{"id": 3, "email": "bob@bobbers.on"}]
+                }),
+                vec![]
+            ))
+        );
+    }
 }
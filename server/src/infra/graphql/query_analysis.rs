@@ -0,0 +1,482 @@
+//! Lightweight static analysis of GraphQL queries, run before handing them to juniper.
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Token {
+    Name(String),
+    Punctuator(char),
+    Spread,
+    /// String, number or other literal value: its content is irrelevant for the analysis.
+    Value,
+}
+
+/// Splits a GraphQL document into tokens, dropping whitespace, commas and comments.
+pub(crate) fn tokenize(query: &str) -> Vec<Token> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() || c == ',' || c == '\u{feff}' {
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' && chars[i] != '\r' {
+                i += 1;
+            }
+        } else if c == '"' {
+            if chars[i..].starts_with(&['"', '"', '"']) {
+                i += 3;
+                while i < chars.len() && !chars[i..].starts_with(&['"', '"', '"']) {
+                    if chars[i..].starts_with(&['\\', '"', '"', '"']) {
+                        i += 4;
+                    } else {
+                        i += 1;
+                    }
+                }
+                i += 3;
+            } else {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' && chars[i] != '\n' {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            tokens.push(Token::Value);
+        } else if c == '.' && chars[i..].starts_with(&['.', '.', '.']) {
+            tokens.push(Token::Spread);
+            i += 3;
+        } else if c == '_' || c.is_ascii_alphabetic() {
+            let start = i;
+            while i < chars.len() && (chars[i] == '_' || chars[i].is_ascii_alphanumeric()) {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect()));
+        } else if c == '-' || c.is_ascii_digit() {
+            i += 1;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '.' || chars[i] == '-')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Value);
+        } else {
+            tokens.push(Token::Punctuator(c));
+            i += 1;
+        }
+    }
+    tokens
+}
+
+/// Whether the query reads the schema through the `__schema` or `__type` introspection fields.
+/// `__typename` is allowed, since clients routinely use it and it doesn't disclose the schema.
+pub(crate) fn uses_introspection(query: &str) -> bool {
+    tokenize(query)
+        .iter()
+        .any(|t| matches!(t, Token::Name(name) if name == "__schema" || name == "__type"))
+}
+
+/// The fields that return a list of objects: their selections are resolved for every item.
+const LIST_FIELDS: &[&str] = &["users", "groups", "listInvitations", "userTokens"];
+
+/// The number of items a list field is assumed to return, since the size is only known once
+/// the query runs.
+const LIST_SIZE_ESTIMATE: usize = 10;
+
+/// A selection in a query, with only what is needed to measure it.
+#[derive(Debug)]
+enum Selection {
+    /// The name of the field (not its alias), and its selections.
+    Field(String, Vec<Selection>),
+    FragmentSpread(String),
+    InlineFragment(Vec<Selection>),
+}
+
+#[derive(Debug, Default)]
+struct Document {
+    operations: Vec<Vec<Selection>>,
+    fragments: HashMap<String, Vec<Selection>>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next_token(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "Unexpected end of query".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn peek_is(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punctuator(c))
+    }
+
+    fn expect_name(&mut self) -> Result<String, String> {
+        match self.next_token()? {
+            Token::Name(name) => Ok(name),
+            t => Err(format!("Expected a name, got {:?}", t)),
+        }
+    }
+
+    /// Skips a parenthesized block (arguments or variable definitions), which can contain
+    /// object values with braces.
+    fn skip_parentheses(&mut self) -> Result<(), String> {
+        if !self.peek_is('(') {
+            return Ok(());
+        }
+        let mut depth = 0;
+        loop {
+            match self.next_token()? {
+                Token::Punctuator('(') => depth += 1,
+                Token::Punctuator(')') => depth -= 1,
+                _ => (),
+            }
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_directives(&mut self) -> Result<(), String> {
+        while self.peek_is('@') {
+            self.next_token()?;
+            self.expect_name()?;
+            self.skip_parentheses()?;
+        }
+        Ok(())
+    }
+
+    fn parse_document(&mut self) -> Result<Document, String> {
+        let mut document = Document::default();
+        while let Some(token) = self.peek().cloned() {
+            match token {
+                Token::Punctuator('{') => document.operations.push(self.parse_selection_set()?),
+                Token::Name(keyword) if keyword == "fragment" => {
+                    self.next_token()?;
+                    let name = self.expect_name()?;
+                    if self.expect_name()? != "on" {
+                        return Err(format!(r#"Expected "on" after fragment "{}""#, name));
+                    }
+                    self.expect_name()?;
+                    self.skip_directives()?;
+                    let selections = self.parse_selection_set()?;
+                    if document
+                        .fragments
+                        .insert(name.clone(), selections)
+                        .is_some()
+                    {
+                        return Err(format!(r#"Duplicate fragment "{}""#, name));
+                    }
+                }
+                Token::Name(keyword)
+                    if keyword == "query" || keyword == "mutation" || keyword == "subscription" =>
+                {
+                    self.next_token()?;
+                    if matches!(self.peek(), Some(Token::Name(_))) {
+                        self.next_token()?;
+                    }
+                    self.skip_parentheses()?;
+                    self.skip_directives()?;
+                    document.operations.push(self.parse_selection_set()?);
+                }
+                t => return Err(format!("Unexpected {:?} in query", t)),
+            }
+        }
+        Ok(document)
+    }
+
+    fn parse_selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        if self.next_token()? != Token::Punctuator('{') {
+            return Err("Expected a selection set".to_string());
+        }
+        let mut selections = Vec::new();
+        while !self.peek_is('}') {
+            match self.next_token()? {
+                Token::Spread => match self.peek() {
+                    Some(Token::Name(name)) if name != "on" => {
+                        selections.push(Selection::FragmentSpread(self.expect_name()?));
+                        self.skip_directives()?;
+                    }
+                    _ => {
+                        if matches!(self.peek(), Some(Token::Name(_))) {
+                            self.next_token()?;
+                            self.expect_name()?;
+                        }
+                        self.skip_directives()?;
+                        selections.push(Selection::InlineFragment(self.parse_selection_set()?));
+                    }
+                },
+                Token::Name(mut name) => {
+                    if self.peek_is(':') {
+                        self.next_token()?;
+                        name = self.expect_name()?;
+                    }
+                    self.skip_parentheses()?;
+                    self.skip_directives()?;
+                    let children = if self.peek_is('{') {
+                        self.parse_selection_set()?
+                    } else {
+                        Vec::new()
+                    };
+                    selections.push(Selection::Field(name, children));
+                }
+                t => return Err(format!("Unexpected {:?} in selection set", t)),
+            }
+        }
+        self.next_token()?;
+        Ok(selections)
+    }
+}
+
+/// The depth and complexity of a query, with fragments expanded.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub(crate) struct QueryCost {
+    /// The maximum nesting of fields: `{ user { groups { id } } }` has a depth of 3.
+    pub depth: usize,
+    /// The number of fields the query resolves, counting the selections of a list field once
+    /// per item (`LIST_SIZE_ESTIMATE`).
+    pub complexity: usize,
+}
+
+struct CostCalculator<'a> {
+    fragments: &'a HashMap<String, Vec<Selection>>,
+    cache: HashMap<&'a str, QueryCost>,
+    in_progress: HashSet<&'a str>,
+}
+
+impl<'a> CostCalculator<'a> {
+    fn selections_cost(&mut self, selections: &'a [Selection]) -> Result<QueryCost, String> {
+        let mut cost = QueryCost::default();
+        for selection in selections {
+            let selection_cost = match selection {
+                Selection::Field(name, children) => {
+                    let children_cost = self.selections_cost(children)?;
+                    let fan_out = if LIST_FIELDS.contains(&name.as_str()) {
+                        LIST_SIZE_ESTIMATE
+                    } else {
+                        1
+                    };
+                    QueryCost {
+                        depth: children_cost.depth + 1,
+                        complexity: children_cost
+                            .complexity
+                            .saturating_mul(fan_out)
+                            .saturating_add(1),
+                    }
+                }
+                Selection::InlineFragment(children) => self.selections_cost(children)?,
+                Selection::FragmentSpread(name) => self.fragment_cost(name)?,
+            };
+            cost.depth = cost.depth.max(selection_cost.depth);
+            cost.complexity = cost.complexity.saturating_add(selection_cost.complexity);
+        }
+        Ok(cost)
+    }
+
+    /// Fragment costs are memoized, so that nested spreads can't blow up the analysis.
+    fn fragment_cost(&mut self, name: &'a str) -> Result<QueryCost, String> {
+        if let Some(cost) = self.cache.get(name) {
+            return Ok(*cost);
+        }
+        let fragments = self.fragments;
+        let selections = fragments
+            .get(name)
+            .ok_or_else(|| format!(r#"Unknown fragment "{}""#, name))?;
+        if !self.in_progress.insert(name) {
+            return Err(format!(r#"Fragment "{}" spreads itself"#, name));
+        }
+        let cost = self.selections_cost(selections)?;
+        self.in_progress.remove(name);
+        self.cache.insert(name, cost);
+        Ok(cost)
+    }
+}
+
+/// Computes the cost of the most expensive operation in the query.
+pub(crate) fn get_query_cost(query: &str) -> Result<QueryCost, String> {
+    let document = Parser {
+        tokens: tokenize(query),
+        position: 0,
+    }
+    .parse_document()?;
+    let mut calculator = CostCalculator {
+        fragments: &document.fragments,
+        cache: HashMap::new(),
+        in_progress: HashSet::new(),
+    };
+    let mut cost = QueryCost::default();
+    for operation in &document.operations {
+        let operation_cost = calculator.selections_cost(operation)?;
+        cost.depth = cost.depth.max(operation_cost.depth);
+        cost.complexity = cost.complexity.max(operation_cost.complexity);
+    }
+    Ok(cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize(
+                r#"query { user(userId: "bob # not a comment") { ...F } } # comment
+                   fragment F on User { id, groups(first: -1.5e3) { displayName } }"#
+            ),
+            vec![
+                Token::Name("query".to_string()),
+                Token::Punctuator('{'),
+                Token::Name("user".to_string()),
+                Token::Punctuator('('),
+                Token::Name("userId".to_string()),
+                Token::Punctuator(':'),
+                Token::Value,
+                Token::Punctuator(')'),
+                Token::Punctuator('{'),
+                Token::Spread,
+                Token::Name("F".to_string()),
+                Token::Punctuator('}'),
+                Token::Punctuator('}'),
+                Token::Name("fragment".to_string()),
+                Token::Name("F".to_string()),
+                Token::Name("on".to_string()),
+                Token::Name("User".to_string()),
+                Token::Punctuator('{'),
+                Token::Name("id".to_string()),
+                Token::Name("groups".to_string()),
+                Token::Punctuator('('),
+                Token::Name("first".to_string()),
+                Token::Punctuator(':'),
+                Token::Value,
+                Token::Punctuator(')'),
+                Token::Punctuator('{'),
+                Token::Name("displayName".to_string()),
+                Token::Punctuator('}'),
+                Token::Punctuator('}'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_uses_introspection() {
+        assert!(uses_introspection("{ __schema { types { name } } }"));
+        assert!(uses_introspection(
+            r#"query { __type(name: "User") { fields { name } } }"#
+        ));
+        assert!(!uses_introspection(
+            "{ user(userId: \"bob\") { __typename id } }"
+        ));
+        assert!(!uses_introspection(
+            "{ user(userId: \"__schema\") { id } } # __schema"
+        ));
+        assert!(!uses_introspection(
+            "{ user(userId: \"\"\"a \\\"\"\" __schema\"\"\") { id } }"
+        ));
+    }
+
+    #[test]
+    fn test_get_query_cost() {
+        let cost = |query| get_query_cost(query).unwrap();
+        assert_eq!(
+            cost("{ users { id } }"),
+            QueryCost {
+                depth: 2,
+                complexity: 11
+            }
+        );
+        assert_eq!(
+            cost(
+                r#"query GetUser($id: String!, $f: RequestFilter = {eq: {field: "a", value: "}"}}) {
+                     bob: user(userId: $id) @include(if: true) {
+                       id
+                       groups { ...GroupFields }
+                       ... on User { email }
+                     }
+                   }
+                   fragment GroupFields on Group { id users { id groups { id } } }"#
+            ),
+            QueryCost {
+                depth: 5,
+                complexity: 1224
+            }
+        );
+        // Fragments are expanded every time they are used.
+        assert_eq!(
+            cost(
+                "{ a: user(userId: \"a\") { ...F } b: user(userId: \"b\") { ...F } }
+                 fragment F on User { id groups { id } }"
+            ),
+            QueryCost {
+                depth: 3,
+                complexity: 26
+            }
+        );
+        // Only the most expensive operation counts.
+        assert_eq!(
+            cost("query A { users { id } } query B { groups { id users { id } } }"),
+            QueryCost {
+                depth: 3,
+                complexity: 121
+            }
+        );
+        // Aliases don't hide the list fields.
+        assert_eq!(
+            cost("{ a: users { b: groups { c: users { id } } } }"),
+            QueryCost {
+                depth: 4,
+                complexity: 1111
+            }
+        );
+    }
+
+    #[test]
+    fn test_get_query_cost_exponential_fragments() {
+        let mut query = "{ ...F0 } fragment F20 on User { id }".to_string();
+        for i in 0..20 {
+            query.push_str(&format!(
+                " fragment F{} on User {{ a: user {{ ...F{} }} b: user {{ ...F{} }} }}",
+                i,
+                i + 1,
+                i + 1
+            ));
+        }
+        assert_eq!(
+            get_query_cost(&query).unwrap(),
+            QueryCost {
+                depth: 21,
+                complexity: 3 * (1 << 20) - 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_get_query_cost_errors() {
+        assert_eq!(
+            get_query_cost("{ ...F } fragment F on User { ...F }").unwrap_err(),
+            r#"Fragment "F" spreads itself"#
+        );
+        assert_eq!(
+            get_query_cost("{ ...G }").unwrap_err(),
+            r#"Unknown fragment "G""#
+        );
+        assert_eq!(
+            get_query_cost("{ users { id }").unwrap_err(),
+            "Unexpected end of query"
+        );
+    }
+}
@@ -25,9 +25,25 @@ pub enum JwtStorage {
 #[derive(Iden)]
 pub enum PasswordResetTokens {
     Table,
+    /// The hash of the token, the token itself is only sent to the user.
     Token,
     UserId,
     ExpiryDate,
+    /// The tokens can only be used once, they're kept until they expire.
+    Used,
+}
+
+/// Contains the long-lived API tokens of the users, e.g. for service accounts.
+#[derive(Iden)]
+pub enum UserTokens {
+    Table,
+    TokenId,
+    /// The hash of the token, the token itself is only returned when it's created.
+    TokenHash,
+    UserId,
+    /// A description of the token, chosen by the user.
+    Name,
+    CreationDate,
 }
 
 /// This needs to be initialized after the domain tables are.
@@ -124,6 +140,12 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
                     .date_time()
                     .not_null(),
             )
+            .col(
+                ColumnDef::new(PasswordResetTokens::Used)
+                    .boolean()
+                    .default(false)
+                    .not_null(),
+            )
             .foreign_key(
                 ForeignKey::create()
                     .name("PasswordResetTokensUserForeignKey")
@@ -137,5 +159,56 @@ pub async fn init_table(pool: &Pool) -> sqlx::Result<()> {
     .execute(pool)
     .await?;
 
+    sqlx::query(
+        &Table::create()
+            .table(UserTokens::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(UserTokens::TokenId)
+                    .integer()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(UserTokens::TokenHash)
+                    .string_len(255)
+                    .unique_key()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(UserTokens::UserId)
+                    .string_len(255)
+                    .not_null(),
+            )
+            .col(ColumnDef::new(UserTokens::Name).string_len(255).not_null())
+            .col(
+                ColumnDef::new(UserTokens::CreationDate)
+                    .date_time()
+                    .not_null(),
+            )
+            .foreign_key(
+                ForeignKey::create()
+                    .name("UserTokensUserForeignKey")
+                    .table(UserTokens::Table, Users::Table)
+                    .col(UserTokens::UserId, Users::UserId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_string(DbQueryBuilder {}),
+    )
+    .execute(pool)
+    .await?;
+
+    // Tables created by older versions don't have this column.
+    add_column_if_missing(
+        pool,
+        PasswordResetTokens::Table,
+        &mut ColumnDef::new(PasswordResetTokens::Used)
+            .boolean()
+            .default(false)
+            .not_null(),
+    )
+    .await?;
+
     Ok(())
 }
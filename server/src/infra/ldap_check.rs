@@ -0,0 +1,282 @@
+use crate::infra::{cli::TestLdapOpts, configuration::Configuration};
+use anyhow::{Context, Result};
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Connection and credentials used to check a running server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdapCheckOptions {
+    pub url: String,
+    pub starttls: bool,
+    pub no_tls_verify: bool,
+    pub bind_dn: String,
+    pub bind_password: String,
+    pub search_base: String,
+}
+
+impl LdapCheckOptions {
+    /// Binds as the admin from the configuration, and searches its users.
+    pub fn from_config(url: String, config: &Configuration) -> Self {
+        Self {
+            url,
+            starttls: false,
+            no_tls_verify: false,
+            bind_dn: format!(
+                "uid={},ou=people,{}",
+                config.ldap_user_dn, config.ldap_base_dn
+            ),
+            bind_password: config.ldap_user_pass.unsecure().to_string(),
+            search_base: format!("ou=people,{}", config.ldap_base_dn),
+        }
+    }
+
+    /// Fills the options that were not given on the command line from the configuration.
+    pub fn new(opts: &TestLdapOpts, config: &Configuration) -> Self {
+        let url = if opts.ldaps {
+            format!("ldaps://{}:{}", opts.host, config.ldaps_options.port)
+        } else {
+            format!("ldap://{}:{}", opts.host, config.ldap_port)
+        };
+        let defaults = Self::from_config(url, config);
+        Self {
+            starttls: opts.starttls,
+            no_tls_verify: opts.no_tls_verify,
+            bind_dn: opts.bind_dn.clone().unwrap_or(defaults.bind_dn),
+            bind_password: opts.bind_pw.clone().unwrap_or(defaults.bind_password),
+            search_base: opts.search_base.clone().unwrap_or(defaults.search_base),
+            ..defaults
+        }
+    }
+}
+
+/// Outcome of one step of the check: a description of the result, or the error.
+pub struct CheckStep {
+    pub name: String,
+    pub outcome: Result<String>,
+}
+
+/// A `CheckStep`, for the JSON output.
+#[derive(Serialize)]
+pub struct CheckStepReport<'a> {
+    pub name: &'a str,
+    pub passed: bool,
+    pub details: String,
+}
+
+impl CheckStep {
+    pub fn report(&self) -> CheckStepReport<'_> {
+        CheckStepReport {
+            name: &self.name,
+            passed: self.outcome.is_ok(),
+            details: match &self.outcome {
+                Ok(details) => details.clone(),
+                Err(e) => format!("{:#}", e),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for CheckStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            Ok(details) => write!(f, "[PASS] {}: {}", self.name, details),
+            Err(e) => write!(f, "[FAIL] {}: {:#}", self.name, e),
+        }
+    }
+}
+
+pub(crate) async fn connect(options: &LdapCheckOptions) -> Result<Ldap> {
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(Duration::from_secs(10))
+        .set_starttls(options.starttls)
+        .set_no_tls_verify(options.no_tls_verify);
+    let (connection, ldap) = LdapConnAsync::with_settings(settings, &options.url).await?;
+    ldap3::drive!(connection);
+    Ok(ldap)
+}
+
+pub(crate) async fn bind(ldap: &mut Ldap, options: &LdapCheckOptions) -> Result<String> {
+    ldap.simple_bind(&options.bind_dn, &options.bind_password)
+        .await?
+        .success()?;
+    Ok(format!("bound as {}", options.bind_dn))
+}
+
+async fn search_root_dse(ldap: &mut Ldap) -> Result<String> {
+    let (entries, _) = ldap
+        .search("", Scope::Base, "(objectClass=*)", vec!["*"])
+        .await?
+        .success()?;
+    let entry = entries
+        .into_iter()
+        .next()
+        .map(SearchEntry::construct)
+        .context("no root DSE returned")?;
+    let naming_contexts = entry
+        .attrs
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("namingContexts"))
+        .map(|(_, v)| v.join(", "))
+        .unwrap_or_default();
+    Ok(format!("naming contexts: {}", naming_contexts))
+}
+
+async fn search_users(ldap: &mut Ldap, options: &LdapCheckOptions) -> Result<String> {
+    let (entries, _) = ldap
+        .search(
+            &options.search_base,
+            Scope::Subtree,
+            "(objectClass=person)",
+            vec!["uid"],
+        )
+        .await?
+        .success()?;
+    Ok(format!(
+        "found {} users under {}",
+        entries.len(),
+        options.search_base
+    ))
+}
+
+/// Adds the connection step, and returns the connection if it succeeded.
+async fn connect_step(options: &LdapCheckOptions, steps: &mut Vec<CheckStep>) -> Option<Ldap> {
+    let connect_step = if options.starttls {
+        format!("Connect to {} with StartTLS", options.url)
+    } else {
+        format!("Connect to {}", options.url)
+    };
+    match connect(options).await {
+        Ok(ldap) => {
+            steps.push(CheckStep {
+                name: connect_step,
+                outcome: Ok("connected".to_string()),
+            });
+            Some(ldap)
+        }
+        Err(e) => {
+            steps.push(CheckStep {
+                name: connect_step,
+                outcome: Err(e),
+            });
+            None
+        }
+    }
+}
+
+/// Runs the checks in order, stopping at the first failure since the following steps depend on
+/// it.
+pub async fn run_checks(options: &LdapCheckOptions) -> Vec<CheckStep> {
+    let mut steps = Vec::new();
+    let mut ldap = match connect_step(options, &mut steps).await {
+        Some(ldap) => ldap,
+        None => return steps,
+    };
+    let outcome = bind(&mut ldap, options).await;
+    let failed = outcome.is_err();
+    steps.push(CheckStep {
+        name: "Bind".to_string(),
+        outcome,
+    });
+    if failed {
+        return steps;
+    }
+    steps.push(CheckStep {
+        name: "Search the root DSE".to_string(),
+        outcome: search_root_dse(&mut ldap).await,
+    });
+    steps.push(CheckStep {
+        name: "Search the users".to_string(),
+        outcome: search_users(&mut ldap, options).await,
+    });
+    let _ = ldap.unbind().await;
+    steps
+}
+
+/// Same as `run_checks` without the credentials: only searches the root DSE, which doesn't need a
+/// bind.
+pub async fn run_anonymous_checks(options: &LdapCheckOptions) -> Vec<CheckStep> {
+    let mut steps = Vec::new();
+    let mut ldap = match connect_step(options, &mut steps).await {
+        Some(ldap) => ldap,
+        None => return steps,
+    };
+    steps.push(CheckStep {
+        name: "Search the root DSE".to_string(),
+        outcome: search_root_dse(&mut ldap).await,
+    });
+    let _ = ldap.unbind().await;
+    steps
+}
+
+/// Whether the server rejected the credentials of the bind, as opposed to failing to answer.
+pub fn is_invalid_credentials(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<ldap3::LdapError>(),
+        Some(ldap3::LdapError::LdapResult { result }) if result.rc == 49
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::{
+        cli::{GeneralConfigOpts, OutputFormat, OutputOpts},
+        configuration::{ConfigurationBuilder, LdapsOptions},
+    };
+
+    fn get_opts() -> TestLdapOpts {
+        TestLdapOpts {
+            general_config: GeneralConfigOpts {
+                config_file: String::new(),
+                verbose: false,
+                quiet: false,
+            },
+            host: "localhost".to_string(),
+            bind_dn: None,
+            bind_pw: None,
+            search_base: None,
+            ldaps: false,
+            starttls: false,
+            no_tls_verify: false,
+            output_opts: OutputOpts {
+                output: OutputFormat::Text,
+            },
+        }
+    }
+
+    #[test]
+    fn test_options_from_config() {
+        let config = ConfigurationBuilder::default()
+            .ldap_port(3890)
+            .ldaps_options(LdapsOptions {
+                port: 6360,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            LdapCheckOptions::new(&get_opts(), &config),
+            LdapCheckOptions {
+                url: "ldap://localhost:3890".to_string(),
+                starttls: false,
+                no_tls_verify: false,
+                bind_dn: "uid=admin,ou=people,dc=example,dc=com".to_string(),
+                bind_password: "password".to_string(),
+                search_base: "ou=people,dc=example,dc=com".to_string(),
+            }
+        );
+        let options = LdapCheckOptions::new(
+            &TestLdapOpts {
+                ldaps: true,
+                bind_dn: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                bind_pw: Some("bob_pass".to_string()),
+                ..get_opts()
+            },
+            &config,
+        );
+        assert_eq!(options.url, "ldaps://localhost:6360");
+        assert_eq!(options.bind_dn, "uid=bob,ou=people,dc=example,dc=com");
+        assert_eq!(options.bind_password, "bob_pass");
+    }
+}
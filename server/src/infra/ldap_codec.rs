@@ -0,0 +1,334 @@
+use bytes::BytesMut;
+use ldap3_server::{proto::LdapMsg, LdapCodec};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Messages bigger than this are rejected without being buffered.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// An LDAP message read from the client.
+#[derive(Debug, PartialEq)]
+pub enum LdapFrame {
+    Message(LdapMsg),
+    /// A message that could not be decoded. The message ID and the tag of the operation are
+    /// recovered when the start of the message is readable, to answer the client.
+    Malformed {
+        msgid: Option<i32>,
+        op_tag: Option<u8>,
+        error: String,
+    },
+}
+
+/// Wraps `LdapCodec` to tell malformed messages apart from I/O errors: the messages are framed
+/// first, so that a decoding error can be reported with the ID of the message.
+#[derive(Default)]
+pub struct LdapFrameCodec;
+
+/// Reads the BER tag and length at the start of the buffer, and returns the tag, the size of the
+/// header and the length of the contents. Returns None if more bytes are needed.
+fn read_header(buf: &[u8]) -> Result<Option<(u8, usize, usize)>, String> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let tag = buf[0];
+    if tag & 0x1f == 0x1f {
+        return Err("Unsupported multi-byte BER tag".to_string());
+    }
+    let length = buf[1];
+    if length < 0x80 {
+        return Ok(Some((tag, 2, length as usize)));
+    }
+    let length_bytes = (length & 0x7f) as usize;
+    if length_bytes == 0 {
+        return Err("Indefinite BER lengths are not allowed".to_string());
+    }
+    if length_bytes > 4 {
+        return Err("BER length too large".to_string());
+    }
+    if buf.len() < 2 + length_bytes {
+        return Ok(None);
+    }
+    let length = buf[2..2 + length_bytes]
+        .iter()
+        .fold(0usize, |acc, b| acc << 8 | *b as usize);
+    Ok(Some((tag, 2 + length_bytes, length)))
+}
+
+/// Reads the message ID and the tag of the operation from the contents of an LDAPMessage.
+fn read_message_id_and_op_tag(contents: &[u8]) -> (Option<i32>, Option<u8>) {
+    let (tag, header_len, length) = match read_header(contents) {
+        Ok(Some(header)) => header,
+        _ => return (None, None),
+    };
+    // The message ID is an INTEGER between 0 and 2^31 - 1 (RFC 4511 section 4.1.1.1).
+    if tag != 0x02 || !(1..=4).contains(&length) || contents.len() < header_len + length {
+        return (None, None);
+    }
+    let value = &contents[header_len..header_len + length];
+    let msgid = value
+        .iter()
+        .fold(if value[0] & 0x80 != 0 { -1i64 } else { 0 }, |acc, b| {
+            acc << 8 | *b as i64
+        });
+    let msgid = match i32::try_from(msgid) {
+        Ok(msgid) if msgid >= 0 => msgid,
+        _ => return (None, None),
+    };
+    (Some(msgid), contents.get(header_len + length).copied())
+}
+
+/// Reports a message that can't be framed. The rest of the stream can't be framed either, so the
+/// buffer is dropped.
+fn malformed(
+    buf: &mut BytesMut,
+    (msgid, op_tag): (Option<i32>, Option<u8>),
+    error: String,
+) -> Result<Option<LdapFrame>, std::io::Error> {
+    buf.clear();
+    Ok(Some(LdapFrame::Malformed {
+        msgid,
+        op_tag,
+        error,
+    }))
+}
+
+impl Decoder for LdapFrameCodec {
+    type Item = LdapFrame;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<LdapFrame>, std::io::Error> {
+        let (tag, header_len, length) = match read_header(buf) {
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(None),
+            Err(e) => return malformed(buf, (None, None), e),
+        };
+        if tag != 0x30 {
+            return malformed(
+                buf,
+                (None, None),
+                format!("Expected an LDAPMessage SEQUENCE, got the tag {:#04x}", tag),
+            );
+        }
+        if length > MAX_MESSAGE_SIZE {
+            let message_id_and_op_tag = read_message_id_and_op_tag(&buf[header_len..]);
+            return malformed(
+                buf,
+                message_id_and_op_tag,
+                format!(
+                    "Message of {} bytes over the limit of {} bytes",
+                    length, MAX_MESSAGE_SIZE
+                ),
+            );
+        }
+        if buf.len() < header_len + length {
+            buf.reserve(header_len + length - buf.len());
+            return Ok(None);
+        }
+        let mut frame = buf.split_to(header_len + length);
+        let (msgid, op_tag) = read_message_id_and_op_tag(&frame[header_len..]);
+        let error = match LdapCodec.decode(&mut frame) {
+            Ok(Some(msg)) => return Ok(Some(LdapFrame::Message(msg))),
+            Ok(None) => "Truncated message".to_string(),
+            Err(e) => e.to_string(),
+        };
+        Ok(Some(LdapFrame::Malformed {
+            msgid,
+            op_tag,
+            error,
+        }))
+    }
+}
+
+/// The result codes of the cancel operation (RFC 3909), that `LdapResultCode` doesn't have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CancelResultCode {
+    Canceled = 118,
+    NoSuchOperation = 119,
+    TooLate = 120,
+    CannotCancel = 121,
+}
+
+/// A response sent with one of the cancel result codes, in place of the code of its result.
+#[derive(Debug, PartialEq)]
+pub struct LdapResponseWithCode {
+    pub msg: LdapMsg,
+    pub code: CancelResultCode,
+}
+
+/// Encodes the responses with `LdapCodec`, including the ones with a cancel result code.
+#[derive(Default)]
+pub struct LdapResponseCodec;
+
+impl Encoder<LdapMsg> for LdapResponseCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: LdapMsg, buf: &mut BytesMut) -> Result<(), std::io::Error> {
+        LdapCodec.encode(msg, buf)
+    }
+}
+
+impl Encoder<LdapResponseWithCode> for LdapResponseCodec {
+    type Error = std::io::Error;
+
+    fn encode(
+        &mut self,
+        response: LdapResponseWithCode,
+        buf: &mut BytesMut,
+    ) -> Result<(), std::io::Error> {
+        let start = buf.len();
+        LdapCodec.encode(response.msg, buf)?;
+        let position = find_result_code(&buf[start..]).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The response has no result code",
+            )
+        })?;
+        buf[start + position] = response.code as u8;
+        Ok(())
+    }
+}
+
+/// The position of the value of the resultCode in an encoded LDAPMessage: it is the first
+/// element of the responses, an ENUMERATED of one byte for the codes below 128.
+fn find_result_code(message: &[u8]) -> Option<usize> {
+    let (_, message_header_len, _) = read_header(message).ok()??;
+    let contents = &message[message_header_len..];
+    let (_, id_header_len, id_length) = read_header(contents).ok()??;
+    let op_start = id_header_len + id_length;
+    let (_, op_header_len, _) = read_header(contents.get(op_start..)?).ok()??;
+    let code_start = op_start + op_header_len;
+    match contents.get(code_start..code_start + 3)? {
+        [0x0a, 0x01, _] => Some(message_header_len + code_start + 2),
+        _ => None,
+    }
+}
+
+/// Reads the ID of the operation to cancel from the value of a cancel request (RFC 3909):
+/// `SEQUENCE { cancelID MessageID }`.
+pub fn decode_cancel_request_value(value: &[u8]) -> Option<i32> {
+    match read_header(value) {
+        Ok(Some((0x30, header_len, length))) if value.len() == header_len + length => {
+            read_message_id_and_op_tag(&value[header_len..]).0
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_server::proto::{LdapExtendedResponse, LdapOp, LdapResult, LdapResultCode};
+
+    fn encode(msg: LdapMsg) -> BytesMut {
+        let mut buf = BytesMut::new();
+        LdapCodec.encode(msg, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_decode_message() {
+        let msg = LdapMsg {
+            msgid: 300,
+            op: LdapOp::UnbindRequest,
+            ctrl: vec![],
+        };
+        let mut buf = encode(msg.clone());
+        let mut partial = buf.split_to(3);
+        assert_eq!(LdapFrameCodec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(
+            LdapFrameCodec.decode(&mut partial).unwrap(),
+            Some(LdapFrame::Message(msg))
+        );
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        // A search request (tag 0x63) with message ID 5, with garbage instead of the contents.
+        let mut buf = BytesMut::from(&[0x30, 0x07, 0x02, 0x01, 0x05, 0x63, 0x02, 0xff, 0xff][..]);
+        match LdapFrameCodec.decode(&mut buf).unwrap() {
+            Some(LdapFrame::Malformed { msgid, op_tag, .. }) => {
+                assert_eq!(msgid, Some(5));
+                assert_eq!(op_tag, Some(0x63));
+            }
+            frame => panic!("Unexpected frame: {:?}", frame),
+        }
+        assert!(buf.is_empty());
+
+        // Not an LDAPMessage at all.
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        match LdapFrameCodec.decode(&mut buf).unwrap() {
+            Some(LdapFrame::Malformed { msgid, .. }) => assert_eq!(msgid, None),
+            frame => panic!("Unexpected frame: {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn test_decode_oversized() {
+        let mut buf = BytesMut::from(&[0x30, 0x84, 0x7f, 0xff, 0xff, 0xff, 0x02, 0x01, 0x07][..]);
+        match LdapFrameCodec.decode(&mut buf).unwrap() {
+            Some(LdapFrame::Malformed { msgid, error, .. }) => {
+                assert_eq!(msgid, Some(7));
+                assert!(error.contains("over the limit"), "{}", error);
+            }
+            frame => panic!("Unexpected frame: {:?}", frame),
+        }
+        let mut buf = BytesMut::from(&[0x30, 0x80][..]);
+        assert!(matches!(
+            LdapFrameCodec.decode(&mut buf).unwrap(),
+            Some(LdapFrame::Malformed { msgid: None, .. })
+        ));
+    }
+
+    #[test]
+    fn test_encode_with_cancel_result_code() {
+        let msg = LdapMsg {
+            msgid: 4,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Other,
+                    matcheddn: "".to_string(),
+                    message: "too late".to_string(),
+                    referral: vec![],
+                },
+                name: None,
+                value: None,
+            }),
+            ctrl: vec![],
+        };
+        let mut buf = BytesMut::new();
+        LdapResponseCodec
+            .encode(
+                LdapResponseWithCode {
+                    msg: msg.clone(),
+                    code: CancelResultCode::TooLate,
+                },
+                &mut buf,
+            )
+            .unwrap();
+        let mut expected = encode(msg);
+        // Only the result code changes, from "other" (80) to "tooLate" (120).
+        let position = expected.iter().position(|b| *b == 80).unwrap();
+        assert_eq!(expected[position - 2..position], [0x0a, 0x01]);
+        expected[position] = 120;
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_decode_cancel_request_value() {
+        assert_eq!(
+            decode_cancel_request_value(&[0x30, 0x03, 0x02, 0x01, 0x05]),
+            Some(5)
+        );
+        assert_eq!(
+            decode_cancel_request_value(&[0x30, 0x04, 0x02, 0x02, 0x01, 0x2c]),
+            Some(300)
+        );
+        assert_eq!(
+            decode_cancel_request_value(&[0x30, 0x03, 0x04, 0x01, 0x05]),
+            None
+        );
+        assert_eq!(decode_cancel_request_value(&[0x30, 0x05, 0x02, 0x01]), None);
+        assert_eq!(decode_cancel_request_value(&[]), None);
+    }
+}
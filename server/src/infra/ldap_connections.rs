@@ -0,0 +1,175 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::sync::Notify;
+
+/// The LDAP connections currently open, for the admins to inspect them and close the misbehaving
+/// ones. Clones share the same registry.
+#[derive(Clone, Default)]
+pub struct LdapConnectionRegistry(Arc<Mutex<Connections>>);
+
+#[derive(Default)]
+struct Connections {
+    next_id: u64,
+    entries: BTreeMap<u64, ConnectionEntry>,
+}
+
+struct ConnectionEntry {
+    peer_address: Option<SocketAddr>,
+    is_tls: bool,
+    connected_since: chrono::DateTime<chrono::Utc>,
+    start: Instant,
+    bound_dn: Option<String>,
+    operations: u64,
+    close: Arc<Notify>,
+}
+
+/// A snapshot of an open connection.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LdapConnectionInfo {
+    pub id: u64,
+    pub peer_address: Option<String>,
+    pub tls: bool,
+    /// The DN the connection is bound as, if any.
+    pub bound_dn: Option<String>,
+    pub connected_since: chrono::DateTime<chrono::Utc>,
+    pub age_secs: u64,
+    /// The number of LDAP operations received on the connection.
+    pub operations: u64,
+}
+
+impl LdapConnectionRegistry {
+    /// Adds a connection to the registry, until the returned handle is dropped.
+    pub fn register(&self, peer_address: Option<SocketAddr>, is_tls: bool) -> LdapConnectionHandle {
+        let close = Arc::new(Notify::new());
+        let mut connections = self.0.lock().unwrap();
+        connections.next_id += 1;
+        let id = connections.next_id;
+        connections.entries.insert(
+            id,
+            ConnectionEntry {
+                peer_address,
+                is_tls,
+                connected_since: chrono::Utc::now(),
+                start: Instant::now(),
+                bound_dn: None,
+                operations: 0,
+                close: close.clone(),
+            },
+        );
+        LdapConnectionHandle {
+            registry: self.clone(),
+            id,
+            close,
+        }
+    }
+
+    /// The open connections, oldest first.
+    pub fn list(&self) -> Vec<LdapConnectionInfo> {
+        self.0
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|(id, entry)| LdapConnectionInfo {
+                id: *id,
+                peer_address: entry.peer_address.map(|address| address.to_string()),
+                tls: entry.is_tls,
+                bound_dn: entry.bound_dn.clone(),
+                connected_since: entry.connected_since,
+                age_secs: entry.start.elapsed().as_secs(),
+                operations: entry.operations,
+            })
+            .collect()
+    }
+
+    /// Asks the session of the connection to close, after the operation in progress if any.
+    /// Returns false if there is no such connection.
+    pub fn close(&self, id: u64) -> bool {
+        match self.0.lock().unwrap().entries.get(&id) {
+            Some(entry) => {
+                // Stores a permit if the session isn't waiting yet.
+                entry.close.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The registration of a connection, removed from the registry on drop.
+pub struct LdapConnectionHandle {
+    registry: LdapConnectionRegistry,
+    id: u64,
+    close: Arc<Notify>,
+}
+
+impl LdapConnectionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Counts an operation, and updates the DN the connection is bound as.
+    pub fn record_operation(&self, bound_dn: Option<&str>) {
+        if let Some(entry) = self.registry.0.lock().unwrap().entries.get_mut(&self.id) {
+            entry.operations += 1;
+            entry.bound_dn = bound_dn.map(str::to_string);
+        }
+    }
+
+    /// Resolves when an admin closes the connection.
+    pub async fn closed(&self) {
+        self.close.notified().await
+    }
+}
+
+impl Drop for LdapConnectionHandle {
+    fn drop(&mut self) {
+        self.registry.0.lock().unwrap().entries.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registry() {
+        let registry = LdapConnectionRegistry::default();
+        let first = registry.register("127.0.0.1:1234".parse().ok(), false);
+        let second = registry.register(None, true);
+        first.record_operation(Some("uid=bob,ou=people,dc=example,dc=com"));
+        first.record_operation(Some("uid=bob,ou=people,dc=example,dc=com"));
+
+        let connections = registry.list();
+        assert_eq!(
+            connections
+                .iter()
+                .map(|c| (
+                    c.id,
+                    c.peer_address.as_deref(),
+                    c.bound_dn.is_some(),
+                    c.operations
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (first.id(), Some("127.0.0.1:1234"), true, 2),
+                (second.id(), None, false, 0)
+            ]
+        );
+
+        // The close request is kept until the session waits for it.
+        assert!(registry.close(second.id()));
+        tokio::time::timeout(std::time::Duration::from_secs(1), second.closed())
+            .await
+            .unwrap();
+        let second_id = second.id();
+        drop(second);
+        assert!(!registry.close(second_id));
+        assert_eq!(registry.list().len(), 1);
+    }
+}
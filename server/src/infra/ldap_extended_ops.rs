@@ -0,0 +1,178 @@
+use crate::{
+    domain::{
+        handler::{BackendHandler, LoginHandler, TransactionHandler},
+        opaque_handler::OpaqueHandler,
+    },
+    infra::{
+        ldap_codec::decode_cancel_request_value,
+        ldap_handler::{make_extended_response, LdapHandler},
+    },
+};
+use async_trait::async_trait;
+use ldap3_server::proto::{
+    LdapExtendedRequest, LdapExtendedResponse, LdapOp, LdapPasswordModifyRequest, LdapResult,
+    LdapResultCode,
+};
+use std::collections::BTreeMap;
+
+/// OID of the password modify extended operation (RFC 3062).
+pub const PASSWORD_MODIFY_OID: &str = "1.3.6.1.4.1.4203.1.11.1";
+/// OID of the "Who am I?" extended operation (RFC 4532).
+pub const WHO_AM_I_OID: &str = "1.3.6.1.4.1.4203.1.11.3";
+/// OID of the cancel extended operation (RFC 3909).
+pub const CANCEL_OID: &str = "1.3.6.1.1.8";
+
+/// Handler of an LDAP extended operation, registered for its OID in an
+/// `ExtendedOperationRegistry`.
+#[async_trait(?Send)]
+pub trait ExtendedOpHandler<Backend>: Send + Sync
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+{
+    async fn handle(
+        &self,
+        request: &LdapExtendedRequest,
+        session: &mut LdapHandler<Backend>,
+    ) -> Vec<LdapOp>;
+}
+
+/// The extended operations supported by the server, by OID.
+pub struct ExtendedOperationRegistry<Backend> {
+    handlers: BTreeMap<String, Box<dyn ExtendedOpHandler<Backend>>>,
+}
+
+impl<Backend> ExtendedOperationRegistry<Backend>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+{
+    /// A registry without any operation.
+    pub fn empty() -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Registers a handler for the OID, replacing any previous handler.
+    pub fn register_extended_op(
+        &mut self,
+        oid: &str,
+        handler: Box<dyn ExtendedOpHandler<Backend>>,
+    ) {
+        self.handlers.insert(oid.to_string(), handler);
+    }
+
+    pub fn get(&self, oid: &str) -> Option<&dyn ExtendedOpHandler<Backend>> {
+        self.handlers.get(oid).map(AsRef::as_ref)
+    }
+
+    /// The OIDs of the registered operations, advertised in the root DSE.
+    pub fn oids(&self) -> Vec<String> {
+        self.handlers.keys().cloned().collect()
+    }
+}
+
+impl<Backend> Default for ExtendedOperationRegistry<Backend>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+{
+    /// A registry with the built-in operations.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register_extended_op(PASSWORD_MODIFY_OID, Box::new(PasswordModifyOp));
+        registry.register_extended_op(WHO_AM_I_OID, Box::new(WhoAmIOp));
+        registry.register_extended_op(CANCEL_OID, Box::new(CancelOp));
+        registry
+    }
+}
+
+struct PasswordModifyOp;
+
+#[async_trait(?Send)]
+impl<Backend> ExtendedOpHandler<Backend> for PasswordModifyOp
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+{
+    async fn handle(
+        &self,
+        request: &LdapExtendedRequest,
+        session: &mut LdapHandler<Backend>,
+    ) -> Vec<LdapOp> {
+        match LdapPasswordModifyRequest::try_from(request) {
+            Ok(password_request) => session.do_password_modification(&password_request).await,
+            Err(e) => vec![make_extended_response(
+                LdapResultCode::ProtocolError,
+                format!("Invalid password modify request: {:?}", e),
+            )],
+        }
+    }
+}
+
+struct WhoAmIOp;
+
+#[async_trait(?Send)]
+impl<Backend> ExtendedOpHandler<Backend> for WhoAmIOp
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+{
+    async fn handle(
+        &self,
+        _request: &LdapExtendedRequest,
+        session: &mut LdapHandler<Backend>,
+    ) -> Vec<LdapOp> {
+        // The authorization identity, or an empty one for anonymous sessions.
+        let authz_id = session
+            .get_bound_dn()
+            .map(|dn| format!("dn:{}", dn))
+            .unwrap_or_default();
+        vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            name: None,
+            value: Some(authz_id.into()),
+        })]
+    }
+}
+
+/// The ID of the operation that a cancel request refers to.
+pub fn parse_cancel_request(request: &LdapExtendedRequest) -> Result<i32, String> {
+    request
+        .value
+        .as_deref()
+        .and_then(decode_cancel_request_value)
+        .ok_or_else(|| "Invalid cancel request: expected the ID of the operation".to_string())
+}
+
+/// The cancel requests refer to the other operations of the connection, they are answered by the
+/// connection (see `ldap_server`) rather than by the session. Registering the operation
+/// advertises it and enables it on the connections.
+struct CancelOp;
+
+#[async_trait(?Send)]
+impl<Backend> ExtendedOpHandler<Backend> for CancelOp
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+{
+    async fn handle(
+        &self,
+        request: &LdapExtendedRequest,
+        _session: &mut LdapHandler<Backend>,
+    ) -> Vec<LdapOp> {
+        vec![match parse_cancel_request(request) {
+            Err(e) => make_extended_response(LdapResultCode::ProtocolError, e),
+            Ok(_) => make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "Operations can only be canceled on an LDAP connection".to_string(),
+            ),
+        }]
+    }
+}
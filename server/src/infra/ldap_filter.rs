@@ -0,0 +1,228 @@
+use crate::{
+    domain::handler::UserRequestFilter,
+    infra::ldap_handler::{convert_user_filter, parse_distinguished_name},
+};
+use anyhow::{bail, Context, Result};
+use ldap3_server::proto::{LdapFilter, LdapSubstringFilter};
+
+/// Parses the string representation of an LDAP search filter (RFC 4515), e.g.
+/// `(&(objectClass=person)(mail=*@example.com))`. Only the operators that the LDAP handler
+/// supports are accepted: `&`, `|`, `!`, equality, presence and substrings.
+pub fn parse_ldap_filter(filter: &str) -> Result<LdapFilter> {
+    let filter = filter.trim();
+    // The outer parentheses are optional for a single item, e.g. `uid=bob`.
+    let (parsed, rest) = if filter.starts_with('(') {
+        parse_filter(filter)?
+    } else {
+        (parse_item(filter)?, "")
+    };
+    if !rest.is_empty() {
+        bail!(r#"Unexpected characters after the filter: "{}""#, rest);
+    }
+    Ok(parsed)
+}
+
+/// Parses the LDAP filter string into a filter on the users, with the DNs relative to
+/// `ldap_base_dn`.
+pub fn parse_user_filter(
+    filter: &str,
+    ldap_base_dn: &str,
+    ignore_dn_value_case: bool,
+) -> Result<UserRequestFilter> {
+    let base_dn = parse_distinguished_name(ldap_base_dn).context("while parsing the base DN")?;
+    convert_user_filter(
+        &parse_ldap_filter(filter)?,
+        &base_dn,
+        ldap_base_dn,
+        ignore_dn_value_case,
+    )
+}
+
+/// Parses a parenthesized filter, and returns it with the rest of the input.
+fn parse_filter(input: &str) -> Result<(LdapFilter, &str)> {
+    let input = input
+        .strip_prefix('(')
+        .with_context(|| format!(r#"Expected "(" at "{}""#, input))?;
+    let (filter, rest) = match input.chars().next() {
+        Some('&') => {
+            let (filters, rest) = parse_filter_list(&input[1..])?;
+            (LdapFilter::And(filters), rest)
+        }
+        Some('|') => {
+            let (filters, rest) = parse_filter_list(&input[1..])?;
+            (LdapFilter::Or(filters), rest)
+        }
+        Some('!') => {
+            let (filter, rest) = parse_filter(&input[1..])?;
+            (LdapFilter::Not(Box::new(filter)), rest)
+        }
+        _ => {
+            let end = input
+                .find(')')
+                .with_context(|| format!(r#"Missing ")" after "{}""#, input))?;
+            (parse_item(&input[..end])?, &input[end..])
+        }
+    };
+    let rest = rest
+        .strip_prefix(')')
+        .with_context(|| format!(r#"Expected ")" at "{}""#, rest))?;
+    Ok((filter, rest))
+}
+
+fn parse_filter_list(mut input: &str) -> Result<(Vec<LdapFilter>, &str)> {
+    let mut filters = Vec::new();
+    while input.starts_with('(') {
+        let (filter, rest) = parse_filter(input)?;
+        filters.push(filter);
+        input = rest;
+    }
+    Ok((filters, input))
+}
+
+/// Parses a comparison like `uid=bob`, `mail=*` or `cn=b*b`, without the parentheses.
+fn parse_item(item: &str) -> Result<LdapFilter> {
+    let (attribute, value) = item
+        .split_once('=')
+        .with_context(|| format!(r#"Missing "=" in "{}""#, item))?;
+    if attribute.is_empty() {
+        bail!(r#"Missing attribute in "{}""#, item);
+    }
+    if attribute.ends_with(&['<', '>', '~'][..]) {
+        bail!(r#"Unsupported comparison in "{}""#, item);
+    }
+    let attribute = attribute.to_string();
+    if value == "*" {
+        return Ok(LdapFilter::Present(attribute));
+    }
+    if !value.contains('*') {
+        return Ok(LdapFilter::Equality(attribute, unescape_value(value)?));
+    }
+    let mut parts = value.split('*');
+    let initial = parts.next().filter(|p| !p.is_empty());
+    let mut any = parts.map(unescape_value).collect::<Result<Vec<_>>>()?;
+    let final_ = any.pop().filter(|p| !p.is_empty());
+    Ok(LdapFilter::Substring(
+        attribute,
+        LdapSubstringFilter {
+            initial: initial.map(unescape_value).transpose()?,
+            any: any.into_iter().filter(|p| !p.is_empty()).collect(),
+            final_,
+        },
+    ))
+}
+
+/// Replaces the `\XX` hexadecimal escapes of the value.
+fn unescape_value(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'\\' {
+            let escape = [
+                input.next().unwrap_or_default(),
+                input.next().unwrap_or_default(),
+            ];
+            let escape = std::str::from_utf8(&escape)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .with_context(|| format!(r#"Invalid escape sequence in "{}""#, value))?;
+            bytes.push(escape);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).with_context(|| format!(r#"Invalid UTF-8 in "{}""#, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn equality(attribute: &str, value: &str) -> LdapFilter {
+        LdapFilter::Equality(attribute.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_parse_ldap_filter() {
+        assert_eq!(
+            parse_ldap_filter("(uid=bob)").unwrap(),
+            equality("uid", "bob")
+        );
+        assert_eq!(
+            parse_ldap_filter("uid=bob").unwrap(),
+            equality("uid", "bob")
+        );
+        assert_eq!(
+            parse_ldap_filter("(&(objectClass=person)(|(mail=*)(!(cn=b\\2ab))))").unwrap(),
+            LdapFilter::And(vec![
+                equality("objectClass", "person"),
+                LdapFilter::Or(vec![
+                    LdapFilter::Present("mail".to_string()),
+                    LdapFilter::Not(Box::new(equality("cn", "b*b"))),
+                ]),
+            ])
+        );
+        assert_eq!(
+            parse_ldap_filter("(member:1.2.840.113556.1.4.1941:=cn=admins,ou=groups)").unwrap(),
+            equality("member:1.2.840.113556.1.4.1941:", "cn=admins,ou=groups")
+        );
+    }
+
+    #[test]
+    fn test_parse_ldap_filter_substrings() {
+        assert_eq!(
+            parse_ldap_filter("(cn=a*b*c*d)").unwrap(),
+            LdapFilter::Substring(
+                "cn".to_string(),
+                LdapSubstringFilter {
+                    initial: Some("a".to_string()),
+                    any: vec!["b".to_string(), "c".to_string()],
+                    final_: Some("d".to_string()),
+                }
+            )
+        );
+        assert_eq!(
+            parse_ldap_filter("(mail=*@example.com)").unwrap(),
+            LdapFilter::Substring(
+                "mail".to_string(),
+                LdapSubstringFilter {
+                    initial: None,
+                    any: vec![],
+                    final_: Some("@example.com".to_string()),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_ldap_filter_errors() {
+        for filter in [
+            "",
+            "(uid=bob",
+            "(uid=bob))",
+            "(&(uid=bob)",
+            "(uidbob)",
+            "(=bob)",
+            "(uidNumber>=10)",
+            "(cn=\\zz)",
+        ] {
+            assert!(parse_ldap_filter(filter).is_err(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn test_parse_user_filter() {
+        assert_eq!(
+            parse_user_filter(
+                "(|(memberOf=cn=Admins,ou=groups,dc=example,dc=com)(uid=Bob))",
+                "dc=example,dc=com",
+                true
+            )
+            .unwrap(),
+            UserRequestFilter::Or(vec![
+                UserRequestFilter::MemberOf("Admins".to_string()),
+                UserRequestFilter::UserId(crate::domain::handler::UserId::new("bob")),
+            ])
+        );
+        assert!(parse_user_filter("(memberOf=cn=admins)", "dc=example,dc=com", true).is_err());
+    }
+}
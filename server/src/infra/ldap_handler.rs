@@ -1,21 +1,161 @@
-use crate::domain::{
-    handler::{
-        BackendHandler, BindRequest, Group, GroupRequestFilter, LoginHandler, User, UserId,
-        UserRequestFilter,
+use crate::{
+    domain::{
+        error::{DomainError, TimeoutKind},
+        handler::{
+            BackendHandler, BackendTransaction, BindRequest, CreateUserRequest, Group, GroupId,
+            GroupRequestFilter, LoginHandler, SubStringFilter, TransactionHandler,
+            UpdateGroupRequest, User, UserId, UserRequestFilter,
+        },
+        opaque_handler::OpaqueHandler,
+        sql_opaque_handler::register_password,
+    },
+    infra::{
+        attribute_visibility::AttributeVisibilityPolicy,
+        configuration::Configuration,
+        ldap_extended_ops::ExtendedOperationRegistry,
+        ldap_search_cache::LdapSearchCache,
+        ldap_upstream::{upstream_bind, UpstreamLdapConfig},
+        mail::Mailer,
+        maintenance::MaintenanceMode,
+        metrics::{
+            LDAP_BINDS, LDAP_BIND_FAILURES, LDAP_FILTER_CACHE_HITS, LDAP_FILTER_CACHE_MISSES,
+            LDAP_MODIFICATIONS, LDAP_SEARCHES,
+        },
+        password_change::on_password_changed,
     },
-    opaque_handler::OpaqueHandler,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::{FutureExt, LocalBoxFuture};
 use ldap3_server::proto::{
-    LdapBindCred, LdapBindRequest, LdapBindResponse, LdapExtendedRequest, LdapExtendedResponse,
-    LdapFilter, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest, LdapResult,
-    LdapResultCode, LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope,
+    LdapAddRequest, LdapBindCred, LdapBindRequest, LdapBindResponse, LdapExtendedRequest,
+    LdapExtendedResponse, LdapFilter, LdapModify, LdapModifyDNRequest, LdapModifyRequest,
+    LdapModifyType, LdapOp, LdapPartialAttribute, LdapPasswordModifyRequest, LdapResult,
+    LdapResultCode, LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope, LdapSubstringFilter,
+};
+use log::{debug, info, warn};
+use lru::LruCache;
+use secstr::SecUtf8;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use log::{debug, warn};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct LdapDn(String);
 
+/// OID of the Active Directory "LDAP_MATCHING_RULE_IN_CHAIN" matching rule, used to check
+/// transitive group membership, e.g.
+/// `(member:1.2.840.113556.1.4.1941:=uid=bob,ou=people,dc=example,dc=com)`.
+const LDAP_MATCHING_RULE_IN_CHAIN: &str = "1.2.840.113556.1.4.1941";
+
+/// Splits an attribute description like `member:1.2.840.113556.1.4.1941:` into the attribute
+/// and the matching rule, if any.
+fn split_matching_rule(field: &str) -> (&str, Option<&str>) {
+    let mut parts = field.split(':').filter(|p| !p.is_empty());
+    let attribute = parts.next().unwrap_or(field);
+    (
+        attribute,
+        parts.filter(|p| !p.eq_ignore_ascii_case("dn")).last(),
+    )
+}
+
+/// Escapes an attribute value to be used in a DN, as per RFC 4514 section 2.4. Non-ASCII
+/// characters are valid UTF-8 in a DN and are left as-is.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | ' ' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if i == last => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape_dn_value`, including the hex-encoded bytes like `\C3\A9`.
+fn unescape_dn_value(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut buffer = [0; 4];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                None => bail!(r#"Trailing escape character in DN value "{}""#, value),
+                Some(high) if high.is_ascii_hexdigit() => {
+                    let low = chars
+                        .next()
+                        .filter(|c| c.is_ascii_hexdigit())
+                        .ok_or_else(|| anyhow!(r#"Invalid hex escape in DN value "{}""#, value))?;
+                    bytes.push(u8::from_str_radix(&format!("{}{}", high, low), 16)?);
+                    continue;
+                }
+                Some(escaped) => escaped,
+            }
+        } else {
+            c
+        };
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    String::from_utf8(bytes).with_context(|| format!(r#"Invalid UTF-8 in DN value "{}""#, value))
+}
+
+/// Splits the string on the occurrences of the separator that are not escaped.
+fn split_unescaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Trims the whitespace around a DN element, except for an escaped trailing space.
+fn trim_unescaped(s: &str) -> &str {
+    let s = s.trim_start();
+    let trimmed = s.trim_end();
+    let trailing_backslashes = trimmed.chars().rev().take_while(|c| *c == '\\').count();
+    if trailing_backslashes % 2 == 1 && trimmed.len() < s.len() {
+        &s[..trimmed.len() + 1]
+    } else {
+        trimmed
+    }
+}
+
+fn make_user_dn(user_id: &str, base_dn_str: &str) -> String {
+    format!("uid={},ou=people,{}", escape_dn_value(user_id), base_dn_str)
+}
+
+fn make_group_dn(group_name: &str, base_dn_str: &str) -> String {
+    format!(
+        "cn={},ou=groups,{}",
+        escape_dn_value(group_name),
+        base_dn_str
+    )
+}
+
 fn make_dn_pair<I>(mut iter: I) -> Result<(String, String)>
 where
     I: Iterator<Item = String>,
@@ -37,23 +177,42 @@ where
     Ok(pair)
 }
 
-fn parse_distinguished_name(dn: &str) -> Result<Vec<(String, String)>> {
-    dn.split(',')
-        .map(|s| make_dn_pair(s.split('=').map(str::trim).map(String::from)))
+pub(crate) fn parse_distinguished_name(dn: &str) -> Result<Vec<(String, String)>> {
+    split_unescaped(dn, ',')
+        .into_iter()
+        .map(|s| {
+            let (attribute, value) = make_dn_pair(
+                split_unescaped(s, '=')
+                    .into_iter()
+                    .map(trim_unescaped)
+                    .map(String::from),
+            )?;
+            // Attribute types are case-insensitive (RFC 4512, section 2.5).
+            Ok((attribute.to_ascii_lowercase(), unescape_dn_value(&value)?))
+        })
         .collect()
 }
 
+/// Whether the DN values are the same, ignoring the case if `ignore_case`.
+fn dn_value_matches(value: &str, expected: &str, ignore_case: bool) -> bool {
+    value == expected || (ignore_case && value.to_lowercase() == expected.to_lowercase())
+}
+
 fn get_group_id_from_distinguished_name(
     dn: &str,
     base_tree: &[(String, String)],
     base_dn_str: &str,
+    ignore_value_case: bool,
 ) -> Result<String> {
     let parts = parse_distinguished_name(dn).context("while parsing a group ID")?;
-    if !is_subtree(&parts, base_tree) {
+    if !is_subtree(&parts, base_tree, ignore_value_case) {
         bail!("Not a subtree of the base tree");
     }
     if parts.len() == base_tree.len() + 2 {
-        if parts[1].0 != "ou" || parts[1].1 != "groups" || parts[0].0 != "cn" {
+        if parts[1].0 != "ou"
+            || !dn_value_matches(&parts[1].1, "groups", ignore_value_case)
+            || parts[0].0 != "cn"
+        {
             bail!(
                 r#"Unexpected group DN format. Got "{}", expected: "cn=groupname,ou=groups,{}""#,
                 dn,
@@ -70,18 +229,20 @@ fn get_group_id_from_distinguished_name(
     }
 }
 
+/// The user ID of the DN. The ID itself is always case-insensitive.
 fn get_user_id_from_distinguished_name(
     dn: &str,
     base_tree: &[(String, String)],
     base_dn_str: &str,
+    ignore_value_case: bool,
 ) -> Result<UserId> {
     let parts = parse_distinguished_name(dn).context("while parsing a user ID")?;
-    if !is_subtree(&parts, base_tree) {
+    if !is_subtree(&parts, base_tree, ignore_value_case) {
         bail!("Not a subtree of the base tree");
     }
     if parts.len() == base_tree.len() + 2 {
         if parts[1].0 != "ou"
-            || parts[1].1 != "people"
+            || !dn_value_matches(&parts[1].1, "people", ignore_value_case)
             || (parts[0].0 != "cn" && parts[0].0 != "uid")
         {
             bail!(
@@ -100,43 +261,234 @@ fn get_user_id_from_distinguished_name(
     }
 }
 
-fn get_user_attribute(user: &User, attribute: &str, dn: &str) -> Result<Option<Vec<String>>> {
+/// The `shadowAccount` attributes are synthesized from the password policy:
+///
+/// | Attribute          | Value                                                         |
+/// |--------------------|---------------------------------------------------------------|
+/// | `shadowLastChange` | Days since the epoch of the last password change, if known.   |
+/// | `shadowMin`        | 0: the password can be changed at any time.                   |
+/// | `shadowMax`        | `password_max_age_days`, absent if passwords don't expire.    |
+///
+/// The other `shadowAccount` attributes, such as `shadowWarning` or `shadowExpire`, have no
+/// equivalent in the password policy and are not supported.
+fn get_user_attribute(
+    user: &User,
+    attribute: &str,
+    dn: &str,
+    base_dn_str: &str,
+    password_max_age_days: u32,
+) -> Result<Option<Vec<String>>> {
     Ok(Some(match attribute.to_lowercase().as_str() {
         "objectclass" => vec![
             "inetOrgPerson".to_string(),
             "posixAccount".to_string(),
             "mailAccount".to_string(),
             "person".to_string(),
+            "inetLocalMailRecipient".to_string(),
+            "shadowAccount".to_string(),
         ],
         "dn" => vec![dn.to_string()],
         "uid" => vec![user.user_id.to_string()],
         "mail" => vec![user.email.clone()],
+        "maillocaladdress" => user.mail_aliases.clone(),
+        "mailforwardingaddress" => user.mail_forwarding.clone(),
+        "telephonenumber" => user.phone_numbers.clone(),
         "givenname" => vec![user.first_name.clone()],
         "sn" => vec![user.last_name.clone()],
         "cn" | "displayname" => vec![user.display_name.clone()],
         "createtimestamp" | "modifytimestamp" => vec![user.creation_date.to_rfc3339()],
+        "lastlogontimestamp" => match user.last_login_at {
+            Some(date) => vec![to_windows_file_time(date).to_string()],
+            None => return Ok(None),
+        },
+        "lastlogon" => match user.last_login_at {
+            Some(date) => vec![date.timestamp().to_string()],
+            None => return Ok(None),
+        },
+        "manager" => match &user.manager_user_id {
+            Some(manager) => vec![make_user_dn(manager.as_str(), base_dn_str)],
+            None => return Ok(None),
+        },
+        "shadowlastchange" => match user.password_modified_date {
+            Some(date) => vec![date.timestamp().div_euclid(24 * 60 * 60).to_string()],
+            None => return Ok(None),
+        },
+        "shadowmin" => vec!["0".to_string()],
+        "shadowmax" if password_max_age_days > 0 => vec![password_max_age_days.to_string()],
+        "shadowmax" => return Ok(None),
         "1.1" => return Ok(None),
         _ => bail!("Unsupported user attribute: {}", attribute),
     }))
 }
 
+/// Fills the first and last names of a user that has neither from the display name: the last
+/// word is the last name, and the words before it the first name. The stored names, when set,
+/// are always more accurate, e.g. for "Ludwig van Beethoven".
+fn derive_names_from_display_name(user: &mut User) {
+    if !user.first_name.is_empty() || !user.last_name.is_empty() {
+        return;
+    }
+    let words = user.display_name.split_whitespace().collect::<Vec<_>>();
+    if let Some((last_name, first_names)) = words.split_last() {
+        user.first_name = first_names.join(" ");
+        user.last_name = last_name.to_string();
+    }
+}
+
+/// The user attributes returned for "*", or when the request doesn't list any attribute.
+const ALL_USER_ATTRIBUTES: &[&str] = &[
+    "objectClass",
+    "uid",
+    "mail",
+    "mailLocalAddress",
+    "mailForwardingAddress",
+    "telephoneNumber",
+    "givenName",
+    "sn",
+    "cn",
+    "displayName",
+    "manager",
+    "shadowLastChange",
+    "shadowMin",
+    "shadowMax",
+];
+
+/// The operational attributes of the users, only returned when requested by name or with "+".
+const USER_OPERATIONAL_ATTRIBUTES: &[&str] = &[
+    "createTimestamp",
+    "modifyTimestamp",
+    "lastLogonTimestamp",
+    "lastLogon",
+];
+
+const ALL_GROUP_ATTRIBUTES: &[&str] = &["objectClass", "cn", "description", "uniqueMember"];
+
+/// The attributes to return for the requested ones (RFC 4511, section 4.5.1.8): an empty list
+/// and "*" mean all the user attributes, "+" all the operational ones, and "1.1" alone none.
+/// Without attributes in the request, a non-empty `defaults` replaces all the user attributes,
+/// keeping the ones listed in `all` or `operational`.
+fn expand_requested_attributes(
+    requested: &[String],
+    all: &[&str],
+    operational: &[&str],
+    defaults: &[String],
+) -> Vec<String> {
+    let supports = |attribute: &str| {
+        all.iter()
+            .chain(operational)
+            .any(|a| a.eq_ignore_ascii_case(attribute))
+    };
+    let expanded: Vec<String> = if requested.is_empty() {
+        if defaults.is_empty() {
+            all.iter().map(|a| a.to_string()).collect()
+        } else {
+            defaults.iter().filter(|a| supports(a)).cloned().collect()
+        }
+    } else {
+        requested
+            .iter()
+            .flat_map(|attribute| match attribute.as_str() {
+                "*" => all.iter().map(|a| a.to_string()).collect(),
+                "+" => operational.iter().map(|a| a.to_string()).collect(),
+                // Only meaningful alone, to return no attribute.
+                "1.1" => vec![],
+                _ => vec![attribute.clone()],
+            })
+            .collect()
+    };
+    let mut attributes: Vec<String> = Vec::with_capacity(expanded.len());
+    for attribute in expanded {
+        if !attributes
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(&attribute))
+        {
+            attributes.push(attribute);
+        }
+    }
+    attributes
+}
+
+/// The number of 100-nanosecond intervals since January 1, 1601 (UTC), as used by the
+/// Active Directory timestamps.
+fn to_windows_file_time(date: chrono::DateTime<chrono::Utc>) -> i64 {
+    const SECONDS_FROM_1601_TO_1970: i64 = 11_644_473_600;
+    (date.timestamp() + SECONDS_FROM_1601_TO_1970) * 10_000_000
+        + i64::from(date.timestamp_subsec_nanos() / 100)
+}
+
+/// Splits an attribute description (RFC 4512, section 2.5) such as "cn;lang-en" into the
+/// attribute name and the description to use in the response. Language tags are kept, and the
+/// value returned as-is for every language. The other options are dropped, including "binary"
+/// since none of the attributes has a binary syntax.
+fn parse_attribute_description(description: &str) -> (&str, String) {
+    let mut parts = description.split(';');
+    let name = parts.next().unwrap_or_default();
+    let atype = std::iter::once(name)
+        .chain(parts.filter(|option| option.to_lowercase().starts_with("lang-")))
+        .collect::<Vec<_>>()
+        .join(";");
+    (name, atype)
+}
+
+/// The attribute an alias stands for, or the attribute itself. The keys of `aliases` are
+/// lowercase.
+fn resolve_attribute_alias<'a>(aliases: &'a HashMap<String, String>, name: &'a str) -> &'a str {
+    aliases
+        .get(&name.to_lowercase())
+        .map(String::as_str)
+        .unwrap_or(name)
+}
+
+/// The user field of an LDAP attribute, as named in `attribute_visibility`.
+fn get_user_attribute_field(attribute: &str) -> Option<&'static str> {
+    Some(match attribute.to_lowercase().as_str() {
+        "mail" => "email",
+        "maillocaladdress" => "mail_aliases",
+        "mailforwardingaddress" => "mail_forwarding",
+        "telephonenumber" => "phone_numbers",
+        "givenname" => "first_name",
+        "sn" => "last_name",
+        "cn" | "displayname" => "display_name",
+        _ => return None,
+    })
+}
+
+/// Builds the entry of the user, leaving out the attributes for which `is_visible` returns false.
 fn make_ldap_search_user_result_entry(
     user: User,
     base_dn_str: &str,
     attributes: &[String],
+    aliases: &HashMap<String, String>,
+    password_max_age_days: u32,
+    is_visible: impl Fn(&str) -> bool,
 ) -> Result<LdapSearchResultEntry> {
-    let dn = format!("uid={},ou=people,{}", user.user_id.as_str(), base_dn_str);
+    let dn = make_user_dn(user.user_id.as_str(), base_dn_str);
     Ok(LdapSearchResultEntry {
         dn: dn.clone(),
         attributes: attributes
             .iter()
             .filter_map(|a| {
-                let values = match get_user_attribute(&user, a, &dn) {
+                let (name, atype) = parse_attribute_description(a);
+                let name = resolve_attribute_alias(aliases, name);
+                if !get_user_attribute_field(name).map_or(true, &is_visible) {
+                    return None;
+                }
+                let values = match get_user_attribute(
+                    &user,
+                    name,
+                    &dn,
+                    base_dn_str,
+                    password_max_age_days,
+                ) {
                     Err(e) => return Some(Err(e)),
                     Ok(v) => v,
                 }?;
+                // An attribute without values is left out of the entry (RFC 4511, section 4.1.7).
+                if values.is_empty() {
+                    return None;
+                }
                 Some(Ok(LdapPartialAttribute {
-                    atype: a.to_string(),
+                    atype,
                     vals: values,
                 }))
             })
@@ -152,16 +504,15 @@ fn get_group_attribute(
 ) -> Result<Option<Vec<String>>> {
     Ok(Some(match attribute.to_lowercase().as_str() {
         "objectclass" => vec!["groupOfUniqueNames".to_string()],
-        "dn" => vec![format!(
-            "cn={},ou=groups,{}",
-            group.display_name, base_dn_str
-        )],
+        "dn" => vec![make_group_dn(&group.display_name, base_dn_str)],
         "cn" | "uid" => vec![group.display_name.clone()],
+        // Only returned when set: the cn is the name to fall back to.
+        "description" => group.description.iter().cloned().collect(),
         "member" | "uniquemember" => group
             .users
             .iter()
             .filter(|u| user_filter.map(|f| *u == f).unwrap_or(true))
-            .map(|u| format!("uid={},ou=people,{}", u, base_dn_str))
+            .map(|u| make_user_dn(u.as_str(), base_dn_str))
             .collect(),
         "1.1" => return Ok(None),
         _ => bail!("Unsupported group attribute: {}", attribute),
@@ -172,19 +523,25 @@ fn make_ldap_search_group_result_entry(
     group: Group,
     base_dn_str: &str,
     attributes: &[String],
+    aliases: &HashMap<String, String>,
     user_filter: &Option<&UserId>,
 ) -> Result<LdapSearchResultEntry> {
     Ok(LdapSearchResultEntry {
-        dn: format!("cn={},ou=groups,{}", group.display_name, base_dn_str),
+        dn: make_group_dn(&group.display_name, base_dn_str),
         attributes: attributes
             .iter()
             .filter_map(|a| {
-                let values = match get_group_attribute(&group, base_dn_str, a, user_filter) {
+                let (name, atype) = parse_attribute_description(a);
+                let name = resolve_attribute_alias(aliases, name);
+                let values = match get_group_attribute(&group, base_dn_str, name, user_filter) {
                     Err(e) => return Some(Err(e)),
                     Ok(v) => v,
                 }?;
+                if values.is_empty() {
+                    return None;
+                }
                 Some(Ok(LdapPartialAttribute {
-                    atype: a.to_string(),
+                    atype,
                     vals: values,
                 }))
             })
@@ -192,13 +549,20 @@ fn make_ldap_search_group_result_entry(
     })
 }
 
-fn is_subtree(subtree: &[(String, String)], base_tree: &[(String, String)]) -> bool {
+fn is_subtree(
+    subtree: &[(String, String)],
+    base_tree: &[(String, String)],
+    ignore_value_case: bool,
+) -> bool {
     if subtree.len() < base_tree.len() {
         return false;
     }
     let size_diff = subtree.len() - base_tree.len();
     for i in 0..base_tree.len() {
-        if subtree[size_diff + i] != base_tree[i] {
+        let (attribute, value) = &subtree[size_diff + i];
+        if *attribute != base_tree[i].0
+            || !dn_value_matches(value, &base_tree[i].1, ignore_value_case)
+        {
             return false;
         }
     }
@@ -232,6 +596,43 @@ fn make_search_success() -> LdapOp {
     make_search_error(LdapResultCode::Success, "".to_string())
 }
 
+/// Keeps the first `size_limit` entries of the results, ending with `sizeLimitExceeded` if some
+/// were left out.
+fn apply_search_size_limit(results: Vec<LdapOp>, size_limit: Option<usize>) -> Vec<LdapOp> {
+    let size_limit = match size_limit {
+        Some(size_limit) => size_limit,
+        None => return results,
+    };
+    let is_entry = |op: &LdapOp| matches!(op, LdapOp::SearchResultEntry(_));
+    if results.iter().filter(|op| is_entry(op)).count() <= size_limit {
+        return results;
+    }
+    let mut results = results
+        .into_iter()
+        .filter(is_entry)
+        .take(size_limit)
+        .collect::<Vec<_>>();
+    results.push(make_search_error(
+        LdapResultCode::SizeLimitExceeded,
+        format!("The search returned more than {} entries", size_limit),
+    ));
+    results
+}
+
+/// Sorts the entries of the results by DN, ignoring the case, and keeps the other messages (the
+/// final `SearchResultDone`) at the end.
+fn sort_search_results(results: Vec<LdapOp>) -> Vec<LdapOp> {
+    let (mut entries, others): (Vec<_>, Vec<_>) = results
+        .into_iter()
+        .partition(|op| matches!(op, LdapOp::SearchResultEntry(_)));
+    entries.sort_by_cached_key(|op| match op {
+        LdapOp::SearchResultEntry(entry) => entry.dn.to_lowercase(),
+        _ => unreachable!(),
+    });
+    entries.extend(others);
+    entries
+}
+
 fn make_search_error(code: LdapResultCode, message: String) -> LdapOp {
     LdapOp::SearchResultDone(LdapResult {
         code,
@@ -241,7 +642,7 @@ fn make_search_error(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
-fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
+pub(crate) fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     LdapOp::ExtendedResponse(LdapExtendedResponse {
         res: LdapResult {
             code,
@@ -254,49 +655,525 @@ fn make_extended_response(code: LdapResultCode, message: String) -> LdapOp {
     })
 }
 
-fn root_dse_response(base_dn: &str) -> LdapOp {
+/// Unsolicited notification sent before the server closes a connection (rfc4511 4.4.1).
+pub fn make_notice_of_disconnection(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::ExtendedResponse(LdapExtendedResponse {
+        res: LdapResult {
+            code,
+            matcheddn: "".to_string(),
+            message,
+            referral: vec![],
+        },
+        name: Some("1.3.6.1.4.1.1466.20036".to_string()),
+        value: None,
+    })
+}
+
+/// The result code for an error returned by the backend.
+fn backend_error_code(error: &DomainError) -> LdapResultCode {
+    match error {
+        DomainError::Timeout(TimeoutKind::Operation) => LdapResultCode::TimeLimitExceeded,
+        DomainError::Timeout(TimeoutKind::Query) => LdapResultCode::OperationsError,
+        DomainError::ConstraintViolation(_) => LdapResultCode::ConstraintViolation,
+        _ => LdapResultCode::Other,
+    }
+}
+
+fn make_add_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::AddResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+/// The values of the attribute of an add request, matched case-insensitively.
+fn get_add_attribute_values<'a>(request: &'a LdapAddRequest, attribute: &str) -> &'a [String] {
+    request
+        .attributes
+        .iter()
+        .find(|a| a.atype.eq_ignore_ascii_case(attribute))
+        .map(|a| a.vals.as_slice())
+        .unwrap_or_default()
+}
+
+fn get_add_attribute_value(request: &LdapAddRequest, attribute: &str) -> Option<String> {
+    get_add_attribute_values(request, attribute)
+        .first()
+        .cloned()
+}
+
+fn make_del_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::DelResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_modify_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::ModifyResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_compare_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::CompareResult(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_modify_dn_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::ModifyDNResponse(LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message,
+        referral: vec![],
+    })
+}
+
+fn make_bind_response(code: LdapResultCode, message: String) -> LdapOp {
+    LdapOp::BindResponse(LdapBindResponse {
+        res: LdapResult {
+            code,
+            matcheddn: "".to_string(),
+            message,
+            referral: vec![],
+        },
+        saslcreds: None,
+    })
+}
+
+/// Builds an error response of the right type for the given request.
+pub fn make_error_response_for_op(op: &LdapOp, code: LdapResultCode, message: String) -> LdapOp {
+    match op {
+        LdapOp::BindRequest(_) => make_bind_response(code, message),
+        LdapOp::SearchRequest(_) => make_search_error(code, message),
+        LdapOp::AddRequest(_) => make_add_response(code, message),
+        LdapOp::DelRequest(_) => make_del_response(code, message),
+        LdapOp::ModifyRequest(_) => make_modify_response(code, message),
+        LdapOp::ModifyDNRequest(_) => make_modify_dn_response(code, message),
+        _ => make_extended_response(code, message),
+    }
+}
+
+/// The error response for a request that could not be decoded, from the BER tag of its protocolOp
+/// (RFC 4511 section 4.2 and following). Returns None for the requests without a response.
+pub fn make_error_response_for_request_tag(
+    tag: u8,
+    code: LdapResultCode,
+    message: String,
+) -> Option<LdapOp> {
+    // The tag number of the [APPLICATION n] tags.
+    Some(match tag & 0x1f {
+        0 => make_bind_response(code, message),
+        // Unbind and abandon requests.
+        2 | 16 => return None,
+        3 => make_search_error(code, message),
+        6 => make_modify_response(code, message),
+        8 => make_add_response(code, message),
+        10 => make_del_response(code, message),
+        12 => make_modify_dn_response(code, message),
+        14 => make_compare_response(code, message),
+        _ => make_extended_response(code, message),
+    })
+}
+
+/// The names of the operations that can be listed in `ldap_disabled_operations`.
+pub const LDAP_OPERATION_NAMES: &[&str] = &[
+    "bind", "unbind", "search", "modify", "add", "delete", "modifydn", "compare", "abandon",
+    "extended",
+];
+
+/// The name of the operation, as used in `ldap_disabled_operations`, one of
+/// `LDAP_OPERATION_NAMES`. The responses, which clients don't send, are "other".
+fn get_operation_name(op: &LdapOp) -> &'static str {
+    match op {
+        LdapOp::BindRequest(_) => "bind",
+        LdapOp::UnbindRequest => "unbind",
+        LdapOp::SearchRequest(_) => "search",
+        LdapOp::ModifyRequest(_) => "modify",
+        LdapOp::AddRequest(_) => "add",
+        LdapOp::DelRequest(_) => "delete",
+        LdapOp::ModifyDNRequest(_) => "modifydn",
+        LdapOp::CompareRequest(_) => "compare",
+        LdapOp::AbandonRequest(_) => "abandon",
+        LdapOp::ExtendedRequest(_) => "extended",
+        _ => "other",
+    }
+}
+
+fn root_dse_response(
+    base_dn: &str,
+    login_banner: Option<&str>,
+    server_id: Option<&str>,
+    supported_extensions: Vec<String>,
+    user_and_group_counts: Option<(u64, u64)>,
+) -> LdapOp {
+    let mut attributes = vec![
+        LdapPartialAttribute {
+            atype: "objectClass".to_string(),
+            vals: vec!["top".to_string()],
+        },
+        LdapPartialAttribute {
+            atype: "vendorName".to_string(),
+            vals: vec!["LLDAP".to_string()],
+        },
+        LdapPartialAttribute {
+            atype: "vendorVersion".to_string(),
+            vals: vec!["lldap_0.2.0".to_string()],
+        },
+        LdapPartialAttribute {
+            atype: "supportedLDAPVersion".to_string(),
+            vals: vec!["3".to_string()],
+        },
+        LdapPartialAttribute {
+            atype: "supportedExtension".to_string(),
+            vals: supported_extensions,
+        },
+        LdapPartialAttribute {
+            atype: "defaultnamingcontext".to_string(),
+            vals: vec![base_dn.to_string()],
+        },
+    ];
+    if let Some(banner) = login_banner {
+        attributes.push(LdapPartialAttribute {
+            atype: "description".to_string(),
+            vals: vec![banner.trim().to_string()],
+        });
+    }
+    if let Some(server_id) = server_id {
+        attributes.push(LdapPartialAttribute {
+            atype: "serverId".to_string(),
+            vals: vec![server_id.to_string()],
+        });
+    }
+    if let Some((num_users, num_groups)) = user_and_group_counts {
+        attributes.push(LdapPartialAttribute {
+            atype: "numUsers".to_string(),
+            vals: vec![num_users.to_string()],
+        });
+        attributes.push(LdapPartialAttribute {
+            atype: "numGroups".to_string(),
+            vals: vec![num_groups.to_string()],
+        });
+    }
     LdapOp::SearchResultEntry(LdapSearchResultEntry {
         dn: "".to_string(),
-        attributes: vec![
-            LdapPartialAttribute {
-                atype: "objectClass".to_string(),
-                vals: vec!["top".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "vendorName".to_string(),
-                vals: vec!["LLDAP".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "vendorVersion".to_string(),
-                vals: vec!["lldap_0.2.0".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "supportedLDAPVersion".to_string(),
-                vals: vec!["3".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "supportedExtension".to_string(),
-                vals: vec!["1.3.6.1.4.1.4203.1.11.1".to_string()],
-            },
-            LdapPartialAttribute {
-                atype: "defaultnamingcontext".to_string(),
-                vals: vec![base_dn.to_string()],
-            },
-        ],
+        attributes,
     })
 }
 
-pub struct LdapHandler<Backend: BackendHandler + LoginHandler + OpaqueHandler> {
+/// Backend filters corresponding to an LDAP filter, or the conversion error.
+#[derive(Clone, Debug)]
+struct CompiledFilter {
+    user_filter: std::result::Result<UserRequestFilter, String>,
+    group_filter: std::result::Result<GroupRequestFilter, String>,
+}
+
+/// Cache key mirroring the structure of an LDAP filter, so that distinct filters never collide.
+#[derive(PartialEq, Eq, Hash)]
+enum FilterKey {
+    And(Vec<FilterKey>),
+    Or(Vec<FilterKey>),
+    Not(Box<FilterKey>),
+    Equality(String, String),
+    Substring(String, Option<String>, Vec<String>, Option<String>),
+    Present(String),
+}
+
+impl FilterKey {
+    fn new(filter: &LdapFilter) -> Self {
+        match filter {
+            LdapFilter::And(filters) => Self::And(filters.iter().map(Self::new).collect()),
+            LdapFilter::Or(filters) => Self::Or(filters.iter().map(Self::new).collect()),
+            LdapFilter::Not(filter) => Self::Not(Box::new(Self::new(filter))),
+            LdapFilter::Equality(field, value) => Self::Equality(field.clone(), value.clone()),
+            LdapFilter::Substring(field, substring) => Self::Substring(
+                field.clone(),
+                substring.initial.clone(),
+                substring.any.clone(),
+                substring.final_.clone(),
+            ),
+            LdapFilter::Present(field) => Self::Present(field.clone()),
+        }
+    }
+}
+
+/// Cache of compiled filters, keyed by the structure of the LDAP filter. Clones share the same
+/// cache, so it can be used across sessions.
+#[derive(Clone, Default)]
+pub struct FilterCache(Option<Arc<Mutex<LruCache<FilterKey, CompiledFilter>>>>);
+
+impl FilterCache {
+    /// Creates a cache holding up to `size` filters. A size of 0 disables the cache.
+    pub fn new(size: usize) -> Self {
+        if size == 0 {
+            Self(None)
+        } else {
+            Self(Some(Arc::new(Mutex::new(LruCache::new(size)))))
+        }
+    }
+}
+
+impl std::fmt::Debug for FilterCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FilterCache")
+            .field("enabled", &self.0.is_some())
+            .finish()
+    }
+}
+
+/// Returns true if the name matches one of the patterns, case-insensitively. A `*` in a pattern
+/// matches any sequence of characters.
+fn matches_any_pattern(patterns: &[String], name: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+            Some((c, rest)) => name
+                .split_first()
+                .map(|(n, name_rest)| n == c && matches(rest, name_rest))
+                .unwrap_or(false),
+        }
+    }
+    let name = name.to_lowercase().chars().collect::<Vec<_>>();
+    patterns
+        .iter()
+        .any(|p| matches(&p.to_lowercase().chars().collect::<Vec<_>>(), &name))
+}
+
+/// Whether the conversion of the filter only depends on the filter itself. Filters with matching
+/// rules depend on the content of the backend.
+fn is_pure_filter(filter: &LdapFilter) -> bool {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => filters.iter().all(is_pure_filter),
+        LdapFilter::Not(filter) => is_pure_filter(filter),
+        LdapFilter::Equality(field, _) => !field.contains(':'),
+        LdapFilter::Substring(field, _) => !field.eq_ignore_ascii_case("memberof"),
+        _ => true,
+    }
+}
+
+/// Case-insensitive matching of a value against a substring filter, like `cn=app_*,ou=groups*`.
+fn matches_substring_filter(filter: &LdapSubstringFilter, value: &str) -> bool {
+    let value = value.to_lowercase();
+    let mut remaining = value.as_str();
+    if let Some(initial) = &filter.initial {
+        match remaining.strip_prefix(initial.to_lowercase().as_str()) {
+            Some(rest) => remaining = rest,
+            None => return false,
+        }
+    }
+    for any in &filter.any {
+        let any = any.to_lowercase();
+        match remaining.find(any.as_str()) {
+            Some(index) => remaining = &remaining[index + any.len()..],
+            None => return false,
+        }
+    }
+    match &filter.final_ {
+        Some(final_) => remaining.ends_with(final_.to_lowercase().as_str()),
+        None => true,
+    }
+}
+
+/// Configuration of a single LDAP session, independent of the backend.
+#[derive(Clone, Debug)]
+pub struct LdapHandlerConfig {
+    pub ldap_base_dn: String,
+    pub ldap_user_dn: UserId,
+    pub maintenance_mode: MaintenanceMode,
+    pub filter_cache: FilterCache,
+    pub search_cache: LdapSearchCache,
+    pub login_banner: Option<String>,
+    /// Returned in the root DSE, to tell the instances apart.
+    pub server_id: Option<String>,
+    /// Users and groups matching these patterns are only visible to the admin.
+    pub hidden_users: Vec<String>,
+    pub hidden_groups: Vec<String>,
+    /// Operations rejected with `unwillingToPerform`, by name (see `get_operation_name`).
+    pub disabled_operations: Vec<String>,
+    /// Maximum number of entries returned to users other than the admin, unless overridden by
+    /// the user's `max_search_results`.
+    pub search_result_limit_for_non_admin: Option<u32>,
+    /// Accept binds with a DN and an empty password as anonymous, instead of rejecting them.
+    pub allow_unauthenticated_bind: bool,
+    /// If non-zero, successful binds with an expired password report the grace logins left.
+    pub password_max_age_days: u32,
+    /// Used to notify admins of changes to their password.
+    pub mailer: Mailer,
+    /// Server to forward the binds of users without a local password to.
+    pub upstream: Option<UpstreamLdapConfig>,
+    /// Reject binds with a password on connections that are not encrypted.
+    pub require_tls_for_password_bind: bool,
+    /// Whether the session runs over an encrypted connection (LDAPS).
+    pub is_tls: bool,
+    /// User attributes left out of the entries for some viewers.
+    pub attribute_visibility: AttributeVisibilityPolicy,
+    /// Return the search results sorted by DN, for clients that expect a stable order.
+    pub sort_search_results: bool,
+    /// Attributes returned to the searches that don't request any, instead of all of them.
+    pub default_search_attributes: Vec<String>,
+    /// Match the values of the DNs case-insensitively, e.g. "OU=People" for "ou=people". The
+    /// attribute types and the user IDs are always case-insensitive.
+    pub ignore_dn_value_case: bool,
+    /// Other names of the attributes in the entries, e.g. "email" for "mail": lowercase alias ->
+    /// attribute.
+    pub attribute_aliases: HashMap<String, String>,
+    /// Return `givenName` and `sn` from the display name for the users that have neither.
+    pub derive_names_from_display_name: bool,
+    /// The admin group, which cannot be deleted.
+    pub admin_group_id: GroupId,
+}
+
+impl LdapHandlerConfig {
+    pub fn new(ldap_base_dn: String, ldap_user_dn: UserId) -> Self {
+        Self {
+            ldap_base_dn,
+            ldap_user_dn,
+            maintenance_mode: MaintenanceMode::default(),
+            filter_cache: FilterCache::default(),
+            search_cache: LdapSearchCache::default(),
+            login_banner: None,
+            server_id: None,
+            hidden_users: Vec::new(),
+            hidden_groups: Vec::new(),
+            disabled_operations: Vec::new(),
+            search_result_limit_for_non_admin: None,
+            allow_unauthenticated_bind: false,
+            password_max_age_days: 0,
+            mailer: Mailer::default(),
+            upstream: None,
+            require_tls_for_password_bind: false,
+            is_tls: false,
+            attribute_visibility: AttributeVisibilityPolicy::default(),
+            sort_search_results: false,
+            default_search_attributes: Vec::new(),
+            ignore_dn_value_case: true,
+            attribute_aliases: HashMap::new(),
+            derive_names_from_display_name: false,
+            admin_group_id: GroupId(1),
+        }
+    }
+}
+
+impl From<&Configuration> for LdapHandlerConfig {
+    fn from(config: &Configuration) -> Self {
+        Self {
+            filter_cache: FilterCache::new(config.ldap_filter_cache_size),
+            search_cache: LdapSearchCache::new(
+                config.ldap_search_cache_size,
+                Duration::from_secs(config.ldap_cache_ttl_secs),
+            ),
+            login_banner: config.login_banner.clone(),
+            server_id: config
+                .ldap_root_dse_server_id
+                .then(|| config.server_id.clone()),
+            hidden_users: config.ldap_hidden_users.clone(),
+            hidden_groups: config.ldap_hidden_groups.clone(),
+            disabled_operations: config.ldap_disabled_operations.clone(),
+            search_result_limit_for_non_admin: config.search_result_limit_for_non_admin,
+            allow_unauthenticated_bind: config.ldap_allow_unauthenticated_bind,
+            password_max_age_days: config.password_max_age_days,
+            upstream: config.ldap_upstream.clone(),
+            require_tls_for_password_bind: config.ldap_require_tls_for_password_bind,
+            attribute_visibility: config.attribute_visibility.clone(),
+            sort_search_results: config.ldap_sort_search_results,
+            default_search_attributes: config.ldap_default_search_attributes.clone(),
+            ignore_dn_value_case: config.ldap_ignore_dn_value_case,
+            attribute_aliases: config
+                .ldap_attribute_aliases
+                .iter()
+                .map(|(alias, attribute)| (alias.to_lowercase(), attribute.clone()))
+                .collect(),
+            derive_names_from_display_name: config.ldap_derive_names_from_display_name,
+            admin_group_id: GroupId(config.admin_group_id),
+            ..Self::new(config.ldap_base_dn.clone(), config.ldap_user_dn.clone())
+        }
+    }
+}
+
+pub struct LdapHandler<Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler>
+{
     dn: LdapDn,
     user_id: UserId,
     backend_handler: Backend,
     pub base_dn: Vec<(String, String)>,
     base_dn_str: String,
     ldap_user_dn: LdapDn,
+    maintenance_mode: MaintenanceMode,
+    filter_cache: FilterCache,
+    search_cache: LdapSearchCache,
+    login_banner: Option<String>,
+    server_id: Option<String>,
+    hidden_users: Vec<String>,
+    hidden_groups: Vec<String>,
+    disabled_operations: Vec<String>,
+    search_result_limit_for_non_admin: Option<u32>,
+    allow_unauthenticated_bind: bool,
+    password_max_age_days: u32,
+    mailer: Mailer,
+    upstream: Option<UpstreamLdapConfig>,
+    require_tls_for_password_bind: bool,
+    is_tls: bool,
+    attribute_visibility: AttributeVisibilityPolicy,
+    sort_search_results: bool,
+    default_search_attributes: Vec<String>,
+    ignore_dn_value_case: bool,
+    attribute_aliases: HashMap<String, String>,
+    derive_names_from_display_name: bool,
+    admin_group_id: GroupId,
+    extended_operations: Arc<ExtendedOperationRegistry<Backend>>,
 }
 
-impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend> {
+impl<Backend> LdapHandler<Backend>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+{
     pub fn new(backend_handler: Backend, ldap_base_dn: String, ldap_user_dn: UserId) -> Self {
+        Self::new_with_config(
+            LdapHandlerConfig::new(ldap_base_dn, ldap_user_dn),
+            backend_handler,
+        )
+    }
+
+    pub fn new_with_config(config: LdapHandlerConfig, backend_handler: Backend) -> Self {
+        let LdapHandlerConfig {
+            ldap_base_dn,
+            ldap_user_dn,
+            maintenance_mode,
+            filter_cache,
+            search_cache,
+            login_banner,
+            server_id,
+            hidden_users,
+            hidden_groups,
+            disabled_operations,
+            search_result_limit_for_non_admin,
+            allow_unauthenticated_bind,
+            password_max_age_days,
+            mailer,
+            upstream,
+            require_tls_for_password_bind,
+            is_tls,
+            attribute_visibility,
+            sort_search_results,
+            default_search_attributes,
+            ignore_dn_value_case,
+            attribute_aliases,
+            derive_names_from_display_name,
+            admin_group_id,
+        } = config;
         Self {
             dn: LdapDn("unauthenticated".to_string()),
             user_id: UserId::new("unauthenticated"),
@@ -307,8 +1184,45 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
                     ldap_base_dn
                 )
             }),
-            ldap_user_dn: LdapDn(format!("uid={},ou=people,{}", ldap_user_dn, &ldap_base_dn)),
+            ldap_user_dn: LdapDn(make_user_dn(ldap_user_dn.as_str(), &ldap_base_dn)),
             base_dn_str: ldap_base_dn,
+            maintenance_mode,
+            filter_cache,
+            search_cache,
+            login_banner,
+            server_id,
+            hidden_users,
+            hidden_groups,
+            disabled_operations,
+            search_result_limit_for_non_admin,
+            allow_unauthenticated_bind,
+            password_max_age_days,
+            mailer,
+            upstream,
+            require_tls_for_password_bind,
+            is_tls,
+            attribute_visibility,
+            sort_search_results,
+            default_search_attributes,
+            ignore_dn_value_case,
+            attribute_aliases,
+            derive_names_from_display_name,
+            admin_group_id,
+            extended_operations: Arc::new(ExtendedOperationRegistry::default()),
+        }
+    }
+
+    /// Replaces the built-in extended operations, e.g. with a registry shared by all the sessions.
+    pub fn set_extended_operations(&mut self, registry: Arc<ExtendedOperationRegistry<Backend>>) {
+        self.extended_operations = registry;
+    }
+
+    /// The DN the session is bound as, if any.
+    pub fn get_bound_dn(&self) -> Option<&str> {
+        if self.user_id.as_str() == "unauthenticated" {
+            None
+        } else {
+            Some(&self.dn.0)
         }
     }
 
@@ -318,11 +1232,42 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
             &request.dn,
             &self.base_dn,
             &self.base_dn_str,
+            self.ignore_dn_value_case,
         ) {
             Ok(s) => s,
             Err(e) => return (LdapResultCode::NamingViolation, e.to_string()),
         };
         let LdapBindCred::Simple(password) = &request.cred;
+        if password.is_empty() {
+            // An "unauthenticated" bind, as per RFC 4513 section 5.1.2: it must not be treated as
+            // a successful authentication of the user.
+            self.dn = LdapDn("unauthenticated".to_string());
+            self.user_id = UserId::new("unauthenticated");
+            if self.allow_unauthenticated_bind {
+                debug!(
+                    r#"Accepting unauthenticated bind for "{}" as anonymous"#,
+                    &request.dn
+                );
+                return (LdapResultCode::Success, "".to_string());
+            }
+            warn!(r#"Rejected unauthenticated bind for "{}""#, &request.dn);
+            return (
+                LdapResultCode::UnwillingToPerform,
+                "Unauthenticated binds are not allowed".to_string(),
+            );
+        }
+        if self.require_tls_for_password_bind && !self.is_tls {
+            self.dn = LdapDn("unauthenticated".to_string());
+            self.user_id = UserId::new("unauthenticated");
+            warn!(
+                r#"Rejected bind with a password for "{}" on a plaintext connection"#,
+                &request.dn
+            );
+            return (
+                LdapResultCode::ConfidentialityRequired,
+                "Binds with a password require an encrypted connection (LDAPS)".to_string(),
+            );
+        }
         match self
             .backend_handler
             .bind(BindRequest {
@@ -332,25 +1277,138 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
             .await
         {
             Ok(()) => {
-                self.dn = LdapDn(request.dn.clone());
+                self.record_login(&user_id).await;
+                // The bound DN is compared to the admin's, whatever the case the client used.
+                self.dn = LdapDn(make_user_dn(user_id.as_str(), &self.base_dn_str));
+                let message = self.get_password_expiry_warning(&user_id).await;
                 self.user_id = user_id;
-                (LdapResultCode::Success, "".to_string())
+                (LdapResultCode::Success, message)
+            }
+            Err(DomainError::PasswordExpired) => (
+                LdapResultCode::InvalidCredentials,
+                "Password expired, change it to unlock the account".to_string(),
+            ),
+            // Not a wrong password: the client can retry.
+            Err(e) if e.is_operation_timeout() => {
+                (LdapResultCode::TimeLimitExceeded, e.to_string())
+            }
+            // E.g. an oversized password, don't send it upstream either.
+            Err(e @ DomainError::ValidationError(_, _)) => {
+                (LdapResultCode::InvalidCredentials, e.to_string())
+            }
+            // Only the users without local credentials are authenticated upstream: a local
+            // password always takes precedence.
+            Err(DomainError::CredentialsNotFound(_)) => {
+                if self.do_upstream_bind(&user_id, password).await {
+                    self.record_login(&user_id).await;
+                    self.dn = LdapDn(make_user_dn(user_id.as_str(), &self.base_dn_str));
+                    self.user_id = user_id;
+                    (LdapResultCode::Success, "".to_string())
+                } else {
+                    (LdapResultCode::InvalidCredentials, "".to_string())
+                }
             }
             Err(_) => (LdapResultCode::InvalidCredentials, "".to_string()),
         }
     }
 
-    async fn change_password(&mut self, user: &UserId, password: &str) -> Result<()> {
-        use lldap_auth::*;
-        let mut rng = rand::rngs::OsRng;
-        let registration_start_request =
-            opaque::client::registration::start_registration(password, &mut rng)?;
-        let req = registration::ClientRegistrationStartRequest {
-            username: user.to_string(),
-            registration_start_request: registration_start_request.message,
-        };
-        let registration_start_response = self.backend_handler.registration_start(req).await?;
-        let registration_finish = opaque::client::registration::finish_registration(
+    /// Records the time of a successful bind. A failure doesn't fail the bind.
+    async fn record_login(&self, user_id: &UserId) {
+        if let Err(e) = self
+            .backend_handler
+            .update_last_login(user_id, chrono::Utc::now())
+            .await
+        {
+            warn!(r#"Could not record the login of "{}": {:#}"#, user_id, e);
+        }
+    }
+
+    /// Authenticates the users that are unknown or don't have a password on the upstream server,
+    /// if there is one. Unknown users are created if `auto_provision` is set.
+    async fn do_upstream_bind(&self, user_id: &UserId, password: &str) -> bool {
+        let upstream = match &self.upstream {
+            Some(upstream) => upstream,
+            None => return false,
+        };
+        let is_known = match self.backend_handler.get_user_details(user_id).await {
+            Ok(_) => true,
+            Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => false,
+            Err(e) => {
+                warn!(r#"Could not check whether "{}" exists: {}"#, user_id, e);
+                return false;
+            }
+        };
+        let upstream_user = match upstream_bind(upstream, user_id.as_str(), password).await {
+            Ok(Some(upstream_user)) => upstream_user,
+            Ok(None) => {
+                debug!(r#"Upstream bind rejected for "{}""#, user_id);
+                return false;
+            }
+            Err(e) => {
+                warn!(r#"Upstream bind failed for "{}": {:#}"#, user_id, e);
+                return false;
+            }
+        };
+        info!(r#"User "{}" authenticated by the upstream server"#, user_id);
+        if !is_known && upstream.auto_provision {
+            match self
+                .backend_handler
+                .create_user(CreateUserRequest {
+                    user_id: user_id.clone(),
+                    email: upstream_user.email,
+                    display_name: Some(upstream_user.display_name),
+                    first_name: Some(upstream_user.first_name),
+                    last_name: Some(upstream_user.last_name),
+                    ..Default::default()
+                })
+                .await
+            {
+                Ok(()) => info!(r#"Created user "{}" from the upstream server"#, user_id),
+                Err(e) => warn!(
+                    r#"Could not create user "{}" from the upstream server: {}"#,
+                    user_id, e
+                ),
+            }
+        }
+        true
+    }
+
+    /// ldap3_server cannot send password policy controls, so the grace logins left after the
+    /// password expired are reported in the diagnostic message of the bind response.
+    async fn get_password_expiry_warning(&self, user_id: &UserId) -> String {
+        if self.password_max_age_days == 0 {
+            return "".to_string();
+        }
+        match self
+            .backend_handler
+            .get_grace_logins_remaining(user_id)
+            .await
+        {
+            Ok(remaining) if remaining >= 0 => {
+                format!("Password expired, {} grace logins remaining", remaining)
+            }
+            Ok(_) => "".to_string(),
+            Err(e) => {
+                warn!(
+                    r#"Could not get the grace logins remaining for "{}": {}"#,
+                    user_id, e
+                );
+                "".to_string()
+            }
+        }
+    }
+
+    async fn change_password(&mut self, user: &UserId, password: &str) -> Result<()> {
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration(password, &mut rng)?;
+        let req = registration::ClientRegistrationStartRequest {
+            username: user.to_string(),
+            registration_start_request: registration_start_request.message,
+        };
+        let registration_start_response = self.backend_handler.registration_start(req).await?;
+        let registration_finish = opaque::client::registration::finish_registration(
             registration_start_request.state,
             registration_start_response.registration_response,
             &mut rng,
@@ -360,16 +1418,35 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
             registration_upload: registration_finish.message,
         };
         self.backend_handler.registration_finish(req).await?;
+        on_password_changed(
+            &self.backend_handler,
+            user,
+            self.user_id.as_str(),
+            &self.mailer,
+            self.admin_group_id,
+        )
+        .await;
         Ok(())
     }
 
-    async fn do_password_modification(
+    pub(crate) async fn do_password_modification(
         &mut self,
         request: &LdapPasswordModifyRequest,
     ) -> Vec<LdapOp> {
+        if self.maintenance_mode.is_enabled() {
+            return vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "The server is in read-only maintenance mode".to_string(),
+            )];
+        }
         match (&request.user_identity, &request.new_password) {
             (Some(user), Some(password)) => {
-                match get_user_id_from_distinguished_name(user, &self.base_dn, &self.base_dn_str) {
+                match get_user_id_from_distinguished_name(
+                    user,
+                    &self.base_dn,
+                    &self.base_dn_str,
+                    self.ignore_dn_value_case,
+                ) {
                     Ok(uid) => {
                         if let Err(e) = self.change_password(&uid, password).await {
                             vec![make_extended_response(
@@ -397,965 +1474,4649 @@ impl<Backend: BackendHandler + LoginHandler + OpaqueHandler> LdapHandler<Backend
     }
 
     async fn do_extended_request(&mut self, request: &LdapExtendedRequest) -> Vec<LdapOp> {
-        match LdapPasswordModifyRequest::try_from(request) {
-            Ok(password_request) => self.do_password_modification(&password_request).await,
-            Err(_) => vec![make_extended_response(
+        let registry = self.extended_operations.clone();
+        match registry.get(&request.name) {
+            Some(handler) => handler.handle(request, self).await,
+            None => vec![make_extended_response(
                 LdapResultCode::UnwillingToPerform,
                 format!("Unsupported extended operation: {}", &request.name),
             )],
         }
     }
 
-    pub async fn do_search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
-        let admin = self.dn == self.ldap_user_dn;
-        if request.base.is_empty()
-            && request.scope == LdapSearchScope::Base
-            && request.filter == LdapFilter::Present("objectClass".to_string())
-        {
-            debug!("Received rootDSE request");
-            return vec![root_dse_response(&self.base_dn_str), make_search_success()];
+    /// Creates a user or a group, depending on the object classes of the entry. Only the admin
+    /// can add entries.
+    pub async fn do_add(&mut self, request: &LdapAddRequest) -> Vec<LdapOp> {
+        debug!(r#"Received add request for "{}""#, &request.dn);
+        if self.dn != self.ldap_user_dn {
+            return vec![make_add_response(
+                LdapResultCode::InsufficentAccessRights,
+                format!(
+                    r#"Current user "{}" is not allowed to add entries"#,
+                    self.dn.0
+                ),
+            )];
         }
-        debug!("Received search request: {:?}", &request);
-        let dn_parts = match parse_distinguished_name(&request.base) {
-            Ok(dn) => dn,
-            Err(_) => {
-                return vec![make_search_error(
-                    LdapResultCode::OperationsError,
-                    format!(r#"Could not parse base DN: "{}""#, request.base),
-                )]
-            }
+        if self.maintenance_mode.is_enabled() {
+            return vec![make_add_response(
+                LdapResultCode::UnwillingToPerform,
+                "The server is in read-only maintenance mode".to_string(),
+            )];
+        }
+        let object_classes = get_add_attribute_values(request, "objectClass")
+            .iter()
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        let has_class =
+            |classes: &[&str]| object_classes.iter().any(|c| classes.contains(&c.as_str()));
+        let (code, message) = if has_class(&[
+            "inetorgperson",
+            "organizationalperson",
+            "person",
+            "posixaccount",
+            "mailaccount",
+        ]) {
+            self.add_user(request).await
+        } else if has_class(&["groupofuniquenames", "groupofnames"]) {
+            self.add_group(request).await
+        } else {
+            (
+                LdapResultCode::ObjectClassViolation,
+                format!("Unsupported object classes: {:?}", object_classes),
+            )
         };
-        if !is_subtree(&dn_parts, &self.base_dn) {
-            // Search path is not in our tree, just return an empty success.
-            warn!(
-                "The specified search tree {:?} is not under the common subtree {:?}",
-                &dn_parts, &self.base_dn
+        vec![make_add_response(code, message)]
+    }
+
+    async fn add_user(&mut self, request: &LdapAddRequest) -> (LdapResultCode, String) {
+        let user_id = match get_user_id_from_distinguished_name(
+            &request.dn,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        ) {
+            Ok(user_id) => user_id,
+            Err(e) => return (LdapResultCode::InvalidDNSyntax, e.to_string()),
+        };
+        // The RDN value must be the one of the entry.
+        let uids = get_add_attribute_values(request, "uid");
+        if !uids.is_empty() && !uids.iter().any(|uid| UserId::new(uid) == user_id) {
+            return (
+                LdapResultCode::NamingViolation,
+                format!(r#"The uid doesn't match the DN "{}""#, request.dn),
             );
-            return vec![make_search_success()];
-        }
-        let mut results = Vec::new();
-        let mut got_match = false;
-        let user_filter = if admin { None } else { Some(&self.user_id) };
-        if dn_parts.len() == self.base_dn.len()
-            || (dn_parts.len() == self.base_dn.len() + 1
-                && dn_parts[0] == ("ou".to_string(), "people".to_string()))
-        {
-            got_match = true;
-            results.extend(self.get_user_list(request, &user_filter).await);
         }
-        if dn_parts.len() == self.base_dn.len()
-            || (dn_parts.len() == self.base_dn.len() + 1
-                && dn_parts[0] == ("ou".to_string(), "groups".to_string()))
+        let email = match get_add_attribute_value(request, "mail") {
+            Some(email) => email,
+            None => {
+                return (
+                    LdapResultCode::ObjectClassViolation,
+                    "Missing required attribute: mail".to_string(),
+                )
+            }
+        };
+        match self
+            .backend_handler
+            .list_users(Some(UserRequestFilter::UserId(user_id.clone())))
+            .await
         {
-            got_match = true;
-            results.extend(self.get_groups_list(request, &user_filter).await);
+            Ok(users) if !users.is_empty() => {
+                return (
+                    LdapResultCode::EntryAlreadyExists,
+                    format!(r#"User "{}" already exists"#, user_id),
+                )
+            }
+            Ok(_) => {}
+            Err(e) => return (backend_error_code(&e), format!("{:#}", e)),
         }
-        if !got_match {
-            warn!(
-                r#"The requested search tree "{}" matches neither the user subtree "ou=people,{}" nor the group subtree "ou=groups,{}""#,
-                &request.base, &self.base_dn_str, &self.base_dn_str
+        let create_request = CreateUserRequest {
+            user_id: user_id.clone(),
+            email,
+            display_name: get_add_attribute_value(request, "cn")
+                .or_else(|| get_add_attribute_value(request, "displayName")),
+            first_name: get_add_attribute_value(request, "givenName"),
+            last_name: get_add_attribute_value(request, "sn"),
+            mail_aliases: get_add_attribute_values(request, "mailLocalAddress").to_vec(),
+            mail_forwarding: get_add_attribute_values(request, "mailForwardingAddress").to_vec(),
+            phone_numbers: get_add_attribute_values(request, "telephoneNumber").to_vec(),
+        };
+        let password = get_add_attribute_value(request, "userPassword").map(SecUtf8::from);
+        let has_password = password.is_some();
+        // Creating the user and setting their password either both happen or neither does.
+        let result = {
+            let user_id = user_id.clone();
+            self.backend_handler
+                .transaction(|txn| {
+                    Box::pin(async move {
+                        txn.create_user(create_request).await?;
+                        if let Some(password) = password {
+                            register_password(txn, &user_id, &password).await?;
+                        }
+                        Ok(())
+                    })
+                })
+                .await
+        };
+        if let Err(e) = result {
+            return (
+                backend_error_code(&e),
+                format!("Error while creating the user: {:#}", e),
             );
         }
-        if results.is_empty() || matches!(results[results.len() - 1], LdapOp::SearchResultEntry(_))
-        {
-            results.push(make_search_success());
+        info!(
+            r#"User "{}" created by "{}" over LDAP"#,
+            user_id, self.user_id
+        );
+        if has_password {
+            on_password_changed(
+                &self.backend_handler,
+                &user_id,
+                self.user_id.as_str(),
+                &self.mailer,
+                self.admin_group_id,
+            )
+            .await;
         }
-        results
+        (LdapResultCode::Success, "".to_string())
     }
 
-    async fn get_user_list(
-        &self,
-        request: &LdapSearchRequest,
-        user_filter: &Option<&UserId>,
-    ) -> Vec<LdapOp> {
-        let filters = match self.convert_user_filter(&request.filter) {
-            Ok(f) => f,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::UnwillingToPerform,
-                    format!("Unsupported user filter: {:#}", e),
-                )]
-            }
+    async fn add_group(&mut self, request: &LdapAddRequest) -> (LdapResultCode, String) {
+        let group_name = match get_group_id_from_distinguished_name(
+            &request.dn,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        ) {
+            Ok(group_name) => group_name,
+            Err(e) => return (LdapResultCode::InvalidDNSyntax, e.to_string()),
         };
-        let filters = match user_filter {
-            None => filters,
-            Some(u) => {
-                UserRequestFilter::And(vec![filters, UserRequestFilter::UserId((*u).clone())])
+        // The RDN value must be the one of the entry.
+        let names = get_add_attribute_values(request, "cn");
+        if !names.is_empty()
+            && !names
+                .iter()
+                .any(|name| dn_value_matches(name, &group_name, self.ignore_dn_value_case))
+        {
+            return (
+                LdapResultCode::NamingViolation,
+                format!(r#"The cn doesn't match the DN "{}""#, request.dn),
+            );
+        }
+        let members = match get_add_attribute_values(request, "member")
+            .iter()
+            .chain(get_add_attribute_values(request, "uniqueMember"))
+            .map(|dn| {
+                get_user_id_from_distinguished_name(
+                    dn,
+                    &self.base_dn,
+                    &self.base_dn_str,
+                    self.ignore_dn_value_case,
+                )
+            })
+            .collect::<Result<Vec<_>>>()
+        {
+            Ok(members) => members,
+            Err(e) => {
+                return (
+                    LdapResultCode::InvalidAttributeSyntax,
+                    format!("Invalid member: {:#}", e),
+                )
             }
         };
-        let users = match self.backend_handler.list_users(Some(filters)).await {
-            Ok(users) => users,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::Other,
-                    format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
-                )]
+        match self
+            .backend_handler
+            .list_groups(Some(GroupRequestFilter::DisplayName(group_name.clone())))
+            .await
+        {
+            Ok(groups) if !groups.is_empty() => {
+                return (
+                    LdapResultCode::EntryAlreadyExists,
+                    format!(r#"Group "{}" already exists"#, group_name),
+                )
             }
+            Ok(_) => {}
+            Err(e) => return (backend_error_code(&e), format!("{:#}", e)),
+        }
+        // The group is only created with all its members.
+        let result = {
+            let group_name = group_name.clone();
+            self.backend_handler
+                .transaction(|txn| {
+                    Box::pin(async move {
+                        let group_id = txn.create_group(&group_name).await?;
+                        for member in members {
+                            txn.add_user_to_group(&member, group_id).await?;
+                        }
+                        Ok(())
+                    })
+                })
+                .await
         };
+        if let Err(e) = result {
+            return (
+                backend_error_code(&e),
+                format!("Error while creating the group: {:#}", e),
+            );
+        }
+        info!(
+            r#"Group "{}" created by "{}" over LDAP"#,
+            group_name, self.user_id
+        );
+        (LdapResultCode::Success, "".to_string())
+    }
 
-        users
-            .into_iter()
-            .map(|u| make_ldap_search_user_result_entry(u, &self.base_dn_str, &request.attrs))
-            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
-            .collect::<Result<Vec<_>>>()
-            .unwrap_or_else(|e| {
-                vec![make_search_error(
-                    LdapResultCode::NoSuchAttribute,
-                    e.to_string(),
-                )]
-            })
+    /// Renames a user or a group, by changing its RDN. Moving entries to another subtree is not
+    /// supported. Only the admin can rename entries.
+    pub async fn do_modify_dn(&mut self, request: &LdapModifyDNRequest) -> Vec<LdapOp> {
+        debug!(
+            r#"Received modify DN request for "{}": "{}""#,
+            &request.dn, &request.newrdn
+        );
+        if self.dn != self.ldap_user_dn {
+            return vec![make_modify_dn_response(
+                LdapResultCode::InsufficentAccessRights,
+                format!(
+                    r#"Current user "{}" is not allowed to rename entries"#,
+                    self.dn.0
+                ),
+            )];
+        }
+        if self.maintenance_mode.is_enabled() {
+            return vec![make_modify_dn_response(
+                LdapResultCode::UnwillingToPerform,
+                "The server is in read-only maintenance mode".to_string(),
+            )];
+        }
+        let (code, message) = self.rename_entry(request).await;
+        vec![make_modify_dn_response(code, message)]
     }
 
-    async fn get_groups_list(
-        &self,
-        request: &LdapSearchRequest,
-        user_filter: &Option<&UserId>,
-    ) -> Vec<LdapOp> {
-        let filter = match self.convert_group_filter(&request.filter) {
-            Ok(f) => f,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::UnwillingToPerform,
-                    format!("Unsupported group filter: {:#}", e),
-                )]
+    async fn rename_entry(&self, request: &LdapModifyDNRequest) -> (LdapResultCode, String) {
+        let parts = match parse_distinguished_name(&request.dn) {
+            Ok(parts) if !parts.is_empty() => parts,
+            _ => {
+                return (
+                    LdapResultCode::InvalidDNSyntax,
+                    format!(r#"Invalid DN: "{}""#, request.dn),
+                )
             }
         };
-        let filter = match user_filter {
-            None => filter,
-            Some(u) => {
-                GroupRequestFilter::And(vec![filter, GroupRequestFilter::Member((*u).clone())])
+        if let Some(new_superior) = &request.new_superior {
+            let same_parent = parse_distinguished_name(new_superior).map_or(false, |superior| {
+                superior.len() == parts.len() - 1
+                    && superior.iter().zip(&parts[1..]).all(|(a, b)| {
+                        a.0.eq_ignore_ascii_case(&b.0) && a.1.eq_ignore_ascii_case(&b.1)
+                    })
+            });
+            if !same_parent {
+                return (
+                    LdapResultCode::UnwillingToPerform,
+                    "Moving entries to another subtree is not supported".to_string(),
+                );
             }
-        };
-
-        let groups = match self.backend_handler.list_groups(Some(filter)).await {
-            Ok(groups) => groups,
-            Err(e) => {
-                return vec![make_search_error(
-                    LdapResultCode::Other,
-                    format!(r#"Error while listing groups "{}": {:#}"#, request.base, e),
-                )]
+        }
+        let (rdn_attribute, new_name) = match parse_distinguished_name(&request.newrdn) {
+            Ok(rdn) if rdn.len() == 1 => rdn.into_iter().next().unwrap(),
+            _ => {
+                return (
+                    LdapResultCode::InvalidDNSyntax,
+                    format!(r#"Invalid new RDN: "{}""#, request.newrdn),
+                )
             }
         };
-
-        groups
-            .into_iter()
-            .map(|u| {
-                make_ldap_search_group_result_entry(
-                    u,
-                    &self.base_dn_str,
-                    &request.attrs,
-                    user_filter,
-                )
-            })
-            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
-            .collect::<Result<Vec<_>>>()
-            .unwrap_or_else(|e| {
-                vec![make_search_error(
-                    LdapResultCode::NoSuchAttribute,
-                    e.to_string(),
-                )]
-            })
-    }
-
-    pub async fn handle_ldap_message(&mut self, ldap_op: LdapOp) -> Option<Vec<LdapOp>> {
-        Some(match ldap_op {
-            LdapOp::BindRequest(request) => {
-                let (code, message) = self.do_bind(&request).await;
-                vec![LdapOp::BindResponse(LdapBindResponse {
-                    res: LdapResult {
-                        code,
-                        matcheddn: "".to_string(),
-                        message,
-                        referral: vec![],
-                    },
-                    saslcreds: None,
-                })]
+        let rdn_attribute = rdn_attribute.to_ascii_lowercase();
+        if let Ok(user_id) = get_user_id_from_distinguished_name(
+            &request.dn,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        ) {
+            if rdn_attribute != "uid" && rdn_attribute != "cn" {
+                return (
+                    LdapResultCode::NamingViolation,
+                    format!(r#"Users are named by "uid", got "{}""#, rdn_attribute),
+                );
             }
-            LdapOp::SearchRequest(request) => self.do_search(&request).await,
-            LdapOp::UnbindRequest => {
-                self.dn = LdapDn("unauthenticated".to_string());
-                self.user_id = UserId::new("unauthenticated");
-                // No need to notify on unbind (per rfc4511)
-                return None;
+            self.rename_user(user_id, UserId::new(&new_name)).await
+        } else if let Ok(group_name) = get_group_id_from_distinguished_name(
+            &request.dn,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        ) {
+            if rdn_attribute != "cn" {
+                return (
+                    LdapResultCode::NamingViolation,
+                    format!(r#"Groups are named by "cn", got "{}""#, rdn_attribute),
+                );
             }
-            LdapOp::ExtendedRequest(request) => self.do_extended_request(&request).await,
-            op => vec![make_extended_response(
-                LdapResultCode::UnwillingToPerform,
-                format!("Unsupported operation: {:#?}", op),
-            )],
-        })
+            self.rename_group(group_name, new_name).await
+        } else {
+            (
+                LdapResultCode::NoSuchObject,
+                format!(r#"Not a user or group DN: "{}""#, request.dn),
+            )
+        }
     }
 
-    fn convert_group_filter(&self, filter: &LdapFilter) -> Result<GroupRequestFilter> {
-        match filter {
-            LdapFilter::Equality(field, value) => {
-                if field == "member" || field.to_lowercase() == "uniquemember" {
-                    let user_name = get_user_id_from_distinguished_name(
-                        value,
-                        &self.base_dn,
-                        &self.base_dn_str,
-                    )?;
-                    Ok(GroupRequestFilter::Member(user_name))
-                } else if field.to_lowercase() == "objectclass" {
-                    if value == "groupOfUniqueNames" || value == "groupOfNames" {
-                        Ok(GroupRequestFilter::And(vec![]))
-                    } else {
-                        Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
-                            vec![],
-                        ))))
-                    }
-                } else {
-                    let field = map_field(field)?;
-                    if field == "display_name" {
-                        Ok(GroupRequestFilter::DisplayName(value.clone()))
-                    } else {
-                        bail!("Unsupported group attribute: {:?}", field)
-                    }
+    async fn rename_user(&self, user_id: UserId, new_user_id: UserId) -> (LdapResultCode, String) {
+        if make_user_dn(user_id.as_str(), &self.base_dn_str) == self.ldap_user_dn.0 {
+            return (
+                LdapResultCode::UnwillingToPerform,
+                "Cannot rename the admin user".to_string(),
+            );
+        }
+        match self
+            .backend_handler
+            .list_users(Some(UserRequestFilter::UserId(user_id.clone())))
+            .await
+        {
+            Ok(users) if users.is_empty() => {
+                return (
+                    LdapResultCode::NoSuchObject,
+                    format!(r#"No such user: "{}""#, user_id),
+                )
+            }
+            Ok(_) => {}
+            Err(e) => return (backend_error_code(&e), format!("{:#}", e)),
+        }
+        match self
+            .backend_handler
+            .rename_user(&user_id, &new_user_id)
+            .await
+        {
+            Ok(()) => {
+                info!(
+                    r#"User "{}" renamed to "{}" by "{}" over LDAP"#,
+                    user_id, new_user_id, self.user_id
+                );
+                (LdapResultCode::Success, "".to_string())
+            }
+            Err(DomainError::ConstraintViolation(_)) => (
+                LdapResultCode::EntryAlreadyExists,
+                format!(r#"User "{}" already exists"#, new_user_id),
+            ),
+            Err(e) => (
+                backend_error_code(&e),
+                format!("Error while renaming the user: {:#}", e),
+            ),
+        }
+    }
+
+    async fn rename_group(&self, group_name: String, new_name: String) -> (LdapResultCode, String) {
+        let find_group = |name: String| {
+            self.backend_handler
+                .list_groups(Some(GroupRequestFilter::DisplayName(name)))
+        };
+        let group = match find_group(group_name.clone()).await {
+            Ok(groups) => match groups.into_iter().next() {
+                Some(group) => group,
+                None => {
+                    return (
+                        LdapResultCode::NoSuchObject,
+                        format!(r#"No such group: "{}""#, group_name),
+                    )
                 }
+            },
+            Err(e) => return (backend_error_code(&e), format!("{:#}", e)),
+        };
+        if group.id == self.admin_group_id {
+            return (
+                LdapResultCode::UnwillingToPerform,
+                "Cannot rename the admin group".to_string(),
+            );
+        }
+        match find_group(new_name.clone()).await {
+            Ok(groups) if !groups.is_empty() => {
+                return (
+                    LdapResultCode::EntryAlreadyExists,
+                    format!(r#"Group "{}" already exists"#, new_name),
+                )
             }
-            LdapFilter::And(filters) => Ok(GroupRequestFilter::And(
-                filters
-                    .iter()
-                    .map(|f| self.convert_group_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Or(filters) => Ok(GroupRequestFilter::Or(
-                filters
-                    .iter()
-                    .map(|f| self.convert_group_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Not(filter) => Ok(GroupRequestFilter::Not(Box::new(
-                self.convert_group_filter(&*filter)?,
-            ))),
-            _ => bail!("Unsupported group filter: {:?}", filter),
+            Ok(_) => {}
+            Err(e) => return (backend_error_code(&e), format!("{:#}", e)),
+        }
+        if let Err(e) = self
+            .backend_handler
+            .update_group(UpdateGroupRequest {
+                group_id: group.id,
+                display_name: Some(new_name.clone()),
+                description: None,
+                dynamic_filter: None,
+            })
+            .await
+        {
+            return (
+                backend_error_code(&e),
+                format!("Error while renaming the group: {:#}", e),
+            );
         }
+        info!(
+            r#"Group "{}" renamed to "{}" by "{}" over LDAP"#,
+            group_name, new_name, self.user_id
+        );
+        (LdapResultCode::Success, "".to_string())
     }
 
-    fn convert_user_filter(&self, filter: &LdapFilter) -> Result<UserRequestFilter> {
-        match filter {
-            LdapFilter::And(filters) => Ok(UserRequestFilter::And(
-                filters
-                    .iter()
-                    .map(|f| self.convert_user_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Or(filters) => Ok(UserRequestFilter::Or(
-                filters
-                    .iter()
-                    .map(|f| self.convert_user_filter(f))
-                    .collect::<Result<_>>()?,
-            )),
-            LdapFilter::Not(filter) => Ok(UserRequestFilter::Not(Box::new(
-                self.convert_user_filter(&*filter)?,
-            ))),
-            LdapFilter::Equality(field, value) => {
-                if field.to_lowercase() == "memberof" {
-                    let group_name = get_group_id_from_distinguished_name(
-                        value,
-                        &self.base_dn,
-                        &self.base_dn_str,
-                    )?;
-                    Ok(UserRequestFilter::MemberOf(group_name))
-                } else if field.to_lowercase() == "objectclass" {
-                    if value == "person"
-                        || value == "inetOrgPerson"
-                        || value == "posixAccount"
-                        || value == "mailAccount"
-                    {
-                        Ok(UserRequestFilter::And(vec![]))
-                    } else {
-                        Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
-                            vec![],
-                        ))))
-                    }
-                } else {
-                    let field = map_field(field)?;
-                    if field == "user_id" {
-                        Ok(UserRequestFilter::UserId(UserId::new(value)))
-                    } else {
-                        Ok(UserRequestFilter::Equality(field, value.clone()))
-                    }
+    /// Deletes the user or group with the given DN. Only the admin can delete entries.
+    pub async fn do_delete(&mut self, dn: &str) -> Vec<LdapOp> {
+        debug!(r#"Received delete request for "{}""#, dn);
+        if self.dn != self.ldap_user_dn {
+            return vec![make_del_response(
+                LdapResultCode::InsufficentAccessRights,
+                format!(
+                    r#"Current user "{}" is not allowed to delete entries"#,
+                    self.dn.0
+                ),
+            )];
+        }
+        if self.maintenance_mode.is_enabled() {
+            return vec![make_del_response(
+                LdapResultCode::UnwillingToPerform,
+                "The server is in read-only maintenance mode".to_string(),
+            )];
+        }
+        let result = if let Ok(user_id) = get_user_id_from_distinguished_name(
+            dn,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        ) {
+            if user_id == self.user_id {
+                return vec![make_del_response(
+                    LdapResultCode::UnwillingToPerform,
+                    "Cannot delete the current user".to_string(),
+                )];
+            }
+            self.delete_user(user_id).await
+        } else if let Ok(group_name) = get_group_id_from_distinguished_name(
+            dn,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        ) {
+            let groups = match self
+                .backend_handler
+                .list_groups(Some(GroupRequestFilter::DisplayName(group_name.clone())))
+                .await
+            {
+                Ok(groups) => groups,
+                Err(e) => {
+                    return vec![make_del_response(
+                        backend_error_code(&e),
+                        format!("Error while deleting the entry: {:#}", e),
+                    )]
+                }
+            };
+            match groups.into_iter().next() {
+                Some(group) if group.id == self.admin_group_id => {
+                    return vec![make_del_response(
+                        LdapResultCode::UnwillingToPerform,
+                        "Cannot delete the admin group".to_string(),
+                    )]
                 }
+                Some(group) => self.delete_group(group.id, group_name).await,
+                None => Ok(false),
             }
-            LdapFilter::Present(field) => {
-                // Check that it's a field we support.
-                if field.to_lowercase() == "objectclass" || map_field(field).is_ok() {
-                    Ok(UserRequestFilter::And(vec![]))
-                } else {
-                    Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
-                        vec![],
-                    ))))
+        } else {
+            return vec![make_del_response(
+                LdapResultCode::NoSuchObject,
+                format!(r#"Not a user or group DN: "{}""#, dn),
+            )];
+        };
+        match result {
+            Ok(true) => vec![make_del_response(LdapResultCode::Success, "".to_string())],
+            Ok(false) => vec![make_del_response(
+                LdapResultCode::NoSuchObject,
+                format!(r#"No such entry: "{}""#, dn),
+            )],
+            Err(e) => vec![make_del_response(
+                backend_error_code(&e),
+                format!("Error while deleting the entry: {:#}", e),
+            )],
+        }
+    }
+
+    /// Applies the changes to a user entry. Only `telephoneNumber` can be modified.
+    pub async fn do_modify(&mut self, request: &LdapModifyRequest) -> Vec<LdapOp> {
+        debug!(r#"Received modify request for "{}""#, &request.dn);
+        if self.dn != self.ldap_user_dn {
+            return vec![make_modify_response(
+                LdapResultCode::InsufficentAccessRights,
+                format!(
+                    r#"Current user "{}" is not allowed to modify entries"#,
+                    self.dn.0
+                ),
+            )];
+        }
+        if self.maintenance_mode.is_enabled() {
+            return vec![make_modify_response(
+                LdapResultCode::UnwillingToPerform,
+                "The server is in read-only maintenance mode".to_string(),
+            )];
+        }
+        let user_id = match get_user_id_from_distinguished_name(
+            &request.dn,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        ) {
+            Ok(user_id) => user_id,
+            Err(_) => {
+                return vec![make_modify_response(
+                    LdapResultCode::UnwillingToPerform,
+                    format!(r#"Only user entries can be modified: "{}""#, request.dn),
+                )]
+            }
+        };
+        // Nothing is changed unless all the attributes can be modified.
+        if let Some(change) = request
+            .changes
+            .iter()
+            .find(|c| !c.modification.atype.eq_ignore_ascii_case("telephoneNumber"))
+        {
+            return vec![make_modify_response(
+                LdapResultCode::UnwillingToPerform,
+                format!(
+                    "Unsupported attribute modification: {}",
+                    change.modification.atype
+                ),
+            )];
+        }
+        match self
+            .backend_handler
+            .list_users(Some(UserRequestFilter::UserId(user_id.clone())))
+            .await
+        {
+            Ok(users) if users.is_empty() => {
+                return vec![make_modify_response(
+                    LdapResultCode::NoSuchObject,
+                    format!(r#"No such entry: "{}""#, request.dn),
+                )]
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return vec![make_modify_response(
+                    backend_error_code(&e),
+                    format!("{:#}", e),
+                )]
+            }
+        }
+        for change in &request.changes {
+            if let Err(e) = self.apply_phone_number_change(&user_id, change).await {
+                return vec![make_modify_response(
+                    backend_error_code(&e),
+                    format!("Error while modifying the user: {:#}", e),
+                )];
+            }
+        }
+        info!(
+            r#"User "{}" modified by "{}" over LDAP"#,
+            user_id, self.user_id
+        );
+        vec![make_modify_response(
+            LdapResultCode::Success,
+            "".to_string(),
+        )]
+    }
+
+    /// Deleting the attribute without values removes all the phone numbers.
+    async fn apply_phone_number_change(
+        &self,
+        user_id: &UserId,
+        change: &LdapModify,
+    ) -> std::result::Result<(), DomainError> {
+        let values = &change.modification.vals;
+        match change.operation {
+            LdapModifyType::Add => {
+                for phone_number in values {
+                    self.backend_handler
+                        .add_phone_number(user_id, phone_number)
+                        .await?;
+                }
+            }
+            LdapModifyType::Delete if values.is_empty() => {
+                self.backend_handler
+                    .set_phone_numbers(user_id, vec![])
+                    .await?
+            }
+            LdapModifyType::Delete => {
+                for phone_number in values {
+                    self.backend_handler
+                        .remove_phone_number(user_id, phone_number)
+                        .await?;
                 }
             }
-            _ => bail!("Unsupported user filter: {:?}", filter),
+            LdapModifyType::Replace => {
+                self.backend_handler
+                    .set_phone_numbers(user_id, values.clone())
+                    .await?
+            }
         }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::domain::{error::Result, handler::*, opaque_handler::*};
-    use async_trait::async_trait;
-    use ldap3_server::proto::{LdapDerefAliases, LdapSearchScope};
-    use mockall::predicate::eq;
-    use std::collections::HashSet;
-    use tokio;
+    /// Deletes the user, if it exists. Returns whether it existed.
+    async fn delete_user(&self, user_id: UserId) -> std::result::Result<bool, DomainError> {
+        let deleted = {
+            let user_id = user_id.clone();
+            self.backend_handler
+                .transaction(|txn| {
+                    Box::pin(async move {
+                        match txn.get_user_details(&user_id).await {
+                            Ok(_) => (),
+                            Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => {
+                                return Ok(false)
+                            }
+                            Err(e) => return Err(e),
+                        }
+                        txn.delete_user(&user_id).await?;
+                        Ok(true)
+                    })
+                })
+                .await?
+        };
+        if !deleted {
+            return Ok(false);
+        }
+        info!(
+            r#"User "{}" deleted by "{}" over LDAP"#,
+            user_id, self.user_id
+        );
+        Ok(true)
+    }
 
-    mockall::mock! {
-        pub TestBackendHandler{}
-        impl Clone for TestBackendHandler {
-            fn clone(&self) -> Self;
+    /// Deletes the group, if it still exists. Returns whether it existed.
+    async fn delete_group(
+        &self,
+        group_id: GroupId,
+        group_name: String,
+    ) -> std::result::Result<bool, DomainError> {
+        let deleted = self
+            .backend_handler
+            .transaction(|txn| {
+                Box::pin(async move {
+                    match txn.get_group_details(group_id).await {
+                        Ok(_) => (),
+                        Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)) => {
+                            return Ok(false)
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    txn.delete_group(group_id).await?;
+                    Ok(true)
+                })
+            })
+            .await?;
+        if !deleted {
+            return Ok(false);
         }
-        #[async_trait]
-        impl LoginHandler for TestBackendHandler {
-            async fn bind(&self, request: BindRequest) -> Result<()>;
+        info!(
+            r#"Group "{}" deleted by "{}" over LDAP"#,
+            group_name, self.user_id
+        );
+        Ok(true)
+    }
+
+    pub async fn do_search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
+        let mut results = self.search(request).await;
+        // Sorted before the size limit, so that the same entries are left out every time.
+        if self.sort_search_results {
+            results = sort_search_results(results);
         }
-        #[async_trait]
-        impl BackendHandler for TestBackendHandler {
-            async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
-            async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
-            async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
-            async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
-            async fn get_user_groups(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
-            async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
-            async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
-            async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
-            async fn delete_user(&self, user_id: &UserId) -> Result<()>;
-            async fn create_group(&self, group_name: &str) -> Result<GroupId>;
-            async fn delete_group(&self, group_id: GroupId) -> Result<()>;
-            async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
-            async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+        let size_limit = self.get_search_size_limit(request).await;
+        apply_search_size_limit(results, size_limit)
+    }
+
+    /// The maximum number of entries to return: the limit of the request, capped for users
+    /// other than the admin by their own limit or the configured one.
+    async fn get_search_size_limit(&self, request: &LdapSearchRequest) -> Option<usize> {
+        let request_limit = usize::try_from(request.sizelimit)
+            .ok()
+            .filter(|limit| *limit > 0);
+        let user_limit = match self.search_result_limit_for_non_admin {
+            Some(limit) if self.dn != self.ldap_user_dn => {
+                let user_override = match self.get_bound_dn() {
+                    Some(_) => self
+                        .backend_handler
+                        .get_user_details(&self.user_id)
+                        .await
+                        .map_err(|e| warn!("Could not get the search limit of the user: {:#}", e))
+                        .ok()
+                        .and_then(|user| user.max_search_results),
+                    None => None,
+                };
+                Some(user_override.unwrap_or(limit) as usize)
+            }
+            _ => None,
+        };
+        request_limit.into_iter().chain(user_limit).min()
+    }
+
+    /// The number of users and groups, for the root DSE. Left out if they can't be counted.
+    async fn get_user_and_group_counts(&self) -> Option<(u64, u64)> {
+        let counts = async {
+            Ok::<_, DomainError>((
+                self.backend_handler.count_users(None).await?,
+                self.backend_handler.count_groups(None).await?,
+            ))
+        };
+        counts
+            .await
+            .map_err(|e| warn!("Could not count the users and groups: {:#}", e))
+            .ok()
+    }
+
+    async fn search(&mut self, request: &LdapSearchRequest) -> Vec<LdapOp> {
+        let admin = self.dn == self.ldap_user_dn;
+        if request.base.is_empty()
+            && request.scope == LdapSearchScope::Base
+            && request.filter == LdapFilter::Present("objectClass".to_string())
+        {
+            debug!("Received rootDSE request");
+            return vec![
+                root_dse_response(
+                    &self.base_dn_str,
+                    self.login_banner.as_deref(),
+                    self.server_id.as_deref(),
+                    self.extended_operations.oids(),
+                    self.get_user_and_group_counts().await,
+                ),
+                make_search_success(),
+            ];
         }
-        #[async_trait]
-        impl OpaqueHandler for TestBackendHandler {
-            async fn login_start(
-                &self,
-                request: login::ClientLoginStartRequest
-            ) -> Result<login::ServerLoginStartResponse>;
-            async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId>;
-            async fn registration_start(
-                &self,
-                request: registration::ClientRegistrationStartRequest
-            ) -> Result<registration::ServerRegistrationStartResponse>;
-            async fn registration_finish(
-                &self,
-                request: registration::ClientRegistrationFinishRequest
-            ) -> Result<()>;
+        debug!("Received search request: {:?}", &request);
+        let dn_parts = match parse_distinguished_name(&request.base) {
+            Ok(dn) => dn,
+            Err(_) => {
+                return vec![make_search_error(
+                    LdapResultCode::OperationsError,
+                    format!(r#"Could not parse base DN: "{}""#, request.base),
+                )]
+            }
+        };
+        if !is_subtree(&dn_parts, &self.base_dn, self.ignore_dn_value_case) {
+            // Search path is not in our tree, just return an empty success.
+            warn!(
+                "The specified search tree {:?} is not under the common subtree {:?}",
+                &dn_parts, &self.base_dn
+            );
+            return vec![make_search_success()];
+        }
+        let user_filter = if admin { None } else { Some(&self.user_id) };
+        // The results depend on the identity of the bound user.
+        let cache_key = format!(
+            "{:?}|{:?}|{:?}|{:?}|{:?}",
+            user_filter, dn_parts, request.scope, request.filter, request.attrs
+        );
+        // Read before searching, so that a write during the search invalidates the results.
+        let data_version = if self.search_cache.is_enabled() {
+            self.backend_handler.data_version()
+        } else {
+            0
+        };
+        if let Some(results) = self.search_cache.get(&cache_key, data_version) {
+            debug!("Returning cached search results");
+            return results;
         }
+        let mut results = Vec::new();
+        let mut got_match = false;
+        if dn_parts.len() == self.base_dn.len()
+            || (dn_parts.len() == self.base_dn.len() + 1
+                && dn_parts[0].0 == "ou"
+                && dn_value_matches(&dn_parts[0].1, "people", self.ignore_dn_value_case))
+        {
+            got_match = true;
+            results.extend(self.get_user_list(request, &user_filter).await);
+        }
+        if dn_parts.len() == self.base_dn.len()
+            || (dn_parts.len() == self.base_dn.len() + 1
+                && dn_parts[0].0 == "ou"
+                && dn_value_matches(&dn_parts[0].1, "groups", self.ignore_dn_value_case))
+        {
+            got_match = true;
+            results.extend(self.get_groups_list(request, &user_filter).await);
+        }
+        if !got_match {
+            warn!(
+                r#"The requested search tree "{}" matches neither the user subtree "ou=people,{}" nor the group subtree "ou=groups,{}""#,
+                &request.base, &self.base_dn_str, &self.base_dn_str
+            );
+        }
+        if results.is_empty() || matches!(results[results.len() - 1], LdapOp::SearchResultEntry(_))
+        {
+            results.push(make_search_success());
+            self.search_cache
+                .insert(cache_key, data_version, results.clone());
+        }
+        results
+    }
+
+    async fn get_user_list(
+        &self,
+        request: &LdapSearchRequest,
+        user_filter: &Option<&UserId>,
+    ) -> Vec<LdapOp> {
+        let hide_groups = user_filter.is_some() && !self.hidden_groups.is_empty();
+        let filters = if is_pure_filter(&request.filter) && !hide_groups {
+            self.get_compiled_filter(&request.filter).user_filter
+        } else {
+            match self.resolve_member_of_filter(&request.filter).await {
+                Ok(f) if hide_groups => self
+                    .convert_user_filter(&self.hide_member_of_hidden_groups(f))
+                    .map_err(|e| format!("{:#}", e)),
+                Ok(f) => self.convert_user_filter(&f).map_err(|e| format!("{:#}", e)),
+                Err(e) => Err(format!("{:#}", e)),
+            }
+        };
+        let filters = match filters {
+            Ok(f) => f,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    format!("Unsupported user filter: {}", e),
+                )]
+            }
+        };
+        let filters = match user_filter {
+            None => filters,
+            Some(u) => {
+                UserRequestFilter::And(vec![filters, UserRequestFilter::UserId((*u).clone())])
+            }
+        };
+        let users = match self.backend_handler.list_users(Some(filters)).await {
+            Ok(users) => users,
+            Err(e) => {
+                return vec![make_search_error(
+                    backend_error_code(&e),
+                    format!(r#"Error during searching user "{}": {:#}"#, request.base, e),
+                )]
+            }
+        };
+
+        let attributes = expand_requested_attributes(
+            &request.attrs,
+            ALL_USER_ATTRIBUTES,
+            USER_OPERATIONAL_ATTRIBUTES,
+            &self.default_search_attributes,
+        );
+        users
+            .into_iter()
+            .filter(|u| {
+                user_filter.is_none()
+                    || u.user_id == self.user_id
+                    || !matches_any_pattern(&self.hidden_users, u.user_id.as_str())
+            })
+            .map(|mut u| {
+                let is_owner = u.user_id == self.user_id;
+                if self.derive_names_from_display_name {
+                    derive_names_from_display_name(&mut u);
+                }
+                make_ldap_search_user_result_entry(
+                    u,
+                    &self.base_dn_str,
+                    &attributes,
+                    &self.attribute_aliases,
+                    self.password_max_age_days,
+                    |field| {
+                        self.attribute_visibility
+                            .is_visible(field, user_filter.is_none(), is_owner)
+                    },
+                )
+            })
+            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap_or_else(|e| {
+                vec![make_search_error(
+                    LdapResultCode::NoSuchAttribute,
+                    e.to_string(),
+                )]
+            })
+    }
+
+    async fn get_groups_list(
+        &self,
+        request: &LdapSearchRequest,
+        user_filter: &Option<&UserId>,
+    ) -> Vec<LdapOp> {
+        let filter = if is_pure_filter(&request.filter) {
+            self.get_compiled_filter(&request.filter).group_filter
+        } else {
+            match self.resolve_in_chain_filter(&request.filter).await {
+                Ok(f) => self
+                    .convert_group_filter(&f)
+                    .map_err(|e| format!("{:#}", e)),
+                Err(e) => Err(format!("{:#}", e)),
+            }
+        };
+        let filter = match filter {
+            Ok(f) => f,
+            Err(e) => {
+                return vec![make_search_error(
+                    LdapResultCode::UnwillingToPerform,
+                    format!("Unsupported group filter: {}", e),
+                )]
+            }
+        };
+        let filter = match user_filter {
+            None => filter,
+            Some(u) => {
+                GroupRequestFilter::And(vec![filter, GroupRequestFilter::Member((*u).clone())])
+            }
+        };
+
+        let groups = match self.backend_handler.list_groups(Some(filter)).await {
+            Ok(groups) => groups,
+            Err(e) => {
+                return vec![make_search_error(
+                    backend_error_code(&e),
+                    format!(r#"Error while listing groups "{}": {:#}"#, request.base, e),
+                )]
+            }
+        };
+
+        let attributes = expand_requested_attributes(
+            &request.attrs,
+            ALL_GROUP_ATTRIBUTES,
+            &[],
+            &self.default_search_attributes,
+        );
+        groups
+            .into_iter()
+            .filter(|g| {
+                user_filter.is_none() || !matches_any_pattern(&self.hidden_groups, &g.display_name)
+            })
+            .map(|u| {
+                make_ldap_search_group_result_entry(
+                    u,
+                    &self.base_dn_str,
+                    &attributes,
+                    &self.attribute_aliases,
+                    user_filter,
+                )
+            })
+            .map(|entry| Ok(LdapOp::SearchResultEntry(entry?)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap_or_else(|e| {
+                vec![make_search_error(
+                    LdapResultCode::NoSuchAttribute,
+                    e.to_string(),
+                )]
+            })
+    }
+
+    pub async fn handle_ldap_message(&mut self, ldap_op: LdapOp) -> Option<Vec<LdapOp>> {
+        let operation = get_operation_name(&ldap_op);
+        if self
+            .disabled_operations
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(operation))
+        {
+            info!(
+                r#"Rejected disabled LDAP operation "{}" from "{}""#,
+                operation, self.dn.0
+            );
+            return Some(vec![make_error_response_for_op(
+                &ldap_op,
+                LdapResultCode::UnwillingToPerform,
+                format!("The {} operation is disabled on this server", operation),
+            )]);
+        }
+        Some(match ldap_op {
+            LdapOp::BindRequest(request) => {
+                let (code, message) = self.do_bind(&request).await;
+                LDAP_BINDS.inc();
+                if code != LdapResultCode::Success {
+                    LDAP_BIND_FAILURES.inc();
+                }
+                vec![make_bind_response(code, message)]
+            }
+            LdapOp::SearchRequest(request) => {
+                LDAP_SEARCHES.inc();
+                self.do_search(&request).await
+            }
+            LdapOp::UnbindRequest => {
+                self.dn = LdapDn("unauthenticated".to_string());
+                self.user_id = UserId::new("unauthenticated");
+                // No need to notify on unbind (per rfc4511)
+                return None;
+            }
+            LdapOp::ExtendedRequest(request) => self.do_extended_request(&request).await,
+            LdapOp::AddRequest(request) => {
+                LDAP_MODIFICATIONS.inc();
+                self.do_add(&request).await
+            }
+            LdapOp::DelRequest(dn) => {
+                LDAP_MODIFICATIONS.inc();
+                self.do_delete(&dn).await
+            }
+            LdapOp::ModifyRequest(request) => {
+                LDAP_MODIFICATIONS.inc();
+                self.do_modify(&request).await
+            }
+            LdapOp::ModifyDNRequest(request) => {
+                LDAP_MODIFICATIONS.inc();
+                self.do_modify_dn(&request).await
+            }
+            op => vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                format!("Unsupported operation: {:#?}", op),
+            )],
+        })
+    }
+
+    fn compile_filter(&self, filter: &LdapFilter) -> CompiledFilter {
+        CompiledFilter {
+            user_filter: self
+                .convert_user_filter(filter)
+                .map_err(|e| format!("{:#}", e)),
+            group_filter: self
+                .convert_group_filter(filter)
+                .map_err(|e| format!("{:#}", e)),
+        }
+    }
+
+    /// Compiles the filter, going through the cache if it's enabled.
+    fn get_compiled_filter(&self, filter: &LdapFilter) -> CompiledFilter {
+        let cache = match &self.filter_cache.0 {
+            Some(cache) if is_pure_filter(filter) => cache,
+            _ => return self.compile_filter(filter),
+        };
+        let key = FilterKey::new(filter);
+        if let Some(compiled) = cache.lock().unwrap().get(&key) {
+            LDAP_FILTER_CACHE_HITS.inc();
+            return compiled.clone();
+        }
+        LDAP_FILTER_CACHE_MISSES.inc();
+        let compiled = self.compile_filter(filter);
+        cache.lock().unwrap().put(key, compiled.clone());
+        compiled
+    }
+
+    /// Replaces the transitive membership assertions on `member`/`uniqueMember` with the list of
+    /// groups containing the user, as returned by the backend.
+    fn resolve_in_chain_filter<'a>(
+        &'a self,
+        filter: &'a LdapFilter,
+    ) -> LocalBoxFuture<'a, Result<LdapFilter>> {
+        async move {
+            Ok(match filter {
+                LdapFilter::And(filters) => {
+                    let mut resolved = Vec::with_capacity(filters.len());
+                    for f in filters {
+                        resolved.push(self.resolve_in_chain_filter(f).await?);
+                    }
+                    LdapFilter::And(resolved)
+                }
+                LdapFilter::Or(filters) => {
+                    let mut resolved = Vec::with_capacity(filters.len());
+                    for f in filters {
+                        resolved.push(self.resolve_in_chain_filter(f).await?);
+                    }
+                    LdapFilter::Or(resolved)
+                }
+                LdapFilter::Not(f) => {
+                    LdapFilter::Not(Box::new(self.resolve_in_chain_filter(f).await?))
+                }
+                LdapFilter::Equality(field, value) => {
+                    let (attribute, rule) = split_matching_rule(field);
+                    let attribute = attribute.to_lowercase();
+                    if rule.is_none() {
+                        return Ok(filter.clone());
+                    }
+                    if rule != Some(LDAP_MATCHING_RULE_IN_CHAIN)
+                        || (attribute != "member" && attribute != "uniquemember")
+                    {
+                        bail!("Unsupported matching rule: {}", field);
+                    }
+                    let user = get_user_id_from_distinguished_name(
+                        value,
+                        &self.base_dn,
+                        &self.base_dn_str,
+                        self.ignore_dn_value_case,
+                    )?;
+                    let mut group_names = self
+                        .backend_handler
+                        .get_groups_containing_user_recursive(&user)
+                        .await?
+                        .into_iter()
+                        .map(|g| g.1)
+                        .collect::<Vec<_>>();
+                    if group_names.is_empty() {
+                        // An empty "or" would match everything.
+                        LdapFilter::Not(Box::new(LdapFilter::And(vec![])))
+                    } else {
+                        group_names.sort();
+                        LdapFilter::Or(
+                            group_names
+                                .into_iter()
+                                .map(|name| LdapFilter::Equality("cn".to_string(), name))
+                                .collect(),
+                        )
+                    }
+                }
+                f => f.clone(),
+            })
+        }
+        .boxed_local()
+    }
+
+    /// Replaces the substring assertions on `memberOf` with the list of matching groups.
+    fn resolve_member_of_filter<'a>(
+        &'a self,
+        filter: &'a LdapFilter,
+    ) -> LocalBoxFuture<'a, Result<LdapFilter>> {
+        async move {
+            Ok(match filter {
+                LdapFilter::And(filters) => {
+                    let mut resolved = Vec::with_capacity(filters.len());
+                    for f in filters {
+                        resolved.push(self.resolve_member_of_filter(f).await?);
+                    }
+                    LdapFilter::And(resolved)
+                }
+                LdapFilter::Or(filters) => {
+                    let mut resolved = Vec::with_capacity(filters.len());
+                    for f in filters {
+                        resolved.push(self.resolve_member_of_filter(f).await?);
+                    }
+                    LdapFilter::Or(resolved)
+                }
+                LdapFilter::Not(f) => {
+                    LdapFilter::Not(Box::new(self.resolve_member_of_filter(f).await?))
+                }
+                LdapFilter::Substring(field, substring)
+                    if field.eq_ignore_ascii_case("memberof") =>
+                {
+                    let group_dns = self
+                        .backend_handler
+                        .list_groups(None)
+                        .await?
+                        .into_iter()
+                        .map(|g| make_group_dn(&g.display_name, &self.base_dn_str))
+                        .filter(|dn| matches_substring_filter(substring, dn))
+                        .collect::<Vec<_>>();
+                    if group_dns.is_empty() {
+                        // An empty "or" would match everything.
+                        LdapFilter::Not(Box::new(LdapFilter::And(vec![])))
+                    } else {
+                        LdapFilter::Or(
+                            group_dns
+                                .into_iter()
+                                .map(|dn| LdapFilter::Equality("memberOf".to_string(), dn))
+                                .collect(),
+                        )
+                    }
+                }
+                f => f.clone(),
+            })
+        }
+        .boxed_local()
+    }
+
+    /// Makes the `memberOf` assertions on hidden groups match nobody, so that the users can't find
+    /// out whether they are members of one.
+    fn hide_member_of_hidden_groups(&self, filter: LdapFilter) -> LdapFilter {
+        match filter {
+            LdapFilter::And(filters) => LdapFilter::And(
+                filters
+                    .into_iter()
+                    .map(|f| self.hide_member_of_hidden_groups(f))
+                    .collect(),
+            ),
+            LdapFilter::Or(filters) => LdapFilter::Or(
+                filters
+                    .into_iter()
+                    .map(|f| self.hide_member_of_hidden_groups(f))
+                    .collect(),
+            ),
+            LdapFilter::Not(f) => LdapFilter::Not(Box::new(self.hide_member_of_hidden_groups(*f))),
+            LdapFilter::Equality(field, value)
+                if split_matching_rule(&field)
+                    .0
+                    .eq_ignore_ascii_case("memberof") =>
+            {
+                match get_group_id_from_distinguished_name(
+                    &value,
+                    &self.base_dn,
+                    &self.base_dn_str,
+                    self.ignore_dn_value_case,
+                ) {
+                    Ok(group_name) if matches_any_pattern(&self.hidden_groups, &group_name) => {
+                        LdapFilter::Not(Box::new(LdapFilter::And(vec![])))
+                    }
+                    _ => LdapFilter::Equality(field, value),
+                }
+            }
+            f => f,
+        }
+    }
+
+    fn convert_group_filter(&self, filter: &LdapFilter) -> Result<GroupRequestFilter> {
+        match filter {
+            LdapFilter::Equality(field, value) => {
+                if field == "member" || field.to_lowercase() == "uniquemember" {
+                    let user_name = get_user_id_from_distinguished_name(
+                        value,
+                        &self.base_dn,
+                        &self.base_dn_str,
+                        self.ignore_dn_value_case,
+                    )?;
+                    Ok(GroupRequestFilter::Member(user_name))
+                } else if field.to_lowercase() == "objectclass" {
+                    if value == "groupOfUniqueNames" || value == "groupOfNames" {
+                        Ok(GroupRequestFilter::And(vec![]))
+                    } else {
+                        Ok(GroupRequestFilter::Not(Box::new(GroupRequestFilter::And(
+                            vec![],
+                        ))))
+                    }
+                } else {
+                    let field = map_field(field)?;
+                    if field == "display_name" {
+                        Ok(GroupRequestFilter::DisplayName(value.clone()))
+                    } else {
+                        bail!("Unsupported group attribute: {:?}", field)
+                    }
+                }
+            }
+            LdapFilter::And(filters) => Ok(GroupRequestFilter::And(
+                filters
+                    .iter()
+                    .map(|f| self.convert_group_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Or(filters) => Ok(GroupRequestFilter::Or(
+                filters
+                    .iter()
+                    .map(|f| self.convert_group_filter(f))
+                    .collect::<Result<_>>()?,
+            )),
+            LdapFilter::Not(filter) => Ok(GroupRequestFilter::Not(Box::new(
+                self.convert_group_filter(&*filter)?,
+            ))),
+            _ => bail!("Unsupported group filter: {:?}", filter),
+        }
+    }
+
+    fn convert_user_filter(&self, filter: &LdapFilter) -> Result<UserRequestFilter> {
+        convert_user_filter(
+            filter,
+            &self.base_dn,
+            &self.base_dn_str,
+            self.ignore_dn_value_case,
+        )
+    }
+}
+
+/// Converts the LDAP filter into a filter on the users, with the DNs relative to the base DN.
+pub(crate) fn convert_user_filter(
+    filter: &LdapFilter,
+    base_dn: &[(String, String)],
+    base_dn_str: &str,
+    ignore_dn_value_case: bool,
+) -> Result<UserRequestFilter> {
+    match filter {
+        LdapFilter::And(filters) => Ok(UserRequestFilter::And(
+            filters
+                .iter()
+                .map(|f| convert_user_filter(f, base_dn, base_dn_str, ignore_dn_value_case))
+                .collect::<Result<_>>()?,
+        )),
+        LdapFilter::Or(filters) => Ok(UserRequestFilter::Or(
+            filters
+                .iter()
+                .map(|f| convert_user_filter(f, base_dn, base_dn_str, ignore_dn_value_case))
+                .collect::<Result<_>>()?,
+        )),
+        LdapFilter::Not(filter) => Ok(UserRequestFilter::Not(Box::new(convert_user_filter(
+            &*filter,
+            base_dn,
+            base_dn_str,
+            ignore_dn_value_case,
+        )?))),
+        LdapFilter::Equality(field, value) => {
+            let (attribute, rule) = split_matching_rule(field);
+            // Groups can't be nested, so the transitive membership is the direct one.
+            if attribute.eq_ignore_ascii_case("memberof")
+                && (rule.is_none() || rule == Some(LDAP_MATCHING_RULE_IN_CHAIN))
+            {
+                let group_name = get_group_id_from_distinguished_name(
+                    value,
+                    base_dn,
+                    base_dn_str,
+                    ignore_dn_value_case,
+                )?;
+                Ok(UserRequestFilter::MemberOf(group_name))
+            } else if attribute.eq_ignore_ascii_case("manager") {
+                let manager_id = get_user_id_from_distinguished_name(
+                    value,
+                    base_dn,
+                    base_dn_str,
+                    ignore_dn_value_case,
+                )?;
+                Ok(UserRequestFilter::Manager(manager_id))
+            } else if field.to_lowercase() == "maillocaladdress" {
+                Ok(UserRequestFilter::MailAlias(value.clone()))
+            } else if field.to_lowercase() == "objectclass" {
+                if value == "person"
+                    || value == "inetOrgPerson"
+                    || value == "posixAccount"
+                    || value == "mailAccount"
+                    || value == "inetLocalMailRecipient"
+                    || value == "shadowAccount"
+                {
+                    Ok(UserRequestFilter::And(vec![]))
+                } else {
+                    Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+                        vec![],
+                    ))))
+                }
+            } else {
+                let field = map_field(field)?;
+                if field == "user_id" {
+                    Ok(UserRequestFilter::UserId(UserId::new(value)))
+                } else {
+                    Ok(UserRequestFilter::Equality(field, value.clone()))
+                }
+            }
+        }
+        LdapFilter::Present(field) if field.eq_ignore_ascii_case("memberof") => {
+            Ok(UserRequestFilter::MemberOfAnyGroup)
+        }
+        LdapFilter::Present(field) if field.eq_ignore_ascii_case("manager") => {
+            Ok(UserRequestFilter::Present("manager_user_id".to_string()))
+        }
+        LdapFilter::Present(field) if field.eq_ignore_ascii_case("maillocaladdress") => {
+            Ok(UserRequestFilter::Present("mail_aliases".to_string()))
+        }
+        LdapFilter::Present(field) if field.eq_ignore_ascii_case("mailforwardingaddress") => {
+            Ok(UserRequestFilter::Present("mail_forwarding".to_string()))
+        }
+        LdapFilter::Present(field) => {
+            // Check that it's a field we support.
+            if field.to_lowercase() == "objectclass" {
+                Ok(UserRequestFilter::And(vec![]))
+            } else {
+                match map_field(field) {
+                    // The user id is always set.
+                    Ok(field) if field == "user_id" => Ok(UserRequestFilter::And(vec![])),
+                    Ok(field) => Ok(UserRequestFilter::Present(field)),
+                    Err(_) => Ok(UserRequestFilter::Not(Box::new(UserRequestFilter::And(
+                        vec![],
+                    )))),
+                }
+            }
+        }
+        LdapFilter::Substring(field, filter) => Ok(UserRequestFilter::SubString(
+            map_field(field)?,
+            SubStringFilter {
+                initial: filter.initial.clone(),
+                any: filter.any.clone(),
+                final_: filter.final_.clone(),
+            },
+        )),
+        _ => bail!("Unsupported user filter: {:?}", filter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{error::Result, handler::*, opaque_handler::*};
+    use async_trait::async_trait;
+    use ldap3_server::proto::{LdapDerefAliases, LdapSearchScope};
+    use mockall::predicate::{always, eq};
+    use std::collections::{HashMap, HashSet};
+    use tokio;
+
+    mockall::mock! {
+        pub TestBackendHandler{}
+        impl Clone for TestBackendHandler {
+            fn clone(&self) -> Self;
+        }
+        #[async_trait]
+        impl LoginHandler for TestBackendHandler {
+            async fn bind(&self, request: BindRequest) -> Result<()>;
+            async fn get_grace_logins_remaining(&self, user_id: &UserId) -> Result<i32>;
+            async fn has_password(&self, user_id: &UserId) -> Result<bool>;
+            async fn check_password(&self, user_id: &UserId, password: &str) -> Result<bool>;
+        }
+        #[async_trait]
+        impl BackendHandler for TestBackendHandler {
+            async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+            async fn list_users_sorted(&self, filters: Option<UserRequestFilter>, sort: UserSort, page: Option<Page>) -> Result<Vec<User>>;
+            async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+            async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<u64>;
+            async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<u64>;
+            async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
+            async fn get_reports(&self, manager_id: &UserId) -> Result<Vec<User>>;
+            async fn find_user_by_email_alias(&self, alias: &str) -> Result<Option<User>>;
+            async fn find_user_by_certificate_cn(&self, cn: &str) -> Result<Option<User>>;
+            async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
+            async fn get_user_groups(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
+            async fn get_groups_for_users(
+                &self,
+                user_ids: &[UserId],
+            ) -> Result<HashMap<UserId, HashSet<GroupIdAndName>>>;
+            async fn get_groups_containing_user_recursive(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
+            fn clear_membership_cache(&self);
+            fn data_version(&self) -> u64;
+            async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
+            async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
+            async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
+            async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+            async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
+            async fn add_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+            async fn remove_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+            async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()>;
+            async fn update_last_login(&self, user_id: &UserId, timestamp: chrono::DateTime<chrono::Utc>) -> Result<()>;
+            async fn flush_last_logins(&self) -> Result<usize>;
+            async fn get_login_report(&self, since: chrono::NaiveDate, page: Page) -> Result<Vec<UserLoginStats>>;
+            async fn create_group(&self, group_name: &str) -> Result<GroupId>;
+            async fn delete_group(&self, group_id: GroupId) -> Result<()>;
+            async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+            async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+            async fn create_invitation(&self, email: &str) -> Result<Invitation>;
+            async fn get_invitation(&self, token: &str) -> Result<Invitation>;
+            async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool>;
+            async fn list_pending_invitations(&self) -> Result<Vec<Invitation>>;
+        }
+        #[async_trait]
+        impl OpaqueHandler for TestBackendHandler {
+            async fn login_start(
+                &self,
+                request: login::ClientLoginStartRequest
+            ) -> Result<login::ServerLoginStartResponse>;
+            async fn login_finish(&self, request: login::ClientLoginFinishRequest) -> Result<UserId>;
+            async fn registration_start(
+                &self,
+                request: registration::ClientRegistrationStartRequest
+            ) -> Result<registration::ServerRegistrationStartResponse>;
+            async fn registration_finish(
+                &self,
+                request: registration::ClientRegistrationFinishRequest
+            ) -> Result<UserId>;
+        }
+    }
+
+    /// The mock has no transactions: the operations run on the mock itself.
+    #[async_trait]
+    impl TransactionHandler for MockTestBackendHandler {
+        type Transaction = Self;
+        async fn transaction<T, F>(&self, f: F) -> Result<T>
+        where
+            T: Send,
+            F: for<'t> FnOnce(
+                    &'t Self::Transaction,
+                ) -> futures_util::future::BoxFuture<'t, Result<T>>
+                + Send,
+        {
+            f(self).await
+        }
+    }
+
+    #[async_trait]
+    impl BackendTransaction for MockTestBackendHandler {
+        async fn get_user_details(&self, user_id: &UserId) -> Result<User> {
+            BackendHandler::get_user_details(self, user_id).await
+        }
+        async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName> {
+            BackendHandler::get_group_details(self, group_id).await
+        }
+        async fn get_user_groups(&self, user_id: &UserId) -> Result<HashSet<GroupIdAndName>> {
+            BackendHandler::get_user_groups(self, user_id).await
+        }
+        async fn create_user(&self, request: CreateUserRequest) -> Result<()> {
+            BackendHandler::create_user(self, request).await
+        }
+        async fn update_user(&self, request: UpdateUserRequest) -> Result<()> {
+            BackendHandler::update_user(self, request).await
+        }
+        async fn delete_user(&self, user_id: &UserId) -> Result<()> {
+            BackendHandler::delete_user(self, user_id).await
+        }
+        async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()> {
+            BackendHandler::rename_user(self, user_id, new_user_id).await
+        }
+        async fn set_phone_numbers(
+            &self,
+            user_id: &UserId,
+            phone_numbers: Vec<String>,
+        ) -> Result<()> {
+            BackendHandler::set_phone_numbers(self, user_id, phone_numbers).await
+        }
+        async fn create_group(&self, group_name: &str) -> Result<GroupId> {
+            BackendHandler::create_group(self, group_name).await
+        }
+        async fn update_group(&self, request: UpdateGroupRequest) -> Result<()> {
+            BackendHandler::update_group(self, request).await
+        }
+        async fn delete_group(&self, group_id: GroupId) -> Result<()> {
+            BackendHandler::delete_group(self, group_id).await
+        }
+        async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+            BackendHandler::add_user_to_group(self, user_id, group_id).await
+        }
+        async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()> {
+            BackendHandler::remove_user_from_group(self, user_id, group_id).await
+        }
+        async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool> {
+            BackendHandler::mark_invitation_used(self, invitation_id).await
+        }
+    }
+
+    fn make_search_request<S: Into<String>>(
+        base: &str,
+        filter: LdapFilter,
+        attrs: Vec<S>,
+    ) -> LdapSearchRequest {
+        LdapSearchRequest {
+            base: base.to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter,
+            attrs: attrs.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn make_user_search_request<S: Into<String>>(
+        filter: LdapFilter,
+        attrs: Vec<S>,
+    ) -> LdapSearchRequest {
+        make_search_request::<S>("ou=people,dc=example,dc=com", filter, attrs)
+    }
+
+    async fn setup_bound_handler(
+        mut mock: MockTestBackendHandler,
+    ) -> LdapHandler<MockTestBackendHandler> {
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .return_once(|_| Ok(()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        ldap_handler
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login()
+            .with(eq(UserId::new("bob")), always())
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
+
+        let request = LdapOp::BindRequest(LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![LdapOp::BindResponse(LdapBindResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                saslcreds: None,
+            })]),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_mixed_case_dn() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .times(2)
+            .returning(|_| Ok(()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
+        let request = LdapBindRequest {
+            dn: "UID=Test,OU=People,DC=Example,DC=COM".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        // Bound as the admin, not as an unknown DN.
+        assert_eq!(ldap_handler.dn, ldap_handler.ldap_user_dn);
+        let request = LdapBindRequest {
+            dn: " uid = test , ou = people , dc = example , Dc = com ".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(ldap_handler.dn, ldap_handler.ldap_user_dn);
+    }
+
+    #[tokio::test]
+    async fn test_bind_dn_exact_value_case() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let config = LdapHandlerConfig {
+            ignore_dn_value_case: false,
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        // The attribute types and the user ID are still case-insensitive.
+        let request = LdapBindRequest {
+            dn: "UID=Bob,OU=people,DC=example,DC=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=People,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_with_config() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("admin"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let config = LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"));
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=admin,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        // The admin is not restricted to its own user.
+        let request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request))
+                .await,
+            Some(vec![make_search_success()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_hidden_entries() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().returning(|_| Ok(()));
+        mock.expect_list_groups().times(2).returning(|_| {
+            Ok(vec![
+                Group {
+                    id: GroupId(1),
+                    display_name: "lldap_admin".to_string(),
+                    description: None,
+                    users: vec![UserId::new("admin"), UserId::new("bob")],
+                },
+                Group {
+                    id: GroupId(2),
+                    display_name: "Family".to_string(),
+                    description: None,
+                    users: vec![UserId::new("bob")],
+                },
+            ])
+        });
+        let config = LdapHandlerConfig {
+            hidden_groups: vec!["LLDAP_*".to_string()],
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["1.1"],
+        );
+        let bind_request = |user: &str| LdapBindRequest {
+            dn: format!("uid={},ou=people,dc=example,dc=com", user),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        ldap_handler.do_bind(&bind_request("bob")).await;
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=Family,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success(),
+            ]
+        );
+        // The admin still sees the hidden groups.
+        ldap_handler.do_bind(&bind_request("admin")).await;
+        assert_eq!(ldap_handler.do_search(&request).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_search_hidden_users() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().returning(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::UserId(UserId::new("svc_mail")),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("svc_mail"),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    User {
+                        user_id: UserId::new("svc_mail"),
+                        ..Default::default()
+                    },
+                    User {
+                        user_id: UserId::new("bob"),
+                        ..Default::default()
+                    },
+                ])
+            });
+        let config = LdapHandlerConfig {
+            hidden_users: vec!["svc_*".to_string()],
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = make_search_request(
+            "ou=people,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["1.1"],
+        );
+        let bind_request = |user: &str| LdapBindRequest {
+            dn: format!("uid={},ou=people,dc=example,dc=com", user),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        // Hidden users still see their own entry.
+        ldap_handler.do_bind(&bind_request("svc_mail")).await;
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=svc_mail,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success(),
+            ]
+        );
+        ldap_handler.do_bind(&bind_request("admin")).await;
+        assert_eq!(ldap_handler.do_search(&request).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_search_member_of_hidden_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().returning(|_| Ok(()));
+        mock.expect_list_groups().times(1).returning(|_| {
+            Ok(vec![
+                Group {
+                    id: GroupId(1),
+                    display_name: "lldap_admin".to_string(),
+                    description: None,
+                    users: vec![UserId::new("bob")],
+                },
+                Group {
+                    id: GroupId(2),
+                    display_name: "lldap_password_manager".to_string(),
+                    description: None,
+                    users: vec![UserId::new("bob")],
+                },
+            ])
+        });
+        let nobody = || UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![])));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Or(vec![
+                    nobody(),
+                    UserRequestFilter::Or(vec![nobody(), nobody()]),
+                ]),
+                UserRequestFilter::UserId(UserId::new("bob")),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let config = LdapHandlerConfig {
+            hidden_groups: vec!["lldap_*".to_string()],
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        ldap_handler
+            .do_bind(&LdapBindRequest {
+                dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                cred: LdapBindCred::Simple("pass".to_string()),
+            })
+            .await;
+        let request = make_search_request(
+            "ou=people,dc=example,dc=com",
+            LdapFilter::Or(vec![
+                LdapFilter::Equality(
+                    "memberOf".to_string(),
+                    "cn=lldap_admin,ou=groups,dc=example,dc=com".to_string(),
+                ),
+                LdapFilter::Substring(
+                    "memberOf".to_string(),
+                    LdapSubstringFilter {
+                        initial: Some("cn=lldap_".to_string()),
+                        any: vec![],
+                        final_: None,
+                    },
+                ),
+            ]),
+            vec!["1.1"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
+    #[test]
+    fn test_matches_any_pattern() {
+        let patterns = vec!["svc_*".to_string(), "backup".to_string()];
+        assert!(matches_any_pattern(&patterns, "svc_mail"));
+        assert!(matches_any_pattern(&patterns, "SVC_"));
+        assert!(matches_any_pattern(&patterns, "Backup"));
+        assert!(!matches_any_pattern(&patterns, "backups"));
+        assert!(!matches_any_pattern(&patterns, "my_svc_mail"));
+        assert!(matches_any_pattern(&["*".to_string()], ""));
+        assert!(!matches_any_pattern(&[], "bob"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_unbind() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::UnbindRequest)
+                .await,
+            None
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("unauthenticated"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_unsupported_operation() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let op = LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Success,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(op.clone()).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                format!("Unsupported operation: {:#?}", op),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_bind() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login()
+            .with(eq(UserId::new("bob")), always())
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "wrong".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Err(DomainError::AuthenticationError("wrong".to_string())));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
+        let bind = |password: &str| {
+            LdapOp::BindRequest(LdapBindRequest {
+                dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                cred: LdapBindCred::Simple(password.to_string()),
+            })
+        };
+        assert_eq!(
+            ldap_handler.handle_ldap_message(bind("wrong")).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::InvalidCredentials,
+                "".to_string()
+            )])
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("unauthenticated"));
+        assert_eq!(
+            ldap_handler.handle_ldap_message(bind("pass")).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )])
+        );
+        assert_eq!(ldap_handler.user_id, UserId::new("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_search() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "mail"]);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request))
+                .await,
+            Some(vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["bob@example.com".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_password_modify_maintenance_mode() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        let config = LdapHandlerConfig {
+            maintenance_mode: MaintenanceMode::new(true),
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapOp::BindRequest(LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_bind_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )])
+        );
+        // No registration with the backend.
+        let request = LdapOp::ExtendedRequest(
+            LdapPasswordModifyRequest {
+                user_identity: Some("uid=bob,ou=people,dc=example,dc=com".to_string()),
+                old_password: None,
+                new_password: Some("password".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "The server is in read-only maintenance mode".to_string(),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_operations() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        let config = LdapHandlerConfig {
+            disabled_operations: vec!["Search".to_string(), "extended".to_string()],
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::SearchRequest(request))
+                .await,
+            Some(vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "The search operation is disabled on this server".to_string(),
+            )])
+        );
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: "1.3.6.1.4.1.4203.1.11.1".to_string(),
+            value: None,
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::UnwillingToPerform,
+                "The extended operation is disabled on this server".to_string(),
+            )])
+        );
+    }
+
+    fn make_add_request(dn: &str, attributes: Vec<(&str, Vec<&str>)>) -> LdapAddRequest {
+        LdapAddRequest {
+            dn: dn.to_string(),
+            attributes: attributes
+                .into_iter()
+                .map(|(atype, vals)| LdapPartialAttribute {
+                    atype: atype.to_string(),
+                    vals: vals.into_iter().map(str::to_string).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_user() {
+        let mut mock = MockTestBackendHandler::new();
+        use lldap_auth::*;
+        let mut rng = rand::rngs::OsRng;
+        let registration_start_request =
+            opaque::client::registration::start_registration("password", &mut rng).unwrap();
+        let start_response = opaque::server::registration::start_registration(
+            &opaque::server::ServerSetup::new(&mut rng),
+            registration_start_request.message,
+            "bob",
+        )
+        .unwrap();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::UserId(UserId::new("bob")))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_create_user()
+            .with(eq(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "bob@bob.bob".to_string(),
+                display_name: Some("Bob Bobberson".to_string()),
+                first_name: None,
+                last_name: Some("Bobberson".to_string()),
+                mail_aliases: vec!["bobby@bob.bob".to_string()],
+                mail_forwarding: vec![],
+                phone_numbers: vec!["+1 415 555 0123".to_string()],
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_registration_start().times(1).return_once(|_| {
+            Ok(registration::ServerRegistrationStartResponse {
+                server_data: "".to_string(),
+                registration_response: start_response.message,
+            })
+        });
+        mock.expect_registration_finish()
+            .times(1)
+            .return_once(|_| Ok(UserId::new("bob")));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_add_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            vec![
+                ("objectClass", vec!["top", "inetOrgPerson"]),
+                ("uid", vec!["bob"]),
+                ("mail", vec!["bob@bob.bob"]),
+                ("cn", vec!["Bob Bobberson"]),
+                ("sn", vec!["Bobberson"]),
+                ("mailLocalAddress", vec!["bobby@bob.bob"]),
+                ("telephoneNumber", vec!["+1 415 555 0123"]),
+                ("userPassword", vec!["password"]),
+            ],
+        );
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::AddRequest(request))
+                .await,
+            Some(vec![make_add_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )])
+        );
+    }
+
+    fn make_modify_request(dn: &str, changes: Vec<(LdapModifyType, &str, Vec<&str>)>) -> LdapOp {
+        LdapOp::ModifyRequest(LdapModifyRequest {
+            dn: dn.to_string(),
+            changes: changes
+                .into_iter()
+                .map(|(operation, atype, vals)| LdapModify {
+                    operation,
+                    modification: LdapPartialAttribute {
+                        atype: atype.to_string(),
+                        vals: vals.into_iter().map(str::to_string).collect(),
+                    },
+                })
+                .collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_modify_phone_numbers() {
+        let mut mock = MockTestBackendHandler::new();
+        let bob = UserId::new("bob");
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::UserId(bob.clone()))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                }])
+            });
+        let mut seq = mockall::Sequence::new();
+        mock.expect_set_phone_numbers()
+            .with(eq(bob.clone()), eq(vec!["+14155550123".to_string()]))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(()));
+        mock.expect_add_phone_number()
+            .with(eq(bob.clone()), eq("+33123456789"))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(()));
+        mock.expect_remove_phone_number()
+            .with(eq(bob.clone()), eq("+14155550123"))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(()));
+        mock.expect_set_phone_numbers()
+            .with(eq(bob), eq(Vec::<String>::new()))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_modify_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            vec![
+                (
+                    LdapModifyType::Replace,
+                    "telephoneNumber",
+                    vec!["+14155550123"],
+                ),
+                (LdapModifyType::Add, "telephoneNumber", vec!["+33123456789"]),
+                (
+                    LdapModifyType::Delete,
+                    "TelephoneNumber",
+                    vec!["+14155550123"],
+                ),
+                (LdapModifyType::Delete, "telephoneNumber", vec![]),
+            ],
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_modify_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_errors() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        // Nothing is changed when one of the attributes can't be modified.
+        let request = make_modify_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            vec![
+                (LdapModifyType::Add, "telephoneNumber", vec!["+33123456789"]),
+                (LdapModifyType::Replace, "mail", vec!["bob@bob.bob"]),
+            ],
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_modify_response(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported attribute modification: mail".to_string()
+            )])
+        );
+        let request = make_modify_request(
+            "cn=group_1,ou=groups,dc=example,dc=com",
+            vec![(LdapModifyType::Add, "telephoneNumber", vec!["+33123456789"])],
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await.unwrap()[0],
+            make_modify_response(
+                LdapResultCode::UnwillingToPerform,
+                r#"Only user entries can be modified: "cn=group_1,ou=groups,dc=example,dc=com""#
+                    .to_string()
+            )
+        );
+        let request = make_modify_request(
+            "uid=nobody,ou=people,dc=example,dc=com",
+            vec![(LdapModifyType::Add, "telephoneNumber", vec!["+33123456789"])],
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await.unwrap()[0],
+            make_modify_response(
+                LdapResultCode::NoSuchObject,
+                r#"No such entry: "uid=nobody,ou=people,dc=example,dc=com""#.to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "group_1".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_create_group()
+            .with(eq("group_1"))
+            .times(1)
+            .return_once(|_| Ok(GroupId(3)));
+        mock.expect_add_user_to_group()
+            .with(eq(UserId::new("bob")), eq(GroupId(3)))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_add_request(
+            "cn=group_1,ou=groups,dc=example,dc=com",
+            vec![
+                ("objectClass", vec!["groupOfUniqueNames"]),
+                ("cn", vec!["group_1"]),
+                ("uniqueMember", vec!["uid=bob,ou=people,dc=example,dc=com"]),
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_add(&request).await,
+            vec![make_add_response(LdapResultCode::Success, "".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_errors() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_add_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            vec![
+                ("objectClass", vec!["inetOrgPerson"]),
+                ("mail", vec!["bob@bob.bob"]),
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_add(&request).await,
+            vec![make_add_response(
+                LdapResultCode::EntryAlreadyExists,
+                r#"User "bob" already exists"#.to_string()
+            )]
+        );
+        let request = make_add_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            vec![("objectClass", vec!["inetOrgPerson"])],
+        );
+        assert_eq!(
+            ldap_handler.do_add(&request).await,
+            vec![make_add_response(
+                LdapResultCode::ObjectClassViolation,
+                "Missing required attribute: mail".to_string()
+            )]
+        );
+        let request = make_add_request(
+            "cn=printer,ou=devices,dc=example,dc=com",
+            vec![("objectClass", vec!["device"])],
+        );
+        assert_eq!(
+            ldap_handler.do_add(&request).await,
+            vec![make_add_response(
+                LdapResultCode::ObjectClassViolation,
+                r#"Unsupported object classes: ["device"]"#.to_string()
+            )]
+        );
+        let request = make_add_request(
+            "uid=bob,ou=people,dc=example,dc=com",
+            vec![
+                ("objectClass", vec!["inetOrgPerson"]),
+                ("uid", vec!["mallory"]),
+                ("mail", vec!["bob@bob.bob"]),
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_add(&request).await,
+            vec![make_add_response(
+                LdapResultCode::NamingViolation,
+                r#"The uid doesn't match the DN "uid=bob,ou=people,dc=example,dc=com""#.to_string()
+            )]
+        );
+        let request = make_add_request(
+            "cn=group_1,ou=groups,dc=example,dc=com",
+            vec![
+                ("objectClass", vec!["groupOfNames"]),
+                ("cn", vec!["group_2"]),
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_add(&request).await,
+            vec![make_add_response(
+                LdapResultCode::NamingViolation,
+                r#"The cn doesn't match the DN "cn=group_1,ou=groups,dc=example,dc=com""#
+                    .to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_group_member_error() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_create_group()
+            .with(eq("group_1"))
+            .times(1)
+            .return_once(|_| Ok(GroupId(3)));
+        mock.expect_add_user_to_group()
+            .with(eq(UserId::new("nobody")), eq(GroupId(3)))
+            .times(1)
+            .return_once(|_, _| Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_add_request(
+            "cn=group_1,ou=groups,dc=example,dc=com",
+            vec![
+                ("objectClass", vec!["groupOfNames"]),
+                ("member", vec!["uid=nobody,ou=people,dc=example,dc=com"]),
+            ],
+        );
+        // The whole creation fails, rather than leaving a group without its members.
+        assert_eq!(
+            ldap_handler.do_add(&request).await[0],
+            make_add_response(
+                LdapResultCode::Other,
+                "Error while creating the group: Database error: `no rows returned by a query that expected to return at least one row`".to_string()
+            )
+        );
+    }
+
+    fn make_modify_dn_request(dn: &str, newrdn: &str) -> LdapModifyDNRequest {
+        LdapModifyDNRequest {
+            dn: dn.to_string(),
+            newrdn: newrdn.to_string(),
+            deleteoldrdn: true,
+            new_superior: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_modify_dn_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::UserId(UserId::new("bob")))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                }])
+            });
+        mock.expect_rename_user()
+            .with(eq(UserId::new("bob")), eq(UserId::new("robert")))
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = LdapModifyDNRequest {
+            new_superior: Some("ou=people,dc=example,dc=com".to_string()),
+            ..make_modify_dn_request("uid=bob,ou=people,dc=example,dc=com", "uid=robert")
+        };
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::ModifyDNRequest(request))
+                .await,
+            Some(vec![make_modify_dn_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_dn_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "group_1".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(3),
+                    display_name: "group_1".to_string(),
+                    description: None,
+                    users: vec![],
+                }])
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "group_2".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_update_group()
+            .with(eq(UpdateGroupRequest {
+                group_id: GroupId(3),
+                display_name: Some("group_2".to_string()),
+                description: None,
+                dynamic_filter: None,
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&make_modify_dn_request(
+                    "cn=group_1,ou=groups,dc=example,dc=com",
+                    "cn=group_2"
+                ))
+                .await,
+            vec![make_modify_dn_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_dn_errors() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = LdapModifyDNRequest {
+            new_superior: Some("ou=groups,dc=example,dc=com".to_string()),
+            ..make_modify_dn_request("uid=bob,ou=people,dc=example,dc=com", "uid=bob")
+        };
+        assert_eq!(
+            ldap_handler.do_modify_dn(&request).await,
+            vec![make_modify_dn_response(
+                LdapResultCode::UnwillingToPerform,
+                "Moving entries to another subtree is not supported".to_string()
+            )]
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&make_modify_dn_request(
+                    "uid=test,ou=people,dc=example,dc=com",
+                    "uid=admin"
+                ))
+                .await,
+            vec![make_modify_dn_response(
+                LdapResultCode::UnwillingToPerform,
+                "Cannot rename the admin user".to_string()
+            )]
+        );
+        assert_eq!(
+            ldap_handler
+                .do_modify_dn(&make_modify_dn_request(
+                    "uid=bob,ou=people,dc=example,dc=com",
+                    "mail=bob@bob.bob"
+                ))
+                .await,
+            vec![make_modify_dn_response(
+                LdapResultCode::NamingViolation,
+                r#"Users are named by "uid", got "mail""#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| {
+                Ok(User {
+                    user_id: UserId::new("bob"),
+                    ..Default::default()
+                })
+            });
+        mock.expect_delete_user()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        assert_eq!(
+            ldap_handler
+                .handle_ldap_message(LdapOp::DelRequest(
+                    "uid=bob,ou=people,dc=example,dc=com".to_string()
+                ))
+                .await,
+            Some(vec![make_del_response(
+                LdapResultCode::Success,
+                "".to_string()
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "group_1".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(3),
+                    display_name: "group_1".to_string(),
+                    description: None,
+                    users: vec![],
+                }])
+            });
+        mock.expect_get_group_details()
+            .with(eq(GroupId(3)))
+            .times(1)
+            .return_once(|_| Ok(GroupIdAndName(GroupId(3), "group_1".to_string())));
+        mock.expect_delete_group()
+            .with(eq(GroupId(3)))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        assert_eq!(
+            ldap_handler
+                .do_delete("cn=group_1,ou=groups,dc=example,dc=com")
+                .await,
+            vec![make_del_response(LdapResultCode::Success, "".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_errors() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_details()
+            .times(1)
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)));
+        mock.expect_list_groups()
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        assert_eq!(
+            ldap_handler
+                .do_delete("uid=bob,ou=people,dc=example,dc=com")
+                .await,
+            vec![make_del_response(
+                LdapResultCode::NoSuchObject,
+                r#"No such entry: "uid=bob,ou=people,dc=example,dc=com""#.to_string()
+            )]
+        );
+        assert_eq!(
+            ldap_handler
+                .do_delete("cn=group_1,ou=groups,dc=example,dc=com")
+                .await,
+            vec![make_del_response(
+                LdapResultCode::NoSuchObject,
+                r#"No such entry: "cn=group_1,ou=groups,dc=example,dc=com""#.to_string()
+            )]
+        );
+        assert_eq!(
+            ldap_handler.do_delete("ou=people,dc=example,dc=com").await,
+            vec![make_del_response(
+                LdapResultCode::NoSuchObject,
+                r#"Not a user or group DN: "ou=people,dc=example,dc=com""#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_current_user_or_admin_group() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::DisplayName(
+                "lldap_admin".to_string(),
+            ))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    id: GroupId(1),
+                    display_name: "lldap_admin".to_string(),
+                    description: None,
+                    users: vec![],
+                }])
+            });
+        mock.expect_delete_user().never();
+        mock.expect_delete_group().never();
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        assert_eq!(
+            ldap_handler
+                .do_delete("uid=test,ou=people,dc=example,dc=com")
+                .await,
+            vec![make_del_response(
+                LdapResultCode::UnwillingToPerform,
+                "Cannot delete the current user".to_string()
+            )]
+        );
+        assert_eq!(
+            ldap_handler
+                .do_delete("cn=lldap_admin,ou=groups,dc=example,dc=com")
+                .await,
+            vec![make_del_response(
+                LdapResultCode::UnwillingToPerform,
+                "Cannot delete the admin group".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_not_admin() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            ldap_handler
+                .do_delete("uid=bob,ou=people,dc=example,dc=com")
+                .await,
+            vec![make_del_response(
+                LdapResultCode::InsufficentAccessRights,
+                r#"Current user "uid=test,ou=people,dc=example,dc=com" is not allowed to delete entries"#
+                    .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_get_operation_name() {
+        assert_eq!(get_operation_name(&LdapOp::UnbindRequest), "unbind");
+        assert_eq!(
+            get_operation_name(&LdapOp::SearchRequest(make_search_request::<String>(
+                "dc=example,dc=com",
+                LdapFilter::And(vec![]),
+                vec![]
+            ))),
+            "search"
+        );
+        assert_eq!(
+            get_operation_name(&LdapOp::DelRequest("uid=bob,dc=example,dc=com".to_string())),
+            "delete"
+        );
+        assert_eq!(get_operation_name(&LdapOp::AbandonRequest(2)), "abandon");
+        assert_eq!(
+            get_operation_name(&LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            })),
+            "other"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_bind() {
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("admin"),
+        );
+        let request = LdapBindRequest {
+            dn: "uid=admin,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await,
+            (
+                LdapResultCode::UnwillingToPerform,
+                "Unauthenticated binds are not allowed".to_string()
+            )
+        );
+
+        let config = LdapHandlerConfig {
+            allow_unauthenticated_bind: true,
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+        };
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::UserId(UserId::new("unauthenticated")),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        // The session is anonymous, not bound as the admin.
+        let request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_requires_tls() {
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        let config = LdapHandlerConfig {
+            require_tls_for_password_bind: true,
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+        };
+        // The password is not checked on a plaintext connection.
+        let mut ldap_handler =
+            LdapHandler::new_with_config(config.clone(), MockTestBackendHandler::new());
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::ConfidentialityRequired
+        );
+
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        let mut ldap_handler = LdapHandler::new_with_config(
+            LdapHandlerConfig {
+                is_tls: true,
+                ..config
+            },
+            mock,
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_upstream_fallback() {
+        let mut mock = MockTestBackendHandler::new();
+        let mut seq = mockall::Sequence::new();
+        // A wrong local password: the upstream server is not used.
+        mock.expect_bind()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_| {
+                Err(DomainError::AuthenticationError(
+                    "Invalid password".to_string(),
+                ))
+            });
+        // The local credentials could not be checked: not a reason to trust the upstream server.
+        mock.expect_bind()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut)));
+        // Bob has no password, but we can't tell whether he exists.
+        mock.expect_bind()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_| Err(DomainError::CredentialsNotFound("bob".to_string())));
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut)));
+        // Unknown user: the upstream server is tried, but it is unreachable.
+        mock.expect_bind()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_| Err(DomainError::CredentialsNotFound("bob".to_string())));
+        mock.expect_get_user_details()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::RowNotFound)));
+        let config = LdapHandlerConfig {
+            upstream: Some(UpstreamLdapConfig {
+                url: "ldap://127.0.0.1:1".to_string(),
+                bind_dn_template: "uid={user},ou=people,dc=old,dc=com".to_string(),
+                auto_provision: true,
+            }),
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        for _ in 0..4 {
+            assert_eq!(
+                ldap_handler.do_bind(&request).await,
+                (LdapResultCode::InvalidCredentials, "".to_string())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_expired_password() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_get_grace_logins_remaining()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(2));
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(BindRequest {
+                name: UserId::new("bob"),
+                password: "old_pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Err(DomainError::PasswordExpired));
+        let config = LdapHandlerConfig {
+            password_max_age_days: 30,
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let make_request = |password: &str| LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple(password.to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&make_request("pass")).await,
+            (
+                LdapResultCode::Success,
+                "Password expired, 2 grace logins remaining".to_string()
+            )
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&make_request("old_pass")).await,
+            (
+                LdapResultCode::InvalidCredentials,
+                "Password expired, change it to unlock the account".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_bind() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
+
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_non_admin_user() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind()
+            .with(eq(crate::domain::handler::BindRequest {
+                name: UserId::new("test"),
+                password: "pass".to_string(),
+            }))
+            .times(1)
+            .return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::UserId(UserId::new("test")),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("test"),
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
+
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+
+        let request =
+            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success()
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_attribute_visibility() {
+        use crate::infra::attribute_visibility::AttributeVisibility;
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("test"),
+                email: "test@example.com".to_string(),
+                mail_forwarding: vec!["test@elsewhere.com".to_string()],
+                ..Default::default()
+            }])
+        });
+        let config = LdapHandlerConfig {
+            attribute_visibility: AttributeVisibilityPolicy::new(
+                [
+                    ("email".to_string(), AttributeVisibility::SelfAndAdmins),
+                    ("mail_forwarding".to_string(), AttributeVisibility::Admins),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+
+        // The user sees their own email, but not the admin-only forwarding addresses.
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec!["mail", "mailForwardingAddress"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "mail".to_string(),
+                        vals: vec!["test@example.com".to_string()]
+                    }],
+                }),
+                make_search_success()
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_invalid_dn() {
+        let mock = MockTestBackendHandler::new();
+        let mut ldap_handler =
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
+
+        let request = LdapBindRequest {
+            dn: "cn=bob,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=groups,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob,ou=people,dc=example,dc=fr".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+        let request = LdapBindRequest {
+            dn: "uid=bob=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::NamingViolation,
+        );
+    }
+
+    #[test]
+    fn test_is_subtree() {
+        let subtree1 = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        let root = &[
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert!(is_subtree(subtree1, root, false));
+        assert!(!is_subtree(&[], root, false));
+        let subtree2 = &[
+            ("ou".to_string(), "People".to_string()),
+            ("dc".to_string(), "Example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert!(is_subtree(subtree2, root, true));
+        assert!(!is_subtree(subtree2, root, false));
+    }
+
+    #[test]
+    fn test_parse_distinguished_name() {
+        let parsed_dn = &[
+            ("ou".to_string(), "people".to_string()),
+            ("dc".to_string(), "example".to_string()),
+            ("dc".to_string(), "com".to_string()),
+        ];
+        assert_eq!(
+            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
+            parsed_dn
+        );
+        assert_eq!(
+            parse_distinguished_name(" ou  = people , dc = example , dc =  com ")
+                .expect("parsing failed"),
+            parsed_dn
+        );
+    }
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("bob"), "bob");
+        assert_eq!(escape_dn_value("Sales, EMEA"), r"Sales\, EMEA");
+        assert_eq!(escape_dn_value("a+b;c<d>e\"f\\"), r#"a\+b\;c\<d\>e\"f\\"#);
+        assert_eq!(escape_dn_value(" #lead"), r"\ #lead");
+        assert_eq!(escape_dn_value("#trail "), r"\#trail\ ");
+        assert_eq!(escape_dn_value("Émilie"), "Émilie");
+        assert_eq!(escape_dn_value("山田太郎"), "山田太郎");
+    }
+
+    #[test]
+    fn test_parse_escaped_distinguished_name() {
+        assert_eq!(
+            parse_distinguished_name(r"cn=Sales\, EMEA\ ,ou=groups, dc=example").unwrap(),
+            vec![
+                ("cn".to_string(), "Sales, EMEA ".to_string()),
+                ("ou".to_string(), "groups".to_string()),
+                ("dc".to_string(), "example".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_distinguished_name(r"uid=\C3\A9milie\3Dx,dc=example").unwrap(),
+            vec![
+                ("uid".to_string(), "émilie=x".to_string()),
+                ("dc".to_string(), "example".to_string()),
+            ]
+        );
+        assert!(parse_distinguished_name(r"uid=bob\,dc=example\").is_err());
+        assert!(parse_distinguished_name(r"uid=bob\4,dc=example").is_err());
+    }
+
+    #[test]
+    fn test_dn_value_round_trip() {
+        for value in &[
+            "bob",
+            "Sales, EMEA",
+            r#"a+b;c<d>e"f\g"#,
+            " leading and trailing ",
+            "#hash",
+            "back\\",
+            "Émilie Dupont-Lefèvre",
+            "山田太郎",
+        ] {
+            let dn = make_group_dn(value, "dc=example,dc=com");
+            let parsed = parse_distinguished_name(&dn).unwrap();
+            assert_eq!(&parsed[0].1, value, "DN: {}", dn);
+            assert_eq!(parsed.len(), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_unicode() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: UserId::new("émilie"),
+                    display_name: "Émilie Dupont-Lefèvre".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: UserId::new("yamada"),
+                    display_name: "山田太郎".to_string(),
+                    ..Default::default()
+                },
+            ])
+        });
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                id: GroupId(2),
+                display_name: "Ventes, Europe".to_string(),
+                description: None,
+                users: vec![UserId::new("émilie")],
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["cn", "member"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=émilie,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["Émilie Dupont-Lefèvre".to_string()]
+                    }],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=yamada,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["山田太郎".to_string()]
+                    }],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: r"cn=Ventes\, Europe,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Ventes, Europe".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "member".to_string(),
+                            vals: vec!["uid=émilie,ou=people,dc=example,dc=com".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_users() {
+        use chrono::prelude::*;
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: UserId::new("bob_1"),
+                    email: "bob@bobmail.bob".to_string(),
+                    display_name: "Bôb Böbberson".to_string(),
+                    first_name: "Bôb".to_string(),
+                    last_name: "Böbberson".to_string(),
+                    ..Default::default()
+                },
+                User {
+                    user_id: UserId::new("jim"),
+                    email: "jim@cricket.jim".to_string(),
+                    display_name: "Jimminy Cricket".to_string(),
+                    first_name: "Jim".to_string(),
+                    last_name: "Cricket".to_string(),
+                    creation_date: Utc.ymd(2014, 7, 8).and_hms(9, 10, 11),
+                    ..Default::default()
+                },
+            ])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec![
+                "objectClass",
+                "dn",
+                "uid",
+                "mail",
+                "givenName",
+                "sn",
+                "cn",
+                "createTimestamp",
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob_1,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "inetOrgPerson".to_string(),
+                                "posixAccount".to_string(),
+                                "mailAccount".to_string(),
+                                "person".to_string(),
+                                "inetLocalMailRecipient".to_string(),
+                                "shadowAccount".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["uid=bob_1,ou=people,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob_1".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["bob@bobmail.bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec!["Bôb".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec!["Böbberson".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Bôb Böbberson".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec!["1970-01-01T00:00:00+00:00".to_string()]
+                        }
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec![
+                                "inetOrgPerson".to_string(),
+                                "posixAccount".to_string(),
+                                "mailAccount".to_string(),
+                                "person".to_string(),
+                                "inetLocalMailRecipient".to_string(),
+                                "shadowAccount".to_string()
+                            ]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["uid=jim,ou=people,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["jim@cricket.jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "givenName".to_string(),
+                            vals: vec!["Jim".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn".to_string(),
+                            vals: vec!["Cricket".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["Jimminy Cricket".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "createTimestamp".to_string(),
+                            vals: vec!["2014-07-08T09:10:11+00:00".to_string()]
+                        }
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    Group {
+                        id: GroupId(1),
+                        display_name: "group_1".to_string(),
+                        description: Some("Group One".to_string()),
+                        users: vec![UserId::new("bob"), UserId::new("john")],
+                    },
+                    Group {
+                        id: GroupId(3),
+                        display_name: "bestgroup".to_string(),
+                        description: None,
+                        users: vec![UserId::new("john")],
+                    },
+                ])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["objectClass", "dn", "cn", "description", "uniqueMember"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec!["groupOfUniqueNames".to_string(),]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["group_1".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "description".to_string(),
+                            vals: vec!["Group One".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec![
+                                "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                                "uid=john,ou=people,dc=example,dc=com".to_string(),
+                            ]
+                        },
+                    ],
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bestgroup,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "objectClass".to_string(),
+                            vals: vec!["groupOfUniqueNames".to_string(),]
+                        },
+                        LdapPartialAttribute {
+                            atype: "dn".to_string(),
+                            vals: vec!["cn=bestgroup,ou=groups,dc=example,dc=com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec!["bestgroup".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uniqueMember".to_string(),
+                            vals: vec!["uid=john,ou=people,dc=example,dc=com".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_attribute_options() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                email: "bob@bobmail.bob".to_string(),
+                display_name: "Bôb Böbberson".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock.expect_list_groups().times(1).return_once(|_| {
+            Ok(vec![Group {
+                id: GroupId(3),
+                display_name: "bestgroup".to_string(),
+                description: None,
+                users: vec![UserId::new("bob")],
+            }])
+        });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![]),
+            vec![
+                "cn;lang-fr",
+                "mail;binary",
+                "uid;x-unknown",
+                "sn;foo;lang-en-US",
+            ],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn;lang-fr".to_string(),
+                            vals: vec!["Bôb Böbberson".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["bob@bobmail.bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "sn;lang-en-US".to_string(),
+                            vals: vec!["".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![]),
+            vec!["cn;lang-de", "member;x-foo"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=bestgroup,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn;lang-de".to_string(),
+                            vals: vec!["bestgroup".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "member".to_string(),
+                            vals: vec!["uid=bob,ou=people,dc=example,dc=com".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_sorted_results() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).returning(|_| {
+            Ok(["jim", "Bob", "alice"]
+                .into_iter()
+                .map(|name| User {
+                    user_id: UserId::new(name),
+                    ..Default::default()
+                })
+                .collect())
+        });
+        let mut ldap_handler = LdapHandler::new_with_config(
+            LdapHandlerConfig {
+                sort_search_results: true,
+                ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+            },
+            mock,
+        );
+        ldap_handler.dn = LdapDn("uid=admin,ou=people,dc=example,dc=com".to_string());
+        ldap_handler.user_id = UserId::new("admin");
+        let mut request = make_user_search_request(LdapFilter::And(vec![]), vec!["1.1"]);
+        request.sizelimit = 2;
+        let make_entry = |name: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("uid={},ou=people,dc=example,dc=com", name),
+                attributes: vec![],
+            })
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                make_entry("alice"),
+                make_entry("bob"),
+                make_search_error(
+                    LdapResultCode::SizeLimitExceeded,
+                    "The search returned more than 2 entries".to_string(),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_search_results() {
+        let make_entry = |dn: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: dn.to_string(),
+                attributes: vec![],
+            })
+        };
+        assert_eq!(
+            sort_search_results(vec![
+                make_entry("uid=bob,ou=people,dc=example,dc=com"),
+                make_entry("cn=Admins,ou=groups,dc=example,dc=com"),
+                make_entry("cn=admin,ou=groups,dc=example,dc=com"),
+                make_search_success(),
+            ]),
+            vec![
+                make_entry("cn=admin,ou=groups,dc=example,dc=com"),
+                make_entry("cn=Admins,ou=groups,dc=example,dc=com"),
+                make_entry("uid=bob,ou=people,dc=example,dc=com"),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_size_limit() {
+        let make_users = || -> Result<Vec<User>> {
+            Ok(["bob", "jim", "test"]
+                .into_iter()
+                .map(|name| User {
+                    user_id: UserId::new(name),
+                    ..Default::default()
+                })
+                .collect())
+        };
+        let make_entry = |name: &str| {
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: format!("uid={},ou=people,dc=example,dc=com", name),
+                attributes: vec![],
+            })
+        };
+        let size_limit_exceeded = |limit: usize| {
+            make_search_error(
+                LdapResultCode::SizeLimitExceeded,
+                format!("The search returned more than {} entries", limit),
+            )
+        };
+        let mut request = make_user_search_request(LdapFilter::And(vec![]), vec!["1.1"]);
+
+        // The admin is only limited by the request.
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .times(2)
+            .returning(move |_| make_users());
+        let mut ldap_handler = LdapHandler::new_with_config(
+            LdapHandlerConfig {
+                search_result_limit_for_non_admin: Some(1),
+                ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+            },
+            mock,
+        );
+        ldap_handler.dn = LdapDn("uid=admin,ou=people,dc=example,dc=com".to_string());
+        ldap_handler.user_id = UserId::new("admin");
+        assert_eq!(ldap_handler.do_search(&request).await.len(), 4);
+        request.sizelimit = 2;
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_entry("bob"), make_entry("jim"), size_limit_exceeded(2)]
+        );
+
+        // Other users get the smallest of the configured limit and the request's.
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .times(2)
+            .returning(move |_| make_users());
+        mock.expect_get_user_details()
+            .times(2)
+            .returning(|_| Ok(User::default()));
+        let mut ldap_handler = LdapHandler::new_with_config(
+            LdapHandlerConfig {
+                search_result_limit_for_non_admin: Some(1),
+                ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+            },
+            mock,
+        );
+        let bind_request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&bind_request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_entry("bob"), size_limit_exceeded(1)]
+        );
+        request.sizelimit = 0;
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_entry("bob"), size_limit_exceeded(1)]
+        );
+
+        // The limit of the user overrides the configured one.
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().times(1).return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .times(1)
+            .returning(move |_| make_users());
+        mock.expect_get_user_details().times(1).returning(|_| {
+            Ok(User {
+                max_search_results: Some(10),
+                ..Default::default()
+            })
+        });
+        let mut ldap_handler = LdapHandler::new_with_config(
+            LdapHandlerConfig {
+                search_result_limit_for_non_admin: Some(1),
+                ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+            },
+            mock,
+        );
+        assert_eq!(
+            ldap_handler.do_bind(&bind_request).await.0,
+            LdapResultCode::Success
+        );
+        assert_eq!(ldap_handler.do_search(&request).await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_search_shadow_account() {
+        use chrono::prelude::*;
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: UserId::new("bob"),
+                    password_modified_date: Some(Utc.ymd(2022, 1, 2).and_hms(12, 0, 0)),
+                    ..Default::default()
+                },
+                User {
+                    user_id: UserId::new("jim"),
+                    ..Default::default()
+                },
+            ])
+        });
+        let mut ldap_handler = LdapHandler::new_with_config(
+            LdapHandlerConfig {
+                password_max_age_days: 90,
+                ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+            },
+            mock,
+        );
+        ldap_handler.dn = LdapDn("uid=admin,ou=people,dc=example,dc=com".to_string());
+        ldap_handler.user_id = UserId::new("admin");
+        let request = make_user_search_request(
+            LdapFilter::Equality("objectClass".to_string(), "shadowAccount".to_string()),
+            vec!["shadowLastChange", "shadowMin", "shadowMax"],
+        );
+        let shadow_attributes = |last_change: Option<&str>| {
+            last_change
+                .map(|days| LdapPartialAttribute {
+                    atype: "shadowLastChange".to_string(),
+                    vals: vec![days.to_string()],
+                })
+                .into_iter()
+                .chain([
+                    LdapPartialAttribute {
+                        atype: "shadowMin".to_string(),
+                        vals: vec!["0".to_string()],
+                    },
+                    LdapPartialAttribute {
+                        atype: "shadowMax".to_string(),
+                        vals: vec!["90".to_string()],
+                    },
+                ])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: shadow_attributes(Some("18994")),
+                }),
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: shadow_attributes(None),
+                }),
+                make_search_success(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_filter() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::And(vec![
+                GroupRequestFilter::DisplayName("group_1".to_string()),
+                GroupRequestFilter::Member(UserId::new("bob")),
+                GroupRequestFilter::And(vec![]),
+                GroupRequestFilter::And(vec![]),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    description: None,
+                    id: GroupId(1),
+                    users: vec![],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![
+                LdapFilter::Equality("cn".to_string(), "group_1".to_string()),
+                LdapFilter::Equality(
+                    "uniqueMember".to_string(),
+                    "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                ),
+                LdapFilter::Equality("objectclass".to_string(), "groupOfUniqueNames".to_string()),
+                LdapFilter::Equality("objectclass".to_string(), "groupOfNames".to_string()),
+            ]),
+            vec!["1.1"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![],
+                }),
+                make_search_success(),
+            ]
+        );
     }
 
-    fn make_search_request<S: Into<String>>(
-        base: &str,
-        filter: LdapFilter,
-        attrs: Vec<S>,
-    ) -> LdapSearchRequest {
-        LdapSearchRequest {
-            base: base.to_string(),
-            scope: LdapSearchScope::Base,
-            aliases: LdapDerefAliases::Never,
-            sizelimit: 0,
-            timelimit: 0,
-            typesonly: false,
-            filter,
-            attrs: attrs.into_iter().map(Into::into).collect(),
-        }
+    #[tokio::test]
+    async fn test_search_groups_filter_2() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
+                    "group_2".to_string(),
+                ))),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![Group {
+                    display_name: "group_1".to_string(),
+                    description: None,
+                    id: GroupId(1),
+                    users: vec![],
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
+                "displayname".to_string(),
+                "group_2".to_string(),
+            )))]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "cn".to_string(),
+                        vals: vec!["group_1".to_string()]
+                    },],
+                }),
+                make_search_success(),
+            ]
+        );
     }
 
-    fn make_user_search_request<S: Into<String>>(
-        filter: LdapFilter,
-        attrs: Vec<S>,
-    ) -> LdapSearchRequest {
-        make_search_request::<S>("ou=people,dc=example,dc=com", filter, attrs)
+    #[tokio::test]
+    async fn test_search_groups_member_in_chain() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_groups_containing_user_recursive()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| {
+                let mut groups = HashSet::new();
+                groups.insert(GroupIdAndName(GroupId(3), "bestgroup".to_string()));
+                groups.insert(GroupIdAndName(GroupId(1), "group_1".to_string()));
+                Ok(groups)
+            });
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::DisplayName("bestgroup".to_string()),
+                GroupRequestFilter::DisplayName("group_1".to_string()),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Equality(
+                "member:1.2.840.113556.1.4.1941:".to_string(),
+                "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            ),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Equality(
+                "member:1.2.3.4:".to_string(),
+                "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            ),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported group filter: Unsupported matching rule: member:1.2.3.4:".to_string()
+            )]
+        );
     }
 
-    async fn setup_bound_handler(
-        mut mock: MockTestBackendHandler,
-    ) -> LdapHandler<MockTestBackendHandler> {
-        mock.expect_bind()
-            .with(eq(BindRequest {
-                name: UserId::new("test"),
-                password: "pass".to_string(),
-            }))
-            .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
-        let request = LdapBindRequest {
-            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
+    #[tokio::test]
+    async fn test_search_groups_error() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(Some(GroupRequestFilter::Or(vec![
+                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
+                    "group_2".to_string(),
+                ))),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Err(crate::domain::error::DomainError::InternalError(
+                    "Error getting groups".to_string(),
+                ))
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
+                "displayname".to_string(),
+                "group_2".to_string(),
+            )))]),
+            vec!["cn"],
+        );
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::Success
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::Other,
+                r#"Error while listing groups "ou=groups,dc=example,dc=com": Internal error: `Error getting groups`"#.to_string()
+            )]
         );
-        ldap_handler
     }
 
     #[tokio::test]
-    async fn test_bind() {
+    async fn test_search_users_query_timeout() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: UserId::new("bob"),
-                password: "pass".to_string(),
-            }))
+        mock.expect_list_users()
             .times(1)
-            .return_once(|_| Ok(()));
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
+            .return_once(|_| Err(DomainError::query_timeout()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::OperationsError,
+                r#"Error during searching user "ou=people,dc=example,dc=com": Timeout: the database query didn't finish in time"#.to_string()
+            )]
+        );
+    }
 
-        let request = LdapOp::BindRequest(LdapBindRequest {
-            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        });
+    #[tokio::test]
+    async fn test_search_users_operation_timeout() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .times(1)
+            .return_once(|_| Err(DomainError::operation_timeout()));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid"]);
         assert_eq!(
-            ldap_handler.handle_ldap_message(request).await,
-            Some(vec![LdapOp::BindResponse(LdapBindResponse {
-                res: LdapResult {
-                    code: LdapResultCode::Success,
-                    matcheddn: "".to_string(),
-                    message: "".to_string(),
-                    referral: vec![],
-                },
-                saslcreds: None,
-            })]),
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::TimeLimitExceeded,
+                r#"Error during searching user "ou=people,dc=example,dc=com": Timeout: the backend operation didn't finish in time"#.to_string()
+            )]
         );
     }
 
     #[tokio::test]
-    async fn test_admin_bind() {
+    async fn test_bind_operation_timeout() {
         let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
         mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: UserId::new("test"),
-                password: "pass".to_string(),
-            }))
             .times(1)
-            .return_once(|_| Ok(()));
+            .return_once(|_| Err(DomainError::operation_timeout()));
         let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("test"));
-
+            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
         let request = LdapBindRequest {
-            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
             cred: LdapBindCred::Simple("pass".to_string()),
         };
         assert_eq!(
             ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::Success
+            LdapResultCode::TimeLimitExceeded
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_groups_filter_error() {
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        let request = make_search_request(
+            "ou=groups,dc=example,dc=com",
+            LdapFilter::And(vec![LdapFilter::Equality(
+                "whatever".to_string(),
+                "group_1".to_string(),
+            )]),
+            vec!["cn"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unsupported group filter: Unknown field: whatever".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_filters() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::Or(vec![
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::UserId(UserId::new("bob")))),
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::And(vec![]),
+                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
+                ]),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![LdapFilter::Or(vec![
+                LdapFilter::Not(Box::new(LdapFilter::Equality(
+                    "uid".to_string(),
+                    "bob".to_string(),
+                ))),
+                LdapFilter::Equality("objectclass".to_string(), "person".to_string()),
+                LdapFilter::Equality("objectclass".to_string(), "other".to_string()),
+                LdapFilter::Present("objectClass".to_string()),
+                LdapFilter::Present("uid".to_string()),
+                LdapFilter::Present("unknown".to_string()),
+            ])]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
         );
     }
 
     #[tokio::test]
-    async fn test_search_non_admin_user() {
+    async fn test_search_manager() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_bind()
-            .with(eq(crate::domain::handler::BindRequest {
-                name: UserId::new("test"),
-                password: "pass".to_string(),
-            }))
-            .times(1)
-            .return_once(|_| Ok(()));
         mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![
-                UserRequestFilter::And(vec![]),
-                UserRequestFilter::UserId(UserId::new("test")),
-            ]))))
+            .with(eq(Some(UserRequestFilter::Manager(UserId::new("alice")))))
             .times(1)
             .return_once(|_| {
                 Ok(vec![User {
-                    user_id: UserId::new("test"),
+                    user_id: UserId::new("bob"),
+                    manager_user_id: Some(UserId::new("alice")),
                     ..Default::default()
                 }])
             });
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
-
-        let request = LdapBindRequest {
-            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
-        assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::Success
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::Equality(
+                "manager".to_string(),
+                "uid=alice,ou=people,dc=example,dc=com".to_string(),
+            ),
+            vec!["manager"],
         );
-
-        let request =
-            make_user_search_request::<String>(LdapFilter::And(vec![]), vec!["1.1".to_string()]);
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![],
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "manager".to_string(),
+                        vals: vec!["uid=alice,ou=people,dc=example,dc=com".to_string()]
+                    }],
                 }),
                 make_search_success()
-            ],
+            ]
         );
     }
 
     #[tokio::test]
-    async fn test_bind_invalid_dn() {
-        let mock = MockTestBackendHandler::new();
-        let mut ldap_handler =
-            LdapHandler::new(mock, "dc=example,dc=com".to_string(), UserId::new("admin"));
-
-        let request = LdapBindRequest {
-            dn: "cn=bob,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
-        assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
-        );
-        let request = LdapBindRequest {
-            dn: "uid=bob,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
-        assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+    async fn test_search_mail_local_address() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::MailAlias("postmaster@example.com".to_string()),
+            ]))))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![User {
+                    user_id: UserId::new("bob"),
+                    mail_aliases: vec!["postmaster@example.com".to_string()],
+                    mail_forwarding: vec!["bob@elsewhere.com".to_string()],
+                    ..Default::default()
+                }])
+            });
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality(
+                    "objectClass".to_string(),
+                    "inetLocalMailRecipient".to_string(),
+                ),
+                LdapFilter::Equality(
+                    "mailLocalAddress".to_string(),
+                    "postmaster@example.com".to_string(),
+                ),
+            ]),
+            vec!["mailLocalAddress", "mailForwardingAddress"],
         );
-        let request = LdapBindRequest {
-            dn: "uid=bob,ou=groups,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            ldap_handler.do_search(&request).await,
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "mailLocalAddress".to_string(),
+                            vals: vec!["postmaster@example.com".to_string()]
+                        },
+                        LdapPartialAttribute {
+                            atype: "mailForwardingAddress".to_string(),
+                            vals: vec!["bob@elsewhere.com".to_string()]
+                        },
+                    ],
+                }),
+                make_search_success()
+            ]
         );
-        let request = LdapBindRequest {
-            dn: "uid=bob,ou=people,dc=example,dc=fr".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
+    }
+
+    #[test]
+    fn test_expand_requested_attributes() {
+        let expand = |requested: Vec<&str>, defaults: Vec<&str>| {
+            expand_requested_attributes(
+                &requested
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>(),
+                &["objectClass", "uid", "cn"],
+                &["createTimestamp"],
+                &defaults.into_iter().map(str::to_string).collect::<Vec<_>>(),
+            )
         };
+        assert_eq!(expand(vec![], vec![]), vec!["objectClass", "uid", "cn"]);
+        assert_eq!(expand(vec!["*"], vec![]), vec!["objectClass", "uid", "cn"]);
+        assert_eq!(expand(vec!["1.1"], vec![]), Vec::<String>::new());
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            expand(vec!["cn", "+"], vec![]),
+            vec!["cn", "createTimestamp"]
         );
-        let request = LdapBindRequest {
-            dn: "uid=bob=test,ou=people,dc=example,dc=com".to_string(),
-            cred: LdapBindCred::Simple("pass".to_string()),
-        };
+        // Listed attributes are only returned once.
         assert_eq!(
-            ldap_handler.do_bind(&request).await.0,
-            LdapResultCode::NamingViolation,
+            expand(vec!["UID", "*"], vec![]),
+            vec!["UID", "objectClass", "cn"]
         );
-    }
-
-    #[test]
-    fn test_is_subtree() {
-        let subtree1 = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        let root = &[
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
-        assert!(is_subtree(subtree1, root));
-        assert!(!is_subtree(&[], root));
-    }
-
-    #[test]
-    fn test_parse_distinguished_name() {
-        let parsed_dn = &[
-            ("ou".to_string(), "people".to_string()),
-            ("dc".to_string(), "example".to_string()),
-            ("dc".to_string(), "com".to_string()),
-        ];
+        // The defaults replace the empty list, but not "*".
         assert_eq!(
-            parse_distinguished_name("ou=people,dc=example,dc=com").expect("parsing failed"),
-            parsed_dn
+            expand(vec![], vec!["uid", "mail", "createTimestamp"]),
+            vec!["uid", "createTimestamp"]
         );
         assert_eq!(
-            parse_distinguished_name(" ou  = people , dc = example , dc =  com ")
-                .expect("parsing failed"),
-            parsed_dn
+            expand(vec!["*"], vec!["uid"]),
+            vec!["objectClass", "uid", "cn"]
         );
     }
 
     #[tokio::test]
-    async fn test_search_users() {
-        use chrono::prelude::*;
+    async fn test_search_default_attributes() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users().times(1).return_once(|_| {
-            Ok(vec![
-                User {
-                    user_id: UserId::new("bob_1"),
-                    email: "bob@bobmail.bob".to_string(),
-                    display_name: "Bôb Böbberson".to_string(),
-                    first_name: "Bôb".to_string(),
-                    last_name: "Böbberson".to_string(),
-                    ..Default::default()
-                },
-                User {
-                    user_id: UserId::new("jim"),
-                    email: "jim@cricket.jim".to_string(),
-                    display_name: "Jimminy Cricket".to_string(),
-                    first_name: "Jim".to_string(),
-                    last_name: "Cricket".to_string(),
-                    creation_date: Utc.ymd(2014, 7, 8).and_hms(9, 10, 11),
-                },
-            ])
+        mock.expect_list_users().times(4).returning(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                email: "bob@bob.bob".to_string(),
+                display_name: "Bob".to_string(),
+                ..Default::default()
+            }])
         });
         let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_user_search_request(
-            LdapFilter::And(vec![]),
-            vec![
+        let make_attribute = |atype: &str, vals: Vec<&str>| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vals.into_iter().map(str::to_string).collect(),
+        };
+        let all_attributes = vec![
+            make_attribute(
                 "objectClass",
-                "dn",
-                "uid",
-                "mail",
-                "givenName",
-                "sn",
-                "cn",
-                "createTimestamp",
-            ],
-        );
-        assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "uid=bob_1,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                "inetOrgPerson".to_string(),
-                                "posixAccount".to_string(),
-                                "mailAccount".to_string(),
-                                "person".to_string()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["uid=bob_1,ou=people,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec!["bob_1".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec!["bob@bobmail.bob".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec!["Bôb".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec!["Böbberson".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Bôb Böbberson".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "createTimestamp".to_string(),
-                            vals: vec!["1970-01-01T00:00:00+00:00".to_string()]
-                        }
-                    ],
-                }),
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "uid=jim,ou=people,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec![
-                                "inetOrgPerson".to_string(),
-                                "posixAccount".to_string(),
-                                "mailAccount".to_string(),
-                                "person".to_string()
-                            ]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["uid=jim,ou=people,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uid".to_string(),
-                            vals: vec!["jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "mail".to_string(),
-                            vals: vec!["jim@cricket.jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "givenName".to_string(),
-                            vals: vec!["Jim".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "sn".to_string(),
-                            vals: vec!["Cricket".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["Jimminy Cricket".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "createTimestamp".to_string(),
-                            vals: vec!["2014-07-08T09:10:11+00:00".to_string()]
-                        }
-                    ],
+                vec![
+                    "inetOrgPerson",
+                    "posixAccount",
+                    "mailAccount",
+                    "person",
+                    "inetLocalMailRecipient",
+                    "shadowAccount",
+                ],
+            ),
+            make_attribute("uid", vec!["bob"]),
+            make_attribute("mail", vec!["bob@bob.bob"]),
+            make_attribute("givenName", vec![""]),
+            make_attribute("sn", vec![""]),
+            make_attribute("cn", vec!["Bob"]),
+            make_attribute("displayName", vec!["Bob"]),
+            make_attribute("shadowMin", vec!["0"]),
+        ];
+        let make_result = |attributes: Vec<LdapPartialAttribute>| {
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes,
                 }),
                 make_search_success(),
             ]
+        };
+        // An empty list means all the user attributes, but not the operational ones. The
+        // attributes without values are left out.
+        let request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            make_result(all_attributes.clone())
+        );
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["*"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            make_result(all_attributes)
+        );
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["1.1"]);
+        assert_eq!(ldap_handler.do_search(&request).await, make_result(vec![]));
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["uid", "+"]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            make_result(vec![
+                make_attribute("uid", vec!["bob"]),
+                make_attribute("createTimestamp", vec!["1970-01-01T00:00:00+00:00"]),
+                make_attribute("modifyTimestamp", vec!["1970-01-01T00:00:00+00:00"]),
+            ])
+        );
+
+        // The configured defaults replace the empty list.
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users().times(1).returning(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                email: "bob@bob.bob".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut ldap_handler = LdapHandler::new_with_config(
+            LdapHandlerConfig {
+                default_search_attributes: vec!["uid".to_string(), "mail".to_string()],
+                ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"))
+            },
+            mock,
+        );
+        ldap_handler.dn = LdapDn("uid=admin,ou=people,dc=example,dc=com".to_string());
+        ldap_handler.user_id = UserId::new("admin");
+        let request = make_user_search_request::<String>(LdapFilter::And(vec![]), vec![]);
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            make_result(vec![
+                make_attribute("uid", vec!["bob"]),
+                make_attribute("mail", vec!["bob@bob.bob"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_to_windows_file_time() {
+        use chrono::TimeZone;
+        assert_eq!(
+            to_windows_file_time(chrono::Utc.timestamp(0, 0)),
+            116_444_736_000_000_000
+        );
+        assert_eq!(
+            to_windows_file_time(chrono::Utc.timestamp(1_600_000_000, 1_234_567)),
+            132_444_736_000_012_345
         );
     }
 
     #[tokio::test]
-    async fn test_search_groups() {
+    async fn test_search_last_logon() {
+        use chrono::TimeZone;
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::And(vec![]))))
-            .times(1)
-            .return_once(|_| {
-                Ok(vec![
-                    Group {
-                        id: GroupId(1),
-                        display_name: "group_1".to_string(),
-                        users: vec![UserId::new("bob"), UserId::new("john")],
-                    },
-                    Group {
-                        id: GroupId(3),
-                        display_name: "bestgroup".to_string(),
-                        users: vec![UserId::new("john")],
-                    },
-                ])
-            });
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![
+                User {
+                    user_id: UserId::new("bob"),
+                    last_login_at: Some(chrono::Utc.timestamp(1_600_000_000, 0)),
+                    ..Default::default()
+                },
+                User {
+                    user_id: UserId::new("jim"),
+                    ..Default::default()
+                },
+            ])
+        });
         let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
+        let request = make_user_search_request(
             LdapFilter::And(vec![]),
-            vec!["objectClass", "dn", "cn", "uniqueMember"],
+            vec!["uid", "lastLogonTimestamp", "lastLogon"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
                     attributes: vec![
                         LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=group_1,ou=groups,dc=example,dc=com".to_string()]
+                            atype: "uid".to_string(),
+                            vals: vec!["bob".to_string()]
                         },
                         LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["group_1".to_string()]
+                            atype: "lastLogonTimestamp".to_string(),
+                            vals: vec!["132444736000000000".to_string()]
                         },
                         LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec![
-                                "uid=bob,ou=people,dc=example,dc=com".to_string(),
-                                "uid=john,ou=people,dc=example,dc=com".to_string(),
-                            ]
+                            atype: "lastLogon".to_string(),
+                            vals: vec!["1600000000".to_string()]
                         },
                     ],
                 }),
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=bestgroup,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![
-                        LdapPartialAttribute {
-                            atype: "objectClass".to_string(),
-                            vals: vec!["groupOfUniqueNames".to_string(),]
-                        },
-                        LdapPartialAttribute {
-                            atype: "dn".to_string(),
-                            vals: vec!["cn=bestgroup,ou=groups,dc=example,dc=com".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "cn".to_string(),
-                            vals: vec!["bestgroup".to_string()]
-                        },
-                        LdapPartialAttribute {
-                            atype: "uniqueMember".to_string(),
-                            vals: vec!["uid=john,ou=people,dc=example,dc=com".to_string()]
-                        },
-                    ],
+                    dn: "uid=jim,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "uid".to_string(),
+                        vals: vec!["jim".to_string()]
+                    }],
                 }),
-                make_search_success(),
+                make_search_success()
             ]
         );
     }
 
     #[tokio::test]
-    async fn test_search_groups_filter() {
+    async fn test_search_telephone_number() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::And(vec![
-                GroupRequestFilter::DisplayName("group_1".to_string()),
-                GroupRequestFilter::Member(UserId::new("bob")),
-                GroupRequestFilter::And(vec![]),
-                GroupRequestFilter::And(vec![]),
-            ]))))
-            .times(1)
-            .return_once(|_| {
-                Ok(vec![Group {
-                    display_name: "group_1".to_string(),
-                    id: GroupId(1),
-                    users: vec![],
-                }])
-            });
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                phone_numbers: vec!["+14155550123".to_string(), "+33123456789".to_string()],
+                ..Default::default()
+            }])
+        });
         let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::And(vec![
-                LdapFilter::Equality("cn".to_string(), "group_1".to_string()),
-                LdapFilter::Equality(
-                    "uniqueMember".to_string(),
-                    "uid=bob,ou=people,dc=example,dc=com".to_string(),
-                ),
-                LdapFilter::Equality("objectclass".to_string(), "groupOfUniqueNames".to_string()),
-                LdapFilter::Equality("objectclass".to_string(), "groupOfNames".to_string()),
-            ]),
-            vec!["1.1"],
-        );
+        let request = make_user_search_request(LdapFilter::And(vec![]), vec!["telephoneNumber"]);
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
                 LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![],
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![LdapPartialAttribute {
+                        atype: "telephoneNumber".to_string(),
+                        vals: vec!["+14155550123".to_string(), "+33123456789".to_string()]
+                    }],
                 }),
-                make_search_success(),
+                make_search_success()
             ]
         );
     }
 
     #[tokio::test]
-    async fn test_search_groups_filter_2() {
+    async fn test_search_filter_cache() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::Or(vec![
-                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
-                    "group_2".to_string(),
-                ))),
-            ]))))
-            .times(1)
-            .return_once(|_| {
-                Ok(vec![Group {
-                    display_name: "group_1".to_string(),
-                    id: GroupId(1),
-                    users: vec![],
-                }])
-            });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
-                "displayname".to_string(),
-                "group_2".to_string(),
-            )))]),
-            vec!["cn"],
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::UserId(UserId::new("bob")))))
+            .times(2)
+            .returning(|_| Ok(vec![]));
+        let config = LdapHandlerConfig {
+            filter_cache: FilterCache::new(10),
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            vec!["objectClass"],
         );
+        for _ in 0..2 {
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                vec![make_search_success()]
+            );
+        }
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![
-                LdapOp::SearchResultEntry(LdapSearchResultEntry {
-                    dn: "cn=group_1,ou=groups,dc=example,dc=com".to_string(),
-                    attributes: vec![LdapPartialAttribute {
-                        atype: "cn".to_string(),
-                        vals: vec!["group_1".to_string()]
-                    },],
-                }),
-                make_search_success(),
-            ]
+            ldap_handler
+                .filter_cache
+                .0
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_filter_cache_key() {
+        let substring = |initial: Option<&str>, final_: Option<&str>| {
+            LdapFilter::Substring(
+                "cn".to_string(),
+                LdapSubstringFilter {
+                    initial: initial.map(str::to_string),
+                    any: vec![],
+                    final_: final_.map(str::to_string),
+                },
+            )
+        };
+        let filter = LdapFilter::And(vec![
+            substring(Some("a"), None),
+            LdapFilter::Not(Box::new(LdapFilter::Present("mail".to_string()))),
+        ]);
+        assert!(FilterKey::new(&filter) == FilterKey::new(&filter.clone()));
+        assert!(
+            FilterKey::new(&substring(Some("a"), None))
+                != FilterKey::new(&substring(None, Some("a")))
+        );
+        assert!(
+            FilterKey::new(&LdapFilter::And(vec![filter.clone()]))
+                != FilterKey::new(&LdapFilter::Or(vec![filter]))
         );
     }
 
     #[tokio::test]
-    async fn test_search_groups_error() {
+    async fn test_search_result_cache() {
         let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_groups()
-            .with(eq(Some(GroupRequestFilter::Or(vec![
-                GroupRequestFilter::Not(Box::new(GroupRequestFilter::DisplayName(
-                    "group_2".to_string(),
-                ))),
-            ]))))
-            .times(1)
-            .return_once(|_| {
-                Err(crate::domain::error::DomainError::InternalError(
-                    "Error getting groups".to_string(),
-                ))
-            });
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::Or(vec![LdapFilter::Not(Box::new(LdapFilter::Equality(
-                "displayname".to_string(),
-                "group_2".to_string(),
-            )))]),
-            vec!["cn"],
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        mock.expect_data_version().return_const(1u64);
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                ..Default::default()
+            }])
+        });
+        let config = LdapHandlerConfig {
+            search_cache: LdapSearchCache::new(10, Duration::from_secs(60)),
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            vec!["1.1"],
         );
+        let expected = vec![
+            LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                attributes: vec![],
+            }),
+            make_search_success(),
+        ];
+        // The second search doesn't reach the backend.
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+        assert_eq!(ldap_handler.do_search(&request).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_search_result_cache_data_version() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        // As if the data was written between the searches, e.g. from the web UI.
+        let version = std::sync::atomic::AtomicU64::new(0);
+        mock.expect_data_version()
+            .returning(move || version.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+        mock.expect_list_users().times(2).returning(|_| Ok(vec![]));
+        let config = LdapHandlerConfig {
+            search_cache: LdapSearchCache::new(10, Duration::from_secs(60)),
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::Other,
-                r#"Error while listing groups "ou=groups,dc=example,dc=com": Internal error: `Error getting groups`"#.to_string()
-            )]
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
         );
+        let request = make_user_search_request(
+            LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            vec!["1.1"],
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                ldap_handler.do_search(&request).await,
+                vec![make_search_success()]
+            );
+        }
     }
 
     #[tokio::test]
-    async fn test_search_groups_filter_error() {
-        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
-        let request = make_search_request(
-            "ou=groups,dc=example,dc=com",
-            LdapFilter::And(vec![LdapFilter::Equality(
-                "whatever".to_string(),
-                "group_1".to_string(),
-            )]),
-            vec!["cn"],
+    async fn test_search_attribute_aliases() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_update_last_login().returning(|_, _| Ok(()));
+        mock.expect_bind().return_once(|_| Ok(()));
+        mock.expect_list_users().times(1).return_once(|_| {
+            Ok(vec![User {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                ..Default::default()
+            }])
+        });
+        let mut aliases = HashMap::new();
+        aliases.insert("email".to_string(), "mail".to_string());
+        let config = LdapHandlerConfig {
+            attribute_aliases: aliases,
+            ..LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("test"))
+        };
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapBindRequest {
+            dn: "uid=test,ou=people,dc=example,dc=com".to_string(),
+            cred: LdapBindCred::Simple("pass".to_string()),
+        };
+        assert_eq!(
+            ldap_handler.do_bind(&request).await.0,
+            LdapResultCode::Success
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality("uid".to_string(), "bob".to_string()),
+            vec!["mail", "Email"],
         );
         assert_eq!(
             ldap_handler.do_search(&request).await,
-            vec![make_search_error(
-                LdapResultCode::UnwillingToPerform,
-                "Unsupported group filter: Unknown field: whatever".to_string()
-            )]
+            vec![
+                LdapOp::SearchResultEntry(LdapSearchResultEntry {
+                    dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec!["bob@example.com".to_string()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "Email".to_string(),
+                            vals: vec!["bob@example.com".to_string()],
+                        },
+                    ],
+                }),
+                make_search_success(),
+            ]
         );
     }
 
-    #[tokio::test]
-    async fn test_search_filters() {
-        let mut mock = MockTestBackendHandler::new();
-        mock.expect_list_users()
-            .with(eq(Some(UserRequestFilter::And(vec![
-                UserRequestFilter::Or(vec![
-                    UserRequestFilter::Not(Box::new(UserRequestFilter::UserId(UserId::new("bob")))),
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::And(vec![]),
-                    UserRequestFilter::Not(Box::new(UserRequestFilter::And(vec![]))),
-                ]),
-            ]))))
-            .times(1)
-            .return_once(|_| Ok(vec![]));
-        let mut ldap_handler = setup_bound_handler(mock).await;
-        let request = make_user_search_request(
-            LdapFilter::And(vec![LdapFilter::Or(vec![
-                LdapFilter::Not(Box::new(LdapFilter::Equality(
-                    "uid".to_string(),
-                    "bob".to_string(),
-                ))),
-                LdapFilter::Equality("objectclass".to_string(), "person".to_string()),
-                LdapFilter::Equality("objectclass".to_string(), "other".to_string()),
-                LdapFilter::Present("objectClass".to_string()),
-                LdapFilter::Present("uid".to_string()),
-                LdapFilter::Present("unknown".to_string()),
-            ])]),
-            vec!["objectClass"],
+    #[test]
+    fn test_derive_names_from_display_name() {
+        let derive = |display_name: &str, first_name: &str, last_name: &str| {
+            let mut user = User {
+                display_name: display_name.to_string(),
+                first_name: first_name.to_string(),
+                last_name: last_name.to_string(),
+                ..Default::default()
+            };
+            derive_names_from_display_name(&mut user);
+            (user.first_name, user.last_name)
+        };
+        assert_eq!(
+            derive("Mary Ann  Smith", "", ""),
+            ("Mary Ann".to_string(), "Smith".to_string())
         );
+        assert_eq!(derive("Cher", "", ""), ("".to_string(), "Cher".to_string()));
+        assert_eq!(derive("", "", ""), ("".to_string(), "".to_string()));
         assert_eq!(
-            ldap_handler.do_search(&request).await,
-            vec![make_search_success()]
+            derive("Ludwig van Beethoven", "Ludwig", "van Beethoven"),
+            ("Ludwig".to_string(), "van Beethoven".to_string())
         );
     }
 
@@ -1405,6 +6166,109 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_search_member_of_extended() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_groups()
+            .with(eq(None))
+            .times(1)
+            .return_once(|_| {
+                Ok(vec![
+                    Group {
+                        id: GroupId(1),
+                        display_name: "app_admins".to_string(),
+                        description: None,
+                        users: vec![],
+                    },
+                    Group {
+                        id: GroupId(2),
+                        display_name: "App_Users".to_string(),
+                        description: None,
+                        users: vec![],
+                    },
+                    Group {
+                        id: GroupId(3),
+                        display_name: "family".to_string(),
+                        description: None,
+                        users: vec![],
+                    },
+                ])
+            });
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::And(vec![]),
+                UserRequestFilter::Or(vec![
+                    UserRequestFilter::MemberOf("app_admins".to_string()),
+                    UserRequestFilter::MemberOf("App_Users".to_string()),
+                ]),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::MemberOfAnyGroup)))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::MemberOf("family".to_string()))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "person".to_string()),
+                LdapFilter::Substring(
+                    "memberOf".to_string(),
+                    LdapSubstringFilter {
+                        initial: Some("cn=APP_".to_string()),
+                        any: vec![],
+                        final_: Some(",ou=groups,dc=example,dc=com".to_string()),
+                    },
+                ),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        let request = make_user_search_request(
+            LdapFilter::Present("memberOf".to_string()),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+        let request = make_user_search_request(
+            LdapFilter::Equality(
+                "memberOf:1.2.840.113556.1.4.1941:".to_string(),
+                "cn=family,ou=groups,dc=example,dc=com".to_string(),
+            ),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
+    #[test]
+    fn test_matches_substring_filter() {
+        let filter = LdapSubstringFilter {
+            initial: Some("cn=".to_string()),
+            any: vec!["adm".to_string(), "in".to_string()],
+            final_: Some("s,ou=groups".to_string()),
+        };
+        assert!(matches_substring_filter(&filter, "CN=admins,ou=groups"));
+        assert!(matches_substring_filter(&filter, "cn=app_admins,ou=groups"));
+        assert!(!matches_substring_filter(&filter, "cn=admin,ou=groups"));
+        assert!(!matches_substring_filter(&filter, "uid=admins,ou=groups"));
+        assert!(matches_substring_filter(
+            &LdapSubstringFilter::default(),
+            "anything"
+        ));
+    }
+
     #[tokio::test]
     async fn test_search_filters_lowercase() {
         let mut mock = MockTestBackendHandler::new();
@@ -1439,7 +6303,9 @@ mod tests {
                             "inetOrgPerson".to_string(),
                             "posixAccount".to_string(),
                             "mailAccount".to_string(),
-                            "person".to_string()
+                            "person".to_string(),
+                            "inetLocalMailRecipient".to_string(),
+                            "shadowAccount".to_string()
                         ]
                     },]
                 }),
@@ -1468,6 +6334,7 @@ mod tests {
                 Ok(vec![Group {
                     id: GroupId(1),
                     display_name: "group_1".to_string(),
+                    description: None,
                     users: vec![UserId::new("bob"), UserId::new("john")],
                 }])
             });
@@ -1489,7 +6356,9 @@ mod tests {
                                 "inetOrgPerson".to_string(),
                                 "posixAccount".to_string(),
                                 "mailAccount".to_string(),
-                                "person".to_string()
+                                "person".to_string(),
+                                "inetLocalMailRecipient".to_string(),
+                                "shadowAccount".to_string()
                             ]
                         },
                         LdapPartialAttribute {
@@ -1538,12 +6407,50 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_search_substring_and_presence_filters() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_list_users()
+            .with(eq(Some(UserRequestFilter::And(vec![
+                UserRequestFilter::SubString(
+                    "email".to_string(),
+                    SubStringFilter {
+                        initial: Some("bob".to_string()),
+                        any: vec![],
+                        final_: Some("@example.com".to_string()),
+                    },
+                ),
+                UserRequestFilter::Present("first_name".to_string()),
+            ]))))
+            .times(1)
+            .return_once(|_| Ok(vec![]));
+        let mut ldap_handler = setup_bound_handler(mock).await;
+        let request = make_user_search_request(
+            LdapFilter::And(vec![
+                LdapFilter::Substring(
+                    "mail".to_string(),
+                    LdapSubstringFilter {
+                        initial: Some("bob".to_string()),
+                        any: vec![],
+                        final_: Some("@example.com".to_string()),
+                    },
+                ),
+                LdapFilter::Present("givenName".to_string()),
+            ]),
+            vec!["objectClass"],
+        );
+        assert_eq!(
+            ldap_handler.do_search(&request).await,
+            vec![make_search_success()]
+        );
+    }
+
     #[tokio::test]
     async fn test_search_unsupported_filters() {
         let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
         let request = make_user_search_request(
             LdapFilter::Substring(
-                "uid".to_string(),
+                "unknown".to_string(),
                 ldap3_server::proto::LdapSubstringFilter::default(),
             ),
             vec!["objectClass"],
@@ -1552,7 +6459,7 @@ mod tests {
             ldap_handler.do_search(&request).await,
             vec![make_search_error(
                 LdapResultCode::UnwillingToPerform,
-                "Unsupported user filter: Unsupported user filter: Substring(\"uid\", LdapSubstringFilter { initial: None, any: [], final_: None })".to_string()
+                "Unsupported user filter: Unknown field: unknown".to_string()
             )]
         );
     }
@@ -1582,7 +6489,11 @@ mod tests {
         });
         mock.expect_registration_finish()
             .times(1)
-            .return_once(|_| Ok(()));
+            .return_once(|_| Ok(UserId::new("bob")));
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| Ok(HashSet::new()));
         let mut ldap_handler = setup_bound_handler(mock).await;
         let request = LdapOp::ExtendedRequest(
             LdapPasswordModifyRequest {
@@ -1648,8 +6559,97 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_search_root_dse() {
+    async fn test_who_am_i() {
+        let who_am_i = || {
+            LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+                value: None,
+            })
+        };
+        let expected_response = |authz_id: &str| {
+            Some(vec![LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: LdapResult {
+                    code: LdapResultCode::Success,
+                    matcheddn: "".to_string(),
+                    message: "".to_string(),
+                    referral: vec![],
+                },
+                name: None,
+                value: Some(authz_id.as_bytes().to_vec()),
+            })])
+        };
+        let mut ldap_handler = LdapHandler::new(
+            MockTestBackendHandler::new(),
+            "dc=example,dc=com".to_string(),
+            UserId::new("test"),
+        );
+        assert_eq!(
+            ldap_handler.handle_ldap_message(who_am_i()).await,
+            expected_response("")
+        );
+        let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        assert_eq!(
+            ldap_handler.handle_ldap_message(who_am_i()).await,
+            expected_response("dn:uid=test,ou=people,dc=example,dc=com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_extended_operation() {
+        use crate::infra::ldap_extended_ops::ExtendedOpHandler;
+        struct EchoOp;
+        #[async_trait(?Send)]
+        impl ExtendedOpHandler<MockTestBackendHandler> for EchoOp {
+            async fn handle(
+                &self,
+                request: &LdapExtendedRequest,
+                _: &mut LdapHandler<MockTestBackendHandler>,
+            ) -> Vec<LdapOp> {
+                vec![make_extended_response(
+                    LdapResultCode::Success,
+                    format!("{:?}", request.value),
+                )]
+            }
+        }
+        let mut registry = ExtendedOperationRegistry::default();
+        registry.register_extended_op("1.2.3.4", Box::new(EchoOp));
         let mut ldap_handler = setup_bound_handler(MockTestBackendHandler::new()).await;
+        ldap_handler.set_extended_operations(Arc::new(registry));
+        let request = LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: "1.2.3.4".to_string(),
+            value: Some(vec![1]),
+        });
+        assert_eq!(
+            ldap_handler.handle_ldap_message(request).await,
+            Some(vec![make_extended_response(
+                LdapResultCode::Success,
+                "Some([1])".to_string(),
+            )])
+        );
+        let request = LdapSearchRequest {
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["supportedExtension".to_string()],
+        };
+        match &ldap_handler.do_search(&request).await[0] {
+            LdapOp::SearchResultEntry(entry) => assert!(entry.attributes.iter().any(|a| a.atype
+                == "supportedExtension"
+                && a.vals.contains(&"1.2.3.4".to_string()))),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_root_dse() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_count_users().return_once(|_| Ok(3));
+        mock.expect_count_groups().return_once(|_| Ok(2));
+        let mut ldap_handler = setup_bound_handler(mock).await;
         let request = LdapSearchRequest {
             base: "".to_string(),
             scope: LdapSearchScope::Base,
@@ -1663,9 +6663,105 @@ mod tests {
         assert_eq!(
             ldap_handler.do_search(&request).await,
             vec![
-                root_dse_response("dc=example,dc=com"),
+                root_dse_response(
+                    "dc=example,dc=com",
+                    None,
+                    None,
+                    vec![
+                        "1.3.6.1.1.8".to_string(),
+                        "1.3.6.1.4.1.4203.1.11.1".to_string(),
+                        "1.3.6.1.4.1.4203.1.11.3".to_string()
+                    ],
+                    Some((3, 2))
+                ),
                 make_search_success()
             ]
         );
     }
+
+    #[test]
+    fn test_error_response_for_request_tag() {
+        let response = |tag| {
+            make_error_response_for_request_tag(
+                tag,
+                LdapResultCode::ProtocolError,
+                "bad".to_string(),
+            )
+        };
+        let result = LdapResult {
+            code: LdapResultCode::ProtocolError,
+            matcheddn: "".to_string(),
+            message: "bad".to_string(),
+            referral: vec![],
+        };
+        // [APPLICATION 6] ModifyRequest and [APPLICATION 14] CompareRequest.
+        assert_eq!(response(0x66), Some(LdapOp::ModifyResponse(result.clone())));
+        assert_eq!(response(0x6e), Some(LdapOp::CompareResult(result)));
+        assert_eq!(response(0x42), None);
+    }
+
+    #[tokio::test]
+    async fn test_search_root_dse_login_banner() {
+        let mut config =
+            LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"));
+        config.login_banner = Some("Authorized use only.\nAll activity is logged.\n".to_string());
+        // The counts are left out when they can't be computed.
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_count_users()
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut)));
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapSearchRequest {
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["description".to_string()],
+        };
+        let results = ldap_handler.do_search(&request).await;
+        match &results[0] {
+            LdapOp::SearchResultEntry(entry) => assert_eq!(
+                entry.attributes.last().unwrap(),
+                &LdapPartialAttribute {
+                    atype: "description".to_string(),
+                    vals: vec!["Authorized use only.\nAll activity is logged.".to_string()],
+                }
+            ),
+            _ => panic!("Unexpected result: {:?}", results),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_root_dse_server_id() {
+        let mut config =
+            LdapHandlerConfig::new("dc=example,dc=com".to_string(), UserId::new("admin"));
+        config.server_id = Some("lldap-2".to_string());
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_count_users()
+            .return_once(|_| Err(DomainError::DatabaseError(sqlx::Error::PoolTimedOut)));
+        let mut ldap_handler = LdapHandler::new_with_config(config, mock);
+        let request = LdapSearchRequest {
+            base: "".to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec!["serverId".to_string()],
+        };
+        let results = ldap_handler.do_search(&request).await;
+        match &results[0] {
+            LdapOp::SearchResultEntry(entry) => assert_eq!(
+                entry.attributes.last().unwrap(),
+                &LdapPartialAttribute {
+                    atype: "serverId".to_string(),
+                    vals: vec!["lldap-2".to_string()],
+                }
+            ),
+            _ => panic!("Unexpected result: {:?}", results),
+        }
+    }
 }
@@ -0,0 +1,119 @@
+use crate::infra::metrics::{LDAP_SEARCH_CACHE_HITS, LDAP_SEARCH_CACHE_MISSES};
+use ldap3_server::proto::LdapOp;
+use lru::LruCache;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct CachedResults {
+    inserted_at: Instant,
+    /// The `BackendHandler::data_version` read before the search.
+    data_version: u64,
+    results: Vec<LdapOp>,
+}
+
+/// Per-process cache of LDAP search results. Entries expire after the TTL, and are invalidated by
+/// any write to the users and groups, through LDAP or not. Clones share the same cache.
+#[derive(Clone, Default)]
+pub struct LdapSearchCache {
+    cache: Option<Arc<Mutex<LruCache<String, CachedResults>>>>,
+    ttl: Duration,
+}
+
+impl LdapSearchCache {
+    /// Creates a cache holding up to `size` searches. A size or TTL of 0 disables the cache.
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        if size == 0 || ttl.is_zero() {
+            return Self::default();
+        }
+        Self {
+            cache: Some(Arc::new(Mutex::new(LruCache::new(size)))),
+            ttl,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    /// The results of the search, unless they expired or the data changed since.
+    pub fn get(&self, key: &str, data_version: u64) -> Option<Vec<LdapOp>> {
+        let mut cache = self.cache.as_ref()?.lock().unwrap();
+        let results = match cache.get(key) {
+            Some(entry)
+                if entry.inserted_at.elapsed() < self.ttl && entry.data_version == data_version =>
+            {
+                Some(entry.results.clone())
+            }
+            Some(_) => {
+                cache.pop(key);
+                None
+            }
+            None => None,
+        };
+        if results.is_some() {
+            LDAP_SEARCH_CACHE_HITS.inc();
+        } else {
+            LDAP_SEARCH_CACHE_MISSES.inc();
+        }
+        results
+    }
+
+    /// Stores the results of a search, with the data version read before running it: they are
+    /// never returned if a write happened during the search.
+    pub fn insert(&self, key: String, data_version: u64, results: Vec<LdapOp>) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().put(
+                key,
+                CachedResults {
+                    inserted_at: Instant::now(),
+                    data_version,
+                    results,
+                },
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for LdapSearchCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("LdapSearchCache")
+            .field("enabled", &self.cache.is_some())
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ldap3_server::proto::LdapSearchResultEntry;
+
+    fn make_entry(dn: &str) -> LdapOp {
+        LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: dn.to_string(),
+            attributes: vec![],
+        })
+    }
+
+    #[test]
+    fn test_data_version() {
+        let cache = LdapSearchCache::new(10, Duration::from_secs(60));
+        cache.insert("bob".to_string(), 1, vec![make_entry("uid=bob,dc=example")]);
+        assert_eq!(
+            cache.get("bob", 1),
+            Some(vec![make_entry("uid=bob,dc=example")])
+        );
+        assert_eq!(cache.get("bob", 2), None);
+        // The stale entry is gone, even for a reader with the old version.
+        assert_eq!(cache.get("bob", 1), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = LdapSearchCache::new(0, Duration::from_secs(60));
+        cache.insert("bob".to_string(), 1, vec![make_entry("uid=bob,dc=example")]);
+        assert_eq!(cache.get("bob", 1), None);
+    }
+}
@@ -1,33 +1,362 @@
 use crate::{
     domain::{
-        handler::{BackendHandler, LoginHandler, UserId},
+        handler::{BackendHandler, LoginHandler, TransactionHandler},
         opaque_handler::OpaqueHandler,
     },
-    infra::{configuration::Configuration, ldap_handler::LdapHandler},
+    infra::{
+        configuration::Configuration,
+        connection_filter::ConnectionFilter,
+        ldap_codec::{
+            CancelResultCode, LdapFrame, LdapFrameCodec, LdapResponseCodec, LdapResponseWithCode,
+        },
+        ldap_connections::LdapConnectionRegistry,
+        ldap_extended_ops::{parse_cancel_request, ExtendedOperationRegistry, CANCEL_OID},
+        ldap_handler::{
+            make_error_response_for_op, make_error_response_for_request_tag,
+            make_extended_response, make_notice_of_disconnection, LdapHandler, LdapHandlerConfig,
+        },
+        mail::Mailer,
+        maintenance::MaintenanceMode,
+        metrics::{GaugeGuard, LDAP_ACTIVE_CONNECTIONS, LDAP_CONNECTION_SLOTS_USED},
+    },
 };
 use actix_rt::net::TcpStream;
 use actix_server::ServerBuilder;
 use actix_service::{fn_service, ServiceFactoryExt};
 use anyhow::{Context, Result};
-use ldap3_server::{proto::LdapMsg, LdapCodec};
+use ldap3_server::proto::{LdapMsg, LdapOp, LdapResultCode};
 use log::*;
 use native_tls::{Identity, TlsAcceptor};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
 use tokio_native_tls::TlsAcceptor as NativeTlsAcceptor;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
+/// Server-wide limit on the number of LDAP operations executing concurrently.
+#[derive(Clone)]
+struct OperationLimiter {
+    /// None without a limit, when `ldap_max_concurrent_operations` is 0.
+    semaphore: Option<Arc<Semaphore>>,
+    queue_timeout: Duration,
+}
+
+/// No slot was freed before the queue timeout.
+#[derive(Debug, PartialEq, Eq)]
+struct ServerBusy;
+
+impl OperationLimiter {
+    fn new(config: &Configuration) -> Self {
+        Self::with_limits(
+            config.ldap_max_concurrent_operations,
+            Duration::from_secs(config.ldap_operation_queue_timeout_secs),
+        )
+    }
+
+    fn with_limits(max_concurrent_operations: usize, queue_timeout: Duration) -> Self {
+        Self {
+            semaphore: (max_concurrent_operations > 0)
+                .then(|| Arc::new(Semaphore::new(max_concurrent_operations))),
+            queue_timeout,
+        }
+    }
+
+    /// Waits for a free slot, up to the queue timeout. The permit is None without a limit.
+    async fn acquire(&self) -> Result<Option<SemaphorePermit<'_>>, ServerBusy> {
+        let semaphore = match &self.semaphore {
+            Some(semaphore) => semaphore,
+            None => return Ok(None),
+        };
+        let permit = if self.queue_timeout.is_zero() {
+            semaphore.try_acquire().ok()
+        } else {
+            tokio::time::timeout(self.queue_timeout, semaphore.acquire())
+                .await
+                .ok()
+                .and_then(Result::ok)
+        };
+        permit.map(Some).ok_or(ServerBusy)
+    }
+}
+
+/// Server-wide limit on the number of open LDAP and LDAPS connections.
+#[derive(Clone)]
+struct ConnectionLimiter {
+    /// None without a limit, when `ldap_max_total_connections` is 0.
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl ConnectionLimiter {
+    fn new(max_connections: usize) -> Self {
+        Self {
+            semaphore: (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections))),
+        }
+    }
+
+    /// Returns None if all the slots are taken.
+    fn try_acquire(&self) -> Option<ConnectionSlot> {
+        let permit = match &self.semaphore {
+            Some(semaphore) => Some(semaphore.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(ConnectionSlot {
+            _permit: permit,
+            _used: LDAP_CONNECTION_SLOTS_USED.track(),
+        })
+    }
+}
+
+/// A connection slot, released on drop. The permit is None without a limit.
+struct ConnectionSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    _used: GaugeGuard,
+}
+
+/// How many of the last operations of a connection are remembered, to answer the cancel requests
+/// that arrive after the operation completed.
+const MAX_COMPLETED_OPERATIONS: usize = 64;
+
+/// How long an LDAPS client has to complete the TLS handshake, while it holds a connection slot.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many messages are read ahead while an operation executes, to find the cancel requests.
+const MAX_PENDING_MESSAGES: usize = 16;
+
+/// The last operations completed on a connection, by message ID.
+#[derive(Default)]
+struct CompletedOperations {
+    operations: VecDeque<(i32, bool)>,
+}
+
+impl CompletedOperations {
+    fn record(&mut self, msgid: i32, cancelable: bool) {
+        if self.operations.len() == MAX_COMPLETED_OPERATIONS {
+            self.operations.pop_front();
+        }
+        self.operations.push_back((msgid, cancelable));
+    }
+
+    /// The result of a request to cancel the operation, which is not executing.
+    fn cancel_result(&self, msgid: i32) -> CancelResultCode {
+        // The message IDs can be reused once the operation completed: look at the last one.
+        match self.operations.iter().rev().find(|(id, _)| *id == msgid) {
+            Some((_, true)) => CancelResultCode::TooLate,
+            Some((_, false)) => CancelResultCode::CannotCancel,
+            None => CancelResultCode::NoSuchOperation,
+        }
+    }
+}
+
+/// Only the searches can be canceled while they execute: stopping the other operations could
+/// leave a modification half done, and the binds can't be canceled (RFC 3909).
+fn is_cancelable(op: &LdapOp) -> bool {
+    matches!(op, LdapOp::SearchRequest(_))
+}
+
+/// The ID of the operation to cancel, if the message is a cancel request.
+fn get_cancel_target(msg: &LdapMsg) -> Option<Result<i32, String>> {
+    match &msg.op {
+        LdapOp::ExtendedRequest(request) if request.name == CANCEL_OID => {
+            Some(parse_cancel_request(request))
+        }
+        _ => None,
+    }
+}
+
+async fn send_response_with_code<Writer>(
+    resp: &mut Writer,
+    msgid: i32,
+    op: LdapOp,
+    code: CancelResultCode,
+) -> Result<()>
+where
+    Writer: futures_util::Sink<LdapResponseWithCode> + Unpin,
+    <Writer as futures_util::Sink<LdapResponseWithCode>>::Error:
+        std::error::Error + Send + Sync + 'static,
+{
+    use futures_util::SinkExt;
+    debug!(
+        "Replying with LDAP op: {:?} and the result code {:?}",
+        &op, code
+    );
+    resp.send(LdapResponseWithCode {
+        msg: LdapMsg {
+            msgid,
+            op,
+            ctrl: vec![],
+        },
+        code,
+    })
+    .await
+    .context("while sending a response: {:#}")?;
+    resp.flush()
+        .await
+        .context("while flushing responses: {:#}")?;
+    Ok(())
+}
+
+/// Answers a cancel request for an operation that is not executing.
+async fn answer_cancel_request<Writer>(
+    resp: &mut Writer,
+    msgid: i32,
+    target: Result<i32, String>,
+    completed_operations: &CompletedOperations,
+) -> Result<()>
+where
+    Writer: futures_util::Sink<LdapMsg> + futures_util::Sink<LdapResponseWithCode> + Unpin,
+    <Writer as futures_util::Sink<LdapMsg>>::Error: std::error::Error + Send + Sync + 'static,
+    <Writer as futures_util::Sink<LdapResponseWithCode>>::Error:
+        std::error::Error + Send + Sync + 'static,
+{
+    use futures_util::SinkExt;
+    match target {
+        Err(e) => {
+            resp.send(LdapMsg {
+                msgid,
+                op: make_extended_response(LdapResultCode::ProtocolError, e),
+                ctrl: vec![],
+            })
+            .await
+            .context("while sending a response: {:#}")?;
+            <Writer as futures_util::SinkExt<LdapMsg>>::flush(resp)
+                .await
+                .context("while flushing responses: {:#}")?;
+            Ok(())
+        }
+        Ok(target) => {
+            let code = completed_operations.cancel_result(target);
+            let message = format!("Operation {} can't be canceled: {:?}", target, code);
+            // The result code is replaced when encoding the response.
+            let op = make_extended_response(LdapResultCode::Other, message);
+            send_response_with_code(resp, msgid, op, code).await
+        }
+    }
+}
+
+/// How the handling of an operation ended.
+enum OperationOutcome {
+    /// Whether to keep the connection open.
+    Completed(bool),
+    /// Stopped by the cancel request with this message ID.
+    Canceled(i32),
+}
+
+/// State shared by all the connections of a listener.
+#[derive(Clone)]
+struct LdapServerContext<Backend> {
+    backend_handler: Backend,
+    ldap_config: LdapHandlerConfig,
+    limiter: OperationLimiter,
+    /// None when `ldap_idle_timeout_secs` is 0: the connections are never closed for idleness.
+    idle_timeout: Option<Duration>,
+    extended_operations: Arc<ExtendedOperationRegistry<Backend>>,
+    connections: LdapConnectionRegistry,
+}
+
+/// Answers a message that could not be decoded with `protocolError`, or with a notice of
+/// disconnection if its ID is unknown. The connection is closed afterwards.
+async fn reject_malformed_message<Writer>(
+    msgid: Option<i32>,
+    op_tag: Option<u8>,
+    error: String,
+    resp: &mut Writer,
+) -> Result<()>
+where
+    Writer: futures_util::Sink<LdapMsg> + Unpin,
+    <Writer as futures_util::Sink<LdapMsg>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    use futures_util::SinkExt;
+    warn!(
+        "Closing the LDAP connection after a malformed message (ID {:?}): {}",
+        msgid, error
+    );
+    let message = format!("Malformed LDAP message: {}", error);
+    let response = match (msgid, op_tag) {
+        (Some(msgid), Some(op_tag)) => {
+            make_error_response_for_request_tag(op_tag, LdapResultCode::ProtocolError, message)
+                .map(|op| (msgid, op))
+        }
+        _ => None,
+    };
+    // Unsolicited notifications always use the message ID 0 (rfc4511 4.4).
+    let (msgid, op) = response.unwrap_or_else(|| {
+        (
+            0,
+            make_notice_of_disconnection(
+                LdapResultCode::ProtocolError,
+                "malformed message".to_string(),
+            ),
+        )
+    });
+    resp.send(LdapMsg {
+        msgid,
+        op,
+        ctrl: vec![],
+    })
+    .await
+    .context("while sending a response: {:#}")?;
+    resp.flush()
+        .await
+        .context("while flushing responses: {:#}")?;
+    Ok(())
+}
+
 async fn handle_incoming_message<Backend, Writer>(
-    msg: Result<LdapMsg, std::io::Error>,
+    msg: Result<LdapFrame, std::io::Error>,
     resp: &mut Writer,
     session: &mut LdapHandler<Backend>,
+    limiter: &OperationLimiter,
 ) -> Result<bool>
 where
-    Backend: BackendHandler + LoginHandler + OpaqueHandler,
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
     Writer: futures_util::Sink<LdapMsg> + Unpin,
     <Writer as futures_util::Sink<LdapMsg>>::Error: std::error::Error + Send + Sync + 'static,
 {
     use futures_util::SinkExt;
-    let msg = msg.context("while receiving LDAP op")?;
+    let msg = match msg.context("while receiving LDAP op")? {
+        LdapFrame::Message(msg) => msg,
+        LdapFrame::Malformed {
+            msgid,
+            op_tag,
+            error,
+        } => {
+            reject_malformed_message(msgid, op_tag, error, resp).await?;
+            return Ok(false);
+        }
+    };
     debug!("Received LDAP message: {:?}", &msg);
+    // Unbinding doesn't touch the backend, it doesn't need to wait for a slot.
+    let _permit = if msg.op == LdapOp::UnbindRequest {
+        None
+    } else {
+        match limiter.acquire().await {
+            Ok(permit) => permit,
+            Err(ServerBusy) => {
+                warn!("Too many concurrent LDAP operations, replying with busy");
+                let response = make_error_response_for_op(
+                    &msg.op,
+                    LdapResultCode::Busy,
+                    "Too many concurrent operations, try again later".to_string(),
+                );
+                resp.send(LdapMsg {
+                    msgid: msg.msgid,
+                    op: response,
+                    ctrl: vec![],
+                })
+                .await
+                .context("while sending a response: {:#}")?;
+                resp.flush()
+                    .await
+                    .context("while flushing responses: {:#}")?;
+                return Ok(true);
+            }
+        }
+    };
     match session.handle_ldap_message(msg.op).await {
         None => return Ok(false),
         Some(result) => {
@@ -66,29 +395,255 @@ fn get_file_as_byte_vec(filename: &str) -> Result<Vec<u8>> {
     .context(format!("while reading file {}", filename))
 }
 
+/// Sends an unsolicited notice of disconnection, before the server closes the connection.
+async fn send_notice_of_disconnection<Writer>(
+    resp: &mut Writer,
+    code: LdapResultCode,
+    message: &str,
+) -> Result<()>
+where
+    Writer: futures_util::Sink<LdapMsg> + Unpin,
+    <Writer as futures_util::Sink<LdapMsg>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    use futures_util::SinkExt;
+    // Unsolicited notifications always use the message ID 0 (rfc4511 4.4).
+    resp.send(LdapMsg {
+        msgid: 0,
+        op: make_notice_of_disconnection(code, message.to_string()),
+        ctrl: vec![],
+    })
+    .await
+    .context("while sending the notice of disconnection")?;
+    resp.flush()
+        .await
+        .context("while flushing the notice of disconnection")?;
+    Ok(())
+}
+
+/// Tells the client that the server has too many connections, before closing the connection.
+async fn reject_busy_connection<Stream>(
+    stream: Stream,
+    peer_address: Option<SocketAddr>,
+) -> Result<()>
+where
+    Stream: tokio::io::AsyncWrite + Unpin,
+{
+    warn!(
+        "Rejecting the LDAP connection from {:?}: too many connections",
+        peer_address
+    );
+    let mut resp = FramedWrite::new(stream, LdapResponseCodec);
+    send_notice_of_disconnection(&mut resp, LdapResultCode::Busy, "too many connections").await
+}
+
+/// Handles a message, while reading the next ones to stop the operation if they cancel it. The
+/// other messages read in the meantime are added to `pending`. Returns whether to keep the
+/// connection open.
+#[allow(clippy::too_many_arguments)]
+async fn handle_message_with_cancel<Backend, Requests, Writer>(
+    msg: Result<LdapFrame, std::io::Error>,
+    requests: &mut Requests,
+    resp: &mut Writer,
+    session: &mut LdapHandler<Backend>,
+    limiter: &OperationLimiter,
+    pending: &mut VecDeque<Result<LdapFrame, std::io::Error>>,
+    client_done: &mut bool,
+    completed_operations: &mut CompletedOperations,
+    cancel_enabled: bool,
+) -> Result<bool>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler,
+    Backend::Transaction: OpaqueHandler,
+    Requests: tokio_stream::Stream<Item = Result<LdapFrame, std::io::Error>> + Unpin,
+    Writer: futures_util::Sink<LdapMsg> + futures_util::Sink<LdapResponseWithCode> + Unpin,
+    <Writer as futures_util::Sink<LdapMsg>>::Error: std::error::Error + Send + Sync + 'static,
+    <Writer as futures_util::Sink<LdapResponseWithCode>>::Error:
+        std::error::Error + Send + Sync + 'static,
+{
+    use tokio_stream::StreamExt;
+    let msg = match msg {
+        Ok(LdapFrame::Message(msg)) if cancel_enabled => match get_cancel_target(&msg) {
+            Some(target) => {
+                answer_cancel_request(resp, msg.msgid, target, completed_operations).await?;
+                completed_operations.record(msg.msgid, false);
+                return Ok(true);
+            }
+            None => Ok(LdapFrame::Message(msg)),
+        },
+        msg => msg,
+    };
+    let (msgid, cancelable) = match &msg {
+        Ok(LdapFrame::Message(msg)) => (Some(msg.msgid), is_cancelable(&msg.op)),
+        _ => (None, false),
+    };
+    // The response of the operation if it gets canceled.
+    let mut canceled_response = match &msg {
+        Ok(LdapFrame::Message(msg)) if cancelable && cancel_enabled => {
+            Some(make_error_response_for_op(
+                &msg.op,
+                // Replaced when encoding the response.
+                LdapResultCode::Other,
+                "The operation was canceled".to_string(),
+            ))
+        }
+        _ => None,
+    };
+    let outcome = {
+        let handling = handle_incoming_message(msg, resp, session, limiter);
+        tokio::pin!(handling);
+        loop {
+            tokio::select! {
+                keep_going = &mut handling => {
+                    break OperationOutcome::Completed(
+                        keep_going.context("while handling incoming messages")?,
+                    );
+                }
+                next = requests.next(), if !*client_done && pending.len() < MAX_PENDING_MESSAGES => {
+                    match next {
+                        Some(Ok(LdapFrame::Message(next)))
+                            if canceled_response.is_some()
+                                && get_cancel_target(&next).and_then(Result::ok) == msgid =>
+                        {
+                            break OperationOutcome::Canceled(next.msgid);
+                        }
+                        Some(next) => pending.push_back(next),
+                        None => *client_done = true,
+                    }
+                }
+            }
+        }
+    };
+    let keep_going = match outcome {
+        OperationOutcome::Completed(keep_going) => keep_going,
+        OperationOutcome::Canceled(cancel_msgid) => {
+            use futures_util::SinkExt;
+            let msgid = msgid.expect("Only the operations can be canceled");
+            let response = canceled_response
+                .take()
+                .expect("Only the cancelable operations can be canceled");
+            info!("LDAP operation {} canceled by the client", msgid);
+            send_response_with_code(resp, msgid, response, CancelResultCode::Canceled).await?;
+            resp.send(LdapMsg {
+                msgid: cancel_msgid,
+                op: make_extended_response(LdapResultCode::Success, "".to_string()),
+                ctrl: vec![],
+            })
+            .await
+            .context("while sending a response: {:#}")?;
+            <Writer as futures_util::SinkExt<LdapMsg>>::flush(resp)
+                .await
+                .context("while flushing responses: {:#}")?;
+            completed_operations.record(cancel_msgid, false);
+            true
+        }
+    };
+    if let Some(msgid) = msgid {
+        completed_operations.record(msgid, cancelable);
+    }
+    Ok(keep_going)
+}
+
+/// Waits for the next message, up to the idle timeout if there is one.
+async fn with_idle_timeout<F: std::future::Future>(
+    idle_timeout: Option<Duration>,
+    next: F,
+) -> Result<F::Output, tokio::time::error::Elapsed> {
+    match idle_timeout {
+        Some(idle_timeout) => tokio::time::timeout(idle_timeout, next).await,
+        None => Ok(next.await),
+    }
+}
+
 async fn handle_ldap_stream<Stream, Backend>(
     stream: Stream,
-    backend_handler: Backend,
-    ldap_base_dn: String,
-    ldap_user_dn: UserId,
+    peer_address: Option<SocketAddr>,
+    context: LdapServerContext<Backend>,
 ) -> Result<Stream>
 where
-    Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler + 'static,
+    Backend::Transaction: OpaqueHandler,
     Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite,
 {
     use tokio_stream::StreamExt;
+    let LdapServerContext {
+        backend_handler,
+        ldap_config,
+        limiter,
+        idle_timeout,
+        extended_operations,
+        connections,
+    } = context;
+    let _connection = LDAP_ACTIVE_CONNECTIONS.track();
+    let registration = connections.register(peer_address, ldap_config.is_tls);
     let (r, w) = tokio::io::split(stream);
     // Configure the codec etc.
-    let mut requests = FramedRead::new(r, LdapCodec);
-    let mut resp = FramedWrite::new(w, LdapCodec);
+    let mut requests = FramedRead::new(r, LdapFrameCodec);
+    let mut resp = FramedWrite::new(w, LdapResponseCodec);
 
-    let mut session = LdapHandler::new(backend_handler, ldap_base_dn, ldap_user_dn);
+    let cancel_enabled = extended_operations.get(CANCEL_OID).is_some();
+    let mut session = LdapHandler::new_with_config(ldap_config, backend_handler);
+    session.set_extended_operations(extended_operations);
+    let session_start = Instant::now();
+    // The messages read while an operation executed, to handle once it completes.
+    let mut pending = VecDeque::new();
+    let mut client_done = false;
+    let mut completed_operations = CompletedOperations::default();
 
-    while let Some(msg) = requests.next().await {
-        if !handle_incoming_message(msg, &mut resp, &mut session)
-            .await
-            .context("while handling incoming messages")?
-        {
+    loop {
+        let msg = match pending.pop_front() {
+            Some(msg) => msg,
+            None if client_done => break,
+            None => {
+                let next = tokio::select! {
+                    next = with_idle_timeout(idle_timeout, requests.next()) => next,
+                    _ = registration.closed() => {
+                        info!(
+                            "Closing the LDAP connection {} from {:?} at the request of an admin",
+                            registration.id(),
+                            peer_address
+                        );
+                        send_notice_of_disconnection(
+                            &mut resp,
+                            LdapResultCode::Unavailable,
+                            "connection closed by an administrator",
+                        )
+                        .await?;
+                        break;
+                    }
+                };
+                match next {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break,
+                    Err(_) => {
+                        debug!(
+                            "Closing idle LDAP connection after {:?}",
+                            session_start.elapsed()
+                        );
+                        send_notice_of_disconnection(
+                            &mut resp,
+                            LdapResultCode::OperationsError,
+                            "idle timeout exceeded",
+                        )
+                        .await?;
+                        break;
+                    }
+                }
+            }
+        };
+        let keep_going = handle_message_with_cancel(
+            msg,
+            &mut requests,
+            &mut resp,
+            &mut session,
+            &limiter,
+            &mut pending,
+            &mut client_done,
+            &mut completed_operations,
+            cancel_enabled,
+        )
+        .await?;
+        registration.record_operation(session.get_bound_dn());
+        if !keep_going {
             break;
         }
     }
@@ -103,32 +658,71 @@ fn get_tls_acceptor(config: &Configuration) -> Result<NativeTlsAcceptor> {
     Ok(TlsAcceptor::new(identity)?.into())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn build_ldap_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    maintenance_mode: MaintenanceMode,
+    mailer: Mailer,
+    extended_operations: ExtendedOperationRegistry<Backend>,
+    connections: LdapConnectionRegistry,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
-    Backend: BackendHandler + LoginHandler + OpaqueHandler + 'static,
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + TransactionHandler + 'static,
+    Backend::Transaction: OpaqueHandler,
 {
-    let context = (
+    let ldap_config = LdapHandlerConfig {
+        maintenance_mode,
+        mailer,
+        ..LdapHandlerConfig::from(config)
+    };
+    let context = LdapServerContext {
         backend_handler,
-        config.ldap_base_dn.clone(),
-        config.ldap_user_dn.clone(),
-    );
+        ldap_config,
+        limiter: OperationLimiter::new(config),
+        idle_timeout: (config.ldap_idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.ldap_idle_timeout_secs)),
+        extended_operations: Arc::new(extended_operations),
+        connections,
+    };
 
     let tls_context = (
-        context.clone(),
+        LdapServerContext {
+            ldap_config: LdapHandlerConfig {
+                is_tls: true,
+                ..context.ldap_config.clone()
+            },
+            ..context.clone()
+        },
         get_tls_acceptor(config).context("while setting up the SSL certificate")?,
     );
 
+    let connection_filter = ConnectionFilter::new(config);
+    let tls_connection_filter = connection_filter.clone();
+    // Shared by both listeners.
+    let connection_limiter = ConnectionLimiter::new(config.ldap_max_total_connections);
+    let tls_connection_limiter = connection_limiter.clone();
+
     let binder = move || {
         let context = context.clone();
+        let connection_filter = connection_filter.clone();
+        let connection_limiter = connection_limiter.clone();
         fn_service(move |stream: TcpStream| {
             let context = context.clone();
+            let peer_address = stream.peer_addr().ok();
+            let accepted = connection_filter.accept(stream.peer_addr(), "LDAP");
+            let slot = accepted.then(|| connection_limiter.try_acquire()).flatten();
             async move {
-                let (handler, base_dn, user_dn) = context;
-                handle_ldap_stream(stream, handler, base_dn, user_dn).await
+                if !accepted {
+                    return Ok(());
+                }
+                let _slot = match slot {
+                    Some(slot) => slot,
+                    None => return reject_busy_connection(stream, peer_address).await,
+                };
+                handle_ldap_stream(stream, peer_address, context).await?;
+                Ok(())
             }
         })
         .map_err(|err: anyhow::Error| error!("[LDAP] Service Error: {:#}", err))
@@ -136,12 +730,39 @@ where
 
     let tls_binder = move || {
         let tls_context = tls_context.clone();
+        let connection_filter = tls_connection_filter.clone();
+        let connection_limiter = tls_connection_limiter.clone();
         fn_service(move |stream: TcpStream| {
             let tls_context = tls_context.clone();
+            let peer_address = stream.peer_addr().ok();
+            let accepted = connection_filter.accept(stream.peer_addr(), "LDAPS");
+            // Taken before the TLS handshake, which also uses resources.
+            let slot = accepted.then(|| connection_limiter.try_acquire()).flatten();
             async move {
-                let ((handler, base_dn, user_dn), tls_acceptor) = tls_context;
-                let tls_stream = tls_acceptor.clone().accept(stream).await?;
-                handle_ldap_stream(tls_stream, handler, base_dn, user_dn).await
+                if !accepted {
+                    return Ok(());
+                }
+                // Without a slot, the connection is closed right away: there is no handshake to
+                // send the notice of disconnection over.
+                let _slot = match slot {
+                    Some(slot) => slot,
+                    None => {
+                        warn!(
+                            "Rejecting the LDAPS connection from {:?}: too many connections",
+                            peer_address
+                        );
+                        return Ok(());
+                    }
+                };
+                let (context, tls_acceptor) = tls_context;
+                let tls_stream = tokio::time::timeout(
+                    TLS_HANDSHAKE_TIMEOUT,
+                    tls_acceptor.clone().accept(stream),
+                )
+                .await
+                .context("TLS handshake timed out")??;
+                handle_ldap_stream(tls_stream, peer_address, context).await?;
+                Ok(())
             }
         })
         .map_err(|err: anyhow::Error| error!("[LDAPS] Service Error: {:#}", err))
@@ -159,3 +780,354 @@ where
         server_builder
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            sql_backend_handler::SqlBackendHandler,
+            sql_tables::{init_table, PoolOptions},
+        },
+        infra::configuration::ConfigurationBuilder,
+    };
+    use ldap3_server::LdapCodec;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_connection_limiter() {
+        let limiter = ConnectionLimiter::new(2);
+        let first = limiter.try_acquire().unwrap();
+        let _second = limiter.try_acquire().unwrap();
+        assert!(limiter.try_acquire().is_none());
+        drop(first);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_connection_limiter_unlimited() {
+        let limiter = ConnectionLimiter::new(0);
+        let slots = (0..100)
+            .map(|_| limiter.try_acquire())
+            .collect::<Option<Vec<_>>>();
+        assert_eq!(slots.map(|slots| slots.len()), Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_operation_limiter() {
+        let limiter = OperationLimiter::with_limits(1, Duration::ZERO);
+        let permit = limiter.acquire().await.unwrap();
+        assert!(permit.is_some());
+        assert_eq!(limiter.acquire().await.unwrap_err(), ServerBusy);
+        drop(permit);
+        assert!(limiter.acquire().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_operation_limiter_queue_timeout() {
+        let limiter = Arc::new(OperationLimiter::with_limits(1, Duration::from_secs(5)));
+        let permit = limiter
+            .semaphore
+            .clone()
+            .unwrap()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let waiting = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire().await.map(|p| p.is_some()) })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(permit);
+        assert_eq!(waiting.await.unwrap(), Ok(true));
+        let limiter = OperationLimiter::with_limits(1, Duration::from_millis(10));
+        let _permit = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.acquire().await.unwrap_err(), ServerBusy);
+    }
+
+    #[tokio::test]
+    async fn test_operation_limiter_unlimited() {
+        let limiter = OperationLimiter::with_limits(0, Duration::ZERO);
+        let _first = limiter.acquire().await.unwrap();
+        let second = limiter.acquire().await.unwrap();
+        assert!(second.is_none());
+    }
+
+    async fn get_test_context(
+        idle_timeout: Option<Duration>,
+    ) -> LdapServerContext<SqlBackendHandler> {
+        let config = ConfigurationBuilder::default().build().unwrap();
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        LdapServerContext {
+            backend_handler: SqlBackendHandler::new(config.clone(), sql_pool),
+            ldap_config: LdapHandlerConfig::from(&config),
+            limiter: OperationLimiter::new(&config),
+            idle_timeout,
+            extended_operations: Arc::new(ExtendedOperationRegistry::default()),
+            connections: LdapConnectionRegistry::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (client, server) = tokio::io::duplex(4096);
+        let context = get_test_context(Some(Duration::from_millis(50))).await;
+        let mut responses = FramedRead::new(client, LdapCodec);
+        let (closed, notice) = tokio::join!(handle_ldap_stream(server, None, context), async {
+            tokio::time::timeout(Duration::from_secs(5), responses.next()).await
+        });
+        closed.unwrap();
+        assert_eq!(
+            notice.unwrap().unwrap().unwrap(),
+            LdapMsg {
+                msgid: 0,
+                op: make_notice_of_disconnection(
+                    LdapResultCode::OperationsError,
+                    "idle timeout exceeded".to_string()
+                ),
+                ctrl: vec![],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_idle_timeout() {
+        let (client, server) = tokio::io::duplex(4096);
+        let context = get_test_context(None).await;
+        let mut responses = FramedRead::new(client, LdapCodec);
+        tokio::select! {
+            _ = handle_ldap_stream(server, None, context) => panic!("The connection was closed"),
+            next = tokio::time::timeout(Duration::from_millis(100), responses.next()) => {
+                assert!(next.is_err(), "Unexpected message: {:?}", next);
+            }
+        }
+    }
+
+    /// Encodes the responses expected on the connection, to compare them byte for byte: the
+    /// cancel result codes can't be decoded by `LdapCodec`.
+    fn encode_responses(responses: Vec<(LdapMsg, Option<CancelResultCode>)>) -> Vec<u8> {
+        use tokio_util::codec::Encoder;
+        let mut buf = bytes::BytesMut::new();
+        for (msg, code) in responses {
+            match code {
+                Some(code) => {
+                    LdapResponseCodec.encode(LdapResponseWithCode { msg, code }, &mut buf)
+                }
+                None => LdapResponseCodec.encode(msg, &mut buf),
+            }
+            .unwrap();
+        }
+        buf.to_vec()
+    }
+
+    fn make_cancel_request(msgid: i32, target: u8) -> LdapMsg {
+        LdapMsg {
+            msgid,
+            op: LdapOp::ExtendedRequest(ldap3_server::proto::LdapExtendedRequest {
+                name: CANCEL_OID.to_string(),
+                value: Some(vec![0x30, 0x03, 0x02, 0x01, target]),
+            }),
+            ctrl: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_search() {
+        use futures_util::SinkExt;
+        use ldap3_server::proto::{
+            LdapDerefAliases, LdapFilter, LdapSearchRequest, LdapSearchScope,
+        };
+        use tokio::io::AsyncReadExt;
+        let mut context = get_test_context(None).await;
+        context.limiter = OperationLimiter::with_limits(1, Duration::from_secs(5));
+        // The search waits for this slot, it stays in flight until it's canceled.
+        let _permit = context
+            .limiter
+            .semaphore
+            .clone()
+            .unwrap()
+            .acquire_owned()
+            .await
+            .unwrap();
+        let (client, server) = tokio::io::duplex(4096);
+        let (mut reader, writer) = tokio::io::split(client);
+        let mut requests = FramedWrite::new(writer, LdapCodec);
+        let search = LdapOp::SearchRequest(LdapSearchRequest {
+            base: "dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs: vec![],
+        });
+        let expected_canceled = encode_responses(vec![
+            (
+                LdapMsg {
+                    msgid: 2,
+                    op: make_error_response_for_op(
+                        &search,
+                        LdapResultCode::Other,
+                        "The operation was canceled".to_string(),
+                    ),
+                    ctrl: vec![],
+                },
+                Some(CancelResultCode::Canceled),
+            ),
+            (
+                LdapMsg {
+                    msgid: 3,
+                    op: make_extended_response(LdapResultCode::Success, "".to_string()),
+                    ctrl: vec![],
+                },
+                None,
+            ),
+        ]);
+        // Once the search is over, it's too late to cancel it.
+        let expected_too_late = encode_responses(vec![
+            (
+                LdapMsg {
+                    msgid: 4,
+                    op: make_extended_response(
+                        LdapResultCode::Other,
+                        "Operation 2 can't be canceled: TooLate".to_string(),
+                    ),
+                    ctrl: vec![],
+                },
+                Some(CancelResultCode::TooLate),
+            ),
+            (
+                LdapMsg {
+                    msgid: 5,
+                    op: make_extended_response(
+                        LdapResultCode::Other,
+                        "Operation 9 can't be canceled: NoSuchOperation".to_string(),
+                    ),
+                    ctrl: vec![],
+                },
+                Some(CancelResultCode::NoSuchOperation),
+            ),
+        ]);
+        let client = async {
+            requests
+                .send(LdapMsg {
+                    msgid: 2,
+                    op: search.clone(),
+                    ctrl: vec![],
+                })
+                .await
+                .unwrap();
+            requests.send(make_cancel_request(3, 2)).await.unwrap();
+            let mut canceled = vec![0; expected_canceled.len()];
+            reader.read_exact(&mut canceled).await.unwrap();
+            requests.send(make_cancel_request(4, 2)).await.unwrap();
+            requests.send(make_cancel_request(5, 9)).await.unwrap();
+            let mut too_late = vec![0; expected_too_late.len()];
+            reader.read_exact(&mut too_late).await.unwrap();
+            requests
+                .send(LdapMsg {
+                    msgid: 6,
+                    op: LdapOp::UnbindRequest,
+                    ctrl: vec![],
+                })
+                .await
+                .unwrap();
+            (canceled, too_late)
+        };
+        let (closed, (canceled, too_late)) = tokio::time::timeout(
+            Duration::from_secs(5),
+            futures::future::join(handle_ldap_stream(server, None, context), client),
+        )
+        .await
+        .unwrap();
+        closed.unwrap();
+        assert_eq!(canceled, expected_canceled);
+        assert_eq!(too_late, expected_too_late);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_bind() {
+        use crate::{
+            domain::{
+                handler::{CreateUserRequest, UserId},
+                sql_opaque_handler::register_password,
+            },
+            infra::ldap_upstream::{upstream_bind, UpstreamLdapConfig, UpstreamUser},
+        };
+        let context = get_test_context(None).await;
+        context
+            .backend_handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "bob@bob.bob".to_string(),
+                display_name: Some("Bob Bobbersson".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        register_password(
+            &context.backend_handler,
+            &UserId::new("bob"),
+            &secstr::SecUtf8::from("bob00"),
+        )
+        .await
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = UpstreamLdapConfig {
+            url: format!("ldap://{}", listener.local_addr().unwrap()),
+            bind_dn_template: "uid={user},ou=people,dc=example,dc=com".to_string(),
+            auto_provision: false,
+        };
+        // The handler is not Send: serve the two connections on this task.
+        let server = async {
+            for _ in 0..2 {
+                let (stream, peer) = listener.accept().await.unwrap();
+                handle_ldap_stream(stream, Some(peer), context.clone())
+                    .await
+                    .unwrap();
+            }
+        };
+        let client = async {
+            let accepted = upstream_bind(&config, "bob", "bob00").await.unwrap();
+            let rejected = upstream_bind(&config, "bob", "wrong_password")
+                .await
+                .unwrap();
+            (accepted, rejected)
+        };
+        let (_, (accepted, rejected)) = tokio::join!(server, client);
+        assert_eq!(
+            accepted,
+            Some(UpstreamUser {
+                email: "bob@bob.bob".to_string(),
+                display_name: "Bob Bobbersson".to_string(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(rejected, None);
+    }
+
+    #[test]
+    fn test_completed_operations_cancel_result() {
+        let mut operations = CompletedOperations::default();
+        operations.record(2, true);
+        operations.record(3, false);
+        assert_eq!(operations.cancel_result(2), CancelResultCode::TooLate);
+        assert_eq!(operations.cancel_result(3), CancelResultCode::CannotCancel);
+        assert_eq!(
+            operations.cancel_result(4),
+            CancelResultCode::NoSuchOperation
+        );
+        // The message IDs can be reused.
+        operations.record(2, false);
+        assert_eq!(operations.cancel_result(2), CancelResultCode::CannotCancel);
+        for msgid in 10..10 + MAX_COMPLETED_OPERATIONS as i32 {
+            operations.record(msgid, true);
+        }
+        assert_eq!(
+            operations.cancel_result(3),
+            CancelResultCode::NoSuchOperation
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use ldap3::{LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Upstream LDAP server used to authenticate the users that don't have credentials in lldap, for
+/// migrations.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UpstreamLdapConfig {
+    /// e.g. "ldaps://old-ldap.example.com".
+    pub url: String,
+    /// DN used to bind as the user, with `{user}` replaced by the user id.
+    pub bind_dn_template: String,
+    /// Create the users that successfully bind upstream, with the attributes of their upstream
+    /// entry.
+    #[serde(default)]
+    pub auto_provision: bool,
+}
+
+/// Attributes of a user that bound successfully upstream.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UpstreamUser {
+    pub email: String,
+    pub display_name: String,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+impl UpstreamUser {
+    fn from_attributes(attrs: &HashMap<String, Vec<String>>) -> Self {
+        let get = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| {
+                    attrs
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                        .and_then(|(_, values)| values.first().cloned())
+                })
+                .unwrap_or_default()
+        };
+        Self {
+            email: get(&["mail"]),
+            display_name: get(&["displayName", "cn"]),
+            first_name: get(&["givenName"]),
+            last_name: get(&["sn"]),
+        }
+    }
+}
+
+fn get_bind_dn(config: &UpstreamLdapConfig, user_id: &str) -> String {
+    config
+        .bind_dn_template
+        .replace("{user}", &ldap3::dn_escape(user_id))
+}
+
+/// Time allowed for the whole exchange with the upstream server, so that a server that accepts
+/// the connection but never answers doesn't hold the bind forever.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Binds as the user on the upstream server. Returns the attributes of the user if the
+/// credentials are valid, None if they are rejected.
+pub async fn upstream_bind(
+    config: &UpstreamLdapConfig,
+    user_id: &str,
+    password: &str,
+) -> Result<Option<UpstreamUser>> {
+    if password.is_empty() {
+        // That would be an unauthenticated bind, which always succeeds.
+        return Ok(None);
+    }
+    tokio::time::timeout(
+        UPSTREAM_TIMEOUT,
+        bind_and_read_entry(config, user_id, password),
+    )
+    .await
+    .with_context(|| format!("timed out waiting for {}", &config.url))?
+}
+
+async fn bind_and_read_entry(
+    config: &UpstreamLdapConfig,
+    user_id: &str,
+    password: &str,
+) -> Result<Option<UpstreamUser>> {
+    let dn = get_bind_dn(config, user_id);
+    let settings = LdapConnSettings::new().set_conn_timeout(UPSTREAM_TIMEOUT);
+    let (connection, mut ldap) = LdapConnAsync::with_settings(settings, &config.url)
+        .await
+        .with_context(|| format!("while connecting to {}", &config.url))?;
+    ldap3::drive!(connection);
+    if ldap.simple_bind(&dn, password).await?.rc != 0 {
+        let _ = ldap.unbind().await;
+        return Ok(None);
+    }
+    let (entries, _) = ldap
+        .search(
+            &dn,
+            Scope::Base,
+            "(objectClass=*)",
+            vec!["mail", "displayName", "cn", "givenName", "sn"],
+        )
+        .await?
+        .success()
+        .with_context(|| format!("while reading the upstream entry {}", &dn))?;
+    let _ = ldap.unbind().await;
+    Ok(Some(
+        entries
+            .into_iter()
+            .next()
+            .map(|entry| UpstreamUser::from_attributes(&SearchEntry::construct(entry).attrs))
+            .unwrap_or_default(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_bind_dn() {
+        let config = UpstreamLdapConfig {
+            url: "ldap://localhost".to_string(),
+            bind_dn_template: "uid={user},ou=people,dc=old,dc=com".to_string(),
+            auto_provision: false,
+        };
+        assert_eq!(
+            get_bind_dn(&config, "bob"),
+            "uid=bob,ou=people,dc=old,dc=com"
+        );
+        assert_eq!(
+            get_bind_dn(&config, "bob,dc"),
+            "uid=bob\\2cdc,ou=people,dc=old,dc=com"
+        );
+    }
+
+    #[test]
+    fn test_upstream_user_from_attributes() {
+        let attrs: HashMap<String, Vec<String>> = [
+            ("mail", vec!["bob@example.com"]),
+            ("cn", vec!["Bob B", "Bobby"]),
+            ("givenname", vec!["Bob"]),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.into_iter().map(str::to_string).collect()))
+        .collect();
+        assert_eq!(
+            UpstreamUser::from_attributes(&attrs),
+            UpstreamUser {
+                email: "bob@example.com".to_string(),
+                display_name: "Bob B".to_string(),
+                first_name: "Bob".to_string(),
+                last_name: "".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::infra::{
+    cli::{SearchUsersOpts, TestLdapOpts},
+    configuration::Configuration,
+    ldap_check::{bind, connect, LdapCheckOptions},
+};
+use anyhow::Result;
+use ldap3::{Scope, SearchEntry};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A user entry returned by the search, with the attributes sorted by name.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct UserEntry {
+    pub dn: String,
+    pub attributes: BTreeMap<String, Vec<String>>,
+    /// The values that are not valid UTF-8, base64-encoded.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub binary_attributes: BTreeMap<String, Vec<String>>,
+}
+
+impl From<SearchEntry> for UserEntry {
+    fn from(entry: SearchEntry) -> Self {
+        Self {
+            dn: entry.dn,
+            attributes: entry.attrs.into_iter().collect(),
+            binary_attributes: entry
+                .bin_attrs
+                .into_iter()
+                .map(|(name, values)| (name, values.iter().map(base64::encode).collect()))
+                .collect(),
+        }
+    }
+}
+
+/// Connects to the server with the admin credentials of the configuration.
+pub fn make_options(opts: &SearchUsersOpts, config: &Configuration) -> LdapCheckOptions {
+    LdapCheckOptions::new(
+        &TestLdapOpts {
+            general_config: opts.general_config.clone(),
+            host: opts.host.clone(),
+            bind_dn: None,
+            bind_pw: None,
+            search_base: None,
+            ldaps: opts.ldaps,
+            starttls: opts.starttls,
+            no_tls_verify: opts.no_tls_verify,
+            output_opts: opts.output_opts.clone(),
+        },
+        config,
+    )
+}
+
+pub async fn search_users(
+    options: &LdapCheckOptions,
+    filter: &str,
+    attrs: &[String],
+) -> Result<Vec<UserEntry>> {
+    let mut ldap = connect(options).await?;
+    bind(&mut ldap, options).await?;
+    let (entries, _) = ldap
+        .search(&options.search_base, Scope::Subtree, filter, attrs.to_vec())
+        .await?
+        .success()?;
+    let _ = ldap.unbind().await;
+    Ok(entries
+        .into_iter()
+        .map(|entry| SearchEntry::construct(entry).into())
+        .collect())
+}
+
+/// Whether the value can be written as is in LDIF, i.e. is a SAFE-STRING (RFC 2849).
+fn is_safe_ldif_string(value: &str) -> bool {
+    !value.starts_with(|c| c == ' ' || c == ':' || c == '<')
+        && !value.ends_with(' ')
+        && value
+            .bytes()
+            .all(|b| b != 0 && b != b'\n' && b != b'\r' && b < 0x80)
+}
+
+fn push_ldif_line(output: &mut String, name: &str, value: &str) {
+    if is_safe_ldif_string(value) {
+        output.push_str(&format!("{}: {}\n", name, value));
+    } else {
+        output.push_str(&format!("{}:: {}\n", name, base64::encode(value)));
+    }
+}
+
+/// The LDIF record of the entry, each line ending with a line break.
+pub fn to_ldif(entry: &UserEntry) -> String {
+    let mut output = String::new();
+    push_ldif_line(&mut output, "dn", &entry.dn);
+    for (name, values) in &entry.attributes {
+        for value in values {
+            push_ldif_line(&mut output, name, value);
+        }
+    }
+    for (name, values) in &entry.binary_attributes {
+        for value in values {
+            output.push_str(&format!("{}:: {}\n", name, value));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_entries() -> Vec<UserEntry> {
+        let mut bob = UserEntry {
+            dn: "uid=bob,ou=people,dc=example,dc=com".to_string(),
+            ..Default::default()
+        };
+        bob.attributes
+            .insert("mail".to_string(), vec!["bob@example.com".to_string()]);
+        bob.attributes.insert(
+            "memberOf".to_string(),
+            vec![
+                "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+                "cn=users,ou=groups,dc=example,dc=com".to_string(),
+            ],
+        );
+        bob.attributes
+            .insert("cn".to_string(), vec!["Bôb".to_string()]);
+        let alice = UserEntry {
+            dn: "uid=alice,ou=people,dc=example,dc=com".to_string(),
+            ..Default::default()
+        };
+        vec![bob, alice]
+    }
+
+    #[test]
+    fn test_to_ldif() {
+        let entries = make_entries();
+        assert_eq!(
+            to_ldif(&entries[0]),
+            "dn: uid=bob,ou=people,dc=example,dc=com\n\
+             cn:: QsO0Yg==\n\
+             mail: bob@example.com\n\
+             memberOf: cn=admins,ou=groups,dc=example,dc=com\n\
+             memberOf: cn=users,ou=groups,dc=example,dc=com\n"
+        );
+        assert_eq!(
+            to_ldif(&entries[1]),
+            "dn: uid=alice,ou=people,dc=example,dc=com\n"
+        );
+    }
+
+    #[test]
+    fn test_is_safe_ldif_string() {
+        assert!(is_safe_ldif_string("Bob Bobberson"));
+        assert!(is_safe_ldif_string(""));
+        assert!(!is_safe_ldif_string(" leading space"));
+        assert!(!is_safe_ldif_string("trailing space "));
+        assert!(!is_safe_ldif_string(":colon"));
+        assert!(!is_safe_ldif_string("<url"));
+        assert!(!is_safe_ldif_string("two\nlines"));
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(
+            serde_json::to_value(&make_entries()[1]).unwrap(),
+            serde_json::json!({
+                "dn": "uid=alice,ou=people,dc=example,dc=com",
+                "attributes": {},
+            })
+        );
+    }
+}
@@ -1,14 +1,97 @@
-use crate::infra::configuration::Configuration;
-use tracing_subscriber::prelude::*;
+use crate::infra::{cli_output::Verbosity, configuration::Configuration};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format::{self, FormatEvent, FormatFields},
+        FmtContext, MakeWriter,
+    },
+    prelude::*,
+    registry::LookupSpan,
+};
+
+/// Prefixes the log lines with the id of the server, to tell the instances apart.
+struct WithServerId<F> {
+    server_id: String,
+    inner: F,
+}
+
+impl<S, N, F> FormatEvent<S, N> for WithServerId<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        write!(writer, "[{}] ", self.server_id)?;
+        self.inner.format_event(ctx, writer, event)
+    }
+}
 
 pub fn init(config: &Configuration) -> anyhow::Result<()> {
-    let max_log_level = log_level_from_config(config);
-    let sqlx_max_log_level = sqlx_log_level_from_config(config);
-    let filter = tracing_subscriber::filter::Targets::new()
+    init_with_writer(
+        config,
+        log_level_from_config(config),
+        sqlx_log_level_from_config(config),
+        std::io::stdout,
+        super::trace_propagation::init_tracer(&config.opentelemetry)?,
+    )
+}
+
+/// Logs to the standard error for the CLI commands, to keep the standard output for the results.
+pub fn init_cli(config: &Configuration, verbosity: Verbosity) -> anyhow::Result<()> {
+    let (max_log_level, sqlx_max_log_level) = match verbosity {
+        Verbosity::Quiet => (tracing::Level::ERROR, tracing::Level::ERROR),
+        _ => (
+            log_level_from_config(config),
+            sqlx_log_level_from_config(config),
+        ),
+    };
+    init_with_writer(
+        config,
+        max_log_level,
+        sqlx_max_log_level,
+        std::io::stderr,
+        None,
+    )
+}
+
+fn init_with_writer<W>(
+    config: &Configuration,
+    max_log_level: tracing::Level,
+    sqlx_max_log_level: tracing::Level,
+    writer: W,
+    tracer: Option<opentelemetry::sdk::trace::Tracer>,
+) -> anyhow::Result<()>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let mut filter = tracing_subscriber::filter::Targets::new()
         .with_target("lldap", max_log_level)
         .with_target("sqlx", sqlx_max_log_level);
+    if config.request_logging.enabled {
+        // The request bodies are logged at the trace level, without enabling the other traces.
+        filter = filter.with_target(super::request_logging::LOG_TARGET, tracing::Level::TRACE);
+    }
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_filter(filter))
+        .with(tracer.map(|tracer| {
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(filter.clone())
+        }))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .event_format(WithServerId {
+                    server_id: config.server_id.clone(),
+                    inner: format::Format::default(),
+                })
+                .with_filter(filter),
+        )
         .init();
     Ok(())
 }
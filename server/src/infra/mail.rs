@@ -1,44 +1,322 @@
-use crate::infra::configuration::MailOptions;
-use anyhow::Result;
+use crate::infra::{
+    configuration::{MailOptions, OutboundProxyOptions},
+    outbound_proxy::{self, Tunnel},
+};
+use anyhow::{anyhow, bail, Result};
 use lettre::{
-    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
-    Transport,
+    address::Envelope,
+    message::Mailbox,
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Tls, TlsParameters},
+        response::Code,
+        Error as SmtpError, PoolConfig, SmtpTransportBuilder,
+    },
+    Message, SmtpTransport, Transport,
 };
-use log::debug;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+
+/// Outcome of an attempt to send a test email, reported to the administrator.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "message", rename_all = "snake_case")]
+pub enum MailTestResult {
+    Success,
+    AuthenticationFailure(String),
+    TlsFailure(String),
+    Timeout(String),
+    Error(String),
+}
 
-fn send_email(to: Mailbox, subject: &str, body: String, options: &MailOptions) -> Result<()> {
-    let from = options
+/// The replies to AUTH when the server refuses the credentials, or requires them.
+fn is_authentication_failure(code: Code) -> bool {
+    matches!(code.to_string().as_str(), "454" | "530" | "534" | "535")
+}
+
+impl From<anyhow::Error> for MailTestResult {
+    fn from(error: anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        let is_timeout = error.chain().any(|e| {
+            e.downcast_ref::<std::io::Error>()
+                .map(|e| e.kind() == std::io::ErrorKind::TimedOut)
+                .unwrap_or(false)
+        });
+        if let Some(smtp_error) = error.downcast_ref::<SmtpError>() {
+            return if is_timeout || smtp_error.is_timeout() {
+                MailTestResult::Timeout(message)
+            } else if smtp_error.is_tls() {
+                MailTestResult::TlsFailure(message)
+            } else if smtp_error.status().map_or(false, is_authentication_failure) {
+                MailTestResult::AuthenticationFailure(message)
+            } else {
+                // The other replies of the server, and the connection errors.
+                MailTestResult::Error(message)
+            };
+        }
+        // Last resort, for the errors that don't come from the SMTP transport.
+        let lowercase = message.to_lowercase();
+        if is_timeout || lowercase.contains("timed out") {
+            MailTestResult::Timeout(message)
+        } else if lowercase.contains("535") || lowercase.contains("authentication") {
+            MailTestResult::AuthenticationFailure(message)
+        } else if lowercase.contains("tls") || lowercase.contains("certificate") {
+            MailTestResult::TlsFailure(message)
+        } else {
+            MailTestResult::Error(message)
+        }
+    }
+}
+
+fn make_email(to: Mailbox, subject: &str, body: String, options: &MailOptions) -> Result<Message> {
+    let mut from = options
         .from
         .clone()
         .unwrap_or_else(|| "LLDAP <nobody@lldap>".parse().unwrap());
+    if let Some(display_name) = &options.display_name {
+        from.name = Some(display_name.clone());
+    }
     let reply_to = options.reply_to.clone().unwrap_or_else(|| from.clone());
     debug!(
         "Sending email to '{}' as '{}' via '{}'@'{}':'{}'",
         &to, &from, &options.user, &options.server, options.port
     );
-    let email = Message::builder()
+    let mut builder = Message::builder();
+    if let Some(sender) = &options.sender {
+        // The envelope sender receives the bounces.
+        builder = builder.sender(sender.clone()).envelope(Envelope::new(
+            Some(sender.email.clone()),
+            vec![to.email.clone()],
+        )?);
+    }
+    Ok(builder
         .from(from)
         .reply_to(reply_to)
         .to(to)
         .subject(subject)
-        .body(body)?;
+        .body(body)?)
+}
+
+/// Goes through the tunnel of the outbound proxy, unless the server is excluded from it. The tunnel
+/// must be kept as long as the transport, and told about each use of the transport.
+fn make_transport_builder(
+    options: &MailOptions,
+    proxy: &OutboundProxyOptions,
+) -> Result<(SmtpTransportBuilder, Option<Tunnel>)> {
     let creds = Credentials::new(
         options.user.clone(),
         options.password.unsecure().to_string(),
     );
-    let mailer = SmtpTransport::relay(&options.server)?
+    // The TLS parameters keep the name of the server, to check its certificate through the tunnel.
+    let tls_parameters = || TlsParameters::new(options.server.clone());
+    let tls = if !options.tls_required {
+        Tls::None
+    } else if options.port == 465 {
+        // Port 465 is for implicit TLS, the others use STARTTLS.
+        Tls::Wrapper(tls_parameters()?)
+    } else {
+        Tls::Required(tls_parameters()?)
+    };
+    let tunnel = outbound_proxy::get_proxy_for(proxy, &options.server)?
+        .map(|proxy| Tunnel::start(proxy, options.server.clone(), options.port))
+        .transpose()?;
+    let (host, port) = match &tunnel {
+        Some(tunnel) => (
+            tunnel.local_address().ip().to_string(),
+            tunnel.local_address().port(),
+        ),
+        None => (options.server.clone(), options.port),
+    };
+    let builder = SmtpTransport::builder_dangerous(host)
+        .port(port)
+        .tls(tls)
         .credentials(creds)
-        .build();
-    mailer.send(&email)?;
+        .timeout(Some(Duration::from_secs(30)));
+    Ok((builder, tunnel))
+}
+
+/// Sends the email over a new connection, to report errors without going through the pool.
+fn deliver(email: &Message, options: &MailOptions, proxy: &OutboundProxyOptions) -> Result<()> {
+    // The tunnel stops when dropped, after the transport.
+    let (builder, tunnel) = make_transport_builder(options, proxy)?;
+    if let Some(tunnel) = &tunnel {
+        tunnel.allow_connection();
+    }
+    builder.build().send(email)?;
     Ok(())
 }
 
-pub fn send_password_reset_email(
+/// Sends the emails over a pool of SMTP connections, created at startup and shared by the server,
+/// to avoid a new connection and authentication for every email. The pool checks the connections
+/// before reusing them, and drops the ones that failed.
+#[derive(Clone)]
+pub struct Mailer {
+    options: MailOptions,
+    proxy: OutboundProxyOptions,
+    /// The error message if the transport could not be set up, e.g. because of the TLS settings.
+    transport: Result<Arc<SmtpTransport>, String>,
+    /// The tunnel through the outbound proxy used by the transport, if any.
+    tunnel: Option<Arc<Tunnel>>,
+    /// One permit per connection of the pool, to notice when the emails have to wait.
+    connections: Arc<Semaphore>,
+}
+
+impl Mailer {
+    pub fn new(options: MailOptions, proxy: OutboundProxyOptions) -> Self {
+        let pool_size = options.pool_size.max(1);
+        let (transport, tunnel) = match make_transport_builder(&options, &proxy) {
+            Ok((builder, tunnel)) => (
+                Ok(Arc::new(
+                    builder
+                        .pool_config(PoolConfig::new().max_size(pool_size))
+                        .build(),
+                )),
+                tunnel.map(Arc::new),
+            ),
+            Err(e) => {
+                error!("Could not set up the SMTP transport: {:#}", e);
+                (Err(format!("{:#}", e)), None)
+            }
+        };
+        Self {
+            options,
+            proxy,
+            transport,
+            tunnel,
+            connections: Arc::new(Semaphore::new(pool_size as usize)),
+        }
+    }
+
+    pub fn options(&self) -> &MailOptions {
+        &self.options
+    }
+
+    pub fn proxy(&self) -> &OutboundProxyOptions {
+        &self.proxy
+    }
+
+    fn transport(&self) -> Result<Arc<SmtpTransport>> {
+        self.transport.clone().map_err(|e| anyhow!(e))
+    }
+
+    /// Lets the transport open a new connection through the tunnel, in case the pool has none.
+    fn allow_connection(&self) {
+        if let Some(tunnel) = &self.tunnel {
+            tunnel.allow_connection();
+        }
+    }
+
+    async fn deliver(&self, email: Message) -> Result<()> {
+        let transport = self.transport()?;
+        let _connection = match self.connections.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!(
+                    "All the {} SMTP connections are busy, waiting for one to send an email",
+                    self.options.pool_size.max(1)
+                );
+                self.connections.acquire().await?
+            }
+        };
+        self.allow_connection();
+        tokio::task::spawn_blocking(move || transport.send(&email)).await??;
+        Ok(())
+    }
+
+    /// Checks that the SMTP server answers a NOOP command.
+    pub async fn check_connection(&self) -> Result<()> {
+        let transport = self.transport()?;
+        self.allow_connection();
+        if !tokio::task::spawn_blocking(move || transport.test_connection()).await?? {
+            bail!("The SMTP server did not answer the NOOP command");
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Mailer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mailer")
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Mailer {
+    fn default() -> Self {
+        Self::new(MailOptions::default(), OutboundProxyOptions::default())
+    }
+}
+
+fn send_email(
+    to: Mailbox,
+    subject: &str,
+    body: String,
+    options: &MailOptions,
+    proxy: &OutboundProxyOptions,
+) -> Result<()> {
+    deliver(&make_email(to, subject, body, options)?, options, proxy)
+}
+
+/// Whether retrying to send the email cannot succeed (e.g. the recipient was rejected): the SMTP
+/// replies in the 5xx range.
+fn is_permanent_failure(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<SmtpError>()
+        .map_or(false, SmtpError::is_permanent)
+}
+
+async fn retry_delivery(email: Message, mailer: Mailer) {
+    let options = mailer.options();
+    let mut delay = Duration::from_secs(options.retry_initial_delay_secs);
+    for attempt in 1..=options.max_retries {
+        tokio::time::sleep(delay).await;
+        match mailer.deliver(email.clone()).await {
+            Ok(()) => {
+                info!("Email delivered after {} retries", attempt);
+                return;
+            }
+            Err(e) if is_permanent_failure(&e) => {
+                error!("Permanent failure while retrying to send an email: {:#}", e);
+                return;
+            }
+            Err(e) => warn!("Retry {} to send an email failed: {:#}", attempt, e),
+        }
+        delay *= 2;
+    }
+    error!(
+        "Giving up sending an email after {} retries",
+        options.max_retries
+    );
+}
+
+/// Sends the email, and queues it for retries with exponential backoff if the failure is
+/// transient. Only permanent failures of the first attempt are reported.
+async fn send_email_with_retries(
+    to: Mailbox,
+    subject: &str,
+    body: String,
+    mailer: &Mailer,
+) -> Result<()> {
+    let email = make_email(to, subject, body, mailer.options())?;
+    match mailer.deliver(email.clone()).await {
+        Ok(()) => Ok(()),
+        Err(e) if is_permanent_failure(&e) || mailer.options().max_retries == 0 => Err(e),
+        Err(e) => {
+            warn!("Could not send email, queuing it for retries: {:#}", e);
+            actix_rt::spawn(retry_delivery(email, mailer.clone()));
+            Ok(())
+        }
+    }
+}
+
+pub async fn send_password_reset_email(
     username: &str,
     to: &str,
     token: &str,
     domain: &str,
-    options: &MailOptions,
+    mailer: &Mailer,
 ) -> Result<()> {
     let to = to.parse()?;
     let body = format!(
@@ -52,14 +330,357 @@ To reset your password please visit the following URL: {}/reset-password/step2/{
 Please contact an administrator if you did not initiate the process.",
         username, domain, token
     );
-    send_email(to, "[LLDAP] Password reset requested", body, options)
+    send_email_with_retries(to, "[LLDAP] Password reset requested", body, mailer).await
 }
 
-pub fn send_test_email(to: Mailbox, options: &MailOptions) -> Result<()> {
+pub async fn send_invitation_email(
+    to: &str,
+    token: &str,
+    domain: &str,
+    mailer: &Mailer,
+) -> Result<()> {
+    let to = to.parse()?;
+    let body = format!(
+        "Hello,
+You have been invited to create an account on LLDAP.
+
+To choose your display name and password, please visit the following URL:
+{}/accept-invitation/{}
+
+This invitation expires in 48 hours.",
+        domain, token
+    );
+    send_email_with_retries(to, "[LLDAP] You have been invited", body, mailer).await
+}
+
+/// Describes the SMTP settings used to send emails, without the password.
+pub fn describe_options(options: &MailOptions) -> String {
+    format!(
+        "server '{}', port {}, {}, user '{}', from '{}'{}",
+        options.server,
+        options.port,
+        match (options.tls_required, options.port) {
+            (false, _) => "no TLS",
+            (true, 465) => "implicit TLS",
+            (true, _) => "STARTTLS",
+        },
+        options.user,
+        options
+            .from
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "LLDAP <nobody@lldap>".to_string()),
+        options
+            .display_name
+            .as_ref()
+            .map(|name| format!(", display name '{}'", name))
+            .unwrap_or_default(),
+    )
+}
+
+pub async fn send_admin_password_change_email(
+    username: &str,
+    to: &str,
+    changed_by: &str,
+    mailer: &Mailer,
+) -> Result<()> {
+    let to = to.parse()?;
+    let body = format!(
+        "Hello {},
+The password of your LLDAP administrator account was changed by {}.
+
+If you did not expect this change, your account may have been compromised:
+contact another administrator immediately.",
+        username, changed_by
+    );
+    send_email_with_retries(
+        to,
+        "[LLDAP] Your administrator password was changed",
+        body,
+        mailer,
+    )
+    .await
+}
+
+pub fn send_test_email(
+    to: Mailbox,
+    options: &MailOptions,
+    proxy: &OutboundProxyOptions,
+) -> Result<()> {
     send_email(
         to,
         "LLDAP test email",
         "The test is successful! You can send emails from LLDAP".to_string(),
         options,
+        proxy,
     )
 }
+
+/// Same as `send_test_email`, but classifies the failure for the API.
+pub fn diagnose_test_email(
+    to: &str,
+    options: &MailOptions,
+    proxy: &OutboundProxyOptions,
+) -> MailTestResult {
+    match to
+        .parse::<Mailbox>()
+        .map_err(anyhow::Error::from)
+        .and_then(|to| send_test_email(to, options, proxy))
+    {
+        Ok(()) => MailTestResult::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_options() {
+        use crate::infra::configuration::MailOptionsBuilder;
+        assert_eq!(
+            describe_options(&MailOptions::default()),
+            "server 'localhost', port 587, STARTTLS, user 'admin', from 'LLDAP <nobody@lldap>'"
+        );
+        assert_eq!(
+            describe_options(
+                &MailOptionsBuilder::default()
+                    .server("smtp.example.com".to_string())
+                    .port(465)
+                    .from(Some("bob@example.com".parse().unwrap()))
+                    .display_name(Some("Bob".to_string()))
+                    .build()
+                    .unwrap()
+            ),
+            "server 'smtp.example.com', port 465, implicit TLS, user 'admin', \
+             from 'bob@example.com', display name 'Bob'"
+        );
+        assert_eq!(
+            describe_options(
+                &MailOptionsBuilder::default()
+                    .tls_required(false)
+                    .port(25)
+                    .build()
+                    .unwrap()
+            ),
+            "server 'localhost', port 25, no TLS, user 'admin', from 'LLDAP <nobody@lldap>'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_connection_failure() {
+        use crate::infra::configuration::MailOptionsBuilder;
+        // Nothing listens on port 1.
+        let mailer = Mailer::new(
+            MailOptionsBuilder::default()
+                .server("127.0.0.1".to_string())
+                .port(1)
+                .tls_required(false)
+                .pool_size(0)
+                .build()
+                .unwrap(),
+            OutboundProxyOptions::default(),
+        );
+        assert!(mailer.check_connection().await.is_err());
+        assert_eq!(mailer.connections.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_send_through_proxy() {
+        use crate::infra::configuration::{MailOptionsBuilder, OutboundProxyOptionsBuilder};
+        use std::io::{BufRead, BufReader, Write};
+        // Answers the CONNECT request, then plays the SMTP server.
+        let proxy_listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let proxy_port = proxy_listener.local_addr().unwrap().port();
+        let proxy_thread = std::thread::spawn(move || {
+            let (stream, _) = proxy_listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut lines = Vec::new();
+            let mut connected = false;
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                lines.push(line.clone());
+                let command = line.to_uppercase();
+                let response: &[u8] = if !connected {
+                    // The CONNECT request ends with an empty line.
+                    if line != "\r\n" {
+                        continue;
+                    }
+                    connected = true;
+                    b"HTTP/1.1 200 Connection established\r\n\r\n220 smtp.example.com\r\n"
+                } else if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if command.starts_with("EHLO") {
+                    b"250-smtp.example.com\r\n250 AUTH PLAIN LOGIN\r\n"
+                } else if command.starts_with("AUTH") {
+                    b"235 authenticated\r\n"
+                } else if command.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(response).unwrap();
+            }
+            lines
+        });
+        send_test_email(
+            "bob@example.com".parse().unwrap(),
+            &MailOptionsBuilder::default()
+                .server("smtp.example.com".to_string())
+                .port(25)
+                .tls_required(false)
+                .build()
+                .unwrap(),
+            &OutboundProxyOptionsBuilder::default()
+                .url(Some(format!("http://127.0.0.1:{}", proxy_port)))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+        let lines = proxy_thread.join().unwrap();
+        assert_eq!(lines[0], "CONNECT smtp.example.com:25 HTTP/1.1\r\n");
+        assert!(lines.contains(&"RCPT TO:<bob@example.com>\r\n".to_string()));
+        assert!(lines.contains(&"Subject: LLDAP test email\r\n".to_string()));
+    }
+
+    /// Plays an SMTP server for one connection, with the given replies to AUTH and RCPT.
+    fn start_smtp_server(auth_reply: &'static str, rcpt_reply: &'static str) -> u16 {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut reply = |reply: &str| {
+                writer
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .is_ok()
+            };
+            reply("220 smtp.example.com");
+            let mut in_data = false;
+            let mut line = String::new();
+            while matches!(reader.read_line(&mut line), Ok(read) if read > 0) {
+                let command = line.to_uppercase();
+                let response = if in_data {
+                    in_data = line != ".\r\n";
+                    (!in_data).then(|| "250 queued")
+                } else if command.starts_with("EHLO") {
+                    Some("250-smtp.example.com\r\n250 AUTH PLAIN LOGIN")
+                } else if command.starts_with("AUTH") {
+                    Some(auth_reply)
+                } else if command.starts_with("RCPT") {
+                    Some(rcpt_reply)
+                } else if command.starts_with("DATA") {
+                    in_data = true;
+                    Some("354 go ahead")
+                } else if command.starts_with("QUIT") {
+                    reply("221 bye");
+                    break;
+                } else {
+                    Some("250 ok")
+                };
+                line.clear();
+                if !response.map_or(true, &mut reply) {
+                    break;
+                }
+            }
+        });
+        port
+    }
+
+    /// Sends an email to the server started by `start_smtp_server`.
+    fn send_to_smtp_server(port: u16) -> Result<()> {
+        use crate::infra::configuration::MailOptionsBuilder;
+        let options = MailOptionsBuilder::default()
+            .server("127.0.0.1".to_string())
+            .port(port)
+            .tls_required(false)
+            .build()
+            .unwrap();
+        let email = make_email(
+            "bob@example.com".parse().unwrap(),
+            "Test",
+            String::new(),
+            &options,
+        )?;
+        deliver(&email, &options, &OutboundProxyOptions::default())
+    }
+
+    #[test]
+    fn test_is_permanent_failure() {
+        let error =
+            send_to_smtp_server(start_smtp_server("235 ok", "550 5.1.1 no such user")).unwrap_err();
+        assert!(is_permanent_failure(&error));
+        let error = send_to_smtp_server(start_smtp_server("235 ok", "451 4.3.0 try again later"))
+            .unwrap_err();
+        assert!(!is_permanent_failure(&error));
+        // Only the SMTP replies count, not the text.
+        assert!(!is_permanent_failure(&anyhow::anyhow!(
+            "permanent error (550): no such user"
+        )));
+    }
+
+    #[test]
+    fn test_classify_smtp_errors() {
+        let result = |auth_reply: &'static str, rcpt_reply: &'static str| {
+            let port = start_smtp_server(auth_reply, rcpt_reply);
+            match MailTestResult::from(send_to_smtp_server(port).unwrap_err()) {
+                MailTestResult::AuthenticationFailure(_) => "authentication",
+                MailTestResult::TlsFailure(_) => "tls",
+                MailTestResult::Timeout(_) => "timeout",
+                MailTestResult::Error(_) => "error",
+                MailTestResult::Success => "success",
+            }
+        };
+        assert_eq!(
+            result("535 5.7.8 bad credentials", "250 ok"),
+            "authentication"
+        );
+        assert_eq!(
+            result("454 4.7.0 try again later", "250 ok"),
+            "authentication"
+        );
+        // The text of the reply doesn't matter.
+        assert_eq!(
+            result("235 ok", "550 5.7.1 tls 535 authentication"),
+            "error"
+        );
+        assert_eq!(result("235 ok", "451 4.3.0 try again later"), "error");
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        assert_eq!(
+            MailTestResult::from(anyhow::anyhow!("permanent error (535): bad credentials")),
+            MailTestResult::AuthenticationFailure(
+                "permanent error (535): bad credentials".to_string()
+            )
+        );
+        assert_eq!(
+            MailTestResult::from(anyhow::Error::from(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connection"
+            ))),
+            MailTestResult::Timeout("connection".to_string())
+        );
+        assert_eq!(
+            MailTestResult::from(anyhow::anyhow!("tls handshake failed")),
+            MailTestResult::TlsFailure("tls handshake failed".to_string())
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::Result;
+use log::*;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Runtime flag that makes the server read-only: binds and searches keep working, but all the
+/// mutating operations are rejected. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceMode(Arc<AtomicBool>);
+
+impl MaintenanceMode {
+    pub fn new(enabled: bool) -> Self {
+        let mode = Self::default();
+        mode.set(enabled);
+        mode
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        if self.0.swap(enabled, Ordering::Relaxed) != enabled {
+            log_state(enabled);
+        }
+    }
+
+    pub fn toggle(&self) {
+        log_state(!self.0.fetch_xor(true, Ordering::Relaxed));
+    }
+}
+
+fn log_state(enabled: bool) {
+    if enabled {
+        warn!("!!! MAINTENANCE MODE ENABLED: the server is read-only, all writes will be rejected !!!");
+    } else {
+        warn!("!!! MAINTENANCE MODE DISABLED: the server accepts writes again !!!");
+    }
+}
+
+/// Toggles the maintenance mode every time the process receives SIGUSR1.
+#[cfg(unix)]
+pub fn listen_for_toggle_signal(mode: MaintenanceMode) -> Result<()> {
+    use anyhow::Context;
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut signals = signal(SignalKind::user_defined1()).context("while listening for SIGUSR1")?;
+    actix_rt::spawn(async move {
+        while signals.recv().await.is_some() {
+            mode.toggle();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen_for_toggle_signal(_mode: MaintenanceMode) -> Result<()> {
+    warn!("Toggling the maintenance mode with a signal is not supported on this platform");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggle() {
+        let mode = MaintenanceMode::new(false);
+        let clone = mode.clone();
+        assert!(!clone.is_enabled());
+        mode.toggle();
+        assert!(clone.is_enabled());
+        clone.set(false);
+        assert!(!mode.is_enabled());
+    }
+}
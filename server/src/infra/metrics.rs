@@ -0,0 +1,487 @@
+use crate::{
+    domain::handler::BackendHandler,
+    infra::{
+        auth_service::check_bearer_token, tcp_backend_handler::TcpBackendHandler,
+        tcp_server::AppState,
+    },
+};
+use actix_web::{
+    error::{ErrorForbidden, ErrorUnauthorized},
+    web, HttpResponse,
+};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+trait Metric: Sync {
+    /// Renders the metric, with the `labels` (e.g. `server_id="a"`) on every sample.
+    fn render(&self, output: &mut String, labels: &str);
+}
+
+/// Formats the labels of a sample: the common ones, then the ones specific to the sample.
+fn format_labels(labels: &str, sample_labels: &str) -> String {
+    match (labels.is_empty(), sample_labels.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{{{}}}", labels),
+        (true, false) => format!("{{{}}}", sample_labels),
+        (false, false) => format!("{{{},{}}}", labels, sample_labels),
+    }
+}
+
+/// A monotonic counter, exported in the Prometheus text format.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    fn render(&self, output: &mut String, labels: &str) {
+        writeln!(output, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(output, "# TYPE {} counter", self.name).unwrap();
+        writeln!(
+            output,
+            "{}{} {}",
+            self.name,
+            format_labels(labels, ""),
+            self.get()
+        )
+        .unwrap();
+    }
+}
+
+/// A value that can go up and down, exported in the Prometheus text format.
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicI64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn track(&'static self) -> GaugeGuard {
+        self.inc();
+        GaugeGuard(self)
+    }
+}
+
+pub struct GaugeGuard(&'static Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Metric for Gauge {
+    fn render(&self, output: &mut String, labels: &str) {
+        writeln!(output, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(output, "# TYPE {} gauge", self.name).unwrap();
+        writeln!(
+            output,
+            "{}{} {}",
+            self.name,
+            format_labels(labels, ""),
+            self.get()
+        )
+        .unwrap();
+    }
+}
+
+/// A histogram of durations, with fixed bucket upper bounds (in seconds), exported in the
+/// Prometheus text format.
+pub struct Histogram<const N: usize> {
+    name: &'static str,
+    help: &'static str,
+    buckets: [f64; N],
+    /// Number of observations in each bucket (not cumulative).
+    counts: [AtomicU64; N],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(name: &'static str, help: &'static str, buckets: [f64; N]) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            name,
+            help,
+            buckets,
+            counts: [ZERO; N],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = self.buckets.iter().position(|bound| seconds <= *bound) {
+            self.counts[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl<const N: usize> Metric for Histogram<N> {
+    fn render(&self, output: &mut String, labels: &str) {
+        writeln!(output, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(output, "# TYPE {} histogram", self.name).unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter()) {
+            cumulative += count.load(Ordering::Relaxed);
+            writeln!(
+                output,
+                "{}_bucket{} {}",
+                self.name,
+                format_labels(labels, &format!("le=\"{}\"", bound)),
+                cumulative
+            )
+            .unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(
+            output,
+            "{}_bucket{} {}",
+            self.name,
+            format_labels(labels, "le=\"+Inf\""),
+            count
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "{}_sum{} {}",
+            self.name,
+            format_labels(labels, ""),
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "{}_count{} {}",
+            self.name,
+            format_labels(labels, ""),
+            count
+        )
+        .unwrap();
+    }
+}
+
+pub static LDAP_FILTER_CACHE_HITS: Counter = Counter::new(
+    "lldap_ldap_filter_cache_hits_total",
+    "Number of LDAP search filters found in the filter cache.",
+);
+pub static LDAP_FILTER_CACHE_MISSES: Counter = Counter::new(
+    "lldap_ldap_filter_cache_misses_total",
+    "Number of LDAP search filters that had to be compiled.",
+);
+
+pub static LDAP_SEARCH_CACHE_HITS: Counter = Counter::new(
+    "lldap_ldap_search_cache_hits_total",
+    "Number of LDAP searches answered from the result cache.",
+);
+pub static LDAP_SEARCH_CACHE_MISSES: Counter = Counter::new(
+    "lldap_ldap_search_cache_misses_total",
+    "Number of LDAP searches that were not in the result cache.",
+);
+
+pub static MEMBERSHIP_CACHE_HITS: Counter = Counter::new(
+    "lldap_membership_cache_hits_total",
+    "Number of user group lookups answered from the membership cache.",
+);
+pub static MEMBERSHIP_CACHE_MISSES: Counter = Counter::new(
+    "lldap_membership_cache_misses_total",
+    "Number of user group lookups that were not in the membership cache.",
+);
+
+pub static LDAP_BINDS: Counter = Counter::new(
+    "lldap_ldap_binds_total",
+    "Number of LDAP bind requests, successful or not.",
+);
+pub static LDAP_BIND_FAILURES: Counter = Counter::new(
+    "lldap_ldap_bind_failures_total",
+    "Number of LDAP bind requests that were rejected.",
+);
+pub static LDAP_SEARCHES: Counter = Counter::new(
+    "lldap_ldap_searches_total",
+    "Number of LDAP search requests.",
+);
+pub static LDAP_MODIFICATIONS: Counter = Counter::new(
+    "lldap_ldap_modifications_total",
+    "Number of LDAP add, delete and modify DN requests.",
+);
+pub static LDAP_ACTIVE_CONNECTIONS: Gauge = Gauge::new(
+    "lldap_ldap_active_connections",
+    "Number of open LDAP and LDAPS connections.",
+);
+pub static LDAP_CONNECTION_SLOTS_USED: Gauge = Gauge::new(
+    "lldap_ldap_connection_slots_used",
+    "Number of the ldap_max_total_connections slots in use, including the connections in the TLS handshake.",
+);
+
+pub static CREDENTIAL_VERIFICATION_SECONDS: Histogram<12> = Histogram::new(
+    "lldap_credential_verification_seconds",
+    "Time spent verifying credentials (OPAQUE login steps and password binds).",
+    [
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+    ],
+);
+pub static CREDENTIAL_VERIFICATIONS_IN_FLIGHT: Gauge = Gauge::new(
+    "lldap_credential_verifications_in_flight",
+    "Number of credential verifications currently running.",
+);
+
+/// Runs a credential verification step, recording its duration and counting it as in flight
+/// while it runs.
+pub fn time_credential_verification<T>(f: impl FnOnce() -> T) -> T {
+    struct InFlight(Instant);
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            CREDENTIAL_VERIFICATIONS_IN_FLIGHT.dec();
+            CREDENTIAL_VERIFICATION_SECONDS.observe(self.0.elapsed());
+        }
+    }
+    CREDENTIAL_VERIFICATIONS_IN_FLIGHT.inc();
+    let _in_flight = InFlight(Instant::now());
+    f()
+}
+
+static METRICS: &[&dyn Metric] = &[
+    &LDAP_FILTER_CACHE_HITS,
+    &LDAP_FILTER_CACHE_MISSES,
+    &LDAP_SEARCH_CACHE_HITS,
+    &LDAP_SEARCH_CACHE_MISSES,
+    &MEMBERSHIP_CACHE_HITS,
+    &MEMBERSHIP_CACHE_MISSES,
+    &LDAP_BINDS,
+    &LDAP_BIND_FAILURES,
+    &LDAP_SEARCHES,
+    &LDAP_MODIFICATIONS,
+    &LDAP_ACTIVE_CONNECTIONS,
+    &LDAP_CONNECTION_SLOTS_USED,
+    &CREDENTIAL_VERIFICATION_SECONDS,
+    &CREDENTIAL_VERIFICATIONS_IN_FLIGHT,
+];
+
+/// Renders all the metrics, labeled with the id of the server.
+pub fn render(server_id: &str) -> String {
+    let labels = format!(
+        "server_id=\"{}\"",
+        server_id
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    );
+    let mut output = String::new();
+    for metric in METRICS {
+        metric.render(&mut output, &labels);
+    }
+    output
+}
+
+/// Serves the metrics to the admins, or to everyone if they are public.
+pub async fn get_metrics<Backend>(
+    data: web::Data<AppState<Backend>>,
+    bearer: Option<BearerAuth>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler,
+{
+    if !data.metrics_public {
+        let bearer = bearer.ok_or_else(|| ErrorUnauthorized("Missing bearer token"))?;
+        if !check_bearer_token(&data, bearer.token()).await?.is_admin {
+            return Err(ErrorForbidden("Only admins can read the metrics"));
+        }
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&data.server_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::{GroupId, GroupIdAndName},
+            sql_backend_handler::SqlBackendHandler,
+        },
+        infra::{auth_service::create_jwt, configuration::ConfigurationBuilder},
+    };
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+
+    #[test]
+    fn test_render_counter() {
+        let counter = Counter::new("test_total", "A test counter.");
+        counter.inc();
+        counter.inc();
+        let mut output = String::new();
+        counter.render(&mut output, "");
+        assert_eq!(
+            output,
+            "# HELP test_total A test counter.\n# TYPE test_total counter\ntest_total 2\n"
+        );
+    }
+
+    #[test]
+    fn test_render_gauge() {
+        let gauge = Gauge::new("test_in_flight", "A test gauge.");
+        gauge.inc();
+        gauge.inc();
+        gauge.dec();
+        let mut output = String::new();
+        gauge.render(&mut output, "");
+        assert_eq!(
+            output,
+            "# HELP test_in_flight A test gauge.\n# TYPE test_in_flight gauge\ntest_in_flight 1\n"
+        );
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        let histogram = Histogram::new("test_seconds", "A test histogram.", [0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(2));
+        let mut output = String::new();
+        histogram.render(&mut output, "");
+        assert_eq!(
+            output,
+            r#"# HELP test_seconds A test histogram.
+# TYPE test_seconds histogram
+test_seconds_bucket{le="0.1"} 1
+test_seconds_bucket{le="1"} 2
+test_seconds_bucket{le="+Inf"} 3
+test_seconds_sum 2.55
+test_seconds_count 3
+"#
+        );
+    }
+
+    #[test]
+    fn test_render_labels() {
+        let counter = Counter::new("test_total", "A test counter.");
+        let mut output = String::new();
+        counter.render(&mut output, r#"server_id="a""#);
+        assert!(
+            output.ends_with("test_total{server_id=\"a\"} 0\n"),
+            "{}",
+            output
+        );
+        let histogram = Histogram::new("test_seconds", "A test histogram.", [0.1]);
+        histogram.observe(Duration::from_millis(50));
+        let mut output = String::new();
+        histogram.render(&mut output, r#"server_id="a""#);
+        assert!(
+            output.contains(r#"test_seconds_bucket{server_id="a",le="0.1"} 1"#),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains(r#"test_seconds_count{server_id="a"} 1"#),
+            "{}",
+            output
+        );
+        assert!(render("x\"y").contains(r#"{server_id="x\"y"}"#));
+    }
+
+    async fn get_metrics_status(metrics_public: bool, groups: Option<&[&str]>) -> StatusCode {
+        let config = ConfigurationBuilder::default()
+            .metrics_public(metrics_public)
+            .build()
+            .unwrap();
+        let data = AppState::new_for_tests(&config).await;
+        let mut request = TestRequest::get().uri("/metrics");
+        if let Some(groups) = groups {
+            let groups = groups
+                .iter()
+                .map(|name| GroupIdAndName(GroupId(1), name.to_string()))
+                .collect();
+            let token = create_jwt(
+                &data.jwt_key,
+                "bob".to_string(),
+                groups,
+                data.admin_group_id,
+            );
+            request =
+                request.insert_header(("Authorization", format!("Bearer {}", token.as_str())));
+        }
+        let app = init_service(
+            App::new()
+                .app_data(data)
+                .route("/metrics", web::get().to(get_metrics::<SqlBackendHandler>)),
+        )
+        .await;
+        call_service(&app, request.to_request()).await.status()
+    }
+
+    #[actix_rt::test]
+    async fn test_get_metrics_requires_admin() {
+        assert_eq!(
+            get_metrics_status(false, None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get_metrics_status(false, Some(&[])).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            get_metrics_status(false, Some(&["lldap_admin"])).await,
+            StatusCode::OK
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_get_metrics_public() {
+        assert_eq!(get_metrics_status(true, None).await, StatusCode::OK);
+    }
+}
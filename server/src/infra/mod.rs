@@ -1,13 +1,38 @@
+pub mod attribute_visibility;
 pub mod auth_service;
+pub mod cert_auth;
 pub mod cli;
+pub mod cli_output;
+pub mod config_check;
 pub mod configuration;
-pub mod db_cleaner;
+pub mod connection_filter;
+pub mod dns_records;
 pub mod graphql;
 pub mod jwt_sql_tables;
+pub mod ldap_check;
+pub mod ldap_codec;
+pub mod ldap_connections;
+pub mod ldap_extended_ops;
+pub mod ldap_filter;
 pub mod ldap_handler;
+pub mod ldap_search_cache;
 pub mod ldap_server;
+pub mod ldap_upstream;
+pub mod ldap_user_search;
 pub mod logging;
 pub mod mail;
+pub mod maintenance;
+pub mod metrics;
+pub mod outbound_proxy;
+pub mod password_change;
+pub mod provisioning;
+pub mod request_logging;
+pub mod response_headers;
+pub mod scheduled_jobs;
+pub mod self_test;
+pub mod snmp;
 pub mod sql_backend_handler;
 pub mod tcp_backend_handler;
 pub mod tcp_server;
+pub mod trace_propagation;
+pub mod vault;
@@ -0,0 +1,116 @@
+use crate::{
+    domain::handler::{BackendHandler, GroupId, UserId},
+    infra::mail::{self, Mailer},
+};
+use log::*;
+
+/// Reports password changes of members of the admin group: they are logged as a warning, and the
+/// admin is notified by email if `notify_admin_password_change` is set. Failures are logged, since
+/// the password has already been changed.
+pub async fn on_password_changed<Backend: BackendHandler>(
+    backend_handler: &Backend,
+    user_id: &UserId,
+    changed_by: &str,
+    mailer: &Mailer,
+    admin_group_id: GroupId,
+) {
+    match backend_handler.get_user_groups(user_id).await {
+        Ok(groups) if groups.iter().any(|g| g.0 == admin_group_id) => (),
+        Ok(_) => return,
+        Err(e) => {
+            error!(r#"Could not get the groups of "{}": {}"#, user_id, e);
+            return;
+        }
+    }
+    warn!(
+        r#"AUDIT: the password of the admin "{}" was changed by "{}""#,
+        user_id, changed_by
+    );
+    if !mailer.options().notify_admin_password_change {
+        return;
+    }
+    let user = match backend_handler.get_user_details(user_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!(r#"Could not get the details of "{}": {}"#, user_id, e);
+            return;
+        }
+    };
+    if user.email.is_empty() {
+        warn!(
+            r#"Cannot notify "{}" of the password change: no email address"#,
+            user_id
+        );
+        return;
+    }
+    if let Err(e) =
+        mail::send_admin_password_change_email(user_id.as_str(), &user.email, changed_by, mailer)
+            .await
+    {
+        error!(
+            r#"Could not notify "{}" of the password change: {:#}"#,
+            user_id, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::handler::{GroupIdAndName, MockTestBackendHandler},
+        infra::configuration::MailOptions,
+    };
+    use mockall::predicate::eq;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn test_non_admin_is_not_reported() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("bob")))
+            .times(1)
+            .return_once(|_| {
+                let mut set = HashSet::new();
+                set.insert(GroupIdAndName(GroupId(3), "users".to_string()));
+                Ok(set)
+            });
+        // No other call to the backend.
+        on_password_changed(
+            &mock,
+            &UserId::new("bob"),
+            "bob",
+            &Mailer::new(
+                MailOptions {
+                    notify_admin_password_change: true,
+                    ..Default::default()
+                },
+                Default::default(),
+            ),
+            GroupId(1),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_admin_without_notification() {
+        let mut mock = MockTestBackendHandler::new();
+        mock.expect_get_user_groups()
+            .with(eq(UserId::new("admin")))
+            .times(1)
+            .return_once(|_| {
+                let mut set = HashSet::new();
+                set.insert(GroupIdAndName(GroupId(1), "lldap_admin".to_string()));
+                Ok(set)
+            });
+        // The email is only looked up when notifications are enabled.
+        on_password_changed(
+            &mock,
+            &UserId::new("admin"),
+            "other_admin",
+            &Mailer::default(),
+            GroupId(1),
+        )
+        .await;
+    }
+}
@@ -0,0 +1,479 @@
+use crate::domain::{
+    handler::{
+        BackendHandler, CreateUserRequest, Group, GroupId, LoginHandler, UpdateUserRequest, User,
+        UserId,
+    },
+    opaque_handler::OpaqueHandler,
+    sql_opaque_handler::register_password,
+};
+use anyhow::{bail, Context, Result};
+use log::*;
+use secstr::SecUtf8;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// A user declared in the configuration. The attributes that are not set are left untouched.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProvisionedUser {
+    pub id: UserId,
+    pub email: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub first_name: Option<String>,
+    #[serde(default)]
+    pub last_name: Option<String>,
+    /// The groups of the user. The memberships of the unmanaged groups are left untouched, see
+    /// `ProvisioningOptions::manages_group`.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// File containing the password of the user.
+    #[serde(default)]
+    pub password_file: Option<String>,
+    /// Environment variable containing the password of the user.
+    #[serde(default)]
+    pub password_env: Option<String>,
+}
+
+impl ProvisionedUser {
+    fn get_password(&self) -> Result<Option<SecUtf8>> {
+        let password = match (&self.password_file, &self.password_env) {
+            (Some(_), Some(_)) => bail!(
+                "Both password_file and password_env are set for user {}",
+                self.id
+            ),
+            (Some(file), None) => std::fs::read_to_string(file)
+                .with_context(|| format!("while reading the password file {}", file))?
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_string(),
+            (None, Some(var)) => std::env::var(var)
+                .with_context(|| format!("while reading the environment variable {}", var))?,
+            (None, None) => return Ok(None),
+        };
+        if password.len() < 8 {
+            bail!(
+                "Minimum password length is 8 characters, got {} characters for user {}",
+                password.len(),
+                self.id
+            );
+        }
+        Ok(Some(SecUtf8::from(password)))
+    }
+
+    fn get_update_request(&self, user: &User) -> Option<UpdateUserRequest> {
+        fn changed(declared: &Option<String>, current: &str) -> Option<String> {
+            declared.as_ref().filter(|d| d.as_str() != current).cloned()
+        }
+        let request = UpdateUserRequest {
+            user_id: self.id.clone(),
+            email: Some(self.email.clone()).filter(|e| e != &user.email),
+            display_name: changed(&self.display_name, &user.display_name),
+            first_name: changed(&self.first_name, &user.first_name),
+            last_name: changed(&self.last_name, &user.last_name),
+            ..Default::default()
+        };
+        if request.email.is_none()
+            && request.display_name.is_none()
+            && request.first_name.is_none()
+            && request.last_name.is_none()
+        {
+            None
+        } else {
+            Some(request)
+        }
+    }
+}
+
+/// Users and groups reconciled at startup.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProvisioningOptions {
+    pub users: Vec<ProvisionedUser>,
+    /// Groups to create, in addition to the ones referenced by the users.
+    pub groups: Vec<String>,
+    /// Delete the users that are not declared, except for the admin.
+    pub remove_undeclared_users: bool,
+    /// Delete the groups that are not declared, except for the admin group.
+    pub remove_undeclared_groups: bool,
+}
+
+impl ProvisioningOptions {
+    fn is_empty(&self) -> bool {
+        self.users.is_empty()
+            && self.groups.is_empty()
+            && !self.remove_undeclared_users
+            && !self.remove_undeclared_groups
+    }
+
+    fn declared_groups(&self) -> HashSet<&str> {
+        self.groups
+            .iter()
+            .chain(self.users.iter().flat_map(|u| u.groups.iter()))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Whether the memberships of the group are reconciled. Unless the undeclared groups are
+    /// removed, they are managed outside of the configuration and their members are kept.
+    fn manages_group(&self, declared_groups: &HashSet<&str>, group: &str) -> bool {
+        self.remove_undeclared_groups || declared_groups.contains(group)
+    }
+}
+
+/// What the reconciliation changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReconciliationReport {
+    pub created_users: usize,
+    pub updated_users: usize,
+    pub deleted_users: usize,
+    pub passwords_set: usize,
+    pub created_groups: usize,
+    pub deleted_groups: usize,
+    pub added_memberships: usize,
+    pub removed_memberships: usize,
+}
+
+async fn reconcile_groups<Backend: BackendHandler>(
+    backend_handler: &Backend,
+    options: &ProvisioningOptions,
+    admin_group_id: GroupId,
+    report: &mut ReconciliationReport,
+) -> Result<HashMap<String, Group>> {
+    let declared_groups = options.declared_groups();
+    let mut groups = HashMap::new();
+    for group in backend_handler.list_groups(None).await? {
+        if options.remove_undeclared_groups
+            && group.id != admin_group_id
+            && !declared_groups.contains(group.display_name.as_str())
+        {
+            info!("Provisioning: deleting group {}", group.display_name);
+            backend_handler.delete_group(group.id).await?;
+            report.deleted_groups += 1;
+        } else {
+            groups.insert(group.display_name.clone(), group);
+        }
+    }
+    let mut missing_groups = declared_groups
+        .into_iter()
+        .filter(|g| !groups.contains_key(*g))
+        .collect::<Vec<_>>();
+    missing_groups.sort_unstable();
+    for name in missing_groups {
+        info!("Provisioning: creating group {}", name);
+        let id = backend_handler
+            .create_group(name)
+            .await
+            .with_context(|| format!("while creating group {}", name))?;
+        report.created_groups += 1;
+        groups.insert(
+            name.to_string(),
+            Group {
+                id,
+                display_name: name.to_string(),
+                description: None,
+                users: Vec::new(),
+            },
+        );
+    }
+    Ok(groups)
+}
+
+async fn reconcile_user<Backend>(
+    backend_handler: &Backend,
+    user: &ProvisionedUser,
+    existing_user: Option<&User>,
+    groups: &HashMap<String, Group>,
+    options: &ProvisioningOptions,
+    admin: &UserId,
+    admin_group_id: GroupId,
+    report: &mut ReconciliationReport,
+) -> Result<()>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + Sync,
+{
+    let password = user.get_password()?;
+    match existing_user {
+        None => {
+            info!("Provisioning: creating user {}", user.id);
+            backend_handler
+                .create_user(CreateUserRequest {
+                    user_id: user.id.clone(),
+                    email: user.email.clone(),
+                    display_name: user.display_name.clone(),
+                    first_name: user.first_name.clone(),
+                    last_name: user.last_name.clone(),
+                    ..Default::default()
+                })
+                .await?;
+            report.created_users += 1;
+        }
+        Some(existing_user) => {
+            if let Some(request) = user.get_update_request(existing_user) {
+                info!("Provisioning: updating user {}", user.id);
+                backend_handler.update_user(request).await?;
+                report.updated_users += 1;
+            }
+        }
+    }
+    if let Some(password) = password {
+        // Only register a new password if it changed.
+        if !backend_handler
+            .check_password(&user.id, password.unsecure())
+            .await?
+        {
+            info!("Provisioning: setting the password of user {}", user.id);
+            register_password(backend_handler, &user.id, &password).await?;
+            report.passwords_set += 1;
+        }
+    }
+    let declared_groups = options.declared_groups();
+    let user_groups = user
+        .groups
+        .iter()
+        .map(String::as_str)
+        .collect::<HashSet<_>>();
+    for group in groups.values() {
+        let is_member = group.users.contains(&user.id);
+        let should_be_member = user_groups.contains(group.display_name.as_str())
+            // Never lock the admin out.
+            || (&user.id == admin && group.id == admin_group_id);
+        if should_be_member && !is_member {
+            info!(
+                "Provisioning: adding user {} to group {}",
+                user.id, group.display_name
+            );
+            backend_handler
+                .add_user_to_group(&user.id, group.id)
+                .await?;
+            report.added_memberships += 1;
+        } else if !should_be_member
+            && is_member
+            && options.manages_group(&declared_groups, &group.display_name)
+        {
+            info!(
+                "Provisioning: removing user {} from group {}",
+                user.id, group.display_name
+            );
+            backend_handler
+                .remove_user_from_group(&user.id, group.id)
+                .await?;
+            report.removed_memberships += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Creates, updates and optionally deletes the users and groups to match the declared ones. This
+/// is idempotent: running it twice doesn't change anything the second time.
+pub async fn reconcile<Backend>(
+    backend_handler: &Backend,
+    options: &ProvisioningOptions,
+    admin: &UserId,
+    admin_group_id: GroupId,
+) -> Result<ReconciliationReport>
+where
+    Backend: BackendHandler + LoginHandler + OpaqueHandler + Sync,
+{
+    let mut report = ReconciliationReport::default();
+    if options.is_empty() {
+        return Ok(report);
+    }
+    let groups = reconcile_groups(backend_handler, options, admin_group_id, &mut report).await?;
+    let existing_users = backend_handler
+        .list_users(None)
+        .await?
+        .into_iter()
+        .map(|u| (u.user_id.clone(), u))
+        .collect::<HashMap<_, _>>();
+    for user in &options.users {
+        reconcile_user(
+            backend_handler,
+            user,
+            existing_users.get(&user.id),
+            &groups,
+            options,
+            admin,
+            admin_group_id,
+            &mut report,
+        )
+        .await
+        .with_context(|| format!("while provisioning user {}", user.id))?;
+    }
+    if options.remove_undeclared_users {
+        let declared_users = options.users.iter().map(|u| &u.id).collect::<HashSet<_>>();
+        for user_id in existing_users.keys() {
+            if user_id != admin && !declared_users.contains(user_id) {
+                info!("Provisioning: deleting user {}", user_id);
+                backend_handler.delete_user(user_id).await?;
+                report.deleted_users += 1;
+            }
+        }
+    }
+    info!("Provisioning done: {:?}", report);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::BindRequest,
+            sql_backend_handler::SqlBackendHandler,
+            sql_tables::{init_table, PoolOptions},
+        },
+        infra::configuration::ConfigurationBuilder,
+    };
+
+    async fn get_handler() -> SqlBackendHandler {
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let config = ConfigurationBuilder::default()
+            .verbose(true)
+            .build()
+            .unwrap();
+        SqlBackendHandler::new(config, sql_pool)
+    }
+
+    fn make_user(id: &str, groups: &[&str]) -> ProvisionedUser {
+        ProvisionedUser {
+            id: UserId::new(id),
+            email: format!("{}@example.com", id),
+            display_name: Some(id.to_uppercase()),
+            first_name: None,
+            last_name: None,
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            password_file: None,
+            password_env: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let handler = get_handler().await;
+        let admin = UserId::new("admin");
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("stale"),
+                email: "stale@example.com".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        std::env::set_var("LLDAP_TEST_PROVISIONING_PASSWORD", "bob_password");
+        let mut bob = make_user("bob", &["devs", "ops"]);
+        bob.password_env = Some("LLDAP_TEST_PROVISIONING_PASSWORD".to_string());
+        let mut options = ProvisioningOptions {
+            users: vec![bob, make_user("jim", &["devs"])],
+            groups: vec!["empty".to_string()],
+            remove_undeclared_users: true,
+            remove_undeclared_groups: true,
+        };
+        assert_eq!(
+            reconcile(&handler, &options, &admin, GroupId(1))
+                .await
+                .unwrap(),
+            ReconciliationReport {
+                created_users: 2,
+                deleted_users: 1,
+                passwords_set: 1,
+                created_groups: 3,
+                added_memberships: 3,
+                ..Default::default()
+            }
+        );
+        handler
+            .bind(BindRequest {
+                name: UserId::new("bob"),
+                password: "bob_password".to_string(),
+            })
+            .await
+            .unwrap();
+        // Running it again doesn't change anything.
+        assert_eq!(
+            reconcile(&handler, &options, &admin, GroupId(1))
+                .await
+                .unwrap(),
+            ReconciliationReport::default()
+        );
+        // Updates and membership removals.
+        options.users[1] = make_user("jim", &["ops"]);
+        options.users[1].email = "james@example.com".to_string();
+        assert_eq!(
+            reconcile(&handler, &options, &admin, GroupId(1))
+                .await
+                .unwrap(),
+            ReconciliationReport {
+                updated_users: 1,
+                added_memberships: 1,
+                removed_memberships: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            handler
+                .get_user_details(&UserId::new("jim"))
+                .await
+                .unwrap()
+                .email,
+            "james@example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_keeps_unmanaged_groups() {
+        let handler = get_handler().await;
+        let admin = UserId::new("admin");
+        let options = ProvisioningOptions {
+            users: vec![make_user("bob", &["devs"])],
+            ..Default::default()
+        };
+        reconcile(&handler, &options, &admin, GroupId(1))
+            .await
+            .unwrap();
+        let external = handler.create_group("external").await.unwrap();
+        handler
+            .add_user_to_group(&UserId::new("bob"), external)
+            .await
+            .unwrap();
+        // The group isn't declared, but isn't removed either: its members are kept.
+        assert_eq!(
+            reconcile(&handler, &options, &admin, GroupId(1))
+                .await
+                .unwrap(),
+            ReconciliationReport::default()
+        );
+        assert_eq!(
+            handler
+                .get_user_groups(&UserId::new("bob"))
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_empty() {
+        let handler = get_handler().await;
+        assert_eq!(
+            reconcile(
+                &handler,
+                &ProvisioningOptions::default(),
+                &UserId::new("admin"),
+                GroupId(1)
+            )
+            .await
+            .unwrap(),
+            ReconciliationReport::default()
+        );
+    }
+
+    #[test]
+    fn test_password_too_short() {
+        std::env::set_var("LLDAP_TEST_PROVISIONING_SHORT_PASSWORD", "short");
+        let mut user = make_user("bob", &[]);
+        user.password_env = Some("LLDAP_TEST_PROVISIONING_SHORT_PASSWORD".to_string());
+        user.get_password().unwrap_err();
+    }
+}
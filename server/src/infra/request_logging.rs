@@ -0,0 +1,328 @@
+use crate::infra::configuration::RequestLoggingOptions;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::CONTENT_LENGTH,
+};
+use bytes::BytesMut;
+use futures::future::{ok, Ready};
+use futures_util::StreamExt;
+use log::*;
+use serde_json::Value;
+use std::{
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// The target of the logs of the request bodies, enabled at the trace level independently of the
+/// other logs.
+pub const LOG_TARGET: &str = "lldap::infra::request_logging";
+
+/// Only the requests to these scopes are logged, not the static files.
+const LOGGED_SCOPES: &[&str] = &["/api", "/auth"];
+
+/// Bigger bodies are not read ahead of the handler, which enforces its own limits.
+const MAX_LOGGED_BODY_SIZE: usize = 1024 * 1024;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Delimits the GraphQL block strings.
+const BLOCK_QUOTES: &str = "\"\"\"";
+
+fn is_logged_path(path: &str) -> bool {
+    LOGGED_SCOPES.iter().any(|scope| {
+        path.strip_prefix(scope)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Replaces the values of the fields whose name contains one of the (lowercase) `redact_fields`,
+/// at any depth.
+fn redact(value: &mut Value, redact_fields: &[String]) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                let name = name.to_lowercase();
+                if redact_fields.iter().any(|field| name.contains(field)) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value, redact_fields);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| redact(v, redact_fields)),
+        _ => (),
+    }
+}
+
+/// Replaces the string literals of a GraphQL document, e.g. a password passed inline instead of
+/// in the variables. The block strings (`"""..."""`) are replaced too.
+fn redact_graphql_literals(query: &str) -> String {
+    let mut redacted = String::with_capacity(query.len());
+    let mut rest = query;
+    while let Some(start) = rest.find('"') {
+        redacted.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = if let Some(block) = rest.strip_prefix(BLOCK_QUOTES) {
+            block
+                .find(BLOCK_QUOTES)
+                .map(|end| end + 2 * BLOCK_QUOTES.len())
+        } else {
+            // Skip the escaped characters, like \".
+            let mut chars = rest.char_indices().skip(1);
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '"' => {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    _ => (),
+                }
+            }
+            end
+        };
+        redacted.push('"');
+        redacted.push_str(REDACTED);
+        redacted.push('"');
+        // An unterminated string runs to the end of the document.
+        rest = &rest[end.unwrap_or(rest.len())..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Redacts the inline literals of the GraphQL queries, in a single or a batched request.
+fn redact_graphql_queries(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            if let Some(Value::String(query)) = fields.get_mut("query") {
+                *query = redact_graphql_literals(query);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_graphql_queries),
+        _ => (),
+    }
+}
+
+/// The body to log: only JSON bodies are printed, since the redaction can't apply to the others.
+fn sanitize_body(body: &[u8], redact_fields: &[String]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact(&mut value, redact_fields);
+            redact_graphql_queries(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", body.len()),
+    }
+}
+
+/// The route of the request, e.g. `/auth/reset/step2/{token}`: the path itself can hold secrets.
+fn logged_route(req: &ServiceRequest) -> String {
+    req.request()
+        .match_pattern()
+        .unwrap_or_else(|| "<unknown route>".to_string())
+}
+
+/// Logs the bodies of the requests to the API and the authentication endpoints, with the
+/// sensitive fields redacted. When disabled, the requests are passed through untouched.
+#[derive(Clone, Default)]
+pub struct RequestLoggingMiddlewareFactory {
+    enabled: bool,
+    redact_fields: Arc<Vec<String>>,
+}
+
+impl RequestLoggingMiddlewareFactory {
+    pub fn new(options: &RequestLoggingOptions) -> Self {
+        Self {
+            enabled: options.enabled,
+            redact_fields: Arc::new(
+                options
+                    .redact_fields
+                    .iter()
+                    .map(|field| field.to_lowercase())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestLoggingMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RequestLoggingMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestLoggingMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+            redact_fields: self.redact_fields.clone(),
+        })
+    }
+}
+
+pub struct RequestLoggingMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+    redact_fields: Arc<Vec<String>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestLoggingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if !self.enabled || !is_logged_path(req.path()) {
+            return Box::pin(self.service.call(req));
+        }
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+        match content_length {
+            None | Some(0) => {
+                trace!(
+                    target: LOG_TARGET,
+                    "{} {} without body",
+                    req.method(),
+                    logged_route(&req)
+                );
+                return Box::pin(self.service.call(req));
+            }
+            Some(length) if length > MAX_LOGGED_BODY_SIZE => {
+                trace!(
+                    target: LOG_TARGET,
+                    "{} {} with a body of {} bytes, too big to be logged",
+                    req.method(),
+                    logged_route(&req),
+                    length
+                );
+                return Box::pin(self.service.call(req));
+            }
+            Some(_) => (),
+        }
+        let service = self.service.clone();
+        let redact_fields = self.redact_fields.clone();
+        Box::pin(async move {
+            // Read the whole body, then hand a copy of it to the handler.
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                body.extend_from_slice(&chunk?);
+            }
+            let body = body.freeze();
+            trace!(
+                target: LOG_TARGET,
+                "{} {} with body: {}",
+                req.method(),
+                logged_route(&req),
+                sanitize_body(&body, &redact_fields)
+            );
+            let (_, mut forwarded_payload) = actix_http::h1::Payload::create(true);
+            forwarded_payload.unread_data(body);
+            req.set_payload(forwarded_payload.into());
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_redact_fields() -> Vec<String> {
+        RequestLoggingOptions::default().redact_fields
+    }
+
+    #[test]
+    fn test_is_logged_path() {
+        assert!(is_logged_path("/api/graphql"));
+        assert!(is_logged_path("/auth"));
+        assert!(is_logged_path("/auth/opaque/login/start"));
+        assert!(!is_logged_path("/apidocs"));
+        assert!(!is_logged_path("/static/main.js"));
+        assert!(!is_logged_path("/"));
+    }
+
+    #[test]
+    fn test_sanitize_body() {
+        let body = br#"{
+            "query": "mutation ChangePassword($user: UpdateUserInput!) { ok }",
+            "variables": {
+                "user": {"id": "bob", "newPassword": "hunter22"},
+                "tokens": [{"name": "ci"}],
+                "clientSecret": {"value": "abc"}
+            }
+        }"#;
+        let sanitized: Value =
+            serde_json::from_str(&sanitize_body(body, &default_redact_fields())).unwrap();
+        assert_eq!(
+            sanitized,
+            serde_json::json!({
+                "query": "mutation ChangePassword($user: UpdateUserInput!) { ok }",
+                "variables": {
+                    "user": {"id": "bob", "newPassword": REDACTED},
+                    "tokens": REDACTED,
+                    "clientSecret": REDACTED
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_sanitize_body_inline_literals() {
+        let body = br#"[{
+            "query": "mutation { changePassword(id: \"bob\", password: \"hun\\\"ter\") { ok } }"
+        }]"#;
+        let sanitized: Value =
+            serde_json::from_str(&sanitize_body(body, &default_redact_fields())).unwrap();
+        assert_eq!(
+            sanitized,
+            serde_json::json!([{
+                "query": "mutation { changePassword(id: \"[REDACTED]\", password: \"[REDACTED]\") { ok } }"
+            }])
+        );
+    }
+
+    #[test]
+    fn test_redact_graphql_literals() {
+        assert_eq!(
+            redact_graphql_literals(r#"{ user(id: "bob") { groups(first: 3) { id } } }"#),
+            r#"{ user(id: "[REDACTED]") { groups(first: 3) { id } } }"#
+        );
+        assert_eq!(
+            redact_graphql_literals(r#"{ a(b: """x "y" z""", c: "d"#),
+            r#"{ a(b: "[REDACTED]", c: "[REDACTED]""#
+        );
+    }
+
+    #[test]
+    fn test_sanitize_body_not_json() {
+        assert_eq!(
+            sanitize_body(b"password=hunter22", &default_redact_fields()),
+            "<17 bytes, not JSON>"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, STRICT_TRANSPORT_SECURITY},
+};
+use anyhow::{anyhow, Result};
+use futures::future::{ok, Ready};
+use log::*;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Headers that lldap sets itself for security reasons: overriding them is allowed, but logged.
+const SECURITY_HEADERS: &[HeaderName] = &[CONTENT_SECURITY_POLICY, STRICT_TRANSPORT_SECURITY];
+
+/// Parses the `custom_response_headers` of the configuration, sorted by name.
+pub fn parse_custom_headers(
+    headers: &HashMap<String, String>,
+) -> Result<Vec<(HeaderName, HeaderValue)>> {
+    let mut parsed = headers
+        .iter()
+        .map(|(name, value)| {
+            Ok((
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| anyhow!("Invalid header name \"{}\"", name))?,
+                HeaderValue::from_str(value)
+                    .map_err(|_| anyhow!("Invalid value for the header \"{}\"", name))?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    parsed.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+    for (name, _) in &parsed {
+        if SECURITY_HEADERS.contains(name) {
+            warn!(
+                "The custom response header \"{}\" overrides a security header of lldap",
+                name
+            );
+        }
+    }
+    Ok(parsed)
+}
+
+/// Adds the configured headers to every response, replacing the ones set by the handlers and the
+/// other middlewares. It should wrap the whole app, so that it runs last.
+#[derive(Clone, Default)]
+pub struct CustomHeadersMiddlewareFactory {
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl CustomHeadersMiddlewareFactory {
+    pub fn new(headers: Arc<Vec<(HeaderName, HeaderValue)>>) -> Self {
+        Self { headers }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for CustomHeadersMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = CustomHeadersMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CustomHeadersMiddleware {
+            service: Rc::new(service),
+            headers: self.headers.clone(),
+        })
+    }
+}
+
+pub struct CustomHeadersMiddleware<S> {
+    service: Rc<S>,
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl<S> Service<ServiceRequest> for CustomHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let headers = self.headers.clone();
+        let request = req.request().clone();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            // The errors of the inner services, e.g. the authentication failures, are turned into
+            // responses here so that they get the headers too.
+            let mut response = response
+                .await
+                .unwrap_or_else(|e| ServiceResponse::from_err(e, request));
+            for (name, value) in headers.iter() {
+                response.headers_mut().insert(name.clone(), value.clone());
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        error::ErrorUnauthorized,
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    #[actix_rt::test]
+    async fn test_headers_on_errors() {
+        let headers = Arc::new(vec![(
+            HeaderName::from_static("x-frame-options"),
+            HeaderValue::from_static("DENY"),
+        )]);
+        let app = init_service(
+            App::new()
+                .wrap_fn(|req, srv| {
+                    let fails = req.path() == "/fails";
+                    let response = srv.call(req);
+                    async move {
+                        if fails {
+                            Err(ErrorUnauthorized("no token"))
+                        } else {
+                            response.await
+                        }
+                    }
+                })
+                .wrap(CustomHeadersMiddlewareFactory::new(headers))
+                .default_service(web::to(|| async { HttpResponse::Ok().finish() })),
+        )
+        .await;
+        for (path, status) in [("/", StatusCode::OK), ("/fails", StatusCode::UNAUTHORIZED)] {
+            let response = call_service(&app, TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(response.status(), status);
+            assert_eq!(
+                response.headers().get("x-frame-options").unwrap(),
+                "DENY",
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_custom_headers() {
+        let headers = HashMap::from([
+            ("Permissions-Policy".to_string(), "camera=()".to_string()),
+            (
+                "X-Permitted-Cross-Domain-Policies".to_string(),
+                "none".to_string(),
+            ),
+        ]);
+        let parsed = parse_custom_headers(&headers).unwrap();
+        assert_eq!(
+            parsed
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("permissions-policy", "camera=()"),
+                ("x-permitted-cross-domain-policies", "none"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_custom_headers_invalid() {
+        let headers = HashMap::from([("Bad Header".to_string(), "value".to_string())]);
+        assert!(parse_custom_headers(&headers).is_err());
+        let headers = HashMap::from([("X-Header".to_string(), "line\nbreak".to_string())]);
+        assert!(parse_custom_headers(&headers).is_err());
+    }
+}
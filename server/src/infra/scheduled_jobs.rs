@@ -0,0 +1,250 @@
+use crate::{domain::handler::BackendHandler, infra::tcp_backend_handler::TcpBackendHandler};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+/// An administrative task run periodically in the background.
+#[async_trait]
+pub trait ScheduledJob<Backend>: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn interval(&self) -> Duration;
+    async fn run(&self, backend: &Backend) -> anyhow::Result<()>;
+}
+
+/// Removes the expired refresh tokens and JWTs from the database.
+pub struct TokenCleanupJob;
+
+#[async_trait]
+impl<Backend: TcpBackendHandler + Sync> ScheduledJob<Backend> for TokenCleanupJob {
+    fn name(&self) -> &'static str {
+        "token_cleanup"
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60 * 60)
+    }
+
+    async fn run(&self, backend: &Backend) -> anyhow::Result<()> {
+        Ok(backend.delete_expired_tokens().await?)
+    }
+}
+
+/// Removes the JWTs that are no longer in the database, i.e. that expired, from the in-memory
+/// blacklist shared by the HTTP workers.
+pub struct JwtBlacklistCleanupJob {
+    pub blacklist: Arc<RwLock<HashSet<u64>>>,
+    pub interval: Duration,
+}
+
+#[async_trait]
+impl<Backend: TcpBackendHandler + Sync> ScheduledJob<Backend> for JwtBlacklistCleanupJob {
+    fn name(&self) -> &'static str {
+        "jwt_blacklist_cleanup"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn run(&self, backend: &Backend) -> anyhow::Result<()> {
+        let stored_jwts = backend.get_jwt_blacklist().await?;
+        let mut blacklist = self.blacklist.write().unwrap();
+        let size_before = blacklist.len();
+        blacklist.retain(|jwt_hash| stored_jwts.contains(jwt_hash));
+        info!(
+            "Removed {} expired JWTs from the blacklist, {} left",
+            size_before - blacklist.len(),
+            blacklist.len()
+        );
+        Ok(())
+    }
+}
+
+/// Writes the last logins buffered by the backend to the database.
+pub struct LastLoginFlushJob {
+    pub interval: Duration,
+}
+
+#[async_trait]
+impl<Backend: BackendHandler + Sync> ScheduledJob<Backend> for LastLoginFlushJob {
+    fn name(&self) -> &'static str {
+        "last_login_flush"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn run(&self, backend: &Backend) -> anyhow::Result<()> {
+        let count = backend.flush_last_logins().await?;
+        if count > 0 {
+            info!("Recorded the last login of {} users", count);
+        }
+        Ok(())
+    }
+}
+
+/// The state of a job, as reported by `GET /api/v1/admin/jobs`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub interval_secs: u64,
+    pub last_run: Option<DateTime<Utc>>,
+    pub next_run: DateTime<Utc>,
+    /// "success", or the error of the last run.
+    pub last_status: Option<String>,
+}
+
+struct JobEntry<Backend> {
+    job: Box<dyn ScheduledJob<Backend>>,
+    status: Mutex<JobStatus>,
+    /// Held while the job runs, so that a job never runs twice at the same time.
+    running: tokio::sync::Mutex<()>,
+}
+
+/// Runs the scheduled jobs at their interval, and on demand.
+pub struct ScheduledJobRunner<Backend> {
+    backend: Backend,
+    jobs: Vec<JobEntry<Backend>>,
+}
+
+impl<Backend: Send + Sync + 'static> ScheduledJobRunner<Backend> {
+    pub fn new(backend: Backend, jobs: Vec<Box<dyn ScheduledJob<Backend>>>) -> Self {
+        let now = Utc::now();
+        Self {
+            backend,
+            jobs: jobs
+                .into_iter()
+                .map(|job| JobEntry {
+                    status: Mutex::new(JobStatus {
+                        name: job.name().to_string(),
+                        interval_secs: job.interval().as_secs(),
+                        last_run: None,
+                        next_run: now + chrono::Duration::from_std(job.interval()).unwrap(),
+                        last_status: None,
+                    }),
+                    job,
+                    running: tokio::sync::Mutex::new(()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Starts running each job at its interval, in the background.
+    pub fn start(self: &Arc<Self>) {
+        for index in 0..self.jobs.len() {
+            let runner = self.clone();
+            actix_rt::spawn(async move {
+                let entry = &runner.jobs[index];
+                loop {
+                    let next_run = entry.status.lock().unwrap().next_run;
+                    let delay = (next_run - Utc::now()).to_std().unwrap_or_default();
+                    tokio::time::sleep(delay).await;
+                    runner.run_job(entry).await;
+                }
+            });
+        }
+    }
+
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs
+            .iter()
+            .map(|entry| entry.status.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Runs the job immediately, and returns its status afterwards. Returns None if there is no
+    /// job with that name.
+    pub async fn run_now(&self, name: &str) -> Option<JobStatus> {
+        let entry = self.jobs.iter().find(|entry| entry.job.name() == name)?;
+        Some(self.run_job(entry).await)
+    }
+
+    async fn run_job(&self, entry: &JobEntry<Backend>) -> JobStatus {
+        let _running = entry.running.lock().await;
+        let name = entry.job.name();
+        info!("Running the scheduled job {}", name);
+        let started = Utc::now();
+        let last_status = match entry.job.run(&self.backend).await {
+            Ok(()) => "success".to_string(),
+            Err(e) => {
+                error!("Error while running the scheduled job {}: {:#}", name, e);
+                format!("error: {:#}", e)
+            }
+        };
+        let mut status = entry.status.lock().unwrap();
+        status.last_run = Some(started);
+        status.last_status = Some(last_status);
+        status.next_run = Utc::now() + chrono::Duration::from_std(entry.job.interval()).unwrap();
+        status.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::tcp_backend_handler::MockTestTcpBackendHandler;
+
+    struct FailingJob;
+
+    #[async_trait]
+    impl ScheduledJob<MockTestTcpBackendHandler> for FailingJob {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(10)
+        }
+
+        async fn run(&self, _: &MockTestTcpBackendHandler) -> anyhow::Result<()> {
+            anyhow::bail!("no luck")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_now() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_delete_expired_tokens()
+            .times(1)
+            .return_once(|| Ok(()));
+        let runner =
+            ScheduledJobRunner::new(mock, vec![Box::new(TokenCleanupJob), Box::new(FailingJob)]);
+        assert!(runner.list().iter().all(|job| job.last_run.is_none()));
+
+        let status = runner.run_now("token_cleanup").await.unwrap();
+        assert_eq!(status.last_status.as_deref(), Some("success"));
+        assert!(status.last_run.is_some());
+        assert!(status.next_run > status.last_run.unwrap());
+        let status = runner.run_now("failing").await.unwrap();
+        assert_eq!(status.last_status.as_deref(), Some("error: no luck"));
+        assert_eq!(runner.list().len(), 2);
+        assert_eq!(runner.list()[1], status);
+
+        assert_eq!(runner.run_now("unknown").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_jwt_blacklist_cleanup() {
+        let mut mock = MockTestTcpBackendHandler::new();
+        mock.expect_get_jwt_blacklist()
+            .times(1)
+            .return_once(|| Ok([1, 3].into_iter().collect()));
+        let blacklist = Arc::new(RwLock::new([1, 2].into_iter().collect::<HashSet<u64>>()));
+        let job = JwtBlacklistCleanupJob {
+            blacklist: blacklist.clone(),
+            interval: Duration::from_secs(10),
+        };
+        job.run(&mock).await.unwrap();
+        assert_eq!(
+            *blacklist.read().unwrap(),
+            [1].into_iter().collect::<HashSet<u64>>()
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use crate::infra::{
+    configuration::Configuration,
+    connection_filter::ConnectionFilter,
+    ldap_check::{self, CheckStep, LdapCheckOptions},
+};
+use log::*;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// The listeners of the server, reached on the loopback interface. The certificate of the LDAPS
+/// listener is not checked, since it's not issued for the loopback address, but the handshake is.
+fn get_check_options(config: &Configuration) -> Vec<LdapCheckOptions> {
+    let mut options = vec![LdapCheckOptions::from_config(
+        format!("ldap://127.0.0.1:{}", config.ldap_port),
+        config,
+    )];
+    if config.ldaps_options.enabled {
+        options.push(LdapCheckOptions {
+            no_tls_verify: true,
+            ..LdapCheckOptions::from_config(
+                format!("ldaps://127.0.0.1:{}", config.ldaps_options.port),
+                config,
+            )
+        });
+    }
+    options
+}
+
+/// Runs the checks of a listener. `ldap_user_pass` is only used to create the admin, and is often
+/// out of date: if the server rejects it, only the checks without a bind are run.
+async fn check_listener(options: &LdapCheckOptions) -> Vec<CheckStep> {
+    let steps = ldap_check::run_checks(options).await;
+    let rejected_password = steps
+        .last()
+        .and_then(|step| step.outcome.as_ref().err())
+        .map(ldap_check::is_invalid_credentials)
+        .unwrap_or(false);
+    if !rejected_password {
+        return steps;
+    }
+    warn!(
+        "Startup self-test: the admin password doesn't match ldap_user_pass anymore, skipping the \
+         bind on {} and only checking the root DSE",
+        options.url
+    );
+    ldap_check::run_anonymous_checks(options).await
+}
+
+/// Binds as the admin and searches the users on each LDAP listener of the server that just
+/// started, the same way as `lldap test_ldap`. Returns whether all the checks passed. Skipped,
+/// and considered passed, when the connection filter rejects the loopback interface.
+pub async fn run_self_test(config: &Configuration) -> bool {
+    if !ConnectionFilter::new(config).is_allowed(IpAddr::V4(Ipv4Addr::LOCALHOST)) {
+        warn!(
+            "Startup self-test skipped: allowed_networks and denied_networks reject the \
+             connections from 127.0.0.1"
+        );
+        return true;
+    }
+    let mut passed = true;
+    for options in get_check_options(config) {
+        for step in check_listener(&options).await {
+            if step.outcome.is_ok() {
+                info!("Startup self-test: {}", step);
+            } else {
+                error!("Startup self-test: {}", step);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::configuration::{ConfigurationBuilder, LdapsOptions};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_get_check_options() {
+        let config = ConfigurationBuilder::default()
+            .ldap_port(3890)
+            .ldaps_options(LdapsOptions {
+                enabled: true,
+                port: 6360,
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let options = get_check_options(&config);
+        assert_eq!(
+            options
+                .iter()
+                .map(|o| (o.url.as_str(), o.no_tls_verify))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ldap://127.0.0.1:3890", false),
+                ("ldaps://127.0.0.1:6360", true)
+            ]
+        );
+        assert_eq!(options[0].bind_dn, "uid=admin,ou=people,dc=example,dc=com");
+        assert_eq!(options[0].search_base, "ou=people,dc=example,dc=com");
+    }
+
+    /// A listener that closes the connections right away.
+    fn start_failing_listener() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                drop(stream);
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_run_self_test_failure() {
+        let config = ConfigurationBuilder::default()
+            .ldap_port(start_failing_listener())
+            .build()
+            .unwrap();
+        assert!(!run_self_test(&config).await);
+    }
+
+    #[tokio::test]
+    async fn test_run_self_test_loopback_rejected() {
+        let config = ConfigurationBuilder::default()
+            .ldap_port(start_failing_listener())
+            .allowed_networks(vec!["10.0.0.0/8".parse().unwrap()])
+            .build()
+            .unwrap();
+        assert!(run_self_test(&config).await);
+        let config = ConfigurationBuilder::default()
+            .ldap_port(start_failing_listener())
+            .denied_networks(vec!["127.0.0.0/8".parse().unwrap()])
+            .build()
+            .unwrap();
+        assert!(run_self_test(&config).await);
+    }
+}
@@ -0,0 +1,563 @@
+use crate::infra::{
+    configuration::SnmpOptions,
+    metrics::{
+        LDAP_ACTIVE_CONNECTIONS, LDAP_BINDS, LDAP_BIND_FAILURES, LDAP_MODIFICATIONS, LDAP_SEARCHES,
+    },
+};
+use anyhow::{Context, Result};
+use log::*;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// The root of the lldap objects. It uses the enterprise number reserved for documentation
+/// (RFC 5612), since lldap doesn't have one of its own.
+///
+/// | OID                 | Type      | Value                                              |
+/// |---------------------|-----------|----------------------------------------------------|
+/// | `LLDAP_MIB.1.0`     | Counter32 | LDAP binds.                                        |
+/// | `LLDAP_MIB.2.0`     | Counter32 | Failed LDAP binds.                                 |
+/// | `LLDAP_MIB.3.0`     | Gauge32   | Open LDAP and LDAPS connections.                   |
+/// | `LLDAP_MIB.4.0`     | Counter32 | LDAP searches.                                     |
+/// | `LLDAP_MIB.5.0`     | Counter32 | LDAP adds, deletes and modify DNs.                 |
+/// | `LLDAP_MIB.6.0`     | TimeTicks | Time since the server started.                     |
+///
+/// The counters are the ones of the Prometheus metrics. The monitoring systems compute the rates
+/// (e.g. searches per second) from the difference between two polls.
+const LLDAP_MIB: &[u32] = &[1, 3, 6, 1, 4, 1, 32473, 1];
+
+const SNMP_V1: i64 = 0;
+const SNMP_V2C: i64 = 1;
+
+// BER tags.
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const COUNTER32: u8 = 0x41;
+const GAUGE32: u8 = 0x42;
+const TIMETICKS: u8 = 0x43;
+const NO_SUCH_OBJECT: u8 = 0x80;
+const END_OF_MIB_VIEW: u8 = 0x82;
+const GET_REQUEST: u8 = 0xa0;
+const GET_NEXT_REQUEST: u8 = 0xa1;
+const GET_RESPONSE: u8 = 0xa2;
+
+/// The SNMPv1 error status for an unknown object.
+const NO_SUCH_NAME: i64 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Null,
+    /// SNMPv2c exception for a GET on an unknown object.
+    NoSuchObject,
+    /// SNMPv2c exception for a GETNEXT past the last object.
+    EndOfMibView,
+}
+
+type VarBind = (Vec<u32>, Value);
+
+/// The current values of the objects, sorted by OID.
+fn get_objects(uptime: Duration) -> Vec<VarBind> {
+    let scalar = |id: u32| LLDAP_MIB.iter().copied().chain([id, 0]).collect::<Vec<_>>();
+    // The counters wrap around at 2^32, as expected from a Counter32.
+    vec![
+        (scalar(1), Value::Counter32(LDAP_BINDS.get() as u32)),
+        (scalar(2), Value::Counter32(LDAP_BIND_FAILURES.get() as u32)),
+        (
+            scalar(3),
+            Value::Gauge32(u32::try_from(LDAP_ACTIVE_CONNECTIONS.get()).unwrap_or(0)),
+        ),
+        (scalar(4), Value::Counter32(LDAP_SEARCHES.get() as u32)),
+        (scalar(5), Value::Counter32(LDAP_MODIFICATIONS.get() as u32)),
+        // In hundredths of a second.
+        (
+            scalar(6),
+            Value::TimeTicks((uptime.as_millis() / 10) as u32),
+        ),
+    ]
+}
+
+/// Reads the BER-encoded values of a packet, one TLV at a time.
+struct BerReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> BerReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the tag and the contents of the next value.
+    fn read_tlv(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.data.split_first()?;
+        let (&first_length_byte, mut rest) = rest.split_first()?;
+        let length = if first_length_byte & 0x80 == 0 {
+            usize::from(first_length_byte)
+        } else {
+            let length_bytes = usize::from(first_length_byte & 0x7f);
+            if length_bytes == 0 || length_bytes > 4 || rest.len() < length_bytes {
+                return None;
+            }
+            let (length, after_length) = rest.split_at(length_bytes);
+            rest = after_length;
+            length
+                .iter()
+                .fold(0, |length, byte| (length << 8) | usize::from(*byte))
+        };
+        if rest.len() < length {
+            return None;
+        }
+        let (value, rest) = rest.split_at(length);
+        self.data = rest;
+        Some((tag, value))
+    }
+
+    fn read(&mut self, expected_tag: u8) -> Option<&'a [u8]> {
+        match self.read_tlv()? {
+            (tag, value) if tag == expected_tag => Some(value),
+            _ => None,
+        }
+    }
+
+    fn read_integer(&mut self) -> Option<i64> {
+        let value = self.read(INTEGER)?;
+        if value.is_empty() || value.len() > 8 {
+            return None;
+        }
+        let sign_extension = if value[0] & 0x80 != 0 { -1 } else { 0 };
+        Some(
+            value
+                .iter()
+                .fold(sign_extension, |n, byte| (n << 8) | i64::from(*byte)),
+        )
+    }
+}
+
+fn decode_oid(value: &[u8]) -> Option<Vec<u32>> {
+    let mut subidentifiers = Vec::new();
+    let mut current: u32 = 0;
+    for byte in value {
+        current = current.checked_mul(128)? | u32::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            subidentifiers.push(current);
+            current = 0;
+        }
+    }
+    // The last subidentifier must be complete.
+    if value.last()? & 0x80 != 0 {
+        return None;
+    }
+    // The first subidentifier encodes the first two arcs.
+    let first = subidentifiers[0];
+    let (arc1, arc2) = match first {
+        0..=39 => (0, first),
+        40..=79 => (1, first - 40),
+        _ => (2, first - 80),
+    };
+    subidentifiers[0] = arc2;
+    subidentifiers.insert(0, arc1);
+    Some(subidentifiers)
+}
+
+fn encode_tlv(tag: u8, value: &[u8], output: &mut Vec<u8>) {
+    output.push(tag);
+    if value.len() < 0x80 {
+        output.push(value.len() as u8);
+    } else {
+        let length = value.len().to_be_bytes();
+        let skipped = length.iter().take_while(|byte| **byte == 0).count();
+        output.push(0x80 | (length.len() - skipped) as u8);
+        output.extend_from_slice(&length[skipped..]);
+    }
+    output.extend_from_slice(value);
+}
+
+/// Encodes the integer in the minimal number of bytes, in two's complement.
+fn encode_integer(tag: u8, value: i64, output: &mut Vec<u8>) {
+    let bytes = value.to_be_bytes();
+    let skipped = bytes
+        .windows(2)
+        .take_while(|pair| {
+            (pair[0] == 0x00 && pair[1] & 0x80 == 0) || (pair[0] == 0xff && pair[1] & 0x80 != 0)
+        })
+        .count();
+    encode_tlv(tag, &bytes[skipped..], output);
+}
+
+fn encode_oid(oid: &[u32], output: &mut Vec<u8>) {
+    let mut value = Vec::new();
+    let first = oid.first().copied().unwrap_or(0) * 40 + oid.get(1).copied().unwrap_or(0);
+    for subidentifier in std::iter::once(first).chain(oid.iter().skip(2).copied()) {
+        let mut groups = vec![(subidentifier & 0x7f) as u8];
+        let mut rest = subidentifier >> 7;
+        while rest > 0 {
+            groups.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        value.extend(groups.iter().rev());
+    }
+    encode_tlv(OBJECT_IDENTIFIER, &value, output);
+}
+
+fn encode_value(value: &Value, output: &mut Vec<u8>) {
+    match value {
+        Value::Counter32(n) => encode_integer(COUNTER32, i64::from(*n), output),
+        Value::Gauge32(n) => encode_integer(GAUGE32, i64::from(*n), output),
+        Value::TimeTicks(n) => encode_integer(TIMETICKS, i64::from(*n), output),
+        Value::Null => encode_tlv(NULL, &[], output),
+        Value::NoSuchObject => encode_tlv(NO_SUCH_OBJECT, &[], output),
+        Value::EndOfMibView => encode_tlv(END_OF_MIB_VIEW, &[], output),
+    }
+}
+
+struct SnmpRequest {
+    version: i64,
+    community: Vec<u8>,
+    pdu_type: u8,
+    request_id: i64,
+    oids: Vec<Vec<u32>>,
+}
+
+fn parse_request(packet: &[u8]) -> Option<SnmpRequest> {
+    let mut message = BerReader::new(BerReader::new(packet).read(SEQUENCE)?);
+    let version = message.read_integer()?;
+    let community = message.read(OCTET_STRING)?.to_vec();
+    let (pdu_type, pdu) = message.read_tlv()?;
+    let mut pdu = BerReader::new(pdu);
+    let request_id = pdu.read_integer()?;
+    // The error status and index, unused in requests.
+    pdu.read_integer()?;
+    pdu.read_integer()?;
+    let mut varbinds = BerReader::new(pdu.read(SEQUENCE)?);
+    let mut oids = Vec::new();
+    while !varbinds.is_empty() {
+        let mut varbind = BerReader::new(varbinds.read(SEQUENCE)?);
+        oids.push(decode_oid(varbind.read(OBJECT_IDENTIFIER)?)?);
+    }
+    Some(SnmpRequest {
+        version,
+        community,
+        pdu_type,
+        request_id,
+        oids,
+    })
+}
+
+fn encode_response(
+    request: &SnmpRequest,
+    error_status: i64,
+    error_index: i64,
+    varbinds: &[VarBind],
+) -> Vec<u8> {
+    let mut encoded_varbinds = Vec::new();
+    for (oid, value) in varbinds {
+        let mut varbind = Vec::new();
+        encode_oid(oid, &mut varbind);
+        encode_value(value, &mut varbind);
+        encode_tlv(SEQUENCE, &varbind, &mut encoded_varbinds);
+    }
+    let mut pdu = Vec::new();
+    encode_integer(INTEGER, request.request_id, &mut pdu);
+    encode_integer(INTEGER, error_status, &mut pdu);
+    encode_integer(INTEGER, error_index, &mut pdu);
+    encode_tlv(SEQUENCE, &encoded_varbinds, &mut pdu);
+    let mut message = Vec::new();
+    encode_integer(INTEGER, request.version, &mut message);
+    encode_tlv(OCTET_STRING, &request.community, &mut message);
+    encode_tlv(GET_RESPONSE, &pdu, &mut message);
+    let mut output = Vec::new();
+    encode_tlv(SEQUENCE, &message, &mut output);
+    output
+}
+
+/// Answers the SNMPv1 and SNMPv2c GET and GETNEXT requests on the lldap objects.
+struct SnmpAgent {
+    community: Vec<u8>,
+    start: Instant,
+}
+
+impl SnmpAgent {
+    fn new(community: &str) -> Self {
+        Self {
+            community: community.as_bytes().to_vec(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns the response to the packet, or None if it should be ignored.
+    fn handle_packet(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let request = match parse_request(packet) {
+            Some(request) => request,
+            None => {
+                debug!("Ignoring a malformed SNMP packet");
+                return None;
+            }
+        };
+        if request.version != SNMP_V1 && request.version != SNMP_V2C {
+            debug!("Ignoring an SNMP request of version {}", request.version);
+            return None;
+        }
+        if request.community != self.community {
+            debug!("Ignoring an SNMP request with the wrong community");
+            return None;
+        }
+        let is_get = match request.pdu_type {
+            GET_REQUEST => true,
+            GET_NEXT_REQUEST => false,
+            pdu_type => {
+                debug!("Ignoring an unsupported SNMP request {:#x}", pdu_type);
+                return None;
+            }
+        };
+        let objects = get_objects(self.start.elapsed());
+        let mut varbinds = Vec::with_capacity(request.oids.len());
+        for (index, oid) in request.oids.iter().enumerate() {
+            let found = if is_get {
+                objects.iter().find(|(object, _)| object == oid)
+            } else {
+                objects.iter().find(|(object, _)| object > oid)
+            };
+            match found {
+                Some(varbind) => varbinds.push(varbind.clone()),
+                None if request.version == SNMP_V1 => {
+                    // SNMPv1 has no exception values: the whole request fails.
+                    let varbinds = request
+                        .oids
+                        .iter()
+                        .map(|oid| (oid.clone(), Value::Null))
+                        .collect::<Vec<_>>();
+                    return Some(encode_response(
+                        &request,
+                        NO_SUCH_NAME,
+                        index as i64 + 1,
+                        &varbinds,
+                    ));
+                }
+                None if is_get => varbinds.push((oid.clone(), Value::NoSuchObject)),
+                None => varbinds.push((oid.clone(), Value::EndOfMibView)),
+            }
+        }
+        Some(encode_response(&request, 0, 0, &varbinds))
+    }
+}
+
+/// Starts answering the SNMP requests on UDP in the background, if enabled.
+pub async fn start_snmp_agent(options: &SnmpOptions) -> Result<()> {
+    if !options.enabled {
+        return Ok(());
+    }
+    let socket = UdpSocket::bind((options.bind_address.as_str(), options.port))
+        .await
+        .with_context(|| {
+            format!(
+                "while binding to the UDP port {}:{}",
+                options.bind_address, options.port
+            )
+        })?;
+    let agent = SnmpAgent::new(options.community_string.unsecure());
+    info!(
+        "SNMP agent listening on {}:{}",
+        options.bind_address, options.port
+    );
+    actix_rt::spawn(async move {
+        let mut buffer = vec![0; 65535];
+        loop {
+            let (length, peer) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Error receiving an SNMP request: {:#}", e);
+                    continue;
+                }
+            };
+            if let Some(response) = agent.handle_packet(&buffer[..length]) {
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    warn!("Error sending the SNMP response to {}: {:#}", peer, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_request(version: i64, community: &str, pdu_type: u8, oids: &[&[u32]]) -> Vec<u8> {
+        let mut varbinds = Vec::new();
+        for oid in oids {
+            let mut varbind = Vec::new();
+            encode_oid(oid, &mut varbind);
+            encode_value(&Value::Null, &mut varbind);
+            encode_tlv(SEQUENCE, &varbind, &mut varbinds);
+        }
+        let mut pdu = Vec::new();
+        encode_integer(INTEGER, 1234, &mut pdu);
+        encode_integer(INTEGER, 0, &mut pdu);
+        encode_integer(INTEGER, 0, &mut pdu);
+        encode_tlv(SEQUENCE, &varbinds, &mut pdu);
+        let mut message = Vec::new();
+        encode_integer(INTEGER, version, &mut message);
+        encode_tlv(OCTET_STRING, community.as_bytes(), &mut message);
+        encode_tlv(pdu_type, &pdu, &mut message);
+        let mut output = Vec::new();
+        encode_tlv(SEQUENCE, &message, &mut output);
+        output
+    }
+
+    /// Returns the error status, the error index, and the OIDs and value tags of the response.
+    fn parse_response(packet: &[u8]) -> (i64, i64, Vec<(Vec<u32>, u8)>) {
+        let mut message = BerReader::new(BerReader::new(packet).read(SEQUENCE).unwrap());
+        message.read_integer().unwrap();
+        message.read(OCTET_STRING).unwrap();
+        let mut pdu = BerReader::new(message.read(GET_RESPONSE).unwrap());
+        assert_eq!(pdu.read_integer(), Some(1234));
+        let error_status = pdu.read_integer().unwrap();
+        let error_index = pdu.read_integer().unwrap();
+        let mut varbinds = BerReader::new(pdu.read(SEQUENCE).unwrap());
+        let mut result = Vec::new();
+        while !varbinds.is_empty() {
+            let mut varbind = BerReader::new(varbinds.read(SEQUENCE).unwrap());
+            let oid = decode_oid(varbind.read(OBJECT_IDENTIFIER).unwrap()).unwrap();
+            let (tag, _) = varbind.read_tlv().unwrap();
+            result.push((oid, tag));
+        }
+        (error_status, error_index, result)
+    }
+
+    fn lldap_oid(id: u32) -> Vec<u32> {
+        LLDAP_MIB.iter().copied().chain([id, 0]).collect()
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut output = Vec::new();
+        encode_oid(&[1, 3, 6, 1, 4, 1, 32473, 1], &mut output);
+        assert_eq!(
+            output,
+            vec![0x06, 0x09, 0x2b, 6, 1, 4, 1, 0x81, 0xfd, 0x59, 1]
+        );
+        assert_eq!(
+            decode_oid(&output[2..]),
+            Some(vec![1, 3, 6, 1, 4, 1, 32473, 1])
+        );
+        for (value, expected) in [
+            (0, vec![0x02, 0x01, 0x00]),
+            (128, vec![0x02, 0x02, 0x00, 0x80]),
+            (-1, vec![0x02, 0x01, 0xff]),
+            (-129, vec![0x02, 0x02, 0xff, 0x7f]),
+        ] {
+            let mut output = Vec::new();
+            encode_integer(INTEGER, value, &mut output);
+            assert_eq!(output, expected);
+            assert_eq!(BerReader::new(&output).read_integer(), Some(value));
+        }
+        let mut output = Vec::new();
+        encode_tlv(OCTET_STRING, &[0; 200], &mut output);
+        assert_eq!(&output[..3], &[0x04, 0x81, 200]);
+        assert_eq!(
+            BerReader::new(&output).read(OCTET_STRING).map(<[u8]>::len),
+            Some(200)
+        );
+        // Truncated packets are rejected.
+        assert_eq!(BerReader::new(&output[..100]).read_tlv(), None);
+    }
+
+    #[test]
+    fn test_get() {
+        let agent = SnmpAgent::new("public");
+        let request = make_request(
+            SNMP_V2C,
+            "public",
+            GET_REQUEST,
+            &[&lldap_oid(6), &lldap_oid(3), &[1, 3, 6, 1, 2, 1, 1, 3, 0]],
+        );
+        let response = agent.handle_packet(&request).unwrap();
+        assert_eq!(
+            parse_response(&response),
+            (
+                0,
+                0,
+                vec![
+                    (lldap_oid(6), TIMETICKS),
+                    (lldap_oid(3), GAUGE32),
+                    (vec![1, 3, 6, 1, 2, 1, 1, 3, 0], NO_SUCH_OBJECT),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_get_next() {
+        let agent = SnmpAgent::new("public");
+        let request = make_request(
+            SNMP_V2C,
+            "public",
+            GET_NEXT_REQUEST,
+            &[LLDAP_MIB, &lldap_oid(1), &lldap_oid(6)],
+        );
+        let response = agent.handle_packet(&request).unwrap();
+        assert_eq!(
+            parse_response(&response),
+            (
+                0,
+                0,
+                vec![
+                    (lldap_oid(1), COUNTER32),
+                    (lldap_oid(2), COUNTER32),
+                    (lldap_oid(6), END_OF_MIB_VIEW),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_snmp_v1_unknown_object() {
+        let agent = SnmpAgent::new("public");
+        let request = make_request(
+            SNMP_V1,
+            "public",
+            GET_REQUEST,
+            &[&lldap_oid(1), &lldap_oid(7)],
+        );
+        let response = agent.handle_packet(&request).unwrap();
+        assert_eq!(
+            parse_response(&response),
+            (
+                NO_SUCH_NAME,
+                2,
+                vec![(lldap_oid(1), NULL), (lldap_oid(7), NULL)]
+            )
+        );
+    }
+
+    #[test]
+    fn test_ignored_requests() {
+        let agent = SnmpAgent::new("secret");
+        let oid = lldap_oid(1);
+        let oids: &[&[u32]] = &[&oid];
+        assert_eq!(
+            agent.handle_packet(&make_request(SNMP_V2C, "public", GET_REQUEST, oids)),
+            None
+        );
+        // SNMPv3.
+        assert_eq!(
+            agent.handle_packet(&make_request(3, "secret", GET_REQUEST, oids)),
+            None
+        );
+        // SetRequest.
+        assert_eq!(
+            agent.handle_packet(&make_request(SNMP_V2C, "secret", 0xa3, oids)),
+            None
+        );
+        assert_eq!(agent.handle_packet(&[0x30, 0x03, 0x02]), None);
+    }
+}
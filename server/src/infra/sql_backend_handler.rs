@@ -1,21 +1,15 @@
 use super::{jwt_sql_tables::*, tcp_backend_handler::*};
-use crate::domain::{error::*, handler::UserId, sql_backend_handler::SqlBackendHandler};
+use crate::domain::{
+    error::*,
+    handler::UserId,
+    sql_backend_handler::{gen_random_string, SqlBackendHandler},
+};
 use async_trait::async_trait;
 use futures_util::StreamExt;
-use sea_query::{Expr, Iden, Query, SimpleExpr};
+use sea_query::{Expr, Iden, Order, Query, SimpleExpr};
 use sqlx::Row;
 use std::collections::HashSet;
 
-fn gen_random_string(len: usize) -> String {
-    use rand::{distributions::Alphanumeric, rngs::SmallRng, Rng, SeedableRng};
-    let mut rng = SmallRng::from_entropy();
-    std::iter::repeat(())
-        .map(|()| rng.sample(Alphanumeric))
-        .map(char::from)
-        .take(len)
-        .collect()
-}
-
 #[async_trait]
 impl TcpBackendHandler for SqlBackendHandler {
     async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>> {
@@ -129,7 +123,7 @@ impl TcpBackendHandler for SqlBackendHandler {
                 PasswordResetTokens::ExpiryDate,
             ])
             .values_panic(vec![
-                token.clone().into(),
+                hash_token(&token).into(),
                 user.into(),
                 (chrono::Utc::now() + duration).naive_utc().into(),
             ])
@@ -138,26 +132,282 @@ impl TcpBackendHandler for SqlBackendHandler {
         Ok(Some(token))
     }
 
-    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<UserId> {
-        let query = Query::select()
-            .column(PasswordResetTokens::UserId)
-            .from(PasswordResetTokens::Table)
-            .and_where(Expr::col(PasswordResetTokens::Token).eq(token))
+    async fn use_password_reset_token(&self, token: &str) -> Result<PasswordResetTokenUse> {
+        let token_hash = hash_token(token);
+        // A single statement claims the token, and SQLite runs it atomically: when two requests
+        // use the same token at the same time, only one of them updates the row.
+        let query = Query::update()
+            .table(PasswordResetTokens::Table)
+            .values(vec![(PasswordResetTokens::Used, true.into())])
+            .and_where(Expr::col(PasswordResetTokens::Token).eq(token_hash.as_str()))
+            .and_where(Expr::col(PasswordResetTokens::Used).eq(false))
             .and_where(
                 Expr::col(PasswordResetTokens::ExpiryDate).gt(chrono::Utc::now().naive_utc()),
             )
             .to_string(DbQueryBuilder {});
-
-        let (user_id,) = sqlx::query_as(&query).fetch_one(&self.sql_pool).await?;
-        Ok(user_id)
+        let claimed = sqlx::query(&query)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 1;
+        let query = Query::select()
+            .column(PasswordResetTokens::Token)
+            .column(PasswordResetTokens::UserId)
+            .column(PasswordResetTokens::Used)
+            .from(PasswordResetTokens::Table)
+            .and_where(Expr::col(PasswordResetTokens::Token).eq(token_hash.as_str()))
+            .to_string(DbQueryBuilder {});
+        let row = match sqlx::query(&query).fetch_optional(&self.sql_pool).await? {
+            Some(row) => row,
+            None => return Ok(PasswordResetTokenUse::Invalid),
+        };
+        // The database compares hashes, which doesn't leak anything about the token. Still check
+        // the stored hash in constant time, rather than rely on the database alone.
+        let stored_hash = row.get::<String, _>(&*PasswordResetTokens::Token.to_string());
+        if orion::util::secure_cmp(stored_hash.as_bytes(), token_hash.as_bytes()).is_err() {
+            return Ok(PasswordResetTokenUse::Invalid);
+        }
+        Ok(if claimed {
+            PasswordResetTokenUse::Valid(
+                row.get::<UserId, _>(&*PasswordResetTokens::UserId.to_string()),
+            )
+        } else if row.get::<bool, _>(&*PasswordResetTokens::Used.to_string()) {
+            PasswordResetTokenUse::AlreadyUsed
+        } else {
+            PasswordResetTokenUse::Invalid
+        })
     }
 
-    async fn delete_password_reset_token(&self, token: &str) -> Result<()> {
+    async fn delete_expired_tokens(&self) -> Result<()> {
+        let now = chrono::Local::now().naive_utc();
+        let query = Query::delete()
+            .from_table(JwtRefreshStorage::Table)
+            .and_where(Expr::col(JwtRefreshStorage::ExpiryDate).lt(now))
+            .to_string(DbQueryBuilder {});
+        sqlx::query(&query).execute(&self.sql_pool).await?;
+        let query = Query::delete()
+            .from_table(JwtStorage::Table)
+            .and_where(Expr::col(JwtStorage::ExpiryDate).lt(now))
+            .to_string(DbQueryBuilder {});
+        sqlx::query(&query).execute(&self.sql_pool).await?;
         let query = Query::delete()
             .from_table(PasswordResetTokens::Table)
-            .and_where(Expr::col(PasswordResetTokens::Token).eq(token))
+            .and_where(Expr::col(PasswordResetTokens::ExpiryDate).lt(now))
             .to_string(DbQueryBuilder {});
         sqlx::query(&query).execute(&self.sql_pool).await?;
         Ok(())
     }
+
+    async fn create_user_token(&self, user: &UserId, name: &str) -> Result<(UserToken, String)> {
+        let token = format!("{}{}", USER_TOKEN_PREFIX, gen_random_string(40));
+        let creation_date = chrono::Utc::now();
+        let query = Query::insert()
+            .into_table(UserTokens::Table)
+            .columns(vec![
+                UserTokens::TokenHash,
+                UserTokens::UserId,
+                UserTokens::Name,
+                UserTokens::CreationDate,
+            ])
+            .values_panic(vec![
+                hash_token(&token).into(),
+                user.into(),
+                name.into(),
+                creation_date.naive_utc().into(),
+            ])
+            .to_string(DbQueryBuilder {});
+        let token_id = sqlx::query(&query)
+            .execute(&self.sql_pool)
+            .await?
+            .last_insert_rowid();
+        Ok((
+            UserToken {
+                id: token_id as i32,
+                user_id: user.clone(),
+                name: name.to_string(),
+                creation_date,
+            },
+            token,
+        ))
+    }
+
+    async fn list_user_tokens(&self, user: &UserId) -> Result<Vec<UserToken>> {
+        let query = Query::select()
+            .column(UserTokens::TokenId)
+            .column(UserTokens::UserId)
+            .column(UserTokens::Name)
+            .column(UserTokens::CreationDate)
+            .from(UserTokens::Table)
+            .and_where(Expr::col(UserTokens::UserId).eq(user))
+            .order_by(UserTokens::TokenId, Order::Asc)
+            .to_string(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .map(|row: DbRow| UserToken {
+                id: row.get::<i32, _>(&*UserTokens::TokenId.to_string()),
+                user_id: row.get::<UserId, _>(&*UserTokens::UserId.to_string()),
+                name: row.get::<String, _>(&*UserTokens::Name.to_string()),
+                creation_date: row.get::<chrono::DateTime<chrono::Utc>, _>(
+                    &*UserTokens::CreationDate.to_string(),
+                ),
+            })
+            .fetch_all(&self.sql_pool)
+            .await?)
+    }
+
+    async fn revoke_user_token(&self, user: &UserId, token_id: i32) -> Result<bool> {
+        let query = Query::delete()
+            .from_table(UserTokens::Table)
+            .and_where(Expr::col(UserTokens::TokenId).eq(token_id))
+            .and_where(Expr::col(UserTokens::UserId).eq(user))
+            .to_string(DbQueryBuilder {});
+        Ok(sqlx::query(&query)
+            .execute(&self.sql_pool)
+            .await?
+            .rows_affected()
+            == 1)
+    }
+
+    async fn check_user_token(&self, token: &str) -> Result<Option<UserId>> {
+        let token_hash = hash_token(token);
+        let query = Query::select()
+            .column(UserTokens::TokenHash)
+            .column(UserTokens::UserId)
+            .from(UserTokens::Table)
+            .and_where(Expr::col(UserTokens::TokenHash).eq(token_hash.as_str()))
+            .to_string(DbQueryBuilder {});
+        let row = match sqlx::query(&query).fetch_optional(&self.sql_pool).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let stored_hash = row.get::<String, _>(&*UserTokens::TokenHash.to_string());
+        if orion::util::secure_cmp(stored_hash.as_bytes(), token_hash.as_bytes()).is_err() {
+            return Ok(None);
+        }
+        Ok(Some(row.get::<UserId, _>(&*UserTokens::UserId.to_string())))
+    }
+}
+
+/// Only the hash of the password reset and API tokens is stored, so that reading the database
+/// isn't enough to use them.
+fn hash_token(token: &str) -> String {
+    use sha2::{Digest, Sha512};
+    base64::encode(Sha512::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::{BackendHandler, CreateUserRequest},
+            sql_tables::PoolOptions,
+        },
+        infra::configuration::ConfigurationBuilder,
+    };
+
+    async fn get_handler_with_user(user_id: &str) -> SqlBackendHandler {
+        let sql_pool = PoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::domain::sql_tables::init_table(&sql_pool)
+            .await
+            .unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let handler =
+            SqlBackendHandler::new(ConfigurationBuilder::default().build().unwrap(), sql_pool);
+        handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new(user_id),
+                email: "bob@bob.bob".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        handler
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_token_single_use() {
+        let handler = get_handler_with_user("bob").await;
+        let token = handler
+            .start_password_reset(&UserId::new("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Concurrent uses: only one of them gets the user.
+        let (first, second) = futures::join!(
+            handler.use_password_reset_token(&token),
+            handler.use_password_reset_token(&token)
+        );
+        let mut results = vec![first.unwrap(), second.unwrap()];
+        results.sort_by_key(|result| result == &PasswordResetTokenUse::AlreadyUsed);
+        assert_eq!(
+            results,
+            vec![
+                PasswordResetTokenUse::Valid(UserId::new("bob")),
+                PasswordResetTokenUse::AlreadyUsed
+            ]
+        );
+        assert_eq!(
+            handler.use_password_reset_token(&token).await.unwrap(),
+            PasswordResetTokenUse::AlreadyUsed
+        );
+        assert_eq!(
+            handler
+                .use_password_reset_token("not a token")
+                .await
+                .unwrap(),
+            PasswordResetTokenUse::Invalid
+        );
+        // Only the hash is stored.
+        let stored: Vec<(String,)> = sqlx::query_as("SELECT token FROM password_reset_tokens")
+            .fetch_all(&handler.sql_pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![(hash_token(&token),)]);
+    }
+
+    #[tokio::test]
+    async fn test_user_tokens() {
+        let handler = get_handler_with_user("bob").await;
+        let bob = UserId::new("bob");
+        let (first, first_token) = handler.create_user_token(&bob, "backup").await.unwrap();
+        let (second, second_token) = handler.create_user_token(&bob, "ci").await.unwrap();
+        assert!(first_token.starts_with(USER_TOKEN_PREFIX));
+        assert_eq!(
+            handler
+                .list_user_tokens(&bob)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|token| (token.id, token.name))
+                .collect::<Vec<_>>(),
+            vec![
+                (first.id, "backup".to_string()),
+                (second.id, "ci".to_string())
+            ]
+        );
+        assert_eq!(
+            handler.check_user_token(&first_token).await.unwrap(),
+            Some(bob.clone())
+        );
+        assert_eq!(
+            handler.check_user_token("lldap_not_a_token").await.unwrap(),
+            None
+        );
+        // Another user can't revoke the token.
+        assert!(!handler
+            .revoke_user_token(&UserId::new("jim"), first.id)
+            .await
+            .unwrap());
+        assert!(handler.revoke_user_token(&bob, first.id).await.unwrap());
+        assert_eq!(handler.check_user_token(&first_token).await.unwrap(), None);
+        assert_eq!(
+            handler.check_user_token(&second_token).await.unwrap(),
+            Some(bob)
+        );
+    }
 }
@@ -1,8 +1,31 @@
 use async_trait::async_trait;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use crate::domain::{error::Result, handler::UserId};
 
+/// The outcome of using a password reset token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordResetTokenUse {
+    /// The token was valid for this user, and is now used.
+    Valid(UserId),
+    /// The token is valid, but was already used.
+    AlreadyUsed,
+    /// The token is unknown or expired.
+    Invalid,
+}
+
+/// The prefix of the API tokens, to tell them apart from the JWTs.
+pub const USER_TOKEN_PREFIX: &str = "lldap_";
+
+/// A long-lived API token, without the token itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserToken {
+    pub id: i32,
+    pub user_id: UserId,
+    pub name: String,
+    pub creation_date: chrono::DateTime<chrono::Utc>,
+}
+
 #[async_trait]
 pub trait TcpBackendHandler {
     async fn get_jwt_blacklist(&self) -> anyhow::Result<HashSet<u64>>;
@@ -15,10 +38,21 @@ pub trait TcpBackendHandler {
     /// If the user doesn't exist, returns `Ok(None)`, otherwise `Ok(Some(token))`.
     async fn start_password_reset(&self, user: &UserId) -> Result<Option<String>>;
 
-    /// Get the user ID associated with a password reset token.
-    async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<UserId>;
+    /// Marks the password reset token as used, and returns the user it was issued for. A token
+    /// can only be used once, even by concurrent requests.
+    async fn use_password_reset_token(&self, token: &str) -> Result<PasswordResetTokenUse>;
+
+    /// Remove the refresh tokens and JWTs that have expired.
+    async fn delete_expired_tokens(&self) -> Result<()>;
 
-    async fn delete_password_reset_token(&self, token: &str) -> Result<()>;
+    /// Issues a long-lived API token for the user, and returns it along with its details. The
+    /// token can't be retrieved later.
+    async fn create_user_token(&self, user: &UserId, name: &str) -> Result<(UserToken, String)>;
+    async fn list_user_tokens(&self, user: &UserId) -> Result<Vec<UserToken>>;
+    /// Returns false if the user has no token with this ID.
+    async fn revoke_user_token(&self, user: &UserId, token_id: i32) -> Result<bool>;
+    /// The user the API token was issued to, or None if it's unknown or revoked.
+    async fn check_user_token(&self, token: &str) -> Result<Option<UserId>>;
 }
 
 #[cfg(test)]
@@ -32,22 +66,49 @@ mockall::mock! {
     #[async_trait]
     impl LoginHandler for TestTcpBackendHandler {
         async fn bind(&self, request: BindRequest) -> Result<()>;
+        async fn get_grace_logins_remaining(&self, user_id: &UserId) -> Result<i32>;
+        async fn has_password(&self, user_id: &UserId) -> Result<bool>;
+        async fn check_password(&self, user_id: &UserId, password: &str) -> Result<bool>;
     }
     #[async_trait]
     impl BackendHandler for TestTcpBackendHandler {
         async fn list_users(&self, filters: Option<UserRequestFilter>) -> Result<Vec<User>>;
+        async fn list_users_sorted(&self, filters: Option<UserRequestFilter>, sort: UserSort, page: Option<Page>) -> Result<Vec<User>>;
         async fn list_groups(&self, filters: Option<GroupRequestFilter>) -> Result<Vec<Group>>;
+        async fn count_users(&self, filters: Option<UserRequestFilter>) -> Result<u64>;
+        async fn count_groups(&self, filters: Option<GroupRequestFilter>) -> Result<u64>;
         async fn get_user_details(&self, user_id: &UserId) -> Result<User>;
+        async fn get_reports(&self, manager_id: &UserId) -> Result<Vec<User>>;
+        async fn find_user_by_email_alias(&self, alias: &str) -> Result<Option<User>>;
+        async fn find_user_by_certificate_cn(&self, cn: &str) -> Result<Option<User>>;
         async fn get_group_details(&self, group_id: GroupId) -> Result<GroupIdAndName>;
         async fn get_user_groups(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
+        async fn get_groups_for_users(
+            &self,
+            user_ids: &[UserId],
+        ) -> Result<HashMap<UserId, HashSet<GroupIdAndName>>>;
+        async fn get_groups_containing_user_recursive(&self, user: &UserId) -> Result<HashSet<GroupIdAndName>>;
+        fn clear_membership_cache(&self);
+        fn data_version(&self) -> u64;
         async fn create_user(&self, request: CreateUserRequest) -> Result<()>;
         async fn update_user(&self, request: UpdateUserRequest) -> Result<()>;
         async fn update_group(&self, request: UpdateGroupRequest) -> Result<()>;
         async fn delete_user(&self, user_id: &UserId) -> Result<()>;
+        async fn rename_user(&self, user_id: &UserId, new_user_id: &UserId) -> Result<()>;
+        async fn add_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+        async fn remove_phone_number(&self, user_id: &UserId, phone_number: &str) -> Result<()>;
+        async fn set_phone_numbers(&self, user_id: &UserId, phone_numbers: Vec<String>) -> Result<()>;
+        async fn update_last_login(&self, user_id: &UserId, timestamp: chrono::DateTime<chrono::Utc>) -> Result<()>;
+        async fn flush_last_logins(&self) -> Result<usize>;
+        async fn get_login_report(&self, since: chrono::NaiveDate, page: Page) -> Result<Vec<UserLoginStats>>;
         async fn create_group(&self, group_name: &str) -> Result<GroupId>;
         async fn delete_group(&self, group_id: GroupId) -> Result<()>;
         async fn add_user_to_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
         async fn remove_user_from_group(&self, user_id: &UserId, group_id: GroupId) -> Result<()>;
+        async fn create_invitation(&self, email: &str) -> Result<Invitation>;
+        async fn get_invitation(&self, token: &str) -> Result<Invitation>;
+        async fn mark_invitation_used(&self, invitation_id: InvitationId) -> Result<bool>;
+        async fn list_pending_invitations(&self) -> Result<Vec<Invitation>>;
     }
     #[async_trait]
     impl TcpBackendHandler for TestTcpBackendHandler {
@@ -57,7 +118,11 @@ mockall::mock! {
         async fn blacklist_jwts(&self, user: &UserId) -> Result<HashSet<u64>>;
         async fn delete_refresh_token(&self, refresh_token_hash: u64) -> Result<()>;
         async fn start_password_reset(&self, user: &UserId) -> Result<Option<String>>;
-        async fn get_user_id_for_password_reset_token(&self, token: &str) -> Result<UserId>;
-        async fn delete_password_reset_token(&self, token: &str) -> Result<()>;
+        async fn use_password_reset_token(&self, token: &str) -> Result<PasswordResetTokenUse>;
+        async fn delete_expired_tokens(&self) -> Result<()>;
+        async fn create_user_token(&self, user: &UserId, name: &str) -> Result<(UserToken, String)>;
+        async fn list_user_tokens(&self, user: &UserId) -> Result<Vec<UserToken>>;
+        async fn revoke_user_token(&self, user: &UserId, token_id: i32) -> Result<bool>;
+        async fn check_user_token(&self, token: &str) -> Result<Option<UserId>>;
     }
 }
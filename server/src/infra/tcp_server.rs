@@ -1,132 +1,916 @@
 use crate::{
     domain::{
-        error::DomainError,
-        handler::{BackendHandler, LoginHandler},
+        error::{DomainError, TimeoutKind},
+        handler::{
+            BackendHandler, GroupId, LoginHandler, Page, TransactionHandler, UserId, UserLoginStats,
+        },
         opaque_handler::OpaqueHandler,
     },
     infra::{
-        auth_service,
-        configuration::{Configuration, MailOptions},
+        attribute_visibility::AttributeVisibilityPolicy,
+        auth_service::{self, check_request_identity, read_only_response},
+        configuration::{BrandingOptions, Configuration},
+        connection_filter::ConnectionFilter,
+        dns_records::{DnsRecordFormat, DnsSrvRecords},
+        ldap_connections::LdapConnectionRegistry,
+        mail::Mailer,
+        maintenance::MaintenanceMode,
+        metrics::LDAP_CONNECTION_SLOTS_USED,
+        request_logging::RequestLoggingMiddlewareFactory,
+        response_headers::CustomHeadersMiddlewareFactory,
+        scheduled_jobs::{
+            JwtBlacklistCleanupJob, LastLoginFlushJob, ScheduledJob, ScheduledJobRunner,
+            TokenCleanupJob,
+        },
         tcp_backend_handler::*,
+        trace_propagation::PropagatorMiddlewareFactory,
     },
 };
 use actix_files::{Files, NamedFile};
 use actix_http::HttpServiceBuilder;
+use actix_rt::net::TcpStream;
 use actix_server::ServerBuilder;
-use actix_service::map_config;
-use actix_web::{dev::AppConfig, web, App, HttpResponse};
+use actix_service::{apply_fn_factory, boxed, map_config, Service, ServiceFactoryExt};
+use actix_web::{dev::AppConfig, error::ErrorForbidden, web, App, HttpRequest, HttpResponse};
 use anyhow::{Context, Result};
 use hmac::{Hmac, NewMac};
+use lldap_auth::invitation;
+use log::*;
+use serde::{Deserialize, Serialize};
 use sha2::Sha512;
 use std::collections::HashSet;
-use std::path::PathBuf;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-async fn index() -> actix_web::Result<NamedFile> {
+fn index_path() -> PathBuf {
     let mut path = PathBuf::new();
     path.push("app");
     path.push("index.html");
-    Ok(NamedFile::open(path)?)
+    path
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Inserts the banner at the top of the page body. The line breaks are preserved.
+fn inject_login_banner(index: &str, banner: &str) -> String {
+    let banner_html = format!(
+        r#"<div id="login-banner" class="alert alert-warning text-center" style="white-space: pre-line">{}</div>"#,
+        escape_html(banner.trim())
+    );
+    match index.find("<body>") {
+        Some(position) => {
+            let position = position + "<body>".len();
+            format!(
+                "{}\n{}{}",
+                &index[..position],
+                banner_html,
+                &index[position..]
+            )
+        }
+        None => format!("{}{}", banner_html, index),
+    }
+}
+
+/// Sets the title of the page, and adds the links to the custom favicon and stylesheet.
+fn apply_branding(index: &str, branding: &BrandingOptions, custom_stylesheet: bool) -> String {
+    let mut index = index.to_string();
+    if let Some(title) = &branding.title {
+        if let (Some(start), Some(end)) = (index.find("<title>"), index.find("</title>")) {
+            index.replace_range(start + "<title>".len()..end, &escape_html(title));
+        }
+    }
+    let mut head = String::new();
+    if branding.favicon_file.is_some() {
+        head.push_str(r#"<link rel="icon" href="/favicon.ico" />"#);
+    }
+    if custom_stylesheet {
+        head.push_str(r#"<link rel="stylesheet" href="/static/custom.css" />"#);
+    }
+    if !head.is_empty() {
+        if let Some(position) = index.find("</head>") {
+            index.insert_str(position, &format!("{}\n", head));
+        }
+    }
+    index
+}
+
+async fn index<Backend>(
+    data: web::Data<AppState<Backend>>,
+) -> actix_web::Result<actix_web::Either<NamedFile, HttpResponse>>
+where
+    Backend: 'static,
+{
+    if data.login_banner.is_none() && data.branding == BrandingOptions::default() {
+        return Ok(actix_web::Either::Left(NamedFile::open(index_path())?));
+    }
+    let mut index = tokio::fs::read_to_string(index_path()).await?;
+    let custom_stylesheet = data
+        .branding
+        .assets_dir
+        .as_ref()
+        .map_or(false, |dir| Path::new(dir).join("custom.css").is_file());
+    index = apply_branding(&index, &data.branding, custom_stylesheet);
+    if let Some(banner) = &data.login_banner {
+        index = inject_login_banner(&index, banner);
+    }
+    Ok(actix_web::Either::Right(
+        HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(index),
+    ))
+}
+
+async fn favicon<Backend>(data: web::Data<AppState<Backend>>) -> actix_web::Result<NamedFile>
+where
+    Backend: 'static,
+{
+    match &data.branding.favicon_file {
+        Some(file) => Ok(NamedFile::open(file)?),
+        None => Err(actix_web::error::ErrorNotFound("No favicon")),
+    }
+}
+
+#[derive(Deserialize)]
+struct TestEmailRequest {
+    to: String,
+}
+
+async fn post_test_email<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    body: web::Json<TestEmailRequest>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can send test emails"));
+    }
+    let mail_options = data.mailer.options().clone();
+    let proxy = data.mailer.proxy().clone();
+    let to = body.into_inner().to;
+    let result =
+        web::block(move || super::mail::diagnose_test_email(&to, &mail_options, &proxy)).await?;
+    Ok(HttpResponse::Ok().json(&result))
+}
+
+async fn post_invitation<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    body: web::Json<invitation::CreateInvitationRequest>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can invite users"));
+    }
+    if data.maintenance_mode.is_enabled() {
+        return Ok(read_only_response());
+    }
+    let email = body.into_inner().email;
+    if email.parse::<lettre::Address>().is_err() {
+        return Ok(HttpResponse::BadRequest().body(format!("Invalid email address: {}", email)));
+    }
+    let created = match data.backend_handler.create_invitation(&email).await {
+        Ok(created) => created,
+        Err(e) => return Ok(error_to_http_response(e)),
+    };
+    if let Err(e) =
+        super::mail::send_invitation_email(&email, &created.token, &data.server_url, &data.mailer)
+            .await
+    {
+        warn!("Error sending email: {:#?}", e);
+        return Ok(HttpResponse::InternalServerError().body(format!("Could not send email: {}", e)));
+    }
+    Ok(HttpResponse::Ok().json(&invitation::CreateInvitationResponse { id: created.id.0 }))
+}
+
+/// The users reporting directly to the user, for admins and the user themselves. The fields
+/// hidden by the attribute visibility policy are empty.
+async fn get_user_reports<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    user_id: web::Path<String>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let identity = check_request_identity(&data, &request).await?;
+    if !identity.can_access(&user_id) {
+        return Err(ErrorForbidden("Unauthorized access to the user's reports"));
+    }
+    let viewer = UserId::new(&identity.user);
+    match data
+        .backend_handler
+        .get_reports(&UserId::new(&user_id))
+        .await
+    {
+        Ok(mut reports) => {
+            for report in &mut reports {
+                let is_owner = report.user_id == viewer;
+                data.attribute_visibility
+                    .hide_fields(report, identity.is_admin, is_owner);
+            }
+            Ok(HttpResponse::Ok().json(&reports))
+        }
+        Err(e) => Ok(error_to_http_response(e)),
+    }
+}
+
+async fn get_jobs<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + Sync + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can list the scheduled jobs"));
+    }
+    Ok(HttpResponse::Ok().json(&data.jobs.list()))
+}
+
+async fn post_run_job<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    name: web::Path<String>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + Sync + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can run the scheduled jobs"));
+    }
+    match data.jobs.run_now(&name).await {
+        Some(status) => Ok(HttpResponse::Ok().json(&status)),
+        None => Ok(HttpResponse::NotFound().body(format!("No job named {}", name))),
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginReportQuery {
+    #[serde(default = "default_login_report_days")]
+    days: u32,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default = "default_login_report_limit")]
+    limit: u64,
+}
+
+fn default_login_report_days() -> u32 {
+    30
+}
+
+fn default_login_report_limit() -> u64 {
+    100
+}
+
+const MAX_LOGIN_REPORT_LIMIT: u64 = 1000;
+
+#[derive(Serialize)]
+struct LoginReport {
+    /// The first day (UTC) of the window.
+    since: chrono::NaiveDate,
+    offset: u64,
+    limit: u64,
+    users: Vec<UserLoginStats>,
+}
+
+/// The number of logins of each user over the last `days` days, most active users first, to spot
+/// dormant or suspicious accounts. Only the last `login_history_days` days are kept.
+async fn get_login_report<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    query: web::Query<LoginReportQuery>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can read the login report"));
+    }
+    // Older days aren't kept anyway, and a huge number of days would overflow the date.
+    let days = query.days.clamp(1, data.login_history_days.max(1));
+    let since = chrono::Utc::now().date().naive_utc() - chrono::Duration::days(i64::from(days - 1));
+    let page = Page {
+        offset: query.offset,
+        limit: query.limit.min(MAX_LOGIN_REPORT_LIMIT),
+    };
+    match data.backend_handler.get_login_report(since, page).await {
+        Ok(users) => Ok(HttpResponse::Ok().json(&LoginReport {
+            since,
+            offset: page.offset,
+            limit: page.limit,
+            users,
+        })),
+        Err(e) => Ok(error_to_http_response(e)),
+    }
+}
+
+/// Drops the cached group memberships, e.g. after editing the database directly.
+async fn post_flush_cache<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can flush the caches"));
+    }
+    data.backend_handler.clear_membership_cache();
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// The LDAP connections currently open.
+async fn get_ldap_connections<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can list the LDAP connections"));
+    }
+    Ok(HttpResponse::Ok().json(&data.ldap_connections.list()))
+}
+
+/// Closes an LDAP connection, once its operation in progress if any is done.
+async fn delete_ldap_connection<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    let validation_result = check_request_identity(&data, &request).await?;
+    if !validation_result.is_admin {
+        return Err(ErrorForbidden("Only admins can close the LDAP connections"));
+    }
+    let id = id.into_inner();
+    if !data.ldap_connections.close(id) {
+        return Ok(HttpResponse::NotFound().body(format!("No LDAP connection with the ID {}", id)));
+    }
+    info!(
+        r#"LDAP connection {} closed by "{}""#,
+        id, validation_result.user
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The configuration loaded by the server, without the secrets, for debugging.
+async fn get_config<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+) -> actix_web::Result<HttpResponse>
+where
+    Backend: TcpBackendHandler + BackendHandler + 'static,
+{
+    if !check_request_identity(&data, &request).await?.is_admin {
+        return Err(ErrorForbidden("Only admins can read the configuration"));
+    }
+    Ok(HttpResponse::Ok().json(&data.redacted_config))
+}
+
+#[derive(Serialize)]
+struct RegistrationConfig<'a> {
+    /// The email domains users can register with, or None if any domain is allowed.
+    allowed_email_domains: &'a Option<Vec<String>>,
+}
+
+/// Public endpoint exposing the registration constraints, for the frontend to validate forms.
+async fn get_registration_config<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: 'static,
+{
+    HttpResponse::Ok().json(&RegistrationConfig {
+        allowed_email_domains: &data.allowed_email_domains,
+    })
+}
+
+#[derive(Deserialize)]
+struct DnsSrvRecordsQuery {
+    format: Option<DnsRecordFormat>,
+}
+
+/// Public endpoint returning the SRV records to publish for the LDAP service discovery, as JSON
+/// or, with `?format=`, as text to paste in a DNS configuration.
+async fn get_dns_srv_records<Backend>(
+    data: web::Data<AppState<Backend>>,
+    query: web::Query<DnsSrvRecordsQuery>,
+) -> HttpResponse
+where
+    Backend: 'static,
+{
+    let records = match &data.dns_srv_records {
+        Some(records) => records,
+        None => {
+            return HttpResponse::NotFound()
+                .body("The DNS records cannot be derived from the base DN and the HTTP URL")
+        }
+    };
+    match query.format {
+        Some(format) => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(records.format(format)),
+        None => HttpResponse::Ok().json(records.as_ref()),
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    /// "ok", or "error" if the database could not be queried.
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    users: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// The number of LDAP connection slots in use, see `ldap_max_total_connections`.
+    ldap_connections: i64,
+}
+
+/// Checks that the database can be queried, returning the number of users and groups as a sanity
+/// check.
+async fn get_health<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    let counts = async {
+        Ok::<_, DomainError>((
+            data.backend_handler.count_users(None).await?,
+            data.backend_handler.count_groups(None).await?,
+        ))
+    };
+    match counts.await {
+        Ok((users, groups)) => HttpResponse::Ok().json(&Health {
+            status: "ok",
+            users: Some(users),
+            groups: Some(groups),
+            message: None,
+            ldap_connections: LDAP_CONNECTION_SLOTS_USED.get(),
+        }),
+        Err(e) => {
+            warn!("Health check failed: {:#}", e);
+            HttpResponse::ServiceUnavailable().json(&Health {
+                status: "error",
+                users: None,
+                groups: None,
+                message: Some(format!("{:#}", e)),
+                ldap_connections: LDAP_CONNECTION_SLOTS_USED.get(),
+            })
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SmtpHealth {
+    /// "ok", or "error" if the SMTP server could not be reached.
+    status: &'static str,
+}
+
+/// Checks the connection to the SMTP server with a NOOP command.
+///
+/// The endpoint is unauthenticated, so the error (which can contain the SMTP server address or
+/// its replies) is only logged.
+async fn get_smtp_health<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: 'static,
+{
+    match data.mailer.check_connection().await {
+        Ok(()) => HttpResponse::Ok().json(&SmtpHealth { status: "ok" }),
+        Err(e) => {
+            warn!("SMTP health check failed: {:#}", e);
+            HttpResponse::ServiceUnavailable().json(&SmtpHealth { status: "error" })
+        }
+    }
 }
 
 pub(crate) fn error_to_http_response(error: DomainError) -> HttpResponse {
     match error {
-        DomainError::AuthenticationError(_) | DomainError::AuthenticationProtocolError(_) => {
-            HttpResponse::Unauthorized()
-        }
+        DomainError::AuthenticationError(_)
+        | DomainError::AuthenticationProtocolError(_)
+        | DomainError::CredentialsNotFound(_)
+        | DomainError::PasswordExpired => HttpResponse::Unauthorized(),
         DomainError::DatabaseError(_)
         | DomainError::InternalError(_)
         | DomainError::UnknownCryptoError(_) => HttpResponse::InternalServerError(),
-        DomainError::Base64DecodeError(_) | DomainError::BinarySerializationError(_) => {
-            HttpResponse::BadRequest()
-        }
+        DomainError::Base64DecodeError(_)
+        | DomainError::BinarySerializationError(_)
+        | DomainError::ValidationError(_, _) => HttpResponse::BadRequest(),
+        DomainError::ConstraintViolation(_) => HttpResponse::Conflict(),
+        DomainError::Timeout(TimeoutKind::Operation) => HttpResponse::GatewayTimeout(),
+        DomainError::Timeout(TimeoutKind::Query) => HttpResponse::ServiceUnavailable(),
     }
     .body(error.to_string())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn http_config<Backend>(
     cfg: &mut web::ServiceConfig,
     backend_handler: Backend,
     jwt_secret: secstr::SecUtf8,
-    jwt_blacklist: HashSet<u64>,
+    jwt_blacklist: Arc<RwLock<HashSet<u64>>>,
     server_url: String,
-    mail_options: MailOptions,
+    mailer: Mailer,
+    maintenance_mode: MaintenanceMode,
+    login_banner: Option<String>,
+    graphql_introspection: bool,
+    graphql_max_query_depth: usize,
+    graphql_max_query_complexity: usize,
+    graphql_max_body_size: usize,
+    graphql_max_batch_size: usize,
+    web_login_attribute: String,
+    attribute_visibility: AttributeVisibilityPolicy,
+    allowed_email_domains: Option<Vec<String>>,
+    server_id: String,
+    metrics_public: bool,
+    redacted_config: Arc<serde_json::Value>,
+    jobs: Arc<ScheduledJobRunner<Backend>>,
+    branding: BrandingOptions,
+    ldap_connections: LdapConnectionRegistry,
+    dns_srv_records: Option<Arc<DnsSrvRecords>>,
+    max_password_length: usize,
+    admin_group_id: GroupId,
+    login_history_days: u32,
 ) where
-    Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Sync + 'static,
+    Backend: TcpBackendHandler
+        + BackendHandler
+        + LoginHandler
+        + OpaqueHandler
+        + TransactionHandler
+        + Sync
+        + 'static,
+    Backend::Transaction: OpaqueHandler,
 {
     cfg.app_data(web::Data::new(AppState::<Backend> {
         backend_handler,
         jwt_key: Hmac::new_varkey(jwt_secret.unsecure().as_bytes()).unwrap(),
-        jwt_blacklist: RwLock::new(jwt_blacklist),
+        jwt_blacklist,
         server_url,
-        mail_options,
+        mailer,
+        maintenance_mode,
+        login_banner,
+        graphql_introspection,
+        graphql_max_query_depth,
+        graphql_max_query_complexity,
+        graphql_max_body_size,
+        graphql_max_batch_size,
+        web_login_attribute,
+        attribute_visibility,
+        allowed_email_domains,
+        server_id,
+        metrics_public,
+        redacted_config,
+        jobs,
+        branding: branding.clone(),
+        ldap_connections,
+        dns_srv_records,
+        max_password_length,
+        admin_group_id,
+        login_history_days,
     }))
     .service(web::scope("/auth").configure(auth_service::configure_server::<Backend>))
     // API endpoint.
     .service(
         web::scope("/api")
+            .wrap(super::cert_auth::CertAuthMiddlewareFactory::<Backend>::default())
             .wrap(auth_service::CookieToHeaderTranslatorFactory)
-            .configure(super::graphql::api::configure_endpoint::<Backend>),
+            .configure(super::graphql::api::configure_endpoint::<Backend>)
+            .service(web::resource("/mail/test").route(web::post().to(post_test_email::<Backend>)))
+            .service(
+                web::resource("/v1/invitations").route(web::post().to(post_invitation::<Backend>)),
+            )
+            .service(
+                web::resource("/v1/registration-config")
+                    .route(web::get().to(get_registration_config::<Backend>)),
+            )
+            .service(web::resource("/config").route(web::get().to(get_config::<Backend>)))
+            .service(
+                web::resource("/v1/dns-srv-records")
+                    .route(web::get().to(get_dns_srv_records::<Backend>)),
+            )
+            .service(
+                web::resource("/v1/users/{id}/reports")
+                    .route(web::get().to(get_user_reports::<Backend>)),
+            )
+            .service(web::resource("/v1/admin/jobs").route(web::get().to(get_jobs::<Backend>)))
+            .service(
+                web::resource("/v1/admin/jobs/{name}/run")
+                    .route(web::post().to(post_run_job::<Backend>)),
+            )
+            .service(
+                web::resource("/v1/admin/login-report")
+                    .route(web::get().to(get_login_report::<Backend>)),
+            )
+            .service(
+                web::resource("/v1/admin/cache/flush")
+                    .route(web::post().to(post_flush_cache::<Backend>)),
+            )
+            .service(
+                web::resource("/v1/admin/ldap/connections")
+                    .route(web::get().to(get_ldap_connections::<Backend>)),
+            )
+            .service(
+                web::resource("/v1/admin/ldap/connections/{id}")
+                    .route(web::delete().to(delete_ldap_connection::<Backend>)),
+            ),
     )
+    .service(web::resource("/health").route(web::get().to(get_health::<Backend>)))
+    .service(web::resource("/health/smtp").route(web::get().to(get_smtp_health::<Backend>)))
+    // Prometheus metrics.
+    .service(web::resource("/metrics").route(web::get().to(super::metrics::get_metrics::<Backend>)))
     // Serve the /pkg path with the compiled WASM app.
     .service(Files::new("/pkg", "./app/pkg"))
-    // Serve static files
-    .service(Files::new("/static", "./app/static"))
+    .service(web::resource("/favicon.ico").route(web::get().to(favicon::<Backend>)))
+    // Serve static files, from the branding directory first if there is one.
+    .service(match &branding.assets_dir {
+        None => Files::new("/static", "./app/static"),
+        Some(assets_dir) => {
+            Files::new("/static", assets_dir).default_handler(Files::new("/static", "./app/static"))
+        }
+    })
     // Serve static fonts
     .service(Files::new("/static/fonts", "./app/static/fonts"))
     // Default to serve index.html for unknown routes, to support routing.
     .service(
         web::scope("/")
-            .route("", web::get().to(index)) // this is necessary because the below doesn't match a request for "/"
-            .route(".*", web::get().to(index)),
+            .route("", web::get().to(index::<Backend>)) // this is necessary because the below doesn't match a request for "/"
+            .route(".*", web::get().to(index::<Backend>)),
     );
 }
 
 pub(crate) struct AppState<Backend> {
     pub backend_handler: Backend,
     pub jwt_key: Hmac<Sha512>,
-    pub jwt_blacklist: RwLock<HashSet<u64>>,
+    /// Shared by the workers, so that a logout applies to all of them.
+    pub jwt_blacklist: Arc<RwLock<HashSet<u64>>>,
     pub server_url: String,
-    pub mail_options: MailOptions,
+    pub mailer: Mailer,
+    pub maintenance_mode: MaintenanceMode,
+    pub login_banner: Option<String>,
+    pub graphql_introspection: bool,
+    pub graphql_max_query_depth: usize,
+    pub graphql_max_query_complexity: usize,
+    /// In bytes.
+    pub graphql_max_body_size: usize,
+    /// The maximum number of operations in a batched request.
+    pub graphql_max_batch_size: usize,
+    pub web_login_attribute: String,
+    pub attribute_visibility: AttributeVisibilityPolicy,
+    pub allowed_email_domains: Option<Vec<String>>,
+    pub server_id: String,
+    /// Serve /metrics without authentication.
+    pub metrics_public: bool,
+    /// The effective configuration, with the secrets redacted.
+    pub redacted_config: Arc<serde_json::Value>,
+    pub jobs: Arc<ScheduledJobRunner<Backend>>,
+    pub branding: BrandingOptions,
+    /// Shared with the LDAP server.
+    pub ldap_connections: LdapConnectionRegistry,
+    /// None if the configuration doesn't allow deriving them.
+    pub dns_srv_records: Option<Arc<DnsSrvRecords>>,
+    pub max_password_length: usize,
+    pub admin_group_id: GroupId,
+    /// The number of days of daily login counts that are kept.
+    pub login_history_days: u32,
+}
+
+#[cfg(test)]
+impl AppState<crate::domain::sql_backend_handler::SqlBackendHandler> {
+    /// Builds the state of the handlers on top of an empty in-memory database.
+    pub(crate) async fn new_for_tests(config: &Configuration) -> web::Data<Self> {
+        use crate::domain::{
+            sql_backend_handler::SqlBackendHandler,
+            sql_tables::{init_table, PoolOptions},
+        };
+        let sql_pool = PoolOptions::new().connect("sqlite::memory:").await.unwrap();
+        init_table(&sql_pool).await.unwrap();
+        let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool);
+        web::Data::new(Self {
+            backend_handler: backend_handler.clone(),
+            jwt_key: Hmac::new_varkey(config.jwt_secret.unsecure().as_bytes()).unwrap(),
+            jwt_blacklist: Arc::default(),
+            server_url: config.http_url.clone(),
+            mailer: Mailer::default(),
+            maintenance_mode: MaintenanceMode::default(),
+            login_banner: config.login_banner.clone(),
+            graphql_introspection: config.graphql_introspection,
+            graphql_max_query_depth: config.graphql_max_query_depth,
+            graphql_max_query_complexity: config.graphql_max_query_complexity,
+            graphql_max_body_size: config.graphql_max_body_size,
+            graphql_max_batch_size: config.graphql_max_batch_size,
+            web_login_attribute: config.web_login_attribute.clone(),
+            attribute_visibility: config.attribute_visibility.clone(),
+            allowed_email_domains: config.allowed_email_domains.clone(),
+            server_id: config.server_id.clone(),
+            metrics_public: config.metrics_public,
+            redacted_config: Arc::new(serde_json::Value::Null),
+            jobs: Arc::new(ScheduledJobRunner::new(backend_handler, Vec::new())),
+            branding: config.branding.clone(),
+            ldap_connections: LdapConnectionRegistry::default(),
+            dns_srv_records: None,
+            max_password_length: config.max_password_length,
+            admin_group_id: GroupId(config.admin_group_id),
+            login_history_days: config.login_history_days,
+        })
+    }
 }
 
 pub async fn build_tcp_server<Backend>(
     config: &Configuration,
     backend_handler: Backend,
+    maintenance_mode: MaintenanceMode,
+    mailer: Mailer,
+    ldap_connections: LdapConnectionRegistry,
     server_builder: ServerBuilder,
 ) -> Result<ServerBuilder>
 where
-    Backend: TcpBackendHandler + BackendHandler + LoginHandler + OpaqueHandler + Sync + 'static,
+    Backend: TcpBackendHandler
+        + BackendHandler
+        + LoginHandler
+        + OpaqueHandler
+        + TransactionHandler
+        + Sync
+        + 'static,
+    Backend::Transaction: OpaqueHandler,
 {
     let jwt_secret = config.jwt_secret.clone();
-    let jwt_blacklist = backend_handler
-        .get_jwt_blacklist()
-        .await
-        .context("while getting the jwt blacklist")?;
+    let jwt_blacklist = Arc::new(RwLock::new(
+        backend_handler
+            .get_jwt_blacklist()
+            .await
+            .context("while getting the jwt blacklist")?,
+    ));
     let server_url = config.http_url.clone();
-    let mail_options = config.smtp_options.clone();
+    let login_banner = config.login_banner.clone();
+    let graphql_introspection = config.graphql_introspection;
+    let graphql_max_query_depth = config.graphql_max_query_depth;
+    let graphql_max_query_complexity = config.graphql_max_query_complexity;
+    let graphql_max_body_size = config.graphql_max_body_size;
+    let graphql_max_batch_size = config.graphql_max_batch_size;
+    let web_login_attribute = config.web_login_attribute.clone();
+    let attribute_visibility = config.attribute_visibility.clone();
+    let allowed_email_domains = config.allowed_email_domains.clone();
+    let server_id = config.server_id.clone();
+    let metrics_public = config.metrics_public;
+    let max_password_length = config.max_password_length;
+    let admin_group_id = GroupId(config.admin_group_id);
+    let login_history_days = config.login_history_days;
+    let branding = config.branding.clone();
+    let custom_headers = CustomHeadersMiddlewareFactory::new(Arc::new(
+        super::response_headers::parse_custom_headers(&config.custom_response_headers)
+            .context("while reading the custom_response_headers")?,
+    ));
+    let request_logging = RequestLoggingMiddlewareFactory::new(&config.request_logging);
+    let trace_propagation = PropagatorMiddlewareFactory::new(&config.opentelemetry);
+    let redacted_config = Arc::new(
+        config
+            .to_redacted_json()
+            .context("while serializing the configuration")?,
+    );
+    let dns_srv_records = match super::dns_records::make_srv_records(config) {
+        Ok(records) => Some(Arc::new(records)),
+        Err(e) => {
+            warn!("Not serving the DNS SRV records: {:#}", e);
+            None
+        }
+    };
+    let mut scheduled_jobs: Vec<Box<dyn ScheduledJob<Backend>>> = vec![Box::new(TokenCleanupJob)];
+    if config.jwt_blacklist_cleanup_interval_secs > 0 {
+        scheduled_jobs.push(Box::new(JwtBlacklistCleanupJob {
+            blacklist: jwt_blacklist.clone(),
+            interval: Duration::from_secs(config.jwt_blacklist_cleanup_interval_secs),
+        }));
+    }
+    if config.last_login_flush_interval_secs > 0 {
+        scheduled_jobs.push(Box::new(LastLoginFlushJob {
+            interval: Duration::from_secs(config.last_login_flush_interval_secs),
+        }));
+    }
+    let jobs = Arc::new(ScheduledJobRunner::new(
+        backend_handler.clone(),
+        scheduled_jobs,
+    ));
+    jobs.start();
+    let connection_filter = ConnectionFilter::new(config);
+    let tls_acceptor = config
+        .http_mtls_ca_file
+        .as_deref()
+        .map(|ca_file| {
+            super::cert_auth::build_mtls_acceptor(
+                &config.ldaps_options.cert_file,
+                &config.ldaps_options.key_file,
+                ca_file,
+            )
+        })
+        .transpose()
+        .context("while setting up mutual TLS for the HTTP server")?;
     server_builder
+        // Let the in-flight requests, e.g. the OPAQUE logins, complete on SIGTERM.
+        .shutdown_timeout(config.shutdown_timeout_seconds)
         .bind("http", ("0.0.0.0", config.http_port), move || {
             let backend_handler = backend_handler.clone();
             let jwt_secret = jwt_secret.clone();
             let jwt_blacklist = jwt_blacklist.clone();
             let server_url = server_url.clone();
-            let mail_options = mail_options.clone();
-            HttpServiceBuilder::new()
-                .finish(map_config(
-                    App::new().configure(move |cfg| {
+            let mailer = mailer.clone();
+            let maintenance_mode = maintenance_mode.clone();
+            let login_banner = login_banner.clone();
+            let web_login_attribute = web_login_attribute.clone();
+            let attribute_visibility = attribute_visibility.clone();
+            let allowed_email_domains = allowed_email_domains.clone();
+            let server_id = server_id.clone();
+            let redacted_config = redacted_config.clone();
+            let jobs = jobs.clone();
+            let branding = branding.clone();
+            let ldap_connections = ldap_connections.clone();
+            let dns_srv_records = dns_srv_records.clone();
+            let connection_filter = connection_filter.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            let app = map_config(
+                // Outermost, so that the custom headers replace the ones set by the app.
+                App::new()
+                    .wrap(request_logging.clone())
+                    .wrap(trace_propagation.clone())
+                    .wrap(custom_headers.clone())
+                    .configure(move |cfg| {
                         http_config(
                             cfg,
                             backend_handler,
                             jwt_secret,
                             jwt_blacklist,
                             server_url,
-                            mail_options,
+                            mailer,
+                            maintenance_mode,
+                            login_banner,
+                            graphql_introspection,
+                            graphql_max_query_depth,
+                            graphql_max_query_complexity,
+                            graphql_max_body_size,
+                            graphql_max_batch_size,
+                            web_login_attribute,
+                            attribute_visibility,
+                            allowed_email_domains,
+                            server_id,
+                            metrics_public,
+                            redacted_config,
+                            jobs,
+                            branding,
+                            ldap_connections,
+                            dns_srv_records,
+                            max_password_length,
+                            admin_group_id,
+                            login_history_days,
                         )
                     }),
-                    |_| AppConfig::default(),
-                ))
-                .tcp()
+                |_| AppConfig::default(),
+            );
+            let http_service = match tls_acceptor {
+                None => boxed::factory(
+                    HttpServiceBuilder::new()
+                        .finish(app)
+                        .tcp()
+                        .map_err(|err| error!("[HTTP] Service Error: {:?}", err)),
+                ),
+                Some(tls_acceptor) => boxed::factory(
+                    HttpServiceBuilder::new()
+                        .on_connect_ext(super::cert_auth::add_client_certificate_cn)
+                        .finish(app)
+                        .openssl(tls_acceptor)
+                        .map_err(|err| error!("[HTTPS] Service Error: {:?}", err)),
+                ),
+            };
+            // Close the connections from disallowed addresses before handling any request.
+            apply_fn_factory(http_service, move |stream: TcpStream, service| {
+                let response = connection_filter
+                    .accept(stream.peer_addr(), "HTTP")
+                    .then(|| service.call(stream));
+                async move {
+                    match response {
+                        Some(response) => response.await,
+                        None => Ok(()),
+                    }
+                }
+            })
         })
         .with_context(|| {
             format!(
@@ -135,3 +919,173 @@ where
             )
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{
+            handler::{CreateUserRequest, UpdateUserRequest, User},
+            sql_backend_handler::SqlBackendHandler,
+        },
+        infra::{
+            attribute_visibility::AttributeVisibility, auth_service::create_jwt,
+            configuration::ConfigurationBuilder,
+        },
+    };
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, read_body_json, TestRequest},
+    };
+
+    #[actix_rt::test]
+    async fn test_get_user_reports() {
+        let config = ConfigurationBuilder::default()
+            .attribute_visibility(AttributeVisibilityPolicy::new(
+                [("email".to_string(), AttributeVisibility::SelfAndAdmins)]
+                    .into_iter()
+                    .collect(),
+            ))
+            .build()
+            .unwrap();
+        let data = AppState::new_for_tests(&config).await;
+        for user in ["alice", "bob", "carol"] {
+            data.backend_handler
+                .create_user(CreateUserRequest {
+                    user_id: UserId::new(user),
+                    email: format!("{}@example.com", user),
+                    display_name: Some(user.to_string()),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+        data.backend_handler
+            .update_user(UpdateUserRequest {
+                user_id: UserId::new("bob"),
+                manager_user_id: Some(Some(UserId::new("alice"))),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let app = init_service(App::new().app_data(data.clone()).route(
+            "/api/v1/users/{id}/reports",
+            web::get().to(get_user_reports::<SqlBackendHandler>),
+        ))
+        .await;
+        let get_reports = |viewer: &str, path: &str| {
+            let token = create_jwt(
+                &data.jwt_key,
+                viewer.to_string(),
+                HashSet::new(),
+                data.admin_group_id,
+            );
+            TestRequest::get()
+                .uri(path)
+                .insert_header(("Authorization", format!("Bearer {}", token.as_str())))
+                .to_request()
+        };
+        // The user IDs are compared case-insensitively, and bob's email is hidden from alice.
+        let response =
+            call_service(&app, get_reports("alice", "/api/v1/users/Alice/reports")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reports: Vec<User> = read_body_json(response).await;
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].user_id, UserId::new("bob"));
+        assert_eq!(reports[0].display_name, "bob");
+        assert_eq!(reports[0].email, "");
+        let response =
+            call_service(&app, get_reports("carol", "/api/v1/users/alice/reports")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_api_token() {
+        let config = ConfigurationBuilder::default().build().unwrap();
+        let data = AppState::new_for_tests(&config).await;
+        let admin_group = data
+            .backend_handler
+            .create_group("administrators")
+            .await
+            .unwrap();
+        assert_eq!(admin_group, data.admin_group_id);
+        data.backend_handler
+            .create_user(CreateUserRequest {
+                user_id: UserId::new("bob"),
+                email: "bob@example.com".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let bob = UserId::new("bob");
+        let (user_token, token) = data
+            .backend_handler
+            .create_user_token(&bob, "ci")
+            .await
+            .unwrap();
+        let app = init_service(App::new().app_data(data.clone()).route(
+            "/api/v1/config",
+            web::get().to(get_config::<SqlBackendHandler>),
+        ))
+        .await;
+        let get_config = |token: &str| {
+            TestRequest::get()
+                .uri("/api/v1/config")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request()
+        };
+        // The token authenticates bob, who isn't an admin yet.
+        let response = call_service(&app, get_config(&token)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        // The groups are checked on each request, by the ID of the admin group.
+        data.backend_handler
+            .add_user_to_group(&bob, admin_group)
+            .await
+            .unwrap();
+        let response = call_service(&app, get_config(&token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call_service(&app, get_config("lldap_unknown")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        data.backend_handler
+            .revoke_user_token(&bob, user_token.id)
+            .await
+            .unwrap();
+        let response = call_service(&app, get_config(&token)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_apply_branding() {
+        let index =
+            "<html><head>\n<title>LLDAP Administration</title>\n</head><body></body></html>";
+        assert_eq!(
+            apply_branding(index, &BrandingOptions::default(), false),
+            index
+        );
+        let branding = BrandingOptions {
+            title: Some("Example & Co".to_string()),
+            favicon_file: Some("/data/favicon.ico".to_string()),
+            assets_dir: Some("/data/static".to_string()),
+        };
+        assert_eq!(
+            apply_branding(index, &branding, true),
+            "<html><head>\n<title>Example &amp; Co</title>\n\
+             <link rel=\"icon\" href=\"/favicon.ico\" />\
+             <link rel=\"stylesheet\" href=\"/static/custom.css\" />\n\
+             </head><body></body></html>"
+        );
+    }
+
+    #[test]
+    fn test_inject_login_banner() {
+        assert_eq!(
+            inject_login_banner(
+                "<html><body></body></html>",
+                "Authorized use only.\n<No> \"guests\" & co.\n"
+            ),
+            "<html><body>\n<div id=\"login-banner\" class=\"alert alert-warning text-center\" \
+             style=\"white-space: pre-line\">Authorized use only.\n&lt;No&gt; &quot;guests&quot; \
+             &amp; co.</div></body></html>"
+        );
+    }
+}
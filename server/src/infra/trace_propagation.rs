@@ -0,0 +1,211 @@
+use crate::infra::configuration::OpenTelemetryOptions;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::HeaderMap,
+};
+use anyhow::{Context as _, Result};
+use futures::future::{ok, Ready};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, TextMapCompositePropagator},
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    KeyValue,
+};
+use std::{
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Sets up the export of the spans to the OTLP collector, and the propagators reading the trace
+/// context of the incoming requests: W3C Trace Context (`traceparent`) and Zipkin B3
+/// (`X-B3-TraceId`, or the single `b3` header). Returns None when the export is disabled.
+pub fn init_tracer(options: &OpenTelemetryOptions) -> Result<Option<trace::Tracer>> {
+    let endpoint = match &options.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(opentelemetry_zipkin::Propagator::new()),
+    ]));
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                options.service_name.clone(),
+            )])),
+        )
+        // The logs are set up before the runtime of the server starts, so the batches are
+        // exported from a thread of their own.
+        .install_batch(opentelemetry::runtime::TokioCurrentThread)
+        .with_context(|| format!("while setting up the OTLP exporter to {}", endpoint))?;
+    Ok(Some(tracer))
+}
+
+/// Exports the remaining spans.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Wraps the handling of each request in a span that continues the trace of the caller, if the
+/// request carries a trace context, or starts a new one. Does nothing without an OTLP exporter.
+#[derive(Clone, Default)]
+pub struct PropagatorMiddlewareFactory {
+    enabled: bool,
+}
+
+impl PropagatorMiddlewareFactory {
+    pub fn new(options: &OpenTelemetryOptions) -> Self {
+        Self {
+            enabled: options.otlp_endpoint.is_some(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for PropagatorMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = PropagatorMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(PropagatorMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+        })
+    }
+}
+
+pub struct PropagatorMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for PropagatorMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn core::future::Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !self.enabled {
+            return Box::pin(self.service.call(req));
+        }
+        let parent_context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        // The route template, e.g. /auth/reset/step2/{token}: the path itself can hold secrets.
+        let span = tracing::info_span!(
+            "HTTP request",
+            http.method = %req.method(),
+            http.route = tracing::field::Empty,
+            http.status_code = tracing::field::Empty,
+        );
+        if let Some(route) = req.request().match_pattern() {
+            span.record("http.route", &route.as_str());
+        }
+        span.set_parent(parent_context);
+        let response = span.in_scope(|| self.service.call(req));
+        Box::pin(async move {
+            let response = response.instrument(span.clone()).await?;
+            span.record("http.status_code", &response.status().as_u16());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use opentelemetry::{propagation::TextMapPropagator, trace::TraceContextExt};
+
+    fn extract(headers: &[(&'static str, &'static str)]) -> opentelemetry::trace::SpanContext {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        let propagator = TextMapCompositePropagator::new(vec![
+            Box::new(TraceContextPropagator::new()),
+            Box::new(opentelemetry_zipkin::Propagator::new()),
+        ]);
+        propagator
+            .extract(&HeaderExtractor(&header_map))
+            .span()
+            .span_context()
+            .clone()
+    }
+
+    #[test]
+    fn test_extract_trace_context() {
+        let context = extract(&[(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )]);
+        assert!(context.is_remote());
+        assert_eq!(
+            context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(context.span_id().to_string(), "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn test_extract_b3() {
+        let context = extract(&[
+            ("x-b3-traceid", "80f198ee56343ba864fe8b2a57d3eff7"),
+            ("x-b3-spanid", "e457b5a2e4d86bd1"),
+            ("x-b3-sampled", "1"),
+        ]);
+        assert!(context.is_remote());
+        assert_eq!(
+            context.trace_id().to_string(),
+            "80f198ee56343ba864fe8b2a57d3eff7"
+        );
+        assert_eq!(context.span_id().to_string(), "e457b5a2e4d86bd1");
+    }
+
+    #[test]
+    fn test_extract_nothing() {
+        assert!(!extract(&[]).is_valid());
+    }
+}
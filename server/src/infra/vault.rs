@@ -0,0 +1,208 @@
+use crate::infra::configuration::serialize_optional_secret;
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use secstr::SecUtf8;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use vaultrs::client::{Client, VaultClient, VaultClientSettingsBuilder};
+
+/// The key of the secret holding the value, when the path doesn't specify one.
+const DEFAULT_SECRET_KEY: &str = "value";
+
+/// How long to wait before trying again when the renewal of the token fails.
+const RENEWAL_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// HashiCorp Vault server to read some configuration values from, typically the secrets, so that
+/// they don't have to be in the configuration file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VaultConfig {
+    /// e.g. "https://vault.example.com:8200".
+    pub address: String,
+    /// Used unless the AppRole role ID is set.
+    #[serde(default, serialize_with = "serialize_optional_secret")]
+    pub token: Option<SecUtf8>,
+    #[serde(default)]
+    pub approle_role_id: Option<String>,
+    #[serde(default, serialize_with = "serialize_optional_secret")]
+    pub approle_secret_id: Option<SecUtf8>,
+    /// Mount point of the AppRole auth method.
+    #[serde(default = "default_approle_mount")]
+    pub approle_mount: String,
+    /// Configuration field (e.g. "jwt_secret" or "smtp_options.password") -> path of the KV v2
+    /// secret holding its value (e.g. "secret/data/lldap/jwt", see `SecretPath`).
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+    /// The token of the session that read the secrets, kept alive by `spawn_token_renewal`. The
+    /// AppRole secret ID can be single-use, so the server doesn't log in again.
+    #[serde(skip)]
+    pub session_token: Option<SecUtf8>,
+}
+
+fn default_approle_mount() -> String {
+    "approle".to_string()
+}
+
+/// "secret/data/lldap/jwt#key": the mount of the KV v2 engine, the path of the secret in it, and
+/// the key holding the value ("value" if there is no "#").
+#[derive(Debug, PartialEq, Eq)]
+struct SecretPath<'a> {
+    mount: &'a str,
+    path: &'a str,
+    key: &'a str,
+}
+
+impl<'a> SecretPath<'a> {
+    fn parse(secret_path: &'a str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid Vault secret path \"{}\"", secret_path);
+        let (path, key) = secret_path
+            .split_once('#')
+            .unwrap_or((secret_path, DEFAULT_SECRET_KEY));
+        let (mount, path) = path.trim_matches('/').split_once('/').ok_or_else(invalid)?;
+        // The "data/" of the API path is optional.
+        let path = path.strip_prefix("data/").unwrap_or(path);
+        if path.is_empty() || key.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self { mount, path, key })
+    }
+}
+
+fn new_client(address: &str, token: Option<&SecUtf8>) -> Result<VaultClient> {
+    let mut settings = VaultClientSettingsBuilder::default();
+    settings.address(address);
+    if let Some(token) = token {
+        settings.token(token.unsecure());
+    }
+    Ok(VaultClient::new(settings.build()?)?)
+}
+
+/// Returns the client, and the token of the session.
+async fn login(config: &VaultConfig) -> Result<(VaultClient, SecUtf8)> {
+    let mut client = new_client(&config.address, config.token.as_ref())?;
+    let token = match (&config.approle_role_id, &config.approle_secret_id) {
+        (Some(role_id), Some(secret_id)) => {
+            let auth = vaultrs::auth::approle::login(
+                &client,
+                &config.approle_mount,
+                role_id,
+                secret_id.unsecure(),
+            )
+            .await
+            .context("while logging in to Vault with AppRole")?;
+            client.set_token(&auth.client_token);
+            SecUtf8::from(auth.client_token)
+        }
+        (Some(_), None) => bail!("The Vault approle_secret_id is required with approle_role_id"),
+        (None, _) => config
+            .token
+            .clone()
+            .ok_or_else(|| anyhow!("Either a Vault token or an AppRole role ID is required"))?,
+    };
+    Ok((client, token))
+}
+
+/// The configuration values read from Vault.
+pub struct VaultSecrets {
+    /// Configuration field -> value.
+    pub values: Vec<(String, String)>,
+    /// To keep the session alive, see `VaultConfig::session_token`.
+    pub session_token: SecUtf8,
+}
+
+/// Reads the secrets from Vault, and returns the values of the configuration fields.
+pub async fn fetch_secrets(config: &VaultConfig) -> Result<VaultSecrets> {
+    let (client, session_token) = login(config).await?;
+    let mut values = Vec::with_capacity(config.secrets.len());
+    for (field, secret_path) in &config.secrets {
+        let secret = SecretPath::parse(secret_path)?;
+        let mut data: HashMap<String, String> =
+            vaultrs::kv2::read(&client, secret.mount, secret.path)
+                .await
+                .with_context(|| format!("while reading the Vault secret \"{}\"", secret_path))?;
+        let value = data.remove(secret.key).ok_or_else(|| {
+            anyhow!(
+                "No key \"{}\" in the Vault secret \"{}\"",
+                secret.key,
+                secret_path
+            )
+        })?;
+        values.push((field.clone(), value));
+    }
+    Ok(VaultSecrets {
+        values,
+        session_token,
+    })
+}
+
+fn renewal_delay(ttl_secs: u64) -> Duration {
+    Duration::from_secs(ttl_secs / 2).max(Duration::from_secs(1))
+}
+
+async fn renew_token(config: &VaultConfig) -> Result<()> {
+    let session_token = match &config.session_token {
+        Some(token) => token,
+        None => {
+            debug!("No Vault session to keep alive");
+            return Ok(());
+        }
+    };
+    let client = new_client(&config.address, Some(session_token))?;
+    let token = vaultrs::token::lookup_self(&client)
+        .await
+        .context("while looking up the Vault token")?;
+    if !token.renewable || token.ttl == 0 {
+        debug!("The Vault token doesn't need to be renewed");
+        return Ok(());
+    }
+    let mut delay = renewal_delay(token.ttl);
+    loop {
+        tokio::time::sleep(delay).await;
+        delay = match vaultrs::token::renew_self(&client, None).await {
+            Ok(auth) => {
+                debug!("Renewed the Vault token for {}s", auth.lease_duration);
+                renewal_delay(auth.lease_duration)
+            }
+            Err(e) => {
+                warn!("Could not renew the Vault token: {:#}", e);
+                RENEWAL_RETRY_DELAY
+            }
+        };
+    }
+}
+
+/// Keeps the Vault token of the session that read the secrets alive in the background, renewing it
+/// when half of its TTL has elapsed.
+pub fn spawn_token_renewal(config: VaultConfig) {
+    actix_rt::spawn(async move {
+        if let Err(e) = renew_token(&config).await {
+            warn!("Not renewing the Vault token: {:#}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_secret_path() {
+        assert_eq!(
+            SecretPath::parse("secret/data/lldap/jwt").unwrap(),
+            SecretPath {
+                mount: "secret",
+                path: "lldap/jwt",
+                key: "value"
+            }
+        );
+        assert_eq!(
+            SecretPath::parse("/kv/lldap#jwt_secret").unwrap(),
+            SecretPath {
+                mount: "kv",
+                path: "lldap",
+                key: "jwt_secret"
+            }
+        );
+        assert!(SecretPath::parse("secret").is_err());
+        assert!(SecretPath::parse("secret/lldap#").is_err());
+    }
+}
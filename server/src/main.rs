@@ -4,17 +4,31 @@
 
 use crate::{
     domain::{
-        handler::{BackendHandler, CreateUserRequest},
+        handler::{
+            BackendHandler, BackendTransaction, CreateUserRequest, GroupId, GroupRequestFilter,
+            TransactionHandler,
+        },
         sql_backend_handler::SqlBackendHandler,
         sql_opaque_handler::register_password,
         sql_tables::PoolOptions,
+        timeout_backend_handler::TimeoutBackendHandler,
+    },
+    infra::{
+        cli::*,
+        cli_output::{CliLogger, CliOutputConfig, ExitCode},
+        configuration::{BootstrapAdminUser, Configuration},
+        mail,
+        maintenance::MaintenanceMode,
     },
-    infra::{cli::*, configuration::Configuration, db_cleaner::Scheduler, mail},
 };
 use actix::Actor;
-use anyhow::{anyhow, Context, Result};
-use futures_util::TryFutureExt;
+use anyhow::{anyhow, bail, Context, Result};
 use log::*;
+use secstr::SecUtf8;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 mod domain;
 mod infra;
@@ -26,26 +40,119 @@ async fn create_admin_user(handler: &SqlBackendHandler, config: &Configuration)
         "Minimum password length is 8 characters, got {} characters",
         pass_length
     );
-    handler
-        .create_user(CreateUserRequest {
+    let admin_group_id = get_or_create_admin_group(handler, config)
+        .await
+        .context("Error creating admin group")?;
+    create_user_in_admin_group(
+        handler,
+        CreateUserRequest {
             user_id: config.ldap_user_dn.clone(),
             display_name: Some("Administrator".to_string()),
             ..Default::default()
+        },
+        &config.ldap_user_pass,
+        admin_group_id,
+    )
+    .await
+    .context("Error creating admin user")
+}
+
+/// Creates the user with their password in the admin group, all at once: a failure doesn't leave
+/// behind a user that the next startup would skip.
+async fn create_user_in_admin_group(
+    handler: &SqlBackendHandler,
+    request: CreateUserRequest,
+    password: &SecUtf8,
+    admin_group_id: GroupId,
+) -> Result<()> {
+    let password = password.clone();
+    handler
+        .transaction(|txn| {
+            Box::pin(async move {
+                let user_id = request.user_id.clone();
+                txn.create_user(request).await?;
+                register_password(txn, &user_id, &password).await?;
+                txn.add_user_to_group(&user_id, admin_group_id).await
+            })
         })
-        .and_then(|_| register_password(handler, &config.ldap_user_dn, &config.ldap_user_pass))
-        .await
-        .context("Error creating admin user")?;
-    let admin_group_id = handler
-        .create_group("lldap_admin")
+        .await?;
+    Ok(())
+}
+
+/// The group with the configured `admin_group_id`, created on the first startup. Falls back to
+/// the lldap_admin group by name if there is no such group.
+async fn get_or_create_admin_group(
+    handler: &SqlBackendHandler,
+    config: &Configuration,
+) -> Result<GroupId> {
+    let admin_group_id = GroupId(config.admin_group_id);
+    if handler.get_group_details(admin_group_id).await.is_ok() {
+        return Ok(admin_group_id);
+    }
+    warn!(
+        "No group has the admin_group_id {}, using the lldap_admin group",
+        config.admin_group_id
+    );
+    let admin_group = handler
+        .list_groups(Some(GroupRequestFilter::DisplayName(
+            "lldap_admin".to_string(),
+        )))
+        .await?
+        .into_iter()
+        .next();
+    Ok(match admin_group {
+        Some(group) => group.id,
+        None => handler.create_group("lldap_admin").await?,
+    })
+}
+
+/// Creates the `bootstrap_admin_user` in the admin group, unless a user already has its name.
+async fn create_bootstrap_admin_user(
+    handler: &SqlBackendHandler,
+    config: &Configuration,
+    admin: &BootstrapAdminUser,
+) -> Result<()> {
+    let is_weak = zxcvbn::zxcvbn(
+        admin.password.unsecure(),
+        &[admin.username.as_str(), &admin.email],
+    )
+    .map_or(true, |entropy| entropy.score() < 2);
+    if is_weak {
+        warn!(
+            "The password of the bootstrap_admin_user \"{}\" is weak, change it after the first login",
+            admin.username
+        );
+    }
+    if handler.get_user_details(&admin.username).await.is_ok() {
+        debug!(
+            "The bootstrap_admin_user \"{}\" already exists",
+            admin.username
+        );
+        return Ok(());
+    }
+    let admin_group_id = get_or_create_admin_group(handler, config)
         .await
         .context("Error creating admin group")?;
-    handler
-        .add_user_to_group(&config.ldap_user_dn, admin_group_id)
-        .await
-        .context("Error adding admin user to group")
+    create_user_in_admin_group(
+        handler,
+        CreateUserRequest {
+            user_id: admin.username.clone(),
+            email: admin.email.clone(),
+            ..Default::default()
+        },
+        &admin.password,
+        admin_group_id,
+    )
+    .await
+    .context("Error creating the user")?;
+    info!("Created the bootstrap_admin_user \"{}\"", admin.username);
+    Ok(())
 }
 
 async fn run_server(config: Configuration) -> Result<()> {
+    if let Some(vault_config) = &config.vault_options {
+        infra::vault::spawn_token_renewal(vault_config.clone());
+    }
     let sql_pool = PoolOptions::new()
         .max_connections(5)
         .connect(&config.database_url)
@@ -54,7 +161,23 @@ async fn run_server(config: Configuration) -> Result<()> {
     domain::sql_tables::init_table(&sql_pool)
         .await
         .context("while creating the tables")?;
+    // A missing index only makes the searches slower: keep going.
+    if let Err(e) =
+        domain::sql_index_analysis::check_indexes(&sql_pool, config.auto_create_indexes).await
+    {
+        warn!("Could not check the database indexes: {:#}", e);
+    }
     let backend_handler = SqlBackendHandler::new(config.clone(), sql_pool.clone());
+    if backend_handler
+        .init_admin_group()
+        .await
+        .context("while creating the admin group")?
+    {
+        info!(
+            "Empty database: created the lldap_admin group with the ID {}",
+            config.admin_group_id
+        );
+    }
     if let Err(e) = backend_handler.get_user_details(&config.ldap_user_dn).await {
         warn!("Could not get admin user, trying to create it: {:#}", e);
         create_admin_user(&backend_handler, &config)
@@ -62,25 +185,78 @@ async fn run_server(config: Configuration) -> Result<()> {
             .map_err(|e| anyhow!("Error setting up admin login/account: {:#}", e))
             .context("while creating the admin user")?;
     }
+    if let Some(admin) = &config.bootstrap_admin_user {
+        create_bootstrap_admin_user(&backend_handler, &config, admin)
+            .await
+            .with_context(|| {
+                format!("while creating the bootstrap admin user {}", admin.username)
+            })?;
+    }
+    infra::provisioning::reconcile(
+        &backend_handler,
+        &config.provisioning,
+        &config.ldap_user_dn,
+        GroupId(config.admin_group_id),
+    )
+    .await
+    .context("while provisioning the declared users and groups")?;
+    let maintenance_mode = MaintenanceMode::new(config.maintenance_mode);
+    infra::maintenance::listen_for_toggle_signal(maintenance_mode.clone())?;
+    let mailer = mail::Mailer::new(config.smtp_options.clone(), config.outbound_proxy.clone());
+    let backend_handler = TimeoutBackendHandler::new(backend_handler, &config);
+    let ldap_connections = infra::ldap_connections::LdapConnectionRegistry::default();
     let server_builder = infra::ldap_server::build_ldap_server(
         &config,
         backend_handler.clone(),
+        maintenance_mode.clone(),
+        mailer.clone(),
+        infra::ldap_extended_ops::ExtendedOperationRegistry::default(),
+        ldap_connections.clone(),
         actix_server::Server::build(),
     )
     .context("while binding the LDAP server")?;
     infra::jwt_sql_tables::init_table(&sql_pool).await?;
-    let server_builder =
-        infra::tcp_server::build_tcp_server(&config, backend_handler, server_builder)
-            .await
-            .context("while binding the TCP server")?;
-    // Run every hour.
-    let scheduler = Scheduler::new("0 0 * * * * *", sql_pool);
-    scheduler.start();
-    server_builder
-        .workers(1)
-        .run()
+    let server_builder = infra::tcp_server::build_tcp_server(
+        &config,
+        backend_handler.clone(),
+        maintenance_mode,
+        mailer,
+        ldap_connections,
+        server_builder,
+    )
+    .await
+    .context("while binding the TCP server")?;
+    infra::snmp::start_snmp_agent(&config.snmp_options)
         .await
-        .context("while starting the server")?;
+        .context("while starting the SNMP agent")?;
+    let server = server_builder.workers(1).run();
+    let self_test_failed = Arc::new(AtomicBool::new(false));
+    if config.startup_self_test.enabled {
+        let server = server.clone();
+        let self_test_failed = self_test_failed.clone();
+        actix_rt::spawn(async move {
+            if infra::self_test::run_self_test(&config).await {
+                info!("Startup self-test passed");
+            } else if config.startup_self_test.fail_startup {
+                error!("Startup self-test failed, stopping the server");
+                self_test_failed.store(true, Ordering::Relaxed);
+                server.stop(false).await;
+            } else {
+                warn!("Startup self-test failed, the server keeps running");
+            }
+        });
+    }
+    let server_result = server.await;
+    // Write the logins buffered since the last periodic flush, before exiting.
+    match backend_handler.flush_last_logins().await {
+        Ok(0) => {}
+        Ok(count) => info!("Saved the last logins of {} users", count),
+        Err(e) => error!("Could not save the last logins: {:#}", e),
+    }
+    server_result.context("while starting the server")?;
+    if self_test_failed.load(Ordering::Relaxed) {
+        bail!("the startup self-test failed");
+    }
     Ok(())
 }
 
@@ -95,23 +271,158 @@ fn run_server_command(opts: RunOpts) -> Result<()> {
     actix::run(
         run_server(config).unwrap_or_else(|e| error!("Could not bring up the servers: {:#}", e)),
     )?;
+    infra::trace_propagation::shutdown();
 
     info!("End.");
     Ok(())
 }
 
-fn send_test_email_command(opts: TestEmailOpts) -> Result<()> {
+/// Sets up the logs on the standard error, and the output of the results.
+fn init_cli_output(
+    general_config: &GeneralConfigOpts,
+    output_opts: &OutputOpts,
+    config: &Configuration,
+) -> Result<CliLogger> {
+    let output_config = CliOutputConfig::new(general_config, output_opts);
+    infra::logging::init_cli(config, output_config.verbosity)?;
+    Ok(CliLogger::new(output_config))
+}
+
+fn send_test_email_command(opts: TestEmailOpts) -> Result<ExitCode> {
     let to = opts.to.parse()?;
-    let config = infra::configuration::init(opts)?;
-    infra::logging::init(&config)?;
-    mail::send_test_email(to, &config.smtp_options)
+    let config = infra::configuration::init(opts.clone())?;
+    let mut output = init_cli_output(&opts.general_config, &opts.output_opts, &config)?;
+    output.info(format!(
+        "Sending a test email to '{}' with {}",
+        &opts.to,
+        mail::describe_options(&config.smtp_options)
+    ));
+    match mail::send_test_email(to, &config.smtp_options, &config.outbound_proxy) {
+        Ok(()) => {
+            output.result(
+                "Test email sent successfully",
+                &mail::MailTestResult::Success,
+            );
+            Ok(ExitCode::Success)
+        }
+        Err(e) => {
+            // The full chain includes the response of the SMTP server.
+            output.error(format!("Could not send the test email: {:#}", e));
+            output.json_result(&mail::MailTestResult::from(e));
+            Ok(ExitCode::Failure)
+        }
+    }
+}
+
+fn test_ldap_command(opts: TestLdapOpts) -> Result<ExitCode> {
+    let config = infra::configuration::init(opts.clone())?;
+    let mut output = init_cli_output(&opts.general_config, &opts.output_opts, &config)?;
+    let options = infra::ldap_check::LdapCheckOptions::new(&opts, &config);
+    let steps = tokio::runtime::Runtime::new()?.block_on(infra::ldap_check::run_checks(&options));
+    for step in &steps {
+        output.result(step, &step.report());
+    }
+    let failures = steps.iter().filter(|step| step.outcome.is_err()).count();
+    if failures == 0 {
+        output.info("All LDAP checks passed");
+        return Ok(ExitCode::Success);
+    }
+    output.error("LDAP check failed");
+    if failures < steps.len() {
+        Ok(ExitCode::PartialFailure)
+    } else {
+        Ok(ExitCode::Failure)
+    }
+}
+
+fn search_users_command(opts: SearchUsersOpts) -> Result<ExitCode> {
+    let config = infra::configuration::init(opts.clone())?;
+    let mut output = init_cli_output(&opts.general_config, &opts.output_opts, &config)?;
+    let options = infra::ldap_user_search::make_options(&opts, &config);
+    let entries = tokio::runtime::Runtime::new()?.block_on(infra::ldap_user_search::search_users(
+        &options,
+        &opts.filter,
+        &opts.attrs,
+    ));
+    match entries {
+        Ok(entries) => {
+            output.info(format!("Found {} users", entries.len()));
+            for entry in &entries {
+                output.result(infra::ldap_user_search::to_ldif(entry), entry);
+            }
+            Ok(ExitCode::Success)
+        }
+        Err(e) => {
+            output.error(format!(
+                "Could not search the users on {}: {:#}",
+                options.url, e
+            ));
+            Ok(ExitCode::Failure)
+        }
+    }
+}
+
+fn generate_dns_records_command(opts: GenerateDnsRecordsOpts) -> Result<ExitCode> {
+    let config = infra::configuration::init(opts.clone())?;
+    let mut output = init_cli_output(&opts.general_config, &opts.output_opts, &config)?;
+    match infra::dns_records::make_srv_records(&config) {
+        Ok(records) => {
+            output.result(records.format(opts.format), &records);
+            Ok(ExitCode::Success)
+        }
+        Err(e) => {
+            output.error(format!("Could not generate the DNS records: {:#}", e));
+            Ok(ExitCode::Failure)
+        }
+    }
+}
+
+fn verify_config_command(opts: VerifyConfigOpts) -> Result<ExitCode> {
+    let config = match infra::configuration::init(opts.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[FAIL] Parse the configuration: {:#}", e);
+            return Ok(ExitCode::Failure);
+        }
+    };
+    let mut output = init_cli_output(&opts.general_config, &opts.output_opts, &config)?;
+    output.info("[PASS] Parse the configuration");
+    let steps = actix_rt::System::new().block_on(infra::config_check::run_checks(&config));
+    for step in &steps {
+        output.result(step, &step.report());
+    }
+    let failures = steps.iter().filter(|step| step.outcome.is_err()).count();
+    if failures == 0 {
+        output.info("All configuration checks passed");
+        return Ok(ExitCode::Success);
+    }
+    output.error(format!("{} configuration checks failed", failures));
+    if failures < steps.len() {
+        Ok(ExitCode::PartialFailure)
+    } else {
+        Ok(ExitCode::Failure)
+    }
 }
 
 fn main() -> Result<()> {
     let cli_opts = infra::cli::init();
-    match cli_opts.command {
-        Command::ExportGraphQLSchema(opts) => infra::graphql::api::export_schema(opts),
-        Command::Run(opts) => run_server_command(opts),
+    let exit_code = match cli_opts.command {
+        Command::ExportGraphQLSchema(opts) => {
+            infra::graphql::api::export_schema(opts).map(|()| ExitCode::Success)
+        }
+        Command::Run(opts) => run_server_command(opts).map(|()| ExitCode::Success),
         Command::SendTestEmail(opts) => send_test_email_command(opts),
+        Command::TestLdap(opts) => test_ldap_command(opts),
+        Command::SearchUsers(opts) => search_users_command(opts),
+        Command::GenerateDnsRecords(opts) => generate_dns_records_command(opts),
+        Command::VerifyConfig(opts) => verify_config_command(opts),
+    };
+    match exit_code {
+        Ok(ExitCode::Success) => Ok(()),
+        Ok(exit_code) => std::process::exit(exit_code as i32),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(ExitCode::Failure as i32);
+        }
     }
 }